@@ -1,34 +1,58 @@
-use std::sync::Arc;
+use std::{iter, sync::Arc, time::Instant};
 
-use super::{DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
+use super::{
+    issue_refresh_token, refresh_token_requested, user_power_level, DEVICE_ID_LENGTH,
+    SESSION_ID_LENGTH, TOKEN_LENGTH,
+};
 use crate::{
-    database::{admin::make_user_admin, DatabaseGuard},
+    database::{
+        admin::{make_user_admin, render_welcome_message, send_server_notice},
+        threepid::ValidationSession,
+        DatabaseGuard,
+    },
     pdu::PduBuilder,
-    utils, Error, Result, Ruma,
+    utils, Database, Error, PduEvent, RefreshableResponse, Result, Ruma,
 };
 use ruma::{
     api::client::{
         account::{
-            change_password, deactivate, get_3pids, get_username_availability, register, whoami,
+            add_3pid, change_password, deactivate, get_3pids, get_username_availability, register,
+            request_3pid_management_token_via_email, request_registration_token_via_email, whoami,
             ThirdPartyIdRemovalStatus,
         },
         error::ErrorKind,
-        uiaa::{AuthFlow, AuthType, UiaaInfo},
+        uiaa::{AuthFlow, AuthType, IncomingAuthData, IncomingEmailIdentity, UiaaInfo},
     },
     events::{
         room::member::{MembershipState, RoomMemberEventContent},
         room::message::RoomMessageEventContent,
-        GlobalAccountDataEventType, RoomEventType,
+        room::{power_levels::RoomPowerLevelsEventContent, redaction::RoomRedactionEventContent},
+        GlobalAccountDataEventType, RoomEventType, StateEventType,
     },
-    push, UserId,
+    push,
+    thirdparty::{Medium, ThirdPartyIdentifierInit},
+    CanonicalJsonValue, DeviceId, MilliSecondsSinceUnixEpoch, ServerName, SessionId, UserId,
 };
-use serde_json::value::to_raw_value;
-use tracing::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, value::to_raw_value};
+use tracing::{error, info, warn};
 
+use axum::{
+    extract::{Extension, Query},
+    response::{IntoResponse, Json},
+};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
 use register::RegistrationKind;
+use sha1::Sha1;
+use tokio::sync::RwLock;
+
+type HmacSha1 = Hmac<Sha1>;
 
 const GUEST_NAME_LENGTH: usize = 10;
 
+const SUBMIT_EMAIL_TOKEN_PATH: &str = "/_matrix/client/unstable/conduit/email/submit_token";
+
 /// # `GET /_matrix/client/r0/register/available`
 ///
 /// Checks if a username is valid and available on this server.
@@ -37,6 +61,9 @@ const GUEST_NAME_LENGTH: usize = 10;
 /// - The user id is not historical
 /// - The server name of the user id matches this server
 /// - No user or appservice on this server already claimed this username
+/// - The username is not forbidden by the `forbidden_usernames` config, forbidden usernames are
+/// reported as unavailable
+/// - The username is not in the exclusive namespace of an appservice
 ///
 /// Note: This will not reserve the username, so the username might become invalid when trying to register
 pub async fn get_register_available_route(
@@ -55,6 +82,10 @@ pub async fn get_register_available_route(
                 "Username is invalid.",
             ))?;
 
+    if db.globals.username_forbidden(user_id.localpart()) {
+        return Ok(get_username_availability::v3::Response { available: false });
+    }
+
     // Check if username is creative enough
     if db.users.exists(&user_id)? {
         return Err(Error::BadRequest(
@@ -63,12 +94,39 @@ pub async fn get_register_available_route(
         ));
     }
 
-    // TODO add check for appservice namespaces
+    if db
+        .appservice
+        .user_reserved_by_other(&user_id, body.appservice_id.as_deref())?
+    {
+        return Ok(get_username_availability::v3::Response { available: false });
+    }
 
     // If no if check is true we have an username that's available to be used.
     Ok(get_username_availability::v3::Response { available: true })
 }
 
+/// # `GET /_matrix/client/v1/register/m.login.registration_token/validity`
+///
+/// Checks if a registration token can be used, so clients can fail early.
+///
+/// - Valid tokens exist, are not expired and have uses left
+/// - A valid token may still be used up by someone else before the registration completes
+pub async fn check_registration_token_validity_route(
+    db: DatabaseGuard,
+    body: Ruma<check_registration_token_validity::v1::IncomingRequest>,
+) -> Result<check_registration_token_validity::v1::Response> {
+    if !db.globals.allow_registration() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration has been disabled.",
+        ));
+    }
+
+    Ok(check_registration_token_validity::v1::Response::new(
+        db.users.registration_token_valid(&body.token)?,
+    ))
+}
+
 /// # `POST /_matrix/client/r0/register`
 ///
 /// Register an account on this homeserver.
@@ -77,15 +135,27 @@ pub async fn get_register_available_route(
 /// to check if the user id is valid and available.
 ///
 /// - Only works if registration is enabled
-/// - If type is guest: ignores all parameters except initial_device_display_name
-/// - If sender is not appservice: Requires UIAA (but we only use a dummy stage)
+/// - Limited per client IP address if `auth_rate_limit_per_second` is set
+/// - If type is guest: Only works if `allow_guests` is enabled, ignores all parameters except
+/// initial_device_display_name
+/// - Historical usernames can only be registered by appservices in their own namespace
+/// - Forbidden usernames can't be registered, except by appservices
+/// - Usernames in the exclusive namespace of an appservice can only be registered by it
+/// - The password must follow the password policy
+/// - If sender is not appservice: Requires UIAA (a dummy or registration token stage, or a
+/// verified email if email is enabled, after a captcha if reCAPTCHA is enabled)
 /// - If type is not guest and no username is given: Always fails after UIAA check
 /// - Creates a new account and populates it with default account data
+/// - If `welcome_room` is set and the sender is not a guest or appservice: Invites the user to it,
+/// or joins them if `welcome_room_auto_join` is set
 /// - If `inhibit_login` is false: Creates a device and returns device id and access_token
+/// - If `refresh_token` is true: the access token expires and a refresh token is returned too
 pub async fn register_route(
     db: DatabaseGuard,
     body: Ruma<register::v3::IncomingRequest>,
-) -> Result<register::v3::Response> {
+) -> Result<RefreshableResponse<register::v3::Response>> {
+    db.globals.check_auth_rate_limit(body.client_ip)?;
+
     if !db.globals.allow_registration() && !body.from_appservice {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
@@ -95,12 +165,19 @@ pub async fn register_route(
 
     let is_guest = body.kind == RegistrationKind::Guest;
 
+    if is_guest && !db.globals.allow_guests() {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guest access has been disabled.",
+        ));
+    }
+
     let mut missing_username = false;
 
     // Validate user id
     let user_id = UserId::parse_with_server_name(
         if is_guest {
-            utils::random_string(GUEST_NAME_LENGTH)
+            guest_localpart(&db)?
         } else {
             body.username.clone().unwrap_or_else(|| {
                 // If the user didn't send a username field, that means the client is just trying
@@ -114,12 +191,44 @@ pub async fn register_route(
         db.globals.server_name(),
     )
     .ok()
-    .filter(|user_id| !user_id.is_historical() && user_id.server_name() == db.globals.server_name())
     .ok_or(Error::BadRequest(
         ErrorKind::InvalidUsername,
         "Username is invalid.",
     ))?;
 
+    let in_namespace = match body.appservice_id.as_deref() {
+        Some(appservice_id) => db.appservice.user_in_namespace(appservice_id, &user_id)?,
+        None => false,
+    };
+    if !new_user_id_valid(&user_id, db.globals.server_name(), in_namespace) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "Username is invalid.",
+        ));
+    }
+
+    // Appservices may claim any username in their namespace
+    if !missing_username
+        && !body.from_appservice
+        && db.globals.username_forbidden(user_id.localpart())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidUsername,
+            "This username is reserved and can't be registered.",
+        ));
+    }
+
+    if !missing_username
+        && db
+            .appservice
+            .user_reserved_by_other(&user_id, body.appservice_id.as_deref())?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Exclusive,
+            "This username is reserved by an appservice.",
+        ));
+    }
+
     // Check if username is creative enough
     if db.users.exists(&user_id)? {
         return Err(Error::BadRequest(
@@ -128,19 +237,62 @@ pub async fn register_route(
         ));
     }
 
+    if let Some(password) = body.password.as_deref().filter(|_| !is_guest) {
+        check_password_policy(&db, &user_id, password)?;
+    }
+
     // UIAA
-    let mut uiaainfo = UiaaInfo {
-        flows: vec![AuthFlow {
-            stages: vec![AuthType::Dummy],
+    let mut flows = vec![AuthFlow {
+        stages: vec![if db.globals.registration_requires_token() {
+            AuthType::RegistrationToken
+        } else {
+            AuthType::Dummy
         }],
+    }];
+    // Binding an email is optional, but it can't replace a registration token
+    if db.globals.email_enabled() && !db.globals.registration_requires_token() {
+        flows.push(AuthFlow {
+            stages: vec![AuthType::EmailIdentity],
+        });
+    }
+
+    let mut params = Default::default();
+    if let Some((site_key, _)) = db.globals.recaptcha_keys() {
+        for flow in &mut flows {
+            flow.stages.insert(0, AuthType::ReCaptcha);
+        }
+        params = to_raw_value(&json!({
+            "m.login.recaptcha": { "public_key": site_key },
+        }))
+        .expect("json can be serialized");
+    }
+
+    let mut uiaainfo = UiaaInfo {
+        flows,
         completed: Vec::new(),
-        params: Default::default(),
+        params,
         session: None,
         auth_error: None,
     };
 
     if !body.from_appservice {
         if let Some(auth) = &body.auth {
+            if let IncomingAuthData::ReCaptcha(IncomingReCaptcha { response, .. }) = auth {
+                if !verify_recaptcha(&db, response).await {
+                    return Err(Error::Uiaa(
+                        db.uiaa.stage_failed(
+                            &UserId::parse_with_server_name("", db.globals.server_name())
+                                .expect("we know this is valid"),
+                            "".into(),
+                            auth,
+                            &uiaainfo,
+                            ErrorKind::Forbidden,
+                            "Captcha verification failed.",
+                        )?,
+                    ));
+                }
+            }
+
             let (worked, uiaainfo) = db.uiaa.try_auth(
                 &UserId::parse_with_server_name("", db.globals.server_name())
                     .expect("we know this is valid"),
@@ -148,6 +300,7 @@ pub async fn register_route(
                 auth,
                 &uiaainfo,
                 &db.users,
+                &db.threepid,
                 &db.globals,
             )?;
             if !worked {
@@ -176,61 +329,412 @@ pub async fn register_route(
         ));
     }
 
+    let email = match &body.auth {
+        Some(IncomingAuthData::EmailIdentity(IncomingEmailIdentity {
+            thirdparty_id_creds,
+            ..
+        })) => db.threepid.validated_session(
+            thirdparty_id_creds.sid.as_str(),
+            thirdparty_id_creds.client_secret.as_str(),
+            utils::millis_since_unix_epoch(),
+        )?,
+        _ => None,
+    };
+
+    // Bind the address first, so that no other registration can claim it in the meantime
+    if let Some(session) = &email {
+        db.threepid.add(
+            &user_id,
+            &session.medium,
+            &session.address,
+            utils::millis_since_unix_epoch(),
+        )?;
+    }
+
     let password = if is_guest {
         None
     } else {
         body.password.as_deref()
     };
 
-    // Create user
-    db.users.create(&user_id, password)?;
+    let displayname = match create_account(&db, &user_id, password, is_guest).await {
+        Ok(displayname) => displayname,
+        Err(e) => {
+            if let Some(session) = &email {
+                db.threepid
+                    .remove(&user_id, &session.medium, &session.address)?;
+            }
+            return Err(e);
+        }
+    };
+
+    // Users of appservices are usually puppets, they don't need to be welcomed
+    if !is_guest && !body.from_appservice {
+        if let Err(e) = add_to_welcome_room(&db, &user_id).await {
+            warn!("Failed to add {} to the welcome room: {}", user_id, e);
+        }
+    }
+
+    // Inhibit login does not work for guests
+    if !is_guest && body.inhibit_login {
+        return Ok(RefreshableResponse {
+            response: register::v3::Response {
+                access_token: None,
+                user_id,
+                device_id: None,
+            },
+            refresh: None,
+        });
+    }
+
+    // Generate new device id if the user didn't specify one
+    let device_id = if is_guest {
+        None
+    } else {
+        body.device_id.clone()
+    }
+    .unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
+
+    let token = create_device_with_token(
+        &db,
+        &user_id,
+        &device_id,
+        body.initial_device_display_name.clone(),
+    )?;
+
+    let refresh = if refresh_token_requested(body.json_body.as_ref()) {
+        Some(issue_refresh_token(&db, &user_id, &device_id, &token)?)
+    } else {
+        None
+    };
+
+    announce_new_user(&db, &user_id, displayname).await?;
+
+    db.flush()?;
+
+    Ok(RefreshableResponse {
+        response: register::v3::Response {
+            access_token: Some(token),
+            user_id,
+            device_id: Some(device_id),
+        },
+        refresh,
+    })
+}
+
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Hands out a nonce for shared-secret registration.
+pub async fn get_shared_secret_register_nonce_route(
+    db: DatabaseGuard,
+) -> Result<impl IntoResponse> {
+    shared_secret(&db)?;
+
+    Ok(Json(json!({
+        "nonce": db.globals.registration_nonces.create(Instant::now()),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SharedSecretRegistration {
+    nonce: String,
+    username: String,
+    password: String,
+    displayname: Option<String>,
+    #[serde(default)]
+    admin: bool,
+    mac: String,
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Registers a user for provisioning scripts that know the `registration_shared_secret`.
+///
+/// - The mac is a hex HMAC-SHA1 of the nonce, username, password and "admin" or "notadmin",
+/// separated by NUL bytes
+/// - Nonces can only be used once and expire after a minute
+/// - Skips UIAA and works even if registration is disabled
+/// - Accounts are set up like in `/register`
+/// - If `admin` is true: Grants admin privileges
+pub async fn shared_secret_register_route(
+    db: DatabaseGuard,
+    Json(body): Json<SharedSecretRegistration>,
+) -> Result<impl IntoResponse> {
+    let secret = shared_secret(&db)?;
+
+    if !db
+        .globals
+        .registration_nonces
+        .take(&body.nonce, Instant::now())
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Unknown,
+            "Unrecognised or expired nonce.",
+        ));
+    }
+
+    if !shared_secret_mac_valid(secret, &body) {
+        return Err(Error::BadRequest(ErrorKind::Forbidden, "HMAC incorrect."));
+    }
+
+    let user_id =
+        UserId::parse_with_server_name(body.username.to_lowercase(), db.globals.server_name())
+            .ok()
+            .filter(|user_id| {
+                !user_id.is_historical() && user_id.server_name() == db.globals.server_name()
+            })
+            .ok_or(Error::BadRequest(
+                ErrorKind::InvalidUsername,
+                "Username is invalid.",
+            ))?;
+
+    if db.users.exists(&user_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::UserInUse,
+            "Desired user ID is already taken.",
+        ));
+    }
+
+    check_password_policy(&db, &user_id, &body.password)?;
+
+    let mut displayname = create_account(&db, &user_id, Some(&body.password), false).await?;
+    if let Some(custom) = body.displayname {
+        db.users.set_displayname(&user_id, Some(custom.clone()))?;
+        displayname = custom;
+    }
+
+    let device_id: Box<DeviceId> = utils::random_string(DEVICE_ID_LENGTH).into();
+    let token = create_device_with_token(&db, &user_id, &device_id, None)?;
+
+    let made_admin = announce_new_user(&db, &user_id, displayname.clone()).await?;
+    if body.admin && !made_admin {
+        make_user_admin(&db, &user_id, displayname).await?;
+    }
+
+    db.flush()?;
+
+    Ok(Json(json!({
+        "access_token": token,
+        "user_id": user_id,
+        "home_server": db.globals.server_name(),
+        "device_id": device_id,
+    })))
+}
+
+fn shared_secret(db: &Database) -> Result<&str> {
+    db.globals
+        .registration_shared_secret()
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Shared secret registration is not enabled.",
+        ))
+}
+
+/// Checks the mac of a shared-secret registration in constant time.
+fn shared_secret_mac_valid(secret: &str, registration: &SharedSecretRegistration) -> bool {
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(registration.nonce.as_bytes());
+    mac.update(b"\0");
+    mac.update(registration.username.as_bytes());
+    mac.update(b"\0");
+    mac.update(registration.password.as_bytes());
+    mac.update(b"\0");
+    let admin: &[u8] = if registration.admin {
+        b"admin"
+    } else {
+        b"notadmin"
+    };
+    mac.update(admin);
+
+    decode_hex(&registration.mac).map_or(false, |expected| mac.verify(&expected).is_ok())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Historical user ids, e.g. with characters like `!` in the localpart, can't be registered
+/// anymore. Appservices may still use them in their namespaces, e.g. to mirror users of other
+/// networks.
+fn new_user_id_valid(
+    user_id: &UserId,
+    server_name: &ServerName,
+    in_appservice_namespace: bool,
+) -> bool {
+    user_id.server_name() == server_name && (!user_id.is_historical() || in_appservice_namespace)
+}
+
+/// Generates a random localpart for a guest that is not a forbidden username.
+fn guest_localpart(db: &Database) -> Result<String> {
+    iter::repeat_with(|| utils::random_string(GUEST_NAME_LENGTH).to_lowercase())
+        .take(10)
+        .find(|localpart| !db.globals.username_forbidden(localpart))
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Could not find a guest name that is not forbidden.",
+        ))
+}
+
+/// Creates a local account with the default displayname and account data and greets the user.
+/// Returns the displayname.
+async fn create_account(
+    db: &Database,
+    user_id: &UserId,
+    password: Option<&str>,
+    is_guest: bool,
+) -> Result<String> {
+    db.users.create(user_id, password, &db.globals)?;
+    if is_guest {
+        db.users.set_guest(user_id)?;
+    }
 
     // Default to pretty displayname
     let displayname = format!("{} ⚡️", user_id.localpart());
     db.users
-        .set_displayname(&user_id, Some(displayname.clone()))?;
+        .set_displayname(user_id, Some(displayname.clone()))?;
 
     // Initial account data
     db.account_data.update(
         None,
-        &user_id,
+        user_id,
         GlobalAccountDataEventType::PushRules.to_string().into(),
         &ruma::events::push_rules::PushRulesEvent {
             content: ruma::events::push_rules::PushRulesEventContent {
-                global: push::Ruleset::server_default(&user_id),
+                global: db
+                    .globals
+                    .push_action_overrides
+                    .apply(push::Ruleset::server_default(user_id)),
             },
         },
         &db.globals,
     )?;
 
-    // Inhibit login does not work for guests
-    if !is_guest && body.inhibit_login {
-        return Ok(register::v3::Response {
-            access_token: None,
-            user_id,
-            device_id: None,
-        });
+    if db.globals.default_identity_server_account_data() {
+        if let Some(identity_server) = db.globals.default_identity_server() {
+            db.account_data.update(
+                None,
+                user_id,
+                "m.identity_server".to_owned().into(),
+                &identity_server_event(identity_server),
+                &db.globals,
+            )?;
+        }
     }
 
-    // Generate new device id if the user didn't specify one
-    let device_id = if is_guest {
-        None
-    } else {
-        body.device_id.clone()
+    // Greet the new user in their server notice room
+    if let Some(template) = db.globals.welcome_message() {
+        if !(is_guest && db.globals.welcome_message_skip_guests()) {
+            let message = render_welcome_message(template, user_id);
+            if let Err(e) =
+                send_server_notice(db, user_id, RoomMessageEventContent::notice_plain(message))
+                    .await
+            {
+                warn!("Failed to send welcome message to {}: {}", user_id, e);
+            }
+        }
     }
-    .unwrap_or_else(|| utils::random_string(DEVICE_ID_LENGTH).into());
 
-    // Generate new token for the device
-    let token = utils::random_string(TOKEN_LENGTH);
+    Ok(displayname)
+}
 
-    // Create device for this account
-    db.users.create_device(
-        &user_id,
-        &device_id,
-        &token,
-        body.initial_device_display_name.clone(),
+/// Invites a new user to the `welcome_room`, or joins them if `welcome_room_auto_join` is set.
+/// Does nothing if the room doesn't exist or the user is already in it.
+async fn add_to_welcome_room(db: &Database, user_id: &UserId) -> Result<()> {
+    let room_id = match db.globals.welcome_room() {
+        Some(room_id) => room_id,
+        None => return Ok(()),
+    };
+
+    if !db.rooms.exists(room_id)? {
+        warn!("The welcome room {} does not exist.", room_id);
+        return Ok(());
+    }
+
+    let auto_join = db.globals.welcome_room_auto_join();
+    if db.rooms.is_joined(user_id, room_id)?
+        || (!auto_join && db.rooms.is_invited(user_id, room_id)?)
+    {
+        return Ok(());
+    }
+
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    let (membership, sender) = if auto_join {
+        (MembershipState::Join, user_id)
+    } else if db.rooms.is_joined(&conduit_user, room_id)? {
+        (MembershipState::Invite, &*conduit_user)
+    } else {
+        warn!(
+            "Can't invite to the welcome room {}, the server user is not in it.",
+            room_id
+        );
+        return Ok(());
+    };
+
+    let event = RoomMemberEventContent {
+        membership,
+        displayname: db.users.displayname(user_id)?,
+        avatar_url: db.users.avatar_url(user_id)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: db.users.blurhash(user_id)?,
+        reason: None,
+        join_authorized_via_users_server: None,
+    };
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomMember,
+            content: to_raw_value(&event).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        sender,
+        room_id,
+        db,
+        &state_lock,
     )?;
 
+    Ok(())
+}
+
+/// Creates a device with a new access token. Returns the access token.
+fn create_device_with_token(
+    db: &Database,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    display_name: Option<String>,
+) -> Result<String> {
+    let token = utils::random_string(TOKEN_LENGTH);
+    db.users
+        .create_device(user_id, device_id, &token, display_name)?;
+
+    Ok(token)
+}
+
+/// Tells the admins about a new user. The first real user becomes an admin, returns true if that
+/// was attempted.
+async fn announce_new_user(db: &Database, user_id: &UserId, displayname: String) -> Result<bool> {
     info!("New user {} registered on this server.", user_id);
     db.admin
         .send_message(RoomMessageEventContent::notice_plain(format!(
@@ -240,32 +744,117 @@ pub async fn register_route(
 
     // If this is the first real user, grant them admin privileges
     // Note: the server user, @conduit:servername, is generated first
-    if db.users.count()? == 2 {
-        make_user_admin(&db, &user_id, displayname).await?;
+    if !db.admin.enabled || db.users.count()? != 2 {
+        return Ok(false);
+    }
+
+    let result = make_user_admin(db, user_id, displayname).await;
 
-        warn!("Granting {} admin privileges as the first user", user_id);
+    // The account exists at this point, a failed admin setup must not fail the registration
+    match admin_bootstrap_notice(user_id, result) {
+        None => warn!("Granting {} admin privileges as the first user", user_id),
+        Some(notice) => db.admin.send_message(notice),
     }
 
-    db.flush()?;
+    Ok(true)
+}
 
-    Ok(register::v3::Response {
-        access_token: Some(token),
-        user_id,
-        device_id: Some(device_id),
+/// Logs a failed admin setup of the first user and returns a notice for the admin room.
+fn admin_bootstrap_notice(user_id: &UserId, result: Result<()>) -> Option<RoomMessageEventContent> {
+    let error = result.err()?;
+    error!(
+        "Failed to grant {} admin privileges as the first user: {}",
+        user_id, error
+    );
+
+    Some(RoomMessageEventContent::notice_plain(format!(
+        "Failed to grant {} admin privileges as the first user: {}\n\
+         Retry with: bootstrap-admin {}",
+        user_id, error, user_id
+    )))
+}
+
+/// The `m.identity_server` account data event pointing clients to the given identity server.
+fn identity_server_event(base_url: &str) -> serde_json::Value {
+    json!({
+        "type": "m.identity_server",
+        "content": {
+            "base_url": base_url,
+        },
     })
 }
 
+fn check_password_policy(db: &Database, user_id: &UserId, password: &str) -> Result<()> {
+    let policy = db.globals.password_policy();
+
+    policy
+        .check(password)
+        .map_err(|reason| Error::BadRequest(ErrorKind::WeakPassword, reason))?;
+
+    if let Some(minimum_score) = policy.minimum_score {
+        utils::validate_password(
+            password,
+            minimum_score,
+            &[user_id.localpart(), user_id.server_name().as_str()],
+        )
+        .map_err(|reason| Error::BadRequestDetailed(ErrorKind::WeakPassword, reason))?;
+    }
+
+    Ok(())
+}
+
+const RECAPTCHA_VERIFY_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+
+#[derive(Deserialize)]
+struct RecaptchaVerification {
+    success: bool,
+}
+
+/// Asks the captcha provider if the user solved the captcha. Errors count as failed verification.
+async fn verify_recaptcha(db: &Database, response: &str) -> bool {
+    let secret_key = match db.globals.recaptcha_keys() {
+        Some((_, secret_key)) => secret_key,
+        None => return false,
+    };
+
+    let verification = db
+        .globals
+        .default_client()
+        .post(RECAPTCHA_VERIFY_URL)
+        .form(&[("secret", secret_key), ("response", response)])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let body = match verification {
+        Ok(verification) => verification.bytes().await,
+        Err(e) => Err(e),
+    };
+
+    match body {
+        Ok(body) => serde_json::from_slice::<RecaptchaVerification>(&body)
+            .map_or(false, |verification| verification.success),
+        Err(e) => {
+            warn!("Failed to verify reCAPTCHA response: {}", e);
+            false
+        }
+    }
+}
+
 /// # `POST /_matrix/client/r0/account/password`
 ///
 /// Changes the password of this account.
 ///
+/// - Guests can't change their password
 /// - Requires UIAA to verify user password
+/// - The new password must follow the password policy
+/// - Limited per client IP address if `auth_rate_limit_per_second` is set
 /// - Changes the password of the sender user
 /// - The password hash is calculated using argon2 with 32 character salt, the plain password is
 /// not saved
 ///
 /// If logout_devices is true it does the following for each device except the sender device:
-/// - Invalidates access token
+/// - Invalidates access token, the client gets a soft logout
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
@@ -273,9 +862,20 @@ pub async fn change_password_route(
     db: DatabaseGuard,
     body: Ruma<change_password::v3::IncomingRequest>,
 ) -> Result<change_password::v3::Response> {
+    db.globals.check_auth_rate_limit(body.client_ip)?;
+
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
+    if db.users.is_guest(sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests can't change their password.",
+        ));
+    }
+
+    check_password_policy(&db, sender_user, &body.new_password)?;
+
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
             stages: vec![AuthType::Password],
@@ -293,6 +893,7 @@ pub async fn change_password_route(
             auth,
             &uiaainfo,
             &db.users,
+            &db.threepid,
             &db.globals,
         )?;
         if !worked {
@@ -309,7 +910,7 @@ pub async fn change_password_route(
     }
 
     db.users
-        .set_password(sender_user, Some(&body.new_password))?;
+        .set_password(sender_user, Some(&body.new_password), &db.globals)?;
 
     if body.logout_devices {
         // Logout all devices except the current one
@@ -319,7 +920,7 @@ pub async fn change_password_route(
             .filter_map(|id| id.ok())
             .filter(|id| id != sender_device)
         {
-            db.users.remove_device(sender_user, &id)?;
+            db.users.remove_device(sender_user, &id, true)?;
         }
     }
 
@@ -339,6 +940,8 @@ pub async fn change_password_route(
 ///
 /// Get user_id of the sender user.
 ///
+/// - `is_guest` is true if the account was registered as a guest
+///
 /// Note: Also works for Application Services
 pub async fn whoami_route(
     db: DatabaseGuard,
@@ -350,7 +953,7 @@ pub async fn whoami_route(
     Ok(whoami::v3::Response {
         user_id: sender_user.clone(),
         device_id,
-        is_guest: db.users.is_deactivated(&sender_user)?,
+        is_guest: db.users.is_guest(sender_user)?,
     })
 }
 
@@ -364,12 +967,18 @@ pub async fn whoami_route(
 /// - Forgets all to-device events
 /// - Triggers device list updates
 /// - Removes ability to log in again
+/// - If `deactivation_grace_days` is set, rooms are only left after the grace period. Until then
+/// admins can restore the account.
+/// - If `erase` is set: Clears the profile and redacts all messages of the user in the background,
+/// rooms are only left afterwards
 pub async fn deactivate_route(
     db: DatabaseGuard,
+    Extension(db_lock): Extension<Arc<RwLock<Database>>>,
     body: Ruma<deactivate::v3::IncomingRequest>,
 ) -> Result<deactivate::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+    let erase = erase_requested(body.json_body.as_ref());
 
     let mut uiaainfo = UiaaInfo {
         flows: vec![AuthFlow {
@@ -388,6 +997,7 @@ pub async fn deactivate_route(
             auth,
             &uiaainfo,
             &db.users,
+            &db.threepid,
             &db.globals,
         )?;
         if !worked {
@@ -403,29 +1013,105 @@ pub async fn deactivate_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    // Leave all joined rooms and reject all invitations
-    // TODO: work over federation invites
-    let all_rooms = db
-        .rooms
-        .rooms_joined(sender_user)
-        .chain(
-            db.rooms
-                .rooms_invited(sender_user)
-                .map(|t| t.map(|(r, _)| r)),
-        )
-        .collect::<Vec<_>>();
+    let grace_period = db.globals.deactivation_grace_period();
+    deactivate_user(&db, sender_user, erase).await?;
+    if erase && grace_period.is_zero() {
+        // The erasure is already due, start it now instead of waiting for the next cleanup. It is
+        // stored as a pending deactivation, so the cleanup picks it up again after a restart.
+        tokio::spawn(async move {
+            if let Err(e) = crate::database::finalize_deactivations(&*db_lock.read().await).await {
+                error!("Failed to finalize deactivations: {}", e);
+            }
+        });
+    }
 
-    for room_id in all_rooms {
-        let room_id = room_id?;
-        let event = RoomMemberEventContent {
-            membership: MembershipState::Leave,
-            displayname: None,
-            avatar_url: None,
-            is_direct: None,
-            third_party_invite: None,
-            blurhash: None,
-            reason: None,
-            join_authorized_via_users_server: None,
+    info!("User {} deactivated their account.", sender_user);
+    if grace_period.is_zero() {
+        db.admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "User {} deactivated their account.",
+                sender_user
+            )));
+    } else {
+        db.admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "User {} deactivated their account. It can be restored with `restore-account` \
+                during the next {} day(s).",
+                sender_user,
+                grace_period.as_secs() / (60 * 60 * 24)
+            )));
+    }
+
+    db.flush()?;
+
+    Ok(deactivate::v3::Response {
+        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+    })
+}
+
+/// Deactivates an account. During `deactivation_grace_days` it stays in its rooms and can be
+/// restored, only the devices are removed right away. Erasures always happen when the
+/// deactivation is finalized, because redactions have to be sent while the user is still in the
+/// rooms.
+pub(crate) async fn deactivate_user(db: &Database, user_id: &UserId, erase: bool) -> Result<()> {
+    let grace_period = db.globals.deactivation_grace_period();
+    if grace_period.is_zero() && !erase {
+        leave_all_rooms(db, user_id).await?;
+
+        // Remove devices and mark account as deactivated
+        db.users.deactivate_account(user_id)
+    } else {
+        db.users.schedule_deactivation(
+            user_id,
+            utils::millis_since_unix_epoch()
+                .saturating_add(u64::try_from(grace_period.as_millis()).unwrap_or(u64::MAX)),
+            erase,
+        )
+    }
+}
+
+/// Returns true if the client asked for the messages of the account to be erased.
+fn erase_requested(json_body: Option<&CanonicalJsonValue>) -> bool {
+    match json_body {
+        Some(CanonicalJsonValue::Object(body)) => {
+            matches!(body.get("erase"), Some(CanonicalJsonValue::Bool(true)))
+        }
+        _ => false,
+    }
+}
+
+/// Removes the profile of a user and redacts all messages they sent.
+pub(crate) async fn erase_user(db: &Database, user_id: &UserId) -> Result<()> {
+    erase_profile(db, user_id).await?;
+    erase_user_events(db, user_id).await
+}
+
+/// Removes the displayname and avatar of a user, also from the member events in their rooms.
+async fn erase_profile(db: &Database, user_id: &UserId) -> Result<()> {
+    db.users.set_displayname(user_id, None)?;
+    db.users.set_avatar_url(user_id, None)?;
+    db.users.set_blurhash(user_id, None)?;
+
+    let joined_rooms = db.rooms.rooms_joined(user_id).collect::<Vec<_>>();
+
+    for room_id in joined_rooms {
+        let room_id = room_id?;
+
+        let member_event = match db.rooms.room_state_get(
+            &room_id,
+            &StateEventType::RoomMember,
+            user_id.as_str(),
+        )? {
+            Some(member_event) => member_event,
+            None => continue,
+        };
+
+        let content = RoomMemberEventContent {
+            displayname: None,
+            avatar_url: None,
+            blurhash: None,
+            ..serde_json::from_str(member_event.content.get())
+                .map_err(|_| Error::bad_database("Invalid member event in database."))?
         };
 
         let mutex_state = Arc::clone(
@@ -441,44 +1127,1035 @@ pub async fn deactivate_route(
         db.rooms.build_and_append_pdu(
             PduBuilder {
                 event_type: RoomEventType::RoomMember,
-                content: to_raw_value(&event).expect("event is valid, we just created it"),
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
                 unsigned: None,
-                state_key: Some(sender_user.to_string()),
+                state_key: Some(user_id.to_string()),
                 redacts: None,
             },
-            sender_user,
+            user_id,
             &room_id,
-            &db,
+            db,
             &state_lock,
         )?;
     }
 
-    // Remove devices and mark account as deactivated
-    db.users.deactivate_account(sender_user)?;
+    Ok(())
+}
 
-    info!("User {} deactivated their account.", sender_user);
-    db.admin
-        .send_message(RoomMessageEventContent::notice_plain(format!(
-            "User {} deactivated their account.",
-            sender_user
-        )));
+/// Redacts all messages a user sent. The user sends the redactions in rooms they are still in,
+/// otherwise the server user does if it is in the room and its power level allows it. State events
+/// are kept so the rooms stay intact.
+async fn erase_user_events(db: &Database, user_id: &UserId) -> Result<()> {
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
 
-    db.flush()?;
+    let all_rooms = db
+        .rooms
+        .rooms_joined(user_id)
+        .chain(db.rooms.rooms_left(user_id).map(|t| t.map(|(r, _)| r)))
+        .collect::<Vec<_>>();
 
-    Ok(deactivate::v3::Response {
-        id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
-    })
+    for room_id in all_rooms {
+        let room_id = room_id?;
+
+        let redactor: &UserId = if db.rooms.is_joined(user_id, &room_id)? {
+            user_id
+        } else if db.rooms.is_joined(&conduit_user, &room_id)? {
+            &conduit_user
+        } else {
+            continue;
+        };
+
+        let power_levels = db
+            .rooms
+            .room_state_get(&room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|pdu| {
+                serde_json::from_str::<RoomPowerLevelsEventContent>(pdu.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        if !may_redact(&power_levels, redactor, user_id) {
+            warn!(
+                "Not allowed to erase events of {} in {}, skipping room.",
+                user_id, room_id
+            );
+            continue;
+        }
+
+        let event_ids = db
+            .rooms
+            .all_pdus(user_id, &room_id)?
+            .filter_map(|r| r.ok())
+            .filter(|(_, pdu)| should_erase(pdu, user_id))
+            .map(|(_, pdu)| pdu.event_id)
+            .collect::<Vec<_>>();
+
+        if event_ids.is_empty() {
+            continue;
+        }
+
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        for event_id in event_ids {
+            db.rooms.build_and_append_pdu(
+                PduBuilder {
+                    event_type: RoomEventType::RoomRedaction,
+                    content: to_raw_value(&RoomRedactionEventContent {
+                        reason: Some("Account erased".to_owned()),
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: None,
+                    redacts: Some(event_id),
+                },
+                redactor,
+                &room_id,
+                db,
+                &state_lock,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Everyone may redact their own events, others need the redact power level.
+fn may_redact(
+    power_levels: &RoomPowerLevelsEventContent,
+    redactor: &UserId,
+    sender: &UserId,
+) -> bool {
+    redactor == sender || user_power_level(power_levels, redactor) >= power_levels.redact
+}
+
+/// Messages of the user that were not redacted yet.
+fn should_erase(pdu: &PduEvent, user_id: &UserId) -> bool {
+    &*pdu.sender == user_id
+        && pdu.state_key.is_none()
+        && pdu.kind != RoomEventType::RoomRedaction
+        && !pdu.is_redacted()
+}
+
+/// Leaves all joined rooms of a user and rejects all their invitations.
+pub(crate) async fn leave_all_rooms(db: &Database, user_id: &UserId) -> Result<()> {
+    // TODO: work over federation invites
+    let all_rooms = db
+        .rooms
+        .rooms_joined(user_id)
+        .chain(db.rooms.rooms_invited(user_id).map(|t| t.map(|(r, _)| r)))
+        .collect::<Vec<_>>();
+
+    for room_id in all_rooms {
+        let room_id = room_id?;
+        let event = RoomMemberEventContent {
+            membership: MembershipState::Leave,
+            displayname: None,
+            avatar_url: None,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: None,
+            reason: None,
+            join_authorized_via_users_server: None,
+        };
+
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomMember,
+                content: to_raw_value(&event).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            user_id,
+            &room_id,
+            db,
+            &state_lock,
+        )?;
+    }
+
+    Ok(())
 }
 
 /// # `GET _matrix/client/r0/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub async fn third_party_route(
+    db: DatabaseGuard,
     body: Ruma<get_3pids::v3::Request>,
 ) -> Result<get_3pids::v3::Response> {
-    let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    let threepids = db
+        .threepid
+        .threepids(sender_user)
+        .map(|r| {
+            let (medium, address, added_at) = r?;
+            let added_at = MilliSecondsSinceUnixEpoch(added_at.try_into().expect("time is valid"));
+
+            Ok(ThirdPartyIdentifierInit {
+                address,
+                medium,
+                // Addresses are only added right after they were verified
+                validated_at: added_at,
+                added_at,
+            }
+            .into())
+        })
+        .collect::<Result<_>>()?;
 
-    Ok(get_3pids::v3::Response::new(Vec::new()))
+    Ok(get_3pids::v3::Response::new(threepids))
+}
+
+/// # `POST /_matrix/client/r0/register/email/requestToken`
+///
+/// Sends a verification code to an email address that should be bound to a new account.
+///
+/// - Only works if registration and email are enabled
+/// - The email domain must be allowed by `allowed_email_domains` and `denied_email_domains`
+/// - Limited per client IP address if `auth_rate_limit_per_second` is set
+pub async fn request_registration_token_via_email_route(
+    db: DatabaseGuard,
+    body: Ruma<request_registration_token_via_email::v3::IncomingRequest>,
+) -> Result<request_registration_token_via_email::v3::Response> {
+    db.globals.check_auth_rate_limit(body.client_ip)?;
+
+    if !db.globals.allow_registration() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Registration has been disabled.",
+        ));
+    }
+
+    if !db.globals.config.email_domain_allowed(&body.email) {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "This email domain is not allowed to register.",
+        ));
+    }
+
+    let (sid, submit_url) = request_email_token(
+        &db,
+        body.client_secret.as_str(),
+        &body.email,
+        body.send_attempt.into(),
+    )
+    .await?;
+
+    let mut response = request_registration_token_via_email::v3::Response::new(sid);
+    response.submit_url = Some(submit_url);
+    Ok(response)
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/email/requestToken`
+///
+/// Sends a verification code to an email address that should be added to an account.
+///
+/// - The email domain must be allowed by `allowed_email_domains` and `denied_email_domains`
+/// - Limited per client IP address if `auth_rate_limit_per_second` is set
+pub async fn request_3pid_management_token_via_email_route(
+    db: DatabaseGuard,
+    body: Ruma<request_3pid_management_token_via_email::v3::IncomingRequest>,
+) -> Result<request_3pid_management_token_via_email::v3::Response> {
+    db.globals.check_auth_rate_limit(body.client_ip)?;
+
+    if !db.globals.config.email_domain_allowed(&body.email) {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidDenied,
+            "This email domain is not allowed.",
+        ));
+    }
+
+    let (sid, submit_url) = request_email_token(
+        &db,
+        body.client_secret.as_str(),
+        &body.email,
+        body.send_attempt.into(),
+    )
+    .await?;
+
+    let mut response = request_3pid_management_token_via_email::v3::Response::new(sid);
+    response.submit_url = Some(submit_url);
+    Ok(response)
+}
+
+/// Sends a verification code unless the client already requested one with this send attempt.
+///
+/// Returns the session id and the url the code can be submitted to.
+async fn request_email_token(
+    db: &Database,
+    client_secret: &str,
+    email: &str,
+    send_attempt: u64,
+) -> Result<(Box<SessionId>, String)> {
+    if !db.globals.email_enabled() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidMediumNotSupported,
+            "This server does not send emails.",
+        ));
+    }
+
+    if db.threepid.user_id(&Medium::Email, email)?.is_some() {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidInUse,
+            "This email address is already in use by another account.",
+        ));
+    }
+
+    let now = utils::millis_since_unix_epoch();
+    let submit_url = format!(
+        "{}{}",
+        db.globals.well_known_client().trim_end_matches('/'),
+        SUBMIT_EMAIL_TOKEN_PATH
+    );
+
+    let (sid, session) =
+        match db
+            .threepid
+            .find_session(client_secret, &Medium::Email, email, now)?
+        {
+            // Clients retry with the same send attempt if they didn't get our response
+            Some((sid, session)) if send_attempt <= session.send_attempt => {
+                return Ok((sid.try_into().expect("session ids are valid"), submit_url))
+            }
+            Some((sid, mut session)) => {
+                session.send_attempt = send_attempt;
+                (sid, session)
+            }
+            None => (
+                utils::random_string(SESSION_ID_LENGTH),
+                ValidationSession {
+                    client_secret: client_secret.to_owned(),
+                    medium: Medium::Email,
+                    address: email.to_owned(),
+                    token: format!("{:06}", rand::thread_rng().gen_range(0..1_000_000)),
+                    send_attempt,
+                    expires_at: now
+                        .saturating_add(db.globals.threepid_session_ttl().as_millis() as u64),
+                    validated_at: None,
+                    failed_attempts: 0,
+                },
+            ),
+        };
+
+    db.globals
+        .send_email(
+            email,
+            &format!("Verify your email address on {}", db.globals.server_name()),
+            format!(
+                "Your verification code is {token}.\n\n\
+                 To verify your email address, open this link:\n\
+                 {url}?sid={sid}&client_secret={client_secret}&token={token}\n\n\
+                 If you didn't ask for this, you can ignore this email.",
+                token = session.token,
+                url = submit_url,
+                sid = sid,
+                client_secret = client_secret,
+            ),
+        )
+        .await?;
+
+    db.threepid.create_session(&sid, &session, now)?;
+    db.flush()?;
+
+    Ok((
+        sid.try_into().expect("random string is a valid session id"),
+        submit_url,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct SubmitEmailToken {
+    sid: String,
+    client_secret: String,
+    token: String,
+}
+
+/// # `GET /_matrix/client/unstable/conduit/email/submit_token`
+///
+/// Verifies an email address with the link sent to it.
+pub async fn submit_email_token_link_route(
+    db: DatabaseGuard,
+    Query(submission): Query<SubmitEmailToken>,
+) -> Result<impl IntoResponse> {
+    submit_email_token(&db, &submission)?;
+
+    Ok("Your email address has been verified. You can return to your client now.")
+}
+
+/// # `POST /_matrix/client/unstable/conduit/email/submit_token`
+///
+/// Verifies an email address with the code sent to it.
+pub async fn submit_email_token_route(
+    db: DatabaseGuard,
+    Json(submission): Json<SubmitEmailToken>,
+) -> Result<impl IntoResponse> {
+    submit_email_token(&db, &submission)?;
+
+    Ok(Json(json!({ "success": true })))
+}
+
+fn submit_email_token(db: &Database, submission: &SubmitEmailToken) -> Result<()> {
+    if !db.threepid.submit_token(
+        &submission.sid,
+        &submission.client_secret,
+        &submission.token,
+        utils::millis_since_unix_epoch(),
+    )? {
+        return Err(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The verification code is wrong or expired.",
+        ));
+    }
+
+    db.flush()
+}
+
+/// # `POST /_matrix/client/r0/account/3pid/add`
+///
+/// Adds a verified email address to the account.
+///
+/// - Guests can't add addresses
+/// - Requires UIAA to verify password
+/// - Addresses can only belong to one account
+pub async fn add_3pid_route(
+    db: DatabaseGuard,
+    body: Ruma<add_3pid::v3::IncomingRequest>,
+) -> Result<add_3pid::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+    let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+    if db.users.is_guest(sender_user)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests can't add email addresses.",
+        ));
+    }
+
+    let mut uiaainfo = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+
+    if let Some(auth) = &body.auth {
+        let (worked, uiaainfo) = db.uiaa.try_auth(
+            sender_user,
+            sender_device,
+            auth,
+            &uiaainfo,
+            &db.users,
+            &db.threepid,
+            &db.globals,
+        )?;
+        if !worked {
+            return Err(Error::Uiaa(uiaainfo));
+        }
+    // Success!
+    } else if let Some(json) = body.json_body {
+        uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        db.uiaa
+            .create(sender_user, sender_device, &uiaainfo, &json)?;
+        return Err(Error::Uiaa(uiaainfo));
+    } else {
+        return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+    }
+
+    let now = utils::millis_since_unix_epoch();
+    let session = db
+        .threepid
+        .validated_session(body.sid.as_str(), body.client_secret.as_str(), now)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::ThreepidAuthFailed,
+            "The email address has not been verified.",
+        ))?;
+
+    db.threepid
+        .add(sender_user, &session.medium, &session.address, now)?;
+
+    db.flush()?;
+
+    info!(
+        "User {} added the email address {}.",
+        sender_user, session.address
+    );
+
+    Ok(add_3pid::v3::Response {})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        admin_bootstrap_notice, erase_requested, identity_server_event, may_redact,
+        new_user_id_valid, shared_secret_mac_valid, SharedSecretRegistration,
+    };
+    use crate::Error;
+    use ruma::{
+        events::room::power_levels::RoomPowerLevelsEventContent, server_name, user_id,
+        CanonicalJsonValue, UserId,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn failed_admin_bootstrap_produces_notice_instead_of_error() {
+        let user_id = user_id!("@alice:example.org");
+
+        assert!(admin_bootstrap_notice(user_id, Ok(())).is_none());
+
+        let notice = admin_bootstrap_notice(
+            user_id,
+            Err(Error::BadConfig("The admin room does not exist.")),
+        )
+        .expect("failures are reported");
+        let notice = serde_json::to_value(&notice).unwrap();
+        assert!(notice["body"]
+            .as_str()
+            .unwrap()
+            .contains("bootstrap-admin @alice:example.org"));
+    }
+
+    #[test]
+    fn identity_server_account_data_has_base_url() {
+        assert_eq!(
+            identity_server_event("https://id.example.org"),
+            json!({
+                "type": "m.identity_server",
+                "content": { "base_url": "https://id.example.org" },
+            })
+        );
+    }
+
+    #[test]
+    fn shared_secret_mac_covers_admin_flag() {
+        let registration = |admin, mac: &str| SharedSecretRegistration {
+            nonce: "abc".to_owned(),
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+            displayname: None,
+            admin,
+            mac: mac.to_owned(),
+        };
+
+        let admin_mac = "b3fdd4559ee88abf31c18d6e5f5c07b158b0ee17";
+        let user_mac = "b3b0d694c76a258d891f67c245e656b7705014d8";
+
+        assert!(shared_secret_mac_valid(
+            "shared_secret",
+            &registration(true, admin_mac)
+        ));
+        assert!(shared_secret_mac_valid(
+            "shared_secret",
+            &registration(false, user_mac)
+        ));
+        assert!(!shared_secret_mac_valid(
+            "shared_secret",
+            &registration(true, user_mac)
+        ));
+        assert!(!shared_secret_mac_valid(
+            "other_secret",
+            &registration(true, admin_mac)
+        ));
+        assert!(!shared_secret_mac_valid(
+            "shared_secret",
+            &registration(true, "not hex")
+        ));
+    }
+
+    #[test]
+    fn erasure_respects_power_levels() {
+        let alice = user_id!("@alice:example.org");
+        let conduit = user_id!("@conduit:example.org");
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        assert!(may_redact(&power_levels, alice, alice));
+        assert!(!may_redact(&power_levels, conduit, alice));
+
+        power_levels.users.insert(conduit.to_owned(), 50.into());
+        assert!(may_redact(&power_levels, conduit, alice));
+
+        let body = |erase| {
+            CanonicalJsonValue::try_from(json!({ "erase": erase })).expect("valid canonical json")
+        };
+        assert!(erase_requested(Some(&body(true))));
+        assert!(!erase_requested(Some(&body(false))));
+        assert!(!erase_requested(None));
+    }
+
+    #[test]
+    fn appservices_may_register_historical_user_ids_in_their_namespace() {
+        let server_name = server_name!("example.org");
+        let historical = UserId::parse("@bridge_alice!:example.org").unwrap();
+        assert!(historical.is_historical());
+
+        assert!(!new_user_id_valid(&historical, server_name, false));
+        assert!(new_user_id_valid(&historical, server_name, true));
+
+        let alice = user_id!("@alice:example.org");
+        assert!(new_user_id_valid(alice, server_name, false));
+        assert!(!new_user_id_valid(
+            user_id!("@alice:other.org"),
+            server_name,
+            true
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn appservices_register_historical_user_ids_in_their_namespace() {
+        use super::register_route;
+        use crate::{database::DatabaseGuard, Database, Ruma};
+        use ruma::api::{client::account::register, client::error::ErrorKind, IncomingRequest};
+        use std::sync::Arc;
+        use tokio::sync::RwLock;
+
+        async fn register(
+            db: &Arc<RwLock<Database>>,
+            username: &str,
+            appservice_id: Option<&str>,
+        ) -> Result<Box<UserId>, Error> {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("/_matrix/client/r0/register")
+                .body(
+                    serde_json::to_vec(&json!({
+                        "username": username,
+                        "type": "m.login.application_service",
+                        "inhibit_login": true,
+                    }))
+                    .unwrap(),
+                )
+                .unwrap();
+
+            register_route(
+                DatabaseGuard::from(Arc::clone(db).read_owned().await),
+                Ruma {
+                    body: register::v3::IncomingRequest::try_from_http_request::<_, String>(
+                        request,
+                        &[],
+                    )
+                    .unwrap(),
+                    sender_user: None,
+                    sender_device: None,
+                    sender_servername: None,
+                    json_body: None,
+                    from_appservice: appservice_id.is_some(),
+                    appservice_id: appservice_id.map(ToOwned::to_owned),
+                    client_ip: None,
+                },
+            )
+            .await
+            .map(|registered| registered.response.user_id)
+        }
+
+        let db = crate::database::test_database("historical-register").await;
+        db.read()
+            .await
+            .appservice
+            .register_appservice(
+                serde_yaml::from_str(
+                    r#"
+id: bridge
+url: "http://localhost:9000"
+as_token: as_token
+hs_token: hs_token
+sender_localpart: bridge
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@bridge_.*:example.org"
+"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            register(&db, "bridge_alice!", Some("bridge"))
+                .await
+                .unwrap(),
+            user_id!("@bridge_alice!:example.org")
+        );
+        assert!(matches!(
+            register(&db, "alice!", Some("bridge")).await,
+            Err(Error::BadRequest(ErrorKind::InvalidUsername, _))
+        ));
+        assert!(matches!(
+            register(&db, "bridge_bob!", Some("unknown")).await,
+            Err(Error::BadRequest(ErrorKind::InvalidUsername, _))
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn erasing_without_grace_period_is_finalized_by_the_cleanup() {
+        use super::deactivate_route;
+        use crate::{database::DatabaseGuard, Ruma};
+        use axum::extract::Extension;
+        use ruma::{api::client::account::deactivate, api::IncomingRequest, device_id};
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("erase-deactivate").await;
+        let alice = user_id!("@alice:example.org");
+        {
+            let db = db.read().await;
+            db.users
+                .create(alice, Some("password"), &db.globals)
+                .unwrap();
+            db.users
+                .create_device(alice, device_id!("DEVICE"), "token", None)
+                .unwrap();
+            db.users
+                .set_displayname(alice, Some("Alice".to_owned()))
+                .unwrap();
+        }
+
+        let body = json!({
+            "auth": {
+                "type": "m.login.password",
+                "identifier": { "type": "m.id.user", "user": "alice" },
+                "password": "password",
+            },
+            "erase": true,
+        });
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/_matrix/client/r0/account/deactivate")
+            .body(serde_json::to_vec(&body).unwrap())
+            .unwrap();
+
+        deactivate_route(
+            DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+            Extension(Arc::clone(&db)),
+            Ruma {
+                body: deactivate::v3::IncomingRequest::try_from_http_request::<_, String>(
+                    request,
+                    &[],
+                )
+                .unwrap(),
+                sender_user: Some(alice.to_owned()),
+                sender_device: Some(device_id!("DEVICE").to_owned()),
+                sender_servername: None,
+                json_body: Some(serde_json::from_value(body).unwrap()),
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let db = db.read().await;
+        assert!(db.users.is_deactivated(alice).unwrap());
+
+        // The erasure is stored, so it isn't lost if the server stops before it ran
+        crate::database::finalize_deactivations(&db).await.unwrap();
+        assert_eq!(db.users.displayname(alice).unwrap(), None);
+        assert_eq!(db.users.pending_deactivations().count(), 0);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn registration_without_admin_room_sends_no_notice() {
+        use super::register_route;
+        use crate::{
+            database::{admin::find_admin_room, test_config, DatabaseGuard},
+            Database, Ruma,
+        };
+        use ruma::api::{client::account::register, IncomingRequest};
+        use std::sync::Arc;
+        use tokio::sync::mpsc;
+
+        let mut config = test_config("register-without-admin-room");
+        config.allow_registration = true;
+        let db = Database::load_or_create(&config).await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        db.write().await.admin.sender = sender;
+
+        let body = json!({
+            "username": "alice",
+            "password": "correct horse battery staple",
+            "auth": { "type": "m.login.dummy" },
+            "inhibit_login": true,
+        });
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/_matrix/client/r0/register")
+            .body(serde_json::to_vec(&body).unwrap())
+            .unwrap();
+        let registered = register_route(
+            DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+            Ruma {
+                body: register::v3::IncomingRequest::try_from_http_request::<_, String>(
+                    request,
+                    &[],
+                )
+                .unwrap(),
+                sender_user: None,
+                sender_device: None,
+                sender_servername: None,
+                json_body: Some(serde_json::from_value(body).unwrap()),
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let db = db.read().await;
+        let alice = registered.response.user_id;
+        assert!(db.users.exists(&alice).unwrap());
+        assert!(!db.users.is_admin(&alice, &db.rooms, &db.globals).unwrap());
+        assert_eq!(find_admin_room(&db).unwrap(), None);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn new_users_get_the_welcome_notice() {
+        use super::register_route;
+        use crate::{
+            database::{test_config, DatabaseGuard},
+            Database, Ruma,
+        };
+        use ruma::api::{client::account::register, IncomingRequest};
+        use std::sync::Arc;
+        use tokio::sync::RwLock;
+
+        async fn register(
+            db: &Arc<RwLock<Database>>,
+            uri: &str,
+            body: serde_json::Value,
+        ) -> Box<UserId> {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(serde_json::to_vec(&body).unwrap())
+                .unwrap();
+            register_route(
+                DatabaseGuard::from(Arc::clone(db).read_owned().await),
+                Ruma {
+                    body: register::v3::IncomingRequest::try_from_http_request::<_, String>(
+                        request,
+                        &[],
+                    )
+                    .unwrap(),
+                    sender_user: None,
+                    sender_device: None,
+                    sender_servername: None,
+                    json_body: Some(serde_json::from_value(body).unwrap()),
+                    from_appservice: false,
+                    appservice_id: None,
+                    client_ip: None,
+                },
+            )
+            .await
+            .unwrap()
+            .response
+            .user_id
+        }
+
+        let mut config = test_config("welcome-notice");
+        config.allow_registration = true;
+        config.allow_guests = true;
+        config.welcome_message = Some("Welcome to {server_name}, {localpart}!".to_owned());
+        config.welcome_message_skip_guests = true;
+        let db = Database::load_or_create(&config).await.unwrap();
+
+        let alice = register(
+            &db,
+            "/_matrix/client/r0/register",
+            json!({
+                "username": "alice",
+                "password": "correct horse battery staple",
+                "auth": { "type": "m.login.dummy" },
+            }),
+        )
+        .await;
+        let guest = register(
+            &db,
+            "/_matrix/client/r0/register?kind=guest",
+            json!({ "auth": { "type": "m.login.dummy" } }),
+        )
+        .await;
+
+        let db = db.read().await;
+        let notice_room = db.users.server_notice_room(&alice).unwrap().unwrap();
+        let notice = db
+            .rooms
+            .pdus_until(&alice, &notice_room, u64::MAX)
+            .unwrap()
+            .map(|r| r.unwrap().1)
+            .find(|pdu| pdu.kind.to_string() == "m.room.message")
+            .unwrap();
+        let content: serde_json::Value = serde_json::from_str(notice.content.get()).unwrap();
+        assert_eq!(content["msgtype"], "m.notice");
+        assert_eq!(content["body"], "Welcome to example.org, alice!");
+
+        // Guests are skipped as configured
+        assert_eq!(db.users.server_notice_room(&guest).unwrap(), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn failed_admin_setup_keeps_the_registration() {
+        use super::register_route;
+        use crate::{
+            database::{
+                admin::{find_admin_room, make_user_admin},
+                test_config, DatabaseGuard,
+            },
+            Database, Ruma,
+        };
+        use ruma::{
+            api::{client::account::register, IncomingRequest},
+            room_alias_id,
+        };
+        use std::sync::Arc;
+
+        let mut config = test_config("admin-bootstrap");
+        config.allow_registration = true;
+        config.enable_admin_room = true;
+        let db = Database::load_or_create(&config).await.unwrap();
+
+        // Without its alias the admin room can't be found, so granting admin privileges fails
+        let admins = room_alias_id!("#admins:example.org");
+        let admin_room = {
+            let db = db.read().await;
+            let admin_room = find_admin_room(&db).unwrap().unwrap();
+            db.rooms.set_alias(admins, None, &db.globals).unwrap();
+            admin_room
+        };
+
+        let body = json!({
+            "username": "alice",
+            "password": "correct horse battery staple",
+            "auth": { "type": "m.login.dummy" },
+        });
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/_matrix/client/r0/register")
+            .body(serde_json::to_vec(&body).unwrap())
+            .unwrap();
+        let alice = register_route(
+            DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+            Ruma {
+                body: register::v3::IncomingRequest::try_from_http_request::<_, String>(
+                    request,
+                    &[],
+                )
+                .unwrap(),
+                sender_user: None,
+                sender_device: None,
+                sender_servername: None,
+                json_body: Some(serde_json::from_value(body).unwrap()),
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .unwrap()
+        .response
+        .user_id;
+
+        let db = db.read().await;
+        assert!(db.users.exists(&alice).unwrap());
+        assert!(!db.users.is_deactivated(&alice).unwrap());
+        assert!(!db.users.is_admin(&alice, &db.rooms, &db.globals).unwrap());
+
+        // bootstrap-admin retries the same steps once the admin room is back
+        db.rooms
+            .set_alias(admins, Some(&admin_room), &db.globals)
+            .unwrap();
+        make_user_admin(&db, &alice, "alice".to_owned())
+            .await
+            .unwrap();
+        assert!(db.users.is_admin(&alice, &db.rooms, &db.globals).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn registration_token_validity_is_checked_through_the_route() {
+        use super::check_registration_token_validity_route;
+        use crate::{
+            database::{test_config, users::RegistrationTokenInfo, DatabaseGuard},
+            Database, Ruma,
+        };
+        use ruma::api::{client::account::check_registration_token_validity::v1, IncomingRequest};
+        use std::sync::Arc;
+
+        let mut config = test_config("registration-token-validity");
+        config.allow_registration = true;
+        let db = Database::load_or_create(&config).await.unwrap();
+        {
+            let db = db.read().await;
+            let info = RegistrationTokenInfo {
+                uses_allowed: Some(1),
+                completed: 0,
+                expiry_time: None,
+            };
+            db.users.set_registration_token("valid", &info).unwrap();
+            db.users
+                .set_registration_token(
+                    "exhausted",
+                    &RegistrationTokenInfo {
+                        completed: 1,
+                        ..info
+                    },
+                )
+                .unwrap();
+        }
+
+        for (token, valid) in [("valid", true), ("exhausted", false), ("unknown", false)] {
+            let request = http::Request::builder()
+                .uri(format!(
+                    "/_matrix/client/v1/register/m.login.registration_token/validity?token={}",
+                    token
+                ))
+                .body(Vec::<u8>::new())
+                .unwrap();
+            let body =
+                v1::IncomingRequest::try_from_http_request::<_, String>(request, &[]).unwrap();
+            let response = check_registration_token_validity_route(
+                DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+                Ruma {
+                    body,
+                    sender_user: None,
+                    sender_device: None,
+                    sender_servername: None,
+                    json_body: None,
+                    from_appservice: false,
+                    appservice_id: None,
+                    client_ip: None,
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.valid, valid, "{}", token);
+        }
+    }
 }
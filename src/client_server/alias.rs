@@ -1,4 +1,11 @@
-use crate::{database::DatabaseGuard, Database, Error, Result, Ruma};
+use std::sync::Arc;
+
+use crate::{
+    client_server::{may_change_canonical_alias, room_power_levels},
+    database::DatabaseGuard,
+    pdu::PduBuilder,
+    Database, Error, Result, Ruma,
+};
 use regex::Regex;
 use ruma::{
     api::{
@@ -9,16 +16,25 @@ use ruma::{
         },
         federation,
     },
-    RoomAliasId,
+    events::{
+        room::canonical_alias::RoomCanonicalAliasEventContent, RoomEventType, StateEventType,
+    },
+    RoomAliasId, RoomId, UserId,
 };
+use serde_json::value::to_raw_value;
+use tracing::warn;
 
 /// # `PUT /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Creates a new room alias on this server.
+///
+/// - The creator is remembered, they may delete the alias again
 pub async fn create_alias_route(
     db: DatabaseGuard,
     body: Ruma<create_alias::v3::IncomingRequest>,
 ) -> Result<create_alias::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     if body.room_alias.server_name() != db.globals.server_name() {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -26,12 +42,17 @@ pub async fn create_alias_route(
         ));
     }
 
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
     if db.rooms.id_from_alias(&body.room_alias)?.is_some() {
         return Err(Error::Conflict("Alias already exists."));
     }
 
     db.rooms
         .set_alias(&body.room_alias, Some(&body.room_id), &db.globals)?;
+    db.rooms.set_alias_creator(&body.room_alias, sender_user)?;
 
     db.flush()?;
 
@@ -42,12 +63,15 @@ pub async fn create_alias_route(
 ///
 /// Deletes a room alias from this server.
 ///
-/// - TODO: additional access control checks
-/// - TODO: Update canonical alias event
+/// - Only the creator of the alias, server admins and members who may change the canonical alias
+/// can delete it
+/// - The alias is also removed from the canonical alias event of the room
 pub async fn delete_alias_route(
     db: DatabaseGuard,
     body: Ruma<delete_alias::v3::IncomingRequest>,
 ) -> Result<delete_alias::v3::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
     if body.room_alias.server_name() != db.globals.server_name() {
         return Err(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -55,15 +79,113 @@ pub async fn delete_alias_route(
         ));
     }
 
+    let room_id = db
+        .rooms
+        .id_from_alias(&body.room_alias)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Alias does not exist.",
+        ))?;
+
+    let is_creator = db.rooms.alias_creator(&body.room_alias)?.as_ref() == Some(sender_user);
+    let may_change_alias = || -> Result<bool> {
+        Ok(db.rooms.is_joined(sender_user, &room_id)?
+            && may_change_canonical_alias(&room_power_levels(&db, &room_id)?, sender_user))
+    };
+
+    if !is_creator
+        && !db.users.is_admin(sender_user, &db.rooms, &db.globals)?
+        && !may_change_alias()?
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only the creator of the alias or room admins can delete it.",
+        ));
+    }
+
     db.rooms.set_alias(&body.room_alias, None, &db.globals)?;
 
-    // TODO: update alt_aliases?
+    if may_change_alias()? {
+        remove_from_canonical_alias(&db, sender_user, &room_id, &body.room_alias).await?;
+    } else {
+        warn!(
+            "{} can't change the canonical alias of {}, {} stays in it",
+            sender_user, room_id, body.room_alias
+        );
+    }
 
     db.flush()?;
 
     Ok(delete_alias::v3::Response::new())
 }
 
+/// Removes a deleted alias from the canonical alias event, if it is in there.
+async fn remove_from_canonical_alias(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    room_alias: &RoomAliasId,
+) -> Result<()> {
+    let mut content = match db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+        .map(|pdu| serde_json::from_str::<RoomCanonicalAliasEventContent>(pdu.content.get()))
+    {
+        Some(Ok(content)) => content,
+        Some(Err(_)) => {
+            return Err(Error::bad_database(
+                "Invalid canonical alias event in database.",
+            ))
+        }
+        None => return Ok(()),
+    };
+
+    if !remove_alias(&mut content, room_alias) {
+        return Ok(());
+    }
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomCanonicalAlias,
+            content: to_raw_value(&content).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some("".to_owned()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        &state_lock,
+    )?;
+
+    Ok(())
+}
+
+/// Removes the alias from the canonical alias content. Returns false if it wasn't in there.
+fn remove_alias(content: &mut RoomCanonicalAliasEventContent, room_alias: &RoomAliasId) -> bool {
+    let mut removed = false;
+
+    if content.alias.as_deref() == Some(room_alias) {
+        content.alias = None;
+        removed = true;
+    }
+
+    let alt_aliases = content.alt_aliases.len();
+    content.alt_aliases.retain(|alias| &**alias != room_alias);
+
+    removed || content.alt_aliases.len() != alt_aliases
+}
+
 /// # `GET /_matrix/client/r0/directory/room/{roomAlias}`
 ///
 /// Resolve an alias locally or over federation.
@@ -149,3 +271,28 @@ pub(crate) async fn get_alias_helper(
         vec![db.globals.server_name().to_owned()],
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::remove_alias;
+    use ruma::{events::room::canonical_alias::RoomCanonicalAliasEventContent, room_alias_id};
+
+    #[test]
+    fn deleted_alias_leaves_canonical_alias() {
+        let main = room_alias_id!("#main:example.org");
+        let alt = room_alias_id!("#alt:example.org");
+        let other = room_alias_id!("#other:example.org");
+
+        let mut content = RoomCanonicalAliasEventContent::new();
+        content.alias = Some(main.to_owned());
+        content.alt_aliases = vec![alt.to_owned(), main.to_owned()];
+
+        assert!(!remove_alias(&mut content, other));
+        assert!(remove_alias(&mut content, main));
+        assert_eq!(content.alias, None);
+        assert_eq!(content.alt_aliases, vec![alt.to_owned()]);
+
+        assert!(remove_alias(&mut content, alt));
+        assert!(content.alt_aliases.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{database::DatabaseGuard, Result, Ruma};
+use axum::{response::IntoResponse, Json};
 use ruma::api::client::discovery::get_capabilities::{
     self, Capabilities, RoomVersionStability, RoomVersionsCapability,
 };
@@ -30,6 +31,16 @@ pub async fn get_capabilities_route(
         default: db.globals.default_room_version(),
         available,
     };
+    capabilities
+        .set("m.password_policy", db.globals.password_policy().to_json())
+        .expect("password policy is valid json");
 
     Ok(get_capabilities::v3::Response { capabilities })
 }
+
+/// # `GET /_matrix/client/r0/password_policy`
+///
+/// Tells clients which rules new passwords have to follow.
+pub async fn get_password_policy_route(db: DatabaseGuard) -> impl IntoResponse {
+    Json(db.globals.password_policy().to_json())
+}
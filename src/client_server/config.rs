@@ -1,4 +1,4 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
+use crate::{database::DatabaseGuard, Database, Error, Result, Ruma};
 use ruma::{
     api::client::{
         config::{
@@ -9,21 +9,26 @@ use ruma::{
     },
     events::{AnyGlobalAccountDataEventContent, AnyRoomAccountDataEventContent},
     serde::Raw,
+    UserId,
 };
-use serde::Deserialize;
-use serde_json::{json, value::RawValue as RawJsonValue};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
+use std::time::Instant;
 
 /// # `PUT /_matrix/client/r0/user/{userId}/account_data/{type}`
 ///
 /// Sets some account data for the sender user.
+///
+/// - The content is stored exactly as sent, so encrypted secrets (e.g. `m.secret_storage.*` and
+/// `m.cross_signing.*`) round-trip unchanged
 pub async fn set_global_account_data_route(
     db: DatabaseGuard,
     body: Ruma<set_global_account_data::v3::IncomingRequest>,
 ) -> Result<set_global_account_data::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let data: serde_json::Value = serde_json::from_str(body.data.json().get())
-        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Data is invalid."))?;
+    check_account_data_rate(&db, sender_user)?;
+    check_account_data(body.data.json(), db.globals.max_account_data_size())?;
 
     let event_type = body.event_type.to_string();
 
@@ -31,10 +36,10 @@ pub async fn set_global_account_data_route(
         None,
         sender_user,
         event_type.clone().into(),
-        &json!({
-            "type": event_type,
-            "content": data,
-        }),
+        &AccountDataEvent {
+            event_type: &event_type,
+            content: body.data.json(),
+        },
         &db.globals,
     )?;
 
@@ -52,8 +57,8 @@ pub async fn set_room_account_data_route(
 ) -> Result<set_room_account_data::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let data: serde_json::Value = serde_json::from_str(body.data.json().get())
-        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Data is invalid."))?;
+    check_account_data_rate(&db, sender_user)?;
+    check_account_data(body.data.json(), db.globals.max_account_data_size())?;
 
     let event_type = body.event_type.to_string();
 
@@ -61,10 +66,10 @@ pub async fn set_room_account_data_route(
         Some(&body.room_id),
         sender_user,
         event_type.clone().into(),
-        &json!({
-            "type": event_type,
-            "content": data,
-        }),
+        &AccountDataEvent {
+            event_type: &event_type,
+            content: body.data.json(),
+        },
         &db.globals,
     )?;
 
@@ -119,6 +124,37 @@ pub async fn get_room_account_data_route(
     Ok(get_room_account_data::v3::Response { account_data })
 }
 
+/// Limits how often a user can write account data. Account data written by the server itself,
+/// e.g. push rules at registration, does not go through here.
+fn check_account_data_rate(db: &Database, sender_user: &UserId) -> Result<()> {
+    match &db.globals.account_data_rate_limiter {
+        Some(rate_limiter) => rate_limiter.check(Some(sender_user), None, Instant::now()),
+        None => Ok(()),
+    }
+}
+
+/// Checks that account data content is a JSON object of acceptable size.
+fn check_account_data(content: &RawJsonValue, max_size: usize) -> Result<()> {
+    if content.get().len() > max_size {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Account data is too large.",
+        ));
+    }
+
+    serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(content.get())
+        .map_err(|_| Error::BadRequest(ErrorKind::BadJson, "Data is invalid."))?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AccountDataEvent<'a> {
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    content: &'a RawJsonValue,
+}
+
 #[derive(Deserialize)]
 struct ExtractRoomEventContent {
     content: Raw<AnyRoomAccountDataEventContent>,
@@ -128,3 +164,147 @@ struct ExtractRoomEventContent {
 struct ExtractGlobalEventContent {
     content: Raw<AnyGlobalAccountDataEventContent>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_account_data, AccountDataEvent, ExtractGlobalEventContent};
+    use serde_json::value::RawValue as RawJsonValue;
+
+    #[test]
+    fn secret_storage_round_trips_unchanged() {
+        let default_key = r#"{"key":"abcdefg","z":1.10}"#;
+        let secret =
+            r#"{"encrypted":{"abcdefg":{"iv":"aXY=","mac":"bWFj","ciphertext":"Y2lwaGVy"}}}"#;
+
+        for content in [default_key, secret] {
+            let content = RawJsonValue::from_string(content.to_owned()).unwrap();
+            check_account_data(&content, 1024).unwrap();
+
+            let stored = serde_json::to_vec(&AccountDataEvent {
+                event_type: "m.secret_storage.default_key",
+                content: &content,
+            })
+            .unwrap();
+
+            let read: ExtractGlobalEventContent = serde_json::from_slice(&stored).unwrap();
+            assert_eq!(read.content.json().get(), content.get());
+        }
+    }
+
+    #[test]
+    fn oversized_account_data_is_rejected() {
+        let content =
+            RawJsonValue::from_string(format!(r#"{{"a":"{}"}}"#, "x".repeat(100))).unwrap();
+        assert!(check_account_data(&content, 50).is_err());
+
+        let content = RawJsonValue::from_string("[]".to_owned()).unwrap();
+        assert!(check_account_data(&content, 50).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn secret_storage_round_trips_through_the_routes() {
+        use super::{get_global_account_data_route, set_global_account_data_route};
+        use crate::{
+            database::{test_config, DatabaseGuard},
+            Database, Error, Ruma,
+        };
+        use ruma::{
+            api::{
+                client::{
+                    config::{get_global_account_data, set_global_account_data},
+                    error::ErrorKind,
+                },
+                IncomingRequest,
+            },
+            user_id, UserId,
+        };
+        use std::sync::Arc;
+
+        fn ruma<T>(body: T, user_id: &UserId) -> Ruma<T> {
+            Ruma {
+                body,
+                sender_user: Some(user_id.to_owned()),
+                sender_device: None,
+                sender_servername: None,
+                json_body: None,
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            }
+        }
+
+        let mut config = test_config("secret-storage");
+        config.max_account_data_size = 200;
+        let db = Database::load_or_create(&config).await.unwrap();
+        let alice = user_id!("@alice:example.org");
+
+        let set = |event_type: &str, content: &str| {
+            let request = http::Request::builder()
+                .method("PUT")
+                .uri(format!(
+                    "/_matrix/client/r0/user/%40alice%3Aexample.org/account_data/{}",
+                    event_type
+                ))
+                .body(content.as_bytes().to_vec())
+                .unwrap();
+            let body = set_global_account_data::v3::IncomingRequest::try_from_http_request(
+                request,
+                &["@alice:example.org", event_type],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            async move {
+                set_global_account_data_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    ruma(body, alice),
+                )
+                .await
+            }
+        };
+        let get = |event_type: &str| {
+            let request = http::Request::builder()
+                .uri(format!(
+                    "/_matrix/client/r0/user/%40alice%3Aexample.org/account_data/{}",
+                    event_type
+                ))
+                .body(Vec::<u8>::new())
+                .unwrap();
+            let body = get_global_account_data::v3::IncomingRequest::try_from_http_request(
+                request,
+                &["@alice:example.org", event_type],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            async move {
+                get_global_account_data_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    ruma(body, alice),
+                )
+                .await
+                .unwrap()
+                .account_data
+                .json()
+                .get()
+                .to_owned()
+            }
+        };
+
+        let default_key = r#"{"key":"abcdefg","z":1.10}"#;
+        let secret =
+            r#"{"encrypted":{"abcdefg":{"iv":"aXY=","mac":"bWFj","ciphertext":"Y2lwaGVy"}}}"#;
+        for (event_type, content) in [
+            ("m.secret_storage.default_key", default_key),
+            ("m.cross_signing.master", secret),
+        ] {
+            set(event_type, content).await.unwrap();
+            assert_eq!(get(event_type).await, content);
+        }
+
+        let oversized = format!(r#"{{"a":"{}"}}"#, "x".repeat(200));
+        assert!(matches!(
+            set("m.secret_storage.key.abcdefg", &oversized).await,
+            Err(Error::BadRequest(ErrorKind::TooLarge, _))
+        ));
+    }
+}
@@ -1,9 +1,9 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
+use crate::{database::DatabaseGuard, utils, Error, Result, Ruma};
 use ruma::{
     api::client::{context::get_context, error::ErrorKind, filter::LazyLoadOptions},
     events::StateEventType,
 };
-use std::{collections::HashSet, convert::TryFrom};
+use std::collections::HashSet;
 use tracing::error;
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/context`
@@ -12,6 +12,9 @@ use tracing::error;
 ///
 /// - Only works if the user is joined (TODO: always allow, but only show events if the user was
 /// joined, depending on history_visibility)
+/// - Guests additionally need the room to allow guest access or be world readable
+/// - The limit is capped at the configured `max_pagination_limit`
+/// - Relations are bundled with the events
 pub async fn get_context_route(
     db: DatabaseGuard,
     body: Ruma<get_context::v3::IncomingRequest>,
@@ -55,6 +58,13 @@ pub async fn get_context_route(
         ));
     }
 
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(&room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
+        ));
+    }
+
     if !db.rooms.lazy_load_was_sent_before(
         sender_user,
         sender_device,
@@ -65,17 +75,14 @@ pub async fn get_context_route(
         lazy_loaded.insert(base_event.sender.as_str().to_owned());
     }
 
-    let base_event = base_event.to_room_event();
+    let base_event = db.rooms.bundle_relations(base_event)?.to_room_event();
+
+    let limit = utils::clamp_limit(body.limit.into(), db.globals.max_pagination_limit());
 
     let events_before: Vec<_> = db
         .rooms
         .pdus_until(sender_user, &room_id, base_token)?
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
+        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect();
 
@@ -98,18 +105,17 @@ pub async fn get_context_route(
 
     let events_before: Vec<_> = events_before
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
+        .map(|(_, pdu)| {
+            db.rooms
+                .bundle_relations(pdu)
+                .map(|pdu| pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
 
     let events_after: Vec<_> = db
         .rooms
         .pdus_after(sender_user, &room_id, base_token)?
-        .take(
-            u32::try_from(body.limit).map_err(|_| {
-                Error::BadRequest(ErrorKind::InvalidParam, "Limit value is invalid.")
-            })? as usize
-                / 2,
-        )
+        .take(limit / 2)
         .filter_map(|r| r.ok()) // Remove buggy events
         .collect();
 
@@ -146,8 +152,12 @@ pub async fn get_context_route(
 
     let events_after: Vec<_> = events_after
         .into_iter()
-        .map(|(_, pdu)| pdu.to_room_event())
-        .collect();
+        .map(|(_, pdu)| {
+            db.rooms
+                .bundle_relations(pdu)
+                .map(|pdu| pdu.to_room_event())
+        })
+        .collect::<Result<_>>()?;
 
     let mut state = Vec::new();
 
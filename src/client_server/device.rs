@@ -71,7 +71,7 @@ pub async fn update_device_route(
 /// Deletes the given device.
 ///
 /// - Requires UIAA to verify user password
-/// - Invalidates access token
+/// - Invalidates access token, the client gets a soft logout unless it deleted itself
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
@@ -100,6 +100,7 @@ pub async fn delete_device_route(
             auth,
             &uiaainfo,
             &db.users,
+            &db.threepid,
             &db.globals,
         )?;
         if !worked {
@@ -115,7 +116,11 @@ pub async fn delete_device_route(
         return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
     }
 
-    db.users.remove_device(sender_user, &body.device_id)?;
+    db.users.remove_device(
+        sender_user,
+        &body.device_id,
+        &body.device_id != sender_device,
+    )?;
 
     db.flush()?;
 
@@ -129,7 +134,7 @@ pub async fn delete_device_route(
 /// - Requires UIAA to verify user password
 ///
 /// For each device:
-/// - Invalidates access token
+/// - Invalidates access token, the client gets a soft logout unless it deleted itself
 /// - Deletes device metadata (device id, device display name, last seen ip, last seen ts)
 /// - Forgets to-device events
 /// - Triggers device list updates
@@ -158,6 +163,7 @@ pub async fn delete_devices_route(
             auth,
             &uiaainfo,
             &db.users,
+            &db.threepid,
             &db.globals,
         )?;
         if !worked {
@@ -174,7 +180,8 @@ pub async fn delete_devices_route(
     }
 
     for device_id in &body.devices {
-        db.users.remove_device(sender_user, device_id)?
+        db.users
+            .remove_device(sender_user, device_id, device_id != sender_device)?
     }
 
     db.flush()?;
@@ -1,4 +1,6 @@
-use crate::{database::DatabaseGuard, Database, Error, Result, Ruma};
+use crate::{
+    client_server::may_send_state_event, database::DatabaseGuard, Database, Error, Result, Ruma,
+};
 use ruma::{
     api::{
         client::{
@@ -19,15 +21,17 @@ use ruma::{
         room::{
             avatar::RoomAvatarEventContent,
             canonical_alias::RoomCanonicalAliasEventContent,
+            create::RoomCreateEventContent,
             guest_access::{GuestAccess, RoomGuestAccessEventContent},
             history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
             join_rules::{JoinRule, RoomJoinRulesEventContent},
             name::RoomNameEventContent,
+            power_levels::RoomPowerLevelsEventContent,
             topic::RoomTopicEventContent,
         },
-        StateEventType,
+        RoomEventType, StateEventType,
     },
-    ServerName, UInt,
+    RoomId, ServerName, UInt, UserId,
 };
 use tracing::{info, warn};
 
@@ -36,6 +40,8 @@ use tracing::{info, warn};
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - The limit is capped at the configured `max_pagination_limit`
+/// - Published rooms that can't be joined or read anymore are left out
 pub async fn get_public_rooms_filtered_route(
     db: DatabaseGuard,
     body: Ruma<get_public_rooms_filtered::v3::IncomingRequest>,
@@ -47,6 +53,7 @@ pub async fn get_public_rooms_filtered_route(
         body.since.as_deref(),
         &body.filter,
         &body.room_network,
+        false,
     )
     .await
 }
@@ -56,6 +63,8 @@ pub async fn get_public_rooms_filtered_route(
 /// Lists the public rooms on this server.
 ///
 /// - Rooms are ordered by the number of joined members
+/// - The limit is capped at the configured `max_pagination_limit`
+/// - Published rooms that can't be joined or read anymore are left out
 pub async fn get_public_rooms_route(
     db: DatabaseGuard,
     body: Ruma<get_public_rooms::v3::IncomingRequest>,
@@ -67,6 +76,7 @@ pub async fn get_public_rooms_route(
         body.since.as_deref(),
         &IncomingFilter::default(),
         &IncomingRoomNetwork::Matrix,
+        false,
     )
     .await?;
 
@@ -82,15 +92,41 @@ pub async fn get_public_rooms_route(
 ///
 /// Sets the visibility of a given room in the room directory.
 ///
-/// - TODO: Access control checks
+/// - Only server admins and members who may change the canonical alias can change it
+/// - Only rooms anyone can join, knock on or read can be published
 pub async fn set_room_visibility_route(
     db: DatabaseGuard,
     body: Ruma<set_room_visibility::v3::IncomingRequest>,
 ) -> Result<set_room_visibility::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
+    if !db.users.is_admin(sender_user, &db.rooms, &db.globals)? {
+        let power_levels = room_power_levels(&db, &body.room_id)?;
+
+        if !db.rooms.is_joined(sender_user, &body.room_id)?
+            || !may_change_canonical_alias(&power_levels, sender_user)
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "You are not allowed to change the visibility of this room.",
+            ));
+        }
+    }
+
     match &body.visibility {
         room::Visibility::Public => {
+            if !is_listable(&db, &body.room_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Only rooms with a public join rule or world readable history can be \
+                     published.",
+                ));
+            }
+
             db.rooms.set_public(&body.room_id, true)?;
             info!("{} made {} public", sender_user, body.room_id);
         }
@@ -131,6 +167,7 @@ pub(crate) async fn get_public_rooms_filtered_helper(
     since: Option<&str>,
     filter: &IncomingFilter,
     _network: &IncomingRoomNetwork,
+    from_federation: bool,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
     if let Some(other_server) = server.filter(|server| *server != db.globals.server_name().as_str())
     {
@@ -158,7 +195,9 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         });
     }
 
-    let limit = limit.map_or(10, u64::from);
+    let limit = limit
+        .map_or(10, u64::from)
+        .min(db.globals.max_pagination_limit() as u64);
     let mut num_since = 0_u64;
 
     if let Some(s) = &since {
@@ -184,12 +223,26 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         }
     }
 
-    let mut all_rooms: Vec<_> = db
-        .rooms
-        .public_rooms()
-        .map(|room_id| {
-            let room_id = room_id?;
+    let mut room_ids = Vec::new();
+    for room_id in db.rooms.public_rooms() {
+        let room_id = room_id?;
 
+        // Rooms can stop being listable after they were published
+        if !is_listable(db, &room_id)? {
+            continue;
+        }
+
+        // Other servers can't join rooms that don't federate
+        if from_federation && !federates(db, &room_id)? {
+            continue;
+        }
+
+        room_ids.push(room_id);
+    }
+
+    let mut all_rooms: Vec<_> = room_ids
+        .into_iter()
+        .map(|room_id| {
             let chunk = PublicRoomsChunk {
                 canonical_alias: db
                     .rooms
@@ -272,19 +325,14 @@ pub(crate) async fn get_public_rooms_filtered_helper(
                 join_rule: db
                     .rooms
                     .room_state_get(&room_id, &StateEventType::RoomJoinRules, "")?
-                    .map(|s| {
-                        serde_json::from_str(s.content.get())
-                            .map(|c: RoomJoinRulesEventContent| match c.join_rule {
-                                JoinRule::Public => Some(PublicRoomJoinRule::Public),
-                                JoinRule::Knock => Some(PublicRoomJoinRule::Knock),
-                                _ => None,
-                            })
-                            .map_err(|_| {
-                                Error::bad_database("Invalid room join rule event in database.")
-                            })
+                    .and_then(|s| {
+                        // World readable rooms can have any join rule
+                        serde_json::from_str::<serde_json::Value>(s.content.get())
+                            .ok()?
+                            .get("join_rule")?
+                            .as_str()
+                            .map(|join_rule| PublicRoomJoinRule::from(join_rule.to_owned()))
                     })
-                    .transpose()?
-                    .flatten()
                     .ok_or(Error::bad_database(
                         "Invalid room join rule event in database.",
                     ))?,
@@ -355,3 +403,85 @@ pub(crate) async fn get_public_rooms_filtered_helper(
         total_room_count_estimate: Some(total_room_count_estimate),
     })
 }
+
+/// The current power levels of the room, or the defaults if it has none.
+pub(crate) fn room_power_levels(
+    db: &Database,
+    room_id: &RoomId,
+) -> Result<RoomPowerLevelsEventContent> {
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_str::<RoomPowerLevelsEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in db."))
+        })
+        .transpose()?
+        .unwrap_or_default())
+}
+
+/// Whether the user's power level is enough to change the canonical alias of the room. Publishing
+/// the room or deleting its aliases needs the same level.
+pub(crate) fn may_change_canonical_alias(
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+) -> bool {
+    may_send_state_event(power_levels, user_id, &RoomEventType::RoomCanonicalAlias)
+}
+
+/// Only rooms anyone can join, knock on or read can be listed in the room directory.
+fn is_listable(db: &Database, room_id: &RoomId) -> Result<bool> {
+    let joinable = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+        .and_then(|s| serde_json::from_str::<RoomJoinRulesEventContent>(s.content.get()).ok())
+        .map_or(false, |c| {
+            matches!(c.join_rule, JoinRule::Public | JoinRule::Knock)
+        });
+
+    let world_readable = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?
+        .and_then(|s| {
+            serde_json::from_str::<RoomHistoryVisibilityEventContent>(s.content.get()).ok()
+        })
+        .map_or(false, |c| {
+            c.history_visibility == HistoryVisibility::WorldReadable
+        });
+
+    Ok(joinable || world_readable)
+}
+
+/// Whether users of other servers can join the room, see `m.federate` in the create event.
+fn federates(db: &Database, room_id: &RoomId) -> Result<bool> {
+    Ok(db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomCreate, "")?
+        .and_then(|s| serde_json::from_str::<RoomCreateEventContent>(s.content.get()).ok())
+        .map_or(true, |c| c.federate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::may_change_canonical_alias;
+    use ruma::{
+        events::{room::power_levels::RoomPowerLevelsEventContent, RoomEventType},
+        user_id,
+    };
+
+    #[test]
+    fn publishing_needs_canonical_alias_power_level() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(alice.to_owned(), 100.into());
+        assert!(may_change_canonical_alias(&power_levels, alice));
+        assert!(!may_change_canonical_alias(&power_levels, bob));
+
+        power_levels
+            .events
+            .insert(RoomEventType::RoomCanonicalAlias, 0.into());
+        assert!(may_change_canonical_alias(&power_levels, bob));
+    }
+}
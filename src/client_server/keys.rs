@@ -1,6 +1,6 @@
 use super::SESSION_ID_LENGTH;
 use crate::{database::DatabaseGuard, utils, Database, Error, Result, Ruma};
-use futures_util::{stream::FuturesUnordered, StreamExt};
+use futures_util::{stream, StreamExt};
 use ruma::{
     api::{
         client::{
@@ -13,18 +13,24 @@ use ruma::{
         },
         federation,
     },
+    encryption::DeviceKeys,
     serde::Raw,
     DeviceId, DeviceKeyAlgorithm, UserId,
 };
 use serde_json::json;
 use std::collections::{BTreeMap, HashMap, HashSet};
 
+/// How many servers are asked for keys at the same time.
+const MAX_CONCURRENT_KEY_QUERIES: usize = 20;
+
 /// # `POST /_matrix/client/r0/keys/upload`
 ///
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
-/// - If there are no device keys yet: Adds device keys (TODO: merge with existing keys?)
+/// - Adds or replaces the device keys, keeping signatures from the previous upload
+/// - Rejects device keys that change the device's ed25519 signing key
+/// - Marks the user's device list as changed, which the sending queue announces to other servers
 pub async fn upload_keys_route(
     db: DatabaseGuard,
     body: Ruma<upload_keys::v3::Request>,
@@ -38,17 +44,14 @@ pub async fn upload_keys_route(
     }
 
     if let Some(device_keys) = &body.device_keys {
-        // TODO: merge this and the existing event?
-        // This check is needed to assure that signatures are kept
-        if db
-            .users
-            .get_device_keys(sender_user, sender_device)?
-            .is_none()
+        let existing = db.users.get_device_keys(sender_user, sender_device)?;
+        if let Some(device_keys) =
+            replacement_device_keys(existing.as_ref(), device_keys, sender_device)?
         {
             db.users.add_device_keys(
                 sender_user,
                 sender_device,
-                device_keys,
+                &device_keys,
                 &db.rooms,
                 &db.globals,
             )?;
@@ -62,6 +65,56 @@ pub async fn upload_keys_route(
     })
 }
 
+/// Decides what to store when a device uploads `new` device keys.
+///
+/// Returns `None` if the upload only repeats the stored keys, so that signatures added to them
+/// later (e.g. by cross-signing) are kept. Changing the device's ed25519 key is rejected, because
+/// every signature made with the old key would silently become invalid.
+fn replacement_device_keys(
+    existing: Option<&Raw<DeviceKeys>>,
+    new: &Raw<DeviceKeys>,
+    device_id: &DeviceId,
+) -> Result<Option<Raw<DeviceKeys>>> {
+    let existing = match existing {
+        Some(existing) => existing,
+        None => return Ok(Some(new.clone())),
+    };
+
+    let mut old_value: serde_json::Value = serde_json::from_str(existing.json().get())
+        .map_err(|_| Error::bad_database("DeviceKeys in db are invalid."))?;
+    let mut new_value: serde_json::Value = serde_json::from_str(new.json().get())
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid device keys."))?;
+
+    let signing_key_id = format!("ed25519:{}", device_id);
+    let old_signing_key = old_value
+        .get("keys")
+        .and_then(|keys| keys.get(&signing_key_id));
+    if old_signing_key.is_some()
+        && old_signing_key
+            != new_value
+                .get("keys")
+                .and_then(|keys| keys.get(&signing_key_id))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Device keys may not change the signing key of an existing device, log in again to get a new device.",
+        ));
+    }
+
+    for value in [&mut old_value, &mut new_value] {
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signatures");
+            object.remove("unsigned");
+        }
+    }
+
+    if old_value == new_value {
+        Ok(None)
+    } else {
+        Ok(Some(new.clone()))
+    }
+}
+
 /// # `POST /_matrix/client/r0/keys/query`
 ///
 /// Get end-to-end encryption keys for the given users.
@@ -130,6 +183,7 @@ pub async fn upload_signing_keys_route(
             auth,
             &uiaainfo,
             &db.users,
+            &db.threepid,
             &db.globals,
         )?;
         if !worked {
@@ -286,11 +340,28 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     let mut device_keys = BTreeMap::new();
 
     let mut get_over_federation = HashMap::new();
+    let mut keychanges = HashMap::new();
 
     for (user_id, device_ids) in device_keys_input {
         let user_id: &UserId = &**user_id;
 
         if user_id.server_name() != db.globals.server_name() {
+            // Only queries for all devices are cached
+            if device_ids.is_empty() {
+                if let Some(cached) = db.users.cached_remote_keys(user_id)? {
+                    device_keys.insert(user_id.to_owned(), cached.device_keys);
+                    if let Some(master_key) = cached.master_key {
+                        master_keys.insert(user_id.to_owned(), master_key);
+                    }
+                    if let Some(self_signing_key) = cached.self_signing_key {
+                        self_signing_keys.insert(user_id.to_owned(), self_signing_key);
+                    }
+                    continue;
+                }
+
+                keychanges.insert(user_id, db.users.last_keychange(user_id)?);
+            }
+
             get_over_federation
                 .entry(user_id.server_name())
                 .or_insert_with(Vec::new)
@@ -353,8 +424,7 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
 
     let mut failures = BTreeMap::new();
 
-    let mut futures: FuturesUnordered<_> = get_over_federation
-        .into_iter()
+    let mut futures = stream::iter(get_over_federation)
         .map(|(server, vec)| async move {
             let mut device_keys_input_fed = BTreeMap::new();
             for (user_id, keys) in vec {
@@ -373,11 +443,23 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
                     .await,
             )
         })
-        .collect();
+        .buffer_unordered(MAX_CONCURRENT_KEY_QUERIES);
 
     while let Some((server, response)) = futures.next().await {
         match response {
             Ok(response) => {
+                for (user_id, user_device_keys) in &response.device_keys {
+                    if let Some(keychange) = keychanges.get(&**user_id) {
+                        db.users.cache_remote_keys(
+                            user_id,
+                            *keychange,
+                            user_device_keys.clone(),
+                            response.master_keys.get(user_id).cloned(),
+                            response.self_signing_keys.get(user_id).cloned(),
+                        );
+                    }
+                }
+
                 master_keys.extend(response.master_keys);
                 self_signing_keys.extend(response.self_signing_keys);
                 device_keys.extend(response.device_keys);
@@ -397,8 +479,37 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool>(
     })
 }
 
+/// Fetches all keys of a remote user with `/user/keys/query` and caches them, e.g. after device
+/// list updates of the user were missed.
+pub(crate) async fn resync_remote_keys(db: &Database, user_id: &UserId) -> Result<()> {
+    let keychange = db.users.last_keychange(user_id)?;
+
+    let mut response = db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            user_id.server_name(),
+            federation::keys::get_keys::v1::Request {
+                device_keys: BTreeMap::from([(user_id.to_owned(), Vec::new())]),
+            },
+        )
+        .await?;
+
+    if let Some(device_keys) = response.device_keys.remove(user_id) {
+        db.users.cache_remote_keys(
+            user_id,
+            keychange,
+            device_keys,
+            response.master_keys.remove(user_id),
+            response.self_signing_keys.remove(user_id),
+        );
+    }
+
+    Ok(())
+}
+
 fn add_unsigned_device_display_name(
-    keys: &mut Raw<ruma::encryption::DeviceKeys>,
+    keys: &mut Raw<DeviceKeys>,
     metadata: ruma::api::client::device::Device,
 ) -> serde_json::Result<()> {
     if let Some(display_name) = metadata.display_name {
@@ -475,3 +586,200 @@ pub(crate) async fn claim_keys_helper(
         one_time_keys,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::replacement_device_keys;
+    use ruma::{device_id, encryption::DeviceKeys, serde::Raw};
+    use serde_json::{json, value::to_raw_value, Value};
+
+    fn device_keys(value: Value) -> Raw<DeviceKeys> {
+        Raw::from_json(to_raw_value(&value).unwrap())
+    }
+
+    fn keys(curve25519: &str, signatures: Value) -> Raw<DeviceKeys> {
+        device_keys(json!({
+            "user_id": "@alice:example.org",
+            "device_id": "ABCDEF",
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"],
+            "keys": {
+                "curve25519:ABCDEF": curve25519,
+                "ed25519:ABCDEF": "ed25519key",
+            },
+            "signatures": signatures,
+        }))
+    }
+
+    #[test]
+    fn first_upload_is_stored() {
+        let new = keys("curve1", json!({}));
+        assert!(replacement_device_keys(None, &new, device_id!("ABCDEF"))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn reupload_with_new_keys_replaces_stored_keys() {
+        let old = keys("curve1", json!({}));
+        let new = keys("curve2", json!({}));
+        let stored = replacement_device_keys(Some(&old), &new, device_id!("ABCDEF"))
+            .unwrap()
+            .expect("changed keys must be stored and marked as changed");
+        assert_eq!(stored.json().get(), new.json().get());
+    }
+
+    #[test]
+    fn identical_reupload_keeps_signatures() {
+        let old = keys(
+            "curve1",
+            json!({ "@alice:example.org": { "ed25519:master": "sig" } }),
+        );
+        let new = keys("curve1", json!({}));
+        assert!(
+            replacement_device_keys(Some(&old), &new, device_id!("ABCDEF"))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn changed_signing_key_is_rejected() {
+        let old = keys("curve1", json!({}));
+        let new = device_keys(json!({
+            "user_id": "@alice:example.org",
+            "device_id": "ABCDEF",
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": {
+                "curve25519:ABCDEF": "curve1",
+                "ed25519:ABCDEF": "otherkey",
+            },
+            "signatures": {},
+        }));
+        assert!(replacement_device_keys(Some(&old), &new, device_id!("ABCDEF")).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn reuploaded_device_keys_mark_the_device_list_as_changed() {
+        use super::upload_keys_route;
+        use crate::{database::DatabaseGuard, Error, Ruma};
+        use ruma::{
+            api::{
+                client::{error::ErrorKind, keys::upload_keys},
+                IncomingRequest,
+            },
+            user_id,
+        };
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("keys-reupload").await;
+        let alice = user_id!("@alice:example.org");
+        {
+            let db = db.read().await;
+            db.users.create(alice, None, &db.globals).unwrap();
+            db.users
+                .create_device(alice, device_id!("ABCDEF"), "token", None)
+                .unwrap();
+        }
+
+        let upload = |device_keys: Raw<DeviceKeys>| {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("/_matrix/client/r0/keys/upload")
+                .body(serde_json::to_vec(&json!({ "device_keys": device_keys })).unwrap())
+                .unwrap();
+            let body =
+                upload_keys::v3::Request::try_from_http_request::<_, String>(request, &[]).unwrap();
+            let db = Arc::clone(&db);
+            async move {
+                upload_keys_route(
+                    DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(alice.to_owned()),
+                        sender_device: Some(device_id!("ABCDEF").to_owned()),
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await?;
+                Ok::<_, Error>(db.read().await.users.last_keychange(alice).unwrap())
+            }
+        };
+
+        let first = upload(keys("curve1", json!({}))).await.unwrap();
+        assert!(first.is_some());
+
+        // Repeating the upload changes nothing
+        assert_eq!(upload(keys("curve1", json!({}))).await.unwrap(), first);
+
+        // After a reinstall the new keys replace the old ones
+        let second = upload(keys("curve2", json!({}))).await.unwrap();
+        assert!(second > first);
+        let stored = db
+            .read()
+            .await
+            .users
+            .get_device_keys(alice, device_id!("ABCDEF"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.json().get(), keys("curve2", json!({})).json().get());
+
+        let other_signing_key = device_keys(json!({
+            "user_id": "@alice:example.org",
+            "device_id": "ABCDEF",
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": {
+                "curve25519:ABCDEF": "curve2",
+                "ed25519:ABCDEF": "otherkey",
+            },
+            "signatures": {},
+        }));
+        assert!(matches!(
+            upload(other_signing_key).await,
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn remote_keys_are_cached_until_the_device_list_changes() {
+        use super::get_keys_helper;
+        use ruma::user_id;
+        use std::collections::BTreeMap;
+
+        let db = crate::database::test_database("keys-remote-cache").await;
+        let db = db.read().await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:remote.example.org");
+        let query = BTreeMap::from([(bob.to_owned(), Vec::new())]);
+
+        db.users.cache_remote_keys(
+            bob,
+            db.users.last_keychange(bob).unwrap(),
+            BTreeMap::from([(device_id!("ABCDEF").to_owned(), keys("curve1", json!({})))]),
+            None,
+            None,
+        );
+
+        // Federation is disabled, so only the cache can answer
+        let response = get_keys_helper(Some(alice), &query, |_| true, &db)
+            .await
+            .unwrap();
+        assert!(response.failures.is_empty());
+        assert!(response.device_keys[bob].contains_key(device_id!("ABCDEF")));
+
+        db.users
+            .mark_device_key_update(bob, &db.rooms, &db.globals)
+            .unwrap();
+
+        let response = get_keys_helper(Some(alice), &query, |_| true, &db)
+            .await
+            .unwrap();
+        assert!(!response.device_keys.contains_key(bob));
+        assert!(response.failures.contains_key("remote.example.org"));
+    }
+}
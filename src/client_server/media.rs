@@ -1,17 +1,78 @@
 use crate::{
-    database::{media::FileMeta, DatabaseGuard},
-    utils, Error, Result, Ruma,
+    database::{
+        media::{load_image, FileMeta},
+        DatabaseGuard,
+    },
+    ruma_wrapper::read_limited_body,
+    utils, Database, Error, Result, Ruma,
+};
+use axum::{
+    extract::{Path, Query, RawBody},
+    http::HeaderMap,
+    response::IntoResponse,
+    Json,
 };
+use image::GenericImageView;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{header, Url};
+use ring::digest;
 use ruma::api::client::{
     error::ErrorKind,
     media::{
         create_content, get_content, get_content_as_filename, get_content_thumbnail,
-        get_media_config,
+        get_media_config, get_media_preview,
     },
+    UserId,
+};
+use serde::Deserialize;
+use serde_json::{json, value::to_raw_value};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
 };
 
 const MXC_LENGTH: usize = 32;
 
+/// Unstable response field with the blurhash of an uploaded image (MSC2448).
+const BLURHASH_FIELD: &str = "xyz.amorgan.blurhash";
+
+/// How many redirects are followed when fetching a URL preview.
+const MAX_PREVIEW_REDIRECTS: usize = 5;
+
+/// Larger preview images are scaled down to fit into this size.
+const PREVIEW_IMAGE_WIDTH: u32 = 800;
+const PREVIEW_IMAGE_HEIGHT: u32 = 600;
+
+static META_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<meta\s([^>]*)>").expect("regex is valid"));
+static ATTRIBUTE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).expect("regex is valid")
+});
+static TITLE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("regex is valid"));
+
+/// Content types browsers would render or execute when the media is opened directly.
+const DANGEROUS_CONTENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/javascript",
+    "application/javascript",
+    "application/ecmascript",
+];
+
+/// File signatures of the content types uploads are checked against.
+const MAGIC_BYTES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+];
+
 /// # `GET /_matrix/media/r0/config`
 ///
 /// Returns max upload size.
@@ -20,7 +81,7 @@ pub async fn get_media_config_route(
     _body: Ruma<get_media_config::v3::Request>,
 ) -> Result<get_media_config::v3::Response> {
     Ok(get_media_config::v3::Response {
-        upload_size: db.globals.max_request_size().into(),
+        upload_size: db.globals.max_upload_size().into(),
     })
 }
 
@@ -30,10 +91,23 @@ pub async fn get_media_config_route(
 ///
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
+/// - The uploader is remembered, so admins can delete the media together with the account
+/// - Files larger than `max_upload_size` are rejected
+/// - The declared content type has to match the file, types browsers would execute are rejected
+/// - If `allow_blurhash` is set, the blurhash of images is computed and returned
 pub async fn create_content_route(
     db: DatabaseGuard,
     body: Ruma<create_content::v3::IncomingRequest>,
 ) -> Result<create_content::v3::Response> {
+    if body.file.len() > db.globals.max_upload_size() as usize {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "File is larger than the upload limit.",
+        ));
+    }
+
+    check_upload_content_type(body.content_type.as_deref(), &body.file)?;
+
     let mxc = format!(
         "mxc://{}/{}",
         db.globals.server_name(),
@@ -54,14 +128,169 @@ pub async fn create_content_route(
         )
         .await?;
 
+    db.media.set_uploader(
+        body.sender_user.as_ref().expect("user is authenticated"),
+        &mxc,
+    )?;
+
+    let blurhash = if db.globals.allow_blurhash() {
+        db.media.create_blurhash(&mxc, body.file.clone()).await?
+    } else {
+        None
+    };
+
     db.flush()?;
 
     Ok(create_content::v3::Response {
         content_uri: mxc.try_into().expect("Invalid mxc:// URI"),
-        blurhash: None,
+        blurhash,
     })
 }
 
+#[derive(Deserialize)]
+pub struct AsyncUploadQuery {
+    access_token: Option<String>,
+    filename: Option<String>,
+}
+
+/// # `POST /_matrix/media/v1/create`
+///
+/// Reserves a media id whose content the user uploads later (MSC2246).
+///
+/// - The id expires if nothing was uploaded within `media_upload_timeout_secs`
+/// - Users can only have a limited number of ids waiting for their content
+pub async fn create_mxc_uri_route(
+    db: DatabaseGuard,
+    headers: HeaderMap,
+    Query(query): Query<AsyncUploadQuery>,
+) -> Result<impl IntoResponse> {
+    let sender_user = access_token_user(&db, &headers, query.access_token.as_deref())?;
+
+    let mxc = format!(
+        "mxc://{}/{}",
+        db.globals.server_name(),
+        utils::random_string(MXC_LENGTH)
+    );
+    let now = utils::millis_since_unix_epoch();
+    let expires_at = now.saturating_add(db.globals.media_upload_timeout().as_millis() as u64);
+
+    db.media.reserve(&mxc, &sender_user, expires_at, now)?;
+
+    db.flush()?;
+
+    Ok(Json(json!({
+        "content_uri": mxc,
+        "unused_expires_at": expires_at,
+    })))
+}
+
+/// # `PUT /_matrix/media/v3/upload/{serverName}/{mediaId}`
+///
+/// Uploads the content of a media id reserved with `/create` (MSC2246).
+///
+/// - Only the user who reserved the id can upload, and only once. Concurrent uploads to the same
+/// id are rejected
+/// - The same size and content type checks as for other uploads apply
+/// - If `allow_blurhash` is set, the blurhash of images is computed and returned
+pub async fn upload_reserved_content_route(
+    db: DatabaseGuard,
+    Path((server_name, media_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    Query(query): Query<AsyncUploadQuery>,
+    RawBody(body): RawBody,
+) -> Result<impl IntoResponse> {
+    let sender_user = access_token_user(&db, &headers, query.access_token.as_deref())?;
+
+    let mxc = format!("mxc://{}/{}", server_name, media_id);
+
+    // Holds off other uploads to the same media id until this one is done
+    let _upload =
+        match db
+            .media
+            .start_upload(&mxc, &sender_user, utils::millis_since_unix_epoch())?
+        {
+            Some(upload) => upload,
+            None if db.media.get(&db.globals, &mxc).await?.is_some() => {
+                return Err(Error::Conflict("Media has already been uploaded."))
+            }
+            None => {
+                return Err(Error::BadRequest(
+                    ErrorKind::NotFound,
+                    "Media id not found or expired.",
+                ))
+            }
+        };
+
+    let file = read_limited_body(&headers, body, db.globals.max_upload_size() as usize).await?;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok());
+
+    check_upload_content_type(content_type, &file)?;
+
+    db.media
+        .create(
+            mxc.clone(),
+            &db.globals,
+            &query
+                .filename
+                .as_ref()
+                .map(|filename| "inline; filename=".to_owned() + filename)
+                .as_deref(),
+            &content_type,
+            &file,
+        )
+        .await?;
+
+    db.media.set_uploader(&sender_user, &mxc)?;
+    db.media.finish_upload(&mxc)?;
+
+    let mut response = json!({});
+    if db.globals.allow_blurhash() {
+        if let Some(blurhash) = db.media.create_blurhash(&mxc, file).await? {
+            response[BLURHASH_FIELD] = blurhash.into();
+        }
+    }
+
+    db.flush()?;
+
+    Ok(Json(response))
+}
+
+/// Finds the user an access token belongs to, for routes ruma has no request type for yet.
+fn access_token_user(
+    db: &Database,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<Box<UserId>> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query_token)
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing access token.",
+        ))?;
+
+    db.users
+        .authenticate(token, false)
+        .map(|(user_id, _)| user_id)
+}
+
+/// The error for media that doesn't exist, unless its content is still being uploaded.
+fn missing_media_error(db: &Database, mxc: &str) -> Result<Error> {
+    if db
+        .media
+        .pending_upload(mxc, utils::millis_since_unix_epoch())?
+        .is_some()
+    {
+        Ok(Error::NotYetUploaded)
+    } else {
+        Ok(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+    }
+}
+
 pub async fn get_remote_content(
     db: &DatabaseGuard,
     mxc: &str,
@@ -99,6 +328,9 @@ pub async fn get_remote_content(
 /// Load media from our server or over federation.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Returns `M_NOT_YET_UPLOADED` while the content of a reserved media id is missing
+/// - Never redirects, even if the client allows it with `allow_redirect` (MSC3860). Media is only
+///   stored on the local filesystem, so there is no object storage a redirect could point to
 pub async fn get_content_route(
     db: DatabaseGuard,
     body: Ruma<get_content::v3::IncomingRequest>,
@@ -121,7 +353,7 @@ pub async fn get_content_route(
             get_remote_content(&db, &mxc, &body.server_name, &body.media_id).await?;
         Ok(remote_content_response)
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+        Err(missing_media_error(&db, &mxc)?)
     }
 }
 
@@ -130,6 +362,7 @@ pub async fn get_content_route(
 /// Load media from our server or over federation, permitting desired filename.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Returns `M_NOT_YET_UPLOADED` while the content of a reserved media id is missing
 pub async fn get_content_as_filename_route(
     db: DatabaseGuard,
     body: Ruma<get_content_as_filename::v3::IncomingRequest>,
@@ -157,7 +390,7 @@ pub async fn get_content_as_filename_route(
             file: remote_content_response.file,
         })
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+        Err(missing_media_error(&db, &mxc)?)
     }
 }
 
@@ -166,6 +399,7 @@ pub async fn get_content_as_filename_route(
 /// Load media thumbnail from our server or over federation.
 ///
 /// - Only allows federation if `allow_remote` is true
+/// - Returns `M_NOT_YET_UPLOADED` while the content of a reserved media id is missing
 pub async fn get_content_thumbnail_route(
     db: DatabaseGuard,
     body: Ruma<get_content_thumbnail::v3::IncomingRequest>,
@@ -220,6 +454,508 @@ pub async fn get_content_thumbnail_route(
 
         Ok(get_thumbnail_response)
     } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "Media not found."))
+        Err(missing_media_error(&db, &mxc)?)
+    }
+}
+
+/// # `GET /_matrix/media/r0/preview_url`
+///
+/// Returns the OpenGraph data of a web page, for link previews.
+///
+/// - Only works if `allow_url_preview` is enabled
+/// - Hosts that resolve to private, loopback or link-local addresses are refused, also after
+/// redirects
+/// - Downloads are limited to `url_preview_max_size`
+/// - The `og:image` is downloaded, scaled down and replaced by an mxc:// URI
+/// - Previews are cached in memory for an hour
+pub async fn get_media_preview_route(
+    db: DatabaseGuard,
+    body: Ruma<get_media_preview::v3::IncomingRequest>,
+) -> Result<get_media_preview::v3::Response> {
+    if !db.globals.allow_url_preview() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "URL previews are disabled on this server.",
+        ));
+    }
+
+    let url = Url::parse(&body.url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid URL."))?;
+
+    let preview = match db
+        .globals
+        .url_preview_cache
+        .get(url.as_str(), Instant::now())
+    {
+        Some(preview) => preview,
+        None => {
+            let preview = Arc::new(generate_preview(&db, url.clone()).await?);
+            db.globals.url_preview_cache.insert(
+                url.to_string(),
+                Arc::clone(&preview),
+                Instant::now(),
+            );
+            preview
+        }
+    };
+
+    Ok(get_media_preview::v3::Response {
+        data: Some(to_raw_value(&*preview).expect("preview is valid json")),
+    })
+}
+
+async fn generate_preview(db: &Database, url: Url) -> Result<serde_json::Value> {
+    let (url, content_type, body) = fetch_preview_url(db, url).await?;
+
+    let mut preview = serde_json::Map::new();
+
+    let image = if content_type.starts_with("image/") {
+        Some((url, content_type, body))
+    } else {
+        let mut tags = if content_type.starts_with("text/html") {
+            parse_opengraph(&String::from_utf8_lossy(&body))
+        } else {
+            BTreeMap::new()
+        };
+
+        let image_url = tags
+            .remove("og:image")
+            .and_then(|image_url| url.join(&image_url).ok());
+
+        preview.extend(tags.into_iter().map(|(key, value)| (key, json!(value))));
+
+        match image_url {
+            // A broken image shouldn't break the whole preview
+            Some(image_url) => fetch_preview_url(db, image_url)
+                .await
+                .ok()
+                .filter(|(_, content_type, _)| content_type.starts_with("image/")),
+            None => None,
+        }
+    };
+
+    if let Some((image_url, content_type, file)) = image {
+        // Decoding and scaling the image takes a while, it must not block the other requests
+        let scaled = tokio::task::spawn_blocking(move || scale_preview_image(file, content_type))
+            .await
+            .map_err(std::io::Error::from)??;
+
+        if let Some((file, content_type, width, height)) = scaled {
+            // The same image gets the same media id, refreshed previews don't store it again
+            let mxc = format!(
+                "mxc://{}/{}",
+                db.globals.server_name(),
+                preview_media_id(&image_url)
+            );
+            if !db.media.exists(&mxc)? {
+                db.media
+                    .create(
+                        mxc.clone(),
+                        &db.globals,
+                        &None,
+                        &Some(content_type.as_str()),
+                        &file,
+                    )
+                    .await?;
+            }
+
+            preview.insert("og:image".to_owned(), json!(mxc));
+            preview.insert("og:image:type".to_owned(), json!(content_type));
+            preview.insert("og:image:width".to_owned(), json!(width));
+            preview.insert("og:image:height".to_owned(), json!(height));
+            preview.insert("matrix:image:size".to_owned(), json!(file.len()));
+        }
+    }
+
+    Ok(serde_json::Value::Object(preview))
+}
+
+/// Scales a preview image down if it is too large. Returns the file, its content type, width and
+/// height, or `None` if the file isn't an image that can be decoded.
+fn scale_preview_image(
+    file: Vec<u8>,
+    content_type: String,
+) -> Result<Option<(Vec<u8>, String, u32, u32)>> {
+    let image = match load_image(&file) {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+
+    if image.width() > PREVIEW_IMAGE_WIDTH || image.height() > PREVIEW_IMAGE_HEIGHT {
+        let thumbnail = image.thumbnail(PREVIEW_IMAGE_WIDTH, PREVIEW_IMAGE_HEIGHT);
+        let mut bytes = Vec::new();
+        thumbnail.write_to(&mut bytes, image::ImageOutputFormat::Png)?;
+        Ok(Some((
+            bytes,
+            "image/png".to_owned(),
+            thumbnail.width(),
+            thumbnail.height(),
+        )))
+    } else {
+        Ok(Some((file, content_type, image.width(), image.height())))
+    }
+}
+
+/// The media id of a preview image, derived from the URL it was downloaded from.
+fn preview_media_id(image_url: &Url) -> String {
+    base64::encode_config(
+        digest::digest(&digest::SHA256, image_url.as_str().as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Downloads a URL for a preview. Returns the URL after redirects, the content type and the body.
+async fn fetch_preview_url(db: &Database, mut url: Url) -> Result<(Url, String, Vec<u8>)> {
+    let max_size = db.globals.url_preview_max_size() as usize;
+
+    for _ in 0..=MAX_PREVIEW_REDIRECTS {
+        let ip = check_preview_host(db, &url).await?;
+        let host = url.host_str().expect("checked host exists");
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let mut response = db
+            .globals
+            .pinned_client(host, SocketAddr::new(ip, port))?
+            .get(url.clone())
+            .send()
+            .await?;
+
+        if response.status().is_redirection() {
+            url = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+                .ok_or(Error::BadServerResponse("Invalid redirect in URL preview."))?;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(Error::BadServerResponse("Failed to fetch URL preview."));
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        let too_large =
+            || Error::BadRequest(ErrorKind::TooLarge, "Content is too large to preview.");
+
+        if response
+            .content_length()
+            .map_or(false, |length| length > max_size as u64)
+        {
+            return Err(too_large());
+        }
+
+        // The content length may be missing or wrong, so count while downloading
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > max_size {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        return Ok((url, content_type, body));
+    }
+
+    Err(Error::BadServerResponse(
+        "Too many redirects in URL preview.",
+    ))
+}
+
+/// Makes sure the URL can't be used to reach services in the server's own network. Returns the
+/// address that has to be connected to.
+async fn check_preview_host(db: &Database, url: &Url) -> Result<IpAddr> {
+    let forbidden = || Error::BadRequest(ErrorKind::Forbidden, "This URL can't be previewed.");
+
+    let host = url.host_str().ok_or_else(forbidden)?;
+
+    resolve_public_host(db, host).await?.ok_or_else(forbidden)
+}
+
+/// Resolves a host a user asked the server to send a request to. Returns `None` if any of its
+/// addresses is not public, so users can't reach services in the server's own network.
+pub(crate) async fn resolve_public_host(db: &Database, host: &str) -> Result<Option<IpAddr>> {
+    // IPv6 hosts are written in brackets
+    let ips: Vec<IpAddr> = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => vec![ip],
+        Err(_) => db
+            .globals
+            .dns_resolver()
+            .lookup_ip(host)
+            .await
+            .map_err(|_| Error::BadServerResponse("Failed to resolve host."))?
+            .iter()
+            .collect(),
+    };
+
+    if !ips.iter().copied().all(ip_is_public) {
+        return Ok(None);
+    }
+
+    Ok(ips.into_iter().next())
+}
+
+/// Whether the address is reachable on the public internet.
+fn ip_is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ipv4_is_public(ip),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if segments[..5] == [0; 5] && segments[5] == 0xffff {
+                // IPv4-mapped address
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return ipv4_is_public(Ipv4Addr::new(a, b, c, d));
+            }
+
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00 // Unique local
+                || segments[0] & 0xffc0 == 0xfe80 // Link local
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] // NAT64
+                || segments[0] == 0x2002) // 6to4
+        }
+    }
+}
+
+fn ipv4_is_public(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || a == 0
+        || a >= 240 // Reserved
+        || (a == 100 && b & 0xc0 == 64)) // Carrier-grade NAT
+}
+
+/// Collects the OpenGraph tags of an HTML page. Twitter card tags and the page title are used for
+/// missing OpenGraph tags.
+fn parse_opengraph(html: &str) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    let mut twitter_tags = BTreeMap::new();
+
+    for meta in META_REGEX.captures_iter(html) {
+        let attributes: BTreeMap<_, _> = ATTRIBUTE_REGEX
+            .captures_iter(&meta[1])
+            .filter_map(|attribute| {
+                let value = attribute.get(2).or_else(|| attribute.get(3))?;
+                Some((attribute[1].to_lowercase(), decode_entities(value.as_str())))
+            })
+            .collect();
+
+        let key = match attributes
+            .get("property")
+            .or_else(|| attributes.get("name"))
+        {
+            Some(key) => key.to_lowercase(),
+            None => continue,
+        };
+        let content = match attributes.get("content") {
+            Some(content) => content.clone(),
+            None => continue,
+        };
+
+        if key.starts_with("og:") {
+            tags.entry(key).or_insert(content);
+        } else if let Some(name) = key.strip_prefix("twitter:") {
+            twitter_tags
+                .entry(format!("og:{}", name))
+                .or_insert(content);
+        }
+    }
+
+    for (key, content) in twitter_tags {
+        if matches!(key.as_str(), "og:title" | "og:description" | "og:image") {
+            tags.entry(key).or_insert(content);
+        }
+    }
+
+    if !tags.contains_key("og:title") {
+        if let Some(title) = TITLE_REGEX.captures(html) {
+            tags.insert("og:title".to_owned(), decode_entities(title[1].trim()));
+        }
+    }
+
+    tags
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Guesses the content type of a file from its first bytes.
+fn sniff_content_type(file: &[u8]) -> Option<&'static str> {
+    if let Some((_, content_type)) = MAGIC_BYTES
+        .iter()
+        .find(|(magic, _)| file.starts_with(magic))
+    {
+        return Some(*content_type);
+    }
+
+    if file.len() >= 12 && file.starts_with(b"RIFF") && &file[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    let start = String::from_utf8_lossy(&file[..file.len().min(512)])
+        .trim_start()
+        .to_lowercase();
+
+    if start.starts_with("<!doctype html")
+        || start.starts_with("<html")
+        || start.starts_with("<script")
+    {
+        Some("text/html")
+    } else if start.starts_with("<svg") || (start.starts_with("<?xml") && start.contains("<svg")) {
+        Some("image/svg+xml")
+    } else {
+        None
+    }
+}
+
+/// Rejects uploads whose declared content type is dangerous or contradicts the file.
+fn check_upload_content_type(declared: Option<&str>, file: &[u8]) -> Result<()> {
+    let declared = declared.map(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    });
+    let sniffed = sniff_content_type(file);
+
+    if declared
+        .as_deref()
+        .into_iter()
+        .chain(sniffed)
+        .any(|content_type| DANGEROUS_CONTENT_TYPES.contains(&content_type))
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This content type is not allowed.",
+        ));
+    }
+
+    // Only types we know the signature of can be checked
+    if let Some(declared) = declared.filter(|declared| {
+        MAGIC_BYTES
+            .iter()
+            .any(|(_, content_type)| *content_type == declared.as_str())
+            || declared == "image/webp"
+    }) {
+        if sniffed != Some(declared.as_str()) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Content type does not match the uploaded file.",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_upload_content_type, ip_is_public, parse_opengraph, sniff_content_type};
+    use std::net::IpAddr;
+
+    #[test]
+    fn private_addresses_are_refused() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "2002:7f00:1::",
+        ] {
+            assert!(!ip_is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(ip_is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn opengraph_and_twitter_tags_are_parsed() {
+        let html = r#"
+            <html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="Tom &amp; Jerry">
+            <meta content='A cartoon' name="twitter:description" />
+            <meta name="twitter:title" content="Ignored">
+            <meta property="og:image" content="/cover.png">
+            <meta name="viewport" content="width=device-width">
+            </head></html>
+        "#;
+
+        let tags = parse_opengraph(html);
+        assert_eq!(tags["og:title"], "Tom & Jerry");
+        assert_eq!(tags["og:description"], "A cartoon");
+        assert_eq!(tags["og:image"], "/cover.png");
+        assert_eq!(tags.len(), 3);
+
+        assert_eq!(
+            parse_opengraph("<title> Just a title </title>")["og:title"],
+            "Just a title"
+        );
+    }
+
+    #[test]
+    fn uploads_must_match_their_content_type() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        assert_eq!(sniff_content_type(png), Some("image/png"));
+        assert_eq!(
+            sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            sniff_content_type(b"  <!DOCTYPE html><p>hi"),
+            Some("text/html")
+        );
+        assert_eq!(sniff_content_type(b"hello"), None);
+
+        assert!(check_upload_content_type(Some("image/png"), png).is_ok());
+        assert!(check_upload_content_type(Some("application/octet-stream"), png).is_ok());
+        assert!(check_upload_content_type(Some("text/plain; charset=utf-8"), b"hello").is_ok());
+        assert!(check_upload_content_type(None, b"hello").is_ok());
+
+        assert!(check_upload_content_type(Some("image/jpeg"), png).is_err());
+        assert!(check_upload_content_type(Some("image/gif"), b"hello").is_err());
+        assert!(check_upload_content_type(Some("Text/HTML"), b"hello").is_err());
+        assert!(check_upload_content_type(Some("image/png"), b"<svg onload=alert(1)>").is_err());
+        assert!(check_upload_content_type(Some("text/plain"), b"<html><script>").is_err());
     }
 }
@@ -4,22 +4,33 @@ use crate::{
     pdu::{EventHash, PduBuilder, PduEvent},
     server_server, utils, Database, Error, Result, Ruma,
 };
+use axum::extract::Extension;
+use reqwest::Url;
 use ruma::{
     api::{
         client::{
             error::ErrorKind,
+            knock::knock_room,
             membership::{
                 ban_user, forget_room, get_member_events, invite_user, join_room_by_id,
                 join_room_by_id_or_alias, joined_members, joined_rooms, kick_user, leave_room,
-                unban_user, IncomingThirdPartySigned,
+                unban_user, IncomingInvite3pid, IncomingThirdPartySigned,
             },
         },
-        federation::{self, membership::create_invite},
+        federation::{
+            self,
+            membership::{create_invite, create_join_event::RoomState},
+        },
     },
     events::{
         room::{
             create::RoomCreateEventContent,
-            member::{MembershipState, RoomMemberEventContent},
+            guest_access::GuestAccess,
+            join_rules::{AllowRule, JoinRule, Restricted, RoomJoinRulesEventContent},
+            member::{MembershipState, RoomMemberEventContent, SignedContent, ThirdPartyInvite},
+            power_levels::RoomPowerLevelsEventContent,
+            server_acl::RoomServerAclEventContent,
+            third_party_invite::{PublicKey, RoomThirdPartyInviteEventContent},
         },
         RoomEventType, StateEventType,
     },
@@ -27,13 +38,19 @@ use ruma::{
     state_res::{self, RoomVersion},
     uint, EventId, RoomId, RoomVersionId, ServerName, UserId,
 };
-use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
+use serde::Deserialize;
+use serde_json::{
+    json,
+    value::{to_raw_value, RawValue as RawJsonValue},
+};
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     iter,
+    net::SocketAddr,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
+use tokio::sync::RwLock as TokioRwLock;
 use tracing::{debug, error, warn};
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/join`
@@ -42,8 +59,14 @@ use tracing::{debug, error, warn};
 ///
 /// - If the server knowns about this room: creates the join event and does auth rules locally
 /// - If the server does not know about the room: asks other servers over federation
+/// - If a signed third party invite is given: checks it against the `m.room.third_party_invite`
+/// event and invites the user first
+/// - If the join rule is `restricted`: the user has to be in one of the allowed rooms, a member who
+/// may invite authorises the join
+/// - Memberships of other users in a remote room are validated in the background
 pub async fn join_room_by_id_route(
     db: DatabaseGuard,
+    Extension(db_lock): Extension<Arc<TokioRwLock<Database>>>,
     body: Ruma<join_room_by_id::v3::IncomingRequest>,
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
@@ -64,6 +87,7 @@ pub async fn join_room_by_id_route(
 
     let ret = join_room_by_id_helper(
         &db,
+        db_lock,
         body.sender_user.as_deref(),
         &body.room_id,
         &servers,
@@ -82,8 +106,10 @@ pub async fn join_room_by_id_route(
 ///
 /// - If the server knowns about this room: creates the join event and does auth rules locally
 /// - If the server does not know about the room: asks other servers over federation
+/// - Memberships of other users in a remote room are validated in the background
 pub async fn join_room_by_id_or_alias_route(
     db: DatabaseGuard,
+    Extension(db_lock): Extension<Arc<TokioRwLock<Database>>>,
     body: Ruma<join_room_by_id_or_alias::v3::IncomingRequest>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
     let sender_user = body.sender_user.as_deref().expect("user is authenticated");
@@ -115,6 +141,7 @@ pub async fn join_room_by_id_or_alias_route(
 
     let join_room_response = join_room_by_id_helper(
         &db,
+        db_lock,
         Some(sender_user),
         &room_id,
         &servers,
@@ -129,6 +156,42 @@ pub async fn join_room_by_id_or_alias_route(
     })
 }
 
+/// # `POST /_matrix/client/v3/knock/{roomIdOrAlias}`
+///
+/// Asks to be let into a room.
+///
+/// - Only works in rooms whose join rule is `knock` or `knock_restricted`
+/// - If the server knowns about this room: creates the knock event and does auth rules locally
+/// - If the server does not know about the room: asks other servers over federation
+/// - Members who may invite accept the knock with an invite and reject it with a kick
+pub async fn knock_room_route(
+    db: DatabaseGuard,
+    body: Ruma<knock_room::v3::IncomingRequest>,
+) -> Result<knock_room::v3::Response> {
+    let sender_user = body.sender_user.as_deref().expect("user is authenticated");
+    let body = body.body;
+
+    let (servers, room_id) = match Box::<RoomId>::try_from(body.room_id_or_alias) {
+        Ok(room_id) => {
+            let mut servers: HashSet<_> = body.server_name.into_iter().collect();
+
+            servers.insert(room_id.server_name().to_owned());
+            (servers, room_id)
+        }
+        Err(room_alias) => {
+            let response = client_server::get_alias_helper(&db, &room_alias).await?;
+
+            (response.servers.into_iter().collect(), response.room_id)
+        }
+    };
+
+    knock_room_helper(&db, sender_user, &room_id, &servers, body.reason).await?;
+
+    db.flush()?;
+
+    Ok(knock_room::v3::Response::new(room_id))
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/leave`
 ///
 /// Tries to leave the sender user from a room.
@@ -150,19 +213,29 @@ pub async fn leave_room_route(
 /// # `POST /_matrix/client/r0/rooms/{roomId}/invite`
 ///
 /// Tries to send an invite event into the room.
+///
+/// - Invites for a third party identifier (e.g. an email address) are stored at the identity server
+/// the client chose, which will notify the address. The room gets an `m.room.third_party_invite`
+/// event the invited user's join is checked against later
+/// - Identity servers are only contacted for users who may invite, and not in private networks
 pub async fn invite_user_route(
     db: DatabaseGuard,
     body: Ruma<invite_user::v3::IncomingRequest>,
 ) -> Result<invite_user::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if let invite_user::v3::IncomingInvitationRecipient::UserId { user_id } = &body.recipient {
-        invite_helper(sender_user, user_id, &body.room_id, &db, false).await?;
-        db.flush()?;
-        Ok(invite_user::v3::Response {})
-    } else {
-        Err(Error::BadRequest(ErrorKind::NotFound, "User not found."))
+    match &body.recipient {
+        invite_user::v3::IncomingInvitationRecipient::UserId { user_id } => {
+            invite_helper(sender_user, user_id, &body.room_id, &db, false).await?;
+        }
+        invite_user::v3::IncomingInvitationRecipient::ThirdPartyId(invite) => {
+            third_party_invite_helper(sender_user, invite, &body.room_id, &db).await?;
+        }
     }
+
+    db.flush()?;
+
+    Ok(invite_user::v3::Response {})
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/kick`
@@ -376,19 +449,29 @@ pub async fn forget_room_route(
 /// # `POST /_matrix/client/r0/joined_rooms`
 ///
 /// Lists all rooms the user has joined.
+///
+/// - Rooms are double-checked against the user's membership event in the current room state, so
+/// invited or left rooms never show up
 pub async fn joined_rooms_route(
     db: DatabaseGuard,
     body: Ruma<joined_rooms::v3::Request>,
 ) -> Result<joined_rooms::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    Ok(joined_rooms::v3::Response {
-        joined_rooms: db
+    let mut joined_rooms = Vec::new();
+    for room_id in db.rooms.rooms_joined(sender_user).filter_map(|r| r.ok()) {
+        let membership = db
             .rooms
-            .rooms_joined(sender_user)
-            .filter_map(|r| r.ok())
-            .collect(),
-    })
+            .room_state_get(&room_id, &StateEventType::RoomMember, sender_user.as_str())?
+            .and_then(|pdu| serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok())
+            .map(|content| content.membership);
+
+        if membership == Some(MembershipState::Join) {
+            joined_rooms.push(room_id);
+        }
+    }
+
+    Ok(joined_rooms::v3::Response { joined_rooms })
 }
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
@@ -396,6 +479,8 @@ pub async fn joined_rooms_route(
 /// Lists all joined users in a room (TODO: at a specific point in time, with a specific membership).
 ///
 /// - Only works if the user is currently joined
+/// - Waits until the members of a room joined with partial state are known, fails if fetching
+///   them failed
 pub async fn get_member_events_route(
     db: DatabaseGuard,
     body: Ruma<get_member_events::v3::IncomingRequest>,
@@ -410,6 +495,15 @@ pub async fn get_member_events_route(
         ));
     }
 
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
+        ));
+    }
+
+    db.rooms.wait_for_full_state(&body.room_id).await?;
+
     Ok(get_member_events::v3::Response {
         chunk: db
             .rooms
@@ -423,9 +517,12 @@ pub async fn get_member_events_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/joined_members`
 ///
-/// Lists all members of a room.
+/// Lists all joined members of a room.
 ///
 /// - The sender user must be in the room
+/// - Members and their room-specific profiles are read from the current room state
+/// - Waits until the members of a room joined with partial state are known, fails if fetching
+///   them failed
 /// - TODO: An appservice just needs a puppet joined
 pub async fn joined_members_route(
     db: DatabaseGuard,
@@ -440,30 +537,59 @@ pub async fn joined_members_route(
         ));
     }
 
-    let mut joined = BTreeMap::new();
-    for user_id in db.rooms.room_members(&body.room_id).filter_map(|r| r.ok()) {
-        let display_name = db.users.displayname(&user_id)?;
-        let avatar_url = db.users.avatar_url(&user_id)?;
-
-        joined.insert(
-            user_id,
-            joined_members::v3::RoomMember {
-                display_name,
-                avatar_url,
-            },
-        );
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
+        ));
     }
 
+    db.rooms.wait_for_full_state(&body.room_id).await?;
+
+    let joined = joined_members_from_state(
+        db.rooms
+            .room_state_full(&body.room_id)?
+            .into_iter()
+            .filter(|((event_type, _), _)| *event_type == StateEventType::RoomMember)
+            .filter_map(|((_, state_key), pdu)| {
+                serde_json::from_str(pdu.content.get())
+                    .ok()
+                    .map(|content| (state_key, content))
+            }),
+    );
+
     Ok(joined_members::v3::Response { joined })
 }
 
+/// Builds the `/joined_members` response from the member events of a room state, keyed by state
+/// key.
+fn joined_members_from_state(
+    members: impl IntoIterator<Item = (String, RoomMemberEventContent)>,
+) -> BTreeMap<Box<UserId>, joined_members::v3::RoomMember> {
+    members
+        .into_iter()
+        .filter(|(_, content)| content.membership == MembershipState::Join)
+        .filter_map(|(state_key, content)| {
+            let user_id = UserId::parse(state_key).ok()?;
+            Some((
+                user_id,
+                joined_members::v3::RoomMember {
+                    display_name: content.displayname,
+                    avatar_url: content.avatar_url,
+                },
+            ))
+        })
+        .collect()
+}
+
 #[tracing::instrument(skip(db))]
 async fn join_room_by_id_helper(
     db: &Database,
+    db_lock: Arc<TokioRwLock<Database>>,
     sender_user: Option<&UserId>,
     room_id: &RoomId,
     servers: &HashSet<Box<ServerName>>,
-    _third_party_signed: Option<&IncomingThirdPartySigned>,
+    third_party_signed: Option<&IncomingThirdPartySigned>,
 ) -> Result<join_room_by_id::v3::Response> {
     let sender_user = sender_user.expect("user is authenticated");
 
@@ -477,135 +603,71 @@ async fn join_room_by_id_helper(
     );
     let state_lock = mutex_state.lock().await;
 
-    // Ask a remote server if we don't have this room
-    if !db.rooms.exists(room_id)? && room_id.server_name() != db.globals.server_name() {
-        let mut make_join_response_and_server = Err(Error::BadServerResponse(
-            "No server available to assist in joining.",
-        ));
-
-        for remote_server in servers {
-            let make_join_response = db
-                .sending
-                .send_federation_request(
-                    &db.globals,
-                    remote_server,
-                    federation::membership::prepare_join_event::v1::Request {
-                        room_id,
-                        user_id: sender_user,
-                        ver: &db.globals.supported_room_versions(),
-                    },
-                )
-                .await;
+    if !db.rooms.exists(room_id)? && room_id.server_name() == db.globals.server_name() {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
 
-            make_join_response_and_server = make_join_response.map(|r| (r, remote_server));
+    if !db.rooms.is_joined(sender_user, room_id)? {
+        // Give specific reasons for failures that clients can explain to the user
+        if db.rooms.exists(room_id)? {
+            let member = db
+                .rooms
+                .room_state_get(room_id, &StateEventType::RoomMember, sender_user.as_str())?
+                .map(|pdu| {
+                    serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                        .map_err(|_| Error::bad_database("Invalid member event in database."))
+                })
+                .transpose()?;
+            check_not_banned(member.as_ref())?;
 
-            if make_join_response_and_server.is_ok() {
-                break;
-            }
+            let acl = db.rooms.server_acl(room_id)?;
+            check_server_acl(acl.as_deref(), db.globals.server_name())?;
         }
 
-        let (make_join_response, remote_server) = make_join_response_and_server?;
-
-        let room_version = match make_join_response.room_version {
-            Some(room_version) if db.rooms.is_supported_version(&db, &room_version) => room_version,
-            _ => return Err(Error::BadServerResponse("Room version is not supported")),
-        };
-
-        let mut join_event_stub: CanonicalJsonObject =
-            serde_json::from_str(make_join_response.event.get()).map_err(|_| {
-                Error::BadServerResponse("Invalid make_join event json received from server.")
-            })?;
-
-        // TODO: Is origin needed?
-        join_event_stub.insert(
-            "origin".to_owned(),
-            CanonicalJsonValue::String(db.globals.server_name().as_str().to_owned()),
-        );
-        join_event_stub.insert(
-            "origin_server_ts".to_owned(),
-            CanonicalJsonValue::Integer(
-                utils::millis_since_unix_epoch()
-                    .try_into()
-                    .expect("Timestamp is valid js_int value"),
-            ),
-        );
-        join_event_stub.insert(
-            "content".to_owned(),
-            to_canonical_value(RoomMemberEventContent {
-                membership: MembershipState::Join,
-                displayname: db.users.displayname(sender_user)?,
-                avatar_url: db.users.avatar_url(sender_user)?,
-                is_direct: None,
-                third_party_invite: None,
-                blurhash: db.users.blurhash(sender_user)?,
-                reason: None,
-                join_authorized_via_users_server: None,
-            })
-            .expect("event is valid, we just created it"),
-        );
-
-        // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
-        join_event_stub.remove("event_id");
-
-        // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
-        ruma::signatures::hash_and_sign_event(
-            db.globals.server_name().as_str(),
-            db.globals.keypair(),
-            &mut join_event_stub,
-            &room_version,
-        )
-        .expect("event is valid, we just created it");
-
-        // Generate event id
-        let event_id = format!(
-            "${}",
-            ruma::signatures::reference_hash(&join_event_stub, &room_version)
-                .expect("ruma can calculate reference hashes")
-        );
-        let event_id = <&EventId>::try_from(event_id.as_str())
-            .expect("ruma's reference hashes are valid event ids");
-
-        // Add event_id back
-        join_event_stub.insert(
-            "event_id".to_owned(),
-            CanonicalJsonValue::String(event_id.as_str().to_owned()),
-        );
+        // Joining a replaced room is almost never intended, point the user to the new room
+        if let Some(replacement_room) = db.rooms.tombstone_replacement(room_id)? {
+            return Err(Error::RoomReplaced(replacement_room));
+        }
 
-        // It has enough fields to be called a proper event now
-        let join_event = join_event_stub;
+        // We can only check guest access for rooms we know, otherwise the resident server decides
+        if db.users.is_guest(sender_user)? && db.rooms.exists(room_id)? {
+            check_guest_join(&db.rooms.guest_access(room_id)?)?;
+        }
+    }
 
-        let send_join_response = db
-            .sending
-            .send_federation_request(
-                &db.globals,
-                remote_server,
-                federation::membership::create_join_event::v2::Request {
-                    room_id,
-                    event_id,
-                    pdu: &PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
-                },
-            )
-            .await?;
+    // Ask a remote server if we don't have this room
+    if !db.rooms.exists(room_id)? && room_id.server_name() != db.globals.server_name() {
+        let (room_version, event_id, join_event, send_join_response, remote_server) =
+            send_remote_join(db, sender_user, room_id, servers).await?;
 
         db.rooms.get_or_create_shortroomid(room_id, &db.globals)?;
 
-        let parsed_pdu = PduEvent::from_id_val(event_id, join_event.clone())
+        let parsed_pdu = PduEvent::from_id_val(&event_id, join_event.clone())
             .map_err(|_| Error::BadServerResponse("Invalid join event PDU."))?;
 
         let mut state = HashMap::new();
         let pub_key_map = RwLock::new(BTreeMap::new());
 
+        // The memberships of other users are only needed to authorise their events. Checking
+        // them takes most of the time in big rooms, so they are added in the background and the
+        // user can use the room right away.
+        let RoomState {
+            state: join_state,
+            auth_chain,
+        } = send_join_response.room_state;
+        let (join_state, deferred_state): (Vec<_>, Vec<_>) = join_state
+            .into_iter()
+            .partition(|pdu| !is_other_member_event(pdu, sender_user));
+
         server_server::fetch_join_signing_keys(
-            &send_join_response,
+            join_state.iter().map(|pdu| &**pdu),
             &room_version,
             &pub_key_map,
             db,
         )
         .await?;
 
-        for result in send_join_response
-            .room_state
-            .state
+        for result in join_state
             .iter()
             .map(|pdu| validate_and_add_event_id(pdu, &room_version, &pub_key_map, db))
         {
@@ -659,20 +721,6 @@ async fn join_room_by_id_helper(
             db,
         )?;
 
-        for result in send_join_response
-            .room_state
-            .auth_chain
-            .iter()
-            .map(|pdu| validate_and_add_event_id(pdu, &room_version, &pub_key_map, db))
-        {
-            let (event_id, value) = match result {
-                Ok(t) => t,
-                Err(_) => continue,
-            };
-
-            db.rooms.add_pdu_outlier(&event_id, &value)?;
-        }
-
         // We append to state before appending the pdu, so we don't have a moment in time with the
         // pdu without it's state. This is okay because append_pdu can't fail.
         let statehashid = db.rooms.append_to_state(&parsed_pdu, &db.globals)?;
@@ -687,7 +735,51 @@ async fn join_room_by_id_helper(
         // We set the room state after inserting the pdu, so that we never have a moment in time
         // where events in the current room state do not exist
         db.rooms.set_room_state(room_id, statehashid)?;
+
+        db.rooms
+            .mark_partial_state(room_id, &remote_server, &event_id)?;
+        spawn_state_fill(
+            db_lock,
+            db,
+            room_id,
+            Some(RoomState {
+                state: deferred_state,
+                auth_chain,
+            }),
+        );
     } else {
+        // A signed third party invite becomes a real invite first, which allows the join
+        if let Some(signed) = third_party_signed {
+            if !db.rooms.is_joined(sender_user, room_id)?
+                && !db.rooms.is_invited(sender_user, room_id)?
+            {
+                accept_third_party_invite(db, sender_user, room_id, signed, &state_lock)?;
+            }
+        }
+
+        // Joins of restricted rooms have to name a member who could have invited the user
+        let mut join_authorized_via_users_server = None;
+        if check_restricted_join(db, room_id, sender_user)? {
+            match local_join_authoriser(db, room_id)? {
+                Some(authoriser) => join_authorized_via_users_server = Some(authoriser),
+                None => {
+                    // A server with such a member has to sign the join. The signed event comes
+                    // back over federation, so the room must not stay locked in the meantime.
+                    drop(state_lock);
+
+                    let servers = join_authoriser_servers(db, room_id)?;
+                    let (_, event_id, _, _, remote_server) =
+                        send_remote_join(db, sender_user, room_id, &servers).await?;
+
+                    add_signed_remote_join(db, &remote_server, room_id, &event_id).await?;
+
+                    db.flush()?;
+
+                    return Ok(join_room_by_id::v3::Response::new(room_id.to_owned()));
+                }
+            }
+        }
+
         let event = RoomMemberEventContent {
             membership: MembershipState::Join,
             displayname: db.users.displayname(sender_user)?,
@@ -696,22 +788,28 @@ async fn join_room_by_id_helper(
             third_party_invite: None,
             blurhash: db.users.blurhash(sender_user)?,
             reason: None,
-            join_authorized_via_users_server: None,
+            join_authorized_via_users_server,
         };
 
-        db.rooms.build_and_append_pdu(
-            PduBuilder {
-                event_type: RoomEventType::RoomMember,
-                content: to_raw_value(&event).expect("event is valid, we just created it"),
-                unsigned: None,
-                state_key: Some(sender_user.to_string()),
-                redacts: None,
-            },
-            sender_user,
-            room_id,
-            db,
-            &state_lock,
-        )?;
+        // Joining again without changing anything would only add a redundant event
+        if !db
+            .rooms
+            .is_noop_membership_change(room_id, sender_user, &event)?
+        {
+            db.rooms.build_and_append_pdu(
+                PduBuilder {
+                    event_type: RoomEventType::RoomMember,
+                    content: to_raw_value(&event).expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(sender_user.to_string()),
+                    redacts: None,
+                },
+                sender_user,
+                room_id,
+                db,
+                &state_lock,
+            )?;
+        }
     }
 
     drop(state_lock);
@@ -721,76 +819,951 @@ async fn join_room_by_id_helper(
     Ok(join_room_by_id::v3::Response::new(room_id.to_owned()))
 }
 
-fn validate_and_add_event_id(
-    pdu: &RawJsonValue,
-    room_version: &RoomVersionId,
-    pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+/// Asks the given servers for a join event template, completes and signs it and sends the join
+/// back. Returns the room version, the join event, the state the remote server sent and the
+/// server that accepted the join.
+async fn send_remote_join(
     db: &Database,
-) -> Result<(Box<EventId>, CanonicalJsonObject)> {
-    let mut value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
-        error!("Invalid PDU in server response: {:?}: {:?}", pdu, e);
-        Error::BadServerResponse("Invalid PDU in server response")
-    })?;
-    let event_id = EventId::parse(format!(
-        "${}",
-        ruma::signatures::reference_hash(&value, room_version)
-            .expect("ruma can calculate reference hashes")
-    ))
-    .expect("ruma's reference hashes are valid event ids");
+    sender_user: &UserId,
+    room_id: &RoomId,
+    servers: &HashSet<Box<ServerName>>,
+) -> Result<(
+    RoomVersionId,
+    Box<EventId>,
+    CanonicalJsonObject,
+    federation::membership::create_join_event::v2::Response,
+    Box<ServerName>,
+)> {
+    let mut make_join_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in joining.",
+    ));
+
+    for remote_server in servers {
+        let make_join_response = db
+            .sending
+            .send_federation_request(
+                &db.globals,
+                remote_server,
+                federation::membership::prepare_join_event::v1::Request {
+                    room_id,
+                    user_id: sender_user,
+                    ver: &db.globals.supported_room_versions(),
+                },
+            )
+            .await;
 
-    let back_off = |id| match db.globals.bad_event_ratelimiter.write().unwrap().entry(id) {
-        Entry::Vacant(e) => {
-            e.insert((Instant::now(), 1));
-        }
-        Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
-    };
+        make_join_response_and_server = make_join_response.map(|r| (r, remote_server));
 
-    if let Some((time, tries)) = db
-        .globals
-        .bad_event_ratelimiter
-        .read()
-        .unwrap()
-        .get(&event_id)
-    {
-        // Exponential backoff
-        let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
-        if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
-            min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
+        if make_join_response_and_server.is_ok() {
+            break;
         }
+    }
 
-        if time.elapsed() < min_elapsed_duration {
-            debug!("Backing off from {}", event_id);
-            return Err(Error::BadServerResponse("bad event, still backing off"));
+    let (make_join_response, remote_server) =
+        make_join_response_and_server.map_err(|e| match e {
+            // The remote refused because none of the versions we sent in `ver` match
+            Error::FederationError(origin, error) => {
+                let incompatible_version = match &error.kind {
+                    ErrorKind::IncompatibleRoomVersion { room_version } => {
+                        Some(room_version.clone())
+                    }
+                    _ => None,
+                };
+
+                match incompatible_version {
+                    Some(room_version) => unsupported_room_version(
+                        Some(&room_version),
+                        &db.globals.supported_room_versions(),
+                    ),
+                    None => Error::FederationError(origin, error),
+                }
+            }
+            e => e,
+        })?;
+
+    let room_version = match make_join_response.room_version {
+        Some(room_version) if db.rooms.is_supported_version(&db, &room_version) => room_version,
+        room_version => {
+            return Err(unsupported_room_version(
+                room_version.as_ref(),
+                &db.globals.supported_room_versions(),
+            ))
         }
-    }
+    };
 
-    if let Err(e) = ruma::signatures::verify_event(
-        &*pub_key_map
-            .read()
-            .map_err(|_| Error::bad_database("RwLock is poisoned."))?,
-        &value,
-        room_version,
-    ) {
-        warn!("Event {} failed verification {:?} {}", event_id, pdu, e);
-        back_off(event_id);
-        return Err(Error::BadServerResponse("Event failed verification."));
-    }
+    let (event_id, join_event) = complete_membership_event_template(
+        db,
+        &make_join_response.event,
+        RoomMemberEventContent {
+            membership: MembershipState::Join,
+            displayname: db.users.displayname(sender_user)?,
+            avatar_url: db.users.avatar_url(sender_user)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: db.users.blurhash(sender_user)?,
+            reason: None,
+            join_authorized_via_users_server: None,
+        },
+        &room_version,
+    )?;
 
-    value.insert(
-        "event_id".to_owned(),
-        CanonicalJsonValue::String(event_id.as_str().to_owned()),
-    );
+    // TODO: Third party invites for rooms we are not in yet (exchange_third_party_invite)
 
-    Ok((event_id, value))
+    // TODO: Ask for partial state (MSC3706) once our ruma version has the `omit_members`
+    // parameter. Until then the remote server always sends all members, we only defer checking
+    // them.
+    let send_join_response = db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            remote_server,
+            federation::membership::create_join_event::v2::Request {
+                room_id,
+                event_id: &event_id,
+                pdu: &PduEvent::convert_to_outgoing_federation_event(join_event.clone()),
+            },
+        )
+        .await?;
+
+    Ok((
+        room_version,
+        event_id,
+        join_event,
+        send_join_response,
+        remote_server.to_owned(),
+    ))
 }
 
-pub(crate) async fn invite_helper<'a>(
-    sender_user: &UserId,
-    user_id: &UserId,
-    room_id: &RoomId,
+/// Fetches a join to a room we are in after another server signed it for a restricted join, and
+/// adds it like any other incoming event. Our own copy lacks the signature of that server.
+async fn add_signed_remote_join(
     db: &Database,
-    is_direct: bool,
+    remote_server: &ServerName,
+    room_id: &RoomId,
+    event_id: &EventId,
 ) -> Result<()> {
+    let response = db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            remote_server,
+            federation::event::get_event::v1::Request { event_id },
+        )
+        .await?;
+
+    let (signed_event_id, value) = crate::pdu::gen_event_id_canonical_json(&response.pdu, db)?;
+    if *signed_event_id != *event_id {
+        return Err(Error::BadServerResponse(
+            "Server returned a different join event.",
+        ));
+    }
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    let mutex = Arc::clone(
+        db.globals
+            .roomid_mutex_federation
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let mutex_lock = mutex.lock().await;
+    server_server::handle_incoming_pdu(
+        remote_server,
+        event_id,
+        room_id,
+        value,
+        true,
+        db,
+        &pub_key_map,
+    )
+    .await
+    .map_err(|e| {
+        warn!("Error while handling signed join {}: {}", event_id, e);
+        Error::BadServerResponse("Signed join event was not accepted.")
+    })?
+    .ok_or(Error::BadServerResponse(
+        "Signed join event was not accepted as timeline event.",
+    ))?;
+    drop(mutex_lock);
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(db))]
+async fn knock_room_helper(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    servers: &HashSet<Box<ServerName>>,
+    reason: Option<String>,
+) -> Result<()> {
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let content = RoomMemberEventContent {
+        membership: MembershipState::Knock,
+        displayname: db.users.displayname(sender_user)?,
+        avatar_url: db.users.avatar_url(sender_user)?,
+        is_direct: None,
+        third_party_invite: None,
+        blurhash: db.users.blurhash(sender_user)?,
+        reason,
+        join_authorized_via_users_server: None,
+    };
+
+    if db.rooms.exists(room_id)? {
+        check_knock_allowed(db, room_id, sender_user)?;
+
+        let acl = db.rooms.server_acl(room_id)?;
+        check_server_acl(acl.as_deref(), db.globals.server_name())?;
+
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomMember,
+                content: to_raw_value(&content).expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_user.to_string()),
+                redacts: None,
+            },
+            sender_user,
+            room_id,
+            db,
+            &state_lock,
+        )?;
+
+        return Ok(());
+    }
+
+    if room_id.server_name() == db.globals.server_name() {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found."));
+    }
+
+    // Ask a remote server, we are not in the room
+    let mut make_knock_response_and_server = Err(Error::BadServerResponse(
+        "No server available to assist in knocking.",
+    ));
+
+    for remote_server in servers {
+        let make_knock_response = db
+            .sending
+            .send_federation_request(
+                &db.globals,
+                remote_server,
+                federation::knock::create_knock_event_template::v1::Request {
+                    room_id,
+                    user_id: sender_user,
+                    ver: &db.globals.supported_room_versions(),
+                },
+            )
+            .await;
+
+        make_knock_response_and_server = make_knock_response.map(|r| (r, remote_server));
+
+        if make_knock_response_and_server.is_ok() {
+            break;
+        }
+    }
+
+    let (make_knock_response, remote_server) = make_knock_response_and_server?;
+
+    let room_version = make_knock_response.room_version;
+    if !db.rooms.is_supported_version(db, &room_version) {
+        return Err(unsupported_room_version(
+            Some(&room_version),
+            &db.globals.supported_room_versions(),
+        ));
+    }
+
+    let (event_id, knock_event) =
+        complete_membership_event_template(db, &make_knock_response.event, content, &room_version)?;
+
+    let send_knock_response = db
+        .sending
+        .send_federation_request(
+            &db.globals,
+            remote_server,
+            federation::knock::send_knock::v1::Request {
+                room_id,
+                event_id: &event_id,
+                pdu: &PduEvent::convert_to_outgoing_federation_event(knock_event.clone()),
+            },
+        )
+        .await?;
+
+    let parsed_pdu = PduEvent::from_id_val(&event_id, knock_event.clone())
+        .map_err(|_| Error::BadServerResponse("Invalid knock event PDU."))?;
+
+    db.rooms.add_pdu_outlier(&event_id, &knock_event)?;
+
+    // We are not in the room, the stripped state is all we know until the knock is answered
+    let mut knock_state = send_knock_response.knock_room_state;
+    knock_state.push(parsed_pdu.to_stripped_state_event());
+
+    db.rooms.update_membership(
+        room_id,
+        sender_user,
+        MembershipState::Knock,
+        sender_user,
+        Some(knock_state),
+        db,
+        false,
+    )?;
+
+    drop(state_lock);
+
+    Ok(())
+}
+
+/// Fills in an event template from `make_join` or `make_knock` with our content, then hashes and
+/// signs it.
+fn complete_membership_event_template(
+    db: &Database,
+    template: &RawJsonValue,
+    mut content: RoomMemberEventContent,
+    room_version: &RoomVersionId,
+) -> Result<(Box<EventId>, CanonicalJsonObject)> {
+    let mut event_stub: CanonicalJsonObject = serde_json::from_str(template.get())
+        .map_err(|_| Error::BadServerResponse("Invalid event template received from server."))?;
+
+    // For restricted rooms the remote server picks the member who authorises the join
+    if let Some(CanonicalJsonValue::Object(template_content)) = event_stub.get("content") {
+        if let Some(authoriser) = template_content
+            .get("join_authorised_via_users_server")
+            .and_then(|user_id| UserId::parse(user_id.as_str()?).ok())
+        {
+            content.join_authorized_via_users_server = Some(authoriser);
+        }
+    }
+
+    // TODO: Is origin needed?
+    event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(db.globals.server_name().as_str().to_owned()),
+    );
+    event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(
+            utils::millis_since_unix_epoch()
+                .try_into()
+                .expect("Timestamp is valid js_int value"),
+        ),
+    );
+    event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(content).expect("event is valid, we just created it"),
+    );
+
+    // We don't leave the event id in the pdu because that's only allowed in v1 or v2 rooms
+    event_stub.remove("event_id");
+
+    // In order to create a compatible ref hash (EventID) the `hashes` field needs to be present
+    ruma::signatures::hash_and_sign_event(
+        db.globals.server_name().as_str(),
+        db.globals.keypair(),
+        &mut event_stub,
+        room_version,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        ruma::signatures::reference_hash(&event_stub, room_version)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    // Add event_id back
+    event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    // It has enough fields to be called a proper event now
+    Ok((event_id, event_stub))
+}
+
+/// Checks a join of a room with a `restricted` join rule: the user has to be in one of the allowed
+/// rooms, unless they are invited or already joined. Returns true if the join needs to be
+/// authorised by a member who can invite.
+pub(crate) fn check_restricted_join(
+    db: &Database,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<bool> {
+    let join_rule = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+        .and_then(|pdu| serde_json::from_str::<RoomJoinRulesEventContent>(pdu.content.get()).ok())
+        .map(|content| content.join_rule);
+
+    let allowed_rooms = match &join_rule {
+        Some(JoinRule::Restricted(restricted)) => allowed_rooms(restricted),
+        _ => return Ok(false),
+    };
+
+    if db.rooms.is_joined(user_id, room_id)? || db.rooms.is_invited(user_id, room_id)? {
+        return Ok(false);
+    }
+
+    check_member_of_allowed_room(&allowed_rooms, |allowed_room| {
+        db.rooms.is_joined(user_id, allowed_room)
+    })?;
+
+    Ok(true)
+}
+
+/// The rooms whose members may join a restricted room.
+fn allowed_rooms(restricted: &Restricted) -> Vec<&RoomId> {
+    restricted
+        .allow
+        .iter()
+        .filter_map(|rule| match rule {
+            AllowRule::RoomMembership(membership) => Some(&*membership.room_id),
+            _ => None,
+        })
+        .collect()
+}
+
+fn check_member_of_allowed_room(
+    allowed_rooms: &[&RoomId],
+    is_joined: impl Fn(&RoomId) -> Result<bool>,
+) -> Result<()> {
+    for allowed_room in allowed_rooms {
+        if is_joined(allowed_room)? {
+            return Ok(());
+        }
+    }
+
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "You are not in any of the rooms that allow joining this room.",
+    ))
+}
+
+/// A member of this server who may invite and can therefore authorise restricted joins.
+pub(crate) fn local_join_authoriser(
+    db: &Database,
+    room_id: &RoomId,
+) -> Result<Option<Box<UserId>>> {
+    let power_levels = client_server::room_power_levels(db, room_id)?;
+
+    Ok(db
+        .rooms
+        .room_members(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|user_id| user_id.server_name() == db.globals.server_name())
+        .find(|user_id| may_invite(&power_levels, user_id)))
+}
+
+/// Other servers with a member who may invite, they can authorise restricted joins.
+fn join_authoriser_servers(db: &Database, room_id: &RoomId) -> Result<HashSet<Box<ServerName>>> {
+    let power_levels = client_server::room_power_levels(db, room_id)?;
+
+    Ok(db
+        .rooms
+        .room_members(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|user_id| may_invite(&power_levels, user_id))
+        .map(|user_id| user_id.server_name().to_owned())
+        .filter(|server| &**server != db.globals.server_name())
+        .collect())
+}
+
+fn may_invite(power_levels: &RoomPowerLevelsEventContent, user_id: &UserId) -> bool {
+    client_server::user_power_level(power_levels, user_id) >= power_levels.invite
+}
+
+/// Rejects knocks on rooms that don't allow knocking, and by users who are already in the room,
+/// invited or banned.
+pub(crate) fn check_knock_allowed(db: &Database, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+    let member = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
+        .map(|pdu| {
+            serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid member event in database."))
+        })
+        .transpose()?;
+
+    check_knock(db.rooms.join_rule(room_id)?.as_deref(), member.as_ref())
+}
+
+fn check_knock(join_rule: Option<&str>, member: Option<&RoomMemberEventContent>) -> Result<()> {
+    check_not_banned(member)?;
+
+    match member.map(|member| &member.membership) {
+        Some(MembershipState::Join) => {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "You are already in this room.",
+            ))
+        }
+        Some(MembershipState::Invite) => {
+            return Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "You are already invited to this room, join it instead.",
+            ))
+        }
+        _ => {}
+    }
+
+    if !matches!(join_rule, Some("knock") | Some("knock_restricted")) {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This room does not allow knocking.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Turns the signed token of a third party invite into an invite from the original inviter.
+fn accept_third_party_invite(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    signed: &IncomingThirdPartySigned,
+    state_lock: &tokio::sync::MutexGuard<'_, ()>,
+) -> Result<()> {
+    let invite_event = db
+        .rooms
+        .room_state_get(
+            room_id,
+            &StateEventType::RoomThirdPartyInvite,
+            &signed.token,
+        )?
+        .ok_or(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "No third party invite was issued for this token.",
+        ))?;
+
+    let invite: RoomThirdPartyInviteEventContent = serde_json::from_str(invite_event.content.get())
+        .map_err(|_| Error::bad_database("Invalid third party invite event in database."))?;
+
+    verify_third_party_signed(&invite_event.sender, &invite, user_id, signed)?;
+
+    // The invite has to be sent by the inviter, we can only do that for our own users
+    if invite_event.sender.server_name() != db.globals.server_name() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Third party invites from other servers are not supported.",
+        ));
+    }
+
+    let mut content = serde_json::to_value(&RoomMemberEventContent {
+        membership: MembershipState::Invite,
+        displayname: db.users.displayname(user_id)?,
+        avatar_url: db.users.avatar_url(user_id)?,
+        is_direct: None,
+        third_party_invite: Some(ThirdPartyInvite::new(
+            invite.display_name,
+            SignedContent::new(
+                signed.signatures.clone(),
+                signed.mxid.clone(),
+                signed.token.clone(),
+            ),
+        )),
+        blurhash: db.users.blurhash(user_id)?,
+        reason: None,
+        join_authorized_via_users_server: None,
+    })
+    .expect("event is valid, we just created it");
+
+    // Our ruma version doesn't know the sender field yet, but it is part of what was signed
+    content["third_party_invite"]["signed"]["sender"] = json!(signed.sender);
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomMember,
+            content: to_raw_value(&content).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        &invite_event.sender,
+        room_id,
+        db,
+        state_lock,
+    )?;
+
+    Ok(())
+}
+
+/// Checks that an identity server signed the token of a third party invite for this user, with
+/// one of the keys listed in the `m.room.third_party_invite` event.
+fn verify_third_party_signed(
+    inviter: &UserId,
+    invite: &RoomThirdPartyInviteEventContent,
+    user_id: &UserId,
+    signed: &IncomingThirdPartySigned,
+) -> Result<()> {
+    if &*signed.mxid != user_id || &*signed.sender != inviter {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The third party invite was signed for someone else.",
+        ));
+    }
+
+    let signed_object: CanonicalJsonObject = serde_json::from_value(json!({
+        "mxid": signed.mxid,
+        "sender": signed.sender,
+        "token": signed.token,
+        "signatures": signed.signatures,
+    }))
+    .expect("signed third party invite is valid canonical json");
+
+    let public_keys = iter::once(&invite.public_key).chain(
+        invite
+            .public_keys
+            .iter()
+            .flatten()
+            .map(|key| &key.public_key),
+    );
+
+    let valid = signed.signatures.iter().any(|(server, signatures)| {
+        signatures.keys().any(|key_id| {
+            public_keys.clone().any(|public_key| {
+                let public_key_map = BTreeMap::from_iter([(
+                    server.to_string(),
+                    BTreeMap::from_iter([(key_id.to_string(), public_key.clone())]),
+                )]);
+
+                ruma::signatures::verify_json(&public_key_map, &signed_object).is_ok()
+            })
+        })
+    });
+
+    if !valid {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "The signature of the third party invite is invalid.",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Banned users get the reason of their ban instead of a generic auth failure.
+fn check_not_banned(member: Option<&RoomMemberEventContent>) -> Result<()> {
+    match member {
+        Some(member) if member.membership == MembershipState::Ban => {
+            Err(Error::BadRequestDetailed(
+                ErrorKind::Forbidden,
+                match &member.reason {
+                    Some(reason) => format!("You are banned from this room: {}", reason),
+                    None => "You are banned from this room.".to_owned(),
+                },
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// With `invite_only_from_members` enabled, only users joined to a room may invite others to it.
+pub(crate) fn check_inviter_is_member(inviter_is_joined: bool) -> Result<()> {
+    if inviter_is_joined {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Only members of this room may invite users.",
+        ))
+    }
+}
+
+/// Our users can't join rooms whose server ACL denies this server.
+fn check_server_acl(
+    acl: Option<&RoomServerAclEventContent>,
+    server_name: &ServerName,
+) -> Result<()> {
+    match acl {
+        Some(acl) if !acl.is_allowed(server_name) => Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "This server is denied by the room's server ACL.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Guests may only join rooms that explicitly allow it.
+fn check_guest_join(guest_access: &GuestAccess) -> Result<()> {
+    if *guest_access == GuestAccess::CanJoin {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to join this room.",
+        ))
+    }
+}
+
+/// Explains that we can't join a room because of its version.
+fn unsupported_room_version(
+    room_version: Option<&RoomVersionId>,
+    supported: &[RoomVersionId],
+) -> Error {
+    let supported = supported
+        .iter()
+        .map(|version| version.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let message = match room_version {
+        Some(room_version) => format!(
+            "Room version {} is not supported by this server. Supported versions: {}",
+            room_version, supported
+        ),
+        None => format!(
+            "The remote server didn't tell us the room version. Supported versions: {}",
+            supported
+        ),
+    };
+
+    Error::BadRequestDetailed(ErrorKind::UnsupportedRoomVersion, message)
+}
+
+/// Whether the PDU is the membership of someone other than `user_id`.
+fn is_other_member_event(pdu: &RawJsonValue, user_id: &UserId) -> bool {
+    #[derive(Deserialize)]
+    struct ExtractMember {
+        #[serde(rename = "type")]
+        kind: String,
+        state_key: Option<String>,
+    }
+
+    serde_json::from_str::<ExtractMember>(pdu.get()).map_or(false, |event| {
+        event.kind == "m.room.member" && event.state_key.as_deref() != Some(user_id.as_str())
+    })
+}
+
+/// Adds the state a partial join left out in the background, unless that is already happening.
+///
+/// Without `room_state`, the state at our join event is fetched again from the server we joined
+/// through, e.g. after a restart.
+pub(crate) fn spawn_state_fill(
+    db_lock: Arc<TokioRwLock<Database>>,
+    db: &Database,
+    room_id: &RoomId,
+    room_state: Option<RoomState>,
+) {
+    if !db.rooms.start_state_fill(room_id) {
+        return;
+    }
+
+    let room_id = room_id.to_owned();
+    tokio::spawn(async move {
+        let db = db_lock.read().await;
+
+        if let Err(e) = fill_partial_state(&db, &room_id, room_state).await {
+            warn!("Failed to fill the state of {}: {}", room_id, e);
+        }
+
+        db.rooms.finish_state_fill(&room_id);
+    });
+}
+
+/// Validates the state a partial join left out and adds it to the current room state. Newer
+/// events of the room take precedence.
+async fn fill_partial_state(
+    db: &Database,
+    room_id: &RoomId,
+    room_state: Option<RoomState>,
+) -> Result<()> {
+    let (server, join_event_id) = match db.rooms.partial_state(room_id)? {
+        Some(partial_state) => partial_state,
+        None => return Ok(()),
+    };
+
+    let RoomState { state, auth_chain } = match room_state {
+        Some(room_state) => room_state,
+        None => {
+            let response = db
+                .sending
+                .send_federation_request(
+                    &db.globals,
+                    &server,
+                    federation::event::get_room_state::v1::Request {
+                        room_id,
+                        event_id: &join_event_id,
+                    },
+                )
+                .await?;
+
+            RoomState {
+                state: response.pdus,
+                auth_chain: response.auth_chain,
+            }
+        }
+    };
+
+    let room_version = db.rooms.get_room_version(room_id)?;
+    let pub_key_map = RwLock::new(BTreeMap::new());
+
+    server_server::fetch_join_signing_keys(
+        state.iter().chain(&auth_chain).map(|pdu| &**pdu),
+        &room_version,
+        &pub_key_map,
+        db,
+    )
+    .await?;
+
+    let mut missing_state = HashMap::new();
+    for result in state
+        .into_iter()
+        .map(|pdu| validate_and_add_event_id(&pdu, &room_version, &pub_key_map, db))
+    {
+        let (event_id, value) = match result {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+
+        let pdu = PduEvent::from_id_val(&event_id, value.clone()).map_err(|e| {
+            warn!("{:?}: {}", value, e);
+            Error::BadServerResponse("Invalid PDU in room state.")
+        })?;
+
+        db.rooms.add_pdu_outlier(&event_id, &value)?;
+        if let Some(state_key) = &pdu.state_key {
+            let shortstatekey = db.rooms.get_or_create_shortstatekey(
+                &pdu.kind.to_string().into(),
+                state_key,
+                &db.globals,
+            )?;
+            missing_state.insert(shortstatekey, pdu.event_id.clone());
+        }
+    }
+
+    for result in auth_chain
+        .into_iter()
+        .map(|pdu| validate_and_add_event_id(&pdu, &room_version, &pub_key_map, db))
+    {
+        let (event_id, value) = match result {
+            Ok(t) => t,
+            Err(_) => continue,
+        };
+
+        db.rooms.add_pdu_outlier(&event_id, &value)?;
+    }
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let mut state = match db.rooms.current_shortstatehash(room_id)? {
+        Some(shortstatehash) => db.rooms.state_full_ids(shortstatehash)?,
+        None => BTreeMap::new(),
+    };
+    for (shortstatekey, event_id) in missing_state {
+        state.entry(shortstatekey).or_insert(event_id);
+    }
+
+    db.rooms.force_state(
+        room_id,
+        state
+            .into_iter()
+            .map(|(k, id)| db.rooms.compress_state_event(k, &id, &db.globals))
+            .collect::<Result<_>>()?,
+        db,
+    )?;
+    db.rooms.mark_full_state(room_id)?;
+
+    drop(state_lock);
+
+    db.flush()?;
+
+    Ok(())
+}
+
+fn validate_and_add_event_id(
+    pdu: &RawJsonValue,
+    room_version: &RoomVersionId,
+    pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+    db: &Database,
+) -> Result<(Box<EventId>, CanonicalJsonObject)> {
+    let mut value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
+        error!("Invalid PDU in server response: {:?}: {:?}", pdu, e);
+        Error::BadServerResponse("Invalid PDU in server response")
+    })?;
+    let event_id = EventId::parse(format!(
+        "${}",
+        ruma::signatures::reference_hash(&value, room_version)
+            .expect("ruma can calculate reference hashes")
+    ))
+    .expect("ruma's reference hashes are valid event ids");
+
+    let back_off = |id| match db.globals.bad_event_ratelimiter.write().unwrap().entry(id) {
+        Entry::Vacant(e) => {
+            e.insert((Instant::now(), 1));
+        }
+        Entry::Occupied(mut e) => *e.get_mut() = (Instant::now(), e.get().1 + 1),
+    };
+
+    if let Some((time, tries)) = db
+        .globals
+        .bad_event_ratelimiter
+        .read()
+        .unwrap()
+        .get(&event_id)
+    {
+        // Exponential backoff
+        let mut min_elapsed_duration = Duration::from_secs(30) * (*tries) * (*tries);
+        if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
+            min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
+        }
+
+        if time.elapsed() < min_elapsed_duration {
+            debug!("Backing off from {}", event_id);
+            return Err(Error::BadServerResponse("bad event, still backing off"));
+        }
+    }
+
+    if let Err(e) = ruma::signatures::verify_event(
+        &*pub_key_map
+            .read()
+            .map_err(|_| Error::bad_database("RwLock is poisoned."))?,
+        &value,
+        room_version,
+    ) {
+        warn!("Event {} failed verification {:?} {}", event_id, pdu, e);
+        back_off(event_id);
+        return Err(Error::BadServerResponse("Event failed verification."));
+    }
+
+    value.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+
+    Ok((event_id, value))
+}
+
+pub(crate) async fn invite_helper<'a>(
+    sender_user: &UserId,
+    user_id: &UserId,
+    room_id: &RoomId,
+    db: &Database,
+    is_direct: bool,
+) -> Result<()> {
+    if db.globals.invite_only_from_members() {
+        check_inviter_is_member(db.rooms.is_joined(sender_user, room_id)?)?;
+    }
+
     if user_id.server_name() != db.globals.server_name() {
         let (room_version_id, pdu_json, invite_room_state) = {
             let mutex_state = Arc::clone(
@@ -803,270 +1776,1283 @@ pub(crate) async fn invite_helper<'a>(
             );
             let state_lock = mutex_state.lock().await;
 
-            let prev_events: Vec<_> = db
-                .rooms
-                .get_pdu_leaves(room_id)?
-                .into_iter()
-                .take(20)
-                .collect();
+            let prev_events: Vec<_> = db
+                .rooms
+                .get_pdu_leaves(room_id)?
+                .into_iter()
+                .take(20)
+                .collect();
+
+            let create_event = db
+                .rooms
+                .room_state_get(room_id, &StateEventType::RoomCreate, "")?;
+
+            let create_event_content: Option<RoomCreateEventContent> = create_event
+                .as_ref()
+                .map(|create_event| {
+                    serde_json::from_str(create_event.content.get()).map_err(|e| {
+                        warn!("Invalid create event: {}", e);
+                        Error::bad_database("Invalid create event in db.")
+                    })
+                })
+                .transpose()?;
+
+            // If there was no create event yet, assume we are creating a room with the default
+            // version right now
+            let room_version_id = create_event_content
+                .map_or(db.globals.default_room_version(), |create_event| {
+                    create_event.room_version
+                });
+            let room_version =
+                RoomVersion::new(&room_version_id).expect("room version is supported");
+
+            let content = to_raw_value(&RoomMemberEventContent {
+                avatar_url: None,
+                displayname: None,
+                is_direct: Some(is_direct),
+                membership: MembershipState::Invite,
+                third_party_invite: None,
+                blurhash: None,
+                reason: None,
+                join_authorized_via_users_server: None,
+            })
+            .expect("member event is valid value");
+
+            let state_key = user_id.to_string();
+            let kind = StateEventType::RoomMember;
+
+            let auth_events = db.rooms.get_auth_events(
+                room_id,
+                &kind.to_string().into(),
+                sender_user,
+                Some(&state_key),
+                &content,
+            )?;
+
+            // Our depth is the maximum depth of prev_events + 1
+            let depth = prev_events
+                .iter()
+                .filter_map(|event_id| Some(db.rooms.get_pdu(event_id).ok()??.depth))
+                .max()
+                .unwrap_or_else(|| uint!(0))
+                + uint!(1);
+
+            let mut unsigned = BTreeMap::new();
+
+            if let Some(prev_pdu) = db.rooms.room_state_get(room_id, &kind, &state_key)? {
+                unsigned.insert("prev_content".to_owned(), prev_pdu.content.clone());
+                unsigned.insert(
+                    "prev_sender".to_owned(),
+                    to_raw_value(&prev_pdu.sender).expect("UserId is valid"),
+                );
+            }
+
+            let pdu = PduEvent {
+                event_id: ruma::event_id!("$thiswillbefilledinlater").into(),
+                room_id: room_id.to_owned(),
+                sender: sender_user.to_owned(),
+                origin_server_ts: utils::millis_since_unix_epoch()
+                    .try_into()
+                    .expect("time is valid"),
+                kind: kind.to_string().into(),
+                content,
+                state_key: Some(state_key),
+                prev_events,
+                depth,
+                auth_events: auth_events
+                    .iter()
+                    .map(|(_, pdu)| pdu.event_id.clone())
+                    .collect(),
+                redacts: None,
+                unsigned: if unsigned.is_empty() {
+                    None
+                } else {
+                    Some(to_raw_value(&unsigned).expect("to_raw_value always works"))
+                },
+                hashes: EventHash {
+                    sha256: "aaa".to_owned(),
+                },
+                signatures: None,
+            };
+
+            let auth_check = state_res::auth_check(
+                &room_version,
+                &pdu,
+                None::<PduEvent>, // TODO: third_party_invite
+                |k, s| auth_events.get(&(k.clone(), s.to_owned())),
+            )
+            .map_err(|e| {
+                error!("{:?}", e);
+                Error::bad_database("Auth check failed.")
+            })?;
+
+            if !auth_check {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Event is not authorized.",
+                ));
+            }
+
+            // Hash and sign
+            let mut pdu_json =
+                utils::to_canonical_object(&pdu).expect("event is valid, we just created it");
+
+            pdu_json.remove("event_id");
+
+            // Add origin because synapse likes that (and it's required in the spec)
+            pdu_json.insert(
+                "origin".to_owned(),
+                to_canonical_value(db.globals.server_name())
+                    .expect("server name is a valid CanonicalJsonValue"),
+            );
+
+            ruma::signatures::hash_and_sign_event(
+                db.globals.server_name().as_str(),
+                db.globals.keypair(),
+                &mut pdu_json,
+                &room_version_id,
+            )
+            .expect("event is valid, we just created it");
+
+            let invite_room_state = db.rooms.calculate_invite_state(&pdu)?;
+
+            drop(state_lock);
+
+            (room_version_id, pdu_json, invite_room_state)
+        };
+
+        // Generate event id
+        let expected_event_id = format!(
+            "${}",
+            ruma::signatures::reference_hash(&pdu_json, &room_version_id)
+                .expect("ruma can calculate reference hashes")
+        );
+        let expected_event_id = <&EventId>::try_from(expected_event_id.as_str())
+            .expect("ruma's reference hashes are valid event ids");
+
+        let response = db
+            .sending
+            .send_federation_request(
+                &db.globals,
+                user_id.server_name(),
+                create_invite::v2::Request {
+                    room_id,
+                    event_id: expected_event_id,
+                    room_version: &room_version_id,
+                    event: &PduEvent::convert_to_outgoing_federation_event(pdu_json.clone()),
+                    invite_room_state: &invite_room_state,
+                },
+            )
+            .await?;
+
+        let pub_key_map = RwLock::new(BTreeMap::new());
+
+        // We do not add the event_id field to the pdu here because of signature and hashes checks
+        let (event_id, value) = match crate::pdu::gen_event_id_canonical_json(&response.event, &db)
+        {
+            Ok(t) => t,
+            Err(_) => {
+                // Event could not be converted to canonical json
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Could not convert event to canonical json.",
+                ));
+            }
+        };
+
+        if expected_event_id != event_id {
+            warn!("Server {} changed invite event, that's not allowed in the spec: ours: {:?}, theirs: {:?}", user_id.server_name(), pdu_json, value);
+        }
+
+        let origin: Box<ServerName> = serde_json::from_value(
+            serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Event needs an origin field.",
+            ))?)
+            .expect("CanonicalJson is valid json value"),
+        )
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
+
+        let pdu_id = server_server::handle_incoming_pdu(
+            &origin,
+            &event_id,
+            room_id,
+            value,
+            true,
+            db,
+            &pub_key_map,
+        )
+        .await
+        .map_err(|_| {
+            Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "Error while handling incoming PDU.",
+            )
+        })?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Could not accept incoming PDU as timeline event.",
+        ))?;
 
-            let create_event = db
-                .rooms
-                .room_state_get(room_id, &StateEventType::RoomCreate, "")?;
+        let servers = db
+            .rooms
+            .room_servers(room_id)
+            .filter_map(|r| r.ok())
+            .filter(|server| &**server != db.globals.server_name());
 
-            let create_event_content: Option<RoomCreateEventContent> = create_event
-                .as_ref()
-                .map(|create_event| {
-                    serde_json::from_str(create_event.content.get()).map_err(|e| {
-                        warn!("Invalid create event: {}", e);
-                        Error::bad_database("Invalid create event in db.")
-                    })
-                })
-                .transpose()?;
+        db.sending.send_pdu(servers, &pdu_id)?;
 
-            // If there was no create event yet, assume we are creating a room with the default
-            // version right now
-            let room_version_id = create_event_content
-                .map_or(db.globals.default_room_version(), |create_event| {
-                    create_event.room_version
-                });
-            let room_version =
-                RoomVersion::new(&room_version_id).expect("room version is supported");
+        return Ok(());
+    }
 
-            let content = to_raw_value(&RoomMemberEventContent {
-                avatar_url: None,
-                displayname: None,
-                is_direct: Some(is_direct),
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomMember,
+            content: to_raw_value(&RoomMemberEventContent {
                 membership: MembershipState::Invite,
+                displayname: db.users.displayname(user_id)?,
+                avatar_url: db.users.avatar_url(user_id)?,
+                is_direct: Some(is_direct),
                 third_party_invite: None,
-                blurhash: None,
+                blurhash: db.users.blurhash(user_id)?,
                 reason: None,
                 join_authorized_via_users_server: None,
             })
-            .expect("member event is valid value");
+            .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(user_id.to_string()),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        &state_lock,
+    )?;
 
-            let state_key = user_id.to_string();
-            let kind = StateEventType::RoomMember;
+    drop(state_lock);
 
-            let auth_events = db.rooms.get_auth_events(
-                room_id,
-                &kind.to_string().into(),
-                sender_user,
-                Some(&state_key),
-                &content,
-            )?;
+    Ok(())
+}
 
-            // Our depth is the maximum depth of prev_events + 1
-            let depth = prev_events
-                .iter()
-                .filter_map(|event_id| Some(db.rooms.get_pdu(event_id).ok()??.depth))
-                .max()
-                .unwrap_or_else(|| uint!(0))
-                + uint!(1);
+/// The response of an identity server to `POST /_matrix/identity/v2/store-invite`.
+#[derive(Deserialize)]
+struct StoredInvite {
+    token: String,
+    public_keys: Vec<PublicKey>,
+    display_name: String,
+}
+
+async fn third_party_invite_helper(
+    sender_user: &UserId,
+    invite: &IncomingInvite3pid,
+    room_id: &RoomId,
+    db: &Database,
+) -> Result<()> {
+    // The identity server is only contacted for users who may invite
+    if !db.rooms.is_joined(sender_user, room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You are not a member of this room.",
+        ));
+    }
+
+    let power_levels = client_server::room_power_levels(db, room_id)?;
+    if !may_invite(&power_levels, sender_user)
+        || !client_server::may_send_state_event(
+            &power_levels,
+            sender_user,
+            &RoomEventType::RoomThirdPartyInvite,
+        )
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to invite users to this room.",
+        ));
+    }
+
+    let url = Url::parse(&format!(
+        "https://{}/_matrix/identity/v2/store-invite",
+        invite.id_server
+    ))
+    .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid identity server."))?;
+
+    // The identity server is chosen by the client, it must not be in the server's own network
+    let forbidden =
+        || Error::BadRequest(ErrorKind::Forbidden, "This identity server can't be used.");
+    let host = url.host_str().ok_or_else(forbidden)?;
+    let ip = client_server::resolve_public_host(db, host)
+        .await?
+        .ok_or_else(forbidden)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    // The access token is only valid at the identity server the client chose
+    let response = db
+        .globals
+        .pinned_client(host, SocketAddr::new(ip, port))?
+        .post(url.clone())
+        .bearer_auth(&invite.id_access_token)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(
+            serde_json::to_vec(&json!({
+                "medium": invite.medium,
+                "address": invite.address,
+                "room_id": room_id,
+                "sender": sender_user,
+                "sender_display_name": db.users.displayname(sender_user)?,
+            }))
+            .expect("store-invite request can be serialized"),
+        )
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    let body = match response {
+        Ok(response) => response.bytes().await,
+        Err(e) => Err(e),
+    }
+    .map_err(|e| {
+        warn!(
+            "Failed to store third party invite at identity server: {}",
+            e
+        );
+        Error::BadServerResponse("Identity server did not store the invite.")
+    })?;
+
+    let stored: StoredInvite = serde_json::from_slice(&body).map_err(|_| {
+        Error::BadServerResponse("Invalid store-invite response from identity server.")
+    })?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomThirdPartyInvite,
+            content: to_raw_value(&third_party_invite_content(&stored)?)
+                .expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: Some(stored.token),
+            redacts: None,
+        },
+        sender_user,
+        room_id,
+        db,
+        &state_lock,
+    )?;
+
+    drop(state_lock);
+
+    Ok(())
+}
+
+/// The `m.room.third_party_invite` content for an invite the identity server stored.
+fn third_party_invite_content(stored: &StoredInvite) -> Result<serde_json::Value> {
+    let key = stored.public_keys.first().ok_or(Error::BadServerResponse(
+        "Identity server returned no public keys for the invite.",
+    ))?;
+
+    Ok(json!({
+        "display_name": stored.display_name,
+        "key_validity_url": key.key_validity_url,
+        "public_key": key.public_key,
+        "public_keys": stored.public_keys,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_guest_join, check_knock, check_member_of_allowed_room, check_not_banned,
+        check_server_acl, joined_members_from_state, may_invite, third_party_invite_content,
+        unsupported_room_version, verify_third_party_signed, StoredInvite,
+    };
+    use crate::Error;
+    use ruma::{
+        api::client::error::ErrorKind,
+        events::room::{
+            guest_access::GuestAccess,
+            member::{MembershipState, RoomMemberEventContent},
+            power_levels::RoomPowerLevelsEventContent,
+            server_acl::RoomServerAclEventContent,
+        },
+        int, room_id, server_name, user_id, RoomVersionId,
+    };
+
+    #[test]
+    fn unsupported_room_version_lists_supported_versions() {
+        let error = unsupported_room_version(
+            Some(&RoomVersionId::V1),
+            &[RoomVersionId::V6, RoomVersionId::V9],
+        );
+
+        match error {
+            Error::BadRequestDetailed(ErrorKind::UnsupportedRoomVersion, message) => {
+                assert!(message.contains("Room version 1 is not supported"));
+                assert!(message.contains("Supported versions: 6, 9"));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    fn member(membership: MembershipState, displayname: &str) -> RoomMemberEventContent {
+        let mut content = RoomMemberEventContent::new(membership);
+        content.displayname = Some(displayname.to_owned());
+        content
+    }
+
+    #[test]
+    fn joined_members_excludes_members_who_left() {
+        let joined = joined_members_from_state(vec![
+            (
+                "@alice:example.org".to_owned(),
+                member(MembershipState::Join, "Alice"),
+            ),
+            (
+                "@bob:example.org".to_owned(),
+                member(MembershipState::Leave, "Bob"),
+            ),
+            (
+                "@carol:example.org".to_owned(),
+                member(MembershipState::Invite, "Carol"),
+            ),
+        ]);
+
+        assert_eq!(joined.len(), 1);
+        assert_eq!(
+            joined[user_id!("@alice:example.org")]
+                .display_name
+                .as_deref(),
+            Some("Alice")
+        );
+        assert!(!joined.contains_key(user_id!("@bob:example.org")));
+    }
+
+    #[test]
+    fn guests_only_join_rooms_allowing_them() {
+        match check_guest_join(&GuestAccess::Forbidden) {
+            Err(Error::BadRequest(ErrorKind::GuestAccessForbidden, _)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert!(check_guest_join(&GuestAccess::CanJoin).is_ok());
+    }
+
+    #[test]
+    fn banned_user_gets_ban_reason() {
+        let mut ban = RoomMemberEventContent::new(MembershipState::Ban);
+        ban.reason = Some("spam".to_owned());
+
+        match check_not_banned(Some(&ban)) {
+            Err(Error::BadRequestDetailed(ErrorKind::Forbidden, message)) => {
+                assert_eq!(message, "You are banned from this room: spam")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert!(
+            check_not_banned(Some(&RoomMemberEventContent::new(MembershipState::Leave))).is_ok()
+        );
+        assert!(check_not_banned(None).is_ok());
+    }
+
+    #[test]
+    fn knocking_needs_knock_join_rule() {
+        let left = RoomMemberEventContent::new(MembershipState::Leave);
+
+        assert!(check_knock(Some("knock"), None).is_ok());
+        assert!(check_knock(Some("knock_restricted"), Some(&left)).is_ok());
+        assert!(matches!(
+            check_knock(Some("public"), None),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        assert!(matches!(
+            check_knock(None, None),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        for membership in [
+            MembershipState::Join,
+            MembershipState::Invite,
+            MembershipState::Ban,
+        ] {
+            assert!(check_knock(
+                Some("knock"),
+                Some(&RoomMemberEventContent::new(membership))
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn restricted_joins_need_an_allowed_room() {
+        let space = room_id!("!space:example.org");
+        let other = room_id!("!other:example.org");
+
+        assert!(check_member_of_allowed_room(&[other, space], |room| Ok(room == space)).is_ok());
+        assert!(matches!(
+            check_member_of_allowed_room(&[other], |room| Ok(room == space)),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        assert!(check_member_of_allowed_room(&[], |_| Ok(true)).is_err());
+    }
+
+    #[test]
+    fn join_authorisers_need_invite_power() {
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels
+            .users
+            .insert(user_id!("@mod:example.org").to_owned(), int!(50));
+
+        assert!(may_invite(&power_levels, user_id!("@mod:example.org")));
+        assert!(!may_invite(&power_levels, user_id!("@user:example.org")));
+
+        power_levels.invite = int!(0);
+        assert!(may_invite(&power_levels, user_id!("@user:example.org")));
+    }
+
+    #[test]
+    fn acl_denied_server_gets_acl_error() {
+        let acl = RoomServerAclEventContent::new(
+            false,
+            vec!["*".to_owned()],
+            vec!["evil.example.org".to_owned()],
+        );
+
+        match check_server_acl(Some(&acl), server_name!("evil.example.org")) {
+            Err(Error::BadRequest(ErrorKind::Forbidden, message)) => {
+                assert!(message.contains("server ACL"))
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert!(check_server_acl(Some(&acl), server_name!("example.org")).is_ok());
+        assert!(check_server_acl(None, server_name!("evil.example.org")).is_ok());
+    }
+
+    #[test]
+    fn third_party_invite_is_issued_and_signed_join_validated() {
+        use ruma::{
+            api::client::membership::IncomingThirdPartySigned,
+            events::room::third_party_invite::RoomThirdPartyInviteEventContent,
+            serde::{Base64, CanonicalJsonObject},
+            signatures::{sign_json, Ed25519KeyPair},
+        };
+        use serde_json::json;
+
+        let key_pair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "0".to_owned()).unwrap();
+        let inviter = user_id!("@alice:example.org");
+        let invitee = user_id!("@bob:example.org");
+
+        // What the identity server answers when the invite is stored
+        let stored: StoredInvite = serde_json::from_value(json!({
+            "token": "abc",
+            "public_keys": [{
+                "public_key": Base64::new(key_pair.public_key().to_vec()),
+                "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+            }],
+            "display_name": "b...@example.org",
+        }))
+        .unwrap();
+        let invite: RoomThirdPartyInviteEventContent =
+            serde_json::from_value(third_party_invite_content(&stored).unwrap()).unwrap();
+        assert_eq!(invite.display_name, "b...@example.org");
+
+        // What the identity server signs once bob binds the address
+        let mut signed: CanonicalJsonObject = serde_json::from_value(json!({
+            "mxid": invitee,
+            "sender": inviter,
+            "token": "abc",
+        }))
+        .unwrap();
+        sign_json("id.example.org", &key_pair, &mut signed).unwrap();
+        let signed_join = |mxid, sender| -> IncomingThirdPartySigned {
+            serde_json::from_value(json!({
+                "sender": sender,
+                "mxid": mxid,
+                "token": "abc",
+                "signatures": signed["signatures"],
+            }))
+            .unwrap()
+        };
+
+        assert!(verify_third_party_signed(
+            inviter,
+            &invite,
+            invitee,
+            &signed_join(invitee, inviter)
+        )
+        .is_ok());
+
+        // The sender is signed too, nobody else can claim the invite was theirs
+        let carol = user_id!("@carol:example.org");
+        assert!(
+            verify_third_party_signed(carol, &invite, invitee, &signed_join(invitee, carol))
+                .is_err()
+        );
+
+        // The token was signed for bob only
+        assert!(verify_third_party_signed(
+            inviter,
+            &invite,
+            inviter,
+            &signed_join(inviter, inviter)
+        )
+        .is_err());
+
+        // The signature doesn't come from the identity server of the invite
+        let other_key_pair =
+            Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), "0".to_owned()).unwrap();
+        let other_invite: RoomThirdPartyInviteEventContent = serde_json::from_value(json!({
+            "display_name": "b...@example.org",
+            "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+            "public_key": Base64::new(other_key_pair.public_key().to_vec()),
+        }))
+        .unwrap();
+        assert!(verify_third_party_signed(
+            inviter,
+            &other_invite,
+            invitee,
+            &signed_join(invitee, inviter)
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    async fn create_room(
+        db: &crate::Database,
+        room_id: &ruma::RoomId,
+        creator: &ruma::UserId,
+        join_rule: Option<ruma::events::room::join_rules::JoinRule>,
+    ) {
+        use crate::pdu::PduBuilder;
+        use ruma::events::{
+            room::{create::RoomCreateEventContent, join_rules::RoomJoinRulesEventContent},
+            RoomEventType,
+        };
+        use serde_json::value::to_raw_value;
+        use std::sync::Arc;
+
+        db.rooms
+            .get_or_create_shortroomid(room_id, &db.globals)
+            .unwrap();
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room_id.to_owned())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        let mut create = RoomCreateEventContent::new(creator.to_owned());
+        create.room_version = RoomVersionId::V9;
+
+        let mut events = vec![
+            (RoomEventType::RoomCreate, to_raw_value(&create).unwrap()),
+            (
+                RoomEventType::RoomMember,
+                to_raw_value(&RoomMemberEventContent::new(MembershipState::Join)).unwrap(),
+            ),
+        ];
+        if let Some(join_rule) = join_rule {
+            events.push((
+                RoomEventType::RoomJoinRules,
+                to_raw_value(&RoomJoinRulesEventContent::new(join_rule)).unwrap(),
+            ));
+        }
+
+        for (event_type, content) in events {
+            let state_key = match event_type {
+                RoomEventType::RoomMember => creator.to_string(),
+                _ => String::new(),
+            };
+            db.rooms
+                .build_and_append_pdu(
+                    PduBuilder {
+                        event_type,
+                        content,
+                        unsigned: None,
+                        state_key: Some(state_key),
+                        redacts: None,
+                    },
+                    creator,
+                    room_id,
+                    db,
+                    &state_lock,
+                )
+                .unwrap();
+        }
+    }
+
+    /// Joins a local room through `join_room_by_id_route`.
+    #[cfg(feature = "sqlite")]
+    async fn join(
+        db: &std::sync::Arc<tokio::sync::RwLock<crate::Database>>,
+        user_id: &ruma::UserId,
+        room_id: &ruma::RoomId,
+    ) -> crate::Result<()> {
+        use super::join_room_by_id_route;
+        use crate::{database::DatabaseGuard, Ruma};
+        use axum::extract::Extension;
+        use ruma::api::{client::membership::join_room_by_id, IncomingRequest};
+        use std::sync::Arc;
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri(format!("/_matrix/client/r0/rooms/{}/join", room_id))
+            .body(b"{}".to_vec())
+            .unwrap();
+        join_room_by_id_route(
+            DatabaseGuard::from(Arc::clone(db).read_owned().await),
+            Extension(Arc::clone(db)),
+            Ruma {
+                body: join_room_by_id::v3::IncomingRequest::try_from_http_request(
+                    request,
+                    &[room_id.as_str()],
+                )
+                .unwrap(),
+                sender_user: Some(user_id.to_owned()),
+                sender_device: None,
+                sender_servername: None,
+                json_body: None,
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .map(|_| ())
+    }
 
-            let mut unsigned = BTreeMap::new();
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn restricted_join_needs_member_of_allowed_room() {
+        use super::check_restricted_join;
+        use ruma::events::room::join_rules::{AllowRule, JoinRule, Restricted};
+
+        let db = crate::database::test_database("restricted-join").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let allowed = room_id!("!allowed:example.org");
+        let restricted = room_id!("!restricted:example.org");
+
+        create_room(&db, allowed, bob, None).await;
+        create_room(
+            &db,
+            restricted,
+            alice,
+            Some(JoinRule::Restricted(Restricted::new(vec![
+                AllowRule::room_membership(allowed.to_owned()),
+            ]))),
+        )
+        .await;
 
-            if let Some(prev_pdu) = db.rooms.room_state_get(room_id, &kind, &state_key)? {
-                unsigned.insert("prev_content".to_owned(), prev_pdu.content.clone());
-                unsigned.insert(
-                    "prev_sender".to_owned(),
-                    to_raw_value(&prev_pdu.sender).expect("UserId is valid"),
-                );
-            }
+        // Members of the allowed room may join, but someone has to authorise it
+        assert!(check_restricted_join(&db, restricted, bob).unwrap());
 
-            let pdu = PduEvent {
-                event_id: ruma::event_id!("$thiswillbefilledinlater").into(),
-                room_id: room_id.to_owned(),
-                sender: sender_user.to_owned(),
-                origin_server_ts: utils::millis_since_unix_epoch()
-                    .try_into()
-                    .expect("time is valid"),
-                kind: kind.to_string().into(),
-                content,
-                state_key: Some(state_key),
-                prev_events,
-                depth,
-                auth_events: auth_events
-                    .iter()
-                    .map(|(_, pdu)| pdu.event_id.clone())
-                    .collect(),
-                redacts: None,
-                unsigned: if unsigned.is_empty() {
-                    None
-                } else {
-                    Some(to_raw_value(&unsigned).expect("to_raw_value always works"))
-                },
-                hashes: EventHash {
-                    sha256: "aaa".to_owned(),
-                },
-                signatures: None,
-            };
+        // Everyone else is rejected
+        match check_restricted_join(&db, restricted, carol) {
+            Err(Error::BadRequest(ErrorKind::Forbidden, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
 
-            let auth_check = state_res::auth_check(
-                &room_version,
-                &pdu,
-                None::<PduEvent>, // TODO: third_party_invite
-                |k, s| auth_events.get(&(k.clone(), s.to_owned())),
-            )
-            .map_err(|e| {
-                error!("{:?}", e);
-                Error::bad_database("Auth check failed.")
-            })?;
+        // Joined users and rooms without the join rule need no authorisation
+        assert!(!check_restricted_join(&db, restricted, alice).unwrap());
+        assert!(!check_restricted_join(&db, allowed, carol).unwrap());
+    }
 
-            if !auth_check {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "Event is not authorized.",
-                ));
-            }
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn only_members_may_invite() {
+        use super::invite_helper;
 
-            // Hash and sign
-            let mut pdu_json =
-                utils::to_canonical_object(&pdu).expect("event is valid, we just created it");
+        let mut config = crate::database::test_config("invite-members");
+        config.invite_only_from_members = true;
+        let db = crate::Database::load_or_create(&config).await.unwrap();
+        let db = db.read().await;
 
-            pdu_json.remove("event_id");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let room = room_id!("!room:example.org");
 
-            // Add origin because synapse likes that (and it's required in the spec)
-            pdu_json.insert(
-                "origin".to_owned(),
-                to_canonical_value(db.globals.server_name())
-                    .expect("server name is a valid CanonicalJsonValue"),
-            );
+        create_room(&db, room, alice, None).await;
 
-            ruma::signatures::hash_and_sign_event(
-                db.globals.server_name().as_str(),
-                db.globals.keypair(),
-                &mut pdu_json,
-                &room_version_id,
-            )
-            .expect("event is valid, we just created it");
+        match invite_helper(carol, bob, room, &db, false).await {
+            Err(Error::BadRequest(ErrorKind::Forbidden, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(!db.rooms.is_invited(bob, room).unwrap());
 
-            let invite_room_state = db.rooms.calculate_invite_state(&pdu)?;
+        invite_helper(alice, bob, room, &db, false).await.unwrap();
+        assert!(db.rooms.is_invited(bob, room).unwrap());
+    }
 
-            drop(state_lock);
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn third_party_invites_only_reach_public_identity_servers() {
+        use super::third_party_invite_helper;
+        use ruma::api::client::membership::IncomingInvite3pid;
+        use serde_json::json;
+
+        let db = crate::database::test_database("third-party-invite").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        let carol = user_id!("@carol:example.org");
+        let room = room_id!("!room:example.org");
+        create_room(&db, room, alice, None).await;
+
+        let invite = |id_server: &str| {
+            serde_json::from_value::<IncomingInvite3pid>(json!({
+                "id_server": id_server,
+                "id_access_token": "token",
+                "medium": "email",
+                "address": "bob@example.org",
+            }))
+            .unwrap()
+        };
 
-            (room_version_id, pdu_json, invite_room_state)
+        // Non-members are rejected before the identity server is contacted
+        match third_party_invite_helper(carol, &invite("id.example.org"), room, &db).await {
+            Err(Error::BadRequest(ErrorKind::Forbidden, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        for id_server in ["127.0.0.1", "10.0.0.1:8090", "[::1]"] {
+            match third_party_invite_helper(alice, &invite(id_server), room, &db).await {
+                Err(Error::BadRequest(ErrorKind::Forbidden, _)) => {}
+                r => panic!("unexpected result for {}: {:?}", id_server, r),
+            }
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn partial_state_join_is_filled_in_background() {
+        use super::{fill_partial_state, is_other_member_event};
+        use crate::pdu::{PduBuilder, PduEvent};
+        use ruma::{
+            api::federation::{
+                discovery::{ServerSigningKeys, VerifyKey},
+                membership::create_join_event::RoomState,
+            },
+            events::{room::join_rules::JoinRule, RoomEventType, StateEventType},
+            serde::Base64,
+            MilliSecondsSinceUnixEpoch,
         };
+        use serde_json::value::to_raw_value;
+        use std::{collections::BTreeMap, sync::Arc};
 
-        // Generate event id
-        let expected_event_id = format!(
-            "${}",
-            ruma::signatures::reference_hash(&pdu_json, &room_version_id)
-                .expect("ruma can calculate reference hashes")
+        let db = crate::database::test_database("partial-state-join").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room = room_id!("!partial:example.org");
+
+        create_room(&db, room, alice, Some(JoinRule::Public)).await;
+
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(room.to_owned())
+                .or_default(),
         );
-        let expected_event_id = <&EventId>::try_from(expected_event_id.as_str())
-            .expect("ruma's reference hashes are valid event ids");
+        let state_lock = mutex_state.lock().await;
+        db.rooms
+            .build_and_append_pdu(
+                PduBuilder {
+                    event_type: RoomEventType::RoomMember,
+                    content: to_raw_value(&RoomMemberEventContent::new(MembershipState::Join))
+                        .unwrap(),
+                    unsigned: None,
+                    state_key: Some(bob.to_string()),
+                    redacts: None,
+                },
+                bob,
+                room,
+                &db,
+                &state_lock,
+            )
+            .unwrap();
+        drop(state_lock);
 
-        let response = db
-            .sending
-            .send_federation_request(
-                &db.globals,
-                user_id.server_name(),
-                create_invite::v2::Request {
-                    room_id,
-                    event_id: expected_event_id,
-                    room_version: &room_version_id,
-                    event: &PduEvent::convert_to_outgoing_federation_event(pdu_json.clone()),
-                    invite_room_state: &invite_room_state,
+        let bob_join = db
+            .rooms
+            .room_state_get_id(room, &StateEventType::RoomMember, bob.as_str())
+            .unwrap()
+            .unwrap();
+        let bob_join = PduEvent::convert_to_outgoing_federation_event(
+            db.rooms.get_pdu_json(&bob_join).unwrap().unwrap(),
+        );
+        assert!(is_other_member_event(&bob_join, alice));
+        assert!(!is_other_member_event(&bob_join, bob));
+
+        // Pretend alice joined over federation and bob's membership was deferred
+        let bob_key = db
+            .rooms
+            .get_shortstatekey(&StateEventType::RoomMember, bob.as_str())
+            .unwrap()
+            .unwrap();
+        let partial_state = db
+            .rooms
+            .state_full_ids(db.rooms.current_shortstatehash(room).unwrap().unwrap())
+            .unwrap()
+            .into_iter()
+            .filter(|(shortstatekey, _)| *shortstatekey != bob_key)
+            .map(|(k, id)| db.rooms.compress_state_event(k, &id, &db.globals).unwrap())
+            .collect();
+        db.rooms.force_state(room, partial_state, &db).unwrap();
+        let alice_join = db
+            .rooms
+            .room_state_get_id(room, &StateEventType::RoomMember, alice.as_str())
+            .unwrap()
+            .unwrap();
+        db.rooms
+            .mark_partial_state(room, server_name!("remote.example.org"), &alice_join)
+            .unwrap();
+        assert!(db
+            .rooms
+            .room_state_get(room, &StateEventType::RoomMember, bob.as_str())
+            .unwrap()
+            .is_none());
+
+        // Knowing our own key, nothing has to be fetched over federation
+        let mut verify_keys = BTreeMap::new();
+        verify_keys.insert(
+            format!("ed25519:{}", db.globals.keypair().version())
+                .try_into()
+                .unwrap(),
+            VerifyKey {
+                key: Base64::new(db.globals.keypair().public_key().to_vec()),
+            },
+        );
+        db.globals
+            .add_signing_key(
+                db.globals.server_name(),
+                ServerSigningKeys {
+                    server_name: db.globals.server_name().to_owned(),
+                    verify_keys,
+                    old_verify_keys: BTreeMap::new(),
+                    signatures: BTreeMap::new(),
+                    valid_until_ts: MilliSecondsSinceUnixEpoch::now(),
                 },
             )
-            .await?;
+            .unwrap();
+
+        fill_partial_state(
+            &db,
+            room,
+            Some(RoomState {
+                state: vec![bob_join],
+                auth_chain: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap();
 
-        let pub_key_map = RwLock::new(BTreeMap::new());
+        assert!(db
+            .rooms
+            .room_state_get(room, &StateEventType::RoomMember, bob.as_str())
+            .unwrap()
+            .is_some());
+        assert!(db.rooms.partial_state(room).unwrap().is_none());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn rejoining_creates_no_event() {
+        use ruma::events::{room::join_rules::JoinRule, StateEventType};
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("rejoin").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room = room_id!("!room:example.org");
+        create_room(&*db.read().await, room, alice, Some(JoinRule::Public)).await;
+
+        let state = || {
+            let db = Arc::clone(&db);
+            async move {
+                let db = db.read().await;
+                (
+                    db.rooms
+                        .room_state_get_id(room, &StateEventType::RoomMember, bob.as_str())
+                        .unwrap()
+                        .unwrap(),
+                    db.rooms.current_shortstatehash(room).unwrap(),
+                )
+            }
+        };
+
+        join(&db, bob, room).await.unwrap();
+        let first_join = state().await;
+        assert!(db.read().await.rooms.is_joined(bob, room).unwrap());
+
+        // The second join neither adds a member event nor changes the room state
+        join(&db, bob, room).await.unwrap();
+        assert_eq!(state().await, first_join);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn joined_rooms_and_members_drop_users_who_left() {
+        use super::{joined_members_route, joined_rooms_route};
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Ruma,
+        };
+        use ruma::{
+            api::{
+                client::membership::{joined_members, joined_rooms},
+                IncomingRequest,
+            },
+            UserId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("joined-members").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let room = room_id!("!room:example.org");
 
-        // We do not add the event_id field to the pdu here because of signature and hashes checks
-        let (event_id, value) = match crate::pdu::gen_event_id_canonical_json(&response.event, &db)
         {
-            Ok(t) => t,
-            Err(_) => {
-                // Event could not be converted to canonical json
-                return Err(Error::BadRequest(
-                    ErrorKind::InvalidParam,
-                    "Could not convert event to canonical json.",
-                ));
+            let db = db.read().await;
+            test_room(&db, room, alice).await;
+            for (sender, state_key, content) in [
+                (
+                    alice,
+                    alice,
+                    json!({ "membership": "join", "displayname": "Alice" }),
+                ),
+                (alice, carol, json!({ "membership": "invite" })),
+            ] {
+                test_send(
+                    &db,
+                    room,
+                    sender,
+                    "m.room.member",
+                    Some(state_key.as_str()),
+                    content,
+                )
+                .await
+                .unwrap();
+            }
+            test_send(
+                &db,
+                room,
+                alice,
+                "m.room.join_rules",
+                Some(""),
+                json!({ "join_rule": "public" }),
+            )
+            .await
+            .unwrap();
+            for membership in ["join", "leave"] {
+                test_send(
+                    &db,
+                    room,
+                    bob,
+                    "m.room.member",
+                    Some(bob.as_str()),
+                    json!({ "membership": membership }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        fn ruma<T>(body: T, user_id: &UserId) -> Ruma<T> {
+            Ruma {
+                body,
+                sender_user: Some(user_id.to_owned()),
+                sender_device: None,
+                sender_servername: None,
+                json_body: None,
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            }
+        }
+        let joined_rooms = |user_id: &'static UserId| {
+            let db = Arc::clone(&db);
+            async move {
+                let request = http::Request::builder()
+                    .uri("/_matrix/client/r0/joined_rooms")
+                    .body(Vec::<u8>::new())
+                    .unwrap();
+                joined_rooms_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    ruma(
+                        joined_rooms::v3::Request::try_from_http_request::<_, String>(request, &[])
+                            .unwrap(),
+                        user_id,
+                    ),
+                )
+                .await
+                .unwrap()
+                .joined_rooms
+            }
+        };
+        let joined_members = |user_id: &'static UserId| {
+            let db = Arc::clone(&db);
+            async move {
+                let request = http::Request::builder()
+                    .uri("/_matrix/client/r0/rooms/%21room%3Aexample.org/joined_members")
+                    .body(Vec::<u8>::new())
+                    .unwrap();
+                joined_members_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    ruma(
+                        joined_members::v3::IncomingRequest::try_from_http_request(
+                            request,
+                            &["!room:example.org"],
+                        )
+                        .unwrap(),
+                        user_id,
+                    ),
+                )
+                .await
             }
         };
 
-        if expected_event_id != event_id {
-            warn!("Server {} changed invite event, that's not allowed in the spec: ours: {:?}, theirs: {:?}", user_id.server_name(), pdu_json, value);
+        assert_eq!(joined_rooms(alice).await, vec![room.to_owned()]);
+        assert!(joined_rooms(bob).await.is_empty());
+        assert!(joined_rooms(carol).await.is_empty());
+
+        let joined = joined_members(alice).await.unwrap().joined;
+        assert_eq!(joined.keys().map(|u| &**u).collect::<Vec<_>>(), vec![alice]);
+        assert_eq!(joined[alice].display_name.as_deref(), Some("Alice"));
+
+        match joined_members(bob).await {
+            Err(Error::BadRequest(ErrorKind::Forbidden, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
         }
+    }
 
-        let origin: Box<ServerName> = serde_json::from_value(
-            serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Event needs an origin field.",
-            ))?)
-            .expect("CanonicalJson is valid json value"),
-        )
-        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Origin field is invalid."))?;
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn guests_only_join_rooms_that_allow_it() {
+        use crate::database::test_send;
+        use ruma::events::room::join_rules::JoinRule;
+        use serde_json::json;
 
-        let pdu_id = server_server::handle_incoming_pdu(
-            &origin,
-            &event_id,
-            room_id,
-            value,
-            true,
-            db,
-            &pub_key_map,
-        )
-        .await
-        .map_err(|_| {
-            Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Error while handling incoming PDU.",
-            )
-        })?
-        .ok_or(Error::BadRequest(
-            ErrorKind::InvalidParam,
-            "Could not accept incoming PDU as timeline event.",
-        ))?;
+        let db = crate::database::test_database("guest-join").await;
+        let alice = user_id!("@alice:example.org");
+        let guest = user_id!("@guest:example.org");
+        let forbidden = room_id!("!forbidden:example.org");
+        let open = room_id!("!open:example.org");
 
-        let servers = db
-            .rooms
-            .room_servers(room_id)
-            .filter_map(|r| r.ok())
-            .filter(|server| &**server != db.globals.server_name());
+        {
+            let db = db.read().await;
+            db.users.create(guest, None, &db.globals).unwrap();
+            db.users.set_guest(guest).unwrap();
+
+            create_room(&db, forbidden, alice, Some(JoinRule::Public)).await;
+            create_room(&db, open, alice, Some(JoinRule::Public)).await;
+            test_send(
+                &db,
+                open,
+                alice,
+                "m.room.guest_access",
+                Some(""),
+                json!({ "guest_access": "can_join" }),
+            )
+            .await
+            .unwrap();
+        }
 
-        db.sending.send_pdu(servers, &pdu_id)?;
+        match join(&db, guest, forbidden).await {
+            Err(Error::BadRequest(ErrorKind::GuestAccessForbidden, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        join(&db, guest, open).await.unwrap();
 
-        return Ok(());
+        let db = db.read().await;
+        assert!(!db.rooms.is_joined(guest, forbidden).unwrap());
+        assert!(db.rooms.is_joined(guest, open).unwrap());
     }
 
-    let mutex_state = Arc::clone(
-        db.globals
-            .roomid_mutex_state
-            .write()
-            .unwrap()
-            .entry(room_id.to_owned())
-            .or_default(),
-    );
-    let state_lock = mutex_state.lock().await;
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn joins_fail_with_specific_errors() {
+        use crate::database::test_send;
+        use ruma::events::room::join_rules::JoinRule;
+        use serde_json::json;
 
-    db.rooms.build_and_append_pdu(
-        PduBuilder {
-            event_type: RoomEventType::RoomMember,
-            content: to_raw_value(&RoomMemberEventContent {
-                membership: MembershipState::Invite,
-                displayname: db.users.displayname(user_id)?,
-                avatar_url: db.users.avatar_url(user_id)?,
-                is_direct: Some(is_direct),
-                third_party_invite: None,
-                blurhash: db.users.blurhash(user_id)?,
-                reason: None,
-                join_authorized_via_users_server: None,
-            })
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some(user_id.to_string()),
-            redacts: None,
-        },
-        sender_user,
-        room_id,
-        db,
-        &state_lock,
-    )?;
+        let db = crate::database::test_database("join-errors").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let banned = room_id!("!banned:example.org");
+        let denied = room_id!("!denied:example.org");
 
-    drop(state_lock);
+        {
+            let db = db.read().await;
+            create_room(&db, banned, alice, Some(JoinRule::Public)).await;
+            test_send(
+                &db,
+                banned,
+                alice,
+                "m.room.member",
+                Some(bob.as_str()),
+                json!({ "membership": "ban", "reason": "Spam" }),
+            )
+            .await
+            .unwrap();
+
+            create_room(&db, denied, alice, Some(JoinRule::Public)).await;
+            test_send(
+                &db,
+                denied,
+                alice,
+                "m.room.server_acl",
+                Some(""),
+                json!({ "allow": ["*"], "deny": ["example.org"], "allow_ip_literals": false }),
+            )
+            .await
+            .unwrap();
+        }
 
-    Ok(())
+        match join(&db, bob, banned).await {
+            Err(Error::BadRequestDetailed(ErrorKind::Forbidden, message)) => {
+                assert_eq!(message, "You are banned from this room: Spam");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match join(&db, carol, denied).await {
+            Err(Error::BadRequest(ErrorKind::Forbidden, message)) => {
+                assert!(message.contains("server ACL"));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match join(&db, carol, room_id!("!unknown:example.org")).await {
+            Err(Error::BadRequest(ErrorKind::NotFound, _)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
 }
@@ -2,9 +2,11 @@ use crate::{database::DatabaseGuard, pdu::PduBuilder, utils, Error, Result, Ruma
 use ruma::{
     api::client::{
         error::ErrorKind,
+        filter::LazyLoadOptions,
         message::{get_message_events, send_message_event},
     },
-    events::{RoomEventType, StateEventType},
+    events::{room::power_levels::RoomPowerLevelsEventContent, RoomEventType, StateEventType},
+    UserId,
 };
 use std::{
     collections::{BTreeMap, HashSet},
@@ -18,6 +20,8 @@ use std::{
 /// - Is a NOOP if the txn id was already used before and returns the same event id again
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
+/// - Rejects events in tombstoned rooms with the replacement room, unless the sender replaced the
+/// room or may do so
 pub async fn send_message_event_route(
     db: DatabaseGuard,
     body: Ruma<send_message_event::v3::IncomingRequest>,
@@ -35,6 +39,28 @@ pub async fn send_message_event_route(
     );
     let state_lock = mutex_state.lock().await;
 
+    // Point clients to the new room instead of letting them talk into the old one. Whoever
+    // replaced the room may still have to post there, e.g. to explain the move.
+    if let Some(replacement_room) = db.rooms.tombstone_replacement(&body.room_id)? {
+        let tombstone_sender = db
+            .rooms
+            .room_state_get(&body.room_id, &StateEventType::RoomTombstone, "")?
+            .map(|pdu| pdu.sender.clone());
+        let power_levels = db
+            .rooms
+            .room_state_get(&body.room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|pdu| {
+                serde_json::from_str::<RoomPowerLevelsEventContent>(pdu.content.get())
+                    .map_err(|_| Error::bad_database("Invalid power levels event in database."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        if !may_send_to_replaced_room(tombstone_sender.as_deref(), &power_levels, sender_user) {
+            return Err(Error::RoomReplaced(replacement_room));
+        }
+    }
+
     // Forbid m.room.encrypted if encryption is disabled
     if RoomEventType::RoomEncrypted == body.event_type.to_string().into()
         && !db.globals.allow_encryption()
@@ -106,6 +132,11 @@ pub async fn send_message_event_route(
 ///
 /// - Only works if the user is joined (TODO: always allow, but only show events where the user was
 /// joined, depending on history_visibility)
+/// - Guests additionally need the room to allow guest access or be world readable
+/// - The limit is capped at the configured `max_pagination_limit`
+/// - Relations are bundled with the events
+/// - If the filter enables lazy loading: Returns the member events of the senders in the chunk,
+/// members this device already got are skipped unless `include_redundant_members` is set
 pub async fn get_message_events_route(
     db: DatabaseGuard,
     body: Ruma<get_message_events::v3::IncomingRequest>,
@@ -120,6 +151,13 @@ pub async fn get_message_events_route(
         ));
     }
 
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
+        ));
+    }
+
     let from = match body.from.clone() {
         Some(from) => from
             .parse()
@@ -136,13 +174,20 @@ pub async fn get_message_events_route(
     db.rooms
         .lazy_load_confirm_delivery(sender_user, sender_device, &body.room_id, from)?;
 
-    // Use limit or else 10
-    let limit = body.limit.try_into().map_or(10_usize, |l: u32| l as usize);
+    let limit = utils::clamp_limit(body.limit.into(), db.globals.max_pagination_limit());
 
     let next_token;
 
     let mut resp = get_message_events::v3::Response::new();
 
+    let (lazy_load_enabled, lazy_load_send_redundant) = match &body.filter.lazy_load_options {
+        LazyLoadOptions::Enabled {
+            include_redundant_members,
+        } => (true, *include_redundant_members),
+        _ => (false, false),
+    };
+
+    // Only members that sent events in this chunk, the room state itself stays complete
     let mut lazy_loaded = HashSet::new();
 
     match body.dir {
@@ -162,12 +207,15 @@ pub async fn get_message_events_route(
                 .collect();
 
             for (_, event) in &events_after {
-                if !db.rooms.lazy_load_was_sent_before(
-                    sender_user,
-                    sender_device,
-                    &body.room_id,
-                    &event.sender,
-                )? {
+                if lazy_load_enabled
+                    && (lazy_load_send_redundant
+                        || !db.rooms.lazy_load_was_sent_before(
+                            sender_user,
+                            sender_device,
+                            &body.room_id,
+                            &event.sender,
+                        )?)
+                {
                     lazy_loaded.insert(event.sender.clone());
                 }
             }
@@ -176,8 +224,12 @@ pub async fn get_message_events_route(
 
             let events_after: Vec<_> = events_after
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect();
+                .map(|(_, pdu)| {
+                    db.rooms
+                        .bundle_relations(pdu)
+                        .map(|pdu| pdu.to_room_event())
+                })
+                .collect::<Result<_>>()?;
 
             resp.start = from.to_string();
             resp.end = next_token.map(|count| count.to_string());
@@ -199,12 +251,15 @@ pub async fn get_message_events_route(
                 .collect();
 
             for (_, event) in &events_before {
-                if !db.rooms.lazy_load_was_sent_before(
-                    sender_user,
-                    sender_device,
-                    &body.room_id,
-                    &event.sender,
-                )? {
+                if lazy_load_enabled
+                    && (lazy_load_send_redundant
+                        || !db.rooms.lazy_load_was_sent_before(
+                            sender_user,
+                            sender_device,
+                            &body.room_id,
+                            &event.sender,
+                        )?)
+                {
                     lazy_loaded.insert(event.sender.clone());
                 }
             }
@@ -213,8 +268,12 @@ pub async fn get_message_events_route(
 
             let events_before: Vec<_> = events_before
                 .into_iter()
-                .map(|(_, pdu)| pdu.to_room_event())
-                .collect();
+                .map(|(_, pdu)| {
+                    db.rooms
+                        .bundle_relations(pdu)
+                        .map(|pdu| pdu.to_room_event())
+                })
+                .collect::<Result<_>>()?;
 
             resp.start = from.to_string();
             resp.end = next_token.map(|count| count.to_string());
@@ -232,7 +291,7 @@ pub async fn get_message_events_route(
         }
     }
 
-    if let Some(next_token) = next_token {
+    if let Some(next_token) = next_token.filter(|_| lazy_load_enabled) {
         db.rooms.lazy_load_mark_sent(
             sender_user,
             sender_device,
@@ -244,3 +303,107 @@ pub async fn get_message_events_route(
 
     Ok(resp)
 }
+
+/// Whether the user may still send to a replaced room: the sender of the tombstone and the room
+/// admins who may send one.
+fn may_send_to_replaced_room(
+    tombstone_sender: Option<&UserId>,
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+) -> bool {
+    tombstone_sender == Some(user_id) || super::room::may_send_tombstone(power_levels, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::may_send_to_replaced_room;
+    use ruma::{events::room::power_levels::RoomPowerLevelsEventContent, int, user_id};
+
+    #[test]
+    fn only_upgraders_and_admins_send_to_replaced_rooms() {
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(bob.to_owned(), int!(100));
+
+        // The tombstone sender, e.g. after losing their power level
+        assert!(may_send_to_replaced_room(Some(alice), &power_levels, alice));
+        // Admins of the room
+        assert!(may_send_to_replaced_room(Some(alice), &power_levels, bob));
+        // Everyone else is pointed to the new room
+        assert!(!may_send_to_replaced_room(
+            Some(alice),
+            &power_levels,
+            carol
+        ));
+        assert!(!may_send_to_replaced_room(None, &power_levels, carol));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn over_max_limit_is_clamped() {
+        use super::get_message_events_route;
+        use crate::{
+            database::{test_config, test_room, test_send, DatabaseGuard},
+            Database, Ruma,
+        };
+        use ruma::{
+            api::{client::message::get_message_events, IncomingRequest},
+            device_id, room_id,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let mut config = test_config("messages-limit");
+        config.max_pagination_limit = 3;
+        let db = Database::load_or_create(&config).await.unwrap();
+
+        let alice = user_id!("@alice:example.org");
+        let room = room_id!("!room:example.org");
+        {
+            let db = db.read().await;
+            test_room(&db, room, alice).await;
+            for i in 0..5 {
+                test_send(
+                    &db,
+                    room,
+                    alice,
+                    "m.room.message",
+                    None,
+                    json!({ "msgtype": "m.text", "body": i.to_string() }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let request = http::Request::builder()
+            .uri("/_matrix/client/r0/rooms/%21room%3Aexample.org/messages?dir=b&limit=1000")
+            .body(Vec::<u8>::new())
+            .unwrap();
+        let response = get_message_events_route(
+            DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+            Ruma {
+                body: get_message_events::v3::IncomingRequest::try_from_http_request(
+                    request,
+                    &["!room:example.org"],
+                )
+                .unwrap(),
+                sender_user: Some(alice.to_owned()),
+                sender_device: Some(device_id!("ABCDEF").to_owned()),
+                sender_servername: None,
+                json_body: None,
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.chunk.len(), 3);
+        assert!(response.end.is_some());
+    }
+}
@@ -0,0 +1,56 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::IntoResponse,
+};
+use ruma::api::client::error::ErrorKind;
+
+use crate::{
+    database::{globals::MetricsGauges, DatabaseGuard},
+    Error, Result,
+};
+
+/// # `GET /metrics`
+///
+/// Returns server metrics in the Prometheus text format.
+///
+/// - Only available if `metrics_token` is set
+/// - The token has to be sent in an `Authorization: Bearer` header
+pub async fn metrics_route(db: DatabaseGuard, headers: HeaderMap) -> Result<impl IntoResponse> {
+    let token = db.globals.metrics_token().ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "Metrics are disabled.",
+    ))?;
+
+    let sent_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingToken,
+            "Missing metrics token.",
+        ))?;
+
+    // Don't let response times reveal how much of the token was right
+    if ring::constant_time::verify_slices_are_equal(sent_token.as_bytes(), token.as_bytes())
+        .is_err()
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Invalid metrics token.",
+        ));
+    }
+
+    let gauges = MetricsGauges {
+        active_syncs: db.globals.active_syncs.total(),
+        federation_queue: db.sending.federation_queue_len(),
+        local_users: db.users.list_local_users()?.len(),
+    };
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        db.globals.metrics.render(&gauges),
+    ))
+}
@@ -11,15 +11,18 @@ mod keys;
 mod media;
 mod membership;
 mod message;
+mod metrics;
 mod presence;
 mod profile;
 mod push;
 mod read_marker;
 mod redact;
+mod rendezvous;
 mod report;
 mod room;
 mod search;
 mod session;
+mod space;
 mod state;
 mod sync;
 mod tag;
@@ -43,15 +46,18 @@ pub use keys::*;
 pub use media::*;
 pub use membership::*;
 pub use message::*;
+pub use metrics::*;
 pub use presence::*;
 pub use profile::*;
 pub use push::*;
 pub use read_marker::*;
 pub use redact::*;
+pub use rendezvous::*;
 pub use report::*;
 pub use room::*;
 pub use search::*;
 pub use session::*;
+pub use space::*;
 pub use state::*;
 pub use sync::*;
 pub use tag::*;
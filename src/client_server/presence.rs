@@ -1,16 +1,44 @@
-use crate::{database::DatabaseGuard, utils, Result, Ruma};
-use ruma::api::client::presence::{get_presence, set_presence};
-use std::time::Duration;
+use crate::{
+    database::{globals::PresenceFlush, DatabaseGuard},
+    utils, Database, Error, Result, Ruma,
+};
+use axum::extract::Extension;
+use ruma::{
+    api::{
+        client::{
+            error::ErrorKind,
+            presence::{get_presence, set_presence},
+        },
+        federation::transactions::edu::{Edu, PresenceContent, PresenceUpdate},
+    },
+    presence::PresenceState,
+    uint, ServerName,
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::warn;
 
 /// # `PUT /_matrix/client/r0/presence/{userId}/status`
 ///
 /// Sets the presence state of the sender user.
+///
+/// - Other servers get the new state in batches, see `presence_federation_interval_secs`
+/// - Does nothing if `allow_presence` is disabled
 pub async fn set_presence_route(
     db: DatabaseGuard,
+    Extension(db_lock): Extension<Arc<RwLock<Database>>>,
     body: Ruma<set_presence::v3::IncomingRequest>,
 ) -> Result<set_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if !db.globals.allow_presence() {
+        return Ok(set_presence::v3::Response {});
+    }
+
     for room_id in db.rooms.rooms_joined(sender_user) {
         let room_id = room_id?;
 
@@ -36,22 +64,118 @@ pub async fn set_presence_route(
         )?;
     }
 
+    let mut update = PresenceUpdate::new(sender_user.clone(), body.presence.clone(), uint!(0));
+    update.status_msg = body.status_msg.clone();
+    update.currently_active = body.presence == PresenceState::Online;
+
+    queue_presence_update(db_lock, &db, update);
+
     db.flush()?;
 
     Ok(set_presence::v3::Response {})
 }
 
+/// Queues a presence update of a local user for other servers and schedules sending it.
+pub(crate) fn queue_presence_update(
+    db_lock: Arc<RwLock<Database>>,
+    db: &Database,
+    update: PresenceUpdate,
+) {
+    if let PresenceFlush::Schedule {
+        mut delay,
+        generation,
+    } = db.globals.presence_batcher.queue(update, Instant::now())
+    {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(delay).await;
+
+                let db = db_lock.read().await;
+                let (updates, next) = db
+                    .globals
+                    .presence_batcher
+                    .take_due(Instant::now(), generation);
+                if let Err(e) = send_presence_edus(&db, updates) {
+                    warn!("Failed to send presence updates: {}", e);
+                }
+
+                match next {
+                    Some(next) => delay = next,
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+/// Sends one presence EDU per server with the updates of all users that share a room with it.
+fn send_presence_edus(db: &Database, updates: Vec<PresenceUpdate>) -> Result<()> {
+    let mut batches: HashMap<Box<ServerName>, Vec<PresenceUpdate>> = HashMap::new();
+
+    for mut update in updates {
+        let mut servers = Vec::new();
+        let mut last_active_ago = None;
+        for room_id in db.rooms.rooms_joined(&update.user_id) {
+            let room_id = room_id?;
+
+            // The update may have waited for a while, so this is calculated when it is sent
+            if last_active_ago.is_none() {
+                last_active_ago = db
+                    .rooms
+                    .edus
+                    .get_last_presence_event(&update.user_id, &room_id)?
+                    // Online users are active right now
+                    .map(|presence| presence.content.last_active_ago.unwrap_or_default());
+            }
+
+            servers.extend(
+                db.rooms
+                    .room_servers(&room_id)
+                    .filter_map(|r| r.ok())
+                    .filter(|server| &**server != db.globals.server_name()),
+            );
+        }
+        servers.sort_unstable();
+        servers.dedup();
+
+        update.last_active_ago = last_active_ago.unwrap_or_default();
+
+        for server in servers {
+            batches.entry(server).or_default().push(update.clone());
+        }
+    }
+
+    for (server, push) in batches {
+        let edu = Edu::Presence(PresenceContent::new(push));
+        db.sending.send_reliable_edu(
+            &server,
+            serde_json::to_vec(&edu).expect("Presence EDU can be serialized"),
+            db.globals.next_count()?,
+        )?;
+    }
+
+    Ok(())
+}
+
 /// # `GET /_matrix/client/r0/presence/{userId}/status`
 ///
 /// Gets the presence state of the given user.
 ///
 /// - Only works if you share a room with the user
+/// - Fails if `allow_presence` is disabled
 pub async fn get_presence_route(
     db: DatabaseGuard,
     body: Ruma<get_presence::v3::IncomingRequest>,
 ) -> Result<get_presence::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    if !db.globals.allow_presence() {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Presence is disabled on this server.",
+        ));
+    }
+
     let mut presence_event = None;
 
     for room_id in db
@@ -63,7 +187,7 @@ pub async fn get_presence_route(
         if let Some(presence) = db
             .rooms
             .edus
-            .get_last_presence_event(sender_user, &room_id)?
+            .get_last_presence_event(&body.user_id, &room_id)?
         {
             presence_event = Some(presence);
             break;
@@ -82,6 +206,9 @@ pub async fn get_presence_route(
             presence: presence.content.presence,
         })
     } else {
-        todo!();
+        Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Presence state for this user was not found.",
+        ))
     }
 }
@@ -147,6 +147,7 @@ pub async fn get_displayname_route(
 ///
 /// Updates the avatar_url and blurhash.
 ///
+/// - If the client sends no blurhash, the one computed when the avatar was uploaded is used
 /// - Also makes sure other users receive the update using presence EDUs
 pub async fn set_avatar_url_route(
     db: DatabaseGuard,
@@ -157,7 +158,13 @@ pub async fn set_avatar_url_route(
     db.users
         .set_avatar_url(sender_user, body.avatar_url.clone())?;
 
-    db.users.set_blurhash(sender_user, body.blurhash.clone())?;
+    let blurhash = match (&body.blurhash, &body.avatar_url) {
+        (Some(blurhash), _) => Some(blurhash.clone()),
+        (None, Some(avatar_url)) => db.media.blurhash(avatar_url.as_str())?,
+        (None, None) => None,
+    };
+
+    db.users.set_blurhash(sender_user, blurhash.clone())?;
 
     // Send a new membership event and presence update into all joined rooms
     let all_joined_rooms: Vec<_> = db
@@ -170,6 +177,7 @@ pub async fn set_avatar_url_route(
                     event_type: RoomEventType::RoomMember,
                     content: to_raw_value(&RoomMemberEventContent {
                         avatar_url: body.avatar_url.clone(),
+                        blurhash: blurhash.clone(),
                         ..serde_json::from_str(
                             db.rooms
                                 .room_state_get(
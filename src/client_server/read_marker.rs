@@ -1,11 +1,14 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
+use crate::{database::DatabaseGuard, Database, Error, Result, Ruma};
 use ruma::{
     api::client::{error::ErrorKind, read_marker::set_read_marker, receipt::create_receipt},
-    events::RoomAccountDataEventType,
+    events::{
+        receipt::{Receipt, ReceiptEvent, ReceiptEventContent},
+        RoomAccountDataEventType,
+    },
     receipt::ReceiptType,
-    MilliSecondsSinceUnixEpoch,
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, iter};
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/read_markers`
 ///
@@ -13,12 +16,17 @@ use std::collections::BTreeMap;
 ///
 /// - Updates fully-read account data event to `fully_read`
 /// - If `read_receipt` is set: Update private marker and public read receipt EDU
+/// - Both events have to be in the room and visible to the user
 pub async fn set_read_marker_route(
     db: DatabaseGuard,
     body: Ruma<set_read_marker::v3::IncomingRequest>,
 ) -> Result<set_read_marker::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    for event_id in iter::once(&body.fully_read).chain(&body.read_receipt) {
+        check_event_visible(&db, sender_user, &body.room_id, event_id)?;
+    }
+
     let fully_read_event = ruma::events::fully_read::FullyReadEvent {
         content: ruma::events::fully_read::FullyReadEventContent {
             event_id: body.fully_read.clone(),
@@ -45,26 +53,12 @@ pub async fn set_read_marker_route(
         db.rooms
             .reset_notification_counts(sender_user, &body.room_id)?;
 
-        let mut user_receipts = BTreeMap::new();
-        user_receipts.insert(
-            sender_user.clone(),
-            ruma::events::receipt::Receipt {
-                ts: Some(MilliSecondsSinceUnixEpoch::now()),
-            },
-        );
-
-        let mut receipts = BTreeMap::new();
-        receipts.insert(ReceiptType::Read, user_receipts);
-
-        let mut receipt_content = BTreeMap::new();
-        receipt_content.insert(event.to_owned(), receipts);
-
-        db.rooms.edus.readreceipt_update(
+        db.rooms.read_receipt_set(
             sender_user,
             &body.room_id,
-            ruma::events::receipt::ReceiptEvent {
-                content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-                room_id: body.room_id.clone(),
+            event,
+            Receipt {
+                ts: Some(MilliSecondsSinceUnixEpoch::now()),
             },
             &db.globals,
         )?;
@@ -77,51 +71,99 @@ pub async fn set_read_marker_route(
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId}`
 ///
-/// Sets private read marker and public read receipt EDU.
+/// Sets private read marker and read receipt.
+///
+/// - `m.read`: Also updates the public read receipt EDU that is sent to other users and servers
+/// - `m.read.private`: Only echoed back to the sender user in sync, the public receipt stays
+/// - Receipts for events older than the current receipt of the same type are ignored
 pub async fn create_receipt_route(
     db: DatabaseGuard,
     body: Ruma<create_receipt::v3::IncomingRequest>,
 ) -> Result<create_receipt::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    db.rooms.edus.private_read_set(
-        &body.room_id,
-        sender_user,
-        db.rooms
-            .get_pdu_count(&body.event_id)?
-            .ok_or(Error::BadRequest(
+    let private = match body.receipt_type.as_str() {
+        "m.read" => false,
+        "m.read.private" | "org.matrix.msc2285.read.private" => true,
+        _ => {
+            return Err(Error::BadRequest(
                 ErrorKind::InvalidParam,
-                "Event does not exist.",
-            ))?,
-        &db.globals,
-    )?;
+                "Unsupported receipt type.",
+            ))
+        }
+    };
+
+    let count = db
+        .rooms
+        .get_pdu_count(&body.event_id)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event does not exist.",
+        ))?;
+
+    db.rooms
+        .edus
+        .private_read_set(&body.room_id, sender_user, count, &db.globals)?;
     db.rooms
         .reset_notification_counts(sender_user, &body.room_id)?;
 
-    let mut user_receipts = BTreeMap::new();
-    user_receipts.insert(
-        sender_user.clone(),
-        ruma::events::receipt::Receipt {
-            ts: Some(MilliSecondsSinceUnixEpoch::now()),
-        },
-    );
-    let mut receipts = BTreeMap::new();
-    receipts.insert(ReceiptType::Read, user_receipts);
+    let receipt = Receipt {
+        ts: Some(MilliSecondsSinceUnixEpoch::now()),
+    };
 
-    let mut receipt_content = BTreeMap::new();
-    receipt_content.insert(body.event_id.to_owned(), receipts);
+    if !private {
+        db.rooms.read_receipt_set(
+            sender_user,
+            &body.room_id,
+            &body.event_id,
+            receipt,
+            &db.globals,
+        )?;
+    } else {
+        let mut user_receipts = BTreeMap::new();
+        user_receipts.insert(sender_user.clone(), receipt);
 
-    db.rooms.edus.readreceipt_update(
-        sender_user,
-        &body.room_id,
-        ruma::events::receipt::ReceiptEvent {
-            content: ruma::events::receipt::ReceiptEventContent(receipt_content),
-            room_id: body.room_id.clone(),
-        },
-        &db.globals,
-    )?;
+        let mut receipts = BTreeMap::new();
+        receipts.insert(ReceiptType::from("m.read.private"), user_receipts);
+
+        let mut receipt_content = BTreeMap::new();
+        receipt_content.insert(body.event_id.clone(), receipts);
+
+        db.rooms.edus.private_receipt_set(
+            &body.room_id,
+            sender_user,
+            count,
+            &ReceiptEvent {
+                content: ReceiptEventContent(receipt_content),
+                room_id: body.room_id.clone(),
+            },
+            &db.globals,
+        )?;
+    }
 
     db.flush()?;
 
     Ok(create_receipt::v3::Response {})
 }
+
+/// Markers may only point to events of the room the user is allowed to see.
+fn check_event_visible(
+    db: &Database,
+    user_id: &UserId,
+    room_id: &RoomId,
+    event_id: &EventId,
+) -> Result<()> {
+    let in_room = db
+        .rooms
+        .get_pdu(event_id)?
+        .map_or(false, |pdu| *pdu.room_id == *room_id);
+
+    if !in_room || !db.rooms.user_can_see_event(user_id, room_id, event_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to view this event.",
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use ruma::api::client::error::ErrorKind;
+use serde_json::json;
+use std::net::SocketAddr;
+
+use crate::{
+    database::{rendezvous::new_session_id, DatabaseGuard},
+    utils, Error, Result,
+};
+
+const RENDEZVOUS_PATH: &str = "/_matrix/client/unstable/org.matrix.msc4108/rendezvous";
+
+/// # `POST /_matrix/client/unstable/org.matrix.msc4108/rendezvous`
+///
+/// Opens a rendezvous session two devices can use to exchange login data.
+///
+/// - The request body becomes the initial payload
+/// - The session expires after `rendezvous_ttl_secs`
+/// - No account is needed, so only a limited number of sessions can be open and creating them is
+/// limited per client IP address if `auth_rate_limit_per_second` is set
+pub async fn create_rendezvous_route(
+    db: DatabaseGuard,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse> {
+    let client_ip = peer.map(|ConnectInfo(peer)| {
+        utils::client_ip(
+            peer.ip(),
+            headers
+                .get("x-forwarded-for")
+                .and_then(|header| header.to_str().ok()),
+            db.globals.trusted_proxies(),
+        )
+    });
+    db.globals.check_auth_rate_limit(client_ip)?;
+
+    let session_id = new_session_id();
+    let etag = db.globals.next_count()?;
+    let now = utils::millis_since_unix_epoch();
+    let expires_at = now.saturating_add(db.globals.rendezvous_ttl().as_millis() as u64);
+
+    db.rendezvous
+        .create(&session_id, &body, etag, expires_at, now)?;
+
+    db.flush()?;
+
+    Ok((
+        StatusCode::CREATED,
+        etag_header(etag),
+        Json(json!({
+            "url": format!(
+                "{}{}/{}",
+                db.globals.well_known_client().trim_end_matches('/'),
+                RENDEZVOUS_PATH,
+                session_id
+            ),
+            "expires_ts": expires_at,
+        })),
+    ))
+}
+
+/// # `GET /_matrix/client/unstable/org.matrix.msc4108/rendezvous/{sessionId}`
+///
+/// Reads the current payload of a rendezvous session.
+pub async fn get_rendezvous_route(
+    db: DatabaseGuard,
+    Path(session_id): Path<String>,
+) -> Result<impl IntoResponse> {
+    let session = db
+        .rendezvous
+        .get(&session_id, utils::millis_since_unix_epoch())?
+        .ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Rendezvous session not found or expired.",
+        ))?;
+
+    let mut headers = etag_header(session.etag);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+
+    Ok((headers, session.payload))
+}
+
+/// # `PUT /_matrix/client/unstable/org.matrix.msc4108/rendezvous/{sessionId}`
+///
+/// Replaces the payload of a rendezvous session.
+///
+/// - The `If-Match` header must contain the ETag of the payload the device saw last
+pub async fn update_rendezvous_route(
+    db: DatabaseGuard,
+    Path(session_id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse> {
+    let expected_etag = headers
+        .get(header::IF_MATCH)
+        .ok_or(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Missing If-Match header.",
+        ))?
+        .to_str()
+        .ok()
+        .and_then(|etag| etag.trim_matches('"').parse().ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid If-Match header.",
+        ))?;
+
+    let etag = db.globals.next_count()?;
+    db.rendezvous.update(
+        &session_id,
+        expected_etag,
+        etag,
+        &body,
+        utils::millis_since_unix_epoch(),
+    )?;
+
+    db.flush()?;
+
+    Ok((etag_header(etag), Json(json!({}))))
+}
+
+/// # `DELETE /_matrix/client/unstable/org.matrix.msc4108/rendezvous/{sessionId}`
+///
+/// Closes a rendezvous session.
+pub async fn delete_rendezvous_route(
+    db: DatabaseGuard,
+    Path(session_id): Path<String>,
+) -> Result<impl IntoResponse> {
+    db.rendezvous.remove(&session_id)?;
+
+    db.flush()?;
+
+    Ok(Json(json!({})))
+}
+
+fn etag_header(etag: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&format!("\"{}\"", etag)).expect("etag is a valid header value"),
+    );
+    headers
+}
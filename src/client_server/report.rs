@@ -1,68 +1,84 @@
-use crate::{database::DatabaseGuard, utils::HtmlEscape, Error, Result, Ruma};
+use crate::{
+    database::{rooms::Report, DatabaseGuard},
+    utils::{self, HtmlEscape},
+    Error, Result, Ruma,
+};
 use ruma::{
     api::client::{error::ErrorKind, room::report_content},
     events::room::message,
-    int,
+    int, Int,
 };
 
 /// # `POST /_matrix/client/r0/rooms/{roomId}/report/{eventId}`
 ///
 /// Reports an inappropriate event to homeserver admins
 ///
+/// - The event must be in the room and visible to the reporter
+/// - The report is stored until an admin clears it and announced in the admin room
 pub async fn report_event_route(
     db: DatabaseGuard,
     body: Ruma<report_content::v3::IncomingRequest>,
 ) -> Result<report_content::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+    // Events the reporter can't see don't exist as far as they know
     let pdu = match db.rooms.get_pdu(&body.event_id)? {
-        Some(pdu) => pdu,
-        _ => {
-            return Err(Error::BadRequest(
-                ErrorKind::InvalidParam,
-                "Invalid Event ID",
-            ))
+        Some(pdu)
+            if pdu.room_id == body.room_id
+                && db
+                    .rooms
+                    .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)? =>
+        {
+            pdu
         }
+        _ => return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found.")),
     };
 
-    if let Some(true) = body.score.map(|s| s > int!(0) || s < int!(-100)) {
-        return Err(Error::BadRequest(
-            ErrorKind::InvalidParam,
-            "Invalid score, must be within 0 to -100",
-        ));
-    };
+    check_report(body.score, body.reason.as_deref())?;
 
-    if let Some(true) = body.reason.clone().map(|s| s.chars().count() > 250) {
-        return Err(Error::BadRequest(
-            ErrorKind::InvalidParam,
-            "Reason too long, should be 250 characters or fewer",
-        ));
-    };
+    let report_id = db.rooms.add_report(
+        &Report {
+            reporter: sender_user.clone(),
+            room_id: pdu.room_id.clone(),
+            event_id: pdu.event_id.clone(),
+            score: body.score,
+            reason: body.reason.clone(),
+            reported_at: utils::millis_since_unix_epoch(),
+        },
+        &db.globals,
+    )?;
 
     db.admin
         .send_message(message::RoomMessageEventContent::text_html(
             format!(
-                "Report received from: {}\n\n\
+                "Report {} received from: {}\n\n\
                 Event ID: {:?}\n\
                 Room ID: {:?}\n\
                 Sent By: {:?}\n\n\
                 Report Score: {:?}\n\
                 Report Reason: {:?}",
-                sender_user, pdu.event_id, pdu.room_id, pdu.sender, body.score, body.reason
+                report_id,
+                sender_user,
+                pdu.event_id,
+                pdu.room_id,
+                pdu.sender,
+                body.score,
+                body.reason
             ),
             format!(
                 "<details><summary>Report received from: <a href=\"https://matrix.to/#/{0:?}\">{0:?}\
                 </a></summary><ul><li>Event Info<ul><li>Event ID: <code>{1:?}</code>\
                 <a href=\"https://matrix.to/#/{2:?}/{1:?}\">🔗</a></li><li>Room ID: <code>{2:?}</code>\
                 </li><li>Sent By: <a href=\"https://matrix.to/#/{3:?}\">{3:?}</a></li></ul></li><li>\
-                Report Info<ul><li>Report Score: {4:?}</li><li>Report Reason: {5}</li></ul></li>\
+                Report Info<ul><li>Report ID: {6}</li><li>Report Score: {4:?}</li><li>Report Reason: {5}</li></ul></li>\
                 </ul></details>",
                 sender_user,
                 pdu.event_id,
                 pdu.room_id,
                 pdu.sender,
                 body.score,
-                HtmlEscape(body.reason.as_deref().unwrap_or(""))
+                HtmlEscape(body.reason.as_deref().unwrap_or("")),
+                report_id
             ),
         ));
 
@@ -70,3 +86,135 @@ pub async fn report_event_route(
 
     Ok(report_content::v3::Response {})
 }
+
+/// Rejects scores outside of -100 (most offensive) to 0 (inoffensive) and overly long reasons.
+fn check_report(score: Option<Int>, reason: Option<&str>) -> Result<()> {
+    if let Some(true) = score.map(|s| s > int!(0) || s < int!(-100)) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Invalid score, must be within -100 to 0",
+        ));
+    };
+
+    if let Some(true) = reason.map(|s| s.chars().count() > 250) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Reason too long, should be 250 characters or fewer",
+        ));
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_report;
+    use crate::Error;
+    use ruma::{api::client::error::ErrorKind, int};
+
+    #[test]
+    fn scores_must_be_between_minus_hundred_and_zero() {
+        assert!(check_report(None, None).is_ok());
+        assert!(check_report(Some(int!(-100)), Some("spam")).is_ok());
+        assert!(check_report(Some(int!(0)), None).is_ok());
+        assert!(matches!(
+            check_report(Some(int!(1)), None),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+        assert!(matches!(
+            check_report(Some(int!(-101)), None),
+            Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+        ));
+        assert!(check_report(None, Some(&"a".repeat(251))).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn events_the_reporter_cant_see_are_not_found() {
+        use super::report_event_route;
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Ruma,
+        };
+        use ruma::{
+            api::{client::room::report_content, IncomingRequest},
+            room_id, user_id, EventId, UserId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("report").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        let message = {
+            let db = db.read().await;
+            test_room(&db, room_id, alice).await;
+            test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.message",
+                None,
+                json!({ "msgtype": "m.text", "body": "spam" }),
+            )
+            .await
+            .unwrap()
+        };
+
+        let report = |user_id: &UserId, event_id: &EventId| {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("/_matrix/client/r0/rooms/%21room%3Aexample.org/report/event")
+                .body(serde_json::to_vec(&json!({ "score": -100, "reason": "spam" })).unwrap())
+                .unwrap();
+            let body = report_content::v3::IncomingRequest::try_from_http_request(
+                request,
+                &["!room:example.org", event_id.as_str()],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            let sender_user = user_id.to_owned();
+            async move {
+                report_event_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(sender_user),
+                        sender_device: None,
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await
+            }
+        };
+
+        // Bob isn't in the room and the history is only shared with members
+        assert!(matches!(
+            report(bob, &message).await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+        assert!(matches!(
+            report(alice, ruma::event_id!("$unknown")).await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+        assert_eq!(db.read().await.rooms.reports().count(), 0);
+
+        report(alice, &message).await.unwrap();
+        let reports = db
+            .read()
+            .await
+            .rooms
+            .reports()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(*reports[0].1.event_id, *message);
+        assert_eq!(*reports[0].1.reporter, *alice);
+    }
+}
@@ -1,5 +1,11 @@
 use crate::{
-    client_server::invite_helper, database::DatabaseGuard, pdu::PduBuilder, Error, Result, Ruma,
+    client_server::{
+        check_encryption_algorithm, invite_helper, may_send_state_event, room_power_levels,
+        state_event_power_level, user_power_level, MEGOLM_ALGORITHM,
+    },
+    database::DatabaseGuard,
+    pdu::PduBuilder,
+    Error, Result, Ruma,
 };
 use ruma::{
     api::client::{
@@ -23,7 +29,7 @@ use ruma::{
     },
     int,
     serde::{CanonicalJsonObject, JsonObject},
-    RoomAliasId, RoomId,
+    RoomAliasId, RoomId, UserId,
 };
 use serde_json::{json, value::to_raw_value};
 use std::{cmp::max, collections::BTreeMap, sync::Arc};
@@ -43,6 +49,7 @@ use tracing::{info, warn};
 /// - Send history visibility
 /// - Send guest access
 /// - Send events listed in initial state
+/// - Send encryption event if the server requires encryption
 /// - Send events implied by `name` and `topic`
 /// - Send invite events
 pub async fn create_room_route(
@@ -162,6 +169,15 @@ pub async fn create_room_route(
         ));
     }
 
+    // Reject unusable encryption settings before anything is sent into the room
+    for event in &body.initial_state {
+        if let Ok(pdu_builder) = event.deserialize_as::<PduBuilder>() {
+            if pdu_builder.event_type == RoomEventType::RoomEncryption {
+                check_encryption_algorithm(&pdu_builder.content)?;
+            }
+        }
+    }
+
     // 1. The room create event
     db.rooms.build_and_append_pdu(
         PduBuilder {
@@ -355,6 +371,36 @@ pub async fn create_room_route(
             .build_and_append_pdu(pdu_builder, sender_user, &room_id, &db, &state_lock)?;
     }
 
+    // 6.1 Encryption required by the server policy
+    let encryption_requested = db
+        .rooms
+        .room_state_get(&room_id, &StateEventType::RoomEncryption, "")?
+        .is_some();
+
+    if db.globals.allow_encryption()
+        && must_force_encryption(
+            db.globals.require_encryption(),
+            db.globals.require_encryption_public_rooms(),
+            &preset,
+            encryption_requested,
+        )
+    {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomEncryption,
+                content: to_raw_value(&json!({ "algorithm": MEGOLM_ALGORITHM }))
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &room_id,
+            &db,
+            &state_lock,
+        )?;
+    }
+
     // 7. Events implied by name and topic
     if let Some(name) = &body.name {
         db.rooms.build_and_append_pdu(
@@ -401,6 +447,7 @@ pub async fn create_room_route(
     // Homeserver specific stuff
     if let Some(alias) = alias {
         db.rooms.set_alias(&alias, Some(&room_id), &db.globals)?;
+        db.rooms.set_alias_creator(&alias, sender_user)?;
     }
 
     if body.visibility == room::Visibility::Public {
@@ -414,45 +461,73 @@ pub async fn create_room_route(
     Ok(create_room::v3::Response::new(room_id))
 }
 
+/// Whether a new room has to be encrypted even though the client didn't ask for it.
+fn must_force_encryption(
+    require_encryption: bool,
+    require_for_public_rooms: bool,
+    preset: &create_room::v3::RoomPreset,
+    encryption_requested: bool,
+) -> bool {
+    require_encryption
+        && !encryption_requested
+        && (require_for_public_rooms || *preset != create_room::v3::RoomPreset::PublicChat)
+}
+
 /// # `GET /_matrix/client/r0/rooms/{roomId}/event/{eventId}`
 ///
 /// Gets a single event.
 ///
-/// - You have to currently be joined to the room (TODO: Respect history visibility)
+/// - The history visibility at the event decides whether the user may see it
+/// - Guests additionally need the room to allow guest access or be world readable
+/// - Events the user can't see are reported as not found
+/// - Relations are bundled like in `/context` and `/messages`
 pub async fn get_room_event_route(
     db: DatabaseGuard,
     body: Ruma<get_room_event::v3::IncomingRequest>,
 ) -> Result<get_room_event::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(&body.room_id)? {
         return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view this room.",
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
         ));
     }
 
+    let pdu = db
+        .rooms
+        .get_pdu(&body.event_id)?
+        .filter(|pdu| pdu.room_id == body.room_id)
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?;
+
+    if !db
+        .rooms
+        .user_can_see_event(sender_user, &body.room_id, &body.event_id)?
+    {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found."));
+    }
+
     Ok(get_room_event::v3::Response {
-        event: db
-            .rooms
-            .get_pdu(&body.event_id)?
-            .ok_or(Error::BadRequest(ErrorKind::NotFound, "Event not found."))?
-            .to_room_event(),
+        event: db.rooms.bundle_relations((*pdu).clone())?.to_room_event(),
     })
 }
 
 /// # `GET /_matrix/client/r0/rooms/{roomId}/aliases`
 ///
-/// Lists all aliases of the room.
+/// Lists all local aliases of the room.
 ///
-/// - Only users joined to the room are allowed to call this TODO: Allow any user to call it if history_visibility is world readable
+/// - Only users joined to the room are allowed to call this, unless the history of the room is
+///   world readable
 pub async fn get_room_aliases_route(
     db: DatabaseGuard,
     body: Ruma<aliases::v3::IncomingRequest>,
 ) -> Result<aliases::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_user, &body.room_id)? {
+    if !can_view_room_aliases(
+        db.rooms.is_joined(sender_user, &body.room_id)?,
+        &db.rooms.history_visibility(&body.room_id)?,
+    ) {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -468,15 +543,23 @@ pub async fn get_room_aliases_route(
     })
 }
 
+/// Room members can always list the aliases, everyone else only if the room is world readable.
+fn can_view_room_aliases(is_joined: bool, history_visibility: &HistoryVisibility) -> bool {
+    is_joined || *history_visibility == HistoryVisibility::WorldReadable
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/upgrade`
 ///
 /// Upgrades the room.
 ///
+/// - The sender needs the power level to send `m.room.tombstone` events
 /// - Creates a replacement room
 /// - Sends a tombstone event into the current room
-/// - Sender user joins the room
+/// - Sender user joins the room first
 /// - Transfers some state events
 /// - Moves local aliases
+/// - Invites the other joined members to the replacement room. The sender keeps the power to do
+/// that until the copied power levels are restored afterwards
 /// - Modifies old room power levels to prevent users from speaking
 pub async fn upgrade_room_route(
     db: DatabaseGuard,
@@ -491,6 +574,15 @@ pub async fn upgrade_room_route(
         ));
     }
 
+    if !db.rooms.is_joined(sender_user, &body.room_id)?
+        || !may_send_tombstone(&room_power_levels(&db, &body.room_id)?, sender_user)
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "You don't have permission to upgrade this room.",
+        ));
+    }
+
     // Create a replacement room
     let replacement_room = RoomId::new(db.globals.server_name());
     db.rooms
@@ -642,12 +734,32 @@ pub async fn upgrade_room_route(
     ];
 
     // Replicate transferable state events to the new room
+    let mut original_power_levels = None;
     for event_type in transferable_state_events {
-        let event_content = match db.rooms.room_state_get(&body.room_id, &event_type, "")? {
+        let mut event_content = match db.rooms.room_state_get(&body.room_id, &event_type, "")? {
             Some(v) => v.content.clone(),
             None => continue, // Skipping missing events.
         };
 
+        // The sender needs enough power to invite the members and to put the copied power levels
+        // back afterwards
+        if event_type == StateEventType::RoomPowerLevels {
+            let mut power_levels: RoomPowerLevelsEventContent =
+                serde_json::from_str(event_content.get())
+                    .map_err(|_| Error::bad_database("Invalid room event in database."))?;
+            let needed = max(
+                power_levels.invite,
+                state_event_power_level(&power_levels, &RoomEventType::RoomPowerLevels),
+            );
+
+            if user_power_level(&power_levels, sender_user) < needed {
+                power_levels.users.insert(sender_user.clone(), needed);
+                original_power_levels = Some(event_content);
+                event_content =
+                    to_raw_value(&power_levels).expect("event is valid, we just created it");
+            }
+        }
+
         db.rooms.build_and_append_pdu(
             PduBuilder {
                 event_type: event_type.to_string().into(),
@@ -669,6 +781,62 @@ pub async fn upgrade_room_route(
             .set_alias(&alias, Some(&replacement_room), &db.globals)?;
     }
 
+    drop(state_lock);
+
+    // Invite everyone else who was in the old room, they have to join themselves
+    let members = db
+        .rooms
+        .room_members(&body.room_id)
+        .filter_map(|r| r.ok())
+        .filter(|user_id| user_id != sender_user)
+        .collect::<Vec<_>>();
+
+    for user_id in members {
+        if let Err(e) = invite_helper(sender_user, &user_id, &replacement_room, &db, false).await {
+            warn!(
+                "Failed to invite {} to upgraded room {}: {}",
+                user_id, replacement_room, e
+            );
+        }
+    }
+
+    if let Some(original_power_levels) = original_power_levels {
+        let mutex_state = Arc::clone(
+            db.globals
+                .roomid_mutex_state
+                .write()
+                .unwrap()
+                .entry(replacement_room.clone())
+                .or_default(),
+        );
+        let state_lock = mutex_state.lock().await;
+
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomPowerLevels,
+                content: original_power_levels,
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
+            },
+            sender_user,
+            &replacement_room,
+            &db,
+            &state_lock,
+        )?;
+    }
+
+    // Change lock back to the old room
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(body.room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
     // Get the old room power levels
     let mut power_levels_event_content: RoomPowerLevelsEventContent = serde_json::from_str(
         db.rooms
@@ -679,10 +847,12 @@ pub async fn upgrade_room_route(
     )
     .map_err(|_| Error::bad_database("Invalid room event in database."))?;
 
-    // Setting events_default and invite to the greater of 50 and users_default + 1
+    // Raising events_default and invite to at least the greater of 50 and users_default + 1.
+    // Lowering them would need more power than upgrading does.
     let new_level = max(int!(50), power_levels_event_content.users_default + int!(1));
-    power_levels_event_content.events_default = new_level;
-    power_levels_event_content.invite = new_level;
+    power_levels_event_content.events_default =
+        max(power_levels_event_content.events_default, new_level);
+    power_levels_event_content.invite = max(power_levels_event_content.invite, new_level);
 
     // Modify the power levels in the old room to prevent sending of events and inviting new users
     let _ = db.rooms.build_and_append_pdu(
@@ -707,3 +877,501 @@ pub async fn upgrade_room_route(
     // Return the replacement room id
     Ok(upgrade_room::v3::Response { replacement_room })
 }
+
+/// Whether the user's power level is enough to send the tombstone event that replaces the room.
+pub(super) fn may_send_tombstone(
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+) -> bool {
+    may_send_state_event(power_levels, user_id, &RoomEventType::RoomTombstone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{can_view_room_aliases, may_send_tombstone, must_force_encryption};
+    use ruma::{
+        api::client::room::create_room::v3::RoomPreset,
+        events::{
+            room::{
+                history_visibility::HistoryVisibility, power_levels::RoomPowerLevelsEventContent,
+            },
+            RoomEventType,
+        },
+        int, user_id,
+    };
+
+    #[test]
+    fn private_room_is_encrypted_when_required() {
+        assert!(must_force_encryption(
+            true,
+            false,
+            &RoomPreset::PrivateChat,
+            false
+        ));
+        assert!(!must_force_encryption(
+            false,
+            false,
+            &RoomPreset::PrivateChat,
+            false
+        ));
+    }
+
+    #[test]
+    fn requested_encryption_is_not_sent_twice() {
+        assert!(!must_force_encryption(
+            true,
+            true,
+            &RoomPreset::PrivateChat,
+            true
+        ));
+    }
+
+    #[test]
+    fn public_rooms_are_only_encrypted_if_configured() {
+        assert!(!must_force_encryption(
+            true,
+            false,
+            &RoomPreset::PublicChat,
+            false
+        ));
+        assert!(must_force_encryption(
+            true,
+            true,
+            &RoomPreset::PublicChat,
+            false
+        ));
+    }
+
+    #[test]
+    fn room_aliases_are_hidden_from_non_members_of_private_rooms() {
+        assert!(can_view_room_aliases(true, &HistoryVisibility::Joined));
+        assert!(!can_view_room_aliases(false, &HistoryVisibility::Joined));
+        assert!(!can_view_room_aliases(false, &HistoryVisibility::Shared));
+        assert!(can_view_room_aliases(
+            false,
+            &HistoryVisibility::WorldReadable
+        ));
+    }
+
+    #[test]
+    fn upgrading_needs_tombstone_power() {
+        let admin = user_id!("@admin:example.org");
+        let user = user_id!("@user:example.org");
+
+        let mut power_levels = RoomPowerLevelsEventContent::default();
+        power_levels.users.insert(admin.to_owned(), int!(100));
+        power_levels
+            .events
+            .insert(RoomEventType::RoomTombstone, int!(100));
+
+        assert!(may_send_tombstone(&power_levels, admin));
+        assert!(!may_send_tombstone(&power_levels, user));
+
+        power_levels.events.clear();
+        power_levels.users_default = power_levels.state_default;
+        assert!(may_send_tombstone(&power_levels, user));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn upgrade_copies_state_and_invites_members() {
+        use super::upgrade_room_route;
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Ruma,
+        };
+        use ruma::{
+            api::{client::room::upgrade_room, IncomingRequest},
+            events::{
+                room::{
+                    create::RoomCreateEventContent, name::RoomNameEventContent,
+                    tombstone::RoomTombstoneEventContent,
+                },
+                StateEventType,
+            },
+            room_id, RoomId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("upgrade-room").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let room_id = room_id!("!old:example.org");
+
+        // Bob may replace the room, but not invite anyone
+        let power_levels = json!({
+            "users": { "@alice:example.org": 100, "@bob:example.org": 50 },
+            "events": { "m.room.tombstone": 50 },
+            "invite": 100,
+        });
+        {
+            let db = db.read().await;
+            test_room(&db, room_id, alice).await;
+            for (event_type, content) in [
+                ("m.room.power_levels", power_levels.clone()),
+                ("m.room.join_rules", json!({ "join_rule": "public" })),
+                ("m.room.name", json!({ "name": "Old room" })),
+            ] {
+                test_send(&db, room_id, alice, event_type, Some(""), content)
+                    .await
+                    .unwrap();
+            }
+            for user_id in [bob, carol] {
+                test_send(
+                    &db,
+                    room_id,
+                    user_id,
+                    "m.room.member",
+                    Some(user_id.as_str()),
+                    json!({ "membership": "join" }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/_matrix/client/r0/rooms/%21old%3Aexample.org/upgrade")
+            .body(serde_json::to_vec(&json!({ "new_version": "9" })).unwrap())
+            .unwrap();
+        let replacement_room = upgrade_room_route(
+            DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+            Ruma {
+                body: upgrade_room::v3::IncomingRequest::try_from_http_request(
+                    request,
+                    &["!old:example.org"],
+                )
+                .unwrap(),
+                sender_user: Some(bob.to_owned()),
+                sender_device: None,
+                sender_servername: None,
+                json_body: None,
+                from_appservice: false,
+                appservice_id: None,
+                client_ip: None,
+            },
+        )
+        .await
+        .unwrap()
+        .replacement_room;
+
+        let db = db.read().await;
+        let state_content = |room_id: &RoomId, event_type: StateEventType| {
+            db.rooms
+                .room_state_get(room_id, &event_type, "")
+                .unwrap()
+                .unwrap()
+        };
+
+        let tombstone = state_content(room_id, StateEventType::RoomTombstone);
+        let tombstone_content: RoomTombstoneEventContent =
+            serde_json::from_str(tombstone.content.get()).unwrap();
+        assert_eq!(tombstone_content.replacement_room, replacement_room);
+
+        let create: RoomCreateEventContent = serde_json::from_str(
+            state_content(&replacement_room, StateEventType::RoomCreate)
+                .content
+                .get(),
+        )
+        .unwrap();
+        let predecessor = create.predecessor.unwrap();
+        assert_eq!(&*predecessor.room_id, room_id);
+        assert_eq!(&*predecessor.event_id, &*tombstone.event_id);
+
+        let name: RoomNameEventContent = serde_json::from_str(
+            state_content(&replacement_room, StateEventType::RoomName)
+                .content
+                .get(),
+        )
+        .unwrap();
+        assert_eq!(name.name.unwrap().as_str(), "Old room");
+
+        // The invites worked although Bob's power wasn't enough in the old room, and his power is
+        // back to what it was afterwards
+        assert!(db.rooms.is_joined(bob, &replacement_room).unwrap());
+        assert!(db.rooms.is_invited(alice, &replacement_room).unwrap());
+        assert!(db.rooms.is_invited(carol, &replacement_room).unwrap());
+        let copied: serde_json::Value = serde_json::from_str(
+            state_content(&replacement_room, StateEventType::RoomPowerLevels)
+                .content
+                .get(),
+        )
+        .unwrap();
+        assert_eq!(copied, power_levels);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn events_are_bundled_and_hidden_from_outsiders() {
+        use super::get_room_event_route;
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Error, Ruma,
+        };
+        use ruma::{
+            api::{
+                client::{error::ErrorKind, room::get_room_event},
+                IncomingRequest,
+            },
+            room_id, UserId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("room-event").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        let message = {
+            let db = db.read().await;
+            test_room(&db, room_id, alice).await;
+            let message = test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.message",
+                None,
+                json!({ "msgtype": "m.text", "body": "hi" }),
+            )
+            .await
+            .unwrap();
+            test_send(
+                &db,
+                room_id,
+                alice,
+                "m.reaction",
+                None,
+                json!({
+                    "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": message,
+                        "key": "👍",
+                    }
+                }),
+            )
+            .await
+            .unwrap();
+            message
+        };
+
+        let get_event = |user_id: &UserId| {
+            let request = http::Request::builder()
+                .uri(format!(
+                    "/_matrix/client/r0/rooms/%21room%3Aexample.org/event/{}",
+                    message
+                ))
+                .body(Vec::<u8>::new())
+                .unwrap();
+            let body = get_room_event::v3::IncomingRequest::try_from_http_request(
+                request,
+                &["!room:example.org", message.as_str()],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            let sender_user = user_id.to_owned();
+            async move {
+                get_room_event_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(sender_user),
+                        sender_device: None,
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await
+            }
+        };
+
+        let event: serde_json::Value =
+            serde_json::from_str(get_event(alice).await.unwrap().event.json().get()).unwrap();
+        assert_eq!(event["event_id"], json!(message));
+        assert_eq!(
+            event["unsigned"]["m.relations"],
+            json!({
+                "m.annotation": {
+                    "chunk": [{ "type": "m.reaction", "key": "👍", "count": 1 }]
+                }
+            })
+        );
+
+        // Bob never joined the room and its history is only shared with members
+        assert!(matches!(
+            get_event(bob).await,
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn room_aliases_are_listed_to_members() {
+        use super::get_room_aliases_route;
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Error, Ruma,
+        };
+        use ruma::{
+            api::{
+                client::{error::ErrorKind, room::aliases},
+                IncomingRequest,
+            },
+            room_alias_id, room_id, UserId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("room-aliases").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        {
+            let db = db.read().await;
+            test_room(&db, room_id, alice).await;
+            for alias in [
+                room_alias_id!("#first:example.org"),
+                room_alias_id!("#second:example.org"),
+            ] {
+                db.rooms
+                    .set_alias(alias, Some(room_id), &db.globals)
+                    .unwrap();
+            }
+        }
+
+        let list_aliases = |user_id: &UserId| {
+            let request = http::Request::builder()
+                .uri("/_matrix/client/r0/rooms/%21room%3Aexample.org/aliases")
+                .body(Vec::<u8>::new())
+                .unwrap();
+            let body = aliases::v3::IncomingRequest::try_from_http_request(
+                request,
+                &["!room:example.org"],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            let sender_user = user_id.to_owned();
+            async move {
+                get_room_aliases_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(sender_user),
+                        sender_device: None,
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await
+                .map(|response| {
+                    let mut aliases = response
+                        .aliases
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>();
+                    aliases.sort();
+                    aliases
+                })
+            }
+        };
+
+        assert_eq!(
+            list_aliases(alice).await.unwrap(),
+            vec!["#first:example.org", "#second:example.org"]
+        );
+        assert!(matches!(
+            list_aliases(bob).await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        test_send(
+            &*db.read().await,
+            room_id,
+            alice,
+            "m.room.history_visibility",
+            Some(""),
+            json!({ "history_visibility": "world_readable" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(list_aliases(bob).await.unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn required_encryption_is_added_to_new_rooms() {
+        use super::create_room_route;
+        use crate::{
+            database::{test_config, DatabaseGuard},
+            Database, Ruma,
+        };
+        use ruma::{
+            api::{client::room::create_room, IncomingRequest},
+            events::StateEventType,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let mut config = test_config("require-encryption");
+        config.require_encryption = true;
+        let db = Database::load_or_create(&config).await.unwrap();
+        let alice = user_id!("@alice:example.org");
+
+        let create_room = |preset: &str| {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("/_matrix/client/r0/createRoom")
+                .body(serde_json::to_vec(&json!({ "preset": preset })).unwrap())
+                .unwrap();
+            let body =
+                create_room::v3::IncomingRequest::try_from_http_request::<_, String>(request, &[])
+                    .unwrap();
+            let db = Arc::clone(&db);
+            async move {
+                let room_id = create_room_route(
+                    DatabaseGuard::from(Arc::clone(&db).read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(alice.to_owned()),
+                        sender_device: None,
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await
+                .unwrap()
+                .room_id;
+
+                db.read()
+                    .await
+                    .rooms
+                    .room_state_get(&room_id, &StateEventType::RoomEncryption, "")
+                    .unwrap()
+                    .map(|pdu| {
+                        serde_json::from_str::<serde_json::Value>(pdu.content.get()).unwrap()
+                    })
+            }
+        };
+
+        // Encryption wasn't requested, but the policy requires it
+        assert_eq!(
+            create_room("private_chat").await,
+            Some(json!({ "algorithm": "m.megolm.v1.aes-sha2" }))
+        );
+        // Public rooms are only encrypted if require_encryption_public_rooms is set
+        assert_eq!(create_room("public_chat").await, None);
+    }
+}
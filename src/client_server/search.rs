@@ -1,26 +1,55 @@
-use crate::{database::DatabaseGuard, Error, Result, Ruma};
-use ruma::api::client::{
-    error::ErrorKind,
-    search::search_events::{
-        self,
-        v3::{EventContextResult, ResultCategories, ResultRoomEvents, SearchResult},
+use crate::{
+    database::{rooms::search_words, DatabaseGuard},
+    pdu::PduEvent,
+    utils, Database, Error, Result, Ruma,
+};
+use ruma::{
+    api::client::{
+        error::ErrorKind,
+        search::search_events::{
+            self,
+            v3::{
+                EventContextResult, OrderBy, ResultCategories, ResultRoomEvents, SearchResult,
+                UserProfile,
+            },
+        },
     },
+    events::{room::member::RoomMemberEventContent, StateEventType},
+    RoomId, UserId,
 };
+use serde::Deserialize;
+
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeMap, iter, mem::size_of};
+
+/// Hits per room that are ranked, the newest ones are taken.
+const MAX_RANKED_PER_ROOM: usize = 1000;
 
-use std::collections::BTreeMap;
+/// Hits that are ranked in total.
+const MAX_RANKED: usize = 5000;
 
 /// # `POST /_matrix/client/r0/search`
 ///
 /// Searches rooms for messages.
 ///
-/// - Only works if the user is currently joined to the room (TODO: Respect history visibility)
+/// - Only works if the user is currently joined to the room
+/// - Hits and their context follow the history visibility, e.g. messages from before the user
+/// joined are left out unless the history was shared
+/// - Results are ordered by recency or by how much of the message matches the search term. Only
+/// the newest hits of each room are ranked
+/// - Each result includes `event_context.before_limit` and `after_limit` surrounding events,
+/// capped at the configured `max_pagination_limit`
+/// - Relations are bundled with the hits and their context
 pub async fn search_events_route(
     db: DatabaseGuard,
     body: Ruma<search_events::v3::IncomingRequest>,
 ) -> Result<search_events::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    let search_criteria = body.search_categories.room_events.as_ref().unwrap();
+    let search_criteria = match body.search_categories.room_events.as_ref() {
+        Some(criteria) => criteria,
+        // Room events are the only category there is
+        None => return Ok(search_events::v3::Response::new(ResultCategories::new())),
+    };
     let filter = &search_criteria.filter;
 
     let room_ids = filter.rooms.clone().unwrap_or_else(|| {
@@ -61,39 +90,140 @@ pub async fn search_events_route(
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
+    let words = search_words(&search_criteria.search_term);
+
+    let visible = |pdu: &PduEvent| {
+        db.rooms
+            .user_can_see_event(sender_user, &pdu.room_id, &pdu.event_id)
+            .unwrap_or(false)
+    };
+
+    let results: Vec<(PduEvent, Option<f64>)> = match search_criteria.order_by {
+        Some(OrderBy::Rank) => {
+            // Every candidate has to be ranked before the best ones are known
+            let mut results: Vec<_> = ranking_candidates(searches)
+                .filter_map(|pdu_id| db.rooms.get_pdu_from_id(&pdu_id).ok().flatten())
+                .filter(|pdu| visible(pdu))
+                .map(|pdu| {
+                    let rank = message_body(&pdu).map_or(0.0, |body| rank(&body, &words));
+                    (pdu, Some(rank))
+                })
+                .collect();
+
+            results.sort_by(|(a, a_rank), (b, b_rank)| {
+                b_rank
+                    .partial_cmp(a_rank)
+                    .unwrap_or(Ordering::Equal)
+                    .then(b.origin_server_ts.cmp(&a.origin_server_ts))
+            });
+
+            results.into_iter().skip(skip).take(limit).collect()
         }
-    }
+        _ => {
+            let newest_first = iter::from_fn(|| {
+                // Pdu ids start with the room, only the count at the end says which is newer
+                searches
+                    .iter_mut()
+                    .map(|s| {
+                        (
+                            s.peek()
+                                .map(|id| id[id.len() - size_of::<u64>()..].to_vec()),
+                            s,
+                        )
+                    })
+                    .max_by_key(|(count, _)| count.clone())
+                    .and_then(|(_, i)| i.next())
+            });
+
+            newest_first
+                .filter_map(|pdu_id| db.rooms.get_pdu_from_id(&pdu_id).ok().flatten())
+                .filter(|pdu| visible(pdu))
+                .map(|pdu| (pdu, None))
+                .skip(skip)
+                .take(limit)
+                .collect()
+        }
+    };
+
+    let event_context = &search_criteria.event_context;
+    let max_limit = db.globals.max_pagination_limit();
+    let before_limit = utils::clamp_limit(event_context.before_limit.into(), max_limit);
+    let after_limit = utils::clamp_limit(event_context.after_limit.into(), max_limit);
 
     let results: Vec<_> = results
-        .iter()
-        .map(|result| {
-            Ok::<_, Error>(SearchResult {
+        .into_iter()
+        .map(|(pdu, rank)| {
+            let count = match db.rooms.get_pdu_id(&pdu.event_id)? {
+                Some(pdu_id) => db.rooms.pdu_count(&pdu_id)?,
+                None => return Ok(None),
+            };
+
+            let events_before: Vec<_> = db
+                .rooms
+                .pdus_until(sender_user, &pdu.room_id, count)?
+                .take(before_limit)
+                .filter_map(|r| r.ok()) // Remove buggy events
+                .filter(|(_, pdu)| visible(pdu))
+                .collect();
+
+            let events_after: Vec<_> = db
+                .rooms
+                .pdus_after(sender_user, &pdu.room_id, count)?
+                .take(after_limit)
+                .filter_map(|r| r.ok()) // Remove buggy events
+                .filter(|(_, pdu)| visible(pdu))
+                .collect();
+
+            let start = events_before
+                .last()
+                .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
+                .map(|count| count.to_string());
+
+            let end = events_after
+                .last()
+                .and_then(|(pdu_id, _)| db.rooms.pdu_count(pdu_id).ok())
+                .map(|count| count.to_string());
+
+            let mut profile_info = BTreeMap::new();
+            if event_context.include_profile {
+                for sender in events_before
+                    .iter()
+                    .chain(&events_after)
+                    .map(|(_, event)| &event.sender)
+                    .chain(Some(&pdu.sender))
+                {
+                    if !profile_info.contains_key(sender) {
+                        profile_info
+                            .insert(sender.clone(), member_profile(&db, &pdu.room_id, sender)?);
+                    }
+                }
+            }
+
+            let bundled = |pdu: PduEvent| {
+                db.rooms
+                    .bundle_relations(pdu)
+                    .map(|pdu| pdu.to_room_event())
+            };
+
+            Ok::<_, Error>(Some(SearchResult {
                 context: EventContextResult {
-                    end: None,
-                    events_after: Vec::new(),
-                    events_before: Vec::new(),
-                    profile_info: BTreeMap::new(),
-                    start: None,
+                    end,
+                    events_after: events_after
+                        .into_iter()
+                        .map(|(_, pdu)| bundled(pdu))
+                        .collect::<Result<_>>()?,
+                    events_before: events_before
+                        .into_iter()
+                        .map(|(_, pdu)| bundled(pdu))
+                        .collect::<Result<_>>()?,
+                    profile_info,
+                    start,
                 },
-                rank: None,
-                result: db
-                    .rooms
-                    .get_pdu_from_id(result)?
-                    .map(|pdu| pdu.to_room_event()),
-            })
+                rank,
+                result: Some(bundled(pdu)?),
+            }))
         })
-        .filter_map(|r| r.ok())
-        .skip(skip)
-        .take(limit)
+        .filter_map(|r| r.ok().flatten())
         .collect();
 
     let next_batch = if results.len() < limit as usize {
@@ -109,11 +239,257 @@ pub async fn search_events_route(
             next_batch,
             results,
             state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            highlights: words,
         },
     }))
 }
+
+/// The hits that are ranked: the newest of each room, up to a total limit.
+fn ranking_candidates<T>(
+    searches: impl IntoIterator<Item = impl Iterator<Item = T>>,
+) -> impl Iterator<Item = T> {
+    searches
+        .into_iter()
+        .flat_map(|search| search.take(MAX_RANKED_PER_ROOM))
+        .take(MAX_RANKED)
+}
+
+/// The share of words in the message body that are search words.
+fn rank(body: &str, words: &[String]) -> f64 {
+    let body_words = search_words(body);
+
+    if body_words.is_empty() {
+        return 0.0;
+    }
+
+    let matches = body_words
+        .iter()
+        .filter(|word| words.contains(word))
+        .count();
+
+    matches as f64 / body_words.len() as f64
+}
+
+fn message_body(pdu: &PduEvent) -> Option<String> {
+    #[derive(Deserialize)]
+    struct ExtractBody<'a> {
+        #[serde(borrow)]
+        body: Option<Cow<'a, str>>,
+    }
+
+    serde_json::from_str::<ExtractBody<'_>>(pdu.content.get())
+        .ok()
+        .and_then(|content| content.body)
+        .map(Cow::into_owned)
+}
+
+/// The displayname and avatar a user currently has in the room.
+fn member_profile(db: &Database, room_id: &RoomId, user_id: &UserId) -> Result<UserProfile> {
+    let mut profile = UserProfile::new();
+
+    if let Some(member) = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
+        .and_then(|pdu| serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok())
+    {
+        profile.displayname = member.displayname;
+        profile.avatar_url = member.avatar_url;
+    }
+
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rank, ranking_candidates, search_words, MAX_RANKED, MAX_RANKED_PER_ROOM};
+
+    #[test]
+    fn closer_matches_rank_higher() {
+        let words = search_words("Lunch today");
+        assert_eq!(words, vec!["lunch".to_owned(), "today".to_owned()]);
+
+        let exact = rank("lunch today?", &words);
+        let longer = rank("Who wants to get lunch today?", &words);
+
+        assert_eq!(exact, 1.0);
+        assert!(longer < exact);
+        assert_eq!(rank("", &words), 0.0);
+    }
+
+    #[test]
+    fn ranking_candidates_are_capped() {
+        let candidates: Vec<_> = ranking_candidates(vec![0..usize::MAX, 0..10]).collect();
+        assert_eq!(candidates.len(), MAX_RANKED_PER_ROOM + 10);
+
+        let rooms = (0..MAX_RANKED).map(|_| 0..usize::MAX);
+        assert_eq!(ranking_candidates(rooms).count(), MAX_RANKED);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn only_visible_messages_are_found() {
+        use super::search_events_route;
+        use crate::{
+            database::{test_room, test_send, DatabaseGuard},
+            Ruma,
+        };
+        use ruma::{
+            api::{client::search::search_events, IncomingRequest},
+            room_id, user_id, UserId,
+        };
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("search").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        let (before, after) = {
+            let db = db.read().await;
+            test_room(&db, room_id, alice).await;
+            for (event_type, content) in [
+                ("m.room.join_rules", json!({ "join_rule": "public" })),
+                (
+                    "m.room.history_visibility",
+                    json!({ "history_visibility": "joined" }),
+                ),
+            ] {
+                test_send(&db, room_id, alice, event_type, Some(""), content)
+                    .await
+                    .unwrap();
+            }
+            let message = |body: &str| json!({ "msgtype": "m.text", "body": body });
+
+            let before = test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.message",
+                None,
+                message("Lunch today?"),
+            )
+            .await
+            .unwrap();
+            test_send(
+                &db,
+                room_id,
+                bob,
+                "m.room.member",
+                Some(bob.as_str()),
+                json!({ "membership": "join" }),
+            )
+            .await
+            .unwrap();
+            let after = test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.message",
+                None,
+                message("Lunch tomorrow?"),
+            )
+            .await
+            .unwrap();
+            test_send(
+                &db,
+                room_id,
+                bob,
+                "m.reaction",
+                None,
+                json!({
+                    "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": after,
+                        "key": "👍",
+                    }
+                }),
+            )
+            .await
+            .unwrap();
+
+            (before, after)
+        };
+
+        let search = |user_id: &UserId| {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("/_matrix/client/r0/search")
+                .body(
+                    serde_json::to_vec(&json!({
+                        "search_categories": {
+                            "room_events": {
+                                "search_term": "lunch",
+                                "event_context": { "before_limit": 5 },
+                            }
+                        }
+                    }))
+                    .unwrap(),
+                )
+                .unwrap();
+            let body = search_events::v3::IncomingRequest::try_from_http_request::<_, String>(
+                request,
+                &[],
+            )
+            .unwrap();
+            let db = Arc::clone(&db);
+            let sender_user = user_id.to_owned();
+            async move {
+                let results = search_events_route(
+                    DatabaseGuard::from(db.read_owned().await),
+                    Ruma {
+                        body,
+                        sender_user: Some(sender_user),
+                        sender_device: None,
+                        sender_servername: None,
+                        json_body: None,
+                        from_appservice: false,
+                        appservice_id: None,
+                        client_ip: None,
+                    },
+                )
+                .await
+                .unwrap()
+                .search_categories
+                .room_events
+                .results;
+
+                results
+                    .into_iter()
+                    .map(|result| {
+                        let event = |raw: &ruma::serde::Raw<_>| {
+                            serde_json::from_str::<serde_json::Value>(raw.json().get()).unwrap()
+                        };
+                        (
+                            event(result.result.as_ref().unwrap()),
+                            result.context.events_before.iter().map(event).collect(),
+                        )
+                    })
+                    .collect::<Vec<(serde_json::Value, Vec<serde_json::Value>)>>()
+            }
+        };
+
+        // New messages are indexed as they are sent
+        let alice_results = search(alice).await;
+        assert_eq!(
+            alice_results
+                .iter()
+                .map(|(event, _)| event["event_id"].clone())
+                .collect::<Vec<_>>(),
+            vec![json!(after), json!(before)]
+        );
+
+        // Bob joined after the first message and the history is only visible to members
+        let bob_results = search(bob).await;
+        assert_eq!(bob_results.len(), 1);
+        let (event, events_before) = &bob_results[0];
+        assert_eq!(event["event_id"], json!(after));
+        assert_eq!(
+            event["unsigned"]["m.relations"]["m.annotation"]["chunk"],
+            json!([{ "type": "m.reaction", "key": "👍", "count": 1 }])
+        );
+        assert!(events_before
+            .iter()
+            .all(|event| event["event_id"] != json!(before)));
+    }
+}
@@ -1,14 +1,20 @@
 use super::{DEVICE_ID_LENGTH, TOKEN_LENGTH};
-use crate::{database::DatabaseGuard, utils, Error, Result, Ruma};
+use crate::{
+    database::DatabaseGuard, utils, Database, Error, RefreshToken, RefreshableResponse, Result,
+    Ruma,
+};
+use axum::{response::IntoResponse, Json};
 use ruma::{
     api::client::{
         error::ErrorKind,
         session::{get_login_types, login, logout, logout_all},
         uiaa::IncomingUserIdentifier,
     },
-    UserId,
+    signatures::CanonicalJsonValue,
+    DeviceId, UserId,
 };
 use serde::Deserialize;
+use serde_json::json;
 use tracing::info;
 
 #[derive(Debug, Deserialize)]
@@ -21,12 +27,34 @@ struct Claims {
 ///
 /// Get the supported login types of this server. One of these should be used as the `type` field
 /// when logging in.
+///
+/// - Password login unless `allow_password_login` is false
+/// - Token login if a JWT secret is configured
+/// - Appservice login is always possible
+/// - SSO is not supported, so `m.login.sso` is never advertised
 pub async fn get_login_types_route(
+    db: DatabaseGuard,
     _body: Ruma<get_login_types::v3::IncomingRequest>,
 ) -> Result<get_login_types::v3::Response> {
-    Ok(get_login_types::v3::Response::new(vec![
-        get_login_types::v3::LoginType::Password(Default::default()),
-    ]))
+    Ok(get_login_types::v3::Response::new(login_types(
+        db.globals.allow_password_login(),
+        db.globals.jwt_decoding_key().is_some(),
+    )))
+}
+
+fn login_types(password_login: bool, token_login: bool) -> Vec<get_login_types::v3::LoginType> {
+    use get_login_types::v3::LoginType;
+
+    let mut login_types = Vec::new();
+    if password_login {
+        login_types.push(LoginType::Password(Default::default()));
+    }
+    if token_login {
+        login_types.push(LoginType::Token(Default::default()));
+    }
+    login_types.push(LoginType::ApplicationService(Default::default()));
+
+    login_types
 }
 
 /// # `POST /_matrix/client/r0/login`
@@ -34,16 +62,21 @@ pub async fn get_login_types_route(
 /// Authenticates the user and returns an access token it can use in subsequent requests.
 ///
 /// - The user needs to authenticate using their password (or if enabled using a json web token)
+/// - Appservices can log in users in their namespace with their `as_token`
 /// - If `device_id` is known: invalidates old access token of that device
 /// - If `device_id` is unknown: creates a new device
 /// - Returns access token that is associated with the user and device
+/// - If `refresh_token` is true: the access token expires and a refresh token is returned too
+/// - Limited per client IP address if `auth_rate_limit_per_second` is set
 ///
 /// Note: You can use [`GET /_matrix/client/r0/login`](fn.get_supported_versions_route.html) to see
 /// supported login types.
 pub async fn login_route(
     db: DatabaseGuard,
     body: Ruma<login::v3::IncomingRequest>,
-) -> Result<login::v3::Response> {
+) -> Result<RefreshableResponse<login::v3::Response>> {
+    db.globals.check_auth_rate_limit(body.client_ip)?;
+
     // Validate login method
     // TODO: Other login methods
     let user_id = match &body.login_info {
@@ -51,6 +84,13 @@ pub async fn login_route(
             identifier,
             password,
         }) => {
+            if !db.globals.allow_password_login() {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "Password login is disabled on this server.",
+                ));
+            }
+
             let username = if let IncomingUserIdentifier::UserIdOrLocalpart(user_id) = identifier {
                 user_id.to_lowercase()
             } else {
@@ -82,6 +122,12 @@ pub async fn login_route(
                 ));
             }
 
+            // The password is known now, so hashes from before a config change can be replaced
+            if utils::hash_needs_upgrade(&hash, db.globals.password_hashing()) {
+                db.users
+                    .set_password(&user_id, Some(password.as_str()), &db.globals)?;
+            }
+
             user_id
         }
         login::v3::IncomingLoginInfo::Token(login::v3::IncomingToken { token }) => {
@@ -103,6 +149,40 @@ pub async fn login_route(
                 ));
             }
         }
+        login::v3::IncomingLoginInfo::ApplicationService(
+            login::v3::IncomingApplicationService { identifier },
+        ) => {
+            let appservice_id = body.appservice_id.as_deref().ok_or(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Only appservices can use this login type.",
+            ))?;
+
+            let username = if let IncomingUserIdentifier::UserIdOrLocalpart(user_id) = identifier {
+                user_id.to_lowercase()
+            } else {
+                return Err(Error::BadRequest(ErrorKind::Forbidden, "Bad login type."));
+            };
+            let user_id = UserId::parse_with_server_name(username, db.globals.server_name())
+                .map_err(|_| {
+                    Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid.")
+                })?;
+
+            if !db.appservice.user_in_namespace(appservice_id, &user_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Exclusive,
+                    "User is not in the namespace of the appservice.",
+                ));
+            }
+
+            if !db.users.exists(&user_id)? {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "User does not exist.",
+                ));
+            }
+
+            user_id
+        }
         _ => {
             return Err(Error::BadRequest(
                 ErrorKind::Unknown,
@@ -138,16 +218,104 @@ pub async fn login_route(
         )?;
     }
 
+    let refresh = if refresh_token_requested(body.json_body.as_ref()) {
+        Some(issue_refresh_token(&db, &user_id, &device_id, &token)?)
+    } else {
+        None
+    };
+
     info!("{} logged in", user_id);
 
     db.flush()?;
 
-    Ok(login::v3::Response {
+    Ok(RefreshableResponse {
+        response: login::v3::Response {
+            user_id,
+            access_token: token,
+            home_server: Some(db.globals.server_name().to_owned()),
+            device_id,
+            well_known: None,
+        },
+        refresh,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// # `POST /_matrix/client/v3/refresh`
+///
+/// Trades a refresh token for a new access token and a new refresh token.
+///
+/// - The old access token and refresh token stop working
+/// - Fails for locked users
+pub async fn refresh_route(
+    db: DatabaseGuard,
+    Json(body): Json<RefreshRequest>,
+) -> Result<impl IntoResponse> {
+    let (user_id, device_id) = db
+        .users
+        .find_from_refresh_token(&body.refresh_token)?
+        .ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown refresh token.",
+        ))?;
+
+    // Locked users may only log out, so they don't get new access tokens either
+    if db.users.is_locked(&user_id)? {
+        return Err(Error::UserLocked);
+    }
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    db.users.set_token(&user_id, &device_id, &token)?;
+    let refresh = issue_refresh_token(&db, &user_id, &device_id, &token)?;
+
+    db.flush()?;
+
+    Ok(Json(json!({
+        "access_token": token,
+        "refresh_token": refresh.refresh_token,
+        "expires_in_ms": refresh.expires_in.as_millis() as u64,
+    })))
+}
+
+/// Whether a login or registration request asked for a refresh token. Ruma doesn't know the field
+/// yet, so it is read from the raw json body.
+pub(crate) fn refresh_token_requested(json_body: Option<&CanonicalJsonValue>) -> bool {
+    match json_body {
+        Some(CanonicalJsonValue::Object(body)) => {
+            matches!(
+                body.get("refresh_token"),
+                Some(CanonicalJsonValue::Bool(true))
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Lets the current access token of a device expire and creates a refresh token for it.
+pub(crate) fn issue_refresh_token(
+    db: &Database,
+    user_id: &UserId,
+    device_id: &DeviceId,
+    token: &str,
+) -> Result<RefreshToken> {
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    let expires_in = db.globals.refreshable_access_token_ttl();
+
+    db.users.set_refresh_token(
         user_id,
-        access_token: token,
-        home_server: Some(db.globals.server_name().to_owned()),
         device_id,
-        well_known: None,
+        token,
+        utils::millis_since_unix_epoch().saturating_add(expires_in.as_millis() as u64),
+        &refresh_token,
+    )?;
+
+    Ok(RefreshToken {
+        refresh_token,
+        expires_in,
     })
 }
 
@@ -166,7 +334,7 @@ pub async fn logout_route(
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
     let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
-    db.users.remove_device(sender_user, sender_device)?;
+    db.users.remove_device(sender_user, sender_device, false)?;
 
     db.flush()?;
 
@@ -191,10 +359,79 @@ pub async fn logout_all_route(
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
     for device_id in db.users.all_device_ids(sender_user).flatten() {
-        db.users.remove_device(sender_user, &device_id)?;
+        db.users.remove_device(sender_user, &device_id, false)?;
     }
 
     db.flush()?;
 
     Ok(logout_all::v3::Response::new())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::login_types;
+    use ruma::api::client::session::get_login_types::v3::LoginType;
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn locked_users_can_not_refresh_or_use_their_tokens() {
+        use super::{refresh_route, RefreshRequest};
+        use crate::{database::DatabaseGuard, Database, Error};
+        use axum::Json;
+        use ruma::{device_id, user_id};
+        use std::sync::Arc;
+        use tokio::sync::OwnedRwLockReadGuard;
+
+        let db = crate::database::test_database("locked-user").await;
+        let alice = user_id!("@alice:example.org");
+        {
+            let db = db.read().await;
+            db.users.create(alice, None, &db.globals).unwrap();
+            db.users
+                .create_device(alice, device_id!("DEVICE"), "access", None)
+                .unwrap();
+            db.users
+                .set_refresh_token(alice, device_id!("DEVICE"), "access", u64::MAX, "refresh")
+                .unwrap();
+            db.users.set_locked(alice, true).unwrap();
+
+            // The access token only works for logging out
+            assert!(matches!(
+                db.users.authenticate("access", false),
+                Err(Error::UserLocked)
+            ));
+            assert!(db.users.authenticate("access", true).is_ok());
+        }
+
+        let refresh = |db: OwnedRwLockReadGuard<Database>| {
+            refresh_route(
+                DatabaseGuard::from(db),
+                Json(RefreshRequest {
+                    refresh_token: "refresh".to_owned(),
+                }),
+            )
+        };
+
+        assert!(matches!(
+            refresh(Arc::clone(&db).read_owned().await).await,
+            Err(Error::UserLocked)
+        ));
+
+        db.read().await.users.set_locked(alice, false).unwrap();
+        assert!(refresh(Arc::clone(&db).read_owned().await).await.is_ok());
+    }
+
+    #[test]
+    fn login_types_follow_config() {
+        assert!(matches!(
+            login_types(true, false)[..],
+            [LoginType::Password(_), LoginType::ApplicationService(_)]
+        ));
+
+        // Password login disabled, users log in with a JWT
+        assert!(matches!(
+            login_types(false, true)[..],
+            [LoginType::Token(_), LoginType::ApplicationService(_)]
+        ));
+    }
+}
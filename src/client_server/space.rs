@@ -0,0 +1,662 @@
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+};
+
+use crate::{
+    database::{globals::HierarchyPage, DatabaseGuard},
+    utils, Database, Error, Result, Ruma,
+};
+use ruma::{
+    api::{
+        client::{
+            error::ErrorKind,
+            space::{get_hierarchy, SpaceHierarchyRoomsChunk},
+        },
+        federation,
+    },
+    events::{
+        room::{
+            avatar::RoomAvatarEventContent, canonical_alias::RoomCanonicalAliasEventContent,
+            create::RoomCreateEventContent, guest_access::GuestAccess,
+            history_visibility::HistoryVisibility, name::RoomNameEventContent,
+            topic::RoomTopicEventContent,
+        },
+        space::child::{HierarchySpaceChildEvent, SpaceChildEventContent},
+        StateEventType,
+    },
+    room::RoomType,
+    serde::Raw,
+    space::SpaceRoomJoinRule,
+    RoomId, ServerName, UserId,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::warn;
+
+/// Nested spaces deeper than this are not walked, whatever the client asks for.
+const MAX_DEPTH: usize = 10;
+
+/// Rooms per page if the client does not ask for a limit.
+const DEFAULT_LIMIT: u64 = 50;
+
+/// Rooms visited per request, including the ones the user may not see.
+const MAX_ROOMS_PER_REQUEST: usize = 100;
+
+/// Federation requests per request, the walk continues on the next page after that.
+const MAX_REMOTE_REQUESTS: usize = 10;
+
+/// What the servers in `via` said about a room we are not in.
+enum RemoteRoom {
+    Found(SpaceHierarchyRoomsChunk),
+    /// No server returned the room, or our server may not see it
+    Unavailable,
+    /// The room has to be asked for on the next page
+    BudgetExhausted,
+}
+
+/// # `GET /_matrix/client/v1/rooms/{roomId}/hierarchy`
+///
+/// Lists the rooms of a space, walking nested spaces breadth first.
+///
+/// - Children are the `m.space.child` state events of a space that have a `via` list
+/// - Rooms this server is not in are asked for over federation, at the servers in `via`. Their
+/// answer also describes the children of the room, which saves asking again for them
+/// - Only rooms the user is joined to or that are world readable are included
+/// - Paginated with `from` and `limit`, nested spaces are walked up to `max_depth`. Each page
+/// visits a limited number of rooms and remote servers, the `next_batch` token continues the walk
+/// where it stopped
+pub async fn get_hierarchy_route(
+    db: DatabaseGuard,
+    body: Ruma<get_hierarchy::v1::IncomingRequest>,
+) -> Result<get_hierarchy::v1::Response> {
+    let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+    // A page without rooms would hand out the same next_batch forever
+    let limit = utils::clamp_limit(
+        body.limit.map_or(DEFAULT_LIMIT, u64::from),
+        db.globals.max_pagination_limit(),
+    )
+    .max(1);
+
+    let mut page = match &body.from {
+        Some(from) => {
+            let page = db
+                .globals
+                .hierarchy_pages
+                .get(from)
+                .ok_or(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Invalid from token.",
+                ))?;
+
+            if page.user_id != *sender_user
+                || page.room_id != body.room_id
+                || page.suggested_only != body.suggested_only
+            {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "The from token belongs to another request.",
+                ));
+            }
+
+            page
+        }
+        None => HierarchyPage {
+            user_id: sender_user.clone(),
+            room_id: body.room_id.clone(),
+            suggested_only: body.suggested_only,
+            max_depth: body.max_depth.map_or(MAX_DEPTH, |max_depth| {
+                utils::clamp_limit(max_depth.into(), MAX_DEPTH)
+            }),
+            queue: iter::once((body.room_id.clone(), Vec::new(), 0)).collect(),
+            seen: HashSet::new(),
+            remote_children: HashMap::new(),
+        },
+    };
+
+    let mut rooms = Vec::new();
+    let mut visited = 0;
+    let mut remote_requests = 0;
+
+    while rooms.len() < limit && visited < MAX_ROOMS_PER_REQUEST {
+        let (room_id, via, depth) = match page.queue.pop_front() {
+            Some(next) => next,
+            None => break,
+        };
+
+        if page.seen.contains(&room_id) {
+            continue;
+        }
+
+        let room = if db.rooms.exists(&room_id)? {
+            local_room(&db, &room_id, sender_user)?
+        } else {
+            match page.remote_children.remove(&room_id) {
+                // Spaces need another request to learn about their children
+                Some(room)
+                    if room.room_type != Some(RoomType::Space) || depth >= page.max_depth =>
+                {
+                    Some(room)
+                }
+                _ if remote_requests >= MAX_REMOTE_REQUESTS => {
+                    page.queue.push_front((room_id, via, depth));
+                    break;
+                }
+                _ => match remote_room(
+                    &db,
+                    &room_id,
+                    &via,
+                    page.suggested_only,
+                    &mut remote_requests,
+                    &mut page,
+                )
+                .await
+                {
+                    RemoteRoom::Found(room) => Some(room),
+                    RemoteRoom::Unavailable => None,
+                    RemoteRoom::BudgetExhausted => {
+                        page.queue.push_front((room_id, via, depth));
+                        break;
+                    }
+                },
+            }
+            // Our server is not in the room, so the user can't be joined to it either
+            .filter(|room| room.world_readable)
+        };
+
+        page.seen.insert(room_id);
+        visited += 1;
+
+        let room = match room {
+            Some(room) => room,
+            None if depth == 0 => {
+                return Err(Error::BadRequest(
+                    ErrorKind::Forbidden,
+                    "You are not allowed to see this space.",
+                ))
+            }
+            None => continue,
+        };
+
+        if depth < page.max_depth {
+            for (child, via) in space_children(&room.children_state, page.suggested_only) {
+                page.queue.push_back((child, via, depth + 1));
+            }
+        }
+
+        rooms.push(room);
+    }
+
+    // Rooms that were already seen don't need another page
+    let seen = &page.seen;
+    page.queue.retain(|(room_id, _, _)| !seen.contains(room_id));
+
+    let next_batch = if page.queue.is_empty() {
+        None
+    } else {
+        Some(db.globals.hierarchy_pages.insert(page))
+    };
+
+    Ok(get_hierarchy::v1::Response { next_batch, rooms })
+}
+
+/// The summary of a room we are in, if the user is joined to it or it is world readable.
+fn local_room(
+    db: &Database,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<Option<SpaceHierarchyRoomsChunk>> {
+    let world_readable = db.rooms.history_visibility(room_id)? == HistoryVisibility::WorldReadable;
+
+    if !world_readable && !db.rooms.is_joined(user_id, room_id)? {
+        return Ok(None);
+    }
+
+    let children_state = db
+        .rooms
+        .room_state_type(room_id, &StateEventType::SpaceChild)?
+        .into_iter()
+        .map(|pdu| pdu.to_stripped_spacechild_state_event())
+        .collect();
+
+    Ok(Some(SpaceHierarchyRoomsChunk {
+        canonical_alias: state_content::<RoomCanonicalAliasEventContent>(
+            db,
+            room_id,
+            &StateEventType::RoomCanonicalAlias,
+        )?
+        .and_then(|content| content.alias),
+        name: state_content::<RoomNameEventContent>(db, room_id, &StateEventType::RoomName)?
+            .and_then(|content| content.name),
+        num_joined_members: db
+            .rooms
+            .room_joined_count(room_id)?
+            .unwrap_or_else(|| {
+                warn!("Room {} has no member count", room_id);
+                0
+            })
+            .try_into()
+            .expect("user count should not be that big"),
+        room_id: room_id.to_owned(),
+        topic: state_content::<RoomTopicEventContent>(db, room_id, &StateEventType::RoomTopic)?
+            .map(|content| content.topic),
+        world_readable,
+        guest_can_join: db.rooms.guest_access(room_id)? == GuestAccess::CanJoin,
+        avatar_url: state_content::<RoomAvatarEventContent>(
+            db,
+            room_id,
+            &StateEventType::RoomAvatar,
+        )?
+        .and_then(|content| content.url),
+        // Rooms without join rules are invite only
+        join_rule: SpaceRoomJoinRule::from(
+            db.rooms
+                .join_rule(room_id)?
+                .unwrap_or_else(|| "invite".to_owned()),
+        ),
+        room_type: state_content::<RoomCreateEventContent>(
+            db,
+            room_id,
+            &StateEventType::RoomCreate,
+        )?
+        .and_then(|content| content.room_type),
+        children_state,
+    }))
+}
+
+/// Asks the servers in `via` about a room we are not in. They only return it if our server may
+/// see it. The children they describe are remembered for the rest of the walk, children they say
+/// we can't see are not asked for at all.
+async fn remote_room(
+    db: &Database,
+    room_id: &RoomId,
+    via: &[Box<ServerName>],
+    suggested_only: bool,
+    remote_requests: &mut usize,
+    page: &mut HierarchyPage,
+) -> RemoteRoom {
+    for server in via
+        .iter()
+        .map(|server| &**server)
+        .chain(iter::once(room_id.server_name()))
+        .filter(|server| *server != db.globals.server_name())
+    {
+        if *remote_requests >= MAX_REMOTE_REQUESTS {
+            return RemoteRoom::BudgetExhausted;
+        }
+        *remote_requests += 1;
+
+        match db
+            .sending
+            .send_federation_request(
+                &db.globals,
+                server,
+                federation::space::get_hierarchy::v1::Request {
+                    room_id,
+                    suggested_only,
+                },
+            )
+            .await
+        {
+            Ok(response) => {
+                for child in response.children {
+                    page.remote_children
+                        .entry(child.room_id.clone())
+                        .or_insert_with(|| SpaceHierarchyRoomsChunk {
+                            canonical_alias: child.canonical_alias,
+                            name: child.name,
+                            num_joined_members: child.num_joined_members,
+                            room_id: child.room_id,
+                            topic: child.topic,
+                            world_readable: child.world_readable,
+                            guest_can_join: child.guest_can_join,
+                            avatar_url: child.avatar_url,
+                            join_rule: child.join_rule,
+                            room_type: child.room_type,
+                            children_state: Vec::new(),
+                        });
+                }
+                page.seen.extend(response.inaccessible_children);
+
+                let room = response.room;
+
+                return RemoteRoom::Found(SpaceHierarchyRoomsChunk {
+                    canonical_alias: room.canonical_alias,
+                    name: room.name,
+                    num_joined_members: room.num_joined_members,
+                    room_id: room.room_id,
+                    topic: room.topic,
+                    world_readable: room.world_readable,
+                    guest_can_join: room.guest_can_join,
+                    avatar_url: room.avatar_url,
+                    join_rule: room.join_rule,
+                    room_type: room.room_type,
+                    children_state: room.children_state,
+                });
+            }
+            Err(e) => warn!(
+                "Failed to get hierarchy of {} from {}: {}",
+                room_id, server, e
+            ),
+        }
+    }
+
+    RemoteRoom::Unavailable
+}
+
+fn state_content<T: DeserializeOwned>(
+    db: &Database,
+    room_id: &RoomId,
+    event_type: &StateEventType,
+) -> Result<Option<T>> {
+    db.rooms
+        .room_state_get(room_id, event_type, "")?
+        .map(|pdu| {
+            serde_json::from_str(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid room state event in database."))
+        })
+        .transpose()
+}
+
+/// The children of a space and the servers to ask about them. Children without `via` were removed
+/// from the space.
+fn space_children(
+    children_state: &[Raw<HierarchySpaceChildEvent>],
+    suggested_only: bool,
+) -> Vec<(Box<RoomId>, Vec<Box<ServerName>>)> {
+    #[derive(Deserialize)]
+    struct ExtractChild {
+        state_key: Box<RoomId>,
+        content: SpaceChildEventContent,
+    }
+
+    children_state
+        .iter()
+        .filter_map(|event| event.deserialize_as::<ExtractChild>().ok())
+        .filter(|child| !suggested_only || child.content.suggested)
+        .filter_map(|child| match child.content.via {
+            Some(via) if !via.is_empty() => Some((child.state_key, via)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::space_children;
+    use ruma::{room_id, serde::Raw, server_name};
+    use serde_json::{json, value::to_raw_value};
+
+    #[test]
+    fn children_need_via() {
+        let child = |room_id: &str, content| {
+            Raw::from_json(
+                to_raw_value(&json!({
+                    "type": "m.space.child",
+                    "state_key": room_id,
+                    "sender": "@admin:example.org",
+                    "origin_server_ts": 0,
+                    "content": content,
+                }))
+                .unwrap(),
+            )
+        };
+
+        let children_state = vec![
+            child(
+                "!suggested:example.org",
+                json!({ "via": ["example.org"], "suggested": true }),
+            ),
+            child("!other:example.com", json!({ "via": ["example.com"] })),
+            child("!removed:example.org", json!({})),
+            child("!empty:example.org", json!({ "via": [] })),
+        ];
+
+        assert_eq!(
+            space_children(&children_state, false),
+            vec![
+                (
+                    room_id!("!suggested:example.org").to_owned(),
+                    vec![server_name!("example.org").to_owned()]
+                ),
+                (
+                    room_id!("!other:example.com").to_owned(),
+                    vec![server_name!("example.com").to_owned()]
+                ),
+            ]
+        );
+        assert_eq!(space_children(&children_state, true).len(), 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn hierarchy_is_paginated_and_only_shows_visible_rooms() {
+        use super::get_hierarchy_route;
+        use crate::{
+            database::{globals::HierarchyPage, test_room, test_send, DatabaseGuard},
+            Database, Error, Ruma,
+        };
+        use ruma::{
+            api::client::{
+                error::ErrorKind,
+                space::{get_hierarchy, SpaceHierarchyRoomsChunk},
+            },
+            api::IncomingRequest,
+            space::SpaceRoomJoinRule,
+            uint, user_id, RoomId, UserId,
+        };
+        use std::{collections::HashSet, sync::Arc};
+        use tokio::sync::RwLock;
+
+        async fn hierarchy(
+            db: &Arc<RwLock<Database>>,
+            user_id: &UserId,
+            query: &str,
+        ) -> Result<get_hierarchy::v1::Response, Error> {
+            let request = http::Request::builder()
+                .uri(format!(
+                    "/_matrix/client/v1/rooms/%21space%3Aexample.org/hierarchy{}",
+                    query
+                ))
+                .body(Vec::<u8>::new())
+                .unwrap();
+
+            get_hierarchy_route(
+                DatabaseGuard::from(Arc::clone(db).read_owned().await),
+                Ruma {
+                    body: get_hierarchy::v1::IncomingRequest::try_from_http_request(
+                        request,
+                        &["!space:example.org"],
+                    )
+                    .unwrap(),
+                    sender_user: Some(user_id.to_owned()),
+                    sender_device: None,
+                    sender_servername: None,
+                    json_body: None,
+                    from_appservice: false,
+                    appservice_id: None,
+                    client_ip: None,
+                },
+            )
+            .await
+        }
+
+        fn room_ids(response: &get_hierarchy::v1::Response) -> HashSet<&str> {
+            response
+                .rooms
+                .iter()
+                .map(|room| room.room_id.as_str())
+                .collect()
+        }
+
+        let db = crate::database::test_database("hierarchy").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        {
+            let guard = db.read().await;
+            let db: &Database = &guard;
+
+            let space = |room_id: &str| {
+                let room_id = RoomId::parse(room_id).unwrap();
+                async move {
+                    test_send(
+                        db,
+                        &room_id,
+                        alice,
+                        "m.room.create",
+                        Some(""),
+                        json!({ "creator": alice, "room_version": "9", "type": "m.space" }),
+                    )
+                    .await
+                    .unwrap();
+                    test_send(
+                        db,
+                        &room_id,
+                        alice,
+                        "m.room.member",
+                        Some(alice.as_str()),
+                        json!({ "membership": "join" }),
+                    )
+                    .await
+                    .unwrap();
+                }
+            };
+            space("!space:example.org").await;
+            space("!sub:example.org").await;
+
+            test_room(db, room_id!("!joined:example.org"), alice).await;
+            test_room(db, room_id!("!deep:example.org"), alice).await;
+            test_room(db, room_id!("!hidden:example.org"), bob).await;
+            test_room(db, room_id!("!readable:example.org"), bob).await;
+            test_send(
+                db,
+                room_id!("!readable:example.org"),
+                bob,
+                "m.room.history_visibility",
+                Some(""),
+                json!({ "history_visibility": "world_readable" }),
+            )
+            .await
+            .unwrap();
+
+            for (parent, child) in [
+                ("!space:example.org", "!joined:example.org"),
+                ("!space:example.org", "!hidden:example.org"),
+                ("!space:example.org", "!readable:example.org"),
+                ("!space:example.org", "!sub:example.org"),
+                ("!sub:example.org", "!deep:example.org"),
+            ] {
+                test_send(
+                    db,
+                    &RoomId::parse(parent).unwrap(),
+                    alice,
+                    "m.space.child",
+                    Some(child),
+                    json!({ "via": ["example.org"] }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let everything: HashSet<_> = [
+            "!space:example.org",
+            "!joined:example.org",
+            "!readable:example.org",
+            "!sub:example.org",
+            "!deep:example.org",
+        ]
+        .into_iter()
+        .collect();
+
+        let response = hierarchy(&db, alice, "").await.unwrap();
+        assert_eq!(room_ids(&response), everything);
+        assert!(response.next_batch.is_none());
+
+        let response = hierarchy(&db, alice, "?max_depth=0").await.unwrap();
+        assert_eq!(
+            room_ids(&response),
+            ["!space:example.org"].into_iter().collect()
+        );
+        let response = hierarchy(&db, alice, "?max_depth=1").await.unwrap();
+        assert!(!room_ids(&response).contains("!deep:example.org"));
+        assert_eq!(response.rooms.len(), 4);
+
+        // Pages continue the walk where the last one stopped
+        let mut pages = 0;
+        let mut seen = HashSet::new();
+        let mut query = "?limit=2".to_owned();
+        loop {
+            let response = hierarchy(&db, alice, &query).await.unwrap();
+            assert!(response.rooms.len() <= 2);
+            for room in &response.rooms {
+                assert!(seen.insert(room.room_id.to_string()));
+            }
+            pages += 1;
+            match response.next_batch {
+                Some(next_batch) => query = format!("?limit=2&from={}", next_batch),
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(
+            seen.iter().map(String::as_str).collect::<HashSet<_>>(),
+            everything
+        );
+
+        // Every page has at least one room
+        let response = hierarchy(&db, alice, "?limit=0").await.unwrap();
+        assert_eq!(response.rooms.len(), 1);
+        assert!(response.next_batch.is_some());
+
+        assert!(matches!(
+            hierarchy(&db, bob, "").await,
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+
+        // Remote rooms the user can't see are left out as well
+        let remote = |room_id: &str, world_readable| SpaceHierarchyRoomsChunk {
+            canonical_alias: None,
+            name: None,
+            num_joined_members: uint!(1),
+            room_id: RoomId::parse(room_id).unwrap(),
+            topic: None,
+            world_readable,
+            guest_can_join: false,
+            avatar_url: None,
+            join_rule: SpaceRoomJoinRule::Public,
+            room_type: None,
+            children_state: Vec::new(),
+        };
+        let token = db
+            .read()
+            .await
+            .globals
+            .hierarchy_pages
+            .insert(HierarchyPage {
+                user_id: alice.to_owned(),
+                room_id: room_id!("!space:example.org").to_owned(),
+                suggested_only: false,
+                max_depth: 10,
+                queue: ["!private:remote.org", "!public:remote.org"]
+                    .into_iter()
+                    .map(|room_id| (RoomId::parse(room_id).unwrap(), Vec::new(), 1))
+                    .collect(),
+                seen: HashSet::new(),
+                remote_children: [
+                    remote("!private:remote.org", false),
+                    remote("!public:remote.org", true),
+                ]
+                .into_iter()
+                .map(|room| (room.room_id.clone(), room))
+                .collect(),
+            });
+        let response = hierarchy(&db, alice, &format!("?from={}", token))
+            .await
+            .unwrap();
+        assert_eq!(
+            room_ids(&response),
+            ["!public:remote.org"].into_iter().collect()
+        );
+    }
+}
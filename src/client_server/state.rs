@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::{
-    database::DatabaseGuard, pdu::PduBuilder, Database, Error, Result, Ruma, RumaResponse,
+    client_server::get_alias_helper, database::DatabaseGuard, pdu::PduBuilder, Database, Error,
+    PduEvent, Result, Ruma, RumaResponse,
 };
 use ruma::{
     api::client::{
@@ -11,13 +12,19 @@ use ruma::{
     events::{
         room::{
             canonical_alias::RoomCanonicalAliasEventContent,
-            history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
+            history_visibility::HistoryVisibility,
+            member::{MembershipState, RoomMemberEventContent},
+            power_levels::RoomPowerLevelsEventContent,
         },
-        AnyStateEventContent, StateEventType,
+        AnyStateEventContent, RoomEventType, StateEventType,
     },
     serde::Raw,
-    EventId, RoomId, UserId,
+    EventId, Int, RoomAliasId, RoomId, UserId,
 };
+use serde_json::value::RawValue as RawJsonValue;
+
+/// The room encryption algorithm supported by clients.
+pub(crate) const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey}`
 ///
@@ -25,7 +32,9 @@ use ruma::{
 ///
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
-/// - If event is new canonical_alias: Rejects if alias is incorrect
+/// - If event is new canonical_alias: Rejects added aliases that don't point to this room
+/// - If event is new power_levels and `prevent_power_level_lockout` is set: Rejects if no member
+/// could change power levels or invite afterwards
 pub async fn send_state_event_for_key_route(
     db: DatabaseGuard,
     body: Ruma<send_state_event::v3::IncomingRequest>,
@@ -54,7 +63,9 @@ pub async fn send_state_event_for_key_route(
 ///
 /// - The only requirement for the content is that it has to be valid json
 /// - Tries to send the event into the room, auth rules will determine if it is allowed
-/// - If event is new canonical_alias: Rejects if alias is incorrect
+/// - If event is new canonical_alias: Rejects added aliases that don't point to this room
+/// - If event is new power_levels and `prevent_power_level_lockout` is set: Rejects if no member
+/// could change power levels or invite afterwards
 pub async fn send_state_event_for_empty_key_route(
     db: DatabaseGuard,
     body: Ruma<send_state_event::v3::IncomingRequest>,
@@ -89,45 +100,25 @@ pub async fn send_state_event_for_empty_key_route(
 ///
 /// Get all state events for a room.
 ///
-/// - If not joined: Only works if current room history visibility is world readable
+/// - If not joined: Only works if current room history visibility is world readable or the user
+/// left the room, then they get the state at the time they left
+/// - Guests additionally need the room to allow guest access or be world readable
 pub async fn get_state_events_route(
     db: DatabaseGuard,
     body: Ruma<get_state_events::v3::IncomingRequest>,
 ) -> Result<get_state_events::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    #[allow(clippy::blocks_in_if_conditions)]
-    // Users not in the room should not be able to access the state unless history_visibility is
-    // WorldReadable
-    if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &StateEventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_str(event.content.get())
-                        .map(|e: RoomHistoryVisibilityEventContent| e.history_visibility)
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
-        ));
-    }
+    let state = match visible_state(&db, sender_user, &body.room_id)? {
+        VisibleState::Current => db.rooms.room_state_full(&body.room_id)?,
+        VisibleState::AtLeave {
+            shortstatehash,
+            leave_event,
+        } => state_at_leave(db.rooms.state_full(shortstatehash)?, leave_event),
+    };
 
     Ok(get_state_events::v3::Response {
-        room_state: db
-            .rooms
-            .room_state_full(&body.room_id)?
-            .values()
-            .map(|pdu| pdu.to_state_event())
-            .collect(),
+        room_state: state.values().map(|pdu| pdu.to_state_event()).collect(),
     })
 }
 
@@ -135,49 +126,23 @@ pub async fn get_state_events_route(
 ///
 /// Get single state event of a room.
 ///
-/// - If not joined: Only works if current room history visibility is world readable
+/// - If not joined: Only works if current room history visibility is world readable or the user
+/// left the room, then they get the state at the time they left
+/// - Guests additionally need the room to allow guest access or be world readable
 pub async fn get_state_events_for_key_route(
     db: DatabaseGuard,
     body: Ruma<get_state_events_for_key::v3::IncomingRequest>,
 ) -> Result<get_state_events_for_key::v3::Response> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    #[allow(clippy::blocks_in_if_conditions)]
-    // Users not in the room should not be able to access the state unless history_visibility is
-    // WorldReadable
-    if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &StateEventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_str(event.content.get())
-                        .map(|e: RoomHistoryVisibilityEventContent| e.history_visibility)
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
-    {
-        return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
-        ));
-    }
-
-    let event = db
-        .rooms
-        .room_state_get(&body.room_id, &body.event_type, &body.state_key)?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "State event not found.",
-        ))?;
-
     Ok(get_state_events_for_key::v3::Response {
-        content: serde_json::from_str(event.content.get())
-            .map_err(|_| Error::bad_database("Invalid event content in database"))?,
+        content: visible_state_event(
+            &db,
+            sender_user,
+            &body.room_id,
+            &body.event_type,
+            &body.state_key,
+        )?,
     })
 }
 
@@ -185,51 +150,130 @@ pub async fn get_state_events_for_key_route(
 ///
 /// Get single state event of a room.
 ///
-/// - If not joined: Only works if current room history visibility is world readable
+/// - If not joined: Only works if current room history visibility is world readable or the user
+/// left the room, then they get the state at the time they left
+/// - Guests additionally need the room to allow guest access or be world readable
 pub async fn get_state_events_for_empty_key_route(
     db: DatabaseGuard,
     body: Ruma<get_state_events_for_key::v3::IncomingRequest>,
 ) -> Result<RumaResponse<get_state_events_for_key::v3::Response>> {
     let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
-    #[allow(clippy::blocks_in_if_conditions)]
-    // Users not in the room should not be able to access the state unless history_visibility is
-    // WorldReadable
-    if !db.rooms.is_joined(sender_user, &body.room_id)?
-        && !matches!(
-            db.rooms
-                .room_state_get(&body.room_id, &StateEventType::RoomHistoryVisibility, "")?
-                .map(|event| {
-                    serde_json::from_str(event.content.get())
-                        .map(|e: RoomHistoryVisibilityEventContent| e.history_visibility)
-                        .map_err(|_| {
-                            Error::bad_database(
-                                "Invalid room history visibility event in database.",
-                            )
-                        })
-                }),
-            Some(Ok(HistoryVisibility::WorldReadable))
-        )
-    {
+    Ok(get_state_events_for_key::v3::Response {
+        content: visible_state_event(&db, sender_user, &body.room_id, &body.event_type, "")?,
+    }
+    .into())
+}
+
+/// The room state a user is allowed to see.
+enum VisibleState {
+    /// Members and everyone in world readable rooms see the current state
+    Current,
+    /// Former members see the state as it was when they left
+    AtLeave {
+        /// The state before the leave event
+        shortstatehash: u64,
+        leave_event: Arc<PduEvent>,
+    },
+}
+
+fn visible_state(db: &Database, sender_user: &UserId, room_id: &RoomId) -> Result<VisibleState> {
+    if db.users.is_guest(sender_user)? && !db.rooms.guest_can_read(room_id)? {
         return Err(Error::BadRequest(
-            ErrorKind::Forbidden,
-            "You don't have permission to view the room state.",
+            ErrorKind::GuestAccessForbidden,
+            "Guests are not allowed to view this room.",
         ));
     }
 
-    let event = db
-        .rooms
-        .room_state_get(&body.room_id, &body.event_type, "")?
-        .ok_or(Error::BadRequest(
-            ErrorKind::NotFound,
-            "State event not found.",
-        ))?;
+    if db.rooms.is_joined(sender_user, room_id)?
+        || db.rooms.history_visibility(room_id)? == HistoryVisibility::WorldReadable
+    {
+        return Ok(VisibleState::Current);
+    }
+
+    let membership = |pdu: &PduEvent| {
+        serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+            .map(|content| content.membership)
+            .map_err(|_| Error::bad_database("Invalid member event in database."))
+    };
 
-    Ok(get_state_events_for_key::v3::Response {
-        content: serde_json::from_str(event.content.get())
-            .map_err(|_| Error::bad_database("Invalid event content in database"))?,
+    if let Some(leave_event) =
+        db.rooms
+            .room_state_get(room_id, &StateEventType::RoomMember, sender_user.as_str())?
+    {
+        if matches!(
+            membership(&leave_event)?,
+            MembershipState::Leave | MembershipState::Ban
+        ) {
+            if let Some(shortstatehash) = db.rooms.pdu_shortstatehash(&leave_event.event_id)? {
+                // Users who only rejected an invite never saw the room
+                let was_joined = db
+                    .rooms
+                    .state_get(
+                        shortstatehash,
+                        &StateEventType::RoomMember,
+                        sender_user.as_str(),
+                    )?
+                    .map(|pdu| membership(&pdu))
+                    .transpose()?
+                    == Some(MembershipState::Join);
+
+                if was_joined {
+                    return Ok(VisibleState::AtLeave {
+                        shortstatehash,
+                        leave_event,
+                    });
+                }
+            }
+        }
     }
-    .into())
+
+    Err(Error::BadRequest(
+        ErrorKind::Forbidden,
+        "You don't have permission to view the room state.",
+    ))
+}
+
+/// The state right after a user left: the state before their leave event plus the leave event.
+fn state_at_leave(
+    mut state_before: HashMap<(StateEventType, String), Arc<PduEvent>>,
+    leave_event: Arc<PduEvent>,
+) -> HashMap<(StateEventType, String), Arc<PduEvent>> {
+    if let Some(state_key) = leave_event.state_key.clone() {
+        state_before.insert((StateEventType::RoomMember, state_key), leave_event);
+    }
+
+    state_before
+}
+
+/// Returns the content of a state event the user is allowed to see.
+fn visible_state_event(
+    db: &Database,
+    sender_user: &UserId,
+    room_id: &RoomId,
+    event_type: &StateEventType,
+    state_key: &str,
+) -> Result<Raw<AnyStateEventContent>> {
+    let event = match visible_state(db, sender_user, room_id)? {
+        VisibleState::Current => db.rooms.room_state_get(room_id, event_type, state_key)?,
+        VisibleState::AtLeave {
+            shortstatehash,
+            leave_event,
+        } => state_at_leave(db.rooms.state_full(shortstatehash)?, leave_event)
+            .remove(&(event_type.clone(), state_key.to_owned())),
+    };
+
+    state_event_content(event)
+}
+
+fn state_event_content(event: Option<Arc<PduEvent>>) -> Result<Raw<AnyStateEventContent>> {
+    let event = event.ok_or(Error::BadRequest(
+        ErrorKind::NotFound,
+        "State event not found.",
+    ))?;
+
+    serde_json::from_str(event.content.get())
+        .map_err(|_| Error::bad_database("Invalid event content in database"))
 }
 
 async fn send_state_event_for_key_helper(
@@ -242,30 +286,60 @@ async fn send_state_event_for_key_helper(
 ) -> Result<Arc<EventId>> {
     let sender_user = sender;
 
-    // TODO: Review this check, error if event is unparsable, use event type, allow alias if it
-    // previously existed
-    if let Ok(canonical_alias) =
-        serde_json::from_str::<RoomCanonicalAliasEventContent>(json.json().get())
-    {
-        let mut aliases = canonical_alias.alt_aliases.clone();
+    if *event_type == StateEventType::RoomEncryption {
+        check_encryption_algorithm(json.json())?;
+    }
+
+    if *event_type == StateEventType::RoomPowerLevels && db.globals.prevent_power_level_lockout() {
+        // Unparsable content is rejected by the auth rules later
+        if let Ok(power_levels) =
+            serde_json::from_str::<RoomPowerLevelsEventContent>(json.json().get())
+        {
+            let members = db
+                .rooms
+                .room_members(room_id)
+                .filter_map(|r| r.ok())
+                .collect::<Vec<_>>();
 
-        if let Some(alias) = canonical_alias.alias {
-            aliases.push(alias);
+            check_power_level_floor(&power_levels, &members)?;
         }
+    }
 
-        for alias in aliases {
-            if alias.server_name() != db.globals.server_name()
-                || db
-                    .rooms
-                    .id_from_alias(&alias)?
-                    .filter(|room| room == room_id) // Make sure it's the right room
-                    .is_none()
-            {
-                return Err(Error::BadRequest(
-                    ErrorKind::Forbidden,
-                    "You are only allowed to send canonical_alias \
-                    events when it's aliases already exists",
-                ));
+    if *event_type == StateEventType::RoomCanonicalAlias {
+        // Unparsable content is rejected by the auth rules later
+        if let Ok(canonical_alias) =
+            serde_json::from_str::<RoomCanonicalAliasEventContent>(json.json().get())
+        {
+            let current_aliases = db
+                .rooms
+                .room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+                .and_then(|pdu| {
+                    serde_json::from_str::<RoomCanonicalAliasEventContent>(pdu.content.get()).ok()
+                })
+                .map_or_else(Vec::new, canonical_aliases);
+
+            for alias in canonical_aliases(canonical_alias) {
+                // Aliases that are already in the event can stay
+                if current_aliases.contains(&alias) {
+                    continue;
+                }
+
+                let points_to_room = if alias.server_name() == db.globals.server_name() {
+                    db.rooms
+                        .id_from_alias(&alias)?
+                        .map_or(false, |room| &*room == room_id)
+                } else {
+                    get_alias_helper(db, &alias)
+                        .await
+                        .map_or(false, |response| &*response.room_id == room_id)
+                };
+
+                if !points_to_room {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Forbidden,
+                        "Canonical aliases have to exist and point to this room.",
+                    ));
+                }
             }
         }
     }
@@ -296,3 +370,175 @@ async fn send_state_event_for_key_helper(
 
     Ok(event_id)
 }
+
+/// The main alias and the alternative aliases of a canonical alias event.
+fn canonical_aliases(content: RoomCanonicalAliasEventContent) -> Vec<Box<RoomAliasId>> {
+    content
+        .alias
+        .into_iter()
+        .chain(content.alt_aliases)
+        .collect()
+}
+
+/// The power level of a user, `users_default` if the power levels don't list them.
+pub(crate) fn user_power_level(
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+) -> Int {
+    power_levels
+        .users
+        .get(user_id)
+        .copied()
+        .unwrap_or(power_levels.users_default)
+}
+
+/// The power level needed to send state events of the given type.
+pub(crate) fn state_event_power_level(
+    power_levels: &RoomPowerLevelsEventContent,
+    event_type: &RoomEventType,
+) -> Int {
+    power_levels
+        .events
+        .get(event_type)
+        .copied()
+        .unwrap_or(power_levels.state_default)
+}
+
+/// Whether the user's power level is enough to send state events of the given type.
+pub(crate) fn may_send_state_event(
+    power_levels: &RoomPowerLevelsEventContent,
+    user_id: &UserId,
+    event_type: &RoomEventType,
+) -> bool {
+    user_power_level(power_levels, user_id) >= state_event_power_level(power_levels, event_type)
+}
+
+/// Rejects power levels after which no member could change power levels or invite anyone, the
+/// room could never be governed again.
+fn check_power_level_floor(
+    power_levels: &RoomPowerLevelsEventContent,
+    members: &[Box<UserId>],
+) -> Result<()> {
+    let change_power_levels =
+        state_event_power_level(power_levels, &RoomEventType::RoomPowerLevels);
+
+    let highest_level = members
+        .iter()
+        .map(|user_id| user_power_level(power_levels, user_id))
+        .max();
+
+    match highest_level {
+        Some(level) if level >= change_power_levels && level >= power_levels.invite => Ok(()),
+        _ => Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "No room member could change power levels or invite users anymore.",
+        )),
+    }
+}
+
+/// Rejects `m.room.encryption` content that doesn't use megolm, clients couldn't send messages
+/// in such a room.
+pub(crate) fn check_encryption_algorithm(content: &RawJsonValue) -> Result<()> {
+    let algorithm = serde_json::from_str::<serde_json::Value>(content.get())
+        .ok()
+        .and_then(|content| content.get("algorithm")?.as_str().map(ToOwned::to_owned));
+
+    match algorithm.as_deref() {
+        Some(MEGOLM_ALGORITHM) => Ok(()),
+        Some(_) => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Unsupported encryption algorithm, only m.megolm.v1.aes-sha2 is supported.",
+        )),
+        None => Err(Error::BadRequest(
+            ErrorKind::MissingParam,
+            "Encryption event is missing the algorithm.",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_encryption_algorithm, check_power_level_floor, state_at_leave, state_event_content,
+    };
+    use crate::{Error, PduEvent};
+    use ruma::{api::client::error::ErrorKind, events::StateEventType};
+    use serde_json::{json, value::to_raw_value};
+    use std::{collections::HashMap, sync::Arc};
+
+    fn member_event(event_id: &str, membership: &str) -> Arc<PduEvent> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "event_id": event_id,
+                "room_id": "!room:example.org",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 1,
+                "type": "m.room.member",
+                "state_key": "@alice:example.org",
+                "content": { "membership": membership },
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn only_megolm_is_accepted() {
+        let check = |content: serde_json::Value| {
+            check_encryption_algorithm(&to_raw_value(&content).unwrap())
+        };
+
+        assert!(check(json!({ "algorithm": "m.megolm.v1.aes-sha2" })).is_ok());
+        assert!(check(json!({ "algorithm": "m.olm.v1.curve25519-aes-sha2" })).is_err());
+        assert!(check(json!({ "algorithm": "org.example.rot13" })).is_err());
+        assert!(check(json!({})).is_err());
+    }
+
+    #[test]
+    fn left_user_sees_state_at_leave() {
+        let key = (StateEventType::RoomMember, "@alice:example.org".to_owned());
+        let state_before = HashMap::from([(key.clone(), member_event("$join", "join"))]);
+
+        let state = state_at_leave(state_before, member_event("$leave", "leave"));
+
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[&key].event_id.as_str(), "$leave");
+    }
+
+    #[test]
+    fn missing_state_event_is_not_found() {
+        assert!(matches!(
+            state_event_content(None),
+            Err(Error::BadRequest(ErrorKind::NotFound, _))
+        ));
+        assert!(state_event_content(Some(member_event("$join", "join"))).is_ok());
+    }
+
+    #[test]
+    fn power_levels_without_admin_are_rejected() {
+        let members = [
+            ruma::user_id!("@alice:example.org").to_owned(),
+            ruma::user_id!("@bob:example.org").to_owned(),
+        ];
+        let check = |content: serde_json::Value| {
+            check_power_level_floor(&serde_json::from_value(content).unwrap(), &members)
+        };
+
+        assert!(check(json!({ "users": { "@alice:example.org": 100 } })).is_ok());
+        assert!(matches!(
+            check(json!({ "users": { "@alice:example.org": 0 } })),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        // Admins who left the room don't count
+        assert!(check(json!({ "users": { "@carol:example.org": 100 } })).is_err());
+        assert!(check(json!({
+            "users": { "@alice:example.org": 50 },
+            "events": { "m.room.power_levels": 50 },
+            "invite": 100,
+        }))
+        .is_err());
+    }
+}
@@ -1,17 +1,28 @@
-use crate::{database::DatabaseGuard, Database, Error, Result, Ruma, RumaResponse};
+use crate::{
+    database::{globals::ActiveSync, pusher, DatabaseGuard},
+    Database, Error, PduEvent, Result, Ruma, RumaResponse,
+};
 use ruma::{
     api::client::{
-        filter::{IncomingFilterDefinition, LazyLoadOptions},
+        filter::{IncomingFilterDefinition, IncomingRoomFilter, LazyLoadOptions},
         sync::sync_events,
         uiaa::UiaaResponse,
     },
     events::{
-        room::member::{MembershipState, RoomMemberEventContent},
-        RoomEventType, StateEventType,
+        ignored_user_list::IgnoredUserListEvent,
+        push_rules::PushRulesEvent,
+        room::{
+            member::{MembershipState, RoomMemberEventContent},
+            power_levels::RoomPowerLevelsEventContent,
+        },
+        AnyStrippedStateEvent, AnySyncEphemeralRoomEvent, GlobalAccountDataEventType,
+        RoomAccountDataEventType, RoomEventType, StateEventType,
     },
+    push::Ruleset,
     serde::Raw,
     DeviceId, RoomId, UserId,
 };
+use serde_json::{json, value::to_raw_value};
 use std::{
     collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     sync::Arc,
@@ -44,16 +55,23 @@ use tracing::error;
 /// - Device list updates that happened after `since`
 /// - If there are events in the timeline we send or the user send updated his read mark: Notification counts
 /// - EDUs that are active now (read receipts, typing updates, presence)
+/// - Messages, receipts and typing notifications of ignored users are left out
+/// - If the ignore list changed: The timelines start over and are marked as limited
 /// - TODO: Allow multiple sync streams to support Pantalaimon
 ///
 /// For invited rooms:
 /// - If the user was invited after `since`: A subset of the state of the room at the point of the invite
 ///
+/// For knocked rooms:
+/// - If the user knocked after `since`: A subset of the state of the room at the point of the knock
+///
 /// For left rooms:
 /// - If the user left after `since`: prev_batch token, empty state (TODO: subset of the state at the point of the leave)
 ///
 /// - Sync is handled in an async task, multiple requests from the same device with the same
 /// `since` will be cached
+/// - Users can only have `max_concurrent_syncs_per_user` syncs running at the same time, a sync
+/// counts until its async task is done
 pub async fn sync_events_route(
     db: DatabaseGuard,
     body: Ruma<sync_events::v3::IncomingRequest>,
@@ -72,6 +90,11 @@ pub async fn sync_events_route(
         .entry((sender_user.clone(), sender_device.clone()))
     {
         Entry::Vacant(v) => {
+            let active_sync = arc_db
+                .globals
+                .active_syncs
+                .start(&sender_user)
+                .map_err(|e| e.to_response())?;
             let (tx, rx) = tokio::sync::watch::channel(None);
 
             v.insert((body.since.to_owned(), rx.clone()));
@@ -82,12 +105,18 @@ pub async fn sync_events_route(
                 sender_device.clone(),
                 body,
                 tx,
+                active_sync,
             ));
 
             rx
         }
         Entry::Occupied(mut o) => {
             if o.get().0 != body.since {
+                let active_sync = arc_db
+                    .globals
+                    .active_syncs
+                    .start(&sender_user)
+                    .map_err(|e| e.to_response())?;
                 let (tx, rx) = tokio::sync::watch::channel(None);
 
                 o.insert((body.since.clone(), rx.clone()));
@@ -98,6 +127,7 @@ pub async fn sync_events_route(
                     sender_device.clone(),
                     body,
                     tx,
+                    active_sync,
                 ));
 
                 rx
@@ -132,6 +162,8 @@ async fn sync_helper_wrapper(
     sender_device: Box<DeviceId>,
     body: sync_events::v3::IncomingRequest,
     tx: Sender<Option<Result<sync_events::v3::Response>>>,
+    // Counts against the limit of the user until the sync is done
+    _active_sync: ActiveSync,
 ) {
     let since = body.since.clone();
 
@@ -177,12 +209,15 @@ async fn sync_helper(
 ) -> Result<(sync_events::v3::Response, bool), Error> {
     use sync_events::v3::{
         DeviceLists, Ephemeral, GlobalAccountData, IncomingFilter, InviteState, InvitedRoom,
-        JoinedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary, Rooms, State, Timeline,
-        ToDevice, UnreadNotificationsCount,
+        JoinedRoom, KnockState, KnockedRoom, LeftRoom, Presence, RoomAccountData, RoomSummary,
+        Rooms, State, Timeline, ToDevice, UnreadNotificationsCount,
     };
 
     // TODO: match body.set_presence {
-    db.rooms.edus.ping_presence(&sender_user)?;
+    if db.globals.allow_presence() {
+        db.rooms.edus.ping_presence(&sender_user)?;
+    }
+    db.users.touch_device(&sender_user, &sender_device)?;
 
     // Setup watchers, so if there's no response, we can wait for them
     let watcher = db.watch(&sender_user, &sender_device);
@@ -214,6 +249,13 @@ async fn sync_helper(
         .and_then(|string| string.parse().ok())
         .unwrap_or(0);
 
+    let ignored = ignored_users(&db, &sender_user)?;
+
+    // Which events are hidden depends on the ignore list, so after it changed the timelines start
+    // over instead of continuing where the last sync stopped
+    let ignore_list_changed = ignore_list_changed(&db, &sender_user, since)?;
+    let timeline_since = if ignore_list_changed { 0 } else { since };
+
     let mut presence_updates = HashMap::new();
     let mut left_encrypted_users = HashSet::new(); // Users that have left any encrypted rooms the sender was in
     let mut device_list_updates = HashSet::new();
@@ -226,10 +268,25 @@ async fn sync_helper(
             .filter_map(|r| r.ok()),
     );
 
-    let all_joined_rooms = db.rooms.rooms_joined(&sender_user).collect::<Vec<_>>();
+    let all_joined_rooms = match &filter.room.rooms {
+        // Clients tracking a few rooms don't have to pay for all the others
+        Some(rooms) => rooms
+            .iter()
+            .filter_map(|room_id| match db.rooms.is_joined(&sender_user, room_id) {
+                Ok(true) => Some(Ok(room_id.clone())),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Vec<_>>(),
+        None => db.rooms.rooms_joined(&sender_user).collect::<Vec<_>>(),
+    };
     for room_id in all_joined_rooms {
         let room_id = room_id?;
 
+        if !room_is_included(&filter.room, &room_id) {
+            continue;
+        }
+
         // Get and drop the lock to wait for remaining operations to finish
         // This will make sure the we have all events until next_batch
         let mutex_insert = Arc::clone(
@@ -245,7 +302,7 @@ async fn sync_helper(
 
         let timeline_pdus;
         let limited;
-        if db.rooms.last_timeline_count(&sender_user, &room_id)? > since {
+        if db.rooms.last_timeline_count(&sender_user, &room_id)? > timeline_since {
             let mut non_timeline_pdus = db
                 .rooms
                 .pdus_until(&sender_user, &room_id, u64::MAX)?
@@ -256,10 +313,11 @@ async fn sync_helper(
                     }
                     r.ok()
                 })
+                .filter(|(_, pdu)| !hidden_by_ignore(pdu, &ignored))
                 .take_while(|(pduid, _)| {
                     db.rooms
                         .pdu_count(pduid)
-                        .map_or(false, |count| count > since)
+                        .map_or(false, |count| count > timeline_since)
                 });
 
             // Take the last 10 events for the timeline
@@ -273,7 +331,7 @@ async fn sync_helper(
 
             // They /sync response doesn't always return all messages, so we say the output is
             // limited unless there are events in non_timeline_pdus
-            limited = non_timeline_pdus.next().is_some();
+            limited = non_timeline_pdus.next().is_some() || ignore_list_changed;
         } else {
             timeline_pdus = Vec::new();
             limited = false;
@@ -646,24 +704,77 @@ async fn sync_helper(
                 Ok(Some(db.rooms.pdu_count(pdu_id)?.to_string()))
             })?;
 
-        let room_events: Vec<_> = timeline_pdus
-            .iter()
-            .map(|(_, pdu)| pdu.to_sync_room_event())
-            .collect();
+        // Evaluate push rules so clients don't have to
+        let rules_for_user = db
+            .account_data
+            .get(
+                None,
+                &sender_user,
+                GlobalAccountDataEventType::PushRules.to_string().into(),
+            )?
+            .map(|ev: PushRulesEvent| ev.content.global)
+            .unwrap_or_else(|| Ruleset::server_default(&sender_user));
 
-        let mut edus: Vec<_> = db
+        let power_levels: RoomPowerLevelsEventContent = db
             .rooms
-            .edus
-            .readreceipts_since(&room_id, since)
-            .filter_map(|r| r.ok()) // Filter out buggy events
-            .map(|(_, _, v)| v)
-            .collect();
+            .room_state_get(&room_id, &StateEventType::RoomPowerLevels, "")?
+            .map(|ev| {
+                serde_json::from_str(ev.content.get())
+                    .map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let room_events = timeline_pdus
+            .iter()
+            .map(|(_, pdu)| {
+                let event = pdu.to_sync_room_event();
+
+                // Users are never notified about their own events
+                if pdu.sender == sender_user {
+                    return Ok(event);
+                }
+
+                let actions = pusher::get_actions(
+                    &sender_user,
+                    &rules_for_user,
+                    &power_levels,
+                    &event,
+                    &room_id,
+                    &db,
+                )?;
+
+                Ok(pusher::with_push_actions(event, actions))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut edus: Vec<_> = aggregate_receipts(
+            db.rooms
+                .edus
+                .readreceipts_since(&room_id, since)
+                .filter_map(|r| r.ok()) // Filter out buggy events
+                .filter(|(user_id, _, _)| !ignored.contains(user_id))
+                .map(|(_, _, v)| v)
+                // Private receipts are only visible to their sender
+                .chain(
+                    db.rooms
+                        .edus
+                        .private_receipt_since(&room_id, &sender_user, since)?,
+                ),
+        )
+        .into_iter()
+        .collect();
 
         if db.rooms.edus.last_typing_update(&room_id, &db.globals)? > since {
+            let mut typings = db.rooms.edus.typings_all(&room_id)?;
+            typings
+                .content
+                .user_ids
+                .retain(|user_id| !ignored.contains(user_id));
+
             edus.push(
                 serde_json::from_str(
-                    &serde_json::to_string(&db.rooms.edus.typings_all(&room_id)?)
-                        .expect("event is valid, we just created it"),
+                    &serde_json::to_string(&typings).expect("event is valid, we just created it"),
                 )
                 .expect("event is valid, we just created it"),
             );
@@ -714,11 +825,15 @@ async fn sync_helper(
         }
 
         // Take presence updates from this room
-        for (user_id, presence) in
+        let room_presence = if db.globals.allow_presence() {
             db.rooms
                 .edus
                 .presence_since(&room_id, since, &db.rooms, &db.globals)?
-        {
+        } else {
+            HashMap::new()
+        };
+
+        for (user_id, presence) in room_presence {
             match presence_updates.entry(user_id) {
                 Entry::Vacant(v) => {
                     v.insert(presence);
@@ -753,6 +868,10 @@ async fn sync_helper(
     for result in all_left_rooms {
         let (room_id, left_state_events) = result?;
 
+        if !room_is_included(&filter.room, &room_id) {
+            continue;
+        }
+
         // Get and drop the lock to wait for remaining operations to finish
         let mutex_insert = Arc::clone(
             db.globals
@@ -793,6 +912,10 @@ async fn sync_helper(
     for result in all_invited_rooms {
         let (room_id, invite_state_events) = result?;
 
+        if !room_is_included(&filter.room, &room_id) {
+            continue;
+        }
+
         // Get and drop the lock to wait for remaining operations to finish
         let mutex_insert = Arc::clone(
             db.globals
@@ -807,8 +930,14 @@ async fn sync_helper(
 
         let invite_count = db.rooms.get_invite_count(&room_id, &sender_user)?;
 
-        // Invited before last sync
-        if Some(since) >= invite_count {
+        // Invited before last sync, invites of users who are no longer ignored are sent again
+        if Some(since) >= invite_count && !ignore_list_changed {
+            continue;
+        }
+
+        // The invite may be older than the ignore
+        if inviter(&invite_state_events, &sender_user).map_or(false, |user| ignored.contains(&user))
+        {
             continue;
         }
 
@@ -822,6 +951,32 @@ async fn sync_helper(
         );
     }
 
+    let mut knocked_rooms = BTreeMap::new();
+    let all_knocked_rooms: Vec<_> = db.rooms.rooms_knocked(&sender_user).collect();
+    for result in all_knocked_rooms {
+        let (room_id, knock_state_events) = result?;
+
+        if !room_is_included(&filter.room, &room_id) {
+            continue;
+        }
+
+        let knock_count = db.rooms.get_knock_count(&room_id, &sender_user)?;
+
+        // Knocked before last sync
+        if Some(since) >= knock_count {
+            continue;
+        }
+
+        knocked_rooms.insert(
+            room_id.clone(),
+            KnockedRoom {
+                knock_state: KnockState {
+                    events: knock_state_events,
+                },
+            },
+        );
+    }
+
     for user_id in left_encrypted_users {
         let still_share_encrypted_room = db
             .rooms
@@ -853,7 +1008,7 @@ async fn sync_helper(
             leave: left_rooms,
             join: joined_rooms,
             invite: invited_rooms,
-            knock: BTreeMap::new(), // TODO
+            knock: knocked_rooms,
         },
         presence: Presence {
             events: presence_updates
@@ -908,6 +1063,15 @@ async fn sync_helper(
     }
 }
 
+/// Applies the `rooms` and `not_rooms` fields of a sync filter.
+fn room_is_included(filter: &IncomingRoomFilter, room_id: &RoomId) -> bool {
+    !filter.not_rooms.iter().any(|r| r == room_id)
+        && filter
+            .rooms
+            .as_ref()
+            .map_or(true, |rooms| rooms.iter().any(|r| r == room_id))
+}
+
 #[tracing::instrument(skip(db))]
 fn share_encrypted_room(
     db: &Database,
@@ -930,3 +1094,519 @@ fn share_encrypted_room(
         })
         .any(|encrypted| encrypted))
 }
+
+/// Users the sender ignores according to their `m.ignored_user_list` account data.
+fn ignored_users(db: &Database, user_id: &UserId) -> Result<HashSet<Box<UserId>>> {
+    Ok(db
+        .account_data
+        .get::<IgnoredUserListEvent>(
+            None,
+            user_id,
+            GlobalAccountDataEventType::IgnoredUserList
+                .to_string()
+                .into(),
+        )?
+        .map(|event| event.content.ignored_users.into_iter().collect())
+        .unwrap_or_default())
+}
+
+/// Whether the user changed their `m.ignored_user_list` since the last sync.
+fn ignore_list_changed(db: &Database, user_id: &UserId, since: u64) -> Result<bool> {
+    Ok(since != 0
+        && db
+            .account_data
+            .changes_since(None, user_id, since)?
+            .contains_key(&RoomAccountDataEventType::from(
+                GlobalAccountDataEventType::IgnoredUserList.to_string(),
+            )))
+}
+
+/// Messages of ignored users are hidden, their state events are still needed to show the room.
+fn hidden_by_ignore(pdu: &PduEvent, ignored: &HashSet<Box<UserId>>) -> bool {
+    pdu.state_key.is_none() && ignored.contains(&pdu.sender)
+}
+
+/// Returns who sent the invite of `user_id` according to the stripped invite state.
+fn inviter(invite_state: &[Raw<AnyStrippedStateEvent>], user_id: &UserId) -> Option<Box<UserId>> {
+    invite_state
+        .iter()
+        .filter_map(|event| serde_json::from_str::<serde_json::Value>(event.json().get()).ok())
+        .find(|event| event["type"] == "m.room.member" && event["state_key"] == user_id.as_str())
+        .and_then(|event| event["sender"].as_str().and_then(|s| UserId::parse(s).ok()))
+}
+
+/// Merges the read receipts of all users into a single `m.receipt` event.
+fn aggregate_receipts(
+    receipts: impl Iterator<Item = Raw<AnySyncEphemeralRoomEvent>>,
+) -> Option<Raw<AnySyncEphemeralRoomEvent>> {
+    let mut content = serde_json::Map::new();
+    let mut found = false;
+
+    for receipt in receipts {
+        let event = match serde_json::from_str::<serde_json::Value>(receipt.json().get()) {
+            Ok(serde_json::Value::Object(event)) => event,
+            _ => continue, // Filter out buggy events
+        };
+
+        // event id -> receipt type -> user id -> receipt
+        if let Some(serde_json::Value::Object(event_receipts)) = event.get("content") {
+            for (event_id, receipt_types) in event_receipts {
+                let merged = content.entry(event_id.clone()).or_insert_with(|| json!({}));
+                for (receipt_type, users) in receipt_types.as_object().into_iter().flatten() {
+                    let merged_users = &mut merged[receipt_type];
+                    for (user_id, receipt) in users.as_object().into_iter().flatten() {
+                        merged_users[user_id] = receipt.clone();
+                    }
+                }
+            }
+            found = true;
+        }
+    }
+
+    found.then(|| {
+        Raw::from_json(
+            to_raw_value(&json!({ "type": "m.receipt", "content": content }))
+                .expect("json is valid raw value"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aggregate_receipts, hidden_by_ignore, inviter, room_is_included};
+    use crate::PduEvent;
+    use ruma::{api::client::filter::IncomingRoomFilter, room_id, serde::Raw, user_id, UserId};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn room_filter_restricts_sync_to_listed_rooms() {
+        let filter = |json| serde_json::from_value::<IncomingRoomFilter>(json).unwrap();
+
+        let all = filter(json!({}));
+        assert!(room_is_included(&all, room_id!("!a:example.org")));
+
+        let only_a = filter(json!({ "rooms": ["!a:example.org"] }));
+        assert!(room_is_included(&only_a, room_id!("!a:example.org")));
+        assert!(!room_is_included(&only_a, room_id!("!b:example.org")));
+
+        let not_a = filter(json!({ "not_rooms": ["!a:example.org"] }));
+        assert!(!room_is_included(&not_a, room_id!("!a:example.org")));
+        assert!(room_is_included(&not_a, room_id!("!b:example.org")));
+    }
+
+    #[test]
+    fn receipts_of_multiple_users_are_aggregated() {
+        let receipt = |event_id: &str, user_id: &str, ts: u64| {
+            Raw::from_json(
+                serde_json::value::to_raw_value(&json!({
+                    "type": "m.receipt",
+                    "content": { event_id: { "m.read": { user_id: { "ts": ts } } } },
+                }))
+                .unwrap(),
+            )
+        };
+
+        let aggregated = aggregate_receipts(
+            vec![
+                receipt("$a", "@alice:example.org", 1),
+                receipt("$b", "@bob:example.org", 2),
+                receipt("$b", "@carol:example.org", 3),
+            ]
+            .into_iter(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(aggregated.json().get()).unwrap(),
+            json!({
+                "type": "m.receipt",
+                "content": {
+                    "$a": { "m.read": { "@alice:example.org": { "ts": 1 } } },
+                    "$b": { "m.read": {
+                        "@bob:example.org": { "ts": 2 },
+                        "@carol:example.org": { "ts": 3 },
+                    } },
+                },
+            })
+        );
+
+        assert!(aggregate_receipts(Vec::new().into_iter()).is_none());
+    }
+
+    #[test]
+    fn ignoring_hides_messages_until_unignored() {
+        let pdu = |event_id: &str, sender: &str, state_key: Option<&str>| {
+            let mut event = json!({
+                "event_id": event_id,
+                "room_id": "!room:example.org",
+                "sender": sender,
+                "origin_server_ts": 1,
+                "type": if state_key.is_some() { "m.room.member" } else { "m.room.message" },
+                "content": {},
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            });
+            if let Some(state_key) = state_key {
+                event["state_key"] = json!(state_key);
+            }
+            serde_json::from_value::<PduEvent>(event).unwrap()
+        };
+
+        let conversation = vec![
+            pdu("$1", "@alice:example.org", None),
+            pdu("$2", "@bob:example.org", None),
+            pdu("$3", "@bob:example.org", Some("@bob:example.org")),
+            pdu("$4", "@alice:example.org", None),
+        ];
+        let visible = |ignored: &HashSet<Box<UserId>>| {
+            conversation
+                .iter()
+                .filter(|pdu| !hidden_by_ignore(pdu, ignored))
+                .map(|pdu| pdu.event_id.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        let mut ignored = HashSet::new();
+        ignored.insert(user_id!("@bob:example.org").to_owned());
+        assert_eq!(visible(&ignored), vec!["$1", "$3", "$4"]);
+
+        ignored.clear();
+        assert_eq!(visible(&ignored), vec!["$1", "$2", "$3", "$4"]);
+    }
+
+    #[test]
+    fn inviter_is_read_from_invite_state() {
+        let invite_state = vec![
+            Raw::from_json(
+                serde_json::value::to_raw_value(&json!({
+                    "type": "m.room.name",
+                    "state_key": "",
+                    "sender": "@carol:example.org",
+                    "content": { "name": "Room" },
+                }))
+                .unwrap(),
+            ),
+            Raw::from_json(
+                serde_json::value::to_raw_value(&json!({
+                    "type": "m.room.member",
+                    "state_key": "@alice:example.org",
+                    "sender": "@bob:example.org",
+                    "content": { "membership": "invite" },
+                }))
+                .unwrap(),
+            ),
+        ];
+
+        assert_eq!(
+            inviter(&invite_state, user_id!("@alice:example.org")).as_deref(),
+            Some(user_id!("@bob:example.org"))
+        );
+        assert_eq!(inviter(&invite_state, user_id!("@carol:example.org")), None);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn changing_the_ignore_list_wakes_sync_and_restarts_timelines() {
+        use super::ignore_list_changed;
+        use ruma::{device_id, events::GlobalAccountDataEventType};
+        use std::time::Duration;
+        use tokio::time::timeout;
+
+        let db = crate::database::test_database("ignore-list").await;
+        let db = db.read().await;
+        let alice = user_id!("@alice:example.org");
+
+        let since = db.globals.current_count().unwrap();
+        assert!(!ignore_list_changed(&db, alice, since).unwrap());
+
+        // A sync is waiting for new events
+        let mut watcher = Box::pin(db.watch(alice, device_id!("DEVICE")));
+        assert!(timeout(Duration::from_millis(10), &mut watcher)
+            .await
+            .is_err());
+
+        db.account_data
+            .update(
+                None,
+                alice,
+                GlobalAccountDataEventType::IgnoredUserList
+                    .to_string()
+                    .into(),
+                &json!({
+                    "type": "m.ignored_user_list",
+                    "content": { "ignored_users": { "@bob:example.org": {} } },
+                }),
+                &db.globals,
+            )
+            .unwrap();
+
+        assert!(timeout(Duration::from_secs(5), watcher).await.is_ok());
+        assert!(ignore_list_changed(&db, alice, since).unwrap());
+
+        // The next sync continues normally, initial syncs start over anyway
+        let since = db.globals.current_count().unwrap();
+        assert!(!ignore_list_changed(&db, alice, since).unwrap());
+        assert!(!ignore_list_changed(&db, alice, 0).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn ignored_users_are_hidden_until_unignored() {
+        use super::sync_helper;
+        use crate::database::{test_room, test_send, DatabaseGuard};
+        use ruma::{
+            api::{client::sync::sync_events, IncomingRequest},
+            device_id,
+            events::GlobalAccountDataEventType,
+        };
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("ignore-sync").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        let set_ignored = |ignored_users: serde_json::Value| {
+            let db = Arc::clone(&db);
+            async move {
+                let db = db.read().await;
+                db.account_data
+                    .update(
+                        None,
+                        alice,
+                        GlobalAccountDataEventType::IgnoredUserList
+                            .to_string()
+                            .into(),
+                        &json!({
+                            "type": "m.ignored_user_list",
+                            "content": { "ignored_users": ignored_users },
+                        }),
+                        &db.globals,
+                    )
+                    .unwrap();
+            }
+        };
+
+        // Returns the senders of the timeline events, whether the timeline is limited and the
+        // next batch token
+        let sync = |since: Option<String>| {
+            let db = Arc::clone(&db);
+            async move {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}", since),
+                    None => "/_matrix/client/r0/sync".to_owned(),
+                };
+                let request = http::Request::builder()
+                    .uri(uri)
+                    .body(Vec::<u8>::new())
+                    .unwrap();
+                let (response, _) = sync_helper(
+                    Arc::new(DatabaseGuard::from(db.read_owned().await)),
+                    alice.to_owned(),
+                    device_id!("DEVICE").to_owned(),
+                    sync_events::v3::IncomingRequest::try_from_http_request::<_, String>(
+                        request,
+                        &[],
+                    )
+                    .unwrap(),
+                )
+                .await
+                .unwrap();
+
+                // Rooms without anything to show are left out
+                let (senders, limited) = response.rooms.join.get(room_id).map_or_else(
+                    || (Vec::<String>::new(), false),
+                    |room| {
+                        let senders = room
+                            .timeline
+                            .events
+                            .iter()
+                            .map(|event| {
+                                serde_json::from_str::<serde_json::Value>(event.json().get())
+                                    .unwrap()
+                            })
+                            .filter(|event| event["type"] == "m.room.message")
+                            .map(|event| event["sender"].as_str().unwrap().to_owned())
+                            .collect();
+                        (senders, room.timeline.limited)
+                    },
+                );
+                (senders, limited, response.next_batch)
+            }
+        };
+
+        {
+            let db = db.read().await;
+            db.users.create(alice, None, &db.globals).unwrap();
+            db.users
+                .create_device(alice, device_id!("DEVICE"), "token", None)
+                .unwrap();
+            test_room(&db, room_id, alice).await;
+            test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.join_rules",
+                Some(""),
+                json!({ "join_rule": "public" }),
+            )
+            .await
+            .unwrap();
+            test_send(
+                &db,
+                room_id,
+                bob,
+                "m.room.member",
+                Some(bob.as_str()),
+                json!({ "membership": "join" }),
+            )
+            .await
+            .unwrap();
+            for sender in [alice, bob] {
+                test_send(
+                    &db,
+                    room_id,
+                    sender,
+                    "m.room.message",
+                    None,
+                    json!({ "msgtype": "m.text", "body": "hi" }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let (senders, _, next_batch) = sync(None).await;
+        assert_eq!(senders, vec![alice.as_str(), bob.as_str()]);
+
+        // Ignoring bob mid-conversation hides his messages
+        set_ignored(json!({ "@bob:example.org": {} })).await;
+        let (senders, limited, next_batch) = sync(Some(next_batch)).await;
+        assert_eq!(senders, vec![alice.as_str()]);
+        assert!(limited);
+
+        test_send(
+            &*db.read().await,
+            room_id,
+            bob,
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.text", "body": "still there?" }),
+        )
+        .await
+        .unwrap();
+        let (senders, _, next_batch) = sync(Some(next_batch)).await;
+        assert!(senders.is_empty());
+
+        // After unignoring him his messages are sent again
+        set_ignored(json!({})).await;
+        let (senders, limited, _) = sync(Some(next_batch)).await;
+        assert_eq!(senders, vec![alice.as_str(), bob.as_str(), bob.as_str()]);
+        assert!(limited);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn mentions_carry_a_highlight_action() {
+        use super::sync_helper;
+        use crate::database::{
+            pusher::PUSH_ACTIONS_UNSIGNED_KEY, test_room, test_send, DatabaseGuard,
+        };
+        use ruma::{
+            api::{client::sync::sync_events, IncomingRequest},
+            device_id,
+        };
+        use std::sync::Arc;
+
+        let db = crate::database::test_database("push-actions-sync").await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        {
+            let db = db.read().await;
+            db.users.create(alice, None, &db.globals).unwrap();
+            db.users
+                .create_device(alice, device_id!("DEVICE"), "token", None)
+                .unwrap();
+            test_room(&db, room_id, alice).await;
+            test_send(
+                &db,
+                room_id,
+                alice,
+                "m.room.join_rules",
+                Some(""),
+                json!({ "join_rule": "public" }),
+            )
+            .await
+            .unwrap();
+            test_send(
+                &db,
+                room_id,
+                bob,
+                "m.room.member",
+                Some(bob.as_str()),
+                json!({ "membership": "join" }),
+            )
+            .await
+            .unwrap();
+            for body in ["hello", "hello alice"] {
+                test_send(
+                    &db,
+                    room_id,
+                    bob,
+                    "m.room.message",
+                    None,
+                    json!({ "msgtype": "m.text", "body": body }),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let request = http::Request::builder()
+            .uri("/_matrix/client/r0/sync")
+            .body(Vec::<u8>::new())
+            .unwrap();
+        let (response, _) = sync_helper(
+            Arc::new(DatabaseGuard::from(Arc::clone(&db).read_owned().await)),
+            alice.to_owned(),
+            device_id!("DEVICE").to_owned(),
+            sync_events::v3::IncomingRequest::try_from_http_request::<_, String>(request, &[])
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let highlighted = response.rooms.join[room_id]
+            .timeline
+            .events
+            .iter()
+            .map(|event| serde_json::from_str::<serde_json::Value>(event.json().get()).unwrap())
+            .filter(|event| event["type"] == "m.room.message")
+            .map(|event| {
+                let highlight = event["unsigned"][PUSH_ACTIONS_UNSIGNED_KEY]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|action| {
+                        action["set_tweak"] == "highlight" && action["value"] != json!(false)
+                    });
+                (
+                    event["content"]["body"].as_str().unwrap().to_owned(),
+                    highlight,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            highlighted,
+            vec![
+                ("hello".to_owned(), false),
+                ("hello alice".to_owned(), true)
+            ]
+        );
+    }
+}
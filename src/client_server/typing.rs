@@ -1,11 +1,30 @@
-use crate::{database::DatabaseGuard, utils, Error, Result, Ruma};
-use ruma::api::client::{error::ErrorKind, typing::create_typing_event};
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    database::{globals::TypingFanout, DatabaseGuard},
+    utils, Database, Error, Result, Ruma,
+};
+use axum::extract::Extension;
+use ruma::{
+    api::{
+        client::{error::ErrorKind, typing::create_typing_event},
+        federation::transactions::edu::{Edu, TypingContent},
+    },
+    RoomId, UserId,
+};
+use tokio::sync::RwLock;
+use tracing::warn;
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/typing/{userId}`
 ///
 /// Sets the typing state of the sender user.
+///
+/// - Repeated typing notifications only extend the timeout
+/// - Other servers get at most one update per user and room in a short window, rapid changes are
+/// coalesced into the latest state
 pub async fn create_typing_event_route(
     db: DatabaseGuard,
+    Extension(db_lock): Extension<Arc<RwLock<Database>>>,
     body: Ruma<create_typing_event::v3::IncomingRequest>,
 ) -> Result<create_typing_event::v3::Response> {
     use create_typing_event::v3::Typing;
@@ -19,18 +38,77 @@ pub async fn create_typing_event_route(
         ));
     }
 
-    if let Typing::Yes(duration) = body.state {
+    let typing = if let Typing::Yes(duration) = body.state {
         db.rooms.edus.typing_add(
             sender_user,
             &body.room_id,
             duration.as_millis() as u64 + utils::millis_since_unix_epoch(),
             &db.globals,
         )?;
+        true
     } else {
         db.rooms
             .edus
             .typing_remove(sender_user, &body.room_id, &db.globals)?;
+        false
+    };
+
+    match db
+        .globals
+        .typing_throttle
+        .update(&body.room_id, sender_user, typing, Instant::now())
+    {
+        TypingFanout::Send => send_typing_edu(&db, &body.room_id, sender_user, typing)?,
+        TypingFanout::Skip => {}
+        TypingFanout::Defer(after) => {
+            let room_id = body.room_id.clone();
+            let sender_user = sender_user.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep(after).await;
+
+                let db = db_lock.read().await;
+                if let Some(typing) =
+                    db.globals
+                        .typing_throttle
+                        .flush(&room_id, &sender_user, Instant::now())
+                {
+                    if let Err(e) = send_typing_edu(&db, &room_id, &sender_user, typing) {
+                        warn!("Failed to send typing update: {}", e);
+                    }
+                }
+            });
+        }
     }
 
     Ok(create_typing_event::v3::Response {})
 }
+
+/// Tells all other servers in the room whether the user is typing.
+fn send_typing_edu(db: &Database, room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
+    let servers = db
+        .rooms
+        .room_servers(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| &**server != db.globals.server_name())
+        .collect::<Vec<_>>();
+
+    if servers.is_empty() {
+        return Ok(());
+    }
+
+    let count = db.globals.next_count()?;
+    let edu = Edu::Typing(TypingContent::new(
+        room_id.to_owned(),
+        user_id.to_owned(),
+        typing,
+    ));
+    let serialized = serde_json::to_vec(&edu).expect("Typing EDU can be serialized");
+
+    for server in servers {
+        db.sending
+            .send_reliable_edu(&server, serialized.clone(), count)?;
+    }
+
+    Ok(())
+}
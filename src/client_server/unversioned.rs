@@ -1,8 +1,10 @@
 use std::{collections::BTreeMap, iter::FromIterator};
 
+use axum::{response::IntoResponse, Json};
 use ruma::api::client::discovery::get_supported_versions;
+use serde_json::json;
 
-use crate::{Result, Ruma};
+use crate::{database::DatabaseGuard, Result, Ruma};
 
 /// # `GET /_matrix/client/versions`
 ///
@@ -19,8 +21,58 @@ pub async fn get_supported_versions_route(
 ) -> Result<get_supported_versions::Response> {
     let resp = get_supported_versions::Response {
         versions: vec!["r0.5.0".to_owned(), "r0.6.0".to_owned()],
-        unstable_features: BTreeMap::from_iter([("org.matrix.e2e_cross_signing".to_owned(), true)]),
+        unstable_features: BTreeMap::from_iter([
+            ("org.matrix.e2e_cross_signing".to_owned(), true),
+            ("org.matrix.msc4108".to_owned(), true),
+        ]),
     };
 
     Ok(resp)
 }
+
+/// # `GET /.well-known/matrix/client`
+///
+/// Tells clients where to find this homeserver and which identity server to use by default.
+pub async fn well_known_client_route(db: DatabaseGuard) -> impl IntoResponse {
+    Json(well_known_client(
+        &db.globals.well_known_client(),
+        db.globals.default_identity_server(),
+    ))
+}
+
+fn well_known_client(homeserver: &str, identity_server: Option<&str>) -> serde_json::Value {
+    let mut response = json!({
+        "m.homeserver": { "base_url": homeserver },
+    });
+
+    if let Some(identity_server) = identity_server {
+        response["m.identity_server"] = json!({ "base_url": identity_server });
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::well_known_client;
+    use serde_json::json;
+
+    #[test]
+    fn well_known_advertises_identity_server() {
+        assert_eq!(
+            well_known_client("https://matrix.example.org", Some("https://id.example.org")),
+            json!({
+                "m.homeserver": { "base_url": "https://matrix.example.org" },
+                "m.identity_server": { "base_url": "https://id.example.org" },
+            })
+        );
+    }
+
+    #[test]
+    fn well_known_without_identity_server() {
+        assert_eq!(
+            well_known_client("https://matrix.example.org", None),
+            json!({ "m.homeserver": { "base_url": "https://matrix.example.org" } })
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{database::DatabaseGuard, Result, Ruma};
 use hmac::{Hmac, Mac, NewMac};
-use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch};
+use ruma::{api::client::voip::get_turn_server_info, SecondsSinceUnixEpoch, UserId};
 use sha1::Sha1;
 use std::time::{Duration, SystemTime};
 
@@ -8,7 +8,12 @@ type HmacSha1 = Hmac<Sha1>;
 
 /// # `GET /_matrix/client/r0/voip/turnServer`
 ///
-/// TODO: Returns information about the recommended turn server.
+/// Returns information about the recommended turn server.
+///
+/// - If `turn_secret` is set: Returns time-limited credentials for coturn's `use-auth-secret`
+/// scheme, they expire after `turn_ttl` seconds
+/// - Otherwise returns the static `turn_username` and `turn_password`
+/// - If no `turn_uris` are configured: Returns an empty list of URIs and no credentials
 pub async fn turn_server_route(
     db: DatabaseGuard,
     body: Ruma<get_turn_server_info::v3::IncomingRequest>,
@@ -17,21 +22,15 @@ pub async fn turn_server_route(
 
     let turn_secret = db.globals.turn_secret();
 
-    let (username, password) = if !turn_secret.is_empty() {
+    let (username, password) = if db.globals.turn_uris().is_empty() {
+        (String::new(), String::new())
+    } else if !turn_secret.is_empty() {
         let expiry = SecondsSinceUnixEpoch::from_system_time(
             SystemTime::now() + Duration::from_secs(db.globals.turn_ttl()),
         )
         .expect("time is valid");
 
-        let username: String = format!("{}:{}", expiry.get(), sender_user);
-
-        let mut mac = HmacSha1::new_from_slice(turn_secret.as_bytes())
-            .expect("HMAC can take key of any size");
-        mac.update(username.as_bytes());
-
-        let password: String = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
-
-        (username, password)
+        turn_credentials(turn_secret, expiry.get().into(), sender_user)
     } else {
         (
             db.globals.turn_username().clone(),
@@ -46,3 +45,34 @@ pub async fn turn_server_route(
         ttl: Duration::from_secs(db.globals.turn_ttl()),
     })
 }
+
+/// The username is `expiry:user_id`, the password its base64 encoded HMAC-SHA1 with the shared
+/// secret.
+fn turn_credentials(secret: &str, expiry: u64, user_id: &UserId) -> (String, String) {
+    let username = format!("{}:{}", expiry, user_id);
+
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(username.as_bytes());
+
+    let password = base64::encode_config(mac.finalize().into_bytes(), base64::STANDARD);
+
+    (username, password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::turn_credentials;
+    use ruma::user_id;
+
+    #[test]
+    fn shared_secret_credentials_match_coturn() {
+        assert_eq!(
+            turn_credentials("secret", 1000, user_id!("@alice:example.org")),
+            (
+                "1000:@alice:example.org".to_owned(),
+                "Fr/V9gYdR6Kq5LHbnCbxJTj2nK4=".to_owned()
+            )
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
     collections::BTreeMap,
-    fmt,
+    fmt, fs, io,
     net::{IpAddr, Ipv4Addr},
+    path::Path,
 };
 
-use ruma::{RoomVersionId, ServerName};
+use ruma::{RoomId, RoomVersionId, ServerName, UserId};
 use serde::{de::IgnoredAny, Deserialize};
 use tracing::warn;
 
@@ -36,16 +37,100 @@ pub struct Config {
     pub cleanup_second_interval: u32,
     #[serde(default = "default_max_request_size")]
     pub max_request_size: u32,
+    #[serde(default = "default_max_request_size")]
+    pub max_upload_size: u32,
+    #[serde(default = "default_media_upload_timeout_secs")]
+    pub media_upload_timeout_secs: u64,
+    #[serde(default = "false_fn")]
+    pub allow_blurhash: bool,
+    #[serde(default = "false_fn")]
+    pub allow_url_preview: bool,
+    #[serde(default = "default_url_preview_max_size")]
+    pub url_preview_max_size: u32,
+    pub remote_media_retention_days: Option<u64>,
+    #[serde(default = "default_max_account_data_size")]
+    pub max_account_data_size: usize,
+    #[serde(default = "default_max_pagination_limit")]
+    pub max_pagination_limit: usize,
     #[serde(default = "default_max_concurrent_requests")]
     pub max_concurrent_requests: u16,
+    pub rate_limit_per_second: Option<f64>,
+    #[serde(default = "default_rate_limit_burst")]
+    pub rate_limit_burst: u32,
+    #[serde(default = "Vec::new")]
+    pub rate_limit_exempt_users: Vec<Box<UserId>>,
+    #[serde(default = "Vec::new")]
+    pub rate_limit_exempt_servers: Vec<Box<ServerName>>,
+    pub account_data_rate_limit_per_second: Option<f64>,
+    #[serde(default = "default_account_data_rate_limit_burst")]
+    pub account_data_rate_limit_burst: u32,
+    pub auth_rate_limit_per_second: Option<f64>,
+    #[serde(default = "default_auth_rate_limit_burst")]
+    pub auth_rate_limit_burst: u32,
+    #[serde(default = "default_rate_limit_jitter_ms")]
+    pub rate_limit_jitter_ms: u64,
+    #[serde(default = "Vec::new")]
+    pub trusted_proxies: Vec<IpAddr>,
+    #[serde(default = "default_max_concurrent_syncs_per_user")]
+    pub max_concurrent_syncs_per_user: usize,
+    #[serde(default = "true_fn")]
+    pub allow_presence: bool,
+    #[serde(default = "default_presence_federation_interval_secs")]
+    pub presence_federation_interval_secs: u64,
     #[serde(default = "false_fn")]
     pub allow_registration: bool,
     #[serde(default = "true_fn")]
+    pub allow_guests: bool,
+    #[serde(default = "true_fn")]
+    pub allow_password_login: bool,
+    #[serde(default)]
+    pub deactivation_grace_days: u64,
+    pub inactive_account_days: Option<u64>,
+    #[serde(default = "default_inactive_account_warning_days")]
+    pub inactive_account_warning_days: Vec<u64>,
+    #[serde(default = "false_fn")]
+    pub registration_requires_token: bool,
+    #[serde(default = "Vec::new")]
+    pub allowed_email_domains: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub denied_email_domains: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub forbidden_usernames: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub forbidden_username_patterns: Vec<String>,
+    pub smtp: Option<SmtpConfig>,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+    #[serde(default)]
+    pub password_hashing: PasswordHashing,
+    pub recaptcha_site_key: Option<String>,
+    pub recaptcha_secret_key: Option<String>,
+    pub registration_shared_secret: Option<String>,
+    pub metrics_token: Option<String>,
+    #[serde(default = "default_threepid_session_ttl_secs")]
+    pub threepid_session_ttl_secs: u64,
+    #[serde(default = "true_fn")]
     pub allow_encryption: bool,
     #[serde(default = "false_fn")]
     pub allow_federation: bool,
+    #[serde(default = "Vec::new")]
+    pub federation_join_room_versions: Vec<RoomVersionId>,
+    #[serde(default = "default_max_fetched_auth_chain_size")]
+    pub max_fetched_auth_chain_size: usize,
+    #[serde(default = "default_max_transaction_pdus")]
+    pub max_transaction_pdus: usize,
+    #[serde(default = "default_max_transaction_edus")]
+    pub max_transaction_edus: usize,
+    #[serde(default = "false_fn")]
+    pub require_encryption: bool,
+    #[serde(default = "false_fn")]
+    pub require_encryption_public_rooms: bool,
     #[serde(default = "true_fn")]
     pub allow_room_creation: bool,
+    #[serde(default = "false_fn")]
+    pub invite_only_from_members: bool,
+    #[serde(default = "false_fn")]
+    pub prevent_power_level_lockout: bool,
     #[serde(default = "true_fn")]
     pub allow_unstable_room_versions: bool,
     #[serde(default = "default_default_room_version")]
@@ -56,6 +141,9 @@ pub struct Config {
     pub tracing_flame: bool,
     #[serde(default)]
     pub proxy: ProxyConfig,
+    pub user_agent: Option<String>,
+    #[serde(default = "false_fn")]
+    pub hide_server_version: bool,
     pub jwt_secret: Option<String>,
     #[serde(default = "Vec::new")]
     pub trusted_servers: Vec<Box<ServerName>>,
@@ -71,19 +159,140 @@ pub struct Config {
     pub turn_secret: String,
     #[serde(default = "default_turn_ttl")]
     pub turn_ttl: u64,
+    #[serde(default = "default_todevice_inactive_secs")]
+    pub todevice_inactive_secs: u64,
+    #[serde(default = "default_max_todevice_events_per_device")]
+    pub max_todevice_events_per_device: usize,
+    #[serde(default = "default_signing_key_validity")]
+    pub signing_key_validity: u64,
+    #[serde(default = "default_rendezvous_ttl_secs")]
+    pub rendezvous_ttl_secs: u64,
+    #[serde(default = "default_refreshable_access_token_ttl_secs")]
+    pub refreshable_access_token_ttl_secs: u64,
 
     pub emergency_password: Option<String>,
 
+    pub well_known_client: Option<String>,
+    pub default_identity_server: Option<String>,
+    #[serde(default = "false_fn")]
+    pub default_identity_server_account_data: bool,
+
+    #[serde(default = "true_fn")]
+    pub enable_admin_room: bool,
+    pub admin_room_max_notices: Option<usize>,
+    pub admin_room_max_notice_age_secs: Option<u64>,
+
+    pub welcome_message: Option<String>,
+    #[serde(default = "false_fn")]
+    pub welcome_message_skip_guests: bool,
+    pub welcome_room: Option<Box<RoomId>>,
+    #[serde(default = "false_fn")]
+    pub welcome_room_auto_join: bool,
+
+    #[serde(default)]
+    pub default_push_actions: DefaultPushActions,
+
     #[serde(flatten)]
     pub catchall: BTreeMap<String, IgnoredAny>,
 }
 
+/// Actions that replace the ones of the server default push rules for new users. Each entry is a
+/// list of push actions in their JSON form, e.g. `["notify", { set_tweak = "sound", value = "default" }]`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DefaultPushActions {
+    /// `.m.rule.contains_display_name`
+    pub contains_display_name: Option<serde_json::Value>,
+    /// `.m.rule.roomnotif`
+    pub roomnotif: Option<serde_json::Value>,
+    /// All content (keyword) rules
+    pub keyword: Option<serde_json::Value>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct TlsConfig {
     pub certs: String,
     pub key: String,
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PasswordPolicy {
+    #[serde(default)]
+    pub minimum_length: usize,
+    #[serde(default)]
+    pub require_digit: bool,
+    #[serde(default)]
+    pub require_symbol: bool,
+    #[serde(default)]
+    pub require_uppercase: bool,
+    /// Minimum zxcvbn score from 0 (weakest) to 4 (strongest)
+    pub minimum_score: Option<u8>,
+}
+
+/// Parameters of new argon2id password hashes. Existing hashes are upgraded on login.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PasswordHashing {
+    /// Memory cost in KiB
+    #[serde(default = "default_argon2_memory_cost")]
+    pub memory_cost: u32,
+    /// Number of iterations
+    #[serde(default = "default_argon2_time_cost")]
+    pub time_cost: u32,
+    /// Number of lanes
+    #[serde(default = "default_argon2_parallelism")]
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        Self {
+            memory_cost: default_argon2_memory_cost(),
+            time_cost: default_argon2_time_cost(),
+            parallelism: default_argon2_parallelism(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Returns why the password is too weak, if it is.
+    pub fn check(&self, password: &str) -> Result<(), &'static str> {
+        if password.chars().count() < self.minimum_length {
+            return Err("The password is too short.");
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err("The password must contain a digit.");
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            return Err("The password must contain a symbol.");
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            return Err("The password must contain an uppercase letter.");
+        }
+
+        Ok(())
+    }
+
+    /// The policy in the format of the password policy endpoint and capability.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "m.minimum_length": self.minimum_length,
+            "m.require_digit": self.require_digit,
+            "m.require_symbol": self.require_symbol,
+            "m.require_uppercase": self.require_uppercase,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    /// Sender of verification emails, e.g. `Conduit <noreply@example.org>`
+    pub from: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
 const DEPRECATED_KEYS: &[&str] = &["cache_capacity"];
 
 impl Config {
@@ -102,6 +311,108 @@ impl Config {
             warn!("Read conduit documentation and check your configuration if any new configuration parameters should be adjusted");
         }
     }
+
+    /// Checks settings that would otherwise only fail once they are used, e.g. on the first
+    /// media upload. Without `create_dirs`, nothing is written to disk.
+    pub fn check(&self, create_dirs: bool) -> Result<(), String> {
+        if self.database_path.is_empty() {
+            return Err("database_path must not be empty.".to_owned());
+        }
+
+        let media_folder = Path::new(&self.database_path).join("media");
+        check_writable_dir(&media_folder, create_dirs).map_err(|e| {
+            format!(
+                "Media directory {} is not usable: {}",
+                media_folder.display(),
+                e
+            )
+        })?;
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.certs, &tls.key] {
+                fs::File::open(path)
+                    .map_err(|e| format!("TLS file {} is not readable: {}", path, e))?;
+            }
+        }
+
+        if let Some(days) = self.inactive_account_days {
+            if self
+                .inactive_account_warning_days
+                .iter()
+                .any(|&warning| warning == 0 || warning >= days)
+            {
+                return Err(
+                    "inactive_account_warning_days must be between 1 and inactive_account_days."
+                        .to_owned(),
+                );
+            }
+        }
+
+        if self.password_policy.minimum_score > Some(4) {
+            return Err("password_policy.minimum_score must be between 0 and 4.".to_owned());
+        }
+
+        let hashing = &self.password_hashing;
+        if hashing.time_cost == 0 || hashing.parallelism == 0 {
+            return Err(
+                "password_hashing.time_cost and password_hashing.parallelism must be at least 1."
+                    .to_owned(),
+            );
+        }
+        if hashing.memory_cost < 8 * hashing.parallelism {
+            return Err(
+                "password_hashing.memory_cost must be at least 8 times the parallelism.".to_owned(),
+            );
+        }
+
+        if let Some(well_known_client) = &self.well_known_client {
+            if !well_known_client.starts_with("https://")
+                && !well_known_client.starts_with("http://")
+            {
+                return Err(format!(
+                    "well_known_client must be an http(s) URL, got {}.",
+                    well_known_client
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether an email address may be used to register.
+    ///
+    /// Domains match themselves and their subdomains. Denied domains take precedence, and an
+    /// empty allowlist allows every domain that is not denied.
+    pub fn email_domain_allowed(&self, email: &str) -> bool {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return false,
+        };
+        let matches = |rule: &String| {
+            let rule = rule.to_lowercase();
+            domain == rule || domain.ends_with(&format!(".{}", rule))
+        };
+
+        !self.denied_email_domains.iter().any(matches)
+            && (self.allowed_email_domains.is_empty()
+                || self.allowed_email_domains.iter().any(matches))
+    }
+
+    /// The `Server` header of our responses.
+    pub fn server_header(&self) -> String {
+        if self.hide_server_version {
+            "Conduit".to_owned()
+        } else {
+            format!("Conduit/{}", env!("CARGO_PKG_VERSION"))
+        }
+    }
+
+    /// The `User-Agent` header of outgoing federation and media requests.
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(|| self.server_header())
+    }
 }
 
 impl fmt::Display for Config {
@@ -130,14 +441,136 @@ impl fmt::Display for Config {
                 &self.cleanup_second_interval.to_string(),
             ),
             ("Maximum request size", &self.max_request_size.to_string()),
+            ("Maximum upload size", &self.max_upload_size.to_string()),
+            (
+                "Reserved media expires after seconds",
+                &self.media_upload_timeout_secs.to_string(),
+            ),
+            ("Compute blurhashes", &self.allow_blurhash.to_string()),
+            ("Allow URL previews", &self.allow_url_preview.to_string()),
+            (
+                "Maximum URL preview download size",
+                &self.url_preview_max_size.to_string(),
+            ),
+            (
+                "Delete cached remote media after days",
+                &self
+                    .remote_media_retention_days
+                    .map_or("disabled".to_owned(), |days| days.to_string()),
+            ),
+            (
+                "Maximum account data size",
+                &self.max_account_data_size.to_string(),
+            ),
+            (
+                "Maximum pagination limit",
+                &self.max_pagination_limit.to_string(),
+            ),
             (
                 "Maximum concurrent requests",
                 &self.max_concurrent_requests.to_string(),
             ),
+            (
+                "Rate limit per second",
+                &self
+                    .rate_limit_per_second
+                    .map_or_else(|| "disabled".to_owned(), |rate| rate.to_string()),
+            ),
+            ("Rate limit burst", &self.rate_limit_burst.to_string()),
+            (
+                "Account data rate limit per second",
+                &self
+                    .account_data_rate_limit_per_second
+                    .map_or_else(|| "disabled".to_owned(), |rate| rate.to_string()),
+            ),
+            (
+                "Login and registration rate limit per second",
+                &self
+                    .auth_rate_limit_per_second
+                    .map_or_else(|| "disabled".to_owned(), |rate| rate.to_string()),
+            ),
+            ("Rate limit jitter", &self.rate_limit_jitter_ms.to_string()),
+            ("Trusted proxies", {
+                &self
+                    .trusted_proxies
+                    .iter()
+                    .map(|proxy| proxy.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+            (
+                "Maximum concurrent syncs per user",
+                &self.max_concurrent_syncs_per_user.to_string(),
+            ),
+            ("Allow presence", &self.allow_presence.to_string()),
+            (
+                "Presence federation interval in seconds",
+                &self.presence_federation_interval_secs.to_string(),
+            ),
             ("Allow registration", &self.allow_registration.to_string()),
+            ("Allow guests", &self.allow_guests.to_string()),
+            (
+                "Allow password login",
+                &self.allow_password_login.to_string(),
+            ),
+            (
+                "Deactivation grace period in days",
+                &self.deactivation_grace_days.to_string(),
+            ),
+            (
+                "Deactivate accounts inactive for days",
+                &self
+                    .inactive_account_days
+                    .map_or("disabled".to_owned(), |days| days.to_string()),
+            ),
+            (
+                "Warn inactive accounts days before deactivation",
+                &format!("{:?}", self.inactive_account_warning_days),
+            ),
+            (
+                "Registration requires token",
+                &self.registration_requires_token.to_string(),
+            ),
             ("Allow encryption", &self.allow_encryption.to_string()),
             ("Allow federation", &self.allow_federation.to_string()),
+            (
+                "Maximum fetched auth chain size",
+                &self.max_fetched_auth_chain_size.to_string(),
+            ),
+            (
+                "Maximum PDUs per incoming transaction",
+                &self.max_transaction_pdus.to_string(),
+            ),
+            (
+                "Maximum EDUs per incoming transaction",
+                &self.max_transaction_edus.to_string(),
+            ),
+            ("Room versions remote servers may join", {
+                &if self.federation_join_room_versions.is_empty() {
+                    "all supported".to_owned()
+                } else {
+                    self.federation_join_room_versions
+                        .iter()
+                        .map(|version| version.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            }),
             ("Allow room creation", &self.allow_room_creation.to_string()),
+            ("Forbidden usernames", &self.forbidden_usernames.join(", ")),
+            (
+                "Forbidden username patterns",
+                &self.forbidden_username_patterns.join(", "),
+            ),
+            (
+                "Invites only from room members",
+                &self.invite_only_from_members.to_string(),
+            ),
+            (
+                "Prevent power level lockout",
+                &self.prevent_power_level_lockout.to_string(),
+            ),
+            ("User agent", &self.user_agent()),
             (
                 "JWT secret",
                 match self.jwt_secret {
@@ -145,6 +578,49 @@ impl fmt::Display for Config {
                     None => "not set",
                 },
             ),
+            (
+                "Minimum password length",
+                &self.password_policy.minimum_length.to_string(),
+            ),
+            (
+                "Minimum password strength",
+                &self
+                    .password_policy
+                    .minimum_score
+                    .map_or_else(|| "disabled".to_owned(), |score| score.to_string()),
+            ),
+            (
+                "Password hashing",
+                &format!(
+                    "argon2id m={},t={},p={}",
+                    self.password_hashing.memory_cost,
+                    self.password_hashing.time_cost,
+                    self.password_hashing.parallelism
+                ),
+            ),
+            (
+                "reCAPTCHA",
+                match (&self.recaptcha_site_key, &self.recaptcha_secret_key) {
+                    (Some(_), Some(_)) => "enabled",
+                    _ => "disabled",
+                },
+            ),
+            (
+                "Registration shared secret",
+                if self.registration_shared_secret.is_some() {
+                    "set"
+                } else {
+                    "not set"
+                },
+            ),
+            (
+                "Metrics",
+                if self.metrics_token.is_some() {
+                    "enabled"
+                } else {
+                    "disabled"
+                },
+            ),
             ("Trusted servers", {
                 let mut lst = vec![];
                 for server in &self.trusted_servers {
@@ -175,6 +651,25 @@ impl fmt::Display for Config {
                 }
             }),
             ("Turn TTL", &self.turn_ttl.to_string()),
+            ("Rendezvous TTL", &self.rendezvous_ttl_secs.to_string()),
+            (
+                "Refreshable access token TTL",
+                &self.refreshable_access_token_ttl_secs.to_string(),
+            ),
+            (
+                "SMTP host",
+                self.smtp
+                    .as_ref()
+                    .map_or("disabled", |smtp| smtp.host.as_str()),
+            ),
+            (
+                "Email verification TTL",
+                &self.threepid_session_ttl_secs.to_string(),
+            ),
+            (
+                "Signing key validity",
+                &self.signing_key_validity.to_string(),
+            ),
             ("Turn URIs", {
                 let mut lst = vec![];
                 for item in self.turn_uris.to_vec().into_iter().enumerate() {
@@ -183,6 +678,32 @@ impl fmt::Display for Config {
                 }
                 &lst.join(", ")
             }),
+            (
+                "Default identity server",
+                match &self.default_identity_server {
+                    Some(identity_server) => identity_server,
+                    None => "not set",
+                },
+            ),
+            ("Admin room", &self.enable_admin_room.to_string()),
+            ("Welcome message", {
+                if self.welcome_message.is_some() {
+                    "set"
+                } else {
+                    "not set"
+                }
+            }),
+            (
+                "Welcome room",
+                match &self.welcome_room {
+                    Some(room_id) => room_id.as_str(),
+                    None => "not set",
+                },
+            ),
+            (
+                "Welcome room auto join",
+                &self.welcome_room_auto_join.to_string(),
+            ),
         ];
 
         let mut msg: String = "Active config values:\n\n".to_string();
@@ -195,6 +716,40 @@ impl fmt::Display for Config {
     }
 }
 
+/// Creates the directory if necessary and makes sure files can be written to it.
+/// Makes sure files can be created in `path`. Without `create`, nothing is written and the
+/// directory, or its closest existing parent, only has to be a writable directory.
+fn check_writable_dir(path: &Path, create: bool) -> io::Result<()> {
+    if !create {
+        let existing = path
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or_else(|| Path::new("."));
+        let metadata = fs::metadata(existing)?;
+
+        if !metadata.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is not a directory", existing.display()),
+            ));
+        }
+        if metadata.permissions().readonly() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is read-only", existing.display()),
+            ));
+        }
+
+        return Ok(());
+    }
+
+    fs::create_dir_all(path)?;
+
+    let probe = path.join(".conduit-write-check");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
 fn false_fn() -> bool {
     false
 }
@@ -239,10 +794,50 @@ fn default_max_request_size() -> u32 {
     20 * 1024 * 1024 // Default to 20 MB
 }
 
+fn default_media_upload_timeout_secs() -> u64 {
+    60 * 60 * 24 // 1 day
+}
+
+fn default_url_preview_max_size() -> u32 {
+    5 * 1024 * 1024 // 5 MB
+}
+
+fn default_max_account_data_size() -> usize {
+    1024 * 1024 // Default to 1 MB
+}
+
+fn default_max_pagination_limit() -> usize {
+    100
+}
+
 fn default_max_concurrent_requests() -> u16 {
     100
 }
 
+fn default_rate_limit_burst() -> u32 {
+    20
+}
+
+fn default_account_data_rate_limit_burst() -> u32 {
+    20
+}
+
+fn default_auth_rate_limit_burst() -> u32 {
+    5
+}
+
+fn default_rate_limit_jitter_ms() -> u64 {
+    1000
+}
+
+fn default_max_concurrent_syncs_per_user() -> usize {
+    0
+}
+
+fn default_presence_federation_interval_secs() -> u64 {
+    30
+}
+
 fn default_log() -> String {
     "info,state_res=warn,_=off,sled=off".to_owned()
 }
@@ -251,7 +846,189 @@ fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
 
+fn default_todevice_inactive_secs() -> u64 {
+    60 * 60 * 24 * 30
+}
+
+fn default_inactive_account_warning_days() -> Vec<u64> {
+    vec![30, 7, 1]
+}
+
+fn default_rendezvous_ttl_secs() -> u64 {
+    60 * 5
+}
+
+fn default_refreshable_access_token_ttl_secs() -> u64 {
+    60 * 5
+}
+
+fn default_argon2_memory_cost() -> u32 {
+    4096
+}
+
+fn default_argon2_time_cost() -> u32 {
+    3
+}
+
+fn default_argon2_parallelism() -> u32 {
+    1
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_threepid_session_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_max_fetched_auth_chain_size() -> usize {
+    5_000
+}
+
+fn default_max_transaction_pdus() -> usize {
+    50
+}
+
+fn default_max_transaction_edus() -> usize {
+    100
+}
+
+fn default_max_todevice_events_per_device() -> usize {
+    10_000
+}
+
+fn default_signing_key_validity() -> u64 {
+    60 * 60 * 24 * 7
+}
+
 // I know, it's a great name
 fn default_default_room_version() -> RoomVersionId {
     RoomVersionId::V6
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, PasswordPolicy};
+    use serde_json::json;
+    use std::{env, fs};
+
+    fn config(extra: serde_json::Value) -> Config {
+        let mut config = json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn user_agent_defaults_to_conduit_version() {
+        let version = format!("Conduit/{}", env!("CARGO_PKG_VERSION"));
+
+        assert_eq!(config(json!({})).user_agent(), version);
+        assert_eq!(config(json!({})).server_header(), version);
+
+        let hidden = config(json!({ "hide_server_version": true }));
+        assert_eq!(hidden.user_agent(), "Conduit");
+        assert_eq!(hidden.server_header(), "Conduit");
+    }
+
+    #[test]
+    fn user_agent_can_be_configured() {
+        let config = config(json!({ "user_agent": "ExampleBot/1.0" }));
+        assert_eq!(config.user_agent(), "ExampleBot/1.0");
+        assert_eq!(
+            config.server_header(),
+            format!("Conduit/{}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn unusable_media_directory_fails_the_check() {
+        // The media directory can't be created below a regular file
+        let file = env::temp_dir().join(format!("conduit-check-{}", std::process::id()));
+        fs::write(&file, b"").unwrap();
+
+        let config = config(json!({ "database_path": file.to_str().unwrap() }));
+        let error = config.check(true).unwrap_err();
+        let dry_run_error = config.check(false).unwrap_err();
+        fs::remove_file(&file).unwrap();
+
+        assert!(error.starts_with("Media directory"), "{}", error);
+        assert!(
+            dry_run_error.starts_with("Media directory"),
+            "{}",
+            dry_run_error
+        );
+    }
+
+    #[test]
+    fn writable_database_path_passes_the_check() {
+        let dir = env::temp_dir().join(format!("conduit-check-dir-{}", std::process::id()));
+
+        let config = config(json!({ "database_path": dir.to_str().unwrap() }));
+
+        // Only checking the config doesn't create the directories
+        assert_eq!(config.check(false), Ok(()));
+        assert!(!dir.exists());
+
+        let result = config.check(true);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn email_domains_are_checked_against_allow_and_deny_lists() {
+        let corporate = config(json!({
+            "allowed_email_domains": ["example.com"],
+            "denied_email_domains": ["contractors.example.com"],
+        }));
+
+        assert!(corporate.email_domain_allowed("alice@example.com"));
+        assert!(corporate.email_domain_allowed("bob@Mail.Example.com"));
+        assert!(!corporate.email_domain_allowed("eve@example.org"));
+        assert!(!corporate.email_domain_allowed("eve@notexample.com"));
+        assert!(!corporate.email_domain_allowed("carol@contractors.example.com"));
+        assert!(!corporate.email_domain_allowed("no-domain"));
+
+        let open = config(json!({ "denied_email_domains": ["spam.example"] }));
+        assert!(open.email_domain_allowed("alice@example.org"));
+        assert!(!open.email_domain_allowed("eve@spam.example"));
+    }
+
+    #[test]
+    fn passwords_are_checked_against_policy() {
+        assert!(PasswordPolicy::default().check("").is_ok());
+
+        let policy: PasswordPolicy = config(json!({
+            "password_policy": {
+                "minimum_length": 8,
+                "require_digit": true,
+                "require_symbol": true,
+                "require_uppercase": true,
+            },
+        }))
+        .password_policy;
+
+        assert_eq!(policy.check("Ab1!"), Err("The password is too short."));
+        assert_eq!(
+            policy.check("Abcdefg!"),
+            Err("The password must contain a digit.")
+        );
+        assert_eq!(
+            policy.check("Abcdefg1"),
+            Err("The password must contain a symbol.")
+        );
+        assert_eq!(
+            policy.check("abcdef1!"),
+            Err("The password must contain an uppercase letter.")
+        );
+        assert!(policy.check("Abcdef1!").is_ok());
+    }
+}
@@ -7,25 +7,30 @@ pub mod globals;
 pub mod key_backups;
 pub mod media;
 pub mod pusher;
+pub mod rendezvous;
 pub mod rooms;
 pub mod sending;
+pub mod threepid;
 pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
 
-use self::admin::create_admin_room;
+use self::admin::{create_admin_room, create_server_user, find_admin_room};
+use self::users::InactivityAction;
 use crate::{utils, Config, Error, Result};
-use abstraction::DatabaseEngine;
+use abstraction::{DatabaseEngine, Tree};
 use directories::ProjectDirs;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
+    api::federation::transactions::edu::PresenceUpdate,
     events::{
         push_rules::PushRulesEventContent, room::message::RoomMessageEventContent,
         GlobalAccountDataEvent, GlobalAccountDataEventType,
     },
+    presence::PresenceState,
     push::Ruleset,
-    DeviceId, EventId, RoomId, UserId,
+    DeviceId, EventId, RoomId, UInt, UserId,
 };
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
@@ -35,8 +40,9 @@ use std::{
     ops::Deref,
     path::Path,
     sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
-use tokio::sync::{mpsc, OwnedRwLockReadGuard, RwLock as TokioRwLock, Semaphore};
+use tokio::sync::{broadcast, mpsc, OwnedRwLockReadGuard, RwLock as TokioRwLock, Semaphore};
 use tracing::{debug, error, info, warn};
 
 pub struct Database {
@@ -46,13 +52,15 @@ pub struct Database {
     pub uiaa: uiaa::Uiaa,
     pub rooms: rooms::Rooms,
     pub account_data: account_data::AccountData,
-    pub media: media::Media,
+    pub media: Arc<media::Media>,
     pub key_backups: key_backups::KeyBackups,
     pub transaction_ids: transaction_ids::TransactionIds,
     pub sending: sending::Sending,
     pub admin: admin::Admin,
     pub appservice: appservice::Appservice,
     pub pusher: pusher::PushData,
+    pub rendezvous: rendezvous::Rendezvous,
+    pub threepid: threepid::ThreePid,
 }
 
 impl Database {
@@ -115,16 +123,8 @@ impl Database {
         Ok(())
     }
 
-    /// Load an existing database or create a new one.
-    pub async fn load_or_create(config: &Config) -> Result<Arc<TokioRwLock<Self>>> {
-        Self::check_db_setup(config)?;
-
-        if !Path::new(&config.database_path).exists() {
-            std::fs::create_dir_all(&config.database_path)
-                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
-        }
-
-        let builder: Arc<dyn DatabaseEngine> = match &*config.database_backend {
+    fn open_engine(config: &Config) -> Result<Arc<dyn DatabaseEngine>> {
+        Ok(match &*config.database_backend {
             "sqlite" => {
                 #[cfg(not(feature = "sqlite"))]
                 return Err(Error::BadConfig("Database backend not found."));
@@ -146,7 +146,63 @@ impl Database {
             _ => {
                 return Err(Error::BadConfig("Database backend not found."));
             }
+        })
+    }
+
+    /// Fails if the database belongs to another server name. Only local users have an entry in
+    /// `userid_password`.
+    fn check_server_name(userid_password: &dyn Tree, config: &Config) -> Result<()> {
+        if let Some((user_id, _)) = userid_password.iter().next() {
+            let user_id = utils::string_from_bytes(&user_id)
+                .ok()
+                .and_then(|user_id| UserId::parse(user_id).ok())
+                .ok_or_else(|| Error::bad_database("User ID in userid_password is invalid."))?;
+
+            if user_id.server_name() != config.server_name {
+                return Err(Error::bad_config(
+                    "The database belongs to a different server_name.",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks an existing database against the config without loading it: the backend, the
+    /// server name and the signing key. A missing database passes, it is created on startup.
+    pub fn check(config: &Config) -> Result<()> {
+        Self::check_db_setup(config)?;
+
+        let file = match &*config.database_backend {
+            "sqlite" => "conduit.db",
+            "rocksdb" => "IDENTITY",
+            "persy" => "db.persy",
+            _ => return Err(Error::BadConfig("Database backend not found.")),
         };
+        if !Path::new(&config.database_path).join(file).exists() {
+            return Ok(());
+        }
+
+        let builder = Self::open_engine(config)?;
+        Self::check_server_name(&*builder.open_tree("userid_password")?, config)?;
+        if let Some(keypair) = builder.open_tree("global")?.get(b"keypair")? {
+            globals::parse_keypair(&keypair)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load an existing database or create a new one.
+    pub async fn load_or_create(config: &Config) -> Result<Arc<TokioRwLock<Self>>> {
+        Self::check_db_setup(config)?;
+
+        if !Path::new(&config.database_path).exists() {
+            std::fs::create_dir_all(&config.database_path)
+                .map_err(|_| Error::BadConfig("Database folder doesn't exists and couldn't be created (e.g. due to missing permissions). Please create the database folder yourself."))?;
+        }
+
+        let builder = Self::open_engine(config)?;
+        Self::check_server_name(&*builder.open_tree("userid_password")?, config)?;
 
         if config.max_request_size < 1024 {
             eprintln!("ERROR: Max request size is less than 1KB. Please increase it.");
@@ -166,6 +222,12 @@ impl Database {
                 userdeviceid_metadata: builder.open_tree("userdeviceid_metadata")?,
                 userid_devicelistversion: builder.open_tree("userid_devicelistversion")?,
                 token_userdeviceid: builder.open_tree("token_userdeviceid")?,
+                softlogouttoken_userdeviceid: builder.open_tree("softlogouttoken_userdeviceid")?,
+                userdeviceid_softlogouttoken: builder.open_tree("userdeviceid_softlogouttoken")?,
+                expiresat_softlogouttoken: builder.open_tree("expiresat_softlogouttoken")?,
+                token_expiresat: builder.open_tree("token_expiresat")?,
+                refreshtoken_userdeviceid: builder.open_tree("refreshtoken_userdeviceid")?,
+                userdeviceid_refreshtoken: builder.open_tree("userdeviceid_refreshtoken")?,
                 onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
                 userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
                 keychangeid_userid: builder.open_tree("keychangeid_userid")?,
@@ -175,6 +237,21 @@ impl Database {
                 userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
                 userfilterid_filter: builder.open_tree("userfilterid_filter")?,
                 todeviceid_events: builder.open_tree("todeviceid_events")?,
+                userid_servernoticeroomid: builder.open_tree("userid_servernoticeroomid")?,
+                guestuserids: builder.open_tree("guestuserids")?,
+                userid_inactivity: builder.open_tree("userid_inactivity")?,
+                inactivityexemptuserids: builder.open_tree("inactivityexemptuserids")?,
+                lockeduserids: builder.open_tree("lockeduserids")?,
+                registrationtoken_info: builder.open_tree("registrationtoken_info")?,
+                userid_pendingdeactivation: builder.open_tree("userid_pendingdeactivation")?,
+                remote_keys_cache: Mutex::new(LruCache::new(
+                    (10_000.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                remote_device_streams: Mutex::new(LruCache::new(
+                    (10_000.0 * config.conduit_cache_capacity_modifier) as usize,
+                )),
+                registration_token_mutex: Mutex::new(()),
+                device_metadata_mutex: Mutex::new(()),
             },
             uiaa: uiaa::Uiaa {
                 userdevicesessionid_uiaainfo: builder.open_tree("userdevicesessionid_uiaainfo")?,
@@ -183,13 +260,16 @@ impl Database {
             rooms: rooms::Rooms {
                 edus: rooms::RoomEdus {
                     readreceiptid_readreceipt: builder.open_tree("readreceiptid_readreceipt")?,
+                    roomuserid_readreceiptid: builder.open_tree("roomuserid_readreceiptid")?,
                     roomuserid_privateread: builder.open_tree("roomuserid_privateread")?, // "Private" read receipt
                     roomuserid_lastprivatereadupdate: builder
                         .open_tree("roomuserid_lastprivatereadupdate")?,
-                    typingid_userid: builder.open_tree("typingid_userid")?,
-                    roomid_lasttypingupdate: builder.open_tree("roomid_lasttypingupdate")?,
-                    presenceid_presence: builder.open_tree("presenceid_presence")?,
-                    userid_lastpresenceupdate: builder.open_tree("userid_lastpresenceupdate")?,
+                    roomuserid_privatereceipt: builder.open_tree("roomuserid_privatereceipt")?,
+                    typing: RwLock::new(HashMap::new()),
+                    last_typing_update: RwLock::new(HashMap::new()),
+                    typing_update_sender: broadcast::channel(100).0,
+                    presence: RwLock::new(HashMap::new()),
+                    presence_last_update: RwLock::new(HashMap::new()),
                 },
                 pduid_pdu: builder.open_tree("pduid_pdu")?,
                 eventid_pduid: builder.open_tree("eventid_pduid")?,
@@ -197,7 +277,9 @@ impl Database {
 
                 alias_roomid: builder.open_tree("alias_roomid")?,
                 aliasid_alias: builder.open_tree("aliasid_alias")?,
+                alias_userid: builder.open_tree("alias_userid")?,
                 publicroomids: builder.open_tree("publicroomids")?,
+                reportid_report: builder.open_tree("reportid_report")?,
 
                 tokenids: builder.open_tree("tokenids")?,
 
@@ -212,6 +294,8 @@ impl Database {
                 roomuserid_invitecount: builder.open_tree("roomuserid_invitecount")?,
                 userroomid_leftstate: builder.open_tree("userroomid_leftstate")?,
                 roomuserid_leftcount: builder.open_tree("roomuserid_leftcount")?,
+                userroomid_knockstate: builder.open_tree("userroomid_knockstate")?,
+                roomuserid_knockcount: builder.open_tree("roomuserid_knockcount")?,
 
                 lazyloadedids: builder.open_tree("lazyloadedids")?,
 
@@ -237,6 +321,8 @@ impl Database {
                 softfailedeventids: builder.open_tree("softfailedeventids")?,
 
                 referencedevents: builder.open_tree("referencedevents")?,
+                relatedeventid_pduid: builder.open_tree("relatedeventid_pduid")?,
+                roomid_partialstate: builder.open_tree("roomid_partialstate")?,
                 pdu_cache: Mutex::new(LruCache::new(
                     config
                         .pdu_cache_capacity
@@ -265,14 +351,24 @@ impl Database {
                     (100.0 * config.conduit_cache_capacity_modifier) as usize,
                 )),
                 lasttimelinecount_cache: Mutex::new(HashMap::new()),
+                server_acl_cache: RwLock::new(HashMap::new()),
+                partial_state_fills: RwLock::new(HashMap::new()),
             },
             account_data: account_data::AccountData {
                 roomuserdataid_accountdata: builder.open_tree("roomuserdataid_accountdata")?,
                 roomusertype_roomuserdataid: builder.open_tree("roomusertype_roomuserdataid")?,
             },
-            media: media::Media {
+            media: Arc::new(media::Media {
                 mediaid_file: builder.open_tree("mediaid_file")?,
-            },
+                userid_mxc: builder.open_tree("userid_mxc")?,
+                mxc_createdat: builder.open_tree("mxc_createdat")?,
+                createdat_mxc: builder.open_tree("createdat_mxc")?,
+                mxc_pendingupload: builder.open_tree("mxc_pendingupload")?,
+                expiresat_mxc: builder.open_tree("expiresat_mxc")?,
+                userid_pendingmxc: builder.open_tree("userid_pendingmxc")?,
+                mxc_blurhash: builder.open_tree("mxc_blurhash")?,
+                uploading: Mutex::new(HashSet::new()),
+            }),
             key_backups: key_backups::KeyBackups {
                 backupid_algorithm: builder.open_tree("backupid_algorithm")?,
                 backupid_etag: builder.open_tree("backupid_etag")?,
@@ -290,6 +386,7 @@ impl Database {
             },
             admin: admin::Admin {
                 sender: admin_sender,
+                enabled: config.enable_admin_room,
             },
             appservice: appservice::Appservice {
                 cached_registrations: Arc::new(RwLock::new(HashMap::new())),
@@ -298,6 +395,19 @@ impl Database {
             pusher: pusher::PushData {
                 senderkey_pusher: builder.open_tree("senderkey_pusher")?,
             },
+            rendezvous: rendezvous::Rendezvous {
+                sessionid_payload: builder.open_tree("rendezvous_sessionid_payload")?,
+                expiresat_sessionid: builder.open_tree("rendezvous_expiresat_sessionid")?,
+                update_mutex: Mutex::new(()),
+            },
+            threepid: threepid::ThreePid {
+                threepid_userid: builder.open_tree("threepid_userid")?,
+                userthreepid_addedat: builder.open_tree("userthreepid_addedat")?,
+                sessionid_validationsession: builder.open_tree("sessionid_validationsession")?,
+                secretthreepid_sessionid: builder.open_tree("secretthreepid_sessionid")?,
+                expiresatsessionid: builder.open_tree("expiresatsessionid")?,
+                add_lock: Mutex::new(()),
+            },
             globals: globals::Globals::load(
                 builder.open_tree("global")?,
                 builder.open_tree("server_signingkeys")?,
@@ -326,7 +436,7 @@ impl Database {
         }
 
         // If the database has any data, perform data migrations before starting
-        let latest_database_version = 11;
+        let latest_database_version = 18;
 
         if guard.users.count()? > 0 {
             let db = &*guard;
@@ -403,7 +513,7 @@ impl Database {
                             let user = user?;
                             if user.server_name() != db.globals.server_name() {
                                 println!("Migration: Creating user {}", user);
-                                db.users.create(&user, None)?;
+                                db.users.create(&user, None, &db.globals)?;
                             }
                         }
                     }
@@ -730,7 +840,65 @@ impl Database {
                 warn!("Migration: 10 -> 11 finished");
             }
 
-            assert_eq!(11, latest_database_version);
+            if db.globals.database_version()? < 12 {
+                // Email validation sessions are indexed now, they only live for a short time
+                db.threepid.sessionid_validationsession.clear()?;
+                db.globals.bump_database_version(12)?;
+
+                warn!("Migration: 11 -> 12 finished");
+            }
+
+            if db.globals.database_version()? < 13 {
+                // Rendezvous sessions are indexed by expiry now, they only live for a short time
+                db.rendezvous.sessionid_payload.clear()?;
+                db.globals.bump_database_version(13)?;
+
+                warn!("Migration: 12 -> 13 finished");
+            }
+
+            if db.globals.database_version()? < 14 {
+                // Media id reservations are indexed now, they only live for a short time
+                db.media.mxc_pendingupload.clear()?;
+                db.globals.bump_database_version(14)?;
+
+                warn!("Migration: 13 -> 14 finished");
+            }
+
+            if db.globals.database_version()? < 15 {
+                // Soft logouts expire now, and logging out on purpose doesn't cause one anymore
+                db.users.softlogouttoken_userdeviceid.clear()?;
+                db.users.userdeviceid_softlogouttoken.clear()?;
+                db.globals.bump_database_version(15)?;
+
+                warn!("Migration: 14 -> 15 finished");
+            }
+
+            if db.globals.database_version()? < 16 {
+                // Old media is purged by creation time, which was only stored for new files
+                db.media.index_created_at(&db.globals.get_media_folder())?;
+                db.globals.bump_database_version(16)?;
+
+                warn!("Migration: 15 -> 16 finished");
+            }
+
+            if db.globals.database_version()? < 17 {
+                // Receipts are looked up by user now, and private receipts track their own updates
+                db.rooms.edus.index_readreceipts()?;
+                db.rooms.edus.roomuserid_privatereceipt.clear()?;
+                db.globals.bump_database_version(17)?;
+
+                warn!("Migration: 16 -> 17 finished");
+            }
+
+            if db.globals.database_version()? < 18 {
+                // Relations are bundled with the events they relate to, which needs an index
+                db.rooms.index_relations()?;
+                db.globals.bump_database_version(18)?;
+
+                warn!("Migration: 17 -> 18 finished");
+            }
+
+            assert_eq!(18, latest_database_version);
 
             info!(
                 "Loaded {} database with version {}",
@@ -742,7 +910,11 @@ impl Database {
                 .bump_database_version(latest_database_version)?;
 
             // Create the admin room and server user on first run
-            create_admin_room(&guard).await?;
+            if guard.admin.enabled {
+                create_admin_room(&guard).await?;
+            } else {
+                create_server_user(&guard)?;
+            }
 
             warn!(
                 "Created new {} database with version {}",
@@ -750,10 +922,14 @@ impl Database {
             );
         }
 
-        // This data is probably outdated
-        guard.rooms.edus.presenceid_presence.clear()?;
+        if guard.admin.enabled {
+            // The admin room is missing if it was disabled when the database was created
+            if find_admin_room(&guard)?.is_none() {
+                create_admin_room(&guard).await?;
+            }
 
-        guard.admin.start_handler(Arc::clone(&db), admin_receiver);
+            guard.admin.start_handler(Arc::clone(&db), admin_receiver);
+        }
 
         // Set emergency access for the conduit user
         match set_emergency_access(&guard) {
@@ -778,6 +954,7 @@ impl Database {
         drop(guard);
 
         Self::start_cleanup_task(Arc::clone(&db), config).await;
+        Self::start_typing_task(Arc::clone(&db)).await;
 
         Ok(db)
     }
@@ -814,6 +991,11 @@ impl Database {
                 .watch_prefix(&userid_prefix),
         );
         futures.push(self.rooms.userroomid_leftstate.watch_prefix(&userid_prefix));
+        futures.push(
+            self.rooms
+                .userroomid_knockstate
+                .watch_prefix(&userid_prefix),
+        );
         futures.push(
             self.rooms
                 .userroomid_notificationcount
@@ -836,20 +1018,20 @@ impl Database {
                 .to_be_bytes()
                 .to_vec();
 
-            let roomid_bytes = room_id.as_bytes().to_vec();
-            let mut roomid_prefix = roomid_bytes.clone();
+            let mut roomid_prefix = room_id.as_bytes().to_vec();
             roomid_prefix.push(0xff);
 
             // PDUs
             futures.push(self.rooms.pduid_pdu.watch_prefix(&short_roomid));
 
             // EDUs
-            futures.push(
+            let typing_room_id = room_id.clone();
+            futures.push(Box::pin(async move {
                 self.rooms
                     .edus
-                    .roomid_lasttypingupdate
-                    .watch_prefix(&roomid_bytes),
-            );
+                    .wait_for_typing_update(&typing_room_id)
+                    .await
+            }));
 
             futures.push(
                 self.rooms
@@ -924,6 +1106,7 @@ impl Database {
             let mut i = interval(timer_interval);
             #[cfg(unix)]
             let mut s = signal(SignalKind::hangup()).unwrap();
+            let mut last_todevice_trim = Instant::now();
 
             loop {
                 #[cfg(unix)]
@@ -947,18 +1130,269 @@ impl Database {
                 } else {
                     info!("cleanup: Finished in {:?}", start.elapsed());
                 }
+
+                let guard = db.read().await;
+                if let Err(e) = finalize_deactivations(&guard).await {
+                    error!("cleanup: Failed to finalize deactivations: {}", e);
+                }
+                if let Err(e) = resume_state_fills(Arc::clone(&db), &guard) {
+                    error!("cleanup: Failed to resume partial state fills: {}", e);
+                }
+                if guard.globals.allow_presence() {
+                    if let Err(e) = expire_presence(Arc::clone(&db), &guard) {
+                        error!("cleanup: Failed to update idle presence: {}", e);
+                    }
+                }
+                drop(guard);
+
+                // Walking all devices is expensive, so only do it once an hour
+                if last_todevice_trim.elapsed() > Duration::from_secs(60 * 60) {
+                    last_todevice_trim = Instant::now();
+
+                    let guard = db.read().await;
+                    match guard.users.trim_to_device_events(
+                        guard.globals.todevice_inactive(),
+                        guard.globals.max_todevice_events_per_device(),
+                    ) {
+                        Ok(removed) => {
+                            info!("cleanup: Removed {} stale to-device messages", removed)
+                        }
+                        Err(e) => error!("cleanup: Failed to trim to-device messages: {}", e),
+                    }
+
+                    if let Err(e) = guard
+                        .users
+                        .purge_soft_logouts(utils::millis_since_unix_epoch())
+                    {
+                        error!("cleanup: Failed to forget old soft logouts: {}", e);
+                    }
+
+                    // Inactivity is counted in days, so this doesn't have to happen more often
+                    if let Err(e) = deactivate_inactive_accounts(&guard).await {
+                        error!("cleanup: Failed to deactivate inactive accounts: {}", e);
+                    }
+                    drop(guard);
+
+                    if let Err(e) = expire_remote_media(Arc::clone(&db)).await {
+                        error!("cleanup: Failed to delete old remote media: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Expires typing notifications once their timeout is reached.
+    pub async fn start_typing_task(db: Arc<TokioRwLock<Self>>) {
+        use std::time::Duration;
+        use tokio::time::interval;
+
+        tokio::spawn(async move {
+            let mut i = interval(Duration::from_secs(1));
+
+            loop {
+                i.tick().await;
+
+                let guard = db.read().await;
+                if let Err(e) = guard.rooms.edus.typings_maintain_all(&guard.globals) {
+                    error!("Failed to expire typing notifications: {}", e);
+                }
             }
         });
     }
 }
 
+/// Restarts the background fill of rooms joined with partial state, e.g. after a restart or if
+/// the server we joined through was unreachable.
+fn resume_state_fills(db_lock: Arc<TokioRwLock<Database>>, db: &Database) -> Result<()> {
+    for room_id in db.rooms.partial_state_rooms() {
+        crate::client_server::spawn_state_fill(Arc::clone(&db_lock), db, &room_id?, None);
+    }
+
+    Ok(())
+}
+
+/// Sets idle users to unavailable and tells the servers they share rooms with.
+fn expire_presence(db_lock: Arc<TokioRwLock<Database>>, db: &Database) -> Result<()> {
+    let now = utils::millis_since_unix_epoch();
+
+    for (user_id, presence) in db.rooms.edus.presence_maintain(&db.rooms, &db.globals)? {
+        let last_active_ago = presence
+            .content
+            .last_active_ago
+            .map_or(0, |timestamp| now.saturating_sub(timestamp.into()));
+
+        let mut update = PresenceUpdate::new(
+            user_id,
+            PresenceState::Unavailable,
+            last_active_ago.try_into().unwrap_or(UInt::MAX),
+        );
+        update.status_msg = presence.content.status_msg;
+
+        crate::client_server::queue_presence_update(Arc::clone(&db_lock), db, update);
+    }
+
+    Ok(())
+}
+
+/// Leaves the rooms of accounts whose deactivation grace period is over and erases them if that
+/// was requested.
+pub(crate) async fn finalize_deactivations(db: &Database) -> Result<()> {
+    let now = utils::millis_since_unix_epoch();
+
+    let due = db
+        .users
+        .pending_deactivations()
+        .filter_map(|r| r.ok())
+        .filter(|(_, pending)| pending.is_due(now))
+        .collect::<Vec<_>>();
+
+    for (user_id, pending) in due {
+        if pending.erase {
+            if let Err(e) = crate::client_server::erase_user(db, &user_id).await {
+                error!("Failed to erase {}: {}", user_id, e);
+            }
+        }
+        crate::client_server::leave_all_rooms(db, &user_id).await?;
+        db.users.finish_deactivation(&user_id)?;
+
+        info!("Finalized the deactivation of {}.", user_id);
+    }
+
+    db.flush()
+}
+
+/// Deletes media cached from other servers once it is older than the retention period and tells
+/// the admins how much space was freed.
+async fn expire_remote_media(db: Arc<TokioRwLock<Database>>) -> Result<()> {
+    let db = db.read_owned().await;
+    let retention = match db.globals.remote_media_retention() {
+        Some(retention) => retention,
+        None => return Ok(()),
+    };
+
+    // Deleting the files takes a while, it must not block the other requests
+    let (db, purged) = tokio::task::spawn_blocking(move || {
+        let purged = db.media.purge_media_before(
+            &db.globals.get_media_folder(),
+            db.globals.server_name(),
+            SystemTime::now() - retention,
+            false,
+        );
+        (db, purged)
+    })
+    .await
+    .map_err(std::io::Error::from)?;
+    let (files, bytes) = purged?;
+
+    if files > 0 {
+        info!("cleanup: Removed {} old remote media file(s)", files);
+        db.admin
+            .send_message(RoomMessageEventContent::notice_plain(format!(
+                "Removed {} cached remote media file(s) older than {} day(s), freeing {} bytes.",
+                files,
+                retention.as_secs() / (60 * 60 * 24),
+                bytes
+            )));
+    }
+
+    Ok(())
+}
+
+/// Warns the users of accounts nobody used for `inactive_account_days` and deactivates the
+/// accounts once all warnings were sent. Admins, exempted accounts, the server user and users of
+/// appservices are skipped.
+async fn deactivate_inactive_accounts(db: &Database) -> Result<()> {
+    let period = match db.globals.inactive_account_period() {
+        Some(period) => period,
+        None => return Ok(()),
+    };
+    let warnings = db.globals.inactive_account_warnings();
+
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is a valid UserId");
+
+    let local_users = db
+        .users
+        .iter()
+        .filter_map(|r| r.ok())
+        .filter(|user_id| user_id.server_name() == db.globals.server_name())
+        .collect::<Vec<_>>();
+
+    for user_id in local_users {
+        // One broken account must not keep the others from being handled
+        if let Err(e) =
+            handle_inactive_account(db, &user_id, &conduit_user, period, &warnings).await
+        {
+            error!("cleanup: Failed to check inactivity of {}: {}", user_id, e);
+        }
+    }
+
+    db.flush()
+}
+
+async fn handle_inactive_account(
+    db: &Database,
+    user_id: &UserId,
+    conduit_user: &UserId,
+    period: Duration,
+    warnings: &[Duration],
+) -> Result<()> {
+    if user_id == conduit_user
+        || db.users.is_deactivated(user_id)?
+        || db.users.is_inactivity_exempt(user_id)?
+        || db.users.is_admin(user_id, &db.rooms, &db.globals)?
+        || db.appservice.is_appservice_user(user_id)?
+    {
+        return Ok(());
+    }
+
+    let now = utils::millis_since_unix_epoch();
+    let mut inactivity = db.users.inactivity(user_id, now)?;
+
+    match inactivity.action(now, period, warnings) {
+        InactivityAction::Nothing => {}
+        InactivityAction::Warn { deactivate_at } => {
+            admin::send_server_notice(
+                db,
+                user_id,
+                RoomMessageEventContent::notice_plain(format!(
+                    "Your account was not used for a long time and will be deactivated in {} \
+                     day(s). Log in to keep it.",
+                    deactivate_at.saturating_sub(now) / (24 * 60 * 60 * 1000)
+                )),
+            )
+            .await?;
+
+            inactivity.warnings_sent += 1;
+            inactivity.deactivate_at = Some(deactivate_at);
+        }
+        InactivityAction::Deactivate => {
+            crate::client_server::deactivate_user(db, user_id, false).await?;
+            db.users.forget_inactivity(user_id)?;
+
+            info!("Deactivated the inactive account of {}.", user_id);
+            db.admin
+                .send_message(RoomMessageEventContent::notice_plain(format!(
+                    "Deactivated the inactive account of {}.",
+                    user_id
+                )));
+            return Ok(());
+        }
+    }
+
+    db.users.set_inactivity(user_id, &inactivity)
+}
+
 /// Sets the emergency password and push rules for the @conduit account in case emergency password is set
 fn set_emergency_access(db: &Database) -> Result<bool> {
     let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
         .expect("@conduit:server_name is a valid UserId");
 
-    db.users
-        .set_password(&conduit_user, db.globals.emergency_password().as_deref())?;
+    db.users.set_password(
+        &conduit_user,
+        db.globals.emergency_password().as_deref(),
+        &db.globals,
+    )?;
 
     let (ruleset, res) = match db.globals.emergency_password() {
         Some(_) => (Ruleset::server_default(&conduit_user), Ok(true)),
@@ -1013,3 +1447,233 @@ impl From<OwnedRwLockReadGuard<Database>> for DatabaseGuard {
         Self(val)
     }
 }
+
+/// Config for a new sqlite database without admin room in an empty temporary directory.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) fn test_config(name: &str) -> Config {
+    let path = std::env::temp_dir().join(format!("conduit-{}-test-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+
+    serde_json::from_value(serde_json::json!({
+        "server_name": "example.org",
+        "database_path": path.to_str().unwrap(),
+        "database_backend": "sqlite",
+        "enable_admin_room": false,
+    }))
+    .unwrap()
+}
+
+/// Opens a new sqlite database without admin room in a temporary directory.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_database(name: &str) -> Arc<TokioRwLock<Database>> {
+    Database::load_or_create(&test_config(name)).await.unwrap()
+}
+
+/// Sends an event to a room of a test database the way a client would. The first event of a room
+/// has to be its `m.room.create` event.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_send(
+    db: &Database,
+    room_id: &RoomId,
+    sender: &UserId,
+    event_type: &str,
+    state_key: Option<&str>,
+    content: serde_json::Value,
+) -> Result<Arc<EventId>> {
+    db.rooms.get_or_create_shortroomid(room_id, &db.globals)?;
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        crate::pdu::PduBuilder {
+            event_type: event_type.into(),
+            content: serde_json::value::to_raw_value(&content).expect("json is valid raw json"),
+            unsigned: None,
+            state_key: state_key.map(ToOwned::to_owned),
+            redacts: None,
+        },
+        sender,
+        room_id,
+        db,
+        &state_lock,
+    )
+}
+
+/// Creates a room of version 9 with `creator` as its only member.
+#[cfg(all(test, feature = "sqlite"))]
+pub(crate) async fn test_room(db: &Database, room_id: &RoomId, creator: &UserId) {
+    test_send(
+        db,
+        room_id,
+        creator,
+        "m.room.create",
+        Some(""),
+        serde_json::json!({ "creator": creator, "room_version": "9" }),
+    )
+    .await
+    .unwrap();
+    test_send(
+        db,
+        room_id,
+        creator,
+        "m.room.member",
+        Some(creator.as_str()),
+        serde_json::json!({ "membership": "join" }),
+    )
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn existing_database_is_checked_against_the_config() {
+        use super::{test_config, Database};
+        use ruma::{server_name, user_id};
+
+        let config = test_config("check");
+        // Nothing to check before the database is created
+        Database::check(&config).unwrap();
+
+        let db = Database::load_or_create(&config).await.unwrap();
+        let db = db.read().await;
+        db.users
+            .create(user_id!("@alice:example.org"), None, &db.globals)
+            .unwrap();
+        Database::check(&config).unwrap();
+
+        let mut other_server = config.clone();
+        other_server.server_name = server_name!("other.example.org").to_owned();
+        assert!(Database::check(&other_server).is_err());
+
+        db.globals
+            .globals
+            .insert(b"keypair", b"1\xffnot a key")
+            .unwrap();
+        assert!(Database::check(&config).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn due_deactivations_are_finalized() {
+        use super::{finalize_deactivations, test_room, utils};
+        use ruma::{room_id, user_id};
+
+        let db = super::test_database("finalize-deactivations").await;
+        let db = db.read().await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        test_room(&db, room_id, carol).await;
+        super::test_send(
+            &db,
+            room_id,
+            carol,
+            "m.room.join_rules",
+            Some(""),
+            serde_json::json!({ "join_rule": "public" }),
+        )
+        .await
+        .unwrap();
+        for user_id in [alice, bob] {
+            db.users
+                .create(user_id, Some("password"), &db.globals)
+                .unwrap();
+            db.users
+                .set_displayname(user_id, Some("Name".to_owned()))
+                .unwrap();
+            super::test_send(
+                &db,
+                room_id,
+                user_id,
+                "m.room.member",
+                Some(user_id.as_str()),
+                serde_json::json!({ "membership": "join" }),
+            )
+            .await
+            .unwrap();
+        }
+
+        db.users.schedule_deactivation(alice, 0, true).unwrap();
+        db.users
+            .schedule_deactivation(bob, utils::millis_since_unix_epoch() + 60_000, true)
+            .unwrap();
+
+        finalize_deactivations(&db).await.unwrap();
+
+        // Alice's grace period is over
+        assert!(!db.rooms.is_joined(alice, room_id).unwrap());
+        assert_eq!(db.users.displayname(alice).unwrap(), None);
+        assert!(!db.users.restore_account(alice).unwrap());
+
+        // Bob can still change his mind
+        assert!(db.rooms.is_joined(bob, room_id).unwrap());
+        assert_eq!(db.users.displayname(bob).unwrap(), Some("Name".to_owned()));
+        assert_eq!(db.users.pending_deactivations().count(), 1);
+        assert!(db.users.restore_account(bob).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn inactive_appservice_and_server_users_are_kept() {
+        use super::{handle_inactive_account, users::Inactivity};
+        use ruma::{user_id, UserId};
+        use std::time::Duration;
+
+        let db = super::test_database("inactive-appservice").await;
+        let db = db.read().await;
+        db.appservice
+            .register_appservice(
+                serde_yaml::from_str(
+                    r#"
+id: bridge
+url: "http://localhost:9000"
+as_token: as_token
+hs_token: hs_token
+sender_localpart: bridgebot
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@bridge_.*:example.org"
+"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        let conduit = user_id!("@conduit:example.org");
+        let users: [&UserId; 4] = [
+            user_id!("@alice:example.org"),
+            user_id!("@bridge_alice:example.org"),
+            user_id!("@bridgebot:example.org"),
+            conduit,
+        ];
+        for user_id in users {
+            db.users
+                .create(user_id, Some("password"), &db.globals)
+                .unwrap();
+            db.users
+                .set_inactivity(user_id, &Inactivity::new(0))
+                .unwrap();
+            handle_inactive_account(&db, user_id, conduit, Duration::from_secs(1), &[])
+                .await
+                .unwrap();
+        }
+
+        assert!(db.users.is_deactivated(users[0]).unwrap());
+        for user_id in &users[1..] {
+            assert!(!db.users.is_deactivated(user_id).unwrap());
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use super::Config;
-use crate::Result;
+use crate::{Error, Result};
 
 use std::{future::Future, pin::Pin, sync::Arc};
 
@@ -38,6 +38,11 @@ pub trait DatabaseEngine: Send + Sync {
     fn memory_usage(&self) -> Result<String> {
         Ok("Current database engine does not support memory usage reporting.".to_owned())
     }
+    fn compact(&self) -> Result<()> {
+        Err(Error::BadConfig(
+            "Current database engine does not support compaction.",
+        ))
+    }
 }
 
 pub trait Tree: Send + Sync {
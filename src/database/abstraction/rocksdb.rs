@@ -121,6 +121,22 @@ impl DatabaseEngine for Arc<Engine> {
             self.cache.get_pinned_usage() as f64 / 1024.0 / 1024.0,
         ))
     }
+
+    fn compact(&self) -> Result<()> {
+        let cfs = rocksdb::DBWithThreadMode::<rocksdb::MultiThreaded>::list_cf(
+            &rocksdb::Options::default(),
+            self.rocks.path(),
+        )?;
+
+        for name in cfs {
+            if let Some(cf) = self.rocks.cf_handle(&name) {
+                self.rocks
+                    .compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl RocksDbEngineTree<'_> {
@@ -123,6 +123,13 @@ impl DatabaseEngine for Arc<Engine> {
     fn cleanup(&self) -> Result<()> {
         self.flush_wal()
     }
+
+    fn compact(&self) -> Result<()> {
+        self.flush_wal()?;
+        // VACUUM rebuilds the whole file, writers are blocked until it is done
+        self.write_lock().execute("VACUUM", [])?;
+        self.flush_wal()
+    }
 }
 
 pub struct SqliteTable {
@@ -347,3 +354,41 @@ impl Tree for SqliteTable {
         Ok(())
     }
 }
+
+/// Opens an engine on a new database in a temporary directory. The config tells where it is.
+#[cfg(test)]
+pub(crate) fn test_engine(name: &str) -> (Arc<Engine>, Config) {
+    let config = crate::database::test_config(name);
+    let engine = <Arc<Engine> as DatabaseEngine>::open(&config).unwrap();
+
+    (engine, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_keeps_data() {
+        let (engine, config) = test_engine("sqlite");
+        let tree = engine.open_tree("test").unwrap();
+
+        for i in 0..100_u8 {
+            tree.insert(&[i], &[i; 1024]).unwrap();
+        }
+        for i in 0..50_u8 {
+            tree.remove(&[i]).unwrap();
+        }
+
+        engine.compact().unwrap();
+
+        assert_eq!(tree.get(&[10]).unwrap(), None);
+        assert_eq!(tree.get(&[60]).unwrap(), Some(vec![60; 1024]));
+        tree.insert(&[10], b"after").unwrap();
+        assert_eq!(tree.get(&[10]).unwrap(), Some(b"after".to_vec()));
+
+        drop(tree);
+        drop(engine);
+        std::fs::remove_dir_all(&config.database_path).unwrap();
+    }
+}
@@ -6,7 +6,7 @@ use ruma::{
     RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, mem, sync::Arc};
 
 use super::abstraction::Tree;
 
@@ -51,9 +51,10 @@ impl AccountData {
             ));
         }
 
+        // Serialize `data` itself, going through `json` would reorder the keys of raw content
         self.roomuserdataid_accountdata.insert(
             &roomuserdataid,
-            &serde_json::to_vec(&json).expect("to_vec always works on json values"),
+            &serde_json::to_vec(data).expect("all types here can be serialized"),
         )?;
 
         let prev = self.roomusertype_roomuserdataid.get(&key)?;
@@ -102,6 +103,28 @@ impl AccountData {
             .transpose()
     }
 
+    /// Returns the count of the newest global account data change of this user.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn last_global_change(&self, user_id: &UserId) -> Result<Option<u64>> {
+        let mut prefix = vec![0xff];
+        prefix.extend_from_slice(user_id.as_bytes());
+        prefix.push(0xff);
+
+        let mut last = prefix.clone();
+        last.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        self.roomuserdataid_accountdata
+            .iter_from(&last, true)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .next()
+            .map(|(k, _)| {
+                k.get(prefix.len()..prefix.len() + mem::size_of::<u64>())
+                    .and_then(|count| utils::u64_from_bytes(count).ok())
+                    .ok_or_else(|| Error::bad_database("RoomUserData ID in db is invalid."))
+            })
+            .transpose()
+    }
+
     /// Returns all changes to the account data that happened after `since`.
     #[tracing::instrument(skip(self, room_id, user_id, since))]
     pub fn changes_since(
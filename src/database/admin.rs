@@ -1,16 +1,18 @@
 use std::{
     collections::BTreeMap,
     convert::{TryFrom, TryInto},
+    path::Path,
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
 use crate::{
+    database::{media::Media, rooms::Report, users::RegistrationTokenInfo},
     error::{Error, Result},
     pdu::PduBuilder,
     server_server, utils,
     utils::HtmlEscape,
-    Database, PduEvent,
+    Config, Database, PduEvent,
 };
 use clap::Parser;
 use regex::Regex;
@@ -26,14 +28,21 @@ use ruma::{
             message::RoomMessageEventContent,
             name::RoomNameEventContent,
             power_levels::RoomPowerLevelsEventContent,
+            redaction::RoomRedactionEventContent,
+            server_acl::RoomServerAclEventContent,
             topic::RoomTopicEventContent,
         },
-        RoomEventType,
+        tag::{TagEvent, TagEventContent, TagInfo},
+        RoomAccountDataEventType, RoomEventType, StateEventType,
     },
     EventId, RoomAliasId, RoomId, RoomName, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::to_raw_value;
 use tokio::sync::{mpsc, MutexGuard, RwLock, RwLockReadGuard};
+use tracing::{info, warn};
+
+const REGISTRATION_TOKEN_LENGTH: usize = 16;
+const CONFIRMATION_TOKEN_LENGTH: usize = 8;
 
 #[derive(Debug)]
 pub enum AdminRoomEvent {
@@ -44,6 +53,8 @@ pub enum AdminRoomEvent {
 #[derive(Clone)]
 pub struct Admin {
     pub sender: mpsc::UnboundedSender<AdminRoomEvent>,
+    /// Whether there is an admin room. Messages are dropped if there isn't.
+    pub enabled: bool,
 }
 
 impl Admin {
@@ -96,8 +107,27 @@ impl Admin {
                     .unwrap();
             };
 
+            // Walking the admin room history is expensive, so old notices are pruned periodically
+            let mut prune_interval = tokio::time::interval(ADMIN_ROOM_PRUNE_INTERVAL);
+
             loop {
                 tokio::select! {
+                    _ = prune_interval.tick() => {
+                        let guard = db.read().await;
+                        let mutex_state = Arc::clone(
+                            guard.globals
+                                .roomid_mutex_state
+                                .write()
+                                .unwrap()
+                                .entry(conduit_room.clone())
+                                .or_default(),
+                        );
+                        let state_lock = mutex_state.lock().await;
+
+                        if let Err(e) = prune_admin_room(&guard, &conduit_user, &conduit_room, &state_lock) {
+                            warn!("Failed to prune old admin room notices: {}", e);
+                        }
+                    }
                     Some(event) = receiver.recv() => {
                         let guard = db.read().await;
                         let mutex_state = Arc::clone(
@@ -115,7 +145,9 @@ impl Admin {
                                 send_message(content, guard, &state_lock);
                             }
                             AdminRoomEvent::ProcessMessage(room_message) => {
-                                let reply_message = process_admin_message(&*guard, room_message);
+                                let reply_message =
+                                    process_admin_message(&*guard, room_message, &state_lock)
+                                        .await;
 
                                 send_message(reply_message, guard, &state_lock);
                             }
@@ -129,20 +161,28 @@ impl Admin {
     }
 
     pub fn process_message(&self, room_message: String) {
-        self.sender
-            .send(AdminRoomEvent::ProcessMessage(room_message))
-            .unwrap();
+        if self.enabled {
+            self.sender
+                .send(AdminRoomEvent::ProcessMessage(room_message))
+                .unwrap();
+        }
     }
 
     pub fn send_message(&self, message_content: RoomMessageEventContent) {
-        self.sender
-            .send(AdminRoomEvent::SendMessage(message_content))
-            .unwrap();
+        if self.enabled {
+            self.sender
+                .send(AdminRoomEvent::SendMessage(message_content))
+                .unwrap();
+        }
     }
 }
 
 // Parse and process a message from the admin room
-fn process_admin_message(db: &Database, room_message: String) -> RoomMessageEventContent {
+async fn process_admin_message(
+    db: &Database,
+    room_message: String,
+    mutex_lock: &MutexGuard<'_, ()>,
+) -> RoomMessageEventContent {
     let mut lines = room_message.lines();
     let command_line = lines.next().expect("each string has at least one line");
     let body: Vec<_> = lines.collect();
@@ -160,7 +200,7 @@ fn process_admin_message(db: &Database, room_message: String) -> RoomMessageEven
         }
     };
 
-    match process_admin_command(db, admin_command, body) {
+    match process_admin_command(db, admin_command, body, mutex_lock).await {
         Ok(reply_message) => reply_message,
         Err(error) => {
             let markdown_message = format!(
@@ -257,23 +297,202 @@ enum AdminCommand {
         event_id: Box<EventId>,
     },
 
+    /// List the rooms a user is joined to, invited to or has left
+    UserRooms {
+        /// The user to inspect, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+
+        /// Which page of rooms to show
+        #[clap(long, default_value = "1")]
+        page: usize,
+    },
+
+    /// Print the server ACL that is currently enforced for a room
+    AclStatus {
+        /// The room to inspect, e.g. `!abc:example.org`
+        room_id: Box<RoomId>,
+    },
+
+    /// Drop the cached server ACL of a room
+    ///
+    /// The ACL is loaded from the room state again the next time it is needed.
+    AclRefresh {
+        /// The room whose cached ACL should be dropped
+        room_id: Box<RoomId>,
+    },
+
     /// Print database memory usage statistics
     DatabaseMemoryUsage,
 
+    /// Print the current positions of the internal streams
+    ///
+    /// All streams share one global counter. If a user is given, the position of their newest
+    /// account data and device key changes are printed as well.
+    StreamPositions {
+        /// The user to inspect, e.g. `@alice:example.org`
+        user_id: Option<Box<UserId>>,
+    },
+
+    /// Remove undelivered to-device messages of devices that stopped syncing
+    TrimTodevice {
+        /// Devices inactive for longer than this lose their messages, e.g. `30d` or `12h`
+        #[clap(long)]
+        older_than: String,
+    },
+
+    /// Delete media of a server, e.g. media cached from a remote server
+    #[clap(alias = "purge-remote-media")]
+    PurgeMediaFrom {
+        /// The server whose media should be removed, e.g. `example.com`
+        server: Box<ServerName>,
+
+        /// Only remove media created before this date, e.g. `2022-03-31`
+        #[clap(long)]
+        before: Option<String>,
+
+        /// Allow removing files uploaded by local users, if the server is this server
+        #[clap(long)]
+        include_local: bool,
+    },
+
+    /// Delete media of all servers created before a date
+    ///
+    /// Files uploaded by local users are kept unless `--include-local` is given.
+    PurgeMediaBefore {
+        /// The date, e.g. `2022-03-31`
+        date: String,
+
+        /// Also remove files uploaded by local users
+        #[clap(long)]
+        include_local: bool,
+    },
+
+    /// Compact the database to reclaim unused disk space
+    ///
+    /// This can take a while on large databases and may temporarily need as much free disk space
+    /// as the database currently occupies.
+    CompactDatabase,
+
     /// Show configuration values
     ShowConfig,
 
+    /// Print the Conduit version, build information and database backend
+    Version,
+
+    /// Make a local user an admin by joining them to this room
+    ///
+    /// Use this if granting admin privileges to the first user failed during registration.
+    BootstrapAdmin {
+        /// The user to make an admin, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+    },
+
+    /// Create a token that allows registering an account
+    ///
+    /// Tokens are only required if `registration_requires_token` is enabled.
+    CreateRegistrationToken {
+        /// How often the token can be used, unlimited by default
+        #[clap(long)]
+        uses: Option<u64>,
+
+        /// The date the token expires at, e.g. `2022-03-31`. Never expires by default.
+        #[clap(long)]
+        expires: Option<String>,
+    },
+
+    /// List all registration tokens with their remaining uses and expiry
+    ListRegistrationTokens,
+
+    /// Delete a registration token
+    DeleteRegistrationToken {
+        /// The token to delete
+        token: String,
+    },
+
+    /// List the events users reported, oldest first
+    ListReports,
+
+    /// Delete a report once it was dealt with
+    ClearReports {
+        /// The report to delete, as printed by `list-reports`. Deletes all reports if not given.
+        report_id: Option<u64>,
+    },
+
+    /// Restore an account that was deactivated during the deactivation grace period
+    ///
+    /// The user keeps their password and rooms, but has to log in again.
+    RestoreAccount {
+        /// The user to restore, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+    },
+
+    /// Deactivate a local user right away, e.g. one who abuses other servers
+    ///
+    /// The user leaves all rooms, including the admin room, and is logged out everywhere. There is
+    /// no grace period, the account can't be restored.
+    DeactivateUser {
+        /// The user to deactivate, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+
+        /// Also delete the media the user uploaded. Uploaders weren't recorded before this command
+        /// existed, so older uploads are kept.
+        #[clap(long)]
+        purge_media: bool,
+    },
+
+    /// Never deactivate this user for inactivity, see `inactive_account_days`
+    InactivityExempt {
+        /// The user to exempt, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+
+        /// Remove the exemption instead
+        #[clap(long)]
+        remove: bool,
+    },
+
+    /// Lock a local user out of their account until it is unlocked again
+    ///
+    /// The user keeps their rooms and devices, but every request except logging out fails.
+    LockUser {
+        /// The user to lock, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+    },
+
+    /// Unlock an account that was locked with `lock-user`
+    UnlockUser {
+        /// The user to unlock, e.g. `@alice:example.org`
+        user_id: Box<UserId>,
+    },
+
+    /// Deactivate all local users, e.g. to decommission the server
+    ///
+    /// The first invocation prints a confirmation token, run the command again with the token to
+    /// start. Admins are skipped unless `--force` is given. The server user is always kept, it
+    /// runs this room.
+    DeactivateAll {
+        /// The token printed by the first invocation
+        confirmation: Option<String>,
+
+        /// Also deactivate admins
+        #[clap(long)]
+        force: bool,
+    },
+
     /// Reset user password
+    ///
+    /// The user's clients get a soft logout, they can log in again with the new password without
+    /// losing their encryption keys.
     ResetPassword {
         /// Username of the user for whom the password should be reset
         username: String,
     },
 }
 
-fn process_admin_command(
+async fn process_admin_command(
     db: &Database,
     command: AdminCommand,
     body: Vec<&str>,
+    mutex_lock: &MutexGuard<'_, ()>,
 ) -> Result<RoomMessageEventContent> {
     let reply_message_content = match command {
         AdminCommand::RegisterAppservice => {
@@ -430,6 +649,58 @@ fn process_admin_command(
                 None => RoomMessageEventContent::text_plain("PDU not found."),
             }
         }
+        AdminCommand::UserRooms { user_id, page } => {
+            let mut rooms = Vec::new();
+            for room_id in db.rooms.rooms_joined(&user_id) {
+                rooms.push((room_id?, MembershipState::Join));
+            }
+            for result in db.rooms.rooms_invited(&user_id) {
+                rooms.push((result?.0, MembershipState::Invite));
+            }
+            for result in db.rooms.rooms_left(&user_id) {
+                rooms.push((result?.0, MembershipState::Leave));
+            }
+
+            let rooms = rooms
+                .into_iter()
+                .map(|(room_id, membership)| {
+                    let name = db
+                        .rooms
+                        .room_state_get(&room_id, &StateEventType::RoomName, "")?
+                        .map_or(Ok(None), |s| {
+                            serde_json::from_str(s.content.get())
+                                .map(|c: RoomNameEventContent| c.name)
+                                .map_err(|_| {
+                                    Error::bad_database("Invalid room name event in database.")
+                                })
+                        })?;
+                    Ok((room_id, membership, name.map(|name| name.to_string())))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            RoomMessageEventContent::text_plain(format_user_rooms(&user_id, &rooms, page))
+        }
+        AdminCommand::AclStatus { room_id } => {
+            if !db.rooms.exists(&room_id)? {
+                RoomMessageEventContent::text_plain("Room not found.")
+            } else {
+                let acl = db.rooms.server_acl(&room_id)?;
+                RoomMessageEventContent::text_plain(format_server_acl(&room_id, acl.as_deref()))
+            }
+        }
+        AdminCommand::AclRefresh { room_id } => {
+            if db.rooms.invalidate_server_acl_cache(&room_id) {
+                RoomMessageEventContent::text_plain(format!(
+                    "Dropped the cached server ACL of {}.",
+                    room_id
+                ))
+            } else {
+                RoomMessageEventContent::text_plain(format!(
+                    "No server ACL of {} was cached.",
+                    room_id
+                ))
+            }
+        }
         AdminCommand::DatabaseMemoryUsage => match db._db.memory_usage() {
             Ok(response) => RoomMessageEventContent::text_plain(response),
             Err(e) => RoomMessageEventContent::text_plain(format!(
@@ -437,10 +708,431 @@ fn process_admin_command(
                 e
             )),
         },
+        AdminCommand::StreamPositions { user_id } => {
+            let mut msg = format!("Global counter: {}\n", db.globals.current_count()?);
+
+            if let Some(user_id) = user_id {
+                let position = |count: Option<u64>| {
+                    count.map_or_else(|| "none".to_owned(), |count| count.to_string())
+                };
+
+                msg += &format!(
+                    "\n{}:\n\
+                     Account data: {}\n\
+                     Device key changes: {}\n\
+                     Device list version: {}\n\
+                     Pending to-device messages: {}\n",
+                    user_id,
+                    position(db.account_data.last_global_change(&user_id)?),
+                    position(db.users.last_keychange(&user_id)?),
+                    position(db.users.get_devicelist_version(&user_id)?),
+                    db.users.pending_to_device_events(&user_id),
+                );
+            }
+
+            RoomMessageEventContent::text_plain(msg)
+        }
+        AdminCommand::TrimTodevice { older_than } => match utils::parse_duration(&older_than) {
+            Some(inactive_for) => {
+                let removed = db.users.trim_to_device_events(
+                    inactive_for,
+                    db.globals.max_todevice_events_per_device(),
+                )?;
+                RoomMessageEventContent::text_plain(format!(
+                    "Removed {} to-device messages.",
+                    removed
+                ))
+            }
+            None => RoomMessageEventContent::text_plain(
+                "Invalid duration. Use a number followed by s, m, h, d or w, e.g. `30d`.",
+            ),
+        },
+        AdminCommand::CreateRegistrationToken { uses, expires } => {
+            let expiry_time = match expires {
+                Some(expires) => match utils::parse_date(&expires)
+                    .and_then(|date| date.duration_since(UNIX_EPOCH).ok())
+                    .and_then(|since_epoch| u64::try_from(since_epoch.as_millis()).ok())
+                {
+                    Some(expiry) if expiry <= utils::millis_since_unix_epoch() => {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "The expiry date has already passed.",
+                        ))
+                    }
+                    Some(expiry) => Some(expiry),
+                    None => {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Invalid date. Use the format YYYY-MM-DD, e.g. `2022-03-31`.",
+                        ))
+                    }
+                },
+                None => None,
+            };
+
+            let token = utils::random_string(REGISTRATION_TOKEN_LENGTH);
+            db.users.set_registration_token(
+                &token,
+                &RegistrationTokenInfo {
+                    uses_allowed: uses,
+                    completed: 0,
+                    expiry_time,
+                },
+            )?;
+
+            RoomMessageEventContent::text_plain(format!("Created registration token: {}", token))
+        }
+        AdminCommand::ListRegistrationTokens => {
+            let now = utils::millis_since_unix_epoch();
+            let tokens = db.users.registration_tokens().collect::<Result<Vec<_>>>()?;
+
+            let mut msg = format!("Found {} registration token(s):\n", tokens.len());
+            for (token, info) in tokens {
+                msg += &format!(
+                    "{}\tused {} of {}\t{}\n",
+                    token,
+                    info.completed,
+                    info.uses_allowed
+                        .map_or_else(|| "unlimited".to_owned(), |uses| uses.to_string()),
+                    match info.expiry_time {
+                        Some(expiry) if expiry <= now => "expired".to_owned(),
+                        Some(expiry) => format!(
+                            "expires in {:?}",
+                            Duration::from_secs((expiry - now) / 1000)
+                        ),
+                        None => "never expires".to_owned(),
+                    }
+                );
+            }
+
+            RoomMessageEventContent::text_plain(msg)
+        }
+        AdminCommand::DeleteRegistrationToken { token } => {
+            if db.users.remove_registration_token(&token)? {
+                RoomMessageEventContent::text_plain("Registration token deleted.")
+            } else {
+                RoomMessageEventContent::text_plain("Registration token not found.")
+            }
+        }
+        AdminCommand::ListReports => {
+            let reports = db.rooms.reports().collect::<Result<Vec<_>>>()?;
+            RoomMessageEventContent::text_plain(format_reports(&reports))
+        }
+        AdminCommand::ClearReports { report_id } => match report_id {
+            Some(report_id) => {
+                if db.rooms.remove_report(report_id)? {
+                    RoomMessageEventContent::text_plain("Report deleted.")
+                } else {
+                    RoomMessageEventContent::text_plain("Report not found.")
+                }
+            }
+            None => RoomMessageEventContent::text_plain(format!(
+                "Deleted {} report(s).",
+                db.rooms.clear_reports()?
+            )),
+        },
+        AdminCommand::PurgeMediaFrom {
+            server,
+            before,
+            include_local,
+        } => {
+            if &*server == db.globals.server_name() && !include_local {
+                return Ok(RoomMessageEventContent::text_plain(
+                    "Media of this server was uploaded by local users. Add --include-local to \
+                     remove it anyway.",
+                ));
+            }
+
+            let before = match before {
+                Some(before) => match utils::parse_date(&before) {
+                    Some(before) => Some(before),
+                    None => {
+                        return Ok(RoomMessageEventContent::text_plain(
+                            "Invalid date. Use the format YYYY-MM-DD, e.g. `2022-03-31`.",
+                        ))
+                    }
+                },
+                None => None,
+            };
+
+            let (files, bytes) = {
+                let server = server.clone();
+                purge_media(db, move |media, media_folder| {
+                    media.purge_server_media(media_folder, &server, before)
+                })
+                .await?
+            };
+
+            RoomMessageEventContent::text_plain(format!(
+                "Removed {} file(s) from {}, freeing {} bytes.",
+                files, server, bytes
+            ))
+        }
+        AdminCommand::PurgeMediaBefore {
+            date,
+            include_local,
+        } => match utils::parse_date(&date) {
+            Some(before) => {
+                let local_server = db.globals.server_name().to_owned();
+                let (files, bytes) = purge_media(db, move |media, media_folder| {
+                    media.purge_media_before(media_folder, &local_server, before, include_local)
+                })
+                .await?;
+
+                RoomMessageEventContent::text_plain(format!(
+                    "Removed {} file(s) created before {}, freeing {} bytes.",
+                    files, date, bytes
+                ))
+            }
+            None => RoomMessageEventContent::text_plain(
+                "Invalid date. Use the format YYYY-MM-DD, e.g. `2022-03-31`.",
+            ),
+        },
+        AdminCommand::DeactivateUser {
+            user_id,
+            purge_media,
+        } => {
+            let admin_room = admin_room_id(db)?;
+            let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+            if user_id.server_name() != db.globals.server_name() || !db.users.exists(&user_id)? {
+                RoomMessageEventContent::text_plain(format!(
+                    "{} is not a local user of this server.",
+                    user_id
+                ))
+            } else if user_id == conduit_user {
+                RoomMessageEventContent::text_plain("The server user can't be deactivated.")
+            } else {
+                // Leaving the admin room needs the lock we are holding, so it happens here
+                leave_admin_room(db, &user_id, &admin_room, mutex_lock)?;
+                crate::client_server::leave_all_rooms(db, &user_id).await?;
+                db.users.deactivate_account(&user_id)?;
+                // A pending self-deactivation must not be restorable anymore
+                db.users.finish_deactivation(&user_id)?;
+
+                let mut message = format!("Deactivated {}.", user_id);
+                if purge_media {
+                    let (files, bytes) = {
+                        let user_id = user_id.clone();
+                        purge_media(db, move |media, media_folder| {
+                            media.purge_user_media(media_folder, &user_id)
+                        })
+                        .await?
+                    };
+                    message += &format!(" Removed {} file(s), freeing {} bytes.", files, bytes);
+                }
+
+                info!("Admin deactivated {}.", user_id);
+                RoomMessageEventContent::text_plain(message)
+            }
+        }
+        AdminCommand::DeactivateAll {
+            confirmation,
+            force,
+        } => {
+            let admin_room = admin_room_id(db)?;
+            let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+                .expect("@conduit:server_name is valid");
+
+            let mut users = Vec::new();
+            for user_id in db.users.iter().filter_map(|r| r.ok()) {
+                if user_id.server_name() == db.globals.server_name()
+                    && user_id != conduit_user
+                    && !db.users.is_deactivated(&user_id)?
+                    && (force || !db.users.is_admin(&user_id, &db.rooms, &db.globals)?)
+                {
+                    users.push(user_id);
+                }
+            }
+
+            let confirmed = take_confirmation(
+                &mut db.globals.deactivate_all_confirmation.lock().unwrap(),
+                confirmation.as_deref(),
+                force,
+            );
+
+            if !confirmed {
+                let token = utils::random_string(CONFIRMATION_TOKEN_LENGTH);
+                *db.globals.deactivate_all_confirmation.lock().unwrap() =
+                    Some((token.clone(), force));
+
+                return Ok(RoomMessageEventContent::text_plain(format!(
+                    "This will deactivate {} user(s){}. Run `deactivate-all {}{}` to confirm.",
+                    users.len(),
+                    if force { ", including admins" } else { "" },
+                    token,
+                    if force { " --force" } else { "" },
+                )));
+            }
+
+            let total = users.len();
+            let report_every = (total / 10).max(1);
+            let mut deactivated = 0;
+            let mut failed = 0;
+
+            for user_id in users {
+                // Leaving the admin room needs the lock we are holding, so it happens here
+                let result = match leave_admin_room(db, &user_id, &admin_room, mutex_lock) {
+                    Ok(()) => match crate::client_server::leave_all_rooms(db, &user_id).await {
+                        Ok(()) => db.users.deactivate_account(&user_id),
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                };
+
+                match result {
+                    Ok(()) => deactivated += 1,
+                    Err(e) => {
+                        warn!("Failed to deactivate {}: {}", user_id, e);
+                        failed += 1;
+                    }
+                }
+
+                if (deactivated + failed) % report_every == 0 && deactivated + failed < total {
+                    send_admin_room_message(
+                        db,
+                        RoomMessageEventContent::text_plain(format!(
+                            "Deactivated {}/{} users.",
+                            deactivated, total
+                        )),
+                        mutex_lock,
+                    )?;
+                }
+            }
+
+            info!("Admin deactivated {}/{} local users.", deactivated, total);
+            RoomMessageEventContent::text_plain(format!(
+                "Deactivated {}/{} users, {} failed.",
+                deactivated, total, failed
+            ))
+        }
+        AdminCommand::InactivityExempt { user_id, remove } => {
+            if user_id.server_name() != db.globals.server_name() || !db.users.exists(&user_id)? {
+                RoomMessageEventContent::text_plain(format!(
+                    "{} is not a local user of this server.",
+                    user_id
+                ))
+            } else {
+                db.users.set_inactivity_exempt(&user_id, !remove)?;
+                // Warnings sent so far don't apply anymore
+                db.users.forget_inactivity(&user_id)?;
+
+                RoomMessageEventContent::text_plain(if remove {
+                    format!("{} can be deactivated for inactivity again.", user_id)
+                } else {
+                    format!("{} won't be deactivated for inactivity.", user_id)
+                })
+            }
+        }
+        AdminCommand::LockUser { user_id } => {
+            let admin_room = admin_room_id(db)?;
+
+            if user_id.server_name() != db.globals.server_name() || !db.users.exists(&user_id)? {
+                RoomMessageEventContent::text_plain(format!(
+                    "{} is not a local user of this server.",
+                    user_id
+                ))
+            } else if db.rooms.is_joined(&user_id, &admin_room)? {
+                RoomMessageEventContent::text_plain(format!(
+                    "{} is an admin. Remove them from the admin room first.",
+                    user_id
+                ))
+            } else {
+                db.users.set_locked(&user_id, true)?;
+                info!("Admin locked {}.", user_id);
+                RoomMessageEventContent::text_plain(format!("Locked {}.", user_id))
+            }
+        }
+        AdminCommand::UnlockUser { user_id } => {
+            if db.users.is_locked(&user_id)? {
+                db.users.set_locked(&user_id, false)?;
+                info!("Admin unlocked {}.", user_id);
+                RoomMessageEventContent::text_plain(format!("Unlocked {}.", user_id))
+            } else {
+                RoomMessageEventContent::text_plain(format!("{} is not locked.", user_id))
+            }
+        }
+        AdminCommand::RestoreAccount { user_id } => {
+            if db.users.restore_account(&user_id)? {
+                RoomMessageEventContent::text_plain(format!("Restored the account of {}.", user_id))
+            } else {
+                RoomMessageEventContent::text_plain(
+                    "The account isn't pending deactivation or its grace period is over.",
+                )
+            }
+        }
+        AdminCommand::CompactDatabase => {
+            let path = db.globals.config.database_path.clone();
+            let size_before = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || utils::directory_size(Path::new(&path)))
+                    .await
+                    .map_err(std::io::Error::from)??
+            };
+
+            // Warn up front, the disk might fill up before compaction finishes
+            send_admin_room_message(
+                db,
+                RoomMessageEventContent::text_plain(format!(
+                    "Compacting the database. This may temporarily need up to {:.3} MB of \
+                     additional free disk space.",
+                    size_before as f64 / 1024.0 / 1024.0,
+                )),
+                mutex_lock,
+            )?;
+
+            let start = Instant::now();
+            // Compacting blocks for a long time, it must not stall the other requests
+            let engine = Arc::clone(&db._db);
+            let compacted = tokio::task::spawn_blocking(move || {
+                engine
+                    .compact()
+                    .map(|()| utils::directory_size(Path::new(&path)))
+            })
+            .await
+            .map_err(std::io::Error::from)?;
+
+            match compacted {
+                Ok(size_after) => {
+                    let size_after = size_after?;
+                    RoomMessageEventContent::text_plain(format!(
+                        "Compacted the database in {:?}.\n\
+                         Size before: {:.3} MB\n\
+                         Size after: {:.3} MB",
+                        start.elapsed(),
+                        size_before as f64 / 1024.0 / 1024.0,
+                        size_after as f64 / 1024.0 / 1024.0,
+                    ))
+                }
+                Err(e) => RoomMessageEventContent::text_plain(format!(
+                    "Failed to compact the database: {}",
+                    e
+                )),
+            }
+        }
         AdminCommand::ShowConfig => {
             // Construct and send the response
             RoomMessageEventContent::text_plain(format!("{}", db.globals.config))
         }
+        AdminCommand::BootstrapAdmin { user_id } => {
+            if user_id.server_name() != db.globals.server_name() || !db.users.exists(&user_id)? {
+                RoomMessageEventContent::text_plain(format!(
+                    "{} is not a local user of this server.",
+                    user_id
+                ))
+            } else {
+                let displayname = db
+                    .users
+                    .displayname(&user_id)?
+                    .unwrap_or_else(|| user_id.localpart().to_owned());
+                let admin_room = admin_room_id(db)?;
+                grant_admin(db, &user_id, displayname, &admin_room, mutex_lock)?;
+
+                RoomMessageEventContent::text_plain(format!("{} is now an admin.", user_id))
+            }
+        }
+        AdminCommand::Version => {
+            RoomMessageEventContent::text_plain(format_version_info(&db.globals.config))
+        }
         AdminCommand::ResetPassword { username } => {
             let user_id = match UserId::parse_with_server_name(
                 username.as_str().to_lowercase(),
@@ -469,7 +1161,19 @@ fn process_admin_command(
 
             let new_password = utils::random_string(20);
 
-            match db.users.set_password(&user_id, Some(new_password.as_str())) {
+            let reset = db
+                .users
+                .set_password(&user_id, Some(new_password.as_str()), &db.globals)
+                .and_then(|()| {
+                    let now = utils::millis_since_unix_epoch();
+                    for device_id in db.users.all_device_ids(&user_id) {
+                        db.users.soft_logout_device(&user_id, &device_id?, now)?;
+                    }
+
+                    Ok(())
+                });
+
+            match reset {
                 Ok(()) => RoomMessageEventContent::text_plain(format!(
                     "Successfully reset the password for user {}: {}",
                     user_id, new_password
@@ -485,6 +1189,19 @@ fn process_admin_command(
     Ok(reply_message_content)
 }
 
+/// Runs a media purge on the blocking thread pool, deleting many files takes a while.
+async fn purge_media<F>(db: &Database, purge: F) -> Result<(usize, u64)>
+where
+    F: FnOnce(&Media, &Path) -> Result<(usize, u64)> + Send + 'static,
+{
+    let media = Arc::clone(&db.media);
+    let media_folder = db.globals.get_media_folder();
+
+    tokio::task::spawn_blocking(move || purge(&media, &media_folder))
+        .await
+        .map_err(std::io::Error::from)?
+}
+
 // Utility to turn clap's `--help` text to HTML.
 fn usage_to_html(text: &str, server_name: &ServerName) -> String {
     // Replace `@conduit:servername:-subcmdname` with `@conduit:servername: subcmdname`
@@ -565,6 +1282,14 @@ fn usage_to_html(text: &str, server_name: &ServerName) -> String {
     text
 }
 
+/// Create the server user, @conduit:server_name, which sends admin and server notices.
+pub(crate) fn create_server_user(db: &Database) -> Result<()> {
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    db.users.create(&conduit_user, None, &db.globals)
+}
+
 /// Create the admin room.
 ///
 /// Users in this room are considered admins by conduit, and the room can be
@@ -588,7 +1313,7 @@ pub(crate) async fn create_admin_room(db: &Database) -> Result<()> {
     let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
         .expect("@conduit:server_name is valid");
 
-    db.users.create(&conduit_user, None)?;
+    create_server_user(db)?;
 
     let mut content = RoomCreateEventContent::new(conduit_user.clone());
     content.federate = true;
@@ -778,13 +1503,7 @@ pub(crate) async fn make_user_admin(
     user_id: &UserId,
     displayname: String,
 ) -> Result<()> {
-    let admin_room_alias: Box<RoomAliasId> = format!("#admins:{}", db.globals.server_name())
-        .try_into()
-        .expect("#admins:server_name is a valid alias name");
-    let room_id = db
-        .rooms
-        .id_from_alias(&admin_room_alias)?
-        .expect("Admin room must exist");
+    let room_id = admin_room_id(db)?;
 
     let mutex_state = Arc::clone(
         db.globals
@@ -796,40 +1515,72 @@ pub(crate) async fn make_user_admin(
     );
     let state_lock = mutex_state.lock().await;
 
-    // Use the server user to grant the new admin's power level
+    grant_admin(db, user_id, displayname, &room_id, &state_lock)
+}
+
+/// Consumes the pending confirmation of a destructive command if the given token and flag match.
+fn take_confirmation(
+    pending: &mut Option<(String, bool)>,
+    confirmation: Option<&str>,
+    force: bool,
+) -> bool {
+    let confirmed = matches!(
+        (&*pending, confirmation),
+        (Some((token, pending_force)), Some(confirmation))
+            if token == confirmation && *pending_force == force
+    );
+
+    if confirmed {
+        *pending = None;
+    }
+
+    confirmed
+}
+
+/// Sends a message to the admin room while a command is still running.
+fn send_admin_room_message(
+    db: &Database,
+    content: RoomMessageEventContent,
+    mutex_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
     let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
         .expect("@conduit:server_name is valid");
 
-    // Invite and join the real user
     db.rooms.build_and_append_pdu(
         PduBuilder {
-            event_type: RoomEventType::RoomMember,
-            content: to_raw_value(&RoomMemberEventContent {
-                membership: MembershipState::Invite,
-                displayname: None,
-                avatar_url: None,
-                is_direct: None,
-                third_party_invite: None,
-                blurhash: None,
-                reason: None,
-                join_authorized_via_users_server: None,
-            })
-            .expect("event is valid, we just created it"),
+            event_type: RoomEventType::RoomMessage,
+            content: to_raw_value(&content).expect("event is valid, we just created it"),
             unsigned: None,
-            state_key: Some(user_id.to_string()),
+            state_key: None,
             redacts: None,
         },
         &conduit_user,
-        &room_id,
-        &db,
-        &state_lock,
+        &admin_room_id(db)?,
+        db,
+        mutex_lock,
     )?;
+
+    Ok(())
+}
+
+/// Makes a user leave the admin room or reject the invite to it, using the lock of the running
+/// admin command.
+fn leave_admin_room(
+    db: &Database,
+    user_id: &UserId,
+    admin_room: &RoomId,
+    mutex_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    if !db.rooms.is_joined(user_id, admin_room)? && !db.rooms.is_invited(user_id, admin_room)? {
+        return Ok(());
+    }
+
     db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type: RoomEventType::RoomMember,
             content: to_raw_value(&RoomMemberEventContent {
-                membership: MembershipState::Join,
-                displayname: Some(displayname),
+                membership: MembershipState::Leave,
+                displayname: None,
                 avatar_url: None,
                 is_direct: None,
                 third_party_invite: None,
@@ -842,33 +1593,119 @@ pub(crate) async fn make_user_admin(
             state_key: Some(user_id.to_string()),
             redacts: None,
         },
-        &user_id,
-        &room_id,
-        &db,
-        &state_lock,
+        user_id,
+        admin_room,
+        db,
+        mutex_lock,
     )?;
 
-    // Set power level
-    let mut users = BTreeMap::new();
-    users.insert(conduit_user.to_owned(), 100.into());
-    users.insert(user_id.to_owned(), 100.into());
+    Ok(())
+}
+
+fn admin_room_id(db: &Database) -> Result<Box<RoomId>> {
+    find_admin_room(db)?.ok_or(Error::BadConfig("The admin room does not exist."))
+}
+
+/// Returns the ID of the admin room, if it was created.
+pub(crate) fn find_admin_room(db: &Database) -> Result<Option<Box<RoomId>>> {
+    let admin_room_alias: Box<RoomAliasId> = format!("#admins:{}", db.globals.server_name())
+        .try_into()
+        .expect("#admins:server_name is a valid alias name");
+
+    db.rooms.id_from_alias(&admin_room_alias)
+}
+
+/// Joins the user to the admin room and gives them power level 100.
+///
+/// Safe to call again after a partial failure: steps that already happened are skipped.
+fn grant_admin(
+    db: &Database,
+    user_id: &UserId,
+    displayname: String,
+    room_id: &RoomId,
+    state_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    // Use the server user to grant the new admin's power level
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    if !db.rooms.is_joined(user_id, room_id)? {
+        // Invite and join the real user
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent {
+                    membership: MembershipState::Invite,
+                    displayname: None,
+                    avatar_url: None,
+                    is_direct: None,
+                    third_party_invite: None,
+                    blurhash: None,
+                    reason: None,
+                    join_authorized_via_users_server: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            &conduit_user,
+            room_id,
+            db,
+            state_lock,
+        )?;
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomMember,
+                content: to_raw_value(&RoomMemberEventContent {
+                    membership: MembershipState::Join,
+                    displayname: Some(displayname),
+                    avatar_url: None,
+                    is_direct: None,
+                    third_party_invite: None,
+                    blurhash: None,
+                    reason: None,
+                    join_authorized_via_users_server: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            user_id,
+            room_id,
+            db,
+            state_lock,
+        )?;
+    }
+
+    // Set power level, keeping the other admins
+    let mut power_levels = db
+        .rooms
+        .room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+        .map(|pdu| {
+            serde_json::from_str::<RoomPowerLevelsEventContent>(pdu.content.get())
+                .map_err(|_| Error::bad_database("Invalid power levels event in admin room."))
+        })
+        .transpose()?
+        .unwrap_or_default();
+    power_levels
+        .users
+        .insert(conduit_user.to_owned(), 100.into());
+    power_levels.users.insert(user_id.to_owned(), 100.into());
 
     db.rooms.build_and_append_pdu(
         PduBuilder {
             event_type: RoomEventType::RoomPowerLevels,
-            content: to_raw_value(&RoomPowerLevelsEventContent {
-                users,
-                ..Default::default()
-            })
-            .expect("event is valid, we just created it"),
+            content: to_raw_value(&power_levels).expect("event is valid, we just created it"),
             unsigned: None,
             state_key: Some("".to_owned()),
             redacts: None,
         },
         &conduit_user,
-        &room_id,
-        &db,
-        &state_lock,
+        room_id,
+        db,
+        state_lock,
     )?;
 
     // Send welcome message
@@ -885,10 +1722,759 @@ pub(crate) async fn make_user_admin(
             redacts: None,
         },
         &conduit_user,
+        room_id,
+        db,
+        state_lock,
+    )?;
+
+    Ok(())
+}
+
+/// Cargo features that change server behaviour, in the order they are listed in `Cargo.toml`.
+const REPORTED_FEATURES: &[(&str, bool)] = &[
+    ("backend_sled", cfg!(feature = "backend_sled")),
+    ("backend_persy", cfg!(feature = "backend_persy")),
+    ("backend_sqlite", cfg!(feature = "backend_sqlite")),
+    ("backend_heed", cfg!(feature = "backend_heed")),
+    ("backend_rocksdb", cfg!(feature = "backend_rocksdb")),
+    ("jemalloc", cfg!(feature = "jemalloc")),
+];
+
+fn format_version_info(config: &Config) -> String {
+    let features = REPORTED_FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "Conduit {}\n\
+         Git commit: {}\n\
+         Features: {}\n\
+         Database backend: {}\n\
+         Registration: {}\n\
+         Federation: {}\n\
+         Presence: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        // Packagers can set this when building from a git checkout
+        option_env!("CONDUIT_GIT_HASH").unwrap_or("unknown"),
+        if features.is_empty() {
+            "none"
+        } else {
+            &features
+        },
+        config.database_backend,
+        enabled(config.allow_registration),
+        enabled(config.allow_federation),
+        enabled(config.allow_presence),
+    )
+}
+
+fn enabled(value: bool) -> &'static str {
+    if value {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+const USER_ROOMS_PER_PAGE: usize = 50;
+const ADMIN_ROOM_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn format_user_rooms(
+    user_id: &UserId,
+    rooms: &[(Box<RoomId>, MembershipState, Option<String>)],
+    page: usize,
+) -> String {
+    let pages = (rooms.len() + USER_ROOMS_PER_PAGE - 1) / USER_ROOMS_PER_PAGE;
+    let page = page.max(1);
+
+    let mut msg = format!(
+        "{} is in {} room(s), page {} of {}:\n",
+        user_id,
+        rooms.len(),
+        page,
+        pages.max(1)
+    );
+
+    for (room_id, membership, name) in rooms
+        .iter()
+        .skip((page - 1) * USER_ROOMS_PER_PAGE)
+        .take(USER_ROOMS_PER_PAGE)
+    {
+        msg += &format!(
+            "{}\t{}\t{}\n",
+            membership,
+            room_id,
+            name.as_deref().unwrap_or("(no name)")
+        );
+    }
+
+    msg
+}
+
+fn format_reports(reports: &[(u64, Report)]) -> String {
+    let mut msg = format!("Found {} report(s):\n", reports.len());
+
+    for (report_id, report) in reports {
+        msg += &format!(
+            "{}\t{} reported {} in {}\tscore {}\t{}\n",
+            report_id,
+            report.reporter,
+            report.event_id,
+            report.room_id,
+            report
+                .score
+                .map_or_else(|| "none".to_owned(), |score| score.to_string()),
+            report.reason.as_deref().unwrap_or("(no reason)")
+        );
+    }
+
+    msg
+}
+
+/// Redacts the server user's oldest messages in the admin room once they exceed the configured
+/// count or age.
+fn prune_admin_room(
+    db: &Database,
+    conduit_user: &UserId,
+    admin_room: &RoomId,
+    mutex_lock: &MutexGuard<'_, ()>,
+) -> Result<()> {
+    let max_count = db.globals.admin_room_max_notices();
+    let max_age = db.globals.admin_room_max_notice_age();
+    if max_count.is_none() && max_age.is_none() {
+        return Ok(());
+    }
+
+    // Newest first
+    let notices = db
+        .rooms
+        .pdus_until(conduit_user, admin_room, u64::MAX)?
+        .filter_map(|r| r.ok())
+        .map(|(_, pdu)| pdu)
+        .filter(|pdu| {
+            pdu.kind == RoomEventType::RoomMessage
+                && &*pdu.sender == conduit_user
+                && !pdu.is_redacted()
+        })
+        .map(|pdu| (pdu.event_id, pdu.origin_server_ts.into()))
+        .collect::<Vec<_>>();
+
+    for event_id in notices_to_prune(
+        notices,
+        max_count,
+        max_age,
+        utils::millis_since_unix_epoch(),
+    ) {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type: RoomEventType::RoomRedaction,
+                content: to_raw_value(&RoomRedactionEventContent {
+                    reason: Some("Admin room retention".to_owned()),
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: None,
+                redacts: Some(event_id),
+            },
+            conduit_user,
+            admin_room,
+            db,
+            mutex_lock,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Picks the notices that are over the limits. `notices` are ordered newest first and carry
+/// their `origin_server_ts`.
+fn notices_to_prune<T>(
+    notices: Vec<(T, u64)>,
+    max_count: Option<usize>,
+    max_age: Option<Duration>,
+    now: u64,
+) -> Vec<T> {
+    let oldest_allowed = max_age
+        .map(|max_age| now.saturating_sub(max_age.as_millis().try_into().unwrap_or(u64::MAX)));
+
+    notices
+        .into_iter()
+        .enumerate()
+        .filter(|(i, (_, ts))| {
+            max_count.map_or(false, |max_count| *i >= max_count)
+                || oldest_allowed.map_or(false, |oldest_allowed| *ts < oldest_allowed)
+        })
+        .map(|(_, (notice, _))| notice)
+        .collect()
+}
+
+/// Sends a notice from the server user to the given local user.
+///
+/// The notice is posted into a per-user "Server Notices" room which is created on first use and
+/// tagged with `m.server_notice` for the user.
+pub(crate) async fn send_server_notice(
+    db: &Database,
+    user_id: &UserId,
+    content: RoomMessageEventContent,
+) -> Result<()> {
+    let conduit_user = UserId::parse_with_server_name("conduit", db.globals.server_name())
+        .expect("@conduit:server_name is valid");
+
+    let mutex_notice = Arc::clone(
+        db.globals
+            .userid_mutex_servernotice
+            .write()
+            .unwrap()
+            .entry(user_id.to_owned())
+            .or_default(),
+    );
+    // Concurrent first notices must not create a room each
+    let notice_lock = mutex_notice.lock().await;
+
+    let room_id = match db.users.server_notice_room(user_id)? {
+        Some(room_id) => room_id,
+        None => create_server_notice_room(db, &conduit_user, user_id).await?,
+    };
+
+    drop(notice_lock);
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    db.rooms.build_and_append_pdu(
+        PduBuilder {
+            event_type: RoomEventType::RoomMessage,
+            content: to_raw_value(&content).expect("event is valid, we just created it"),
+            unsigned: None,
+            state_key: None,
+            redacts: None,
+        },
+        &conduit_user,
         &room_id,
-        &db,
+        db,
         &state_lock,
     )?;
 
     Ok(())
 }
+
+async fn create_server_notice_room(
+    db: &Database,
+    conduit_user: &UserId,
+    user_id: &UserId,
+) -> Result<Box<RoomId>> {
+    let room_id = RoomId::new(db.globals.server_name());
+
+    db.rooms.get_or_create_shortroomid(&room_id, &db.globals)?;
+
+    let mutex_state = Arc::clone(
+        db.globals
+            .roomid_mutex_state
+            .write()
+            .unwrap()
+            .entry(room_id.clone())
+            .or_default(),
+    );
+    let state_lock = mutex_state.lock().await;
+
+    let mut content = RoomCreateEventContent::new(conduit_user.to_owned());
+    content.federate = false;
+    content.predecessor = None;
+    content.room_version = db.globals.default_room_version();
+
+    // Only the server user may post, the recipient can read but not reply
+    let mut users = BTreeMap::new();
+    users.insert(conduit_user.to_owned(), 100.into());
+
+    let events = vec![
+        (
+            RoomEventType::RoomCreate,
+            to_raw_value(&content),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent {
+                membership: MembershipState::Join,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+                blurhash: None,
+                reason: None,
+                join_authorized_via_users_server: None,
+            }),
+            Some(conduit_user.to_string()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomPowerLevels,
+            to_raw_value(&RoomPowerLevelsEventContent {
+                users,
+                events_default: 100.into(),
+                ..Default::default()
+            }),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomJoinRules,
+            to_raw_value(&RoomJoinRulesEventContent::new(JoinRule::Invite)),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomHistoryVisibility,
+            to_raw_value(&RoomHistoryVisibilityEventContent::new(
+                HistoryVisibility::Shared,
+            )),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomGuestAccess,
+            to_raw_value(&RoomGuestAccessEventContent::new(GuestAccess::Forbidden)),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomName,
+            to_raw_value(&RoomNameEventContent::new(Some(
+                "Server Notices".try_into().expect("Room name is valid"),
+            ))),
+            Some("".to_owned()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent {
+                membership: MembershipState::Invite,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+                blurhash: None,
+                reason: None,
+                join_authorized_via_users_server: None,
+            }),
+            Some(user_id.to_string()),
+            conduit_user,
+        ),
+        (
+            RoomEventType::RoomMember,
+            to_raw_value(&RoomMemberEventContent {
+                membership: MembershipState::Join,
+                displayname: db.users.displayname(user_id)?,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+                blurhash: None,
+                reason: None,
+                join_authorized_via_users_server: None,
+            }),
+            Some(user_id.to_string()),
+            user_id,
+        ),
+    ];
+
+    for (event_type, content, state_key, sender) in events {
+        db.rooms.build_and_append_pdu(
+            PduBuilder {
+                event_type,
+                content: content.expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key,
+                redacts: None,
+            },
+            sender,
+            &room_id,
+            db,
+            &state_lock,
+        )?;
+    }
+
+    let mut tags_event = db
+        .account_data
+        .get(Some(&room_id), user_id, RoomAccountDataEventType::Tag)?
+        .unwrap_or_else(|| TagEvent {
+            content: TagEventContent {
+                tags: BTreeMap::new(),
+            },
+        });
+    tags_event
+        .content
+        .tags
+        .insert("m.server_notice".to_owned().into(), TagInfo::new());
+
+    db.account_data.update(
+        Some(&room_id),
+        user_id,
+        RoomAccountDataEventType::Tag,
+        &tags_event,
+        &db.globals,
+    )?;
+
+    db.users.set_server_notice_room(user_id, &room_id)?;
+
+    Ok(room_id)
+}
+
+/// Fills in the placeholders of the configured welcome message for a new user.
+pub(crate) fn render_welcome_message(template: &str, user_id: &UserId) -> String {
+    template
+        .replace("{localpart}", user_id.localpart())
+        .replace("{user_id}", user_id.as_str())
+        .replace("{server_name}", user_id.server_name().as_str())
+}
+
+/// Describes the effective server ACL of a room for the acl-status command.
+fn format_server_acl(room_id: &RoomId, acl: Option<&RoomServerAclEventContent>) -> String {
+    match acl {
+        Some(acl) => format!(
+            "Server ACL of {}:\nallow: {}\ndeny: {}\nallow_ip_literals: {}",
+            room_id,
+            acl.allow.join(", "),
+            acl.deny.join(", "),
+            acl.allow_ip_literals
+        ),
+        None => format!("{} has no server ACL, all servers are allowed.", room_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs an admin command and returns the body of the reply.
+    #[cfg(feature = "sqlite")]
+    async fn run_command(db: &Database, command: &str) -> String {
+        let mutex = tokio::sync::Mutex::new(());
+        let reply = process_admin_message(
+            db,
+            format!("@conduit:example.org: {}", command),
+            &mutex.lock().await,
+        )
+        .await;
+        serde_json::to_value(&reply).unwrap()["body"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn user_rooms_lists_membership_states() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        let rooms = vec![
+            (
+                RoomId::parse("!joined:example.org").unwrap(),
+                MembershipState::Join,
+                Some("Lounge".to_owned()),
+            ),
+            (
+                RoomId::parse("!invited:example.org").unwrap(),
+                MembershipState::Invite,
+                None,
+            ),
+        ];
+
+        let msg = format_user_rooms(&user_id, &rooms, 1);
+
+        assert!(msg.contains("join\t!joined:example.org\tLounge"));
+        assert!(msg.contains("invite\t!invited:example.org\t(no name)"));
+    }
+
+    #[test]
+    fn user_rooms_are_paginated() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+        let rooms = (0..USER_ROOMS_PER_PAGE + 1)
+            .map(|i| {
+                (
+                    RoomId::parse(format!("!room{}:example.org", i)).unwrap(),
+                    MembershipState::Join,
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let second_page = format_user_rooms(&user_id, &rooms, 2);
+
+        assert!(second_page.contains("page 2 of 2"));
+        assert!(second_page.contains(&format!("!room{}:example.org", USER_ROOMS_PER_PAGE)));
+        assert!(!second_page.contains("!room0:example.org"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn user_rooms_lists_joined_invited_and_left_rooms() {
+        use crate::database::{test_room, test_send};
+        use serde_json::json;
+
+        let db = crate::database::test_database("user-rooms").await;
+        let db = db.read().await;
+        let alice = UserId::parse("@alice:example.org").unwrap();
+        let bob = UserId::parse("@bob:example.org").unwrap();
+        let joined = RoomId::parse("!joined:example.org").unwrap();
+        let invited = RoomId::parse("!invited:example.org").unwrap();
+        let left = RoomId::parse("!left:example.org").unwrap();
+
+        test_room(&db, &joined, &alice).await;
+        test_send(
+            &db,
+            &joined,
+            &alice,
+            "m.room.name",
+            Some(""),
+            json!({ "name": "Lounge" }),
+        )
+        .await
+        .unwrap();
+
+        test_room(&db, &invited, &bob).await;
+        test_send(
+            &db,
+            &invited,
+            &bob,
+            "m.room.member",
+            Some(alice.as_str()),
+            json!({ "membership": "invite" }),
+        )
+        .await
+        .unwrap();
+
+        test_room(&db, &left, &bob).await;
+        test_send(
+            &db,
+            &left,
+            &bob,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "public" }),
+        )
+        .await
+        .unwrap();
+        for membership in ["join", "leave"] {
+            test_send(
+                &db,
+                &left,
+                &alice,
+                "m.room.member",
+                Some(alice.as_str()),
+                json!({ "membership": membership }),
+            )
+            .await
+            .unwrap();
+        }
+
+        let msg = run_command(&db, "user-rooms @alice:example.org").await;
+        assert!(msg.contains("join\t!joined:example.org\tLounge"));
+        assert!(msg.contains("invite\t!invited:example.org\t(no name)"));
+        assert!(msg.contains("leave\t!left:example.org\t(no name)"));
+    }
+
+    #[test]
+    fn reports_list_reporter_event_and_score() {
+        let report = Report {
+            reporter: UserId::parse("@alice:example.org").unwrap(),
+            room_id: RoomId::parse("!room:example.org").unwrap(),
+            event_id: EventId::parse("$event:example.org").unwrap(),
+            score: Some(ruma::int!(-100)),
+            reason: None,
+            reported_at: 0,
+        };
+
+        let msg = format_reports(&[(7, report)]);
+
+        assert!(msg.starts_with("Found 1 report(s):\n"));
+        assert!(msg.contains(
+            "7\t@alice:example.org reported $event:example.org in !room:example.org\tscore -100\t(no reason)"
+        ));
+    }
+
+    #[test]
+    fn oldest_admin_notices_are_pruned() {
+        // Newest first
+        let notices = vec![("e", 500), ("d", 400), ("c", 300), ("b", 200), ("a", 100)];
+
+        assert_eq!(
+            notices_to_prune(notices.clone(), Some(3), None, 500),
+            vec!["b", "a"]
+        );
+        assert_eq!(
+            notices_to_prune(notices.clone(), None, Some(Duration::from_millis(250)), 500),
+            vec!["b", "a"]
+        );
+        assert_eq!(
+            notices_to_prune(
+                notices.clone(),
+                Some(4),
+                Some(Duration::from_millis(150)),
+                500
+            ),
+            vec!["c", "b", "a"]
+        );
+        assert!(notices_to_prune(notices, None, None, 500).is_empty());
+    }
+
+    #[test]
+    fn welcome_message_placeholders() {
+        let user_id = UserId::parse("@alice:example.org").unwrap();
+
+        assert_eq!(
+            render_welcome_message(
+                "Hi {localpart}, you are {user_id} on {server_name}. {unknown}",
+                &user_id
+            ),
+            "Hi alice, you are @alice:example.org on example.org. {unknown}"
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn concurrent_server_notices_share_one_room() {
+        let db = crate::database::test_database("server-notice").await;
+        let db = db.read().await;
+
+        let alice = UserId::parse("@alice:example.org").unwrap();
+        db.users.create(&alice, None, &db.globals).unwrap();
+
+        let (first, second) = tokio::join!(
+            send_server_notice(&db, &alice, RoomMessageEventContent::notice_plain("first")),
+            send_server_notice(&db, &alice, RoomMessageEventContent::notice_plain("second")),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        let rooms = db
+            .rooms
+            .rooms_joined(&alice)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(
+            db.users.server_notice_room(&alice).unwrap(),
+            Some(rooms[0].clone())
+        );
+
+        let tags = db
+            .account_data
+            .get::<TagEvent>(Some(&rooms[0]), &alice, RoomAccountDataEventType::Tag)
+            .unwrap()
+            .unwrap();
+        assert!(tags
+            .content
+            .tags
+            .keys()
+            .any(|tag| tag.to_string() == "m.server_notice"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn stream_positions_are_reported() {
+        let db = crate::database::test_database("stream-positions").await;
+        let db = db.read().await;
+
+        let alice = UserId::parse("@alice:example.org").unwrap();
+        db.users.create(&alice, None, &db.globals).unwrap();
+
+        let msg = run_command(&db, "stream-positions @alice:example.org").await;
+        assert!(msg.contains(&format!(
+            "Global counter: {}",
+            db.globals.current_count().unwrap()
+        )));
+        assert!(msg.contains("Account data: none"));
+        assert!(msg.contains("Pending to-device messages: 0"));
+
+        db.account_data
+            .update(
+                None,
+                &alice,
+                "m.test".into(),
+                &serde_json::json!({ "type": "m.test", "content": {} }),
+                &db.globals,
+            )
+            .unwrap();
+        let count = db.globals.current_count().unwrap();
+
+        let msg = run_command(&db, "stream-positions @alice:example.org").await;
+        assert!(msg.contains(&format!("Global counter: {}", count)));
+        assert!(msg.contains(&format!("Account data: {}", count)));
+
+        // Without a user only the global counter is printed
+        let msg = run_command(&db, "stream-positions").await;
+        assert!(!msg.contains("Account data"));
+    }
+
+    #[test]
+    fn version_info_includes_version_and_backend() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+            "database_backend": "sqlite",
+        }))
+        .unwrap();
+
+        let info = format_version_info(&config);
+        assert!(info.contains(env!("CARGO_PKG_VERSION")));
+        assert!(info.contains("Database backend: sqlite"));
+        assert!(info.contains("Registration: disabled"));
+        assert!(info.contains("Presence: enabled"));
+    }
+
+    #[test]
+    fn server_acl_status_lists_rules() {
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let acl = RoomServerAclEventContent::new(
+            false,
+            vec!["*".to_owned()],
+            vec!["evil.example.org".to_owned()],
+        );
+
+        let msg = format_server_acl(&room_id, Some(&acl));
+        assert!(msg.contains("allow: *"));
+        assert!(msg.contains("deny: evil.example.org"));
+        assert!(format_server_acl(&room_id, None).contains("all servers are allowed"));
+    }
+
+    #[test]
+    fn disabled_admin_room_drops_messages() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let admin = Admin {
+            sender,
+            enabled: false,
+        };
+
+        admin.send_message(RoomMessageEventContent::notice_plain(
+            "New user @alice:example.org registered on this server.",
+        ));
+        admin.process_message("@conduit:example.org: version".to_owned());
+
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn deactivate_all_needs_matching_confirmation() {
+        let mut pending = Some(("token".to_owned(), false));
+
+        assert!(!take_confirmation(&mut pending, None, false));
+        assert!(!take_confirmation(&mut pending, Some("other"), false));
+        // The token was issued without --force
+        assert!(!take_confirmation(&mut pending, Some("token"), true));
+
+        assert!(take_confirmation(&mut pending, Some("token"), false));
+        assert_eq!(pending, None);
+
+        // Tokens can only be used once
+        assert!(!take_confirmation(&mut pending, Some("token"), false));
+    }
+}
@@ -1,4 +1,6 @@
 use crate::{utils, Error, Result};
+use regex::Regex;
+use ruma::UserId;
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
@@ -85,4 +87,110 @@ impl Appservice {
             })
             .collect()
     }
+
+    /// Returns true if an appservice other than `appservice_id` claimed the user id in an exclusive
+    /// namespace.
+    pub fn user_reserved_by_other(
+        &self,
+        user_id: &UserId,
+        appservice_id: Option<&str>,
+    ) -> Result<bool> {
+        Ok(self.all()?.iter().any(|(id, registration)| {
+            Some(id.as_str()) != appservice_id && claims_user_exclusively(registration, user_id)
+        }))
+    }
+
+    /// Returns true if the user id is in one of the user namespaces of the appservice.
+    pub fn user_in_namespace(&self, appservice_id: &str, user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .get_registration(appservice_id)?
+            .map_or(false, |registration| {
+                matching_user_namespaces(&registration, user_id)
+                    .next()
+                    .is_some()
+            }))
+    }
+
+    /// Returns true if a local user id belongs to an appservice, either as its sender or in one of
+    /// its user namespaces.
+    pub fn is_appservice_user(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.all()?.iter().any(|(_, registration)| {
+            registration
+                .get("sender_localpart")
+                .and_then(|localpart| localpart.as_str())
+                == Some(user_id.localpart())
+                || matching_user_namespaces(registration, user_id)
+                    .next()
+                    .is_some()
+        }))
+    }
+}
+
+fn claims_user_exclusively(registration: &serde_yaml::Value, user_id: &UserId) -> bool {
+    matching_user_namespaces(registration, user_id).any(|namespace| {
+        namespace
+            .get("exclusive")
+            .and_then(|exclusive| exclusive.as_bool())
+            .unwrap_or(false)
+    })
+}
+
+fn matching_user_namespaces<'a>(
+    registration: &'a serde_yaml::Value,
+    user_id: &'a UserId,
+) -> impl Iterator<Item = &'a serde_yaml::Value> {
+    registration
+        .get("namespaces")
+        .and_then(|ns| ns.get("users"))
+        .and_then(|users| users.as_sequence())
+        .into_iter()
+        .flatten()
+        .filter(move |namespace| {
+            namespace
+                .get("regex")
+                .and_then(|regex| regex.as_str())
+                .and_then(|regex| Regex::new(regex).ok())
+                .map_or(false, |regex| regex.is_match(user_id.as_str()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{claims_user_exclusively, matching_user_namespaces};
+    use ruma::{user_id, UserId};
+
+    #[test]
+    fn only_exclusive_namespaces_claim_users() {
+        let registration: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+id: bridge
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@bridge_.*:example.org"
+    - exclusive: false
+      regex: "@shared_.*:example.org"
+"#,
+        )
+        .unwrap();
+
+        assert!(claims_user_exclusively(
+            &registration,
+            user_id!("@bridge_alice:example.org")
+        ));
+        assert!(!claims_user_exclusively(
+            &registration,
+            user_id!("@shared_alice:example.org")
+        ));
+        assert!(!claims_user_exclusively(
+            &registration,
+            user_id!("@alice:example.org")
+        ));
+
+        let historical = UserId::parse("@bridge_alice!:example.org").unwrap();
+        assert_eq!(
+            matching_user_namespaces(&registration, &historical).count(),
+            1
+        );
+    }
 }
@@ -1,14 +1,30 @@
-use crate::{database::Config, server_server::FedDest, utils, Error, Result};
+use crate::{
+    config::{PasswordHashing, PasswordPolicy, SmtpConfig},
+    database::Config,
+    server_server::FedDest,
+    utils, Error, Result,
+};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use lru_cache::LruCache;
+use rand::Rng;
+use regex::RegexSet;
 use ruma::{
     api::{
-        client::sync::sync_events,
-        federation::discovery::{ServerSigningKeys, VerifyKey},
+        client::{error::ErrorKind, sync::sync_events},
+        federation::{
+            discovery::{ServerSigningKeys, VerifyKey},
+            transactions::edu::PresenceUpdate,
+        },
     },
+    presence::PresenceState,
     DeviceId, EventId, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName,
     ServerSigningKeyId, UserId,
 };
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs,
     future::Future,
     net::{IpAddr, SocketAddr},
@@ -17,10 +33,10 @@ use std::{
     time::{Duration, Instant},
 };
 use tokio::sync::{broadcast, watch::Receiver, Mutex as TokioMutex, Semaphore};
-use tracing::error;
+use tracing::{error, warn};
 use trust_dns_resolver::TokioAsyncResolver;
 
-use super::abstraction::Tree;
+use super::{abstraction::Tree, pusher::PushActionOverrides};
 
 pub const COUNTER: &[u8] = b"c";
 
@@ -42,6 +58,7 @@ pub struct Globals {
     jwt_decoding_key: Option<jsonwebtoken::DecodingKey<'static>>,
     federation_client: reqwest::Client,
     default_client: reqwest::Client,
+    pub url_preview_cache: UrlPreviewCache,
     pub stable_room_versions: Vec<RoomVersionId>,
     pub unstable_room_versions: Vec<RoomVersionId>,
     pub(super) server_signingkeys: Arc<dyn Tree>,
@@ -49,10 +66,26 @@ pub struct Globals {
     pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
     pub servername_ratelimiter: Arc<RwLock<HashMap<Box<ServerName>, Arc<Semaphore>>>>,
     pub sync_receivers: RwLock<HashMap<(Box<UserId>, Box<DeviceId>), SyncHandle>>,
+    pub active_syncs: ActiveSyncs,
+    pub typing_throttle: TypingThrottle,
+    pub presence_batcher: PresenceBatcher,
+    pub remote_presence_limiter: RemotePresenceLimiter,
+    pub registration_nonces: RegistrationNonces,
+    pub hierarchy_pages: HierarchyPages,
+    pub metrics: Arc<Metrics>,
+    /// Token and `--force` flag of the last unconfirmed `deactivate-all` admin command
+    pub deactivate_all_confirmation: Mutex<Option<(String, bool)>>,
+    forbidden_username_patterns: RegexSet,
     pub roomid_mutex_insert: RwLock<HashMap<Box<RoomId>, Arc<Mutex<()>>>>,
     pub roomid_mutex_state: RwLock<HashMap<Box<RoomId>, Arc<TokioMutex<()>>>>,
     pub roomid_mutex_federation: RwLock<HashMap<Box<RoomId>, Arc<TokioMutex<()>>>>, // this lock will be held longer
+    pub userid_mutex_servernotice: RwLock<HashMap<Box<UserId>, Arc<TokioMutex<()>>>>,
     pub rotate: RotationHandler,
+    pub push_action_overrides: PushActionOverrides,
+    pub rate_limiter: Option<RateLimiter>,
+    pub account_data_rate_limiter: Option<RateLimiter>,
+    pub auth_rate_limiter: Option<RateLimiter>,
+    mailer: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
 }
 
 /// Handles "rotation" of long-polling requests. "Rotation" in this context is similar to "rotation" of log files and the like.
@@ -85,6 +118,748 @@ impl Default for RotationHandler {
     }
 }
 
+type RateLimitBucket = (f64, Instant); // Tokens left, time of last request
+
+/// Token bucket rate limiter for requests of local users and remote servers.
+///
+/// Every request takes a token from the bucket of its sender, buckets refill at `per_second`
+/// tokens per second up to `burst`. Rejections tell the sender to retry after a random jitter
+/// on top of the time until the next token.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    jitter: Duration,
+    exempt_users: HashSet<Box<UserId>>,
+    exempt_servers: HashSet<Box<ServerName>>,
+    buckets: Mutex<HashMap<String, RateLimitBucket>>,
+}
+
+impl RateLimiter {
+    /// Returns `None` if rate limiting is disabled.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Self::new(
+            config.rate_limit_per_second,
+            config.rate_limit_burst,
+            config,
+        )
+    }
+
+    /// The limiter for logins, registrations and password changes of each client IP address.
+    /// Returns `None` if it is disabled.
+    pub fn for_authentication(config: &Config) -> Option<Self> {
+        Self::new(
+            config.auth_rate_limit_per_second,
+            config.auth_rate_limit_burst,
+            config,
+        )
+    }
+
+    /// The limiter for account data writes. Returns `None` if it is disabled.
+    pub fn for_account_data(config: &Config) -> Option<Self> {
+        Self::new(
+            config.account_data_rate_limit_per_second,
+            config.account_data_rate_limit_burst,
+            config,
+        )
+    }
+
+    fn new(per_second: Option<f64>, burst: u32, config: &Config) -> Option<Self> {
+        let per_second = per_second.filter(|rate| *rate > 0.0)?;
+
+        Some(Self {
+            per_second,
+            burst: f64::from(burst.max(1)),
+            jitter: Duration::from_millis(config.rate_limit_jitter_ms),
+            exempt_users: config.rate_limit_exempt_users.iter().cloned().collect(),
+            exempt_servers: config.rate_limit_exempt_servers.iter().cloned().collect(),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the sender bypasses all rate limits.
+    pub fn is_exempt(&self, user_id: Option<&UserId>, server: Option<&ServerName>) -> bool {
+        user_id.map_or(false, |user_id| self.exempt_users.contains(user_id))
+            || server.map_or(false, |server| self.exempt_servers.contains(server))
+    }
+
+    /// Takes a token for the sender of a request. Unauthenticated requests are not limited here.
+    pub fn check(
+        &self,
+        user_id: Option<&UserId>,
+        server: Option<&ServerName>,
+        now: Instant,
+    ) -> Result<()> {
+        if self.is_exempt(user_id, server) {
+            return Ok(());
+        }
+
+        let key = match (user_id, server) {
+            (Some(user_id), _) => user_id.as_str(),
+            (None, Some(server)) => server.as_str(),
+            (None, None) => return Ok(()),
+        };
+
+        self.take(key, now)
+    }
+
+    /// Takes a token for a client IP address. Exempt users and servers don't apply here.
+    pub fn check_ip(&self, ip: IpAddr, now: Instant) -> Result<()> {
+        self.take(&ip.to_string(), now)
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Result<()> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // Buckets that refilled completely are the same as new ones
+        if buckets.len() > 10_000 {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, (tokens, last)| {
+                *tokens + now.saturating_duration_since(*last).as_secs_f64() * per_second < burst
+            });
+        }
+
+        let (tokens, last) = buckets.entry(key.to_owned()).or_insert((self.burst, now));
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * self.per_second)
+            .min(self.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            let next_token = Duration::from_secs_f64((1.0 - *tokens) / self.per_second);
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+
+            Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: Some(next_token + jitter),
+                },
+                "Too many requests, try again later.",
+            ))
+        }
+    }
+}
+
+/// Counts the long-polling syncs every local user has running.
+pub struct ActiveSyncs {
+    limit: usize,
+    counts: Arc<Mutex<HashMap<Box<UserId>, usize>>>,
+}
+
+/// Marks a sync as active until it is dropped. It can be moved into the task computing the sync,
+/// which keeps running when the client goes away.
+pub struct ActiveSync {
+    counts: Arc<Mutex<HashMap<Box<UserId>, usize>>>,
+    user_id: Box<UserId>,
+}
+
+impl ActiveSyncs {
+    /// A `limit` of 0 allows any number of syncs.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a new sync, fails if the user already has `limit` syncs running.
+    pub fn start(&self, user_id: &UserId) -> Result<ActiveSync> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(user_id.to_owned()).or_default();
+
+        if self.limit != 0 && *count >= self.limit {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "Too many concurrent sync requests.",
+            ));
+        }
+
+        *count += 1;
+
+        Ok(ActiveSync {
+            counts: Arc::clone(&self.counts),
+            user_id: user_id.to_owned(),
+        })
+    }
+
+    /// Number of open syncs of all users.
+    pub fn total(&self) -> usize {
+        self.counts.lock().unwrap().values().sum()
+    }
+}
+
+impl Drop for ActiveSync {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+/// Upper bounds in seconds of the buckets of the PDU build latency histogram.
+const PDU_BUILD_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative, like Prometheus expects them
+    buckets: [u64; PDU_BUILD_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters and histograms served at `/metrics`. Gauges are read from the database when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, String), u64>>, // (Method, Route) -> Count
+    pdu_build: Mutex<Histogram>,
+}
+
+/// Values of the gauges at the time of a scrape.
+pub struct MetricsGauges {
+    pub active_syncs: usize,
+    pub federation_queue: usize,
+    pub local_users: usize,
+}
+
+impl Metrics {
+    /// Counts a request. `route` should be the matched route, not the path, to keep the number of
+    /// series small.
+    pub fn record_request(&self, method: &str, route: &str) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_owned(), route.to_owned()))
+            .or_default() += 1;
+    }
+
+    pub fn observe_pdu_build(&self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let mut histogram = self.pdu_build.lock().unwrap();
+
+        for (bucket, &bound) in histogram.buckets.iter_mut().zip(&PDU_BUILD_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self, gauges: &MetricsGauges) -> String {
+        let mut out = String::new();
+
+        out += "# HELP conduit_http_requests_total Requests per route.\n";
+        out += "# TYPE conduit_http_requests_total counter\n";
+        for ((method, route), count) in self.requests.lock().unwrap().iter() {
+            out += &format!(
+                "conduit_http_requests_total{{method=\"{}\",route=\"{}\"}} {}\n",
+                method, route, count
+            );
+        }
+
+        for (name, help, value) in [
+            (
+                "conduit_active_syncs",
+                "Open /sync requests.",
+                gauges.active_syncs,
+            ),
+            (
+                "conduit_federation_send_queue",
+                "Events and EDUs waiting to be sent to other servers.",
+                gauges.federation_queue,
+            ),
+            (
+                "conduit_local_users",
+                "Local accounts that are not deactivated.",
+                gauges.local_users,
+            ),
+        ] {
+            out += &format!(
+                "# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n",
+                name, help, value
+            );
+        }
+
+        let histogram = self.pdu_build.lock().unwrap();
+        out += "# HELP conduit_pdu_build_seconds Time to build and append a local PDU.\n";
+        out += "# TYPE conduit_pdu_build_seconds histogram\n";
+        for (count, bound) in histogram.buckets.iter().zip(&PDU_BUILD_BUCKETS) {
+            out += &format!(
+                "conduit_pdu_build_seconds_bucket{{le=\"{}\"}} {}\n",
+                bound, count
+            );
+        }
+        out += &format!(
+            "conduit_pdu_build_seconds_bucket{{le=\"+Inf\"}} {}\n",
+            histogram.count
+        );
+        out += &format!("conduit_pdu_build_seconds_sum {}\n", histogram.sum);
+        out += &format!("conduit_pdu_build_seconds_count {}\n", histogram.count);
+
+        out
+    }
+}
+
+/// Typing updates of a user are sent to other servers at most once per this window.
+pub const TYPING_FEDERATION_WINDOW: Duration = Duration::from_secs(3);
+
+/// What to do with a typing update of a local user regarding other servers.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TypingFanout {
+    /// Send the update now.
+    Send,
+    /// Nothing to send, other servers already know this state or a flush is scheduled.
+    Skip,
+    /// Call [`TypingThrottle::flush`] after this long to send the latest state.
+    Defer(Duration),
+}
+
+struct TypingFanoutState {
+    sent: bool,
+    sent_at: Instant,
+    pending: Option<bool>,
+    flush_scheduled: bool,
+}
+
+/// Coalesces typing updates of local users before they are sent over federation.
+pub struct TypingThrottle {
+    window: Duration,
+    states: Mutex<HashMap<(Box<RoomId>, Box<UserId>), TypingFanoutState>>,
+}
+
+impl TypingThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a typing update and decides if it should be sent to other servers.
+    pub fn update(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        typing: bool,
+        now: Instant,
+    ) -> TypingFanout {
+        let mut states = self.states.lock().unwrap();
+
+        // Users who stopped typing a while ago are the same as unknown ones
+        if states.len() > 10_000 {
+            let window = self.window;
+            states.retain(|_, state| {
+                state.sent
+                    || state.flush_scheduled
+                    || now.saturating_duration_since(state.sent_at) < window
+            });
+        }
+
+        let state = match states.get_mut(&(room_id.to_owned(), user_id.to_owned())) {
+            Some(state) => state,
+            None => {
+                if typing {
+                    states.insert(
+                        (room_id.to_owned(), user_id.to_owned()),
+                        TypingFanoutState {
+                            sent: true,
+                            sent_at: now,
+                            pending: None,
+                            flush_scheduled: false,
+                        },
+                    );
+                    return TypingFanout::Send;
+                }
+                return TypingFanout::Skip;
+            }
+        };
+
+        let elapsed = now.saturating_duration_since(state.sent_at);
+        if elapsed >= self.window {
+            // Repeated starts still refresh the typing timeout on other servers
+            if typing || state.sent {
+                state.sent = typing;
+                state.sent_at = now;
+                state.pending = None;
+                return TypingFanout::Send;
+            }
+            return TypingFanout::Skip;
+        }
+
+        if typing == state.sent {
+            state.pending = None;
+            TypingFanout::Skip
+        } else {
+            state.pending = Some(typing);
+            if state.flush_scheduled {
+                TypingFanout::Skip
+            } else {
+                state.flush_scheduled = true;
+                TypingFanout::Defer(self.window - elapsed)
+            }
+        }
+    }
+
+    /// Returns the state to send if it changed since the last update that was sent.
+    pub fn flush(&self, room_id: &RoomId, user_id: &UserId, now: Instant) -> Option<bool> {
+        let mut states = self.states.lock().unwrap();
+        let state = states.get_mut(&(room_id.to_owned(), user_id.to_owned()))?;
+        state.flush_scheduled = false;
+
+        match state.pending.take() {
+            Some(typing) if typing != state.sent => {
+                state.sent = typing;
+                state.sent_at = now;
+                Some(typing)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PresenceBatchState {
+    last_sent: HashMap<Box<UserId>, Instant>,
+    pending: HashMap<Box<UserId>, PresenceUpdate>,
+    flush_at: Option<Instant>,
+    generation: u64,
+}
+
+/// What [`PresenceBatcher::queue`] did with an update.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PresenceFlush {
+    /// A new flush needs to be scheduled after the delay. Any older flush of the batcher stops
+    /// after its next call to [`PresenceBatcher::take_due`].
+    Schedule { delay: Duration, generation: u64 },
+    /// The update is sent with the flush that is already scheduled after the delay.
+    Pending(Duration),
+}
+
+/// Collects presence updates of local users, so they can be sent to other servers in batches.
+///
+/// A user's presence is sent at most once per interval, only the latest state is kept in between.
+/// Going offline is sent with the next batch regardless.
+pub struct PresenceBatcher {
+    interval: Duration,
+    state: Mutex<PresenceBatchState>,
+}
+
+impl PresenceBatcher {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            state: Mutex::new(PresenceBatchState::default()),
+        }
+    }
+
+    /// Queues a presence update. Returns when [`PresenceBatcher::take_due`] should be called,
+    /// updates which are due earlier than the pending flush get a new one.
+    pub fn queue(&self, update: PresenceUpdate, now: Instant) -> PresenceFlush {
+        let mut state = self.state.lock().unwrap();
+
+        let due = now + self.delay(&state, &update, now);
+        state.pending.insert(update.user_id.clone(), update);
+
+        match state.flush_at {
+            Some(flush_at) if flush_at <= due => {
+                PresenceFlush::Pending(flush_at.saturating_duration_since(now))
+            }
+            _ => {
+                state.flush_at = Some(due);
+                state.generation += 1;
+                PresenceFlush::Schedule {
+                    delay: due.saturating_duration_since(now),
+                    generation: state.generation,
+                }
+            }
+        }
+    }
+
+    /// Returns the updates that can be sent now and when to call this again, if updates are left
+    /// and no newer flush was scheduled since `generation`.
+    pub fn take_due(
+        &self,
+        now: Instant,
+        generation: u64,
+    ) -> (Vec<PresenceUpdate>, Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+
+        let due = state
+            .pending
+            .iter()
+            .filter(|(_, update)| self.delay(&state, update, now).is_zero())
+            .map(|(user_id, _)| user_id.clone())
+            .collect::<Vec<_>>();
+
+        let mut updates = Vec::new();
+        for user_id in due {
+            let update = state.pending.remove(&user_id).expect("user is pending");
+            state.last_sent.insert(user_id, now);
+            updates.push(update);
+        }
+
+        // Users whose interval is over are the same as unknown ones
+        let interval = self.interval;
+        state
+            .last_sent
+            .retain(|_, sent_at| now.saturating_duration_since(*sent_at) < interval);
+
+        if generation != state.generation {
+            // A newer flush takes care of the remaining updates
+            return (updates, None);
+        }
+
+        let next = state
+            .pending
+            .values()
+            .map(|update| self.delay(&state, update, now))
+            .min();
+        state.flush_at = next.map(|delay| now + delay);
+
+        (updates, next)
+    }
+
+    fn delay(&self, state: &PresenceBatchState, update: &PresenceUpdate, now: Instant) -> Duration {
+        if update.presence == PresenceState::Offline {
+            return Duration::ZERO;
+        }
+
+        state
+            .last_sent
+            .get(&update.user_id)
+            .map_or(Duration::ZERO, |sent_at| {
+                self.interval
+                    .saturating_sub(now.saturating_duration_since(*sent_at))
+            })
+    }
+}
+
+/// How many URL previews are kept in memory.
+pub const URL_PREVIEW_CACHE_SIZE: usize = 1000;
+
+/// How long a URL preview is served from the cache before the page is fetched again.
+pub const URL_PREVIEW_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Recently generated URL previews, so popular links are only fetched once in a while.
+pub struct UrlPreviewCache {
+    ttl: Duration,
+    previews: Mutex<LruCache<String, (Instant, Arc<serde_json::Value>)>>,
+}
+
+impl UrlPreviewCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            previews: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns the preview of the URL if it is not older than the TTL.
+    pub fn get(&self, url: &str, now: Instant) -> Option<Arc<serde_json::Value>> {
+        let mut previews = self.previews.lock().unwrap();
+
+        match previews.get_mut(url) {
+            Some((created, preview)) if now.saturating_duration_since(*created) < self.ttl => {
+                Some(Arc::clone(preview))
+            }
+            Some(_) => {
+                previews.remove(url);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, url: String, preview: Arc<serde_json::Value>, now: Instant) {
+        self.previews.lock().unwrap().insert(url, (now, preview));
+    }
+}
+
+/// Presence updates from other servers are counted in windows of this length.
+pub const REMOTE_PRESENCE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How many presence updates another server may send per window, the rest is dropped.
+pub const MAX_REMOTE_PRESENCE_UPDATES: usize = 500;
+
+/// Limits how many presence updates each server can make us process, so one server can't flood
+/// us with them.
+pub struct RemotePresenceLimiter {
+    window: Duration,
+    max_updates: usize,
+    servers: Mutex<HashMap<Box<ServerName>, (Instant, usize)>>,
+}
+
+impl RemotePresenceLimiter {
+    pub fn new(window: Duration, max_updates: usize) -> Self {
+        Self {
+            window,
+            max_updates,
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts one update of the server. Returns false if it already sent too many in this window.
+    pub fn allow(&self, server: &ServerName, now: Instant) -> bool {
+        let mut servers = self.servers.lock().unwrap();
+
+        let window = self.window;
+        if servers.len() > 10_000 {
+            servers.retain(|_, (started, _)| now.saturating_duration_since(*started) < window);
+        }
+
+        let (started, count) = servers.entry(server.to_owned()).or_insert((now, 0));
+        if now.saturating_duration_since(*started) >= window {
+            *started = now;
+            *count = 0;
+        }
+
+        if *count >= self.max_updates {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+}
+
+/// How long a nonce for shared-secret registration can be used.
+pub const REGISTRATION_NONCE_TTL: Duration = Duration::from_secs(60);
+
+/// Nonces handed out for shared-secret registration. Each of them can be used once.
+pub struct RegistrationNonces {
+    ttl: Duration,
+    nonces: Mutex<HashMap<String, Instant>>,
+}
+
+impl RegistrationNonces {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a new nonce. Expired nonces are purged at the same time.
+    pub fn create(&self, now: Instant) -> String {
+        let mut nonces = self.nonces.lock().unwrap();
+        let ttl = self.ttl;
+        nonces.retain(|_, created| now.saturating_duration_since(*created) < ttl);
+
+        let nonce = utils::random_string(32);
+        nonces.insert(nonce.clone(), now);
+        nonce
+    }
+
+    /// Uses up a nonce. Returns false if it is unknown, used or expired.
+    pub fn take(&self, nonce: &str, now: Instant) -> bool {
+        self.nonces
+            .lock()
+            .unwrap()
+            .remove(nonce)
+            .map_or(false, |created| {
+                now.saturating_duration_since(created) < self.ttl
+            })
+    }
+}
+
+/// How many unfinished walks of space hierarchies are remembered.
+pub const HIERARCHY_PAGES_CAPACITY: usize = 1000;
+
+/// Where the walk of a space hierarchy continues on the next page.
+#[derive(Clone, Debug)]
+pub struct HierarchyPage {
+    pub user_id: Box<UserId>,
+    pub room_id: Box<RoomId>,
+    pub suggested_only: bool,
+    pub max_depth: usize,
+    /// Rooms that are not visited yet, with the servers to ask about them and their depth
+    pub queue: VecDeque<(Box<RoomId>, Vec<Box<ServerName>>, usize)>,
+    pub seen: HashSet<Box<RoomId>>,
+    /// Summaries of remote rooms that other servers sent along with their parent space
+    pub remote_children: HashMap<Box<RoomId>, SpaceHierarchyRoomsChunk>,
+}
+
+/// Unfinished walks of space hierarchies by their `next_batch` token.
+pub struct HierarchyPages {
+    pages: Mutex<LruCache<String, HierarchyPage>>,
+}
+
+impl HierarchyPages {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pages: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Remembers where the walk continues and returns the token for the next page.
+    pub fn insert(&self, page: HierarchyPage) -> String {
+        let token = utils::random_string(16);
+        self.pages.lock().unwrap().insert(token.clone(), page);
+        token
+    }
+
+    /// Returns where the walk continues for a token. A client may ask for the same page again.
+    pub fn get(&self, token: &str) -> Option<HierarchyPage> {
+        self.pages.lock().unwrap().get_mut(token).cloned()
+    }
+}
+
+fn username_forbidden(localpart: &str, names: &[String], patterns: &RegexSet) -> bool {
+    names
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(localpart))
+        || patterns.is_match(localpart)
+}
+
+fn build_mailer(smtp: &SmtpConfig) -> Result<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)> {
+    let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
+        .map_err(|_| Error::bad_config("Invalid SMTP host."))?
+        .port(smtp.port);
+
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    let from = smtp
+        .from
+        .parse()
+        .map_err(|_| Error::bad_config("Invalid SMTP from address."))?;
+
+    Ok((transport.build(), from))
+}
+
+/// Parses the signing keypair stored in the database, its version and key separated by 0xff.
+pub(crate) fn parse_keypair(bytes: &[u8]) -> Result<ruma::signatures::Ed25519KeyPair> {
+    let mut parts = bytes.splitn(2, |&b| b == 0xff);
+
+    utils::string_from_bytes(
+        // 1. version
+        parts
+            .next()
+            .expect("splitn always returns at least one element"),
+    )
+    .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
+    .and_then(|version| {
+        // 2. key
+        parts
+            .next()
+            .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
+            .map(|key| (version, key))
+    })
+    .and_then(|(version, key)| {
+        ruma::signatures::Ed25519KeyPair::from_der(key, version)
+            .map_err(|_| Error::bad_database("Private or public keys are invalid."))
+    })
+}
+
 impl Globals {
     pub fn load(
         globals: Arc<dyn Tree>,
@@ -100,28 +875,7 @@ impl Globals {
             |s| Ok(s.to_vec()),
         )?;
 
-        let mut parts = keypair_bytes.splitn(2, |&b| b == 0xff);
-
-        let keypair = utils::string_from_bytes(
-            // 1. version
-            parts
-                .next()
-                .expect("splitn always returns at least one element"),
-        )
-        .map_err(|_| Error::bad_database("Invalid version bytes in keypair."))
-        .and_then(|version| {
-            // 2. key
-            parts
-                .next()
-                .ok_or_else(|| Error::bad_database("Invalid keypair format in database."))
-                .map(|key| (version, key))
-        })
-        .and_then(|(version, key)| {
-            ruma::signatures::Ed25519KeyPair::from_der(key, version)
-                .map_err(|_| Error::bad_database("Private or public keys are invalid."))
-        });
-
-        let keypair = match keypair {
+        let keypair = match parse_keypair(&keypair_bytes) {
             Ok(k) => k,
             Err(e) => {
                 error!("Keypair invalid. Deleting...");
@@ -158,6 +912,18 @@ impl Globals {
         // Experimental, partially supported room versions
         let unstable_room_versions = vec![RoomVersionId::V3, RoomVersionId::V4, RoomVersionId::V5];
 
+        let push_action_overrides = PushActionOverrides::from_config(&config.default_push_actions)?;
+        let rate_limiter = RateLimiter::from_config(&config);
+        let account_data_rate_limiter = RateLimiter::for_account_data(&config);
+        let auth_rate_limiter = RateLimiter::for_authentication(&config);
+        let active_syncs = ActiveSyncs::new(config.max_concurrent_syncs_per_user);
+        let presence_batcher = PresenceBatcher::new(Duration::from_secs(
+            config.presence_federation_interval_secs,
+        ));
+        let mailer = config.smtp.as_ref().map(build_mailer).transpose()?;
+        let forbidden_username_patterns = RegexSet::new(&config.forbidden_username_patterns)
+            .map_err(|_| Error::bad_config("Invalid regex in forbidden_username_patterns."))?;
+
         let mut s = Self {
             globals,
             config,
@@ -173,6 +939,7 @@ impl Globals {
             tls_name_override,
             federation_client,
             default_client,
+            url_preview_cache: UrlPreviewCache::new(URL_PREVIEW_CACHE_SIZE, URL_PREVIEW_TTL),
             server_signingkeys,
             jwt_decoding_key,
             stable_room_versions,
@@ -183,8 +950,26 @@ impl Globals {
             roomid_mutex_state: RwLock::new(HashMap::new()),
             roomid_mutex_insert: RwLock::new(HashMap::new()),
             roomid_mutex_federation: RwLock::new(HashMap::new()),
+            userid_mutex_servernotice: RwLock::new(HashMap::new()),
             sync_receivers: RwLock::new(HashMap::new()),
+            active_syncs,
+            typing_throttle: TypingThrottle::new(TYPING_FEDERATION_WINDOW),
+            presence_batcher,
+            remote_presence_limiter: RemotePresenceLimiter::new(
+                REMOTE_PRESENCE_WINDOW,
+                MAX_REMOTE_PRESENCE_UPDATES,
+            ),
+            registration_nonces: RegistrationNonces::new(REGISTRATION_NONCE_TTL),
+            hierarchy_pages: HierarchyPages::new(HIERARCHY_PAGES_CAPACITY),
+            metrics: Arc::new(Metrics::default()),
+            deactivate_all_confirmation: Mutex::new(None),
+            forbidden_username_patterns,
             rotate: RotationHandler::new(),
+            push_action_overrides,
+            rate_limiter,
+            account_data_rate_limiter,
+            auth_rate_limiter,
+            mailer,
         };
 
         fs::create_dir_all(s.get_media_folder())?;
@@ -211,6 +996,18 @@ impl Globals {
         self.default_client.clone()
     }
 
+    /// Returns a client that doesn't follow redirects and connects to `host` only at `addr`. Used
+    /// for requests to hosts users chose, whose address was checked before. Resolving the host
+    /// again could return a private address (DNS rebinding).
+    pub fn pinned_client(&self, host: &str, addr: SocketAddr) -> Result<reqwest::Client> {
+        let host = host.to_owned();
+
+        Ok(reqwest_client_builder(&self.config)?
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve_fn(move |domain| (domain == host).then(|| addr))
+            .build()?)
+    }
+
     /// Returns a client used for resolving .well-knowns
     pub fn federation_client(&self) -> reqwest::Client {
         // Client is cheap to clone (Arc wrapper) and avoids lifetime issues
@@ -239,10 +1036,55 @@ impl Globals {
         self.config.max_request_size
     }
 
+    /// Largest file a client may upload, never more than the request size limit.
+    pub fn max_upload_size(&self) -> u32 {
+        self.config
+            .max_upload_size
+            .min(self.config.max_request_size)
+    }
+
+    /// Maximum size in bytes of the content of one account data event.
+    pub fn max_account_data_size(&self) -> usize {
+        self.config.max_account_data_size
+    }
+
+    /// Upper bound for the `limit` clients may request when paginating.
+    pub fn max_pagination_limit(&self) -> usize {
+        self.config.max_pagination_limit
+    }
+
     pub fn allow_registration(&self) -> bool {
         self.config.allow_registration
     }
 
+    pub fn allow_guests(&self) -> bool {
+        self.config.allow_guests
+    }
+
+    pub fn allow_presence(&self) -> bool {
+        self.config.allow_presence
+    }
+
+    pub fn allow_blurhash(&self) -> bool {
+        self.config.allow_blurhash
+    }
+
+    pub fn allow_url_preview(&self) -> bool {
+        self.config.allow_url_preview
+    }
+
+    pub fn url_preview_max_size(&self) -> u32 {
+        self.config.url_preview_max_size
+    }
+
+    pub fn allow_password_login(&self) -> bool {
+        self.config.allow_password_login
+    }
+
+    pub fn registration_requires_token(&self) -> bool {
+        self.config.registration_requires_token
+    }
+
     pub fn allow_encryption(&self) -> bool {
         self.config.allow_encryption
     }
@@ -251,10 +1093,79 @@ impl Globals {
         self.config.allow_federation
     }
 
+    /// Room versions remote servers may join rooms of, empty if all supported versions are allowed.
+    pub fn federation_join_room_versions(&self) -> &[RoomVersionId] {
+        &self.config.federation_join_room_versions
+    }
+
+    /// How many missing auth events may be fetched for a single event from another server.
+    pub fn max_fetched_auth_chain_size(&self) -> usize {
+        self.config.max_fetched_auth_chain_size
+    }
+
+    /// Incoming transactions with more PDUs or EDUs are rejected.
+    pub fn max_transaction_size(&self) -> (usize, usize) {
+        (
+            self.config.max_transaction_pdus,
+            self.config.max_transaction_edus,
+        )
+    }
+
+    /// How long deactivated accounts can be restored, zero if deactivations are final right away.
+    pub fn deactivation_grace_period(&self) -> Duration {
+        Duration::from_secs(
+            self.config
+                .deactivation_grace_days
+                .saturating_mul(60 * 60 * 24),
+        )
+    }
+
+    /// After how long media cached from other servers is deleted, if at all.
+    pub fn remote_media_retention(&self) -> Option<Duration> {
+        self.config
+            .remote_media_retention_days
+            .map(|days| Duration::from_secs(days * 60 * 60 * 24))
+    }
+
+    /// After how long without activity accounts are deactivated, if at all.
+    pub fn inactive_account_period(&self) -> Option<Duration> {
+        self.config
+            .inactive_account_days
+            .map(|days| Duration::from_secs(days * 60 * 60 * 24))
+    }
+
+    /// How long before the deactivation of an inactive account its user is warned, longest first.
+    pub fn inactive_account_warnings(&self) -> Vec<Duration> {
+        let mut warnings = self
+            .config
+            .inactive_account_warning_days
+            .iter()
+            .map(|days| Duration::from_secs(days * 60 * 60 * 24))
+            .collect::<Vec<_>>();
+        warnings.sort_unstable_by(|a, b| b.cmp(a));
+        warnings
+    }
+
+    pub fn require_encryption(&self) -> bool {
+        self.config.require_encryption
+    }
+
+    pub fn require_encryption_public_rooms(&self) -> bool {
+        self.config.require_encryption_public_rooms
+    }
+
     pub fn allow_room_creation(&self) -> bool {
         self.config.allow_room_creation
     }
 
+    pub fn invite_only_from_members(&self) -> bool {
+        self.config.invite_only_from_members
+    }
+
+    pub fn prevent_power_level_lockout(&self) -> bool {
+        self.config.prevent_power_level_lockout
+    }
+
     pub fn allow_unstable_room_versions(&self) -> bool {
         self.config.allow_unstable_room_versions
     }
@@ -283,6 +1194,63 @@ impl Globals {
         self.config.turn_ttl
     }
 
+    /// How long a rendezvous session used for logging in new devices stays open.
+    pub fn rendezvous_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.rendezvous_ttl_secs)
+    }
+
+    /// How long an access token stays valid if the client can refresh it.
+    pub fn refreshable_access_token_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.refreshable_access_token_ttl_secs)
+    }
+
+    /// Whether emails can be sent to verify addresses.
+    pub fn email_enabled(&self) -> bool {
+        self.mailer.is_some()
+    }
+
+    /// How long an email verification code stays valid.
+    pub fn threepid_session_ttl(&self) -> Duration {
+        Duration::from_secs(self.config.threepid_session_ttl_secs)
+    }
+
+    pub async fn send_email(&self, to: &str, subject: &str, body: String) -> Result<()> {
+        let (mailer, from) = self.mailer.as_ref().ok_or(Error::BadRequest(
+            ErrorKind::ThreepidMediumNotSupported,
+            "This server does not send emails.",
+        ))?;
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to.parse().map_err(|_| {
+                Error::BadRequest(ErrorKind::InvalidParam, "Email address is invalid.")
+            })?)
+            .subject(subject)
+            .body(body)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Email is invalid."))?;
+
+        mailer.send(message).await.map_err(|e| {
+            warn!("Failed to send email: {}", e);
+            Error::BadServerResponse("Failed to send email.")
+        })?;
+
+        Ok(())
+    }
+
+    /// Devices which didn't sync for this long lose their undelivered to-device messages.
+    pub fn todevice_inactive(&self) -> Duration {
+        Duration::from_secs(self.config.todevice_inactive_secs)
+    }
+
+    pub fn max_todevice_events_per_device(&self) -> usize {
+        self.config.max_todevice_events_per_device
+    }
+
+    /// How long remote servers may cache our published signing keys.
+    pub fn signing_key_validity(&self) -> Duration {
+        Duration::from_secs(self.config.signing_key_validity)
+    }
+
     pub fn turn_uris(&self) -> &[String] {
         &self.config.turn_uris
     }
@@ -291,6 +1259,56 @@ impl Globals {
         &self.config.turn_username
     }
 
+    /// Limits logins, registrations and password changes per client IP address.
+    pub fn check_auth_rate_limit(&self, client_ip: Option<IpAddr>) -> Result<()> {
+        match (&self.auth_rate_limiter, client_ip) {
+            (Some(rate_limiter), Some(ip)) => rate_limiter.check_ip(ip, Instant::now()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpAddr] {
+        &self.config.trusted_proxies
+    }
+
+    pub fn password_policy(&self) -> &PasswordPolicy {
+        &self.config.password_policy
+    }
+
+    pub fn password_hashing(&self) -> &PasswordHashing {
+        &self.config.password_hashing
+    }
+
+    /// The bearer token for `/metrics`, which is disabled if it is not set.
+    pub fn metrics_token(&self) -> Option<&str> {
+        self.config.metrics_token.as_deref()
+    }
+
+    /// The secret for `/_synapse/admin/v1/register`, which is disabled if it is not set.
+    pub fn registration_shared_secret(&self) -> Option<&str> {
+        self.config.registration_shared_secret.as_deref()
+    }
+
+    /// Whether the localpart is on the forbidden username list or matches a forbidden pattern.
+    pub fn username_forbidden(&self, localpart: &str) -> bool {
+        username_forbidden(
+            localpart,
+            &self.config.forbidden_usernames,
+            &self.forbidden_username_patterns,
+        )
+    }
+
+    /// The site key and secret key for captchas during registration, if enabled.
+    pub fn recaptcha_keys(&self) -> Option<(&str, &str)> {
+        match (
+            &self.config.recaptcha_site_key,
+            &self.config.recaptcha_secret_key,
+        ) {
+            (Some(site_key), Some(secret_key)) => Some((site_key, secret_key)),
+            _ => None,
+        }
+    }
+
     pub fn turn_secret(&self) -> &String {
         &self.config.turn_secret
     }
@@ -299,6 +1317,48 @@ impl Globals {
         &self.config.emergency_password
     }
 
+    /// Base URL clients should use to reach this homeserver.
+    pub fn well_known_client(&self) -> String {
+        self.config
+            .well_known_client
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", self.server_name()))
+    }
+
+    pub fn default_identity_server(&self) -> Option<&str> {
+        self.config.default_identity_server.as_deref()
+    }
+
+    pub fn default_identity_server_account_data(&self) -> bool {
+        self.config.default_identity_server_account_data
+    }
+
+    pub fn admin_room_max_notices(&self) -> Option<usize> {
+        self.config.admin_room_max_notices
+    }
+
+    pub fn admin_room_max_notice_age(&self) -> Option<Duration> {
+        self.config
+            .admin_room_max_notice_age_secs
+            .map(Duration::from_secs)
+    }
+
+    pub fn welcome_message(&self) -> Option<&str> {
+        self.config.welcome_message.as_deref()
+    }
+
+    pub fn welcome_message_skip_guests(&self) -> bool {
+        self.config.welcome_message_skip_guests
+    }
+
+    pub fn welcome_room(&self) -> Option<&RoomId> {
+        self.config.welcome_room.as_deref()
+    }
+
+    pub fn welcome_room_auto_join(&self) -> bool {
+        self.config.welcome_room_auto_join
+    }
+
     pub fn supported_room_versions(&self) -> Vec<RoomVersionId> {
         let mut room_versions: Vec<RoomVersionId> = vec![];
         room_versions.extend(self.stable_room_versions.clone());
@@ -406,7 +1466,8 @@ impl Globals {
 fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
     let mut reqwest_client_builder = reqwest::Client::builder()
         .connect_timeout(Duration::from_secs(30))
-        .timeout(Duration::from_secs(60 * 3));
+        .timeout(Duration::from_secs(60 * 3))
+        .user_agent(config.user_agent());
 
     if let Some(proxy) = config.proxy.to_proxy()? {
         reqwest_client_builder = reqwest_client_builder.proxy(proxy);
@@ -414,3 +1475,416 @@ fn reqwest_client_builder(config: &Config) -> Result<reqwest::ClientBuilder> {
 
     Ok(reqwest_client_builder)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        username_forbidden, ActiveSyncs, Metrics, MetricsGauges, PresenceBatcher, PresenceFlush,
+        RateLimiter, RegistrationNonces, RemotePresenceLimiter, TypingFanout, TypingThrottle,
+        UrlPreviewCache,
+    };
+    use crate::{Config, Error};
+    use regex::RegexSet;
+    use ruma::{
+        api::{client::error::ErrorKind, federation::transactions::edu::PresenceUpdate},
+        presence::PresenceState,
+        server_name, uint, user_id,
+    };
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    fn limiter() -> RateLimiter {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+            "rate_limit_per_second": 1.0,
+            "rate_limit_burst": 2,
+            "rate_limit_jitter_ms": 500,
+            "rate_limit_exempt_users": ["@bot:example.org"],
+            "rate_limit_exempt_servers": ["trusted.example.org"],
+        }))
+        .unwrap();
+
+        RateLimiter::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn exempt_user_is_not_rate_limited() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let alice = user_id!("@alice:example.org");
+        let bot = user_id!("@bot:example.org");
+
+        assert!(limiter.check(Some(alice), None, now).is_ok());
+        assert!(limiter.check(Some(alice), None, now).is_ok());
+        assert!(limiter.check(Some(alice), None, now).is_err());
+
+        for _ in 0..10 {
+            assert!(limiter.check(Some(bot), None, now).is_ok());
+        }
+
+        // The bucket refills over time
+        assert!(limiter
+            .check(Some(alice), None, now + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn exempt_server_is_not_rate_limited() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter
+                .check(None, Some(server_name!("trusted.example.org")), now)
+                .is_ok());
+        }
+        for _ in 0..2 {
+            assert!(limiter
+                .check(None, Some(server_name!("other.example.org")), now)
+                .is_ok());
+        }
+        assert!(limiter
+            .check(None, Some(server_name!("other.example.org")), now)
+            .is_err());
+    }
+
+    #[test]
+    fn retry_after_is_jittered() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let alice = user_id!("@alice:example.org");
+
+        limiter.check(Some(alice), None, now).unwrap();
+        limiter.check(Some(alice), None, now).unwrap();
+
+        let retries = (0..20)
+            .map(|_| match limiter.check(Some(alice), None, now) {
+                Err(Error::BadRequest(
+                    ErrorKind::LimitExceeded {
+                        retry_after_ms: Some(retry_after),
+                    },
+                    _,
+                )) => retry_after,
+                _ => panic!("request should be rate limited"),
+            })
+            .collect::<Vec<_>>();
+
+        // The next token is one second away
+        assert!(retries.iter().all(|retry_after| {
+            *retry_after >= Duration::from_secs(1) && *retry_after <= Duration::from_millis(1500)
+        }));
+        assert!(retries.iter().any(|retry_after| *retry_after != retries[0]));
+    }
+
+    #[test]
+    fn account_data_writes_have_their_own_limit() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+            "account_data_rate_limit_per_second": 1.0,
+            "account_data_rate_limit_burst": 3,
+        }))
+        .unwrap();
+
+        // The general limit is disabled, the account data limit is configured on its own
+        assert!(RateLimiter::from_config(&config).is_none());
+        let limiter = RateLimiter::for_account_data(&config).unwrap();
+
+        let now = Instant::now();
+        let alice = user_id!("@alice:example.org");
+        for _ in 0..3 {
+            assert!(limiter.check(Some(alice), None, now).is_ok());
+        }
+        assert!(limiter.check(Some(alice), None, now).is_err());
+
+        // Servers which don't set a rate are not limited
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+        }))
+        .unwrap();
+        assert!(RateLimiter::for_account_data(&config).is_none());
+    }
+
+    #[test]
+    fn concurrent_syncs_are_limited_per_user() {
+        let syncs = ActiveSyncs::new(2);
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        let first = syncs.start(alice).unwrap();
+        let _second = syncs.start(alice).unwrap();
+        assert!(syncs.start(alice).is_err());
+        assert!(syncs.start(bob).is_ok());
+
+        // Finished syncs free their slot
+        drop(first);
+        assert!(syncs.start(alice).is_ok());
+
+        let unlimited = ActiveSyncs::new(0);
+        let _syncs = (0..100)
+            .map(|_| unlimited.start(alice).unwrap())
+            .collect::<Vec<_>>();
+    }
+
+    #[test]
+    fn rapid_typing_toggles_are_coalesced() {
+        let throttle = TypingThrottle::new(Duration::from_secs(3));
+        let room_id = ruma::room_id!("!room:example.org");
+        let alice = user_id!("@alice:example.org");
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut sent = 0;
+        let mut deferred = Vec::new();
+        for (millis, typing) in [
+            (0, true),
+            (100, false),
+            (200, true),
+            (300, false),
+            (400, true),
+        ] {
+            match throttle.update(room_id, alice, typing, at(millis)) {
+                TypingFanout::Send => sent += 1,
+                TypingFanout::Skip => {}
+                TypingFanout::Defer(after) => deferred.push(after),
+            }
+        }
+
+        // One update right away, the rest ends up in a single flush after the window
+        assert_eq!(sent, 1);
+        assert_eq!(deferred, vec![Duration::from_millis(2900)]);
+
+        // The user ended up typing again, which other servers already know
+        assert_eq!(throttle.flush(room_id, alice, at(3000)), None);
+
+        assert_eq!(
+            throttle.update(room_id, alice, false, at(3100)),
+            TypingFanout::Send
+        );
+        assert_eq!(
+            throttle.update(room_id, alice, true, at(3200)),
+            TypingFanout::Defer(Duration::from_millis(2900))
+        );
+        assert_eq!(throttle.flush(room_id, alice, at(6100)), Some(true));
+    }
+
+    #[test]
+    fn rapid_presence_changes_are_batched() {
+        let batcher = PresenceBatcher::new(Duration::from_secs(30));
+        let start = Instant::now();
+        let alice = user_id!("@alice:example.org");
+        let update = |presence| PresenceUpdate::new(alice.to_owned(), presence, uint!(0));
+
+        // The first update can be sent right away
+        assert_eq!(
+            batcher.queue(update(PresenceState::Online), start),
+            PresenceFlush::Schedule {
+                delay: Duration::ZERO,
+                generation: 1
+            }
+        );
+        let (sent, next) = batcher.take_due(start, 1);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(next, None);
+
+        let flush_at = start + Duration::from_secs(30);
+        for i in 1..100 {
+            let now = start + Duration::from_millis(i * 100);
+            let presence = if i % 2 == 0 {
+                PresenceState::Online
+            } else {
+                PresenceState::Unavailable
+            };
+            let delay = flush_at - now;
+
+            // Only the first change schedules a flush, the others know when it happens
+            assert_eq!(
+                batcher.queue(update(presence), now),
+                if i == 1 {
+                    PresenceFlush::Schedule {
+                        delay,
+                        generation: 2,
+                    }
+                } else {
+                    PresenceFlush::Pending(delay)
+                }
+            );
+        }
+
+        // All changes within the interval end up in a single update with the latest state
+        assert!(batcher
+            .take_due(start + Duration::from_secs(29), 2)
+            .0
+            .is_empty());
+        let (sent, next) = batcher.take_due(flush_at, 2);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].presence, PresenceState::Unavailable);
+        assert_eq!(next, None);
+
+        // Going offline is not delayed by the pending flush
+        let now = flush_at + Duration::from_secs(1);
+        assert_eq!(
+            batcher.queue(update(PresenceState::Online), now),
+            PresenceFlush::Schedule {
+                delay: Duration::from_secs(29),
+                generation: 3
+            }
+        );
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            batcher.queue(update(PresenceState::Offline), now),
+            PresenceFlush::Schedule {
+                delay: Duration::ZERO,
+                generation: 4
+            }
+        );
+        let (sent, next) = batcher.take_due(now, 4);
+        assert_eq!(sent[0].presence, PresenceState::Offline);
+        assert_eq!(next, None);
+
+        // The older flush has nothing left to do and stops
+        let (sent, next) = batcher.take_due(flush_at + Duration::from_secs(30), 3);
+        assert!(sent.is_empty());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn registration_nonces_are_single_use_and_expire() {
+        let nonces = RegistrationNonces::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let nonce = nonces.create(start);
+        assert!(nonces.take(&nonce, start + Duration::from_secs(1)));
+        assert!(!nonces.take(&nonce, start + Duration::from_secs(2)));
+
+        let nonce = nonces.create(start);
+        assert!(!nonces.take(&nonce, start + Duration::from_secs(60)));
+        assert!(!nonces.take("unknown", start));
+    }
+
+    #[test]
+    fn forbidden_usernames_match_exactly_or_by_pattern() {
+        let names = vec!["admin".to_owned(), "Support".to_owned()];
+        let patterns = RegexSet::new(&["^root", "moderator"]).unwrap();
+
+        assert!(username_forbidden("admin", &names, &patterns));
+        assert!(username_forbidden("support", &names, &patterns));
+        assert!(username_forbidden("rootkit", &names, &patterns));
+        assert!(username_forbidden("chief_moderator", &names, &patterns));
+        assert!(!username_forbidden("administrator", &names, &patterns));
+        assert!(!username_forbidden("alice", &names, &patterns));
+    }
+
+    #[test]
+    fn metrics_are_rendered_in_prometheus_format() {
+        let metrics = Metrics::default();
+        metrics.record_request("GET", "/_matrix/client/r0/sync");
+        metrics.record_request("GET", "/_matrix/client/r0/sync");
+        metrics.observe_pdu_build(Duration::from_millis(20));
+
+        let rendered = metrics.render(&MetricsGauges {
+            active_syncs: 2,
+            federation_queue: 5,
+            local_users: 10,
+        });
+
+        assert!(rendered.contains(
+            "conduit_http_requests_total{method=\"GET\",route=\"/_matrix/client/r0/sync\"} 2\n"
+        ));
+        assert!(rendered.contains("conduit_active_syncs 2\n"));
+        assert!(rendered.contains("conduit_federation_send_queue 5\n"));
+        assert!(rendered.contains("conduit_local_users 10\n"));
+        assert!(rendered.contains("conduit_pdu_build_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(rendered.contains("conduit_pdu_build_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(rendered.contains("conduit_pdu_build_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(rendered.contains("conduit_pdu_build_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn remote_presence_is_limited_per_server() {
+        let limiter = RemotePresenceLimiter::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+
+        assert!(limiter.allow(server_name!("a.example.org"), now));
+        assert!(limiter.allow(server_name!("a.example.org"), now));
+        assert!(!limiter.allow(server_name!("a.example.org"), now));
+        assert!(limiter.allow(server_name!("b.example.org"), now));
+
+        assert!(limiter.allow(server_name!("a.example.org"), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn url_previews_expire() {
+        let cache = UrlPreviewCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let preview = Arc::new(serde_json::json!({ "og:title": "Example" }));
+
+        cache.insert("https://example.org".to_owned(), preview.clone(), now);
+        assert_eq!(
+            cache.get("https://example.org", now + Duration::from_secs(59)),
+            Some(preview)
+        );
+        assert_eq!(
+            cache.get("https://example.org", now + Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(cache.get("https://example.com", now), None);
+    }
+
+    #[tokio::test]
+    async fn outbound_requests_carry_the_configured_user_agent() {
+        use super::reqwest_client_builder;
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+            thread,
+        };
+
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "server_name": "example.org",
+            "database_path": "/tmp/conduit",
+            "user_agent": "ExampleBot/1.0",
+        }))
+        .unwrap();
+
+        // Answers one request and returns its head
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        // Proxies from the environment would answer instead of the test server
+        reqwest_client_builder(&config)
+            .unwrap()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://{}/", address))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(server
+            .join()
+            .unwrap()
+            .contains("\r\nuser-agent: examplebot/1.0\r\n"));
+    }
+}
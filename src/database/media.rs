@@ -1,13 +1,33 @@
 use crate::database::globals::Globals;
-use image::{imageops::FilterType, GenericImageView};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
 
 use super::abstraction::Tree;
 use crate::{utils, Error, Result};
-use std::{mem, sync::Arc};
+use ruma::{api::client::error::ErrorKind, ServerName, UserId};
+use std::{
+    collections::HashSet,
+    fs, io, mem,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
 };
+use tracing::warn;
+
+/// Number of horizontal and vertical components of computed blurhashes.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Images are scaled down to fit into a square of this size before their blurhash is computed.
+const BLURHASH_IMAGE_SIZE: u32 = 32;
+
+/// Images with more pixels than this are not decoded, a small file can decompress to gigabytes.
+const MAX_IMAGE_PIXELS: u64 = 8192 * 8192;
+
+/// Media ids a user may have reserved without uploading their content.
+pub const MAX_PENDING_UPLOADS_PER_USER: usize = 50;
 
 pub struct FileMeta {
     pub content_disposition: Option<String>,
@@ -17,6 +37,35 @@ pub struct FileMeta {
 
 pub struct Media {
     pub(super) mediaid_file: Arc<dyn Tree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
+    pub(super) userid_mxc: Arc<dyn Tree>,   // UserMxc = UserId + MXC, files uploaded by local users
+    pub(super) mxc_createdat: Arc<dyn Tree>, // When a file was uploaded or cached
+    pub(super) createdat_mxc: Arc<dyn Tree>, // CreatedAt + MXC, to purge old media
+    pub(super) mxc_pendingupload: Arc<dyn Tree>, // PendingUpload = ExpiresAt + UserId
+    pub(super) expiresat_mxc: Arc<dyn Tree>, // ExpiresAt + MXC, to purge expired reservations
+    pub(super) userid_pendingmxc: Arc<dyn Tree>, // UserId + 0xff + MXC, reservations of a user
+    pub(super) mxc_blurhash: Arc<dyn Tree>,
+    /// Reserved media ids whose content is being uploaded right now
+    pub(super) uploading: Mutex<HashSet<String>>,
+}
+
+/// A media id that was handed out before its content was uploaded.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PendingUpload {
+    pub user_id: Box<UserId>,
+    /// Milliseconds since the unix epoch after which the media id can't be used anymore
+    pub expires_at: u64,
+}
+
+/// Marks a reserved media id as being uploaded until it is dropped.
+pub struct ReservedUpload<'a> {
+    media: &'a Media,
+    mxc: String,
+}
+
+impl Drop for ReservedUpload<'_> {
+    fn drop(&mut self) {
+        self.media.uploading.lock().unwrap().remove(&self.mxc);
+    }
 }
 
 impl Media {
@@ -53,6 +102,208 @@ impl Media {
         f.write_all(file).await?;
 
         self.mediaid_file.insert(&key, &[])?;
+        self.set_created_at(mxc.as_bytes(), utils::millis_since_unix_epoch())?;
+        Ok(())
+    }
+
+    /// Remembers when a file was uploaded or cached, replacing an earlier time.
+    pub(super) fn set_created_at(&self, mxc: &[u8], created_at: u64) -> Result<()> {
+        self.forget_created_at(mxc)?;
+
+        self.mxc_createdat.insert(mxc, &created_at.to_be_bytes())?;
+        self.createdat_mxc
+            .insert(&created_at_key(created_at, mxc), &[])
+    }
+
+    fn forget_created_at(&self, mxc: &[u8]) -> Result<()> {
+        if let Some(bytes) = self.mxc_createdat.get(mxc)? {
+            let created_at = utils::u64_from_bytes(&bytes)
+                .map_err(|_| Error::bad_database("Invalid timestamp in mxc_createdat."))?;
+            self.createdat_mxc
+                .remove(&created_at_key(created_at, mxc))?;
+            self.mxc_createdat.remove(mxc)?;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes the creation time of every stored file. Files stored before creation times were
+    /// tracked get the time they were last written.
+    pub(super) fn index_created_at(&self, media_folder: &Path) -> Result<()> {
+        for (key, _) in self.mediaid_file.iter() {
+            let mxc = key.split(|&b| b == 0xff).next().unwrap_or_default();
+
+            let created_at = match self.mxc_createdat.get(mxc)? {
+                Some(bytes) => utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid timestamp in mxc_createdat."))?,
+                None => fs::metadata(
+                    media_folder.join(base64::encode_config(&key, base64::URL_SAFE_NO_PAD)),
+                )
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(utils::millis_since_unix_epoch, |age| {
+                    age.as_millis().try_into().unwrap_or(u64::MAX)
+                }),
+            };
+
+            self.set_created_at(mxc, created_at)?;
+        }
+
+        Ok(())
+    }
+
+    /// Computes and stores the blurhash of an uploaded image. Returns None if the file is no image
+    /// or too large to decode.
+    pub async fn create_blurhash(&self, mxc: &str, file: Vec<u8>) -> Result<Option<String>> {
+        // Decoding the image takes a while, it must not block the other requests
+        let blurhash = match tokio::task::spawn_blocking(move || {
+            load_image(&file).map(|image| image_blurhash(&image))
+        })
+        .await
+        {
+            Ok(Some(blurhash)) => blurhash,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                warn!("Failed to compute the blurhash of {}: {}", mxc, e);
+                return Ok(None);
+            }
+        };
+
+        self.mxc_blurhash
+            .insert(mxc.as_bytes(), blurhash.as_bytes())?;
+
+        Ok(Some(blurhash))
+    }
+
+    /// Returns the blurhash computed when the file was uploaded.
+    pub fn blurhash(&self, mxc: &str) -> Result<Option<String>> {
+        self.mxc_blurhash
+            .get(mxc.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Blurhash in mxc_blurhash is invalid unicode.")
+                })
+            })
+            .transpose()
+    }
+
+    /// Reserves a media id for a file the user uploads later. Expired reservations are purged at
+    /// the same time. Fails if the user has too many reservations.
+    pub fn reserve(&self, mxc: &str, user_id: &UserId, expires_at: u64, now: u64) -> Result<()> {
+        // Also keeps concurrent reservations of a user from going over the limit
+        let _uploading = self.uploading.lock().unwrap();
+
+        self.purge_expired_reservations(now)?;
+
+        if self
+            .userid_pendingmxc
+            .scan_prefix(userid_prefix(user_id))
+            .count()
+            >= MAX_PENDING_UPLOADS_PER_USER
+        {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "Too many media ids are waiting for their content.",
+            ));
+        }
+
+        let mut value = expires_at.to_be_bytes().to_vec();
+        value.extend_from_slice(user_id.as_bytes());
+
+        self.mxc_pendingupload.insert(mxc.as_bytes(), &value)?;
+        self.expiresat_mxc
+            .insert(&expires_at_key(expires_at, mxc), &[])?;
+        self.userid_pendingmxc
+            .insert(&userid_pendingmxc_key(user_id, mxc), &[])
+    }
+
+    /// Returns the reservation of a media id whose content was not uploaded yet.
+    pub fn pending_upload(&self, mxc: &str, now: u64) -> Result<Option<PendingUpload>> {
+        let pending = match self.mxc_pendingupload.get(mxc.as_bytes())? {
+            Some(bytes) => decode_pending_upload(&bytes)?,
+            None => return Ok(None),
+        };
+
+        if pending.expires_at <= now {
+            self.remove_reservation(mxc, &pending)?;
+            return Ok(None);
+        }
+
+        Ok(Some(pending))
+    }
+
+    /// Claims a reserved media id for uploading its content. Returns None if the media id is not
+    /// reserved (anymore), fails if another user reserved it or another upload to it is running.
+    pub fn start_upload(
+        &self,
+        mxc: &str,
+        user_id: &UserId,
+        now: u64,
+    ) -> Result<Option<ReservedUpload<'_>>> {
+        let mut uploading = self.uploading.lock().unwrap();
+
+        match self.pending_upload(mxc, now)? {
+            Some(pending) if pending.user_id != user_id => Err(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "Only the user who created this media id can upload to it.",
+            )),
+            Some(_) if uploading.contains(mxc) => {
+                Err(Error::Conflict("Media is already being uploaded."))
+            }
+            Some(_) => {
+                uploading.insert(mxc.to_owned());
+                Ok(Some(ReservedUpload {
+                    media: self,
+                    mxc: mxc.to_owned(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Forgets the reservation of a media id once its content was uploaded.
+    pub fn finish_upload(&self, mxc: &str) -> Result<()> {
+        match self.mxc_pendingupload.get(mxc.as_bytes())? {
+            Some(bytes) => self.remove_reservation(mxc, &decode_pending_upload(&bytes)?),
+            None => Ok(()),
+        }
+    }
+
+    fn remove_reservation(&self, mxc: &str, pending: &PendingUpload) -> Result<()> {
+        self.mxc_pendingupload.remove(mxc.as_bytes())?;
+        self.expiresat_mxc
+            .remove(&expires_at_key(pending.expires_at, mxc))?;
+        self.userid_pendingmxc
+            .remove(&userid_pendingmxc_key(&pending.user_id, mxc))
+    }
+
+    fn purge_expired_reservations(&self, now: u64) -> Result<()> {
+        let expired = self
+            .expiresat_mxc
+            .iter()
+            .take_while(|(key, _)| {
+                key.get(..8)
+                    .and_then(|expires_at| utils::u64_from_bytes(expires_at).ok())
+                    .map_or(true, |expires_at| expires_at <= now)
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            match utils::string_from_bytes(&key[8..]) {
+                Ok(mxc) => match self.mxc_pendingupload.get(mxc.as_bytes())? {
+                    Some(bytes) => {
+                        self.remove_reservation(&mxc, &decode_pending_upload(&bytes)?)?
+                    }
+                    None => self.expiresat_mxc.remove(&key)?,
+                },
+                Err(_) => self.expiresat_mxc.remove(&key)?,
+            }
+        }
+
         Ok(())
     }
 
@@ -92,10 +343,22 @@ impl Media {
         f.write_all(file).await?;
 
         self.mediaid_file.insert(&key, &[])?;
+        // Thumbnails of a stored file expire together with it
+        if self.mxc_createdat.get(mxc.as_bytes())?.is_none() {
+            self.set_created_at(mxc.as_bytes(), utils::millis_since_unix_epoch())?;
+        }
 
         Ok(())
     }
 
+    /// Whether a file was stored with this mxc.
+    pub fn exists(&self, mxc: &str) -> Result<bool> {
+        let mut prefix = mxc.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        Ok(self.mediaid_file.scan_prefix(prefix).next().is_some())
+    }
+
     /// Downloads a file.
     pub async fn get(&self, globals: &Globals, mxc: &str) -> Result<Option<FileMeta>> {
         let mut prefix = mxc.as_bytes().to_vec();
@@ -146,6 +409,173 @@ impl Media {
         }
     }
 
+    /// Deletes the files and thumbnails of media from a server. If `before` is set, only media
+    /// created before that time is deleted.
+    ///
+    /// Returns the number of deleted files and how many bytes they used.
+    pub fn purge_server_media(
+        &self,
+        media_folder: &Path,
+        server: &ServerName,
+        before: Option<SystemTime>,
+    ) -> Result<(usize, u64)> {
+        let prefix = format!("mxc://{}/", server).into_bytes();
+        let keys = self
+            .mediaid_file
+            .scan_prefix(prefix)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        self.remove_files(media_folder, keys, before)
+    }
+
+    /// Deletes all media created before the given time. Files uploaded by local users are only
+    /// deleted if `include_local` is set.
+    ///
+    /// Returns the number of deleted files and how many bytes they used.
+    pub fn purge_media_before(
+        &self,
+        media_folder: &Path,
+        local_server: &ServerName,
+        before: SystemTime,
+        include_local: bool,
+    ) -> Result<(usize, u64)> {
+        let local_prefix = format!("mxc://{}/", local_server).into_bytes();
+        let before_millis = before
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |age| age.as_millis().try_into().unwrap_or(u64::MAX));
+
+        let mxcs = self
+            .createdat_mxc
+            .iter()
+            .take_while(|(key, _)| {
+                key.get(..8)
+                    .and_then(|created_at| utils::u64_from_bytes(created_at).ok())
+                    .map_or(true, |created_at| created_at < before_millis)
+            })
+            .filter_map(|(key, _)| key.get(8..).map(|mxc| mxc.to_vec()))
+            .filter(|mxc| include_local || !mxc.starts_with(&local_prefix))
+            .collect::<Vec<_>>();
+
+        let mut keys = Vec::new();
+        for mut mxc_prefix in mxcs {
+            mxc_prefix.push(0xff);
+            keys.extend(
+                self.mediaid_file
+                    .scan_prefix(mxc_prefix)
+                    .map(|(key, _)| key),
+            );
+        }
+
+        self.remove_files(media_folder, keys, Some(before))
+    }
+
+    /// Remembers who uploaded a file, so it can be deleted together with their account.
+    pub fn set_uploader(&self, user_id: &UserId, mxc: &str) -> Result<()> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(mxc.as_bytes());
+
+        self.userid_mxc.insert(&key, &[])
+    }
+
+    /// Deletes the files a local user uploaded and their thumbnails.
+    ///
+    /// Returns the number of deleted files and how many bytes they used.
+    pub fn purge_user_media(&self, media_folder: &Path, user_id: &UserId) -> Result<(usize, u64)> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let uploads = self
+            .userid_mxc
+            .scan_prefix(prefix.clone())
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        let mut files = 0;
+        let mut bytes = 0;
+
+        for upload in uploads {
+            let mut mxc_prefix = upload[prefix.len()..].to_vec();
+            mxc_prefix.push(0xff);
+
+            let keys = self
+                .mediaid_file
+                .scan_prefix(mxc_prefix)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+
+            let (removed_files, removed_bytes) = self.remove_files(media_folder, keys, None)?;
+            files += removed_files;
+            bytes += removed_bytes;
+
+            self.userid_mxc.remove(&upload)?;
+        }
+
+        Ok((files, bytes))
+    }
+
+    fn remove_files(
+        &self,
+        media_folder: &Path,
+        keys: Vec<Vec<u8>>,
+        before: Option<SystemTime>,
+    ) -> Result<(usize, u64)> {
+        let mut files = 0;
+        let mut bytes = 0;
+        let mut removed = HashSet::new();
+
+        for key in keys {
+            let path = media_folder.join(base64::encode_config(&key, base64::URL_SAFE_NO_PAD));
+
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => Some(metadata),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mxc = key.split(|&b| b == 0xff).next().unwrap_or_default();
+
+            if let Some(before) = before {
+                if self
+                    .created_at(mxc)?
+                    .map_or(false, |created| created >= before)
+                {
+                    continue;
+                }
+            }
+
+            if let Some(metadata) = metadata {
+                fs::remove_file(&path)?;
+                files += 1;
+                bytes += metadata.len();
+            }
+
+            self.mediaid_file.remove(&key)?;
+            removed.insert(mxc.to_vec());
+        }
+
+        // Thumbnails share the creation time of their file, so it's only forgotten at the end
+        for mxc in removed {
+            self.forget_created_at(&mxc)?;
+            self.mxc_blurhash.remove(&mxc)?;
+        }
+
+        Ok((files, bytes))
+    }
+
+    /// When a file was uploaded to this server or cached from a remote server.
+    fn created_at(&self, mxc: &[u8]) -> Result<Option<SystemTime>> {
+        self.mxc_createdat
+            .get(mxc)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+                    .map_err(|_| Error::bad_database("Invalid timestamp in mxc_createdat."))
+            })
+            .transpose()
+    }
+
     /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
     /// the server should send the original file.
     pub fn thumbnail_properties(&self, width: u32, height: u32) -> Option<(u32, u32, bool)> {
@@ -356,3 +786,299 @@ impl Media {
         }
     }
 }
+
+/// Decodes an image, unless it is no image or its header says it has more than
+/// `MAX_IMAGE_PIXELS` pixels.
+pub fn load_image(file: &[u8]) -> Option<DynamicImage> {
+    let (width, height) = image::io::Reader::new(io::Cursor::new(file))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+
+    if u64::from(width) * u64::from(height) > MAX_IMAGE_PIXELS {
+        return None;
+    }
+
+    image::load_from_memory(file).ok()
+}
+
+/// The blurhash of an image. The hash only describes a few colors, so a small version of the
+/// image is enough.
+fn image_blurhash(image: &DynamicImage) -> String {
+    let small = image.thumbnail(BLURHASH_IMAGE_SIZE, BLURHASH_IMAGE_SIZE);
+
+    blurhash::encode(
+        BLURHASH_COMPONENTS.0,
+        BLURHASH_COMPONENTS.1,
+        small.width(),
+        small.height(),
+        &small.to_rgba8(),
+    )
+}
+
+fn created_at_key(created_at: u64, mxc: &[u8]) -> Vec<u8> {
+    let mut key = created_at.to_be_bytes().to_vec();
+    key.extend_from_slice(mxc);
+    key
+}
+
+fn expires_at_key(expires_at: u64, mxc: &str) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(mxc.as_bytes());
+    key
+}
+
+fn userid_prefix(user_id: &UserId) -> Vec<u8> {
+    let mut prefix = user_id.as_bytes().to_vec();
+    prefix.push(0xff);
+    prefix
+}
+
+fn userid_pendingmxc_key(user_id: &UserId, mxc: &str) -> Vec<u8> {
+    let mut key = userid_prefix(user_id);
+    key.extend_from_slice(mxc.as_bytes());
+    key
+}
+
+fn decode_pending_upload(bytes: &[u8]) -> Result<PendingUpload> {
+    let invalid = || Error::bad_database("Invalid reservation in mxc_pendingupload.");
+
+    if bytes.len() < 8 {
+        return Err(invalid());
+    }
+
+    Ok(PendingUpload {
+        expires_at: utils::u64_from_bytes(&bytes[..8]).map_err(|_| invalid())?,
+        user_id: utils::string_from_bytes(&bytes[8..])
+            .ok()
+            .and_then(|user_id| UserId::parse(user_id).ok())
+            .ok_or_else(invalid)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::image_blurhash;
+    use image::{DynamicImage, Rgba, RgbaImage};
+
+    #[test]
+    fn blurhash_of_single_color() {
+        let white = DynamicImage::ImageRgba8(RgbaImage::from_pixel(64, 64, Rgba([255; 4])));
+        assert_eq!(
+            image_blurhash(&white),
+            "L0TSUAfQ".to_owned() + &"fQ".repeat(10)
+        );
+    }
+
+    /// Opens the media trees on a new database, returns them with the database folder.
+    #[cfg(feature = "sqlite")]
+    fn test_media(name: &str) -> (super::Media, std::path::PathBuf) {
+        use super::Media;
+        use crate::database::abstraction::{sqlite::test_engine, DatabaseEngine};
+        use std::{collections::HashSet, path::PathBuf, sync::Mutex};
+
+        let (engine, config) = test_engine(name);
+        let media = Media {
+            mediaid_file: engine.open_tree("mediaid_file").unwrap(),
+            userid_mxc: engine.open_tree("userid_mxc").unwrap(),
+            mxc_createdat: engine.open_tree("mxc_createdat").unwrap(),
+            createdat_mxc: engine.open_tree("createdat_mxc").unwrap(),
+            mxc_pendingupload: engine.open_tree("mxc_pendingupload").unwrap(),
+            expiresat_mxc: engine.open_tree("expiresat_mxc").unwrap(),
+            userid_pendingmxc: engine.open_tree("userid_pendingmxc").unwrap(),
+            mxc_blurhash: engine.open_tree("mxc_blurhash").unwrap(),
+            uploading: Mutex::new(HashSet::new()),
+        };
+
+        (media, PathBuf::from(config.database_path))
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn purge_only_removes_media_of_server() {
+        use ruma::{server_name, user_id};
+        use std::{
+            fs,
+            time::{Duration, SystemTime},
+        };
+
+        let (media, path) = test_media("media");
+        let media_folder = path.join("media");
+        fs::create_dir_all(&media_folder).unwrap();
+
+        let cache = |mxc: &str, size: usize| {
+            let mut key = mxc.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(&[0; 8]);
+            key.extend_from_slice(&[0xff, 0xff]);
+            fs::write(
+                media_folder.join(base64::encode_config(&key, base64::URL_SAFE_NO_PAD)),
+                vec![0; size],
+            )
+            .unwrap();
+            media.mediaid_file.insert(&key, &[]).unwrap();
+            media
+                .set_created_at(mxc.as_bytes(), crate::utils::millis_since_unix_epoch())
+                .unwrap();
+        };
+
+        cache("mxc://evil.example.com/a", 100);
+        cache("mxc://evil.example.com/b", 20);
+        cache("mxc://evil.example.com.au/c", 30);
+        cache("mxc://example.org/d", 40);
+
+        // Nothing was cached before the epoch
+        assert_eq!(
+            media
+                .purge_server_media(
+                    &media_folder,
+                    server_name!("evil.example.com"),
+                    Some(SystemTime::UNIX_EPOCH)
+                )
+                .unwrap(),
+            (0, 0)
+        );
+
+        assert_eq!(
+            media
+                .purge_server_media(&media_folder, server_name!("evil.example.com"), None)
+                .unwrap(),
+            (2, 120)
+        );
+        assert_eq!(media.mediaid_file.iter().count(), 2);
+        assert_eq!(fs::read_dir(&media_folder).unwrap().count(), 2);
+
+        // Removing a user's uploads also removes their thumbnails, but no other media
+        cache("mxc://example.org/dd", 50);
+        let mut thumbnail = b"mxc://example.org/d".to_vec();
+        thumbnail.push(0xff);
+        thumbnail.extend_from_slice(&[0, 0, 0, 32, 0, 0, 0, 32]);
+        thumbnail.extend_from_slice(&[0xff, 0xff]);
+        fs::write(
+            media_folder.join(base64::encode_config(&thumbnail, base64::URL_SAFE_NO_PAD)),
+            vec![0; 5],
+        )
+        .unwrap();
+        media.mediaid_file.insert(&thumbnail, &[]).unwrap();
+
+        let alice = user_id!("@alice:example.org");
+        media.set_uploader(alice, "mxc://example.org/d").unwrap();
+        assert_eq!(
+            media.purge_user_media(&media_folder, alice).unwrap(),
+            (2, 45)
+        );
+        assert_eq!(media.mediaid_file.iter().count(), 2);
+        assert_eq!(media.userid_mxc.iter().count(), 0);
+
+        // Old remote media goes, local uploads stay unless they are included
+        cache("mxc://old.example.com/e", 60);
+        media
+            .set_created_at(b"mxc://old.example.com/e", 60_000)
+            .unwrap();
+        cache("mxc://new.example.com/f", 70);
+        media
+            .set_created_at(b"mxc://new.example.com/f", 4_000_000_000_000)
+            .unwrap();
+
+        let before = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        assert_eq!(
+            media
+                .purge_media_before(&media_folder, server_name!("example.org"), before, false)
+                .unwrap(),
+            (0, 0)
+        );
+        let before = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(
+            media
+                .purge_media_before(&media_folder, server_name!("example.org"), before, false)
+                .unwrap(),
+            (2, 90)
+        );
+        assert_eq!(media.mxc_createdat.iter().count(), 2);
+        assert_eq!(media.createdat_mxc.iter().count(), 2);
+        assert_eq!(
+            media
+                .purge_media_before(&media_folder, server_name!("example.org"), before, true)
+                .unwrap(),
+            (1, 50)
+        );
+        assert_eq!(media.mediaid_file.iter().count(), 1);
+
+        drop(media);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn reservations_expire_and_are_limited() {
+        use super::{PendingUpload, MAX_PENDING_UPLOADS_PER_USER};
+        use crate::Error;
+        use ruma::{api::client::error::ErrorKind, user_id};
+        use std::fs;
+
+        let (media, path) = test_media("reservation");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        // Reserved media ids expire unless their content is uploaded in time
+        media
+            .reserve("mxc://example.org/a", alice, 1000, 0)
+            .unwrap();
+        assert_eq!(
+            media.pending_upload("mxc://example.org/a", 10).unwrap(),
+            Some(PendingUpload {
+                user_id: alice.to_owned(),
+                expires_at: 1000,
+            })
+        );
+        assert_eq!(
+            media.pending_upload("mxc://example.org/a", 1000).unwrap(),
+            None
+        );
+        assert_eq!(media.expiresat_mxc.iter().count(), 0);
+        assert_eq!(media.userid_pendingmxc.iter().count(), 0);
+
+        // Only the user who reserved the id can upload to it, one upload at a time
+        media
+            .reserve("mxc://example.org/b", alice, 2000, 0)
+            .unwrap();
+        assert!(matches!(
+            media.start_upload("mxc://example.org/b", bob, 10),
+            Err(Error::BadRequest(ErrorKind::Forbidden, _))
+        ));
+        let upload = media
+            .start_upload("mxc://example.org/b", alice, 10)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            media.start_upload("mxc://example.org/b", alice, 10),
+            Err(Error::Conflict(_))
+        ));
+        media.finish_upload("mxc://example.org/b").unwrap();
+        drop(upload);
+        assert!(media
+            .start_upload("mxc://example.org/b", alice, 10)
+            .unwrap()
+            .is_none());
+
+        // Users can only have a limited number of reservations, expired ones don't count
+        for i in 0..MAX_PENDING_UPLOADS_PER_USER {
+            media
+                .reserve(&format!("mxc://example.org/c{}", i), alice, 3000, 0)
+                .unwrap();
+        }
+        assert!(matches!(
+            media.reserve("mxc://example.org/d", alice, 3000, 0),
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+        media.reserve("mxc://example.org/d", bob, 3000, 0).unwrap();
+        media
+            .reserve("mxc://example.org/e", alice, 4000, 3000)
+            .unwrap();
+        assert_eq!(media.userid_pendingmxc.iter().count(), 1);
+
+        drop(media);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Database, Error, PduEvent, Result};
+use crate::{config::DefaultPushActions, Database, Error, PduEvent, Result};
 use bytes::BytesMut;
 use ruma::{
     api::{
@@ -17,9 +17,11 @@ use ruma::{
     serde::Raw,
     uint, RoomId, UInt, UserId,
 };
+use serde::Deserialize;
+use serde_json::json;
 use tracing::{error, info, warn};
 
-use std::{fmt::Debug, mem, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, mem, sync::Arc};
 
 use super::abstraction::Tree;
 
@@ -28,6 +30,51 @@ pub struct PushData {
     pub(super) senderkey_pusher: Arc<dyn Tree>,
 }
 
+/// Global account data event type that holds the rooms a user muted.
+pub const MUTED_ROOMS_EVENT_TYPE: &str = "org.conduit.muted_rooms";
+
+#[derive(Deserialize)]
+struct MutedRoomsEvent {
+    content: MutedRoomsEventContent,
+}
+
+/// Content of the `org.conduit.muted_rooms` account data event, e.g.
+/// `{"rooms": {"!room:example.org": "mentions"}}`.
+#[derive(Deserialize)]
+struct MutedRoomsEventContent {
+    #[serde(default)]
+    rooms: BTreeMap<Box<RoomId>, RoomMute>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomMute {
+    /// Only highlights (e.g. mentions) still notify
+    Mentions,
+    /// The room never notifies
+    All,
+}
+
+/// Returns how the user muted this room, if at all.
+///
+/// Broken account data is ignored so it can't prevent events from being appended.
+pub fn room_mute(user: &UserId, room_id: &RoomId, db: &Database) -> Option<RoomMute> {
+    db.account_data
+        .get::<MutedRoomsEvent>(None, user, MUTED_ROOMS_EVENT_TYPE.to_owned().into())
+        .ok()
+        .flatten()
+        .and_then(|event| event.content.rooms.get(room_id).copied())
+}
+
+/// Applies a room mute to the notify and highlight flags computed from the push rules.
+pub fn apply_room_mute(mute: Option<RoomMute>, notify: bool, highlight: bool) -> (bool, bool) {
+    match mute {
+        None => (notify, highlight),
+        Some(RoomMute::Mentions) => (notify && highlight, highlight),
+        Some(RoomMute::All) => (false, false),
+    }
+}
+
 impl PushData {
     #[tracing::instrument(skip(self, sender, pusher))]
     pub fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::Pusher) -> Result<()> {
@@ -216,7 +263,14 @@ pub async fn send_push_notice(
         notify = Some(n);
     }
 
-    if notify == Some(true) {
+    let highlight = tweaks.iter().any(|t| matches!(t, Tweak::Highlight(true)));
+    let (notify, _) = apply_room_mute(
+        room_mute(user, &pdu.room_id, db),
+        notify == Some(true),
+        highlight,
+    );
+
+    if notify {
         send_notice(unread, pusher, tweaks, pdu, db).await?;
     }
     // Else the event triggered no actions
@@ -248,6 +302,102 @@ pub fn get_actions<'a>(
     Ok(ruleset.get_actions(pdu, &ctx))
 }
 
+/// The configured replacements for the actions of the server default push rules.
+#[derive(Clone, Debug, Default)]
+pub struct PushActionOverrides {
+    contains_display_name: Option<Vec<Action>>,
+    roomnotif: Option<Vec<Action>>,
+    keyword: Option<Vec<Action>>,
+}
+
+impl PushActionOverrides {
+    /// Parses the `default_push_actions` config section, so invalid actions are caught at startup.
+    pub fn from_config(config: &DefaultPushActions) -> Result<Self> {
+        let parse = |actions: &Option<serde_json::Value>, error| {
+            actions
+                .clone()
+                .map(|actions| {
+                    serde_json::from_value::<Vec<Action>>(actions)
+                        .map_err(|_| Error::bad_config(error))
+                })
+                .transpose()
+        };
+
+        Ok(Self {
+            contains_display_name: parse(
+                &config.contains_display_name,
+                "Invalid push actions in default_push_actions.contains_display_name.",
+            )?,
+            roomnotif: parse(
+                &config.roomnotif,
+                "Invalid push actions in default_push_actions.roomnotif.",
+            )?,
+            keyword: parse(
+                &config.keyword,
+                "Invalid push actions in default_push_actions.keyword.",
+            )?,
+        })
+    }
+
+    /// Replaces the actions of the affected rules in a new user's ruleset.
+    pub fn apply(&self, mut ruleset: Ruleset) -> Ruleset {
+        ruleset.override_ = ruleset
+            .override_
+            .into_iter()
+            .map(|mut rule| {
+                let actions = match rule.rule_id.as_str() {
+                    ".m.rule.contains_display_name" => &self.contains_display_name,
+                    ".m.rule.roomnotif" => &self.roomnotif,
+                    _ => &None,
+                };
+                if let Some(actions) = actions {
+                    rule.actions = actions.clone();
+                }
+                rule
+            })
+            .collect();
+
+        if let Some(actions) = &self.keyword {
+            ruleset.content = ruleset
+                .content
+                .into_iter()
+                .map(|mut rule| {
+                    rule.actions = actions.clone();
+                    rule
+                })
+                .collect();
+        }
+
+        ruleset
+    }
+}
+
+/// Unsigned field under which /sync carries the push actions evaluated for the syncing user.
+pub const PUSH_ACTIONS_UNSIGNED_KEY: &str = "org.conduit.push_actions";
+
+/// Adds the evaluated push actions to the unsigned data of a sync event.
+pub fn with_push_actions(
+    event: Raw<AnySyncRoomEvent>,
+    actions: &[Action],
+) -> Raw<AnySyncRoomEvent> {
+    let mut json: serde_json::Value = match serde_json::from_str(event.json().get()) {
+        Ok(json) => json,
+        Err(_) => return event,
+    };
+
+    if let Some(object) = json.as_object_mut() {
+        let unsigned = object.entry("unsigned").or_insert_with(|| json!({}));
+        if unsigned.is_null() {
+            *unsigned = json!({});
+        }
+        if let Some(unsigned) = unsigned.as_object_mut() {
+            unsigned.insert(PUSH_ACTIONS_UNSIGNED_KEY.to_owned(), json!(actions));
+        }
+    }
+
+    serde_json::from_value(json).expect("Raw::from_value always works")
+}
+
 #[tracing::instrument(skip(unread, pusher, tweaks, event, db))]
 async fn send_notice(
     unread: UInt,
@@ -346,3 +496,221 @@ async fn send_notice(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_room_mute, with_push_actions, Action, AnySyncRoomEvent, PushConditionRoomCtx, Raw,
+        RoomId, RoomMute, RoomPowerLevelsEventContent, Ruleset, Tweak, UserId,
+        PUSH_ACTIONS_UNSIGNED_KEY,
+    };
+    use serde_json::json;
+
+    fn sync_event_with_actions(body: &str) -> serde_json::Value {
+        let user = UserId::parse("@alice:example.org").unwrap();
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let power_levels = RoomPowerLevelsEventContent::default();
+        let ruleset = Ruleset::server_default(&user);
+        let ctx = PushConditionRoomCtx {
+            room_id,
+            member_count: 10_u32.into(),
+            user_display_name: "alice".to_owned(),
+            users_power_levels: power_levels.users,
+            default_power_level: power_levels.users_default,
+            notification_power_levels: power_levels.notifications,
+        };
+
+        let event: Raw<AnySyncRoomEvent> = serde_json::from_value(json!({
+            "content": { "msgtype": "m.text", "body": body },
+            "type": "m.room.message",
+            "event_id": "$event:example.org",
+            "sender": "@bob:example.org",
+            "origin_server_ts": 1,
+            "unsigned": null,
+        }))
+        .unwrap();
+
+        let actions = ruleset.get_actions(&event, &ctx).to_vec();
+        let event = with_push_actions(event, &actions);
+
+        serde_json::from_str::<serde_json::Value>(event.json().get()).unwrap()["unsigned"]
+            [PUSH_ACTIONS_UNSIGNED_KEY]
+            .clone()
+    }
+
+    fn highlights(actions: serde_json::Value) -> bool {
+        serde_json::from_value::<Vec<Action>>(actions)
+            .unwrap()
+            .iter()
+            .any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))))
+    }
+
+    #[test]
+    fn configured_mention_actions_are_applied() {
+        use super::{DefaultPushActions, PushActionOverrides};
+
+        let user = UserId::parse("@alice:example.org").unwrap();
+        let overrides = PushActionOverrides::from_config(&DefaultPushActions {
+            contains_display_name: Some(json!([
+                "notify",
+                { "set_tweak": "sound", "value": "ping" },
+            ])),
+            roomnotif: None,
+            keyword: Some(json!(["dont_notify"])),
+        })
+        .unwrap();
+
+        let ruleset = overrides.apply(Ruleset::server_default(&user));
+
+        let mention = ruleset
+            .override_
+            .iter()
+            .find(|rule| rule.rule_id == ".m.rule.contains_display_name")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&mention.actions).unwrap(),
+            json!(["notify", { "set_tweak": "sound", "value": "ping" }])
+        );
+        assert!(ruleset
+            .content
+            .iter()
+            .all(|rule| matches!(rule.actions[..], [Action::DontNotify])));
+    }
+
+    #[test]
+    fn invalid_push_actions_are_rejected() {
+        use super::{DefaultPushActions, PushActionOverrides};
+
+        assert!(PushActionOverrides::from_config(&DefaultPushActions {
+            contains_display_name: None,
+            roomnotif: Some(json!("notify")),
+            keyword: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn mention_carries_highlight_push_action() {
+        assert!(highlights(sync_event_with_actions(
+            "hey alice, look at this"
+        )));
+    }
+
+    #[test]
+    fn normal_message_has_no_highlight_push_action() {
+        let actions = sync_event_with_actions("just a normal message");
+        assert!(actions.is_array());
+        assert!(!highlights(actions));
+    }
+
+    #[test]
+    fn unmuted_room_keeps_push_rule_result() {
+        assert_eq!(apply_room_mute(None, true, false), (true, false));
+        assert_eq!(apply_room_mute(None, true, true), (true, true));
+    }
+
+    #[test]
+    fn muted_room_only_notifies_for_highlights() {
+        // A normal message doesn't count as a notification
+        assert_eq!(
+            apply_room_mute(Some(RoomMute::Mentions), true, false),
+            (false, false)
+        );
+        // A direct mention still notifies and highlights
+        assert_eq!(
+            apply_room_mute(Some(RoomMute::Mentions), true, true),
+            (true, true)
+        );
+    }
+
+    #[test]
+    fn fully_muted_room_never_notifies() {
+        assert_eq!(
+            apply_room_mute(Some(RoomMute::All), true, true),
+            (false, false)
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn muted_rooms_count_only_mentions() {
+        use super::MUTED_ROOMS_EVENT_TYPE;
+        use crate::database::{test_room, test_send};
+        use ruma::{room_id, user_id};
+        use serde_json::json;
+
+        let db = crate::database::test_database("muted-rooms").await;
+        let db = db.read().await;
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let room_id = room_id!("!room:example.org");
+
+        // Deactivated users aren't notified
+        db.users
+            .create(alice, Some("password"), &db.globals)
+            .unwrap();
+        test_room(&db, room_id, alice).await;
+        test_send(
+            &db,
+            room_id,
+            alice,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "public" }),
+        )
+        .await
+        .unwrap();
+        test_send(
+            &db,
+            room_id,
+            bob,
+            "m.room.member",
+            Some(bob.as_str()),
+            json!({ "membership": "join" }),
+        )
+        .await
+        .unwrap();
+
+        let mute = |mute: &str| {
+            db.account_data
+                .update(
+                    None,
+                    alice,
+                    MUTED_ROOMS_EVENT_TYPE.to_owned().into(),
+                    &json!({
+                        "type": MUTED_ROOMS_EVENT_TYPE,
+                        "content": { "rooms": { "!room:example.org": mute } },
+                    }),
+                    &db.globals,
+                )
+                .unwrap()
+        };
+        let send = |body: &'static str| {
+            test_send(
+                &db,
+                room_id,
+                bob,
+                "m.room.message",
+                None,
+                json!({ "msgtype": "m.text", "body": body }),
+            )
+        };
+        let counts = || {
+            (
+                db.rooms.notification_count(alice, room_id).unwrap(),
+                db.rooms.highlight_count(alice, room_id).unwrap(),
+            )
+        };
+
+        mute("mentions");
+        send("hello").await.unwrap();
+        assert_eq!(counts(), (0, 0));
+
+        send("hello alice").await.unwrap();
+        assert_eq!(counts(), (1, 1));
+
+        mute("all");
+        send("alice?").await.unwrap();
+        assert_eq!(counts(), (1, 1));
+    }
+}
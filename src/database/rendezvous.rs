@@ -0,0 +1,253 @@
+use std::{
+    convert::TryInto,
+    sync::{Arc, Mutex},
+};
+
+use crate::{utils, Error, Result};
+use ruma::api::client::error::ErrorKind;
+
+use super::abstraction::Tree;
+
+/// Largest payload a rendezvous session may hold. Login data is small, anything bigger is abuse.
+pub const MAX_PAYLOAD_SIZE: usize = 4096;
+
+/// Sessions that can be open at the same time. Creating sessions needs no account, so their
+/// number has to be limited.
+pub const MAX_SESSIONS: usize = 10_000;
+
+/// A short-lived, server-held channel two devices use to exchange login data.
+pub struct Rendezvous {
+    pub(super) sessionid_payload: Arc<dyn Tree>, // Payload = ExpiresAt + ETag + Data
+    pub(super) expiresat_sessionid: Arc<dyn Tree>, // ExpiresAtSessionId = ExpiresAt + SessionId
+    /// Makes checking and replacing the ETag of a session one step.
+    pub(super) update_mutex: Mutex<()>,
+}
+
+/// A rendezvous session that has not expired yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RendezvousSession {
+    pub expires_at: u64,
+    pub etag: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Rendezvous {
+    /// Stores a new session. Expired sessions are purged at the same time.
+    pub fn create(
+        &self,
+        session_id: &str,
+        payload: &[u8],
+        etag: u64,
+        expires_at: u64,
+        now: u64,
+    ) -> Result<()> {
+        check_payload_size(payload)?;
+        self.purge_expired(now)?;
+
+        if self.expiresat_sessionid.iter().take(MAX_SESSIONS).count() >= MAX_SESSIONS {
+            return Err(Error::BadRequest(
+                ErrorKind::LimitExceeded {
+                    retry_after_ms: None,
+                },
+                "Too many rendezvous sessions are open.",
+            ));
+        }
+
+        self.sessionid_payload.insert(
+            session_id.as_bytes(),
+            &encode_session(expires_at, etag, payload),
+        )?;
+        self.expiresat_sessionid
+            .insert(&expires_at_key(expires_at, session_id), &[])
+    }
+
+    /// Returns the session if it exists and has not expired yet.
+    pub fn get(&self, session_id: &str, now: u64) -> Result<Option<RendezvousSession>> {
+        let session = match self.sessionid_payload.get(session_id.as_bytes())? {
+            Some(bytes) => decode_session(&bytes)?,
+            None => return Ok(None),
+        };
+
+        if session.expires_at <= now {
+            self.remove_session(session_id, session.expires_at)?;
+            return Ok(None);
+        }
+
+        Ok(Some(session))
+    }
+
+    /// Replaces the payload of a session, but only if the caller saw the latest version.
+    pub fn update(
+        &self,
+        session_id: &str,
+        expected_etag: u64,
+        new_etag: u64,
+        payload: &[u8],
+        now: u64,
+    ) -> Result<()> {
+        check_payload_size(payload)?;
+
+        let _lock = self.update_mutex.lock().unwrap();
+
+        let session = self.get(session_id, now)?.ok_or(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Rendezvous session not found or expired.",
+        ))?;
+
+        if session.etag != expected_etag {
+            return Err(Error::Conflict(
+                "Rendezvous session was modified concurrently.",
+            ));
+        }
+
+        self.sessionid_payload.insert(
+            session_id.as_bytes(),
+            &encode_session(session.expires_at, new_etag, payload),
+        )
+    }
+
+    pub fn remove(&self, session_id: &str) -> Result<()> {
+        let _lock = self.update_mutex.lock().unwrap();
+
+        if let Some(bytes) = self.sessionid_payload.get(session_id.as_bytes())? {
+            self.remove_session(session_id, decode_session(&bytes)?.expires_at)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_session(&self, session_id: &str, expires_at: u64) -> Result<()> {
+        self.sessionid_payload.remove(session_id.as_bytes())?;
+        self.expiresat_sessionid
+            .remove(&expires_at_key(expires_at, session_id))
+    }
+
+    /// Removes the sessions that expired, they are ordered by their expiry time.
+    fn purge_expired(&self, now: u64) -> Result<()> {
+        let expired = self
+            .expiresat_sessionid
+            .iter()
+            .take_while(|(key, _)| {
+                key.get(..8)
+                    .and_then(|expires_at| utils::u64_from_bytes(expires_at).ok())
+                    .map_or(true, |expires_at| expires_at <= now)
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            self.expiresat_sessionid.remove(&key)?;
+            if let Some(session_id) = key.get(8..) {
+                self.sessionid_payload.remove(session_id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Generates an id for a new session.
+pub fn new_session_id() -> String {
+    utils::random_string(32)
+}
+
+fn check_payload_size(payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(Error::BadRequest(
+            ErrorKind::TooLarge,
+            "Rendezvous payload is too large.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn expires_at_key(expires_at: u64, session_id: &str) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(session_id.as_bytes());
+    key
+}
+
+fn encode_session(expires_at: u64, etag: u64, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = expires_at.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&etag.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn decode_session(bytes: &[u8]) -> Result<RendezvousSession> {
+    let invalid = || Error::bad_database("Invalid rendezvous session in db.");
+
+    if bytes.len() < 16 {
+        return Err(invalid());
+    }
+
+    Ok(RendezvousSession {
+        expires_at: u64::from_be_bytes(bytes[..8].try_into().map_err(|_| invalid())?),
+        etag: u64::from_be_bytes(bytes[8..16].try_into().map_err(|_| invalid())?),
+        payload: bytes[16..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_session, encode_session, RendezvousSession};
+
+    #[test]
+    fn session_roundtrip() {
+        assert_eq!(
+            decode_session(&encode_session(1000, 7, b"login")).unwrap(),
+            RendezvousSession {
+                expires_at: 1000,
+                etag: 7,
+                payload: b"login".to_vec(),
+            }
+        );
+        assert!(decode_session(b"short").is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn channel_expires() {
+        use super::{Rendezvous, MAX_PAYLOAD_SIZE};
+        use crate::{
+            database::abstraction::{sqlite::test_engine, DatabaseEngine},
+            Error,
+        };
+        use std::sync::Mutex;
+
+        let (engine, config) = test_engine("rendezvous");
+        let rendezvous = Rendezvous {
+            sessionid_payload: engine.open_tree("rendezvous").unwrap(),
+            expiresat_sessionid: engine.open_tree("rendezvous_expiresat").unwrap(),
+            update_mutex: Mutex::new(()),
+        };
+
+        rendezvous.create("abc", b"", 1, 1000, 0).unwrap();
+        rendezvous.update("abc", 1, 2, b"login data", 10).unwrap();
+        assert_eq!(
+            rendezvous.get("abc", 20).unwrap().unwrap().payload,
+            b"login data".to_vec()
+        );
+        assert!(matches!(
+            rendezvous.update("abc", 1, 3, b"stale", 30),
+            Err(Error::Conflict(_))
+        ));
+        assert!(rendezvous
+            .update("abc", 2, 3, &[0; MAX_PAYLOAD_SIZE + 1], 30)
+            .is_err());
+
+        assert_eq!(rendezvous.get("abc", 1000).unwrap(), None);
+        assert!(rendezvous.update("abc", 2, 3, b"late", 1000).is_err());
+
+        // Creating a session purges the expired ones
+        rendezvous.create("def", b"", 4, 2000, 0).unwrap();
+        rendezvous.create("ghi", b"", 5, 3000, 2000).unwrap();
+        assert!(rendezvous.sessionid_payload.get(b"def").unwrap().is_none());
+        assert_eq!(rendezvous.expiresat_sessionid.iter().count(), 1);
+
+        drop(rendezvous);
+        drop(engine);
+        std::fs::remove_dir_all(&config.database_path).unwrap();
+    }
+}
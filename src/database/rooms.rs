@@ -15,22 +15,28 @@ use ruma::{
         direct::DirectEvent,
         ignored_user_list::IgnoredUserListEvent,
         push_rules::PushRulesEvent,
+        receipt::{Receipt, ReceiptEvent, ReceiptEventContent},
         room::{
             create::RoomCreateEventContent,
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
+            history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
             member::{MembershipState, RoomMemberEventContent},
             power_levels::RoomPowerLevelsEventContent,
+            server_acl::RoomServerAclEventContent,
+            tombstone::RoomTombstoneEventContent,
         },
         tag::TagEvent,
         AnyStrippedStateEvent, AnySyncStateEvent, GlobalAccountDataEventType,
         RoomAccountDataEventType, RoomEventType, StateEventType,
     },
     push::{Action, Ruleset, Tweak},
+    receipt::ReceiptType,
     serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
     state_res::{self, RoomVersion, StateMap},
-    uint, DeviceId, EventId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
+    uint, DeviceId, EventId, Int, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
-use serde::Deserialize;
-use serde_json::value::to_raw_value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value};
 use std::{
     borrow::Cow,
     collections::{hash_map, BTreeMap, HashMap, HashSet},
@@ -38,8 +44,9 @@ use std::{
     iter,
     mem::size_of,
     sync::{Arc, Mutex, RwLock},
+    time::Instant,
 };
-use tokio::sync::MutexGuard;
+use tokio::sync::{watch, MutexGuard};
 use tracing::{error, warn};
 
 use super::{abstraction::Tree, pusher};
@@ -51,6 +58,13 @@ use super::{abstraction::Tree, pusher};
 pub type StateHashId = Vec<u8>;
 pub type CompressedStateEvent = [u8; 2 * size_of::<u64>()];
 
+/// Longer words of messages aren't added to the search index.
+const MAX_SEARCH_WORD_LENGTH: usize = 50;
+
+/// How many of the latest references of an event are bundled with it. Annotations are always
+/// counted in full.
+const MAX_BUNDLED_RELATIONS: usize = 100;
+
 pub struct Rooms {
     pub edus: RoomEdus,
     pub(super) pduid_pdu: Arc<dyn Tree>, // PduId = ShortRoomId + Count
@@ -58,7 +72,9 @@ pub struct Rooms {
     pub(super) roomid_pduleaves: Arc<dyn Tree>,
     pub(super) alias_roomid: Arc<dyn Tree>,
     pub(super) aliasid_alias: Arc<dyn Tree>, // AliasId = RoomId + Count
+    pub(super) alias_userid: Arc<dyn Tree>,  // The user who created the alias
     pub(super) publicroomids: Arc<dyn Tree>,
+    pub(super) reportid_report: Arc<dyn Tree>, // ReportId = Count, Report as json
 
     pub(super) tokenids: Arc<dyn Tree>, // TokenId = ShortRoomId + Token + PduIdCount
 
@@ -75,6 +91,8 @@ pub struct Rooms {
     pub(super) roomuserid_invitecount: Arc<dyn Tree>, // InviteCount = Count
     pub(super) userroomid_leftstate: Arc<dyn Tree>,
     pub(super) roomuserid_leftcount: Arc<dyn Tree>,
+    pub(super) userroomid_knockstate: Arc<dyn Tree>, // KnockState = Vec<Raw<Pdu>>
+    pub(super) roomuserid_knockcount: Arc<dyn Tree>, // KnockCount = Count
 
     pub(super) lazyloadedids: Arc<dyn Tree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
@@ -108,6 +126,12 @@ pub struct Rooms {
     /// RoomId + EventId -> Parent PDU EventId.
     pub(super) referencedevents: Arc<dyn Tree>,
 
+    /// Events with an `m.relates_to`, for bundled aggregations.
+    pub(super) relatedeventid_pduid: Arc<dyn Tree>, // RelatedEventId = EventId + RelatingPduId
+
+    /// Rooms we joined before knowing their full state.
+    pub(super) roomid_partialstate: Arc<dyn Tree>, // PartialState = ServerName + JoinEventId
+
     pub(super) pdu_cache: Mutex<LruCache<Box<EventId>, Arc<PduEvent>>>,
     pub(super) shorteventid_cache: Mutex<LruCache<u64, Arc<EventId>>>,
     pub(super) auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<HashSet<u64>>>>,
@@ -130,6 +154,61 @@ pub struct Rooms {
         >,
     >,
     pub(super) lasttimelinecount_cache: Mutex<HashMap<Box<RoomId>, u64>>,
+    /// RoomId -> (ShortStateHash the entry was loaded at, parsed m.room.server_acl content)
+    pub(super) server_acl_cache: RwLock<HashMap<Box<RoomId>, CachedServerAcl>>,
+    /// RoomId -> running background fill of the room state, dropped once it is done
+    pub(super) partial_state_fills:
+        RwLock<HashMap<Box<RoomId>, (watch::Sender<()>, watch::Receiver<()>)>>,
+}
+
+/// An event a user reported to the server admins.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Report {
+    pub reporter: Box<UserId>,
+    pub room_id: Box<RoomId>,
+    pub event_id: Box<EventId>,
+    /// From -100 (most offensive) to 0 (inoffensive)
+    pub score: Option<Int>,
+    pub reason: Option<String>,
+    /// Milliseconds since the unix epoch
+    pub reported_at: u64,
+}
+
+type CachedServerAcl = (u64, Option<Arc<RoomServerAclEventContent>>);
+
+/// Returns the cached ACL of a room if it was loaded at the current state of the room.
+fn cached_server_acl(
+    cache: &HashMap<Box<RoomId>, CachedServerAcl>,
+    room_id: &RoomId,
+    current_shortstatehash: u64,
+) -> Option<Option<Arc<RoomServerAclEventContent>>> {
+    cache
+        .get(room_id)
+        .filter(|(shortstatehash, _)| *shortstatehash == current_shortstatehash)
+        .map(|(_, acl)| acl.clone())
+}
+
+/// Whether replacing the `current` member event of a user with `new` would change nothing.
+///
+/// Joins are only redundant if the profile stays the same, clients rejoin to update it.
+fn membership_change_is_noop(
+    current: Option<&RoomMemberEventContent>,
+    new: &RoomMemberEventContent,
+) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => return false,
+    };
+
+    match (&current.membership, &new.membership) {
+        (MembershipState::Leave, MembershipState::Leave) => true,
+        (MembershipState::Join, MembershipState::Join) => {
+            current.displayname == new.displayname
+                && current.avatar_url == new.avatar_url
+                && current.blurhash == new.blurhash
+        }
+        _ => false,
+    }
 }
 
 impl Rooms {
@@ -186,6 +265,98 @@ impl Rooms {
             .collect())
     }
 
+    /// Returns the current guest access of the room, guests are forbidden if it is not set.
+    #[tracing::instrument(skip(self))]
+    pub fn guest_access(&self, room_id: &RoomId) -> Result<GuestAccess> {
+        self.room_state_get(room_id, &StateEventType::RoomGuestAccess, "")?
+            .map_or(Ok(GuestAccess::Forbidden), |pdu| {
+                serde_json::from_str::<RoomGuestAccessEventContent>(pdu.content.get())
+                    .map(|content| content.guest_access)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room guest access event in database.")
+                    })
+            })
+    }
+
+    /// Returns the current history visibility of the room, which defaults to shared.
+    #[tracing::instrument(skip(self))]
+    pub fn history_visibility(&self, room_id: &RoomId) -> Result<HistoryVisibility> {
+        self.room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(HistoryVisibility::Shared), |pdu| {
+                serde_json::from_str::<RoomHistoryVisibilityEventContent>(pdu.content.get())
+                    .map(|content| content.history_visibility)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room history visibility event in database.")
+                    })
+            })
+    }
+
+    /// Returns the join rule of the room as a string, our ruma version does not know all join
+    /// rules (e.g. `knock_restricted`) yet.
+    #[tracing::instrument(skip(self))]
+    pub fn join_rule(&self, room_id: &RoomId) -> Result<Option<String>> {
+        Ok(self
+            .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+            .and_then(|pdu| {
+                serde_json::from_str::<serde_json::Value>(pdu.content.get())
+                    .ok()?
+                    .get("join_rule")?
+                    .as_str()
+                    .map(ToOwned::to_owned)
+            }))
+    }
+
+    /// Whether guests may read the room: it must allow guests to join or be world readable.
+    #[tracing::instrument(skip(self))]
+    pub fn guest_can_read(&self, room_id: &RoomId) -> Result<bool> {
+        Ok(self.guest_access(room_id)? == GuestAccess::CanJoin
+            || self.history_visibility(room_id)? == HistoryVisibility::WorldReadable)
+    }
+
+    /// Whether the user may see an event, according to the history visibility and their
+    /// membership at that event.
+    #[tracing::instrument(skip(self))]
+    pub fn user_can_see_event(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        let shortstatehash = match self.pdu_shortstatehash(event_id)? {
+            Some(shortstatehash) => shortstatehash,
+            None => return Ok(false),
+        };
+
+        let history_visibility = self
+            .state_get(shortstatehash, &StateEventType::RoomHistoryVisibility, "")?
+            .map_or(Ok(HistoryVisibility::Shared), |pdu| {
+                serde_json::from_str::<RoomHistoryVisibilityEventContent>(pdu.content.get())
+                    .map(|content| content.history_visibility)
+                    .map_err(|_| {
+                        Error::bad_database("Invalid room history visibility event in database.")
+                    })
+            })?;
+
+        let membership = self
+            .state_get(
+                shortstatehash,
+                &StateEventType::RoomMember,
+                user_id.as_str(),
+            )?
+            .map(|pdu| {
+                serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                    .map(|content| content.membership)
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))
+            })
+            .transpose()?;
+
+        Ok(event_visible(
+            &history_visibility,
+            membership.as_ref(),
+            self.is_joined(user_id, room_id)?,
+        ))
+    }
+
     /// Returns a single PDU from `room_id` with key (`event_type`, `state_key`).
     #[tracing::instrument(skip(self))]
     pub fn state_get_id(
@@ -256,6 +427,51 @@ impl Rooms {
             })
     }
 
+    /// Returns the parsed m.room.server_acl content of the current room state.
+    ///
+    /// The result is cached until the state of the room changes. Invalid ACL events are treated
+    /// like a missing one.
+    #[tracing::instrument(skip(self))]
+    pub fn server_acl(&self, room_id: &RoomId) -> Result<Option<Arc<RoomServerAclEventContent>>> {
+        let current_shortstatehash = match self.current_shortstatehash(room_id)? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        if let Some(acl) = cached_server_acl(
+            &self.server_acl_cache.read().unwrap(),
+            room_id,
+            current_shortstatehash,
+        ) {
+            return Ok(acl);
+        }
+
+        let acl = self
+            .state_get(current_shortstatehash, &StateEventType::RoomServerAcl, "")?
+            .and_then(|acl_event| {
+                serde_json::from_str::<RoomServerAclEventContent>(acl_event.content.get())
+                    .map_err(|_| warn!("Invalid ACL event in room {}", room_id))
+                    .ok()
+            })
+            .map(Arc::new);
+
+        self.server_acl_cache
+            .write()
+            .unwrap()
+            .insert(room_id.to_owned(), (current_shortstatehash, acl.clone()));
+
+        Ok(acl)
+    }
+
+    /// Drops the cached server ACL of a room. Returns true if there was a cached entry.
+    pub fn invalidate_server_acl_cache(&self, room_id: &RoomId) -> bool {
+        self.server_acl_cache
+            .write()
+            .unwrap()
+            .remove(room_id)
+            .is_some()
+    }
+
     /// This fetches auth events from the current state.
     #[tracing::instrument(skip(self))]
     pub fn get_auth_events(
@@ -457,6 +673,8 @@ impl Rooms {
         self.roomid_shortstatehash
             .insert(room_id.as_bytes(), &new_shortstatehash.to_be_bytes())?;
 
+        self.invalidate_server_acl_cache(room_id);
+
         Ok(())
     }
 
@@ -949,6 +1167,40 @@ impl Rooms {
         }
     }
 
+    /// Returns the current state events of one type. Only events of that type are loaded.
+    #[tracing::instrument(skip(self))]
+    pub fn room_state_type(
+        &self,
+        room_id: &RoomId,
+        event_type: &StateEventType,
+    ) -> Result<Vec<Arc<PduEvent>>> {
+        let current_shortstatehash = match self.current_shortstatehash(room_id)? {
+            Some(current_shortstatehash) => current_shortstatehash,
+            None => return Ok(Vec::new()),
+        };
+        let full_state = self
+            .load_shortstatehash_info(current_shortstatehash)?
+            .pop()
+            .expect("there is always one layer")
+            .1;
+
+        let mut pdus = Vec::new();
+        for compressed in full_state {
+            let shortstatekey = utils::u64_from_bytes(&compressed[0..size_of::<u64>()])
+                .expect("bytes have right length");
+            if self.get_statekey_from_short(shortstatekey)?.0 != *event_type {
+                continue;
+            }
+
+            let (_, event_id) = self.parse_compressed_state_event(compressed)?;
+            if let Some(pdu) = self.get_pdu(&event_id)? {
+                pdus.push(pdu);
+            }
+        }
+
+        Ok(pdus)
+    }
+
     /// Returns a single PDU from `room_id` with key (`event_type`, `state_key`).
     #[tracing::instrument(skip(self))]
     pub fn room_state_get_id(
@@ -964,6 +1216,127 @@ impl Rooms {
         }
     }
 
+    /// Returns the room that replaced this one, if it was tombstoned.
+    #[tracing::instrument(skip(self))]
+    pub fn tombstone_replacement(&self, room_id: &RoomId) -> Result<Option<Box<RoomId>>> {
+        self.room_state_get(room_id, &StateEventType::RoomTombstone, "")?
+            .map(|pdu| {
+                serde_json::from_str::<RoomTombstoneEventContent>(pdu.content.get())
+                    .map(|content| content.replacement_room)
+                    .map_err(|_| Error::bad_database("Invalid tombstone event in database."))
+            })
+            .transpose()
+    }
+
+    /// Remembers that we joined the room before knowing all of its state. The rest can be fetched
+    /// from `server` at our join event.
+    #[tracing::instrument(skip(self))]
+    pub fn mark_partial_state(
+        &self,
+        room_id: &RoomId,
+        server: &ServerName,
+        join_event_id: &EventId,
+    ) -> Result<()> {
+        let mut value = server.as_bytes().to_vec();
+        value.push(0xff);
+        value.extend_from_slice(join_event_id.as_bytes());
+
+        self.roomid_partialstate.insert(room_id.as_bytes(), &value)
+    }
+
+    /// Returns the server and join event the missing state can be fetched with, if we don't
+    /// know the full state of the room yet.
+    #[tracing::instrument(skip(self))]
+    pub fn partial_state(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<(Box<ServerName>, Box<EventId>)>> {
+        self.roomid_partialstate
+            .get(room_id.as_bytes())?
+            .map(|bytes| {
+                let mut parts = bytes.splitn(2, |&b| b == 0xff);
+                let server = utils::string_from_bytes(parts.next().expect("splitn returns one"))
+                    .ok()
+                    .and_then(|s| ServerName::parse(s).ok());
+                let event_id = parts
+                    .next()
+                    .and_then(|bytes| utils::string_from_bytes(bytes).ok())
+                    .and_then(|s| EventId::parse(s).ok());
+
+                server
+                    .zip(event_id)
+                    .ok_or_else(|| Error::bad_database("Invalid entry in roomid_partialstate."))
+            })
+            .transpose()
+    }
+
+    /// Returns all rooms we don't know the full state of yet.
+    #[tracing::instrument(skip(self))]
+    pub fn partial_state_rooms<'a>(&'a self) -> impl Iterator<Item = Result<Box<RoomId>>> + 'a {
+        self.roomid_partialstate.iter().map(|(key, _)| {
+            RoomId::parse(
+                utils::string_from_bytes(&key).map_err(|_| {
+                    Error::bad_database("Room ID in roomid_partialstate is invalid.")
+                })?,
+            )
+            .map_err(|_| Error::bad_database("Room ID in roomid_partialstate is invalid."))
+        })
+    }
+
+    /// Records that the room state is complete now.
+    #[tracing::instrument(skip(self))]
+    pub fn mark_full_state(&self, room_id: &RoomId) -> Result<()> {
+        self.roomid_partialstate.remove(room_id.as_bytes())
+    }
+
+    /// Registers a background fill of the room state. Returns false if one is already running.
+    pub fn start_state_fill(&self, room_id: &RoomId) -> bool {
+        match self
+            .partial_state_fills
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+        {
+            hash_map::Entry::Occupied(_) => false,
+            hash_map::Entry::Vacant(v) => {
+                v.insert(watch::channel(()));
+                true
+            }
+        }
+    }
+
+    /// Unregisters the background fill of the room state and wakes everyone waiting for it.
+    pub fn finish_state_fill(&self, room_id: &RoomId) {
+        self.partial_state_fills.write().unwrap().remove(room_id);
+    }
+
+    /// Waits until the running background fill of the room state, if any, is done.
+    ///
+    /// Events of other users can only be authorised once their membership is known, so this
+    /// fails if the state is still partial afterwards, e.g. because the fill failed. Failed fills
+    /// are retried by the cleanup task.
+    pub async fn wait_for_full_state(&self, room_id: &RoomId) -> Result<()> {
+        let receiver = self
+            .partial_state_fills
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|(_, receiver)| receiver.clone());
+
+        if let Some(mut receiver) = receiver {
+            // Nothing is ever sent, this returns once the sender is dropped
+            let _ = receiver.changed().await;
+        }
+
+        if self.partial_state(room_id)?.is_some() {
+            return Err(Error::BadServerResponse(
+                "The state of the room is not fully known yet, try again later.",
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Returns a single PDU from `room_id` with key (`event_type`, `state_key`).
     #[tracing::instrument(skip(self))]
     pub fn room_state_get(
@@ -1196,6 +1569,63 @@ impl Rooms {
         Ok(())
     }
 
+    /// Remembers which event the pdu relates to, so the relation can be bundled with it.
+    #[tracing::instrument(skip(self, pdu, pdu_id))]
+    fn index_relation(&self, pdu: &PduEvent, pdu_id: &[u8]) -> Result<()> {
+        if let Some(relation) = relation_of(pdu) {
+            let mut key = relation.event_id.as_bytes().to_vec();
+            key.push(0xff);
+            key.extend_from_slice(pdu_id);
+            self.relatedeventid_pduid.insert(&key, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes the relations of all events in the timeline. Only needed for events that were
+    /// stored before relations were indexed.
+    pub fn index_relations(&self) -> Result<()> {
+        for (pdu_id, pdu) in self.pduid_pdu.iter() {
+            let pdu = serde_json::from_slice::<PduEvent>(&pdu)
+                .map_err(|_| Error::bad_database("PDU in db is invalid."))?;
+            self.index_relation(&pdu, &pdu_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds the aggregated relations of an event to its `unsigned.m.relations` field.
+    #[tracing::instrument(skip(self, pdu))]
+    pub fn bundle_relations(&self, mut pdu: PduEvent) -> Result<PduEvent> {
+        let mut prefix = pdu.event_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        // Pdu ids sort by timeline position, so walking backwards starts at the latest relation
+        let mut last = prefix.clone();
+        last.extend_from_slice(&[0xff; 2 * size_of::<u64>()]);
+
+        let relations = self
+            .relatedeventid_pduid
+            .iter_from(&last, true)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, _)| self.get_pdu_from_id(&key[prefix.len()..]).ok().flatten())
+            .map(Arc::new);
+
+        if let Some(aggregations) = aggregate_relations(&pdu, relations) {
+            let mut unsigned: BTreeMap<String, serde_json::Value> = pdu
+                .unsigned
+                .as_ref()
+                .map(|unsigned| serde_json::from_str(unsigned.get()))
+                .transpose()
+                .map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?
+                .unwrap_or_default();
+            unsigned.insert("m.relations".to_owned(), aggregations);
+            pdu.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+        }
+
+        Ok(pdu)
+    }
+
     /// Replace the leaves of a room.
     ///
     /// The provided `event_ids` become the new leaves, this allows a room to have multiple
@@ -1347,6 +1777,7 @@ impl Rooms {
         self.eventid_pduid
             .insert(pdu.event_id.as_bytes(), &pdu_id)?;
         self.eventid_outlierpdu.remove(pdu.event_id.as_bytes())?;
+        self.index_relation(pdu, &pdu_id)?;
 
         drop(insert_lock);
 
@@ -1404,6 +1835,12 @@ impl Rooms {
                 };
             }
 
+            let (notify, highlight) = pusher::apply_room_mute(
+                pusher::room_mute(user, &pdu.room_id, db),
+                notify,
+                highlight,
+            );
+
             let mut userroom_id = user.as_bytes().to_vec();
             userroom_id.push(0xff);
             userroom_id.extend_from_slice(pdu.room_id.as_bytes());
@@ -1447,7 +1884,7 @@ impl Rooms {
                         .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
 
                     let invite_state = match content.membership {
-                        MembershipState::Invite => {
+                        MembershipState::Invite | MembershipState::Knock => {
                             let state = self.calculate_invite_state(pdu)?;
                             Some(state)
                         }
@@ -1478,18 +1915,13 @@ impl Rooms {
                     .map_err(|_| Error::bad_database("Invalid content in pdu."))?;
 
                 if let Some(body) = content.body {
-                    let mut batch = body
-                        .split_terminator(|c: char| !c.is_alphanumeric())
-                        .filter(|s| !s.is_empty())
-                        .filter(|word| word.len() <= 50)
-                        .map(str::to_lowercase)
-                        .map(|word| {
-                            let mut key = shortroomid.to_be_bytes().to_vec();
-                            key.extend_from_slice(word.as_bytes());
-                            key.push(0xff);
-                            key.extend_from_slice(&pdu_id);
-                            (key, Vec::new())
-                        });
+                    let mut batch = search_words(&body).into_iter().map(|word| {
+                        let mut key = shortroomid.to_be_bytes().to_vec();
+                        key.extend_from_slice(word.as_bytes());
+                        key.push(0xff);
+                        key.extend_from_slice(&pdu_id);
+                        (key, Vec::new())
+                    });
 
                     self.tokenids.insert_batch(&mut batch)?;
 
@@ -1563,6 +1995,55 @@ impl Rooms {
         Ok(())
     }
 
+    /// Moves the public read receipt of a user to `event_id`.
+    ///
+    /// Returns false and keeps the current receipt if it already points to the same or a newer
+    /// event.
+    #[tracing::instrument(skip(self, receipt, globals))]
+    pub fn read_receipt_set(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+        receipt: Receipt,
+        globals: &super::globals::Globals,
+    ) -> Result<bool> {
+        let count = match self.get_pdu_count(event_id)? {
+            Some(count) => count,
+            None => return Ok(false),
+        };
+
+        let old_count = match self.edus.readreceipt_event_id(room_id, user_id)? {
+            Some(old_event_id) => self.get_pdu_count(&old_event_id)?,
+            None => None,
+        };
+
+        if !edus::receipt_advances(old_count, count) {
+            return Ok(false);
+        }
+
+        let mut user_receipts = BTreeMap::new();
+        user_receipts.insert(user_id.to_owned(), receipt);
+
+        let mut receipts = BTreeMap::new();
+        receipts.insert(ReceiptType::Read, user_receipts);
+
+        let mut receipt_content = BTreeMap::new();
+        receipt_content.insert(event_id.to_owned(), receipts);
+
+        self.edus.readreceipt_update(
+            user_id,
+            room_id,
+            ReceiptEvent {
+                content: ReceiptEventContent(receipt_content),
+                room_id: room_id.to_owned(),
+            },
+            globals,
+        )?;
+
+        Ok(true)
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn notification_count(&self, user_id: &UserId, room_id: &RoomId) -> Result<u64> {
         let mut userroom_id = user_id.as_bytes().to_vec();
@@ -1758,12 +2239,15 @@ impl Rooms {
         {
             state.push(e.to_stripped_state_event());
         }
-        if let Some(e) = self.room_state_get(
-            &invite_event.room_id,
-            &StateEventType::RoomMember,
-            invite_event.sender.as_str(),
-        )? {
-            state.push(e.to_stripped_state_event());
+        // Knocks are sent by the target user, the event itself is added below
+        if invite_event.state_key.as_deref() != Some(invite_event.sender.as_str()) {
+            if let Some(e) = self.room_state_get(
+                &invite_event.room_id,
+                &StateEventType::RoomMember,
+                invite_event.sender.as_str(),
+            )? {
+                state.push(e.to_stripped_state_event());
+            }
         }
 
         state.push(invite_event.to_stripped_state_event());
@@ -1775,6 +2259,8 @@ impl Rooms {
         self.roomid_shortstatehash
             .insert(room_id.as_bytes(), &shortstatehash.to_be_bytes())?;
 
+        self.invalidate_server_acl_cache(room_id);
+
         Ok(())
     }
 
@@ -1809,7 +2295,8 @@ impl Rooms {
             .transpose()
     }
 
-    /// Creates a new persisted data unit and adds it to a room.
+    /// Creates a new persisted data unit and adds it to a room. Like all appended events, messages
+    /// are added to the search index.
     #[tracing::instrument(skip(self, db, _mutex_lock))]
     pub fn build_and_append_pdu(
         &self,
@@ -1819,6 +2306,8 @@ impl Rooms {
         db: &Database,
         _mutex_lock: &MutexGuard<'_, ()>, // Take mutex guard to make sure users get the room mutex
     ) -> Result<Arc<EventId>> {
+        let started = Instant::now();
+
         let PduBuilder {
             event_type,
             content,
@@ -1995,6 +2484,11 @@ impl Rooms {
         let mut servers: HashSet<Box<ServerName>> =
             self.room_servers(room_id).filter_map(|r| r.ok()).collect();
 
+        // Until we know all members, at least the server we joined through gets the event
+        if let Some((server, _)) = self.partial_state(room_id)? {
+            servers.insert(server);
+        }
+
         // In case we are kicking or banning a user, we need to inform their server of the change
         if pdu.kind == RoomEventType::RoomMember {
             if let Some(state_key_uid) = &pdu
@@ -2087,6 +2581,8 @@ impl Rooms {
             }
         }
 
+        db.globals.metrics.observe_pdu_build(started.elapsed());
+
         Ok(pdu.event_id)
     }
 
@@ -2237,7 +2733,7 @@ impl Rooms {
     ) -> Result<()> {
         // Keep track what remote users exist by adding them as "deactivated" users
         if user_id.server_name() != db.globals.server_name() {
-            db.users.create(user_id, None)?;
+            db.users.create(user_id, None, &db.globals)?;
             // TODO: displayname, avatar url
         }
 
@@ -2350,6 +2846,8 @@ impl Rooms {
                 self.roomuserid_invitecount.remove(&roomuser_id)?;
                 self.userroomid_leftstate.remove(&userroom_id)?;
                 self.roomuserid_leftcount.remove(&roomuser_id)?;
+                self.userroomid_knockstate.remove(&userroom_id)?;
+                self.roomuserid_knockcount.remove(&roomuser_id)?;
             }
             MembershipState::Invite => {
                 // We want to know if the sender is ignored by the receiver
@@ -2389,6 +2887,19 @@ impl Rooms {
                 self.roomuserid_joined.remove(&roomuser_id)?;
                 self.userroomid_leftstate.remove(&userroom_id)?;
                 self.roomuserid_leftcount.remove(&roomuser_id)?;
+                self.userroomid_knockstate.remove(&userroom_id)?;
+                self.roomuserid_knockcount.remove(&roomuser_id)?;
+            }
+            MembershipState::Knock => {
+                self.userroomid_knockstate.insert(
+                    &userroom_id,
+                    &serde_json::to_vec(&last_state.unwrap_or_default())
+                        .expect("state to bytes always works"),
+                )?;
+                self.roomuserid_knockcount
+                    .insert(&roomuser_id, &db.globals.next_count()?.to_be_bytes())?;
+                self.userroomid_leftstate.remove(&userroom_id)?;
+                self.roomuserid_leftcount.remove(&roomuser_id)?;
             }
             MembershipState::Leave | MembershipState::Ban => {
                 if update_joined_count
@@ -2411,6 +2922,11 @@ impl Rooms {
                 self.roomuserid_joined.remove(&roomuser_id)?;
                 self.userroomid_invitestate.remove(&userroom_id)?;
                 self.roomuserid_invitecount.remove(&roomuser_id)?;
+                self.userroomid_knockstate.remove(&userroom_id)?;
+                self.roomuserid_knockcount.remove(&roomuser_id)?;
+
+                // Users that left can't be typing anymore
+                self.edus.typing_remove(user_id, room_id, &db.globals)?;
             }
             _ => {}
         }
@@ -2590,11 +3106,15 @@ impl Rooms {
                 // Don't tell the client about this error
             }
 
-            let last_state = self
-                .invite_state(user_id, room_id)?
-                .map_or_else(|| self.left_state(user_id, room_id), |s| Ok(Some(s)))?;
+            let last_state = match self.invite_state(user_id, room_id)? {
+                Some(state) => Some(state),
+                None => match self.knock_state(user_id, room_id)? {
+                    Some(state) => Some(state),
+                    None => self.left_state(user_id, room_id)?,
+                },
+            };
 
-            // We always drop the invite, we can't rely on other servers
+            // We always drop the invite or knock, we can't rely on other servers
             self.update_membership(
                 room_id,
                 user_id,
@@ -2626,6 +3146,11 @@ impl Rooms {
             )
             .map_err(|_| Error::bad_database("Invalid member event in database."))?;
 
+            if event.membership == MembershipState::Leave {
+                // Already left, another leave event would only add noise to the room
+                return Ok(());
+            }
+
             event.membership = MembershipState::Leave;
 
             self.build_and_append_pdu(
@@ -2646,6 +3171,25 @@ impl Rooms {
         Ok(())
     }
 
+    /// Returns true if sending `new` as the member event of the user would change nothing.
+    #[tracing::instrument(skip(self, new))]
+    pub fn is_noop_membership_change(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        new: &RoomMemberEventContent,
+    ) -> Result<bool> {
+        let current = self
+            .room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
+            .map(|pdu| {
+                serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+                    .map_err(|_| Error::bad_database("Invalid member event in database."))
+            })
+            .transpose()?;
+
+        Ok(membership_change_is_noop(current.as_ref(), new))
+    }
+
     #[tracing::instrument(skip(self, db))]
     async fn remote_leave_room(
         &self,
@@ -2657,13 +3201,17 @@ impl Rooms {
             "No server available to assist in leaving.",
         ));
 
-        let invite_state = db
-            .rooms
-            .invite_state(user_id, room_id)?
-            .ok_or(Error::BadRequest(
-                ErrorKind::BadState,
-                "User is not invited.",
-            ))?;
+        // A knock can be retracted the same way an invite is rejected
+        let invite_state = match db.rooms.invite_state(user_id, room_id)? {
+            Some(invite_state) => invite_state,
+            None => db
+                .rooms
+                .knock_state(user_id, room_id)?
+                .ok_or(Error::BadRequest(
+                    ErrorKind::BadState,
+                    "User is not invited or knocking.",
+                ))?,
+        };
 
         let servers: HashSet<_> = invite_state
             .iter()
@@ -2672,6 +3220,8 @@ impl Rooms {
             .filter_map(|sender| sender.as_str().map(|s| s.to_owned()))
             .filter_map(|sender| UserId::parse(sender).ok())
             .map(|user| user.server_name().to_owned())
+            // Our own knock is part of the knock state
+            .filter(|server| &**server != db.globals.server_name())
             .collect();
 
         for remote_server in servers {
@@ -2798,10 +3348,16 @@ impl Rooms {
                 let mut prefix = room_id.to_vec();
                 prefix.push(0xff);
 
-                for (key, _) in self.aliasid_alias.scan_prefix(prefix) {
+                // Other aliases of the room stay
+                for (key, _) in self
+                    .aliasid_alias
+                    .scan_prefix(prefix)
+                    .filter(|(_, bytes)| bytes == alias.as_bytes())
+                {
                     self.aliasid_alias.remove(&key)?;
                 }
                 self.alias_roomid.remove(alias.alias().as_bytes())?;
+                self.alias_userid.remove(alias.alias().as_bytes())?;
             } else {
                 return Err(Error::BadRequest(
                     ErrorKind::NotFound,
@@ -2813,6 +3369,25 @@ impl Rooms {
         Ok(())
     }
 
+    /// Remembers who created an alias, they may delete it again.
+    pub fn set_alias_creator(&self, alias: &RoomAliasId, user_id: &UserId) -> Result<()> {
+        self.alias_userid
+            .insert(alias.alias().as_bytes(), user_id.as_bytes())
+    }
+
+    /// Returns the user who created an alias. Aliases created before this was tracked have none.
+    pub fn alias_creator(&self, alias: &RoomAliasId) -> Result<Option<Box<UserId>>> {
+        self.alias_userid
+            .get(alias.alias().as_bytes())?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in alias_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in alias_userid is invalid."))
+            })
+            .transpose()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn id_from_alias(&self, alias: &RoomAliasId) -> Result<Option<Box<RoomId>>> {
         self.alias_roomid
@@ -2870,6 +3445,64 @@ impl Rooms {
         })
     }
 
+    /// Stores a report and returns its id.
+    #[tracing::instrument(skip(self, globals))]
+    pub fn add_report(&self, report: &Report, globals: &super::globals::Globals) -> Result<u64> {
+        let report_id = globals.next_count()?;
+
+        self.reportid_report.insert(
+            &report_id.to_be_bytes(),
+            &serde_json::to_vec(report).expect("Report can be serialized"),
+        )?;
+
+        Ok(report_id)
+    }
+
+    /// Returns all reports, oldest first.
+    #[tracing::instrument(skip(self))]
+    pub fn reports(&self) -> impl Iterator<Item = Result<(u64, Report)>> + '_ {
+        self.reportid_report.iter().map(|(report_id, report)| {
+            Ok((
+                utils::u64_from_bytes(&report_id)
+                    .map_err(|_| Error::bad_database("Report id in reportid_report is invalid."))?,
+                serde_json::from_slice(&report)
+                    .map_err(|_| Error::bad_database("Invalid report in reportid_report."))?,
+            ))
+        })
+    }
+
+    /// Deletes a report. Returns false if it didn't exist.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_report(&self, report_id: u64) -> Result<bool> {
+        if self
+            .reportid_report
+            .get(&report_id.to_be_bytes())?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        self.reportid_report.remove(&report_id.to_be_bytes())?;
+        Ok(true)
+    }
+
+    /// Deletes all reports, also the ones that can't be read anymore. Returns how many were
+    /// deleted.
+    #[tracing::instrument(skip(self))]
+    pub fn clear_reports(&self) -> Result<usize> {
+        let report_ids = self
+            .reportid_report
+            .iter()
+            .map(|(report_id, _)| report_id)
+            .collect::<Vec<_>>();
+
+        for report_id in &report_ids {
+            self.reportid_report.remove(report_id)?;
+        }
+
+        Ok(report_ids.len())
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn search_pdus<'a>(
         &'a self,
@@ -2883,11 +3516,7 @@ impl Rooms {
             .to_vec();
         let prefix_clone = prefix.clone();
 
-        let words: Vec<_> = search_string
-            .split_terminator(|c: char| !c.is_alphanumeric())
-            .filter(|s| !s.is_empty())
-            .map(str::to_lowercase)
-            .collect();
+        let words = search_words(search_string);
 
         let iterators = words.clone().into_iter().map(move |word| {
             let mut prefix2 = prefix.clone();
@@ -3125,6 +3754,21 @@ impl Rooms {
             })
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn get_knock_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        self.roomuserid_knockcount
+            .get(&key)?
+            .map(|bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid knockcount in db."))
+            })
+            .transpose()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn get_left_count(&self, room_id: &RoomId, user_id: &UserId) -> Result<Option<u64>> {
         let mut key = room_id.as_bytes().to_vec();
@@ -3215,6 +3859,56 @@ impl Rooms {
             .transpose()
     }
 
+    /// Returns an iterator over all rooms a user knocked on and has not been let in yet.
+    #[tracing::instrument(skip(self))]
+    pub fn rooms_knocked<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(Box<RoomId>, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userroomid_knockstate
+            .scan_prefix(prefix)
+            .map(|(key, state)| {
+                let room_id = RoomId::parse(
+                    utils::string_from_bytes(
+                        key.rsplit(|&b| b == 0xff)
+                            .next()
+                            .expect("rsplit always returns an element"),
+                    )
+                    .map_err(|_| {
+                        Error::bad_database("Room ID in userroomid_knockstate is invalid unicode.")
+                    })?,
+                )
+                .map_err(|_| Error::bad_database("Room ID in userroomid_knockstate is invalid."))?;
+
+                let state = serde_json::from_slice(&state)
+                    .map_err(|_| Error::bad_database("Invalid state in userroomid_knockstate."))?;
+
+                Ok((room_id, state))
+            })
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn knock_state(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Option<Vec<Raw<AnyStrippedStateEvent>>>> {
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(room_id.as_bytes());
+
+        self.userroomid_knockstate
+            .get(&key)?
+            .map(|state| {
+                serde_json::from_slice(&state)
+                    .map_err(|_| Error::bad_database("Invalid state in userroomid_knockstate."))
+            })
+            .transpose()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn left_state(
         &self,
@@ -3471,3 +4165,487 @@ impl Rooms {
         Ok(room_version)
     }
 }
+
+#[derive(Deserialize)]
+struct Relation {
+    rel_type: String,
+    event_id: Box<EventId>,
+    key: Option<String>,
+}
+
+/// Splits a message body or a search term into the lowercase words of the search index.
+pub fn search_words(text: &str) -> Vec<String> {
+    text.split_terminator(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() <= MAX_SEARCH_WORD_LENGTH)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Returns the `m.relates_to` of the event. Redacted events lose it.
+fn relation_of(pdu: &PduEvent) -> Option<Relation> {
+    #[derive(Deserialize)]
+    struct ExtractRelatesTo {
+        #[serde(rename = "m.relates_to")]
+        relates_to: Relation,
+    }
+
+    serde_json::from_str::<ExtractRelatesTo>(pdu.content.get())
+        .ok()
+        .map(|content| content.relates_to)
+}
+
+/// Aggregates the events relating to `pdu` in the format of `unsigned.m.relations`. The
+/// relations are expected latest first, only the first `MAX_BUNDLED_RELATIONS` references are
+/// bundled. Returns `None` if there is nothing to bundle.
+fn aggregate_relations(
+    pdu: &PduEvent,
+    relations: impl IntoIterator<Item = Arc<PduEvent>>,
+) -> Option<serde_json::Value> {
+    let mut annotations: BTreeMap<(String, String), u64> = BTreeMap::new();
+    let mut references = Vec::new();
+    let mut replacement: Option<Arc<PduEvent>> = None;
+
+    for related in relations
+        .into_iter()
+        .filter(|related| related.room_id == pdu.room_id)
+    {
+        let relation = match relation_of(related) {
+            Some(relation) if *relation.event_id == *pdu.event_id => relation,
+            _ => continue,
+        };
+
+        match &*relation.rel_type {
+            "m.annotation" => {
+                if let Some(key) = relation.key {
+                    *annotations
+                        .entry((related.kind.to_string(), key))
+                        .or_default() += 1;
+                }
+            }
+            "m.reference" if references.len() < MAX_BUNDLED_RELATIONS => {
+                references.push(json!({ "event_id": related.event_id }))
+            }
+            // Only the sender can edit an event, the latest edit wins
+            "m.replace" if related.sender == pdu.sender => {
+                if replacement
+                    .as_ref()
+                    .map_or(true, |r| r.origin_server_ts < related.origin_server_ts)
+                {
+                    replacement = Some(related);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut aggregations = serde_json::Map::new();
+
+    if !annotations.is_empty() {
+        let chunk = annotations
+            .into_iter()
+            .map(|((kind, key), count)| json!({ "type": kind, "key": key, "count": count }))
+            .collect::<Vec<_>>();
+        aggregations.insert("m.annotation".to_owned(), json!({ "chunk": chunk }));
+    }
+
+    if !references.is_empty() {
+        aggregations.insert("m.reference".to_owned(), json!({ "chunk": references }));
+    }
+
+    if let Some(replacement) = replacement {
+        aggregations.insert(
+            "m.replace".to_owned(),
+            json!({
+                "event_id": replacement.event_id,
+                "origin_server_ts": replacement.origin_server_ts,
+                "sender": replacement.sender,
+            }),
+        );
+    }
+
+    (!aggregations.is_empty()).then(|| serde_json::Value::Object(aggregations))
+}
+
+/// Whether an event can be seen by a user with the given membership at that event.
+fn event_visible(
+    history_visibility: &HistoryVisibility,
+    membership_at_event: Option<&MembershipState>,
+    joined_now: bool,
+) -> bool {
+    match history_visibility {
+        HistoryVisibility::WorldReadable => true,
+        HistoryVisibility::Shared => {
+            joined_now || membership_at_event == Some(&MembershipState::Join)
+        }
+        HistoryVisibility::Invited => matches!(
+            membership_at_event,
+            Some(MembershipState::Join | MembershipState::Invite)
+        ),
+        _ => membership_at_event == Some(&MembershipState::Join),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        aggregate_relations, cached_server_acl, event_visible, membership_change_is_noop,
+        CachedServerAcl, MAX_BUNDLED_RELATIONS,
+    };
+    use crate::PduEvent;
+    use ruma::{
+        events::room::{
+            history_visibility::HistoryVisibility,
+            member::{MembershipState, RoomMemberEventContent},
+            server_acl::RoomServerAclEventContent,
+        },
+        server_name, RoomId,
+    };
+    use serde_json::json;
+    use std::{collections::HashMap, sync::Arc};
+
+    fn pdu(event_id: &str, sender: &str, kind: &str, content: serde_json::Value) -> Arc<PduEvent> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "event_id": event_id,
+                "room_id": "!room:example.org",
+                "sender": sender,
+                "origin_server_ts": 1,
+                "type": kind,
+                "content": content,
+                "prev_events": [],
+                "depth": 1,
+                "auth_events": [],
+                "hashes": { "sha256": "" },
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn server_acl_cache_is_refreshed_by_state_updates() {
+        let room_id = RoomId::parse("!room:example.org").unwrap();
+        let deny_evil = RoomServerAclEventContent::new(
+            false,
+            vec!["*".to_owned()],
+            vec!["evil.example.com".to_owned()],
+        );
+
+        let mut cache: HashMap<_, CachedServerAcl> = HashMap::new();
+        cache.insert(room_id.clone(), (1, Some(Arc::new(deny_evil))));
+
+        let acl = cached_server_acl(&cache, &room_id, 1).unwrap().unwrap();
+        assert!(!acl.is_allowed(server_name!("evil.example.com")));
+
+        // An updated ACL event moves the room to a new state, so the old entry must not be used
+        assert!(cached_server_acl(&cache, &room_id, 2).is_none());
+
+        cache.insert(room_id.clone(), (2, None));
+        assert!(cached_server_acl(&cache, &room_id, 2).unwrap().is_none());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn updated_server_acls_are_enforced() {
+        use crate::database::{test_room, test_send};
+        use ruma::{room_id, user_id};
+
+        let db = crate::database::test_database("server-acl").await;
+        let db = db.read().await;
+        let alice = user_id!("@alice:example.org");
+        let room_id = room_id!("!room:example.org");
+        let evil = server_name!("evil.example.com");
+
+        test_room(&db, room_id, alice).await;
+        assert!(db.rooms.server_acl(room_id).unwrap().is_none());
+
+        test_send(
+            &db,
+            room_id,
+            alice,
+            "m.room.server_acl",
+            Some(""),
+            json!({ "allow": ["*"], "deny": ["evil.example.com"], "allow_ip_literals": false }),
+        )
+        .await
+        .unwrap();
+        assert!(!db
+            .rooms
+            .server_acl(room_id)
+            .unwrap()
+            .unwrap()
+            .is_allowed(evil));
+
+        test_send(
+            &db,
+            room_id,
+            alice,
+            "m.room.server_acl",
+            Some(""),
+            json!({ "allow": ["*"], "deny": [], "allow_ip_literals": false }),
+        )
+        .await
+        .unwrap();
+        assert!(db
+            .rooms
+            .server_acl(room_id)
+            .unwrap()
+            .unwrap()
+            .is_allowed(evil));
+
+        // acl-refresh drops the loaded entry, the next check loads it again
+        assert!(db.rooms.invalidate_server_acl_cache(room_id));
+        assert!(db
+            .rooms
+            .server_acl(room_id)
+            .unwrap()
+            .unwrap()
+            .is_allowed(evil));
+    }
+
+    #[test]
+    fn rejoining_is_a_noop() {
+        let mut joined = RoomMemberEventContent::new(MembershipState::Join);
+        joined.displayname = Some("Alice".to_owned());
+
+        assert!(membership_change_is_noop(Some(&joined), &joined.clone()));
+
+        // Rejoining with a new display name updates the profile in the room
+        let mut renamed = joined.clone();
+        renamed.displayname = Some("Alice in Wonderland".to_owned());
+        assert!(!membership_change_is_noop(Some(&joined), &renamed));
+
+        let left = RoomMemberEventContent::new(MembershipState::Leave);
+        assert!(membership_change_is_noop(Some(&left), &left.clone()));
+        assert!(!membership_change_is_noop(Some(&left), &joined));
+        assert!(!membership_change_is_noop(None, &joined));
+    }
+
+    #[test]
+    fn reactions_are_bundled() {
+        let message = pdu(
+            "$message",
+            "@alice:example.org",
+            "m.room.message",
+            json!({ "msgtype": "m.text", "body": "hi" }),
+        );
+        let reaction = |event_id, sender| {
+            pdu(
+                event_id,
+                sender,
+                "m.reaction",
+                json!({
+                    "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": "$message",
+                        "key": "👍",
+                    }
+                }),
+            )
+        };
+        // Redacted reactions lost their relation
+        let redacted = pdu("$redacted", "@carol:example.org", "m.reaction", json!({}));
+
+        assert_eq!(aggregate_relations(&message, Vec::new()), None);
+        assert_eq!(
+            aggregate_relations(
+                &message,
+                [
+                    reaction("$a", "@bob:example.org"),
+                    reaction("$b", "@carol:example.org"),
+                    redacted,
+                ]
+            ),
+            Some(json!({
+                "m.annotation": {
+                    "chunk": [{ "type": "m.reaction", "key": "👍", "count": 2 }]
+                }
+            }))
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn existing_relations_are_indexed_and_bundled() {
+        let db = crate::database::test_database("relations").await;
+        let db = db.read().await;
+        let rooms = &db.rooms;
+
+        let store = |count: u64, pdu: &PduEvent| {
+            let mut pdu_id = 1_u64.to_be_bytes().to_vec();
+            pdu_id.extend_from_slice(&count.to_be_bytes());
+            rooms
+                .pduid_pdu
+                .insert(&pdu_id, &serde_json::to_vec(pdu).unwrap())
+                .unwrap();
+        };
+        let reaction = |count: u64, key: &str| {
+            pdu(
+                &format!("${}", count),
+                "@bob:example.org",
+                "m.reaction",
+                json!({
+                    "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": "$message",
+                        "key": key,
+                    }
+                }),
+            )
+        };
+        let reference = |count: u64| {
+            pdu(
+                &format!("${}", count),
+                "@bob:example.org",
+                "m.room.message",
+                json!({
+                    "msgtype": "m.text",
+                    "body": "see above",
+                    "m.relates_to": { "rel_type": "m.reference", "event_id": "$message" }
+                }),
+            )
+        };
+
+        let message = pdu(
+            "$message",
+            "@alice:example.org",
+            "m.room.message",
+            json!({ "msgtype": "m.text", "body": "hi" }),
+        );
+        store(1, &message);
+        store(2, &reaction(2, "👎"));
+        // The oldest reference is one too many to be bundled
+        let references = MAX_BUNDLED_RELATIONS as u64 + 1;
+        for count in 3..references + 3 {
+            store(count, &reference(count));
+        }
+        // Reactions are counted no matter how many relations came after them
+        for count in references + 3..references + MAX_BUNDLED_RELATIONS as u64 + 3 {
+            store(count, &reaction(count, "👍"));
+        }
+
+        rooms.index_relations().unwrap();
+
+        let bundled = rooms.bundle_relations((*message).clone()).unwrap();
+        let unsigned: serde_json::Value =
+            serde_json::from_str(bundled.unsigned.unwrap().get()).unwrap();
+        let relations = &unsigned["m.relations"];
+        assert_eq!(
+            relations["m.annotation"],
+            json!({
+                "chunk": [
+                    { "type": "m.reaction", "key": "👍", "count": MAX_BUNDLED_RELATIONS },
+                    { "type": "m.reaction", "key": "👎", "count": 1 },
+                ]
+            })
+        );
+        let bundled_references = relations["m.reference"]["chunk"].as_array().unwrap();
+        assert_eq!(bundled_references.len(), MAX_BUNDLED_RELATIONS);
+        assert_eq!(
+            bundled_references[0],
+            json!({ "event_id": format!("${}", references + 2) })
+        );
+        assert!(!bundled_references.contains(&json!({ "event_id": "$3" })));
+    }
+
+    #[test]
+    fn events_follow_history_visibility() {
+        let join = MembershipState::Join;
+        let invite = MembershipState::Invite;
+
+        assert!(event_visible(
+            &HistoryVisibility::WorldReadable,
+            None,
+            false
+        ));
+        assert!(event_visible(&HistoryVisibility::Shared, None, true));
+        assert!(!event_visible(&HistoryVisibility::Shared, None, false));
+        assert!(event_visible(
+            &HistoryVisibility::Invited,
+            Some(&invite),
+            false
+        ));
+        assert!(!event_visible(
+            &HistoryVisibility::Joined,
+            Some(&invite),
+            true
+        ));
+        assert!(event_visible(
+            &HistoryVisibility::Joined,
+            Some(&join),
+            false
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn failed_state_fills_are_refused() {
+        use ruma::{event_id, room_id};
+
+        let db = crate::database::test_database("partial-state").await;
+        let db = db.read().await;
+        let room_id = room_id!("!room:example.org");
+
+        db.rooms.wait_for_full_state(room_id).await.unwrap();
+
+        db.rooms
+            .mark_partial_state(
+                room_id,
+                server_name!("remote.example.org"),
+                event_id!("$join"),
+            )
+            .unwrap();
+        assert!(db.rooms.wait_for_full_state(room_id).await.is_err());
+
+        // The fill ends without completing the state
+        assert!(db.rooms.start_state_fill(room_id));
+        db.rooms.finish_state_fill(room_id);
+        assert!(db.rooms.wait_for_full_state(room_id).await.is_err());
+
+        db.rooms.mark_full_state(room_id).unwrap();
+        db.rooms.wait_for_full_state(room_id).await.unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn reports_are_stored_until_cleared() {
+        use super::Report;
+        use ruma::{event_id, int, room_id, user_id};
+
+        let db = crate::database::test_database("reports").await;
+        let db = db.read().await;
+        let report = |reason: &str| Report {
+            reporter: user_id!("@alice:example.org").to_owned(),
+            room_id: room_id!("!room:example.org").to_owned(),
+            event_id: event_id!("$spam").to_owned(),
+            score: Some(int!(-100)),
+            reason: Some(reason.to_owned()),
+            reported_at: 10,
+        };
+
+        let first = db.rooms.add_report(&report("spam"), &db.globals).unwrap();
+        let second = db.rooms.add_report(&report("abuse"), &db.globals).unwrap();
+        assert!(first < second);
+
+        let reports = db.rooms.reports().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(
+            reports
+                .iter()
+                .map(|(report_id, report)| (*report_id, report.reason.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![(first, Some("spam")), (second, Some("abuse"))]
+        );
+
+        assert!(db.rooms.remove_report(first).unwrap());
+        assert!(!db.rooms.remove_report(first).unwrap());
+        assert_eq!(db.rooms.reports().count(), 1);
+
+        // Reports that can't be read anymore are cleared as well
+        db.rooms
+            .reportid_report
+            .insert(&u64::MAX.to_be_bytes(), b"not json")
+            .unwrap();
+        assert!(db.rooms.reports().any(|r| r.is_err()));
+        assert_eq!(db.rooms.clear_reports().unwrap(), 2);
+        assert_eq!(db.rooms.reports().count(), 0);
+    }
+}
@@ -8,22 +8,32 @@ use ruma::{
     presence::PresenceState,
     serde::Raw,
     signatures::CanonicalJsonObject,
-    RoomId, UInt, UserId,
+    EventId, RoomId, UInt, UserId,
 };
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     mem,
-    sync::Arc,
+    sync::{Arc, RwLock},
 };
+use tokio::sync::broadcast;
+
+/// Users that are online become unavailable after this many milliseconds without activity.
+pub const PRESENCE_IDLE_TIMEOUT: u64 = 5 * 60 * 1000;
+
+type TypingUsers = HashMap<Box<UserId>, u64>; // UserId -> TimeoutTime
+type RoomPresence = HashMap<Box<UserId>, (u64, PresenceEvent)>; // UserId -> (Count, PresenceEvent)
 
 pub struct RoomEdus {
     pub(in super::super) readreceiptid_readreceipt: Arc<dyn Tree>, // ReadReceiptId = RoomId + Count + UserId
+    pub(in super::super) roomuserid_readreceiptid: Arc<dyn Tree>, // The current ReadReceiptId of the user
     pub(in super::super) roomuserid_privateread: Arc<dyn Tree>, // RoomUserId = Room + User, PrivateRead = Count
     pub(in super::super) roomuserid_lastprivatereadupdate: Arc<dyn Tree>, // LastPrivateReadUpdate = Count
-    pub(in super::super) typingid_userid: Arc<dyn Tree>, // TypingId = RoomId + TimeoutTime + Count
-    pub(in super::super) roomid_lasttypingupdate: Arc<dyn Tree>, // LastRoomTypingUpdate = Count
-    pub(in super::super) presenceid_presence: Arc<dyn Tree>, // PresenceId = RoomId + Count + UserId
-    pub(in super::super) userid_lastpresenceupdate: Arc<dyn Tree>, // LastPresenceUpdate = Count
+    pub(in super::super) roomuserid_privatereceipt: Arc<dyn Tree>, // PrivateReceipt = PduCount + UpdateCount + ReceiptEvent as json
+    pub(in super::super) typing: RwLock<HashMap<Box<RoomId>, TypingUsers>>,
+    pub(in super::super) last_typing_update: RwLock<HashMap<Box<RoomId>, u64>>, // LastRoomTypingUpdate = Count
+    pub(in super::super) typing_update_sender: broadcast::Sender<Box<RoomId>>,
+    pub(in super::super) presence: RwLock<HashMap<Box<RoomId>, RoomPresence>>,
+    pub(in super::super) presence_last_update: RwLock<HashMap<Box<UserId>, u64>>, // LastPresenceUpdate = Timestamp
 }
 
 impl RoomEdus {
@@ -38,22 +48,11 @@ impl RoomEdus {
         let mut prefix = room_id.as_bytes().to_vec();
         prefix.push(0xff);
 
-        let mut last_possible_key = prefix.clone();
-        last_possible_key.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut roomuser_id = prefix.clone();
+        roomuser_id.extend_from_slice(user_id.as_bytes());
 
         // Remove old entry
-        if let Some((old, _)) = self
-            .readreceiptid_readreceipt
-            .iter_from(&last_possible_key, true)
-            .take_while(|(key, _)| key.starts_with(&prefix))
-            .find(|(key, _)| {
-                key.rsplit(|&b| b == 0xff)
-                    .next()
-                    .expect("rsplit always returns an element")
-                    == user_id.as_bytes()
-            })
-        {
-            // This is the old room_latest
+        if let Some(old) = self.roomuserid_readreceiptid.get(&roomuser_id)? {
             self.readreceiptid_readreceipt.remove(&old)?;
         }
 
@@ -66,10 +65,63 @@ impl RoomEdus {
             &room_latest_id,
             &serde_json::to_vec(&event).expect("EduEvent::to_string always works"),
         )?;
+        self.roomuserid_readreceiptid
+            .insert(&roomuser_id, &room_latest_id)?;
+
+        Ok(())
+    }
+
+    /// Remembers where the current receipt of each user is stored. Only needed for receipts that
+    /// were stored before this was tracked.
+    pub fn index_readreceipts(&self) -> Result<()> {
+        for (readreceipt_id, _) in self.readreceiptid_readreceipt.iter() {
+            let mut parts = readreceipt_id.splitn(2, |&b| b == 0xff);
+            let room_id = parts.next().expect("splitn always returns one element");
+            let user_id = match parts.next() {
+                // Skip the count, it may contain 0xff bytes
+                Some(rest) if rest.len() > mem::size_of::<u64>() + 1 => {
+                    &rest[mem::size_of::<u64>() + 1..]
+                }
+                _ => return Err(Error::bad_database("Invalid readreceiptid in db.")),
+            };
+
+            let mut roomuser_id = room_id.to_vec();
+            roomuser_id.push(0xff);
+            roomuser_id.extend_from_slice(user_id);
+
+            self.roomuserid_readreceiptid
+                .insert(&roomuser_id, &readreceipt_id)?;
+        }
 
         Ok(())
     }
 
+    /// Returns the event id of the public read receipt a user has in a room.
+    pub fn readreceipt_event_id(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<Box<EventId>>> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        let readreceipt_id = match self.roomuserid_readreceiptid.get(&key)? {
+            Some(readreceipt_id) => readreceipt_id,
+            None => return Ok(None),
+        };
+
+        self.readreceiptid_readreceipt
+            .get(&readreceipt_id)?
+            .map(|v| {
+                serde_json::from_slice::<ReceiptEvent>(&v)
+                    .map_err(|_| Error::bad_database("Invalid read receipt in db."))
+                    .map(|event| event.content.0.into_keys().next())
+            })
+            .transpose()
+            .map(Option::flatten)
+    }
+
     /// Returns an iterator over the most recent read_receipts in a room that happened after the event with id `since`.
     #[tracing::instrument(skip(self))]
     pub fn readreceipts_since<'a>(
@@ -120,7 +172,8 @@ impl RoomEdus {
             })
     }
 
-    /// Sets a private read marker at `count`.
+    /// Sets a private read marker at `count`. Returns false and keeps the marker if it already is
+    /// at or after `count`.
     #[tracing::instrument(skip(self, globals))]
     pub fn private_read_set(
         &self,
@@ -128,7 +181,11 @@ impl RoomEdus {
         user_id: &UserId,
         count: u64,
         globals: &super::super::globals::Globals,
-    ) -> Result<()> {
+    ) -> Result<bool> {
+        if !receipt_advances(self.private_read_get(room_id, user_id)?, count) {
+            return Ok(false);
+        }
+
         let mut key = room_id.as_bytes().to_vec();
         key.push(0xff);
         key.extend_from_slice(user_id.as_bytes());
@@ -139,7 +196,89 @@ impl RoomEdus {
         self.roomuserid_lastprivatereadupdate
             .insert(&key, &globals.next_count()?.to_be_bytes())?;
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Stores the `m.read.private` receipt for the event at `count` that is echoed back to the user
+    /// in sync. It is independent of the public receipt and the private read marker.
+    ///
+    /// Returns false and keeps the current receipt if it already points to the same or a newer
+    /// event.
+    #[tracing::instrument(skip(self, event, globals))]
+    pub fn private_receipt_set(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        count: u64,
+        event: &ReceiptEvent,
+        globals: &super::super::globals::Globals,
+    ) -> Result<bool> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        let old_count = self
+            .private_receipt(&key)?
+            .map(|(old_count, _, _)| old_count);
+        if !receipt_advances(old_count, count) {
+            return Ok(false);
+        }
+
+        let mut value = count.to_be_bytes().to_vec();
+        value.extend_from_slice(&globals.next_count()?.to_be_bytes());
+        value
+            .extend_from_slice(&serde_json::to_vec(event).expect("ReceiptEvent can be serialized"));
+        self.roomuserid_privatereceipt.insert(&key, &value)?;
+
+        Ok(true)
+    }
+
+    /// Returns the `m.read.private` receipt of a user if it changed after `since`.
+    pub fn private_receipt_since(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        since: u64,
+    ) -> Result<Option<Raw<ruma::events::AnySyncEphemeralRoomEvent>>> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+
+        match self.private_receipt(&key)? {
+            Some((_, updated, mut json)) if updated > since => {
+                json.remove("room_id");
+
+                Ok(Some(Raw::from_json(
+                    serde_json::value::to_raw_value(&json).expect("json is valid raw value"),
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the event count, update count and event of a stored private receipt.
+    fn private_receipt(
+        &self,
+        roomuser_id: &[u8],
+    ) -> Result<Option<(u64, u64, CanonicalJsonObject)>> {
+        self.roomuserid_privatereceipt
+            .get(roomuser_id)?
+            .map(|v| {
+                let invalid =
+                    || Error::bad_database("Receipt in roomuserid_privatereceipt is invalid.");
+                if v.len() < 2 * mem::size_of::<u64>() {
+                    return Err(invalid());
+                }
+                let (counts, json) = v.split_at(2 * mem::size_of::<u64>());
+                let (count, updated) = counts.split_at(mem::size_of::<u64>());
+
+                Ok((
+                    utils::u64_from_bytes(count).map_err(|_| invalid())?,
+                    utils::u64_from_bytes(updated).map_err(|_| invalid())?,
+                    serde_json::from_slice(json).map_err(|_| invalid())?,
+                ))
+            })
+            .transpose()
     }
 
     /// Returns the private read marker.
@@ -176,108 +315,133 @@ impl RoomEdus {
             .unwrap_or(0))
     }
 
-    /// Sets a user as typing until the timeout timestamp is reached or roomtyping_remove is
-    /// called.
+    /// Sets a user as typing until the timeout timestamp is reached or typing_remove is called.
+    ///
+    /// Repeated calls only extend the timeout. Returns true if the user wasn't typing before.
     pub fn typing_add(
         &self,
         user_id: &UserId,
         room_id: &RoomId,
         timeout: u64,
         globals: &super::super::globals::Globals,
-    ) -> Result<()> {
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let count = globals.next_count()?.to_be_bytes();
+    ) -> Result<bool> {
+        let was_typing = self
+            .typing
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), timeout)
+            .is_some();
+
+        // Syncs only see who is typing, so there is nothing new to tell them
+        if !was_typing {
+            self.typing_updated(room_id, globals)?;
+        }
 
-        let mut room_typing_id = prefix;
-        room_typing_id.extend_from_slice(&timeout.to_be_bytes());
-        room_typing_id.push(0xff);
-        room_typing_id.extend_from_slice(&count);
+        Ok(!was_typing)
+    }
 
-        self.typingid_userid
-            .insert(&room_typing_id, &*user_id.as_bytes())?;
+    /// Removes a user from typing before the timeout is reached. Returns true if the user was
+    /// typing.
+    pub fn typing_remove(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        globals: &super::super::globals::Globals,
+    ) -> Result<bool> {
+        let was_typing = {
+            let mut typing = self.typing.write().unwrap();
+            let was_typing = typing
+                .get_mut(room_id)
+                .map_or(false, |users| users.remove(user_id).is_some());
+            typing.retain(|_, users| !users.is_empty());
+            was_typing
+        };
 
-        self.roomid_lasttypingupdate
-            .insert(room_id.as_bytes(), &count)?;
+        if was_typing {
+            self.typing_updated(room_id, globals)?;
+        }
 
-        Ok(())
+        Ok(was_typing)
     }
 
-    /// Removes a user from typing before the timeout is reached.
-    pub fn typing_remove(
+    /// Makes sure that typing events with old timestamps get removed.
+    fn typings_maintain(
         &self,
-        user_id: &UserId,
         room_id: &RoomId,
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
+        let current_timestamp = utils::millis_since_unix_epoch();
+
+        let found_outdated = self
+            .typing
+            .write()
+            .unwrap()
+            .get_mut(room_id)
+            .map_or(false, |users| {
+                let before = users.len();
+                users.retain(|_, timeout| *timeout >= current_timestamp);
+                users.len() != before
+            });
+
+        if found_outdated {
+            self.typing_updated(room_id, globals)?;
+        }
 
-        let user_id = user_id.to_string();
+        Ok(())
+    }
 
-        let mut found_outdated = false;
+    /// Removes outdated typing events in all rooms, so syncs waiting for updates learn about them
+    /// without another event in the room.
+    pub fn typings_maintain_all(&self, globals: &super::super::globals::Globals) -> Result<()> {
+        let current_timestamp = utils::millis_since_unix_epoch();
 
-        // Maybe there are multiple ones from calling roomtyping_add multiple times
-        for outdated_edu in self
-            .typingid_userid
-            .scan_prefix(prefix)
-            .filter(|(_, v)| &**v == user_id.as_bytes())
+        let mut rooms = Vec::new();
         {
-            self.typingid_userid.remove(&outdated_edu.0)?;
-            found_outdated = true;
+            let mut typing = self.typing.write().unwrap();
+            for (room_id, users) in typing.iter_mut() {
+                let before = users.len();
+                users.retain(|_, timeout| *timeout >= current_timestamp);
+                if users.len() != before {
+                    rooms.push(room_id.clone());
+                }
+            }
+            typing.retain(|_, users| !users.is_empty());
         }
 
-        if found_outdated {
-            self.roomid_lasttypingupdate
-                .insert(room_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+        for room_id in rooms {
+            self.typing_updated(&room_id, globals)?;
         }
 
         Ok(())
     }
 
-    /// Makes sure that typing events with old timestamps get removed.
-    fn typings_maintain(
+    /// Bumps the typing count of the room and wakes up the syncs that wait for it.
+    fn typing_updated(
         &self,
         room_id: &RoomId,
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
+        self.last_typing_update
+            .write()
+            .unwrap()
+            .insert(room_id.to_owned(), globals.next_count()?);
 
-        let current_timestamp = utils::millis_since_unix_epoch();
+        // Sending only fails if no sync is waiting
+        let _ = self.typing_update_sender.send(room_id.to_owned());
 
-        let mut found_outdated = false;
-
-        // Find all outdated edus before inserting a new one
-        for outdated_edu in self
-            .typingid_userid
-            .scan_prefix(prefix)
-            .map(|(key, _)| {
-                Ok::<_, Error>((
-                    key.clone(),
-                    utils::u64_from_bytes(
-                        &key.splitn(2, |&b| b == 0xff).nth(1).ok_or_else(|| {
-                            Error::bad_database("RoomTyping has invalid timestamp or delimiters.")
-                        })?[0..mem::size_of::<u64>()],
-                    )
-                    .map_err(|_| Error::bad_database("RoomTyping has invalid timestamp bytes."))?,
-                ))
-            })
-            .filter_map(|r| r.ok())
-            .take_while(|&(_, timestamp)| timestamp < current_timestamp)
-        {
-            // This is an outdated edu (time > timestamp)
-            self.typingid_userid.remove(&outdated_edu.0)?;
-            found_outdated = true;
-        }
+        Ok(())
+    }
 
-        if found_outdated {
-            self.roomid_lasttypingupdate
-                .insert(room_id.as_bytes(), &globals.next_count()?.to_be_bytes())?;
+    /// Returns once the typing users of the room changed.
+    pub async fn wait_for_typing_update(&self, room_id: &RoomId) {
+        let mut receiver = self.typing_update_sender.subscribe();
+        while let Ok(next) = receiver.recv().await {
+            if *next == *room_id {
+                break;
+            }
         }
-
-        Ok(())
     }
 
     /// Returns the count of the last typing update in this room.
@@ -290,14 +454,11 @@ impl RoomEdus {
         self.typings_maintain(room_id, globals)?;
 
         Ok(self
-            .roomid_lasttypingupdate
-            .get(room_id.as_bytes())?
-            .map(|bytes| {
-                utils::u64_from_bytes(&bytes).map_err(|_| {
-                    Error::bad_database("Count in roomid_lastroomactiveupdate is invalid.")
-                })
-            })
-            .transpose()?
+            .last_typing_update
+            .read()
+            .unwrap()
+            .get(room_id)
+            .copied()
             .unwrap_or(0))
     }
 
@@ -305,24 +466,16 @@ impl RoomEdus {
         &self,
         room_id: &RoomId,
     ) -> Result<SyncEphemeralRoomEvent<ruma::events::typing::TypingEventContent>> {
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
-
-        let mut user_ids = HashSet::new();
-
-        for (_, user_id) in self.typingid_userid.scan_prefix(prefix) {
-            let user_id = UserId::parse(utils::string_from_bytes(&user_id).map_err(|_| {
-                Error::bad_database("User ID in typingid_userid is invalid unicode.")
-            })?)
-            .map_err(|_| Error::bad_database("User ID in typingid_userid is invalid."))?;
-
-            user_ids.insert(user_id);
-        }
+        let user_ids = self
+            .typing
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|users| users.keys().cloned().collect())
+            .unwrap_or_default();
 
         Ok(SyncEphemeralRoomEvent {
-            content: ruma::events::typing::TypingEventContent {
-                user_ids: user_ids.into_iter().collect(),
-            },
+            content: ruma::events::typing::TypingEventContent { user_ids },
         })
     }
 
@@ -337,50 +490,48 @@ impl RoomEdus {
         presence: PresenceEvent,
         globals: &super::super::globals::Globals,
     ) -> Result<()> {
-        // TODO: Remove old entry? Or maybe just wipe completely from time to time?
-
-        let count = globals.next_count()?.to_be_bytes();
-
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&count);
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(presence.sender.as_bytes());
-
-        self.presenceid_presence.insert(
-            &presence_id,
-            &serde_json::to_vec(&presence).expect("PresenceEvent can be serialized"),
-        )?;
+        let count = globals.next_count()?;
 
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
+        // Only the latest presence of a user is kept
+        self.presence
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned(), (count, presence));
 
-        Ok(())
+        self.ping_presence(user_id)
     }
 
     /// Resets the presence timeout, so the user will stay in their current presence state.
     #[tracing::instrument(skip(self))]
     pub fn ping_presence(&self, user_id: &UserId) -> Result<()> {
-        self.userid_lastpresenceupdate.insert(
-            user_id.as_bytes(),
-            &utils::millis_since_unix_epoch().to_be_bytes(),
-        )?;
+        self.presence_last_update
+            .write()
+            .unwrap()
+            .insert(user_id.to_owned(), utils::millis_since_unix_epoch());
 
         Ok(())
     }
 
     /// Returns the timestamp of the last presence update of this user in millis since the unix epoch.
     pub fn last_presence_update(&self, user_id: &UserId) -> Result<Option<u64>> {
-        self.userid_lastpresenceupdate
-            .get(user_id.as_bytes())?
-            .map(|bytes| {
-                utils::u64_from_bytes(&bytes).map_err(|_| {
-                    Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                })
-            })
-            .transpose()
+        Ok(self
+            .presence_last_update
+            .read()
+            .unwrap()
+            .get(user_id)
+            .copied())
+    }
+
+    /// Returns the stored presence event of a user in a room.
+    fn presence_entry(&self, user_id: &UserId, room_id: &RoomId) -> Option<PresenceEvent> {
+        self.presence
+            .read()
+            .unwrap()
+            .get(room_id)?
+            .get(user_id)
+            .map(|(_, presence)| presence.clone())
     }
 
     pub fn get_last_presence_event(
@@ -388,110 +539,70 @@ impl RoomEdus {
         user_id: &UserId,
         room_id: &RoomId,
     ) -> Result<Option<PresenceEvent>> {
-        let last_update = match self.last_presence_update(user_id)? {
-            Some(last) => last,
-            None => return Ok(None),
-        };
-
-        let mut presence_id = room_id.as_bytes().to_vec();
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(&last_update.to_be_bytes());
-        presence_id.push(0xff);
-        presence_id.extend_from_slice(user_id.as_bytes());
-
-        self.presenceid_presence
-            .get(&presence_id)?
-            .map(|value| {
-                let mut presence: PresenceEvent = serde_json::from_slice(&value)
-                    .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
-                let current_timestamp: UInt = utils::millis_since_unix_epoch()
-                    .try_into()
-                    .expect("time is valid");
-
-                if presence.content.presence == PresenceState::Online {
-                    // Don't set last_active_ago when the user is online
-                    presence.content.last_active_ago = None;
-                } else {
-                    // Convert from timestamp to duration
-                    presence.content.last_active_ago = presence
-                        .content
-                        .last_active_ago
-                        .map(|timestamp| current_timestamp - timestamp);
-                }
-
-                Ok(presence)
-            })
-            .transpose()
+        Ok(self
+            .presence_entry(user_id, room_id)
+            .map(with_last_active_ago))
     }
 
-    /// Sets all users to offline who have been quiet for too long.
-    fn _presence_maintain(
+    /// Marks local users as unavailable who were online, but have been quiet for too long.
+    /// Returns the updated users.
+    pub fn presence_maintain(
         &self,
         rooms: &super::Rooms,
         globals: &super::super::globals::Globals,
-    ) -> Result<()> {
+    ) -> Result<Vec<(Box<UserId>, PresenceEvent)>> {
         let current_timestamp = utils::millis_since_unix_epoch();
 
-        for (user_id_bytes, last_timestamp) in self
-            .userid_lastpresenceupdate
+        let idle_users = self
+            .presence_last_update
+            .read()
+            .unwrap()
             .iter()
-            .filter_map(|(k, bytes)| {
-                Some((
-                    k,
-                    utils::u64_from_bytes(&bytes)
-                        .map_err(|_| {
-                            Error::bad_database("Invalid timestamp in userid_lastpresenceupdate.")
-                        })
-                        .ok()?,
-                ))
+            .filter(|(user_id, timestamp)| {
+                user_id.server_name() == globals.server_name()
+                    && current_timestamp.saturating_sub(**timestamp) > PRESENCE_IDLE_TIMEOUT
             })
-            .take_while(|(_, timestamp)| current_timestamp.saturating_sub(*timestamp) > 5 * 60_000)
-        // 5 Minutes
-        {
-            // Send new presence events to set the user offline
-            let count = globals.next_count()?.to_be_bytes();
-            let user_id: Box<_> = utils::string_from_bytes(&user_id_bytes)
-                .map_err(|_| {
-                    Error::bad_database("Invalid UserId bytes in userid_lastpresenceupdate.")
-                })?
-                .try_into()
-                .map_err(|_| Error::bad_database("Invalid UserId in userid_lastpresenceupdate."))?;
+            .map(|(user_id, timestamp)| (user_id.clone(), *timestamp))
+            .collect::<Vec<_>>();
+
+        let mut updated = Vec::new();
+
+        for (user_id, last_timestamp) in idle_users {
+            let mut presence = None;
+
             for room_id in rooms.rooms_joined(&user_id).filter_map(|r| r.ok()) {
-                let mut presence_id = room_id.as_bytes().to_vec();
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&count);
-                presence_id.push(0xff);
-                presence_id.extend_from_slice(&user_id_bytes);
-
-                self.presenceid_presence.insert(
-                    &presence_id,
-                    &serde_json::to_vec(&PresenceEvent {
-                        content: PresenceEventContent {
-                            avatar_url: None,
-                            currently_active: None,
-                            displayname: None,
-                            last_active_ago: Some(
-                                last_timestamp.try_into().expect("time is valid"),
-                            ),
-                            presence: PresenceState::Offline,
-                            status_msg: None,
-                        },
-                        sender: user_id.to_owned(),
-                    })
-                    .expect("PresenceEvent can be serialized"),
-                )?;
+                let last = match self.presence_entry(&user_id, &room_id) {
+                    Some(last) => last,
+                    None => continue,
+                };
+
+                if last.content.presence != PresenceState::Online {
+                    continue;
+                }
+
+                let event = PresenceEvent {
+                    content: PresenceEventContent {
+                        currently_active: Some(false),
+                        last_active_ago: Some(last_timestamp.try_into().expect("time is valid")),
+                        presence: PresenceState::Unavailable,
+                        ..last.content
+                    },
+                    sender: user_id.clone(),
+                };
+
+                self.update_presence(&user_id, &room_id, event.clone(), globals)?;
+                presence = Some(event);
             }
 
-            self.userid_lastpresenceupdate.insert(
-                user_id.as_bytes(),
-                &utils::millis_since_unix_epoch().to_be_bytes(),
-            )?;
+            if let Some(presence) = presence {
+                updated.push((user_id, presence));
+            }
         }
 
-        Ok(())
+        Ok(updated)
     }
 
-    /// Returns an iterator over the most recent presence updates that happened after the event with id `since`.
+    /// Returns the most recent presence updates that happened after the event with id `since`.
     #[tracing::instrument(skip(self, since, _rooms, _globals))]
     pub fn presence_since(
         &self,
@@ -500,51 +611,271 @@ impl RoomEdus {
         _rooms: &super::Rooms,
         _globals: &super::super::globals::Globals,
     ) -> Result<HashMap<Box<UserId>, PresenceEvent>> {
-        //self.presence_maintain(rooms, globals)?;
+        Ok(self
+            .presence
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|users| {
+                users
+                    .iter()
+                    .filter(|(_, (count, _))| *count > since)
+                    .map(|(user_id, (_, presence))| {
+                        (user_id.clone(), with_last_active_ago(presence.clone()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
 
-        let mut prefix = room_id.as_bytes().to_vec();
-        prefix.push(0xff);
+/// Turns the stored timestamp of the last activity into the time since then.
+fn with_last_active_ago(mut presence: PresenceEvent) -> PresenceEvent {
+    let current_timestamp: UInt = utils::millis_since_unix_epoch()
+        .try_into()
+        .expect("time is valid");
+
+    if presence.content.presence == PresenceState::Online {
+        // Don't set last_active_ago when the user is online
+        presence.content.last_active_ago = None;
+    } else {
+        // Convert from timestamp to duration
+        presence.content.last_active_ago = presence
+            .content
+            .last_active_ago
+            .map(|timestamp| current_timestamp - timestamp);
+    }
 
-        let mut first_possible_edu = prefix.clone();
-        first_possible_edu.extend_from_slice(&(since + 1).to_be_bytes()); // +1 so we don't send the event at since
-        let mut hashmap = HashMap::new();
+    presence
+}
 
-        for (key, value) in self
-            .presenceid_presence
-            .iter_from(&*first_possible_edu, false)
-            .take_while(|(key, _)| key.starts_with(&prefix))
-        {
-            let user_id = UserId::parse(
-                utils::string_from_bytes(
-                    key.rsplit(|&b| b == 0xff)
-                        .next()
-                        .expect("rsplit always returns an element"),
-                )
-                .map_err(|_| Error::bad_database("Invalid UserId bytes in presenceid_presence."))?,
+/// Receipts only ever move forward, an older event must not replace a newer one.
+pub fn receipt_advances(old: Option<u64>, new: u64) -> bool {
+    old.map_or(true, |old| new > old)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::receipt_advances;
+
+    #[test]
+    fn older_receipts_do_not_regress() {
+        assert!(receipt_advances(None, 1));
+        assert!(receipt_advances(Some(1), 2));
+        assert!(!receipt_advances(Some(2), 2));
+        assert!(!receipt_advances(Some(3), 2));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn public_and_private_receipts_are_kept_apart() {
+        use ruma::{event_id, events::receipt::ReceiptEvent, room_id, user_id};
+        use serde_json::json;
+
+        let db = crate::database::test_database("receipts").await;
+        let db = db.read().await;
+        let edus = &db.rooms.edus;
+
+        let room = room_id!("!room:example.org");
+        let alice = user_id!("@alice:example.org");
+        let receipt = |event_id: &str, receipt_type: &str| {
+            serde_json::from_value::<ReceiptEvent>(json!({
+                "type": "m.receipt",
+                "room_id": room,
+                "content": { event_id: { receipt_type: { alice.as_str(): { "ts": 1 } } } },
+            }))
+            .unwrap()
+        };
+
+        // Private receipts only move forward
+        assert!(edus
+            .private_receipt_set(
+                room,
+                alice,
+                5,
+                &receipt("$5", "m.read.private"),
+                &db.globals
             )
-            .map_err(|_| Error::bad_database("Invalid UserId in presenceid_presence."))?;
-
-            let mut presence: PresenceEvent = serde_json::from_slice(&value)
-                .map_err(|_| Error::bad_database("Invalid presence event in db."))?;
-
-            let current_timestamp: UInt = utils::millis_since_unix_epoch()
-                .try_into()
-                .expect("time is valid");
-
-            if presence.content.presence == PresenceState::Online {
-                // Don't set last_active_ago when the user is online
-                presence.content.last_active_ago = None;
-            } else {
-                // Convert from timestamp to duration
-                presence.content.last_active_ago = presence
-                    .content
-                    .last_active_ago
-                    .map(|timestamp| current_timestamp - timestamp);
-            }
+            .unwrap());
+        assert!(!edus
+            .private_receipt_set(
+                room,
+                alice,
+                3,
+                &receipt("$3", "m.read.private"),
+                &db.globals
+            )
+            .unwrap());
+        assert!(edus
+            .private_receipt_since(room, alice, 0)
+            .unwrap()
+            .is_some());
+
+        // A public receipt replaces the previous public one and leaves the private one alone
+        let since = db.globals.current_count().unwrap();
+        edus.readreceipt_update(alice, room, receipt("$6", "m.read"), &db.globals)
+            .unwrap();
+        edus.private_read_set(room, alice, 6, &db.globals).unwrap();
+        edus.readreceipt_update(alice, room, receipt("$7", "m.read"), &db.globals)
+            .unwrap();
+        edus.private_read_set(room, alice, 7, &db.globals).unwrap();
+
+        assert!(edus
+            .private_receipt_since(room, alice, since)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            edus.readreceipt_event_id(room, alice).unwrap().as_deref(),
+            Some(event_id!("$7"))
+        );
+        assert_eq!(edus.readreceipts_since(room, 0).count(), 1);
+
+        // Receipts stored before they were indexed are found after the migration
+        edus.roomuserid_readreceiptid.clear().unwrap();
+        assert_eq!(edus.readreceipt_event_id(room, alice).unwrap(), None);
+        edus.index_readreceipts().unwrap();
+        assert_eq!(
+            edus.readreceipt_event_id(room, alice).unwrap().as_deref(),
+            Some(event_id!("$7"))
+        );
+    }
 
-            hashmap.insert(user_id, presence);
-        }
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn typing_users_expire_and_are_cleared_on_leave() {
+        use crate::{database::test_send, utils};
+        use ruma::{room_id, user_id};
+        use serde_json::json;
+
+        let db = crate::database::test_database("typing").await;
+        let db = db.read().await;
+        let edus = &db.rooms.edus;
+
+        let room = room_id!("!room:example.org");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let now = utils::millis_since_unix_epoch();
+
+        // Waiting syncs wake up when someone starts typing
+        let ((), added) = tokio::join!(edus.wait_for_typing_update(room), async {
+            tokio::task::yield_now().await;
+            edus.typing_add(alice, room, now + 60_000, &db.globals)
+                .unwrap()
+        });
+        assert!(added);
+        let first = edus.last_typing_update(room, &db.globals).unwrap();
+        assert!(first > 0);
+
+        // Repeated notifications only extend the timeout
+        assert!(!edus
+            .typing_add(alice, room, now + 120_000, &db.globals)
+            .unwrap());
+        assert_eq!(edus.last_typing_update(room, &db.globals).unwrap(), first);
+
+        // Expired users are removed
+        edus.typing_add(bob, room, now - 1, &db.globals).unwrap();
+        edus.typings_maintain_all(&db.globals).unwrap();
+        assert_eq!(
+            edus.typings_all(room).unwrap().content.user_ids,
+            vec![alice.to_owned()]
+        );
+        assert!(edus.last_typing_update(room, &db.globals).unwrap() > first);
+
+        assert!(edus.typing_remove(alice, room, &db.globals).unwrap());
+        assert!(!edus.typing_remove(alice, room, &db.globals).unwrap());
+        assert!(edus.typings_all(room).unwrap().content.user_ids.is_empty());
+
+        // Leaving the room stops typing right away
+        let room = room_id!("!typing:example.org");
+        crate::database::test_room(&db, room, alice).await;
+        edus.typing_add(alice, room, now + 60_000, &db.globals)
+            .unwrap();
+        test_send(
+            &db,
+            room,
+            alice,
+            "m.room.member",
+            Some(alice.as_str()),
+            json!({ "membership": "leave" }),
+        )
+        .await
+        .unwrap();
+        assert!(edus.typings_all(room).unwrap().content.user_ids.is_empty());
+    }
 
-        Ok(hashmap)
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn idle_users_become_unavailable() {
+        use super::PRESENCE_IDLE_TIMEOUT;
+        use crate::utils;
+        use ruma::{
+            events::presence::{PresenceEvent, PresenceEventContent},
+            presence::PresenceState,
+            room_id, user_id,
+        };
+
+        let db = crate::database::test_database("presence").await;
+        let db = db.read().await;
+        let edus = &db.rooms.edus;
+
+        let room = room_id!("!presence:example.org");
+        let alice = user_id!("@alice:example.org");
+        crate::database::test_room(&db, room, alice).await;
+
+        let since = db.globals.current_count().unwrap();
+        edus.update_presence(
+            alice,
+            room,
+            PresenceEvent {
+                content: PresenceEventContent {
+                    avatar_url: None,
+                    currently_active: None,
+                    displayname: None,
+                    last_active_ago: Some(utils::millis_since_unix_epoch().try_into().unwrap()),
+                    presence: PresenceState::Online,
+                    status_msg: Some("Working".to_owned()),
+                },
+                sender: alice.to_owned(),
+            },
+            &db.globals,
+        )
+        .unwrap();
+
+        let updates = edus
+            .presence_since(room, since, &db.rooms, &db.globals)
+            .unwrap();
+        assert_eq!(updates[alice].content.presence, PresenceState::Online);
+        assert_eq!(updates[alice].content.last_active_ago, None);
+
+        // Active users stay online
+        assert!(edus
+            .presence_maintain(&db.rooms, &db.globals)
+            .unwrap()
+            .is_empty());
+
+        let since = db.globals.current_count().unwrap();
+        assert!(edus
+            .presence_since(room, since, &db.rooms, &db.globals)
+            .unwrap()
+            .is_empty());
+
+        let last_active = utils::millis_since_unix_epoch() - PRESENCE_IDLE_TIMEOUT - 1;
+        edus.presence_last_update
+            .write()
+            .unwrap()
+            .insert(alice.to_owned(), last_active);
+
+        let updated = edus.presence_maintain(&db.rooms, &db.globals).unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].1.content.presence, PresenceState::Unavailable);
+
+        let presence = edus.get_last_presence_event(alice, room).unwrap().unwrap();
+        assert_eq!(presence.content.presence, PresenceState::Unavailable);
+        assert_eq!(presence.content.status_msg.as_deref(), Some("Working"));
+        assert!(u64::from(presence.content.last_active_ago.unwrap()) > PRESENCE_IDLE_TIMEOUT);
+        assert!(edus
+            .presence_since(room, since, &db.rooms, &db.globals)
+            .unwrap()
+            .contains_key(alice));
     }
 }
@@ -93,6 +93,16 @@ enum TransactionStatus {
 }
 
 impl Sending {
+    /// Number of events and EDUs that still have to be sent to other servers. Requests to
+    /// appservices and push gateways are not counted.
+    pub fn federation_queue_len(&self) -> usize {
+        self.servernameevent_data
+            .iter()
+            .chain(self.servercurrentevent_data.iter())
+            .filter(|(key, _)| !key.starts_with(b"+") && !key.starts_with(b"$"))
+            .count()
+    }
+
     pub fn start_handler(
         &self,
         db: Arc<RwLock<Database>>,
@@ -0,0 +1,415 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{utils, Error, Result};
+use ruma::{api::client::error::ErrorKind, thirdparty::Medium, UserId};
+use serde::{Deserialize, Serialize};
+
+use super::abstraction::Tree;
+
+/// Third party identifiers (email addresses) bound to local accounts.
+pub struct ThreePid {
+    pub(super) threepid_userid: Arc<dyn Tree>, // ThreePid = Medium + Address
+    pub(super) userthreepid_addedat: Arc<dyn Tree>, // UserThreePid = UserId + Medium + Address
+    pub(super) sessionid_validationsession: Arc<dyn Tree>, // ValidationSession as json
+    pub(super) secretthreepid_sessionid: Arc<dyn Tree>, // SecretThreePid = ClientSecret + ThreePid
+    pub(super) expiresatsessionid: Arc<dyn Tree>, // ExpiresAtSessionId = ExpiresAt + SessionId
+    /// Held while an address is checked and bound, so that it can't be bound twice
+    pub(super) add_lock: Mutex<()>,
+}
+
+/// Wrong codes after which a session can't be used anymore, the code is short enough to be
+/// guessed otherwise.
+const MAX_TOKEN_ATTEMPTS: u32 = 5;
+
+/// A pending proof that a user controls a third party identifier.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidationSession {
+    pub client_secret: String,
+    pub medium: Medium,
+    pub address: String,
+    /// The code that was sent to the address
+    pub token: String,
+    pub send_attempt: u64,
+    /// Milliseconds since the unix epoch after which the session can't be used anymore
+    pub expires_at: u64,
+    /// Milliseconds since the unix epoch when the code was submitted
+    pub validated_at: Option<u64>,
+    /// How often a wrong code was submitted
+    #[serde(default)]
+    pub failed_attempts: u32,
+}
+
+impl ThreePid {
+    /// Stores a new validation session. Expired sessions are purged at the same time.
+    pub fn create_session(&self, sid: &str, session: &ValidationSession, now: u64) -> Result<()> {
+        self.purge_expired_sessions(now)?;
+
+        self.sessionid_validationsession.insert(
+            sid.as_bytes(),
+            &serde_json::to_vec(session).expect("ValidationSession can be serialized"),
+        )?;
+        self.secretthreepid_sessionid
+            .insert(&secret_threepid_key(session), sid.as_bytes())?;
+        self.expiresatsessionid
+            .insert(&expires_at_key(session.expires_at, sid), &[])
+    }
+
+    /// Returns the session a client already started for this address, if it has not expired.
+    pub fn find_session(
+        &self,
+        client_secret: &str,
+        medium: &Medium,
+        address: &str,
+        now: u64,
+    ) -> Result<Option<(String, ValidationSession)>> {
+        let mut key = client_secret.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid_key(medium, address));
+
+        let sid = match self.secretthreepid_sessionid.get(&key)? {
+            Some(sid) => utils::string_from_bytes(&sid).map_err(|_| {
+                Error::bad_database("Session id in secretthreepid_sessionid is invalid.")
+            })?,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .session(&sid, client_secret, now)?
+            .filter(|session| &session.medium == medium && session.address == address)
+            .map(|session| (sid, session)))
+    }
+
+    /// Marks a session as validated if the token matches. Returns false otherwise, the session is
+    /// removed after too many wrong tokens.
+    pub fn submit_token(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+        now: u64,
+    ) -> Result<bool> {
+        let mut session = match self.session(sid, client_secret, now)? {
+            Some(session) => session,
+            None => return Ok(false),
+        };
+
+        if !tokens_match(&session.token, token) {
+            session.failed_attempts += 1;
+
+            if session.failed_attempts >= MAX_TOKEN_ATTEMPTS {
+                self.remove_session(sid, &session)?;
+            } else {
+                self.sessionid_validationsession.insert(
+                    sid.as_bytes(),
+                    &serde_json::to_vec(&session).expect("ValidationSession can be serialized"),
+                )?;
+            }
+
+            return Ok(false);
+        }
+
+        session.validated_at.get_or_insert(now);
+        self.sessionid_validationsession.insert(
+            sid.as_bytes(),
+            &serde_json::to_vec(&session).expect("ValidationSession can be serialized"),
+        )?;
+
+        Ok(true)
+    }
+
+    /// Returns the session if the code was submitted and it has not expired yet.
+    pub fn validated_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        now: u64,
+    ) -> Result<Option<ValidationSession>> {
+        Ok(self
+            .session(sid, client_secret, now)?
+            .filter(|session| session.validated_at.is_some()))
+    }
+
+    fn session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        now: u64,
+    ) -> Result<Option<ValidationSession>> {
+        Ok(self
+            .sessionid_validationsession
+            .get(sid.as_bytes())?
+            .map(|bytes| decode_session(&bytes))
+            .transpose()?
+            .filter(|session| session.client_secret == client_secret && now < session.expires_at))
+    }
+
+    fn remove_session(&self, sid: &str, session: &ValidationSession) -> Result<()> {
+        self.sessionid_validationsession.remove(sid.as_bytes())?;
+        self.expiresatsessionid
+            .remove(&expires_at_key(session.expires_at, sid))?;
+
+        // A newer session of the same client may have replaced the index entry
+        let key = secret_threepid_key(session);
+        if self.secretthreepid_sessionid.get(&key)?.as_deref() == Some(sid.as_bytes()) {
+            self.secretthreepid_sessionid.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the sessions that expired, they are ordered by their expiry time.
+    fn purge_expired_sessions(&self, now: u64) -> Result<()> {
+        let expired = self
+            .expiresatsessionid
+            .iter()
+            .take_while(|(key, _)| {
+                key.get(..8)
+                    .and_then(|expires_at| utils::u64_from_bytes(expires_at).ok())
+                    .map_or(true, |expires_at| expires_at <= now)
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            self.expiresatsessionid.remove(&key)?;
+
+            let sid = match key.get(8..).map(utils::string_from_bytes) {
+                Some(Ok(sid)) => sid,
+                _ => continue,
+            };
+            if let Some(bytes) = self.sessionid_validationsession.get(sid.as_bytes())? {
+                self.remove_session(&sid, &decode_session(&bytes)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Binds an address to a user. Fails if another user already has it.
+    pub fn add(&self, user_id: &UserId, medium: &Medium, address: &str, now: u64) -> Result<()> {
+        let _lock = self.add_lock.lock().unwrap();
+
+        match self.user_id(medium, address)? {
+            Some(owner) if &*owner != user_id => {
+                return Err(Error::BadRequest(
+                    ErrorKind::ThreepidInUse,
+                    "This address is already in use by another account.",
+                ))
+            }
+            Some(_) => return Ok(()),
+            None => {}
+        }
+
+        self.threepid_userid
+            .insert(&threepid_key(medium, address), user_id.as_bytes())?;
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid_key(medium, address));
+        self.userthreepid_addedat.insert(&key, &now.to_be_bytes())
+    }
+
+    /// Unbinds an address from a user.
+    pub fn remove(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<()> {
+        let _lock = self.add_lock.lock().unwrap();
+
+        if self.user_id(medium, address)?.as_deref() == Some(user_id) {
+            self.threepid_userid
+                .remove(&threepid_key(medium, address))?;
+        }
+
+        let mut key = user_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&threepid_key(medium, address));
+        self.userthreepid_addedat.remove(&key)
+    }
+
+    /// Returns the user an address is bound to.
+    pub fn user_id(&self, medium: &Medium, address: &str) -> Result<Option<Box<UserId>>> {
+        self.threepid_userid
+            .get(&threepid_key(medium, address))?
+            .map(|bytes| {
+                UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("User ID in threepid_userid is invalid unicode.")
+                })?)
+                .map_err(|_| Error::bad_database("User ID in threepid_userid is invalid."))
+            })
+            .transpose()
+    }
+
+    /// Returns all addresses bound to a user and when they were added.
+    pub fn threepids<'a>(
+        &'a self,
+        user_id: &UserId,
+    ) -> impl Iterator<Item = Result<(Medium, String, u64)>> + 'a {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.userthreepid_addedat
+            .scan_prefix(prefix.clone())
+            .map(move |(key, added_at)| {
+                let mut parts = key[prefix.len()..].splitn(2, |&b| b == 0xff);
+                let mut next_part = || {
+                    parts
+                        .next()
+                        .and_then(|part| utils::string_from_bytes(part).ok())
+                        .ok_or_else(|| Error::bad_database("Invalid key in userthreepid_addedat."))
+                };
+                let medium = next_part()?.into();
+                let address = next_part()?;
+                let added_at = utils::u64_from_bytes(&added_at).map_err(|_| {
+                    Error::bad_database("Invalid timestamp in userthreepid_addedat.")
+                })?;
+
+                Ok((medium, address, added_at))
+            })
+    }
+}
+
+fn threepid_key(medium: &Medium, address: &str) -> Vec<u8> {
+    let mut key = medium.as_str().as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(address.to_lowercase().as_bytes());
+    key
+}
+
+fn secret_threepid_key(session: &ValidationSession) -> Vec<u8> {
+    let mut key = session.client_secret.as_bytes().to_vec();
+    key.push(0xff);
+    key.extend_from_slice(&threepid_key(&session.medium, &session.address));
+    key
+}
+
+fn expires_at_key(expires_at: u64, sid: &str) -> Vec<u8> {
+    let mut key = expires_at.to_be_bytes().to_vec();
+    key.extend_from_slice(sid.as_bytes());
+    key
+}
+
+/// Compares in constant time, so the time it takes doesn't tell how much of the token was right.
+fn tokens_match(expected: &str, submitted: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(expected.as_bytes(), submitted.as_bytes()).is_ok()
+}
+
+fn decode_session(bytes: &[u8]) -> Result<ValidationSession> {
+    serde_json::from_slice(bytes)
+        .map_err(|_| Error::bad_database("Invalid session in sessionid_validationsession."))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn addresses_are_bound_once_and_sessions_expire() {
+        use super::{ThreePid, ValidationSession};
+        use crate::{
+            database::abstraction::{sqlite::test_engine, DatabaseEngine},
+            Error,
+        };
+        use ruma::{api::client::error::ErrorKind, thirdparty::Medium, user_id};
+        use std::sync::Mutex;
+
+        let (engine, config) = test_engine("threepid");
+        let threepid = ThreePid {
+            threepid_userid: engine.open_tree("threepid_userid").unwrap(),
+            userthreepid_addedat: engine.open_tree("userthreepid_addedat").unwrap(),
+            sessionid_validationsession: engine.open_tree("sessionid_validationsession").unwrap(),
+            secretthreepid_sessionid: engine.open_tree("secretthreepid_sessionid").unwrap(),
+            expiresatsessionid: engine.open_tree("expiresatsessionid").unwrap(),
+            add_lock: Mutex::new(()),
+        };
+
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        threepid
+            .add(alice, &Medium::Email, "alice@example.org", 10)
+            .unwrap();
+        assert!(matches!(
+            threepid.add(bob, &Medium::Email, "Alice@example.org", 20),
+            Err(Error::BadRequest(ErrorKind::ThreepidInUse, _))
+        ));
+        assert_eq!(
+            threepid
+                .threepids(alice)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![(Medium::Email, "alice@example.org".to_owned(), 10)]
+        );
+        assert_eq!(threepid.threepids(bob).count(), 0);
+
+        // Unbinding frees the address for other users
+        threepid
+            .remove(alice, &Medium::Email, "alice@example.org")
+            .unwrap();
+        assert_eq!(threepid.threepids(alice).count(), 0);
+        threepid
+            .add(bob, &Medium::Email, "alice@example.org", 30)
+            .unwrap();
+
+        let session = ValidationSession {
+            client_secret: "secret".to_owned(),
+            medium: Medium::Email,
+            address: "bob@example.org".to_owned(),
+            token: "123456".to_owned(),
+            send_attempt: 1,
+            expires_at: 1000,
+            validated_at: None,
+            failed_attempts: 0,
+        };
+        threepid.create_session("sid", &session, 0).unwrap();
+        assert_eq!(
+            threepid
+                .find_session("secret", &Medium::Email, "bob@example.org", 10)
+                .unwrap()
+                .map(|(sid, _)| sid),
+            Some("sid".to_owned())
+        );
+
+        assert!(!threepid
+            .submit_token("sid", "secret", "000000", 10)
+            .unwrap());
+        assert!(threepid
+            .validated_session("sid", "secret", 10)
+            .unwrap()
+            .is_none());
+        assert!(threepid
+            .submit_token("sid", "secret", "123456", 10)
+            .unwrap());
+        assert!(threepid
+            .validated_session("sid", "other", 20)
+            .unwrap()
+            .is_none());
+        assert!(threepid
+            .validated_session("sid", "secret", 20)
+            .unwrap()
+            .is_some());
+        assert!(threepid
+            .validated_session("sid", "secret", 1000)
+            .unwrap()
+            .is_none());
+
+        // Guessing the code ends the session
+        threepid.create_session("guessed", &session, 0).unwrap();
+        for _ in 0..5 {
+            assert!(!threepid
+                .submit_token("guessed", "secret", "000000", 10)
+                .unwrap());
+        }
+        assert!(!threepid
+            .submit_token("guessed", "secret", "123456", 10)
+            .unwrap());
+
+        // Creating a session purges the expired ones
+        threepid.create_session("later", &session, 1000).unwrap();
+        assert!(threepid
+            .sessionid_validationsession
+            .get(b"sid")
+            .unwrap()
+            .is_none());
+
+        drop(threepid);
+        drop(engine);
+        std::fs::remove_dir_all(&config.database_path).unwrap();
+    }
+}
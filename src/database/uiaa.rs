@@ -8,8 +8,8 @@ use ruma::{
     api::client::{
         error::ErrorKind,
         uiaa::{
-            AuthType, IncomingAuthData, IncomingPassword,
-            IncomingUserIdentifier::UserIdOrLocalpart, UiaaInfo,
+            AuthType, IncomingAuthData, IncomingEmailIdentity, IncomingPassword,
+            IncomingRegistrationToken, IncomingUserIdentifier::UserIdOrLocalpart, UiaaInfo,
         },
     },
     signatures::CanonicalJsonValue,
@@ -55,6 +55,7 @@ impl Uiaa {
         auth: &IncomingAuthData,
         uiaainfo: &UiaaInfo,
         users: &super::users::Users,
+        threepid: &super::threepid::ThreePid,
         globals: &super::globals::Globals,
     ) -> Result<(bool, UiaaInfo)> {
         let mut uiaainfo = auth
@@ -109,6 +110,50 @@ impl Uiaa {
             IncomingAuthData::Dummy(_) => {
                 uiaainfo.completed.push(AuthType::Dummy);
             }
+            IncomingAuthData::RegistrationToken(IncomingRegistrationToken { token, .. }) => {
+                // The use is counted right away, the stage may not be the last one of the flow.
+                // Concurrent registrations can't use the token more often than allowed.
+                if !uiaainfo.completed.contains(&AuthType::RegistrationToken)
+                    && !users.use_registration_token(token)?
+                {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                        kind: ErrorKind::Forbidden,
+                        message: "Invalid registration token.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+
+                if !uiaainfo.completed.contains(&AuthType::RegistrationToken) {
+                    uiaainfo.completed.push(AuthType::RegistrationToken);
+                }
+            }
+            IncomingAuthData::EmailIdentity(IncomingEmailIdentity {
+                thirdparty_id_creds,
+                ..
+            }) => {
+                let validated = threepid
+                    .validated_session(
+                        thirdparty_id_creds.sid.as_str(),
+                        thirdparty_id_creds.client_secret.as_str(),
+                        utils::millis_since_unix_epoch(),
+                    )?
+                    .is_some();
+
+                if !validated {
+                    uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+                        kind: ErrorKind::ThreepidAuthFailed,
+                        message: "The email address has not been verified.".to_owned(),
+                    });
+                    return Ok((false, uiaainfo));
+                }
+
+                uiaainfo.completed.push(AuthType::EmailIdentity);
+            }
+            IncomingAuthData::ReCaptcha(_) => {
+                // Checking the captcha needs a request to its provider, so the caller has to
+                // verify the response before, see `register_route`
+                uiaainfo.completed.push(AuthType::ReCaptcha);
+            }
             k => error!("type not supported: {:?}", k),
         }
 
@@ -144,6 +189,29 @@ impl Uiaa {
         Ok((true, uiaainfo))
     }
 
+    /// Returns the session with an error for a stage the caller verified itself.
+    pub fn stage_failed(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        auth: &IncomingAuthData,
+        uiaainfo: &UiaaInfo,
+        kind: ErrorKind,
+        message: &str,
+    ) -> Result<UiaaInfo> {
+        let mut uiaainfo = auth
+            .session()
+            .map(|session| self.get_uiaa_session(user_id, device_id, session))
+            .unwrap_or_else(|| Ok(uiaainfo.clone()))?;
+
+        uiaainfo.auth_error = Some(ruma::api::client::error::ErrorBody {
+            kind,
+            message: message.to_owned(),
+        });
+
+        Ok(uiaainfo)
+    }
+
     fn set_uiaa_request(
         &self,
         user_id: &UserId,
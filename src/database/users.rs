@@ -1,17 +1,27 @@
 use crate::{utils, Error, Result};
+use lru_cache::LruCache;
 use ruma::{
     api::client::{device::Device, error::ErrorKind, filter::IncomingFilterDefinition},
     encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
     events::{AnyToDeviceEvent, StateEventType},
     serde::Raw,
     DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, MxcUri, RoomAliasId,
-    UInt, UserId,
+    RoomId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use std::{collections::BTreeMap, mem, sync::Arc};
 use tracing::warn;
 
 use super::abstraction::Tree;
 
+/// How long clients can still learn that the server invalidated their access token.
+const SOFT_LOGOUT_LIFETIME_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
 pub struct Users {
     pub(super) userid_password: Arc<dyn Tree>,
     pub(super) userid_displayname: Arc<dyn Tree>,
@@ -21,6 +31,13 @@ pub struct Users {
     pub(super) userdeviceid_metadata: Arc<dyn Tree>, // This is also used to check if a device exists
     pub(super) userid_devicelistversion: Arc<dyn Tree>, // DevicelistVersion = u64
     pub(super) token_userdeviceid: Arc<dyn Tree>,
+    /// Tokens the server invalidated, clients using them get a soft logout
+    pub(super) softlogouttoken_userdeviceid: Arc<dyn Tree>,
+    pub(super) userdeviceid_softlogouttoken: Arc<dyn Tree>, // SoftLogoutToken = ExpiresAt + Token
+    pub(super) expiresat_softlogouttoken: Arc<dyn Tree>,    // ExpiresAt + Token
+    pub(super) token_expiresat: Arc<dyn Tree>, // ExpiresAt = u64, only refreshable tokens expire
+    pub(super) refreshtoken_userdeviceid: Arc<dyn Tree>,
+    pub(super) userdeviceid_refreshtoken: Arc<dyn Tree>,
 
     pub(super) onetimekeyid_onetimekeys: Arc<dyn Tree>, // OneTimeKeyId = UserId + DeviceKeyId
     pub(super) userid_lastonetimekeyupdate: Arc<dyn Tree>, // LastOneTimeKeyUpdate = Count
@@ -33,6 +50,159 @@ pub struct Users {
     pub(super) userfilterid_filter: Arc<dyn Tree>, // UserFilterId = UserId + FilterId
 
     pub(super) todeviceid_events: Arc<dyn Tree>, // ToDeviceId = UserId + DeviceId + Count
+
+    pub(super) userid_servernoticeroomid: Arc<dyn Tree>,
+    pub(super) guestuserids: Arc<dyn Tree>,
+    pub(super) registrationtoken_info: Arc<dyn Tree>, // Info = RegistrationTokenInfo as json
+    pub(super) userid_pendingdeactivation: Arc<dyn Tree>, // PendingDeactivation as json
+    pub(super) userid_inactivity: Arc<dyn Tree>,      // Inactivity as json
+    pub(super) inactivityexemptuserids: Arc<dyn Tree>,
+    pub(super) lockeduserids: Arc<dyn Tree>,
+
+    pub(super) remote_keys_cache: Mutex<LruCache<Box<UserId>, CachedRemoteKeys>>,
+    pub(super) remote_device_streams: Mutex<LruCache<Box<UserId>, u64>>, // Last seen stream id
+    pub(super) registration_token_mutex: Mutex<()>,
+    pub(super) device_metadata_mutex: Mutex<()>,
+}
+
+/// Usage limits of a registration token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RegistrationTokenInfo {
+    /// How often the token may be used, unlimited if not set
+    pub uses_allowed: Option<u64>,
+    /// How often the token was used to register
+    pub completed: u64,
+    /// Milliseconds since the unix epoch after which the token can't be used anymore
+    pub expiry_time: Option<u64>,
+}
+
+impl RegistrationTokenInfo {
+    /// Whether the token can still be used at the given time.
+    pub fn is_valid(&self, now: u64) -> bool {
+        self.uses_allowed
+            .map_or(true, |allowed| self.completed < allowed)
+            && self.expiry_time.map_or(true, |expiry| now < expiry)
+    }
+}
+
+/// An account that was deactivated but can still be restored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingDeactivation {
+    /// The password hash to restore
+    pub password_hash: String,
+    /// Milliseconds since the unix epoch after which the deactivation is finalized
+    pub finalize_at: u64,
+    /// Whether the profile and messages of the user are erased once the deactivation is finalized
+    #[serde(default)]
+    pub erase: bool,
+}
+
+impl PendingDeactivation {
+    /// Whether the grace period is over at the given time.
+    pub fn is_due(&self, now: u64) -> bool {
+        now >= self.finalize_at
+    }
+}
+
+/// How long an account was not used and whether its user was warned about the deactivation.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Inactivity {
+    /// Milliseconds since the unix epoch when a device of the user was last seen
+    pub last_active: u64,
+    pub warnings_sent: usize,
+    /// Set once the first warning was sent, a late warning moves the deactivation back
+    pub deactivate_at: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InactivityAction {
+    Nothing,
+    /// Send the next warning, the account will be deactivated at the given time
+    Warn {
+        deactivate_at: u64,
+    },
+    Deactivate,
+}
+
+impl Inactivity {
+    pub fn new(last_active: u64) -> Self {
+        Self {
+            last_active,
+            warnings_sent: 0,
+            deactivate_at: None,
+        }
+    }
+
+    /// What to do with the account at the given time. `warnings` are the times before the
+    /// deactivation users are warned at, longest first. Accounts are only deactivated after all
+    /// warnings were sent and the last one had the time it announced.
+    pub fn action(&self, now: u64, period: Duration, warnings: &[Duration]) -> InactivityAction {
+        let deactivate_at = self.deactivate_at.unwrap_or_else(|| {
+            self.last_active
+                .saturating_add(period.as_millis().try_into().unwrap_or(u64::MAX))
+        });
+
+        match warnings.get(self.warnings_sent) {
+            Some(warning) => {
+                let warning = warning.as_millis().try_into().unwrap_or(u64::MAX);
+                if now >= deactivate_at.saturating_sub(warning) {
+                    InactivityAction::Warn {
+                        deactivate_at: deactivate_at.max(now.saturating_add(warning)),
+                    }
+                } else {
+                    InactivityAction::Nothing
+                }
+            }
+            None if now >= deactivate_at => InactivityAction::Deactivate,
+            None => InactivityAction::Nothing,
+        }
+    }
+}
+
+/// Device and cross-signing keys of a remote user, as last fetched over federation.
+#[derive(Clone)]
+pub struct CachedRemoteKeys {
+    keychange: Option<u64>,
+    fetched_at: Instant,
+    pub device_keys: BTreeMap<Box<DeviceId>, Raw<DeviceKeys>>,
+    pub master_key: Option<Raw<CrossSigningKey>>,
+    pub self_signing_key: Option<Raw<CrossSigningKey>>,
+}
+
+impl CachedRemoteKeys {
+    /// Refetch from time to time in case we missed a device list update.
+    const MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+    /// The keys are still valid if no device list update arrived since they were fetched.
+    fn is_fresh(&self, keychange: Option<u64>, now: Instant) -> bool {
+        self.keychange == keychange
+            && now.saturating_duration_since(self.fetched_at) < Self::MAX_AGE
+    }
+}
+
+/// What to do with a device list update of a remote user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceListUpdateAction {
+    /// The update follows the last one we saw, apply it to the cached keys.
+    Apply,
+    /// We already saw a newer update.
+    Ignore,
+    /// Updates were lost or arrived out of order, refetch all keys with `/user/keys/query`.
+    Resync,
+}
+
+/// Decides how to handle a device list update based on the stream id of the last update we saw.
+fn device_list_update_action(
+    last_stream_id: Option<u64>,
+    stream_id: u64,
+    prev_ids: &[u64],
+) -> DeviceListUpdateAction {
+    match last_stream_id {
+        Some(last) if stream_id <= last => DeviceListUpdateAction::Ignore,
+        Some(last) if prev_ids.contains(&last) => DeviceListUpdateAction::Apply,
+        // Without a common previous update there's no way to know what we missed
+        _ => DeviceListUpdateAction::Resync,
+    }
 }
 
 impl Users {
@@ -65,15 +235,22 @@ impl Users {
     ) -> Result<bool> {
         let admin_room_alias_id = RoomAliasId::parse(format!("#admins:{}", globals.server_name()))
             .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid alias."))?;
-        let admin_room_id = rooms.id_from_alias(&admin_room_alias_id)?.unwrap();
-
-        rooms.is_joined(user_id, &admin_room_id)
+        match rooms.id_from_alias(&admin_room_alias_id)? {
+            Some(admin_room_id) => rooms.is_joined(user_id, &admin_room_id),
+            // Without an admin room there are no admins
+            None => Ok(false),
+        }
     }
 
     /// Create a new user account on this homeserver.
-    #[tracing::instrument(skip(self, user_id, password))]
-    pub fn create(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
-        self.set_password(user_id, password)?;
+    #[tracing::instrument(skip(self, user_id, password, globals))]
+    pub fn create(
+        &self,
+        user_id: &UserId,
+        password: Option<&str>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
+        self.set_password(user_id, password, globals)?;
         Ok(())
     }
 
@@ -111,6 +288,30 @@ impl Users {
             })
     }
 
+    /// Finds the user and device of an access token a client sent. Fails if the token is unknown
+    /// or expired, or if the account is locked and `allow_locked` is false.
+    pub fn authenticate(&self, token: &str, allow_locked: bool) -> Result<(Box<UserId>, String)> {
+        match self.find_from_token(token)? {
+            None => Err(Error::BadRequest(
+                ErrorKind::UnknownToken {
+                    soft_logout: self.is_soft_logged_out(token)?,
+                },
+                "Unknown access token.",
+            )),
+            Some(_) if self.is_token_expired(token, utils::millis_since_unix_epoch())? => {
+                // The client can get a new one with its refresh token
+                Err(Error::BadRequest(
+                    ErrorKind::UnknownToken { soft_logout: true },
+                    "Access token has expired.",
+                ))
+            }
+            Some((user_id, _)) if !allow_locked && self.is_locked(&user_id)? => {
+                Err(Error::UserLocked)
+            }
+            Some(user_and_device) => Ok(user_and_device),
+        }
+    }
+
     /// Returns an iterator over all users on this homeserver.
     #[tracing::instrument(skip(self))]
     pub fn iter(&self) -> impl Iterator<Item = Result<Box<UserId>>> + '_ {
@@ -171,10 +372,15 @@ impl Users {
     }
 
     /// Hash and set the user's password to the Argon2 hash
-    #[tracing::instrument(skip(self, user_id, password))]
-    pub fn set_password(&self, user_id: &UserId, password: Option<&str>) -> Result<()> {
+    #[tracing::instrument(skip(self, user_id, password, globals))]
+    pub fn set_password(
+        &self,
+        user_id: &UserId,
+        password: Option<&str>,
+        globals: &super::globals::Globals,
+    ) -> Result<()> {
         if let Some(password) = password {
-            if let Ok(hash) = utils::calculate_hash(password) {
+            if let Ok(hash) = utils::calculate_hash(password, globals.password_hashing()) {
                 self.userid_password
                     .insert(user_id.as_bytes(), hash.as_bytes())?;
                 Ok(())
@@ -288,6 +494,7 @@ impl Users {
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
 
+        let _lock = self.device_metadata_mutex.lock().unwrap();
         self.userdeviceid_metadata.insert(
             &userdeviceid,
             &serde_json::to_vec(&Device {
@@ -304,17 +511,26 @@ impl Users {
         Ok(())
     }
 
-    /// Removes a device from a user.
+    /// Removes a device from a user. If the device didn't log itself out, e.g. because another
+    /// device removed it, `soft_logout` lets the client log in again without throwing away its
+    /// encryption keys.
     #[tracing::instrument(skip(self, user_id, device_id))]
-    pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+    pub fn remove_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        soft_logout: bool,
+    ) -> Result<()> {
         let mut userdeviceid = user_id.as_bytes().to_vec();
         userdeviceid.push(0xff);
         userdeviceid.extend_from_slice(device_id.as_bytes());
 
         // Remove tokens
-        if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
-            self.userdeviceid_token.remove(&userdeviceid)?;
-            self.token_userdeviceid.remove(&old_token)?;
+        if soft_logout {
+            self.invalidate_token(&userdeviceid, Some(utils::millis_since_unix_epoch()))?;
+        } else {
+            self.invalidate_token(&userdeviceid, None)?;
+            self.forget_soft_logout(&userdeviceid)?;
         }
 
         // Remove todevice events
@@ -330,11 +546,104 @@ impl Users {
         self.userid_devicelistversion
             .increment(user_id.as_bytes())?;
 
+        let _lock = self.device_metadata_mutex.lock().unwrap();
         self.userdeviceid_metadata.remove(&userdeviceid)?;
 
         Ok(())
     }
 
+    /// Invalidates the access token of a device without removing the device, e.g. because an
+    /// admin reset the password. The client gets a soft logout and can log in again as the same
+    /// device without throwing away its encryption keys.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn soft_logout_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        now: u64,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.invalidate_token(&userdeviceid, Some(now))
+    }
+
+    /// Removes the access and refresh token of a device. With `soft_logout_at`, clients using the
+    /// old token get a soft logout for a while.
+    fn invalidate_token(&self, userdeviceid: &[u8], soft_logout_at: Option<u64>) -> Result<()> {
+        if let Some(old_token) = self.userdeviceid_token.get(userdeviceid)? {
+            self.userdeviceid_token.remove(userdeviceid)?;
+            self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(&old_token)?;
+
+            if let Some(now) = soft_logout_at {
+                self.forget_soft_logout(userdeviceid)?;
+                let mut softlogouttoken = now
+                    .saturating_add(SOFT_LOGOUT_LIFETIME_MS)
+                    .to_be_bytes()
+                    .to_vec();
+                softlogouttoken.extend_from_slice(&old_token);
+
+                self.softlogouttoken_userdeviceid
+                    .insert(&old_token, userdeviceid)?;
+                self.userdeviceid_softlogouttoken
+                    .insert(userdeviceid, &softlogouttoken)?;
+                self.expiresat_softlogouttoken
+                    .insert(&softlogouttoken, &[])?;
+            }
+        }
+        self.forget_refresh_token(userdeviceid)?;
+
+        Ok(())
+    }
+
+    /// Whether the server invalidated the token, e.g. by a password reset, and the client should
+    /// log in again without throwing away its encryption keys.
+    pub fn is_soft_logged_out(&self, token: &str) -> Result<bool> {
+        Ok(self
+            .softlogouttoken_userdeviceid
+            .get(token.as_bytes())?
+            .is_some())
+    }
+
+    /// Forgets soft logouts that clients had enough time to notice. Afterwards the tokens are just
+    /// unknown.
+    pub fn purge_soft_logouts(&self, now: u64) -> Result<()> {
+        let expired = self
+            .expiresat_softlogouttoken
+            .iter()
+            .take_while(|(key, _)| {
+                key.get(..8)
+                    .and_then(|expires_at| utils::u64_from_bytes(expires_at).ok())
+                    .map_or(true, |expires_at| expires_at <= now)
+            })
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            let token = key.get(8..).unwrap_or_default();
+            if let Some(userdeviceid) = self.softlogouttoken_userdeviceid.get(token)? {
+                self.userdeviceid_softlogouttoken.remove(&userdeviceid)?;
+            }
+            self.softlogouttoken_userdeviceid.remove(token)?;
+            self.expiresat_softlogouttoken.remove(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn forget_soft_logout(&self, userdeviceid: &[u8]) -> Result<()> {
+        if let Some(softlogouttoken) = self.userdeviceid_softlogouttoken.get(userdeviceid)? {
+            self.userdeviceid_softlogouttoken.remove(userdeviceid)?;
+            self.expiresat_softlogouttoken.remove(&softlogouttoken)?;
+            self.softlogouttoken_userdeviceid
+                .remove(softlogouttoken.get(8..).unwrap_or_default())?;
+        }
+
+        Ok(())
+    }
+
     /// Returns an iterator over all device ids of this user.
     #[tracing::instrument(skip(self, user_id))]
     pub fn all_device_ids<'a>(
@@ -371,8 +680,13 @@ impl Users {
         // Remove old token
         if let Some(old_token) = self.userdeviceid_token.get(&userdeviceid)? {
             self.token_userdeviceid.remove(&old_token)?;
+            self.token_expiresat.remove(&old_token)?;
             // It will be removed from userdeviceid_token by the insert later
         }
+        // A refresh token belongs to the old access token
+        self.forget_refresh_token(&userdeviceid)?;
+        // The client logged in again, its old token is just unknown now
+        self.forget_soft_logout(&userdeviceid)?;
 
         // Assign token to user device combination
         self.userdeviceid_token
@@ -383,6 +697,82 @@ impl Users {
         Ok(())
     }
 
+    /// Lets the access token of a device expire and stores the refresh token the client can trade
+    /// for a new one. Must be called after [`set_token`](Self::set_token).
+    #[tracing::instrument(skip(self, user_id, device_id, token, refresh_token))]
+    pub fn set_refresh_token(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        token: &str,
+        expires_at: u64,
+        refresh_token: &str,
+    ) -> Result<()> {
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        self.forget_refresh_token(&userdeviceid)?;
+
+        self.token_expiresat
+            .insert(token.as_bytes(), &expires_at.to_be_bytes())?;
+        self.refreshtoken_userdeviceid
+            .insert(refresh_token.as_bytes(), &userdeviceid)?;
+        self.userdeviceid_refreshtoken
+            .insert(&userdeviceid, refresh_token.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Find out which device a refresh token belongs to.
+    #[tracing::instrument(skip(self, refresh_token))]
+    pub fn find_from_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(Box<UserId>, Box<DeviceId>)>> {
+        self.refreshtoken_userdeviceid
+            .get(refresh_token.as_bytes())?
+            .map_or(Ok(None), |bytes| {
+                let mut parts = bytes.splitn(2, |&b| b == 0xff);
+                let invalid =
+                    || Error::bad_database("Invalid userdeviceid in refreshtoken_userdeviceid.");
+
+                let user_id = UserId::parse(
+                    parts
+                        .next()
+                        .and_then(|part| utils::string_from_bytes(part).ok())
+                        .ok_or_else(invalid)?,
+                )
+                .map_err(|_| invalid())?;
+                let device_id = parts
+                    .next()
+                    .and_then(|part| utils::string_from_bytes(part).ok())
+                    .ok_or_else(invalid)?;
+
+                Ok(Some((user_id, device_id.into())))
+            })
+    }
+
+    /// Whether a refreshable access token has expired. Other tokens never expire.
+    pub fn is_token_expired(&self, token: &str, now: u64) -> Result<bool> {
+        self.token_expiresat
+            .get(token.as_bytes())?
+            .map_or(Ok(false), |bytes| {
+                utils::u64_from_bytes(&bytes)
+                    .map(|expires_at| expires_at <= now)
+                    .map_err(|_| Error::bad_database("Invalid expiry time in token_expiresat."))
+            })
+    }
+
+    fn forget_refresh_token(&self, userdeviceid: &[u8]) -> Result<()> {
+        if let Some(refresh_token) = self.userdeviceid_refreshtoken.get(userdeviceid)? {
+            self.userdeviceid_refreshtoken.remove(userdeviceid)?;
+            self.refreshtoken_userdeviceid.remove(&refresh_token)?;
+        }
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip(
         self,
         user_id,
@@ -953,6 +1343,8 @@ impl Users {
         userdeviceid.push(0xff);
         userdeviceid.extend_from_slice(device_id.as_bytes());
 
+        let _lock = self.device_metadata_mutex.lock().unwrap();
+
         // Only existing devices should be able to call this.
         assert!(self.userdeviceid_metadata.get(&userdeviceid)?.is_some());
 
@@ -967,6 +1359,202 @@ impl Users {
         Ok(())
     }
 
+    /// Marks the device as active now, so it isn't treated as stale.
+    ///
+    /// The metadata is only rewritten every few minutes to keep syncing cheap.
+    #[tracing::instrument(skip(self, user_id, device_id))]
+    pub fn touch_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+        // Renames and removals in the meantime must not be overwritten with the old metadata
+        let _lock = self.device_metadata_mutex.lock().unwrap();
+
+        let mut device = match self.get_device_metadata(user_id, device_id)? {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+
+        let now = MilliSecondsSinceUnixEpoch::now();
+        let recently_seen = device.last_seen_ts.map_or(false, |last_seen| {
+            u64::from(now.get()).saturating_sub(last_seen.get().into()) < 5 * 60 * 1000
+        });
+        if recently_seen {
+            return Ok(());
+        }
+        device.last_seen_ts = Some(now);
+
+        let mut userdeviceid = user_id.as_bytes().to_vec();
+        userdeviceid.push(0xff);
+        userdeviceid.extend_from_slice(device_id.as_bytes());
+
+        // Not a device list change, so the devicelist version stays the same
+        self.userdeviceid_metadata.insert(
+            &userdeviceid,
+            &serde_json::to_vec(&device).expect("Device::to_string always works"),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the number of undelivered to-device messages of all devices of this user.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn pending_to_device_events(&self, user_id: &UserId) -> usize {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.todeviceid_events.scan_prefix(prefix).count()
+    }
+
+    /// Returns the count of the newest device key change of this user.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn last_keychange(&self, user_id: &UserId) -> Result<Option<u64>> {
+        let mut prefix = user_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        let mut last = prefix.clone();
+        last.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        self.keychangeid_userid
+            .iter_from(&last, true)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .next()
+            .map(|(k, _)| {
+                utils::u64_from_bytes(&k[prefix.len()..])
+                    .map_err(|_| Error::bad_database("KeyChangeId has invalid count bytes."))
+            })
+            .transpose()
+    }
+
+    /// Returns the cached keys of a remote user unless their device list changed since.
+    pub fn cached_remote_keys(&self, user_id: &UserId) -> Result<Option<CachedRemoteKeys>> {
+        let keychange = self.last_keychange(user_id)?;
+
+        Ok(self
+            .remote_keys_cache
+            .lock()
+            .unwrap()
+            .get_mut(user_id)
+            .filter(|cached| cached.is_fresh(keychange, Instant::now()))
+            .cloned())
+    }
+
+    /// Caches the keys of a remote user. `keychange` must be the result of `last_keychange` from
+    /// before the keys were requested, so that updates arriving in between aren't lost.
+    pub fn cache_remote_keys(
+        &self,
+        user_id: &UserId,
+        keychange: Option<u64>,
+        device_keys: BTreeMap<Box<DeviceId>, Raw<DeviceKeys>>,
+        master_key: Option<Raw<CrossSigningKey>>,
+        self_signing_key: Option<Raw<CrossSigningKey>>,
+    ) {
+        self.remote_keys_cache.lock().unwrap().insert(
+            user_id.to_owned(),
+            CachedRemoteKeys {
+                keychange,
+                fetched_at: Instant::now(),
+                device_keys,
+                master_key,
+                self_signing_key,
+            },
+        );
+    }
+
+    /// Handles an `m.device_list_update` EDU of a remote user.
+    ///
+    /// Consecutive updates change the cached keys in place. If updates were missed, the cached
+    /// keys are dropped and the caller has to fetch all of them again with
+    /// `resync_remote_keys`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_remote_device_list_update(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        stream_id: u64,
+        prev_ids: &[u64],
+        deleted: bool,
+        keys: Option<Raw<DeviceKeys>>,
+        rooms: &super::rooms::Rooms,
+        globals: &super::globals::Globals,
+    ) -> Result<DeviceListUpdateAction> {
+        let action = {
+            let mut streams = self.remote_device_streams.lock().unwrap();
+            let action = device_list_update_action(
+                streams.get_mut(user_id).map(|last| *last),
+                stream_id,
+                prev_ids,
+            );
+            if action != DeviceListUpdateAction::Ignore {
+                streams.insert(user_id.to_owned(), stream_id);
+            }
+            action
+        };
+
+        match action {
+            DeviceListUpdateAction::Ignore => return Ok(action),
+            DeviceListUpdateAction::Apply => {
+                if let Some(cached) = self.remote_keys_cache.lock().unwrap().get_mut(user_id) {
+                    if deleted {
+                        cached.device_keys.remove(device_id);
+                    } else if let Some(keys) = keys {
+                        cached.device_keys.insert(device_id.to_owned(), keys);
+                    }
+                }
+            }
+            DeviceListUpdateAction::Resync => {
+                self.remote_keys_cache.lock().unwrap().remove(user_id);
+            }
+        }
+
+        // Local clients still have to learn about the change
+        self.mark_device_key_update(user_id, rooms, globals)?;
+
+        if action == DeviceListUpdateAction::Apply {
+            let keychange = self.last_keychange(user_id)?;
+            if let Some(cached) = self.remote_keys_cache.lock().unwrap().get_mut(user_id) {
+                cached.keychange = keychange;
+            }
+        }
+
+        Ok(action)
+    }
+
+    /// Removes undelivered to-device messages of inactive devices and caps the queue of all other
+    /// devices. Returns the number of removed messages.
+    #[tracing::instrument(skip(self))]
+    pub fn trim_to_device_events(
+        &self,
+        inactive_for: Duration,
+        max_per_device: usize,
+    ) -> Result<usize> {
+        let cutoff = utils::millis_since_unix_epoch()
+            .saturating_sub(inactive_for.as_millis().try_into().unwrap_or(u64::MAX));
+
+        let mut removed = 0;
+        for (userdeviceid, bytes) in self.userdeviceid_metadata.iter() {
+            let device = match serde_json::from_slice::<Device>(&bytes) {
+                Ok(device) => device,
+                Err(_) => {
+                    warn!("Device in userdeviceid_metadata is invalid.");
+                    continue;
+                }
+            };
+
+            let stale = device
+                .last_seen_ts
+                .map_or(true, |last_seen| u64::from(last_seen.get()) < cutoff);
+
+            let mut prefix = userdeviceid;
+            prefix.push(0xff);
+
+            removed += trim_to_device_queue(
+                &*self.todeviceid_events,
+                prefix,
+                if stale { 0 } else { max_per_device },
+            )?;
+        }
+
+        Ok(removed)
+    }
+
     /// Get device metadata.
     #[tracing::instrument(skip(self, user_id, device_id))]
     pub fn get_device_metadata(
@@ -1019,7 +1607,7 @@ impl Users {
     pub fn deactivate_account(&self, user_id: &UserId) -> Result<()> {
         // Remove all associated devices
         for device_id in self.all_device_ids(user_id) {
-            self.remove_device(user_id, &device_id?)?;
+            self.remove_device(user_id, &device_id?, false)?;
         }
 
         // Set the password to "" to indicate a deactivated account. Hashes will never result in an
@@ -1031,6 +1619,265 @@ impl Users {
         Ok(())
     }
 
+    /// Returns the room the server sends notices to this user in, if it was created yet.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn server_notice_room(&self, user_id: &UserId) -> Result<Option<Box<RoomId>>> {
+        self.userid_servernoticeroomid
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                RoomId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
+                    Error::bad_database("Room ID in userid_servernoticeroomid is invalid unicode.")
+                })?)
+                .map_err(|_| {
+                    Error::bad_database("Room ID in userid_servernoticeroomid is invalid.")
+                })
+            })
+            .transpose()
+    }
+
+    /// Stores a new registration token or replaces an existing one.
+    pub fn set_registration_token(&self, token: &str, info: &RegistrationTokenInfo) -> Result<()> {
+        self.registrationtoken_info.insert(
+            token.as_bytes(),
+            &serde_json::to_vec(info).expect("RegistrationTokenInfo can be serialized"),
+        )
+    }
+
+    /// Returns the limits of a registration token, if it exists.
+    pub fn registration_token(&self, token: &str) -> Result<Option<RegistrationTokenInfo>> {
+        self.registrationtoken_info
+            .get(token.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid registration token info in database.")
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns all registration tokens and their limits.
+    pub fn registration_tokens<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = Result<(String, RegistrationTokenInfo)>> + 'a {
+        self.registrationtoken_info.iter().map(|(token, info)| {
+            Ok((
+                utils::string_from_bytes(&token).map_err(|_| {
+                    Error::bad_database("Registration token in database is invalid unicode.")
+                })?,
+                serde_json::from_slice(&info).map_err(|_| {
+                    Error::bad_database("Invalid registration token info in database.")
+                })?,
+            ))
+        })
+    }
+
+    /// Deletes a registration token. Returns false if it didn't exist.
+    pub fn remove_registration_token(&self, token: &str) -> Result<bool> {
+        if self.registrationtoken_info.get(token.as_bytes())?.is_none() {
+            return Ok(false);
+        }
+
+        self.registrationtoken_info.remove(token.as_bytes())?;
+        Ok(true)
+    }
+
+    /// Whether a registration token exists and can still be used.
+    pub fn registration_token_valid(&self, token: &str) -> Result<bool> {
+        Ok(self.registration_token(token)?.map_or(false, |info| {
+            info.is_valid(utils::millis_since_unix_epoch())
+        }))
+    }
+
+    /// Counts a registration with this token. Returns false if the token can't be used (anymore).
+    ///
+    /// Concurrent registrations can't use the same token more often than allowed.
+    pub fn use_registration_token(&self, token: &str) -> Result<bool> {
+        let _lock = self.registration_token_mutex.lock().unwrap();
+
+        let mut info = match self.registration_token(token)? {
+            Some(info) if info.is_valid(utils::millis_since_unix_epoch()) => info,
+            _ => return Ok(false),
+        };
+
+        info.completed += 1;
+        self.set_registration_token(token, &info)?;
+
+        Ok(true)
+    }
+
+    /// Deactivates an account, but keeps what is needed to restore it until `finalize_at`.
+    ///
+    /// Devices are removed right away, rooms are only left and requested erasures only happen once
+    /// the deactivation is finalized.
+    pub fn schedule_deactivation(
+        &self,
+        user_id: &UserId,
+        finalize_at: u64,
+        erase: bool,
+    ) -> Result<()> {
+        let password_hash = self
+            .userid_password
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                utils::string_from_bytes(&bytes)
+                    .map_err(|_| Error::bad_database("Password hash in db is not valid string."))
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        self.userid_pendingdeactivation.insert(
+            user_id.as_bytes(),
+            &serde_json::to_vec(&PendingDeactivation {
+                password_hash,
+                finalize_at,
+                erase,
+            })
+            .expect("PendingDeactivation can be serialized"),
+        )?;
+
+        self.deactivate_account(user_id)
+    }
+
+    /// Returns all deactivations that are not finalized yet.
+    pub fn pending_deactivations<'a>(
+        &'a self,
+    ) -> impl Iterator<Item = Result<(Box<UserId>, PendingDeactivation)>> + 'a {
+        self.userid_pendingdeactivation
+            .iter()
+            .map(|(user_id, pending)| {
+                Ok((
+                    UserId::parse(utils::string_from_bytes(&user_id).map_err(|_| {
+                        Error::bad_database(
+                            "User ID in userid_pendingdeactivation is invalid unicode.",
+                        )
+                    })?)
+                    .map_err(|_| {
+                        Error::bad_database("User ID in userid_pendingdeactivation is invalid.")
+                    })?,
+                    serde_json::from_slice(&pending).map_err(|_| {
+                        Error::bad_database("Invalid pending deactivation in database.")
+                    })?,
+                ))
+            })
+    }
+
+    /// Returns the inactivity of a user at the given time. Any device activity since the last
+    /// check starts over, accounts without devices count as active when they are first checked.
+    pub fn inactivity(&self, user_id: &UserId, now: u64) -> Result<Inactivity> {
+        let last_seen = self
+            .all_devices_metadata(user_id)
+            .filter_map(|device| device.ok()?.last_seen_ts)
+            .map(|ts| u64::from(ts.get()))
+            .max();
+
+        let stored = self
+            .userid_inactivity
+            .get(user_id.as_bytes())?
+            .map(|bytes| {
+                serde_json::from_slice::<Inactivity>(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid inactivity in database."))
+            })
+            .transpose()?;
+
+        Ok(match (stored, last_seen) {
+            (Some(stored), Some(last_seen)) if last_seen > stored.last_active => {
+                Inactivity::new(last_seen)
+            }
+            (Some(stored), _) => stored,
+            (None, last_seen) => Inactivity::new(last_seen.unwrap_or(now)),
+        })
+    }
+
+    pub fn set_inactivity(&self, user_id: &UserId, inactivity: &Inactivity) -> Result<()> {
+        self.userid_inactivity.insert(
+            user_id.as_bytes(),
+            &serde_json::to_vec(inactivity).expect("Inactivity can be serialized"),
+        )
+    }
+
+    pub fn forget_inactivity(&self, user_id: &UserId) -> Result<()> {
+        self.userid_inactivity.remove(user_id.as_bytes())
+    }
+
+    /// Exempts a user from the deactivation of inactive accounts, or removes the exemption.
+    pub fn set_inactivity_exempt(&self, user_id: &UserId, exempt: bool) -> Result<()> {
+        if exempt {
+            self.inactivityexemptuserids.insert(user_id.as_bytes(), &[])
+        } else {
+            self.inactivityexemptuserids.remove(user_id.as_bytes())
+        }
+    }
+
+    pub fn is_inactivity_exempt(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self
+            .inactivityexemptuserids
+            .get(user_id.as_bytes())?
+            .is_some())
+    }
+
+    /// Locks or unlocks an account. Locked users keep their devices and rooms, but can't use their
+    /// access tokens except to log out.
+    pub fn set_locked(&self, user_id: &UserId, locked: bool) -> Result<()> {
+        if locked {
+            self.lockeduserids.insert(user_id.as_bytes(), &[])
+        } else {
+            self.lockeduserids.remove(user_id.as_bytes())
+        }
+    }
+
+    pub fn is_locked(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.lockeduserids.get(user_id.as_bytes())?.is_some())
+    }
+
+    /// Undoes a deactivation during its grace period. Returns false if there is nothing to
+    /// restore.
+    ///
+    /// The password hash is restored as it was, accounts without a password, e.g. of
+    /// appservices, keep logging in the way they did before.
+    pub fn restore_account(&self, user_id: &UserId) -> Result<bool> {
+        let pending: PendingDeactivation =
+            match self.userid_pendingdeactivation.get(user_id.as_bytes())? {
+                Some(bytes) => serde_json::from_slice(&bytes).map_err(|_| {
+                    Error::bad_database("Invalid pending deactivation in database.")
+                })?,
+                None => return Ok(false),
+            };
+
+        if pending.is_due(utils::millis_since_unix_epoch()) {
+            return Ok(false);
+        }
+
+        self.userid_password
+            .insert(user_id.as_bytes(), pending.password_hash.as_bytes())?;
+        self.userid_pendingdeactivation.remove(user_id.as_bytes())?;
+
+        Ok(true)
+    }
+
+    /// Forgets a pending deactivation after it was finalized.
+    pub fn finish_deactivation(&self, user_id: &UserId) -> Result<()> {
+        self.userid_pendingdeactivation.remove(user_id.as_bytes())
+    }
+
+    /// Remembers the server notice room of this user.
+    #[tracing::instrument(skip(self, user_id, room_id))]
+    pub fn set_server_notice_room(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+        self.userid_servernoticeroomid
+            .insert(user_id.as_bytes(), room_id.as_bytes())
+    }
+
+    /// Marks the user as a guest account.
+    #[tracing::instrument(skip(self))]
+    pub fn set_guest(&self, user_id: &UserId) -> Result<()> {
+        self.guestuserids.insert(user_id.as_bytes(), &[])
+    }
+
+    /// Check if a user registered as a guest.
+    #[tracing::instrument(skip(self))]
+    pub fn is_guest(&self, user_id: &UserId) -> Result<bool> {
+        Ok(self.guestuserids.get(user_id.as_bytes())?.is_some())
+    }
+
     /// Creates a new sync filter. Returns the filter id.
     #[tracing::instrument(skip(self))]
     pub fn create_filter(
@@ -1099,3 +1946,370 @@ fn clean_signatures<F: Fn(&UserId) -> bool>(
 
     Ok(())
 }
+
+/// Removes all but the newest `keep` to-device events below `prefix`.
+fn trim_to_device_queue(events: &dyn Tree, prefix: Vec<u8>, keep: usize) -> Result<usize> {
+    let keys = events
+        .scan_prefix(prefix)
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+
+    let excess = keys.len().saturating_sub(keep);
+    for key in &keys[..excess] {
+        events.remove(key)?;
+    }
+
+    Ok(excess)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        device_list_update_action, CachedRemoteKeys, DeviceListUpdateAction, Inactivity,
+        InactivityAction, PendingDeactivation, RegistrationTokenInfo,
+    };
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn remote_keys_cache_is_invalidated_by_device_list_updates() {
+        let fetched_at = Instant::now();
+        let cached = CachedRemoteKeys {
+            keychange: Some(5),
+            fetched_at,
+            device_keys: BTreeMap::new(),
+            master_key: None,
+            self_signing_key: None,
+        };
+
+        // Repeated queries for an unchanged user hit the cache
+        assert!(cached.is_fresh(Some(5), fetched_at + Duration::from_secs(1)));
+        // A device list update bumps the keychange count
+        assert!(!cached.is_fresh(Some(6), fetched_at + Duration::from_secs(1)));
+        assert!(!cached.is_fresh(Some(5), fetched_at + CachedRemoteKeys::MAX_AGE));
+    }
+
+    #[test]
+    fn gapped_device_list_update_triggers_resync() {
+        use DeviceListUpdateAction::*;
+
+        // Nothing to build on after a restart
+        assert_eq!(device_list_update_action(None, 3, &[2]), Resync);
+        assert_eq!(device_list_update_action(Some(2), 3, &[2]), Apply);
+        // Update 3 was lost
+        assert_eq!(device_list_update_action(Some(2), 4, &[3]), Resync);
+        // Update 3 arrives after 4
+        assert_eq!(device_list_update_action(Some(4), 3, &[2]), Ignore);
+        assert_eq!(device_list_update_action(Some(4), 4, &[3]), Ignore);
+        // An empty prev_id asks for a resync
+        assert_eq!(device_list_update_action(Some(4), 5, &[]), Resync);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn stale_to_device_events_are_trimmed() {
+        use super::trim_to_device_queue;
+        use crate::database::abstraction::{sqlite::test_engine, DatabaseEngine};
+
+        let (engine, config) = test_engine("todevice");
+        let events = engine.open_tree("todeviceid_events").unwrap();
+
+        let prefix = |device: &str| {
+            let mut prefix = b"@alice:example.org".to_vec();
+            prefix.push(0xff);
+            prefix.extend_from_slice(device.as_bytes());
+            prefix.push(0xff);
+            prefix
+        };
+        for count in 0..5_u64 {
+            for device in ["STALE", "ACTIVE"] {
+                let mut key = prefix(device);
+                key.extend_from_slice(&count.to_be_bytes());
+                events.insert(&key, b"{}").unwrap();
+            }
+        }
+
+        // The stale device loses everything, the active one keeps its newest messages
+        assert_eq!(
+            trim_to_device_queue(&*events, prefix("STALE"), 0).unwrap(),
+            5
+        );
+        assert_eq!(
+            trim_to_device_queue(&*events, prefix("ACTIVE"), 3).unwrap(),
+            2
+        );
+
+        assert_eq!(events.scan_prefix(prefix("STALE")).count(), 0);
+        let remaining = events
+            .scan_prefix(prefix("ACTIVE"))
+            .map(|(key, _)| key[key.len() - 8..].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remaining,
+            [2_u64, 3, 4].map(|c| c.to_be_bytes().to_vec()).to_vec()
+        );
+
+        drop(events);
+        drop(engine);
+        std::fs::remove_dir_all(&config.database_path).unwrap();
+    }
+
+    #[test]
+    fn registration_token_is_exhausted_by_its_uses() {
+        let mut info = RegistrationTokenInfo {
+            uses_allowed: Some(2),
+            completed: 0,
+            expiry_time: None,
+        };
+
+        assert!(info.is_valid(0));
+        info.completed += 1;
+        assert!(info.is_valid(0));
+        info.completed += 1;
+        assert!(!info.is_valid(0));
+    }
+
+    #[test]
+    fn registration_token_expires() {
+        let info = RegistrationTokenInfo {
+            uses_allowed: None,
+            completed: 1000,
+            expiry_time: Some(5000),
+        };
+
+        assert!(info.is_valid(4999));
+        assert!(!info.is_valid(5000));
+    }
+
+    #[test]
+    fn deactivation_is_finalized_after_grace_period() {
+        let pending = PendingDeactivation {
+            password_hash: "$argon2id$hash".to_owned(),
+            finalize_at: 5000,
+            erase: false,
+        };
+
+        // Within the grace period the account can still be restored
+        assert!(!pending.is_due(4999));
+        assert!(pending.is_due(5000));
+
+        // Deactivations scheduled before erasures were deferred never erase
+        let old: PendingDeactivation =
+            serde_json::from_str(r#"{"password_hash":"","finalize_at":5000}"#).unwrap();
+        assert!(!old.erase);
+    }
+
+    #[test]
+    fn inactive_accounts_are_deactivated_after_warnings() {
+        const DAY: u64 = 24 * 60 * 60 * 1000;
+        let period = Duration::from_millis(365 * DAY);
+        let warnings = [
+            Duration::from_millis(30 * DAY),
+            Duration::from_millis(7 * DAY),
+        ];
+
+        // Recently active
+        let active = Inactivity::new(100 * DAY);
+        assert_eq!(
+            active.action(200 * DAY, period, &warnings),
+            InactivityAction::Nothing
+        );
+
+        // Inactive past the threshold, but never warned: the deactivation moves back
+        let mut inactive = Inactivity::new(0);
+        let now = 400 * DAY;
+        assert_eq!(
+            inactive.action(now, period, &warnings),
+            InactivityAction::Warn {
+                deactivate_at: now + 30 * DAY
+            }
+        );
+        inactive.warnings_sent = 1;
+        inactive.deactivate_at = Some(now + 30 * DAY);
+        assert_eq!(
+            inactive.action(now + DAY, period, &warnings),
+            InactivityAction::Nothing
+        );
+        assert_eq!(
+            inactive.action(now + 23 * DAY, period, &warnings),
+            InactivityAction::Warn {
+                deactivate_at: now + 30 * DAY
+            }
+        );
+        inactive.warnings_sent = 2;
+        assert_eq!(
+            inactive.action(now + 29 * DAY, period, &warnings),
+            InactivityAction::Nothing
+        );
+
+        // All warnings elapsed
+        assert_eq!(
+            inactive.action(now + 30 * DAY, period, &warnings),
+            InactivityAction::Deactivate
+        );
+        assert_eq!(
+            active.action(now + 30 * DAY, period, &warnings),
+            InactivityAction::Nothing
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn only_server_side_logouts_are_soft_and_they_expire() {
+        use super::SOFT_LOGOUT_LIFETIME_MS;
+        use crate::Error;
+        use ruma::{api::client::error::ErrorKind, device_id, user_id};
+
+        let db = crate::database::test_database("soft-logout").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        db.users
+            .create(alice, Some("password"), &db.globals)
+            .unwrap();
+        db.users
+            .create_device(alice, device_id!("RESET"), "reset_token", None)
+            .unwrap();
+        db.users
+            .create_device(alice, device_id!("LOGOUT"), "logout_token", None)
+            .unwrap();
+        db.users
+            .create_device(alice, device_id!("REMOVED"), "removed_token", None)
+            .unwrap();
+
+        let soft_logout = |token: &str| match db.users.authenticate(token, false) {
+            Err(Error::BadRequest(ErrorKind::UnknownToken { soft_logout }, _)) => soft_logout,
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        };
+
+        let now = 1_000;
+        db.users
+            .soft_logout_device(alice, device_id!("RESET"), now)
+            .unwrap();
+        db.users
+            .remove_device(alice, device_id!("LOGOUT"), false)
+            .unwrap();
+        db.users
+            .remove_device(alice, device_id!("REMOVED"), true)
+            .unwrap();
+
+        // The server or another device invalidated the token, logging out was the client's own
+        // choice
+        assert!(soft_logout("reset_token"));
+        assert!(soft_logout("removed_token"));
+        assert!(!soft_logout("logout_token"));
+
+        // Clients had enough time to notice
+        db.users
+            .purge_soft_logouts(now + SOFT_LOGOUT_LIFETIME_MS - 1)
+            .unwrap();
+        assert!(soft_logout("reset_token"));
+        db.users
+            .purge_soft_logouts(now + SOFT_LOGOUT_LIFETIME_MS)
+            .unwrap();
+        assert!(!soft_logout("reset_token"));
+
+        // Logging in again as the same device works as before
+        db.users
+            .set_token(alice, device_id!("RESET"), "new_token")
+            .unwrap();
+        assert!(db.users.authenticate("new_token", false).is_ok());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn deactivated_accounts_can_be_restored_during_the_grace_period() {
+        use crate::utils;
+        use ruma::user_id;
+
+        let db = crate::database::test_database("restore-account").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+        let carol = user_id!("@carol:example.org");
+        db.users
+            .create(alice, Some("password"), &db.globals)
+            .unwrap();
+        db.users.create(bob, None, &db.globals).unwrap();
+        db.users
+            .create(carol, Some("password"), &db.globals)
+            .unwrap();
+        let alice_hash = db.users.password_hash(alice).unwrap();
+
+        let later = utils::millis_since_unix_epoch() + 60_000;
+        db.users.schedule_deactivation(alice, later, false).unwrap();
+        db.users.schedule_deactivation(bob, later, false).unwrap();
+        db.users.schedule_deactivation(carol, 0, false).unwrap();
+        assert!(db.users.is_deactivated(alice).unwrap());
+
+        // The old password works again
+        assert!(db.users.restore_account(alice).unwrap());
+        assert!(!db.users.is_deactivated(alice).unwrap());
+        assert_eq!(db.users.password_hash(alice).unwrap(), alice_hash);
+        assert!(!db.users.restore_account(alice).unwrap());
+
+        // Accounts without a password don't get one
+        assert!(db.users.restore_account(bob).unwrap());
+        assert_eq!(db.users.password_hash(bob).unwrap(), Some(String::new()));
+        assert!(db
+            .users
+            .pending_deactivations()
+            .all(|r| r.unwrap().0 != bob));
+
+        // The grace period is over
+        assert!(!db.users.restore_account(carol).unwrap());
+        assert!(db.users.is_deactivated(carol).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn touching_a_device_keeps_its_metadata() {
+        use crate::utils;
+        use ruma::{api::client::device::Device, device_id, user_id, MilliSecondsSinceUnixEpoch};
+
+        let db = crate::database::test_database("touch-device").await;
+        let db = db.read().await;
+
+        let alice = user_id!("@alice:example.org");
+        let device = device_id!("ABCDEF");
+        db.users.create(alice, None, &db.globals).unwrap();
+        db.users
+            .create_device(alice, device, "token", None)
+            .unwrap();
+
+        let an_hour_ago = utils::millis_since_unix_epoch() - 60 * 60 * 1000;
+        db.users
+            .update_device_metadata(
+                alice,
+                device,
+                &Device {
+                    device_id: device.to_owned(),
+                    display_name: Some("Phone".to_owned()),
+                    last_seen_ip: None,
+                    last_seen_ts: Some(MilliSecondsSinceUnixEpoch(an_hour_ago.try_into().unwrap())),
+                },
+            )
+            .unwrap();
+
+        db.users.touch_device(alice, device).unwrap();
+        let touched = db
+            .users
+            .get_device_metadata(alice, device)
+            .unwrap()
+            .unwrap();
+        assert_eq!(touched.display_name.as_deref(), Some("Phone"));
+        assert!(u64::from(touched.last_seen_ts.unwrap().get()) > an_hour_ago);
+
+        // Removed devices stay removed
+        db.users.remove_device(alice, device, false).unwrap();
+        db.users.touch_device(alice, device).unwrap();
+        assert!(db
+            .users
+            .get_device_metadata(alice, device)
+            .unwrap()
+            .is_none());
+    }
+}
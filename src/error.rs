@@ -6,7 +6,7 @@ use ruma::{
         error::{Error as RumaError, ErrorKind},
         uiaa::{UiaaInfo, UiaaResponse},
     },
-    ServerName,
+    RoomId, ServerName,
 };
 use thiserror::Error;
 use tracing::{error, warn};
@@ -72,8 +72,17 @@ pub enum Error {
     Uiaa(UiaaInfo),
     #[error("{0}: {1}")]
     BadRequest(ErrorKind, &'static str),
+    #[error("{0}: {1}")]
+    /// Like BadRequest, for messages that need to include dynamic details.
+    BadRequestDetailed(ErrorKind, String),
     #[error("{0}")]
     Conflict(&'static str), // This is only needed for when a room alias already exists
+    #[error("This room has been replaced by {0}.")]
+    RoomReplaced(Box<RoomId>),
+    #[error("This account has been locked.")]
+    UserLocked,
+    #[error("Media has not been uploaded yet.")]
+    NotYetUploaded,
     #[cfg(feature = "conduit_bin")]
     #[error("{0}")]
     ExtensionError(#[from] axum::extract::rejection::ExtensionRejection),
@@ -110,7 +119,7 @@ impl Error {
 
         use ErrorKind::*;
         let (kind, status_code) = match self {
-            Self::BadRequest(kind, _) => (
+            Self::BadRequest(kind, _) | Self::BadRequestDetailed(kind, _) => (
                 kind.clone(),
                 match kind {
                     Forbidden | GuestAccessForbidden | ThreepidAuthFailed | ThreepidDenied => {
@@ -125,6 +134,9 @@ impl Error {
                 },
             ),
             Self::Conflict(_) => (Unknown, StatusCode::CONFLICT),
+            Self::RoomReplaced(_) => (Forbidden, StatusCode::FORBIDDEN),
+            Self::UserLocked => (Forbidden, StatusCode::LOCKED),
+            Self::NotYetUploaded => (NotFound, StatusCode::GATEWAY_TIMEOUT),
             _ => (Unknown, StatusCode::INTERNAL_SERVER_ERROR),
         };
 
@@ -153,9 +165,104 @@ impl From<Infallible> for Error {
     }
 }
 
+/// How long clients should wait before asking again for media that is not uploaded yet.
+pub const NOT_YET_UPLOADED_RETRY_MS: u64 = 1000;
+
+/// Unstable error field telling clients which room to use instead of a tombstoned one.
+pub const REPLACEMENT_ROOM_FIELD: &str = "org.conduit.replacement_room";
+
+impl Error {
+    /// The error body for a room that was replaced. Ruma errors can't carry extra fields, so it
+    /// is built by hand.
+    fn room_replaced_body(&self, replacement_room: &RoomId) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "errcode": "M_FORBIDDEN",
+            "error": self.to_string(),
+        });
+        body[REPLACEMENT_ROOM_FIELD] = replacement_room.as_str().into();
+
+        body
+    }
+
+    /// The error body for a locked account. Ruma has no error kind for it yet.
+    fn user_locked_body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "errcode": "M_USER_LOCKED",
+            "error": self.to_string(),
+            "soft_logout": true,
+        })
+    }
+
+    /// The error body for media whose content is not uploaded yet (MSC2246). Ruma has no error
+    /// kind for it yet.
+    fn not_yet_uploaded_body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "errcode": "M_NOT_YET_UPLOADED",
+            "error": self.to_string(),
+            "retry_after_ms": NOT_YET_UPLOADED_RETRY_MS,
+        })
+    }
+}
+
 #[cfg(feature = "conduit_bin")]
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
+        if let Self::RoomReplaced(replacement_room) = &self {
+            warn!("{}: {}", StatusCode::FORBIDDEN, self);
+            return (
+                StatusCode::FORBIDDEN,
+                axum::Json(self.room_replaced_body(replacement_room)),
+            )
+                .into_response();
+        }
+
+        if matches!(self, Self::UserLocked) {
+            warn!("{}: {}", StatusCode::LOCKED, self);
+            return (StatusCode::LOCKED, axum::Json(self.user_locked_body())).into_response();
+        }
+
+        if matches!(self, Self::NotYetUploaded) {
+            warn!("{}: {}", StatusCode::GATEWAY_TIMEOUT, self);
+            return (
+                StatusCode::GATEWAY_TIMEOUT,
+                axum::Json(self.not_yet_uploaded_body()),
+            )
+                .into_response();
+        }
+
         self.to_response().into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, NOT_YET_UPLOADED_RETRY_MS, REPLACEMENT_ROOM_FIELD};
+    use ruma::RoomId;
+
+    #[test]
+    fn room_replaced_error_carries_replacement_room() {
+        let replacement_room = RoomId::parse("!new:example.org").unwrap();
+        let error = Error::RoomReplaced(replacement_room.clone());
+
+        let body = error.room_replaced_body(&replacement_room);
+
+        assert_eq!(body["errcode"], "M_FORBIDDEN");
+        assert_eq!(body[REPLACEMENT_ROOM_FIELD], "!new:example.org");
+    }
+
+    #[test]
+    fn locked_user_error_is_soft_logout() {
+        let body = Error::UserLocked.user_locked_body();
+
+        assert_eq!(body["errcode"], "M_USER_LOCKED");
+        assert_eq!(body["soft_logout"], true);
+    }
+
+    #[test]
+    fn not_yet_uploaded_error_has_retry_time() {
+        let body = Error::NotYetUploaded.not_yet_uploaded_body();
+
+        assert_eq!(body["errcode"], "M_NOT_YET_UPLOADED");
+        assert_eq!(body["retry_after_ms"], NOT_YET_UPLOADED_RETRY_MS);
+    }
+}
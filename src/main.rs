@@ -13,7 +13,7 @@ use axum::{
     extract::{FromRequest, MatchedPath},
     handler::Handler,
     response::IntoResponse,
-    routing::{get, on, MethodFilter},
+    routing::{get, on, post, put, MethodFilter},
     Router,
 };
 use axum_server::{bind, bind_rustls, tls_rustls::RustlsConfig, Handle as ServerHandle};
@@ -22,11 +22,11 @@ use figment::{
     Figment,
 };
 use http::{
-    header::{self, HeaderName},
+    header::{self, HeaderName, HeaderValue},
     Method, Uri,
 };
 use opentelemetry::trace::{FutureExt, Tracer};
-use ruma::api::{client::error::ErrorKind, IncomingRequest};
+use ruma::api::{client::error::ErrorKind, IncomingRequest, OutgoingResponse};
 use tokio::{signal, sync::RwLock};
 use tower::ServiceBuilder;
 use tower_http::{
@@ -66,9 +66,33 @@ async fn main() {
         }
     };
 
+    // Validate the config and the existing database, then exit without serving requests
+    let check_config = std::env::args().skip(1).any(|arg| arg == "--check-config");
+
     let start = async {
         config.warn_deprecated();
 
+        // Only validate, without creating the database or the media directory
+        if check_config {
+            let result = config
+                .check(false)
+                .and_then(|()| Database::check(&config).map_err(|e| e.to_string()));
+
+            match result {
+                Ok(()) => println!("The configuration is valid."),
+                Err(e) => {
+                    eprintln!("The configuration check failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+
+        if let Err(e) = config.check(true) {
+            eprintln!("The startup self-check failed: {}", e);
+            std::process::exit(1);
+        }
+
         let db = match Database::load_or_create(&config).await {
             Ok(db) => db,
             Err(e) => {
@@ -124,6 +148,9 @@ async fn run_server(config: &Config, db: Arc<RwLock<Database>>) -> io::Result<()
     let addr = SocketAddr::from((config.address, config.port));
 
     let x_requested_with = HeaderName::from_static("x-requested-with");
+    let server_header = HeaderValue::from_str(&config.server_header())
+        .expect("server header only contains the version");
+    let metrics = Arc::clone(&db.read().await.globals.metrics);
 
     let middlewares = ServiceBuilder::new()
         .sensitive_headers([header::AUTHORIZATION])
@@ -138,7 +165,22 @@ async fn run_server(config: &Config, db: Arc<RwLock<Database>>) -> io::Result<()
                 tracing::info_span!("http_request", %path)
             }),
         )
+        .map_request(move |request: http::Request<_>| {
+            // Unmatched paths are counted together, anyone could make up new ones
+            let route = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map_or("unmatched", |path| path.as_str());
+            metrics.record_request(request.method().as_str(), route);
+            request
+        })
         .compression()
+        .map_response(move |mut response: http::Response<_>| {
+            response
+                .headers_mut()
+                .insert(header::SERVER, server_header.clone());
+            response
+        })
         .layer(
             CorsLayer::new()
                 .allow_origin(cors::Any)
@@ -160,7 +202,9 @@ async fn run_server(config: &Config, db: Arc<RwLock<Database>>) -> io::Result<()
         )
         .add_extension(db.clone());
 
-    let app = routes().layer(middlewares).into_make_service();
+    let app = routes()
+        .layer(middlewares)
+        .into_make_service_with_connect_info::<SocketAddr>();
     let handle = ServerHandle::new();
 
     tokio::spawn(shutdown_signal(handle.clone()));
@@ -186,6 +230,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_supported_versions_route)
         .ruma_route(client_server::get_register_available_route)
         .ruma_route(client_server::register_route)
+        .ruma_route(client_server::check_registration_token_validity_route)
         .ruma_route(client_server::get_login_types_route)
         .ruma_route(client_server::login_route)
         .ruma_route(client_server::whoami_route)
@@ -194,6 +239,9 @@ fn routes() -> Router {
         .ruma_route(client_server::change_password_route)
         .ruma_route(client_server::deactivate_route)
         .ruma_route(client_server::third_party_route)
+        .ruma_route(client_server::request_registration_token_via_email_route)
+        .ruma_route(client_server::request_3pid_management_token_via_email_route)
+        .ruma_route(client_server::add_3pid_route)
         .ruma_route(client_server::get_capabilities_route)
         .ruma_route(client_server::get_pushrules_all_route)
         .ruma_route(client_server::set_pushrule_route)
@@ -246,6 +294,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_alias_route)
         .ruma_route(client_server::join_room_by_id_route)
         .ruma_route(client_server::join_room_by_id_or_alias_route)
+        .ruma_route(client_server::knock_room_route)
         .ruma_route(client_server::joined_members_route)
         .ruma_route(client_server::leave_room_route)
         .ruma_route(client_server::forget_room_route)
@@ -258,6 +307,7 @@ fn routes() -> Router {
         .ruma_route(client_server::get_room_visibility_route)
         .ruma_route(client_server::get_public_rooms_route)
         .ruma_route(client_server::get_public_rooms_filtered_route)
+        .ruma_route(client_server::get_hierarchy_route)
         .ruma_route(client_server::search_users_route)
         .ruma_route(client_server::get_member_events_route)
         .ruma_route(client_server::get_protocols_route)
@@ -295,6 +345,7 @@ fn routes() -> Router {
         .ruma_route(client_server::turn_server_route)
         .ruma_route(client_server::send_event_to_device_route)
         .ruma_route(client_server::get_media_config_route)
+        .ruma_route(client_server::get_media_preview_route)
         .ruma_route(client_server::create_content_route)
         .ruma_route(client_server::get_content_route)
         .ruma_route(client_server::get_content_as_filename_route)
@@ -315,6 +366,59 @@ fn routes() -> Router {
         // .ruma_route(client_server::third_party_route)
         .ruma_route(client_server::upgrade_room_route)
         .ruma_route(server_server::get_server_version_route)
+        .route(
+            "/.well-known/matrix/client",
+            get(client_server::well_known_client_route),
+        )
+        .route(
+            "/_synapse/admin/v1/register",
+            get(client_server::get_shared_secret_register_nonce_route)
+                .post(client_server::shared_secret_register_route),
+        )
+        .route(
+            "/_matrix/client/v3/refresh",
+            post(client_server::refresh_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc2918/refresh",
+            post(client_server::refresh_route),
+        )
+        .route(
+            "/_matrix/client/r0/password_policy",
+            get(client_server::get_password_policy_route),
+        )
+        .route(
+            "/_matrix/client/unstable/conduit/email/submit_token",
+            get(client_server::submit_email_token_link_route)
+                .post(client_server::submit_email_token_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4108/rendezvous",
+            post(client_server::create_rendezvous_route),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc4108/rendezvous/:session_id",
+            get(client_server::get_rendezvous_route)
+                .put(client_server::update_rendezvous_route)
+                .delete(client_server::delete_rendezvous_route),
+        )
+        .route(
+            "/_matrix/media/v1/create",
+            post(client_server::create_mxc_uri_route),
+        )
+        .route(
+            "/_matrix/media/unstable/fi.mau.msc2246/create",
+            post(client_server::create_mxc_uri_route),
+        )
+        .route(
+            "/_matrix/media/v3/upload/:server_name/:media_id",
+            put(client_server::upload_reserved_content_route),
+        )
+        .route(
+            "/_matrix/media/unstable/fi.mau.msc2246/upload/:server_name/:media_id",
+            put(client_server::upload_reserved_content_route),
+        )
+        .route("/metrics", get(client_server::metrics_route))
         .route(
             "/_matrix/key/v2/server",
             get(server_server::get_server_keys_route),
@@ -334,6 +438,8 @@ fn routes() -> Router {
         .ruma_route(server_server::create_join_event_template_route)
         .ruma_route(server_server::create_join_event_v1_route)
         .ruma_route(server_server::create_join_event_v2_route)
+        .ruma_route(server_server::create_knock_event_template_route)
+        .ruma_route(server_server::create_knock_event_v1_route)
         .ruma_route(server_server::create_invite_route)
         .ruma_route(server_server::get_devices_route)
         .ruma_route(server_server::get_room_information_route)
@@ -404,11 +510,13 @@ macro_rules! impl_ruma_handler {
     ( $($ty:ident),* $(,)? ) => {
         #[axum::async_trait]
         #[allow(non_snake_case)]
-        impl<Req, E, F, Fut, $($ty,)*> RumaHandler<($($ty,)* Ruma<Req>,)> for F
+        impl<Req, Resp, E, F, Fut, $($ty,)*> RumaHandler<($($ty,)* Ruma<Req>,)> for F
         where
             Req: IncomingRequest + Send + 'static,
             F: FnOnce($($ty,)* Ruma<Req>) -> Fut + Clone + Send + 'static,
-            Fut: Future<Output = Result<Req::OutgoingResponse, E>>
+            // Usually Req::OutgoingResponse, but some handlers add fields ruma doesn't know yet
+            Resp: OutgoingResponse,
+            Fut: Future<Output = Result<Resp, E>>
                 + Send,
             E: IntoResponse,
             $( $ty: FromRequest<axum::body::Body> + Send + 'static, )*
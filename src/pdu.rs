@@ -1,8 +1,9 @@
 use crate::{Database, Error};
 use ruma::{
     events::{
-        room::member::RoomMemberEventContent, AnyEphemeralRoomEvent, AnyRoomEvent, AnyStateEvent,
-        AnyStrippedStateEvent, AnySyncRoomEvent, AnySyncStateEvent, RoomEventType, StateEvent,
+        room::member::RoomMemberEventContent, space::child::HierarchySpaceChildEvent,
+        AnyEphemeralRoomEvent, AnyRoomEvent, AnyStateEvent, AnyStrippedStateEvent,
+        AnySyncRoomEvent, AnySyncStateEvent, RoomEventType, StateEvent,
     },
     serde::{CanonicalJsonObject, CanonicalJsonValue, Raw},
     state_res, EventId, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
@@ -46,6 +47,14 @@ pub struct PduEvent {
 }
 
 impl PduEvent {
+    /// Whether the event was already redacted.
+    pub fn is_redacted(&self) -> bool {
+        self.unsigned.as_ref().map_or(false, |unsigned| {
+            serde_json::from_str::<serde_json::Value>(unsigned.get())
+                .map_or(false, |unsigned| unsigned.get("redacted_because").is_some())
+        })
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn redact(&mut self, reason: &PduEvent) -> crate::Result<()> {
         self.unsigned = None;
@@ -80,9 +89,14 @@ impl PduEvent {
             }
         }
 
-        self.unsigned = Some(to_raw_value(&json!({
-            "redacted_because": serde_json::to_value(reason).expect("to_value(PduEvent) always works")
-        })).expect("to string always works"));
+        // Clients read the reason of the redaction from its content, so the redaction event is
+        // served in client format here, without federation-only fields
+        let mut reason = reason.clone();
+        reason.remove_transaction_id()?;
+        self.unsigned = Some(
+            to_raw_value(&json!({ "redacted_because": reason.to_room_event() }))
+                .expect("to string always works"),
+        );
 
         self.content = to_raw_value(&new_content).expect("to string always works");
 
@@ -210,6 +224,19 @@ impl PduEvent {
         serde_json::from_value(json).expect("Raw::from_value always works")
     }
 
+    #[tracing::instrument(skip(self))]
+    pub fn to_stripped_spacechild_state_event(&self) -> Raw<HierarchySpaceChildEvent> {
+        let json = json!({
+            "content": self.content,
+            "type": self.kind,
+            "sender": self.sender,
+            "state_key": self.state_key,
+            "origin_server_ts": self.origin_server_ts,
+        });
+
+        serde_json::from_value(json).expect("Raw::from_value always works")
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn to_member_event(&self) -> Raw<StateEvent<RoomMemberEventContent>> {
         let json = json!({
@@ -368,3 +395,55 @@ pub struct PduBuilder {
     pub state_key: Option<String>,
     pub redacts: Option<Arc<EventId>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PduEvent;
+    use serde_json::json;
+
+    #[test]
+    fn redaction_reason_is_kept_in_unsigned() {
+        let common = json!({
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "prev_events": [],
+            "depth": 1,
+            "auth_events": [],
+            "hashes": { "sha256": "" },
+        });
+        let with = |mut fields: serde_json::Value| -> PduEvent {
+            fields
+                .as_object_mut()
+                .unwrap()
+                .extend(common.as_object().unwrap().clone());
+            serde_json::from_value(fields).unwrap()
+        };
+
+        let mut message = with(json!({
+            "event_id": "$message:example.org",
+            "type": "m.room.message",
+            "content": { "msgtype": "m.text", "body": "spam" },
+        }));
+        let redaction = with(json!({
+            "event_id": "$redaction:example.org",
+            "type": "m.room.redaction",
+            "content": { "reason": "Spam" },
+            "redacts": "$message:example.org",
+            "unsigned": { "transaction_id": "txn" },
+        }));
+
+        assert!(!message.is_redacted());
+        message.redact(&redaction).unwrap();
+        assert!(message.is_redacted());
+
+        let unsigned: serde_json::Value =
+            serde_json::from_str(message.unsigned.unwrap().get()).unwrap();
+        let redacted_because = &unsigned["redacted_because"];
+        assert_eq!(redacted_because["content"]["reason"], "Spam");
+        assert_eq!(redacted_because["redacts"], "$message:example.org");
+        assert!(redacted_because["unsigned"].get("transaction_id").is_none());
+        assert!(redacted_because.get("auth_events").is_none());
+        assert_eq!(message.content.get(), "{}");
+    }
+}
@@ -2,11 +2,15 @@ use crate::Error;
 use ruma::{
     api::client::uiaa::UiaaResponse, signatures::CanonicalJsonValue, DeviceId, ServerName, UserId,
 };
-use std::ops::Deref;
+use serde_json::value::RawValue as RawJsonValue;
+use std::{net::IpAddr, ops::Deref, time::Duration};
 
 #[cfg(feature = "conduit_bin")]
 mod axum;
 
+#[cfg(feature = "conduit_bin")]
+pub(crate) use self::axum::read_limited_body;
+
 /// Extractor for Ruma request structs
 pub struct Ruma<T> {
     pub body: T,
@@ -16,6 +20,10 @@ pub struct Ruma<T> {
     // This is None when body is not a valid string
     pub json_body: Option<CanonicalJsonValue>,
     pub from_appservice: bool,
+    /// The id of the appservice that sent the request
+    pub appservice_id: Option<String>,
+    /// The address of the client, behind trusted proxies if there are any
+    pub client_ip: Option<IpAddr>,
 }
 
 impl<T> Deref for Ruma<T> {
@@ -35,6 +43,26 @@ impl<T> From<T> for RumaResponse<T> {
     }
 }
 
+/// A login or registration response with a refresh token. The ruma response types have no fields
+/// for it yet, so they are added to the json body when the response is sent.
+pub struct RefreshableResponse<T> {
+    pub response: T,
+    pub refresh: Option<RefreshToken>,
+}
+
+/// A `send_join` response. Ruma has no field yet for the join event, which is returned with our
+/// signature if we authorised a restricted join.
+pub struct SendJoinResponse<T> {
+    pub response: T,
+    pub event: Option<Box<RawJsonValue>>,
+}
+
+pub struct RefreshToken {
+    pub refresh_token: String,
+    /// How long the access token is valid
+    pub expires_in: Duration,
+}
+
 impl From<Error> for RumaResponse<UiaaResponse> {
     fn from(t: Error) -> Self {
         t.to_response()
@@ -1,10 +1,11 @@
-use std::{collections::BTreeMap, iter::FromIterator, str};
+use std::{collections::BTreeMap, iter::FromIterator, net::SocketAddr, str, time::Instant};
 
 use axum::{
     async_trait,
     body::{Full, HttpBody},
     extract::{
-        rejection::TypedHeaderRejectionReason, FromRequest, Path, RequestParts, TypedHeader,
+        rejection::TypedHeaderRejectionReason, ConnectInfo, FromRequest, Path, RequestParts,
+        TypedHeader,
     },
     headers::{
         authorization::{Bearer, Credentials},
@@ -13,18 +14,27 @@ use axum::{
     response::{IntoResponse, Response},
     BoxError,
 };
-use bytes::{BufMut, Bytes, BytesMut};
-use http::StatusCode;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{header::CONTENT_LENGTH, HeaderMap, StatusCode};
 use ruma::{
-    api::{client::error::ErrorKind, AuthScheme, IncomingRequest, OutgoingResponse},
+    api::{
+        client::error::ErrorKind, error::IntoHttpError, AuthScheme, IncomingRequest,
+        OutgoingResponse,
+    },
     signatures::CanonicalJsonValue,
     DeviceId, ServerName, UserId,
 };
 use serde::Deserialize;
 use tracing::{debug, error, warn};
 
-use super::{Ruma, RumaResponse};
-use crate::{database::DatabaseGuard, server_server, Error, Result};
+use super::{RefreshableResponse, Ruma, RumaResponse, SendJoinResponse};
+use crate::{database::DatabaseGuard, server_server, utils, Error, Result};
+
+/// Endpoints locked users can still use, by ruma endpoint name.
+const LOCKED_USER_ENDPOINTS: &[&str] = &["logout", "logout_all"];
+
+/// The media upload endpoint, its body is limited by `max_upload_size` instead.
+const UPLOAD_ENDPOINT: &str = "create_media_content";
 
 #[async_trait]
 impl<T, B> FromRequest<B> for Ruma<T>
@@ -47,6 +57,17 @@ where
         let db = DatabaseGuard::from_request(req).await?;
         let auth_header = Option::<TypedHeader<Authorization<Bearer>>>::from_request(req).await?;
         let path_params = Path::<Vec<String>>::from_request(req).await?;
+        let client_ip = Option::<ConnectInfo<SocketAddr>>::from_request(req)
+            .await?
+            .map(|ConnectInfo(peer)| {
+                utils::client_ip(
+                    peer.ip(),
+                    req.headers()
+                        .get("x-forwarded-for")
+                        .and_then(|header| header.to_str().ok()),
+                    db.globals.trusted_proxies(),
+                )
+            });
 
         let query = req.uri().query().unwrap_or_default();
         let query_params: QueryParams = match ruma::serde::urlencoded::from_str(query) {
@@ -65,9 +86,12 @@ where
             None => query_params.access_token.as_deref(),
         };
 
-        let mut body = Bytes::from_request(req)
-            .await
-            .map_err(|_| Error::BadRequest(ErrorKind::MissingToken, "Missing token."))?;
+        let body_limit = if metadata.name == UPLOAD_ENDPOINT {
+            db.globals.max_upload_size()
+        } else {
+            db.globals.max_request_size()
+        };
+        let mut body = read_body(req, body_limit as usize).await?;
 
         let mut json_body = serde_json::from_slice::<CanonicalJsonValue>(&body).ok();
 
@@ -79,6 +103,8 @@ where
                 .map_or(false, |as_token| token == Some(as_token))
         });
 
+        let appservice_id = appservice_registration.map(|(id, _)| id.clone());
+
         let (sender_user, sender_device, sender_servername, from_appservice) =
             if let Some((_id, registration)) = appservice_registration {
                 match metadata.authentication {
@@ -105,6 +131,12 @@ where
                             ));
                         }
 
+                        if !LOCKED_USER_ENDPOINTS.contains(&metadata.name)
+                            && db.users.is_locked(&user_id)?
+                        {
+                            return Err(Error::UserLocked);
+                        }
+
                         // TODO: Check if appservice is allowed to be that user
                         (Some(user_id), None, None, true)
                     }
@@ -124,20 +156,16 @@ where
                             }
                         };
 
-                        match db.users.find_from_token(token).unwrap() {
-                            None => {
-                                return Err(Error::BadRequest(
-                                    ErrorKind::UnknownToken { soft_logout: false },
-                                    "Unknown access token.",
-                                ))
-                            }
-                            Some((user_id, device_id)) => (
-                                Some(user_id),
-                                Some(Box::<DeviceId>::from(device_id)),
-                                None,
-                                false,
-                            ),
-                        }
+                        let (user_id, device_id) = db
+                            .users
+                            .authenticate(token, LOCKED_USER_ENDPOINTS.contains(&metadata.name))?;
+
+                        (
+                            Some(user_id),
+                            Some(Box::<DeviceId>::from(device_id)),
+                            None,
+                            false,
+                        )
                     }
                     AuthScheme::ServerSignatures => {
                         let TypedHeader(Authorization(x_matrix)) =
@@ -246,6 +274,14 @@ where
                 }
             };
 
+        if let Some(rate_limiter) = &db.globals.rate_limiter {
+            rate_limiter.check(
+                sender_user.as_deref(),
+                sender_servername.as_deref(),
+                Instant::now(),
+            )?;
+        }
+
         let mut http_request = http::Request::builder().uri(req.uri()).method(req.method());
         *http_request.headers_mut().unwrap() = req.headers().clone();
 
@@ -294,11 +330,66 @@ where
             sender_device,
             sender_servername,
             from_appservice,
+            appservice_id,
             json_body,
+            client_ip,
         })
     }
 }
 
+async fn read_body<B>(req: &mut RequestParts<B>, limit: usize) -> Result<Bytes>
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let body = req
+        .take_body()
+        .ok_or(Error::BadRequest(ErrorKind::MissingToken, "Missing token."))?;
+
+    read_limited_body(req.headers(), body, limit).await
+}
+
+/// Collects a request body, failing as soon as it grows beyond `limit` bytes.
+pub(crate) async fn read_limited_body<B>(
+    headers: &HeaderMap,
+    body: B,
+    limit: usize,
+) -> Result<Bytes>
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let too_large = || Error::BadRequest(ErrorKind::TooLarge, "Request body is too large.");
+
+    if headers
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok())
+        .map_or(false, |length| length > limit)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = Box::pin(body);
+
+    // Don't trust the Content-Length header, the body could be longer
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            Error::BadRequest(ErrorKind::Unknown, "Failed to read the request body.")
+        })?;
+
+        if bytes.len() + chunk.remaining() > limit {
+            return Err(too_large());
+        }
+        bytes.put(chunk);
+    }
+
+    Ok(bytes.freeze())
+}
+
 struct XMatrix {
     origin: Box<ServerName>,
     key: String, // KeyName?
@@ -365,3 +456,137 @@ impl<T: OutgoingResponse> IntoResponse for RumaResponse<T> {
         }
     }
 }
+
+impl<T: OutgoingResponse> OutgoingResponse for RefreshableResponse<T> {
+    fn try_into_http_response<B: Default + BufMut>(
+        self,
+    ) -> Result<http::Response<B>, IntoHttpError> {
+        let (parts, body) = self
+            .response
+            .try_into_http_response::<Vec<u8>>()?
+            .into_parts();
+
+        let body = match self.refresh {
+            Some(refresh) => {
+                let mut json =
+                    serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&body)?;
+                json.insert("refresh_token".to_owned(), refresh.refresh_token.into());
+                json.insert(
+                    "expires_in_ms".to_owned(),
+                    (refresh.expires_in.as_millis() as u64).into(),
+                );
+                serde_json::to_vec(&json)?
+            }
+            None => body,
+        };
+
+        let mut buf = B::default();
+        buf.put_slice(&body);
+        Ok(http::Response::from_parts(parts, buf))
+    }
+}
+
+impl<T: OutgoingResponse> OutgoingResponse for SendJoinResponse<T> {
+    fn try_into_http_response<B: Default + BufMut>(
+        self,
+    ) -> Result<http::Response<B>, IntoHttpError> {
+        let (parts, body) = self
+            .response
+            .try_into_http_response::<Vec<u8>>()?
+            .into_parts();
+
+        let body = match self.event {
+            Some(event) => {
+                let mut json = serde_json::from_slice::<serde_json::Value>(&body)?;
+                // The v1 response is wrapped in `[200, response]`
+                let room_state = match &mut json {
+                    serde_json::Value::Array(array) => array.get_mut(1),
+                    object => Some(object),
+                };
+                if let Some(serde_json::Value::Object(room_state)) = room_state {
+                    room_state.insert("event".to_owned(), serde_json::from_str(event.get())?);
+                }
+                serde_json::to_vec(&json)?
+            }
+            None => body,
+        };
+
+        let mut buf = B::default();
+        buf.put_slice(&body);
+        Ok(http::Response::from_parts(parts, buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RefreshableResponse, SendJoinResponse};
+    use crate::RefreshToken;
+    use ruma::api::{client::session::logout, OutgoingResponse};
+    use std::time::Duration;
+
+    #[test]
+    fn refresh_token_is_added_to_body() {
+        let response = RefreshableResponse {
+            response: logout::v3::Response::new(),
+            refresh: Some(RefreshToken {
+                refresh_token: "refresh".to_owned(),
+                expires_in: Duration::from_secs(300),
+            }),
+        }
+        .try_into_http_response::<Vec<u8>>()
+        .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            serde_json::json!({ "refresh_token": "refresh", "expires_in_ms": 300_000 })
+        );
+
+        let response = RefreshableResponse {
+            response: logout::v3::Response::new(),
+            refresh: None,
+        }
+        .try_into_http_response::<Vec<u8>>()
+        .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn send_join_event_is_added_to_room_state() {
+        use ruma::api::federation::membership::create_join_event::{self, RoomState};
+        use serde_json::{json, value::to_raw_value};
+
+        let room_state = || RoomState {
+            auth_chain: Vec::new(),
+            state: Vec::new(),
+        };
+        let event = || Some(to_raw_value(&json!({ "type": "m.room.member" })).unwrap());
+
+        let response = SendJoinResponse {
+            response: create_join_event::v2::Response {
+                room_state: room_state(),
+            },
+            event: event(),
+        }
+        .try_into_http_response::<Vec<u8>>()
+        .unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(body["event"], json!({ "type": "m.room.member" }));
+        assert_eq!(body["state"], json!([]));
+
+        let response = SendJoinResponse {
+            response: create_join_event::v1::Response {
+                room_state: room_state(),
+            },
+            event: event(),
+        }
+        .try_into_http_response::<Vec<u8>>()
+        .unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(body[0], 200);
+        assert_eq!(body[1]["event"], json!({ "type": "m.room.member" }));
+    }
+}
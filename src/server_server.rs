@@ -1,8 +1,8 @@
 use crate::{
-    client_server::{self, claim_keys_helper, get_keys_helper},
-    database::{rooms::CompressedStateEvent, DatabaseGuard},
+    client_server::{self, claim_keys_helper, get_keys_helper, resync_remote_keys},
+    database::{rooms::CompressedStateEvent, users::DeviceListUpdateAction, DatabaseGuard},
     pdu::EventHash,
-    utils, Database, Error, PduEvent, Result, Ruma,
+    utils, Database, Error, PduEvent, Result, Ruma, SendJoinResponse,
 };
 use axum::{response::IntoResponse, Json};
 use futures_util::{stream::FuturesUnordered, StreamExt};
@@ -23,6 +23,7 @@ use ruma::{
             },
             event::{get_event, get_missing_events, get_room_state, get_room_state_ids},
             keys::{claim_keys, get_keys},
+            knock::{create_knock_event_template, send_knock},
             membership::{
                 create_invite,
                 create_join_event::{self, RoomState},
@@ -39,23 +40,20 @@ use ruma::{
     },
     directory::{IncomingFilter, IncomingRoomNetwork},
     events::{
-        receipt::{ReceiptEvent, ReceiptEventContent},
+        presence::{PresenceEvent, PresenceEventContent},
         room::{
             create::RoomCreateEventContent,
-            join_rules::{JoinRule, RoomJoinRulesEventContent},
             member::{MembershipState, RoomMemberEventContent},
-            server_acl::RoomServerAclEventContent,
         },
-        RoomEventType, StateEventType,
+        AnyStrippedStateEvent, RoomEventType, StateEventType,
     },
     int,
-    receipt::ReceiptType,
     serde::{Base64, JsonObject, Raw},
     signatures::{CanonicalJsonObject, CanonicalJsonValue},
     state_res::{self, RoomVersion, StateMap},
     to_device::DeviceIdOrAllDevices,
     uint, EventId, MilliSecondsSinceUnixEpoch, RoomId, RoomVersionId, ServerName,
-    ServerSigningKeyId,
+    ServerSigningKeyId, UInt, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use std::{
@@ -509,7 +507,11 @@ pub async fn get_server_version_route(
     Ok(get_server_version::v1::Response {
         server: Some(get_server_version::v1::Server {
             name: Some("Conduit".to_owned()),
-            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            version: if db.globals.config.hide_server_version {
+                None
+            } else {
+                Some(env!("CARGO_PKG_VERSION").to_owned())
+            },
         }),
     })
 }
@@ -520,6 +522,8 @@ pub async fn get_server_version_route(
 ///
 /// - Matrix does not support invalidating public keys, so the key returned by this will be valid
 /// forever.
+/// - `valid_until_ts` is recomputed on every request from the `signing_key_validity` config, so
+/// remote servers always see a key that is valid for the whole window.
 // Response type for this endpoint is Json because we need to calculate a signature for the response
 pub async fn get_server_keys_route(db: DatabaseGuard) -> Result<impl IntoResponse> {
     if !db.globals.allow_federation() {
@@ -542,10 +546,7 @@ pub async fn get_server_keys_route(db: DatabaseGuard) -> Result<impl IntoRespons
                 verify_keys,
                 old_verify_keys: BTreeMap::new(),
                 signatures: BTreeMap::new(),
-                valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-                    SystemTime::now() + Duration::from_secs(86400 * 7),
-                )
-                .expect("time is valid"),
+                valid_until_ts: signing_key_valid_until(db.globals.signing_key_validity()),
             })
             .expect("static conversion, no errors"),
         }
@@ -565,6 +566,16 @@ pub async fn get_server_keys_route(db: DatabaseGuard) -> Result<impl IntoRespons
     Ok(Json(response))
 }
 
+/// Returns the `valid_until_ts` to publish for our signing keys. Windows reaching past the largest
+/// timestamp Matrix can represent are capped.
+fn signing_key_valid_until(validity: Duration) -> MilliSecondsSinceUnixEpoch {
+    let validity = validity.as_millis().try_into().unwrap_or(u64::MAX);
+
+    MilliSecondsSinceUnixEpoch(UInt::new_saturating(
+        utils::millis_since_unix_epoch().saturating_add(validity),
+    ))
+}
+
 /// # `GET /_matrix/key/v2/server/{keyId}`
 ///
 /// Gets the public signing keys of this server.
@@ -578,6 +589,8 @@ pub async fn get_server_keys_deprecated_route(db: DatabaseGuard) -> impl IntoRes
 /// # `POST /_matrix/federation/v1/publicRooms`
 ///
 /// Lists the public rooms on this server.
+///
+/// - Rooms with `m.federate` set to false are left out
 pub async fn get_public_rooms_filtered_route(
     db: DatabaseGuard,
     body: Ruma<get_public_rooms_filtered::v1::IncomingRequest>,
@@ -593,6 +606,7 @@ pub async fn get_public_rooms_filtered_route(
         body.since.as_deref(),
         &body.filter,
         &body.room_network,
+        true,
     )
     .await?;
 
@@ -607,6 +621,8 @@ pub async fn get_public_rooms_filtered_route(
 /// # `GET /_matrix/federation/v1/publicRooms`
 ///
 /// Lists the public rooms on this server.
+///
+/// - Rooms with `m.federate` set to false are left out
 pub async fn get_public_rooms_route(
     db: DatabaseGuard,
     body: Ruma<get_public_rooms::v1::IncomingRequest>,
@@ -622,6 +638,7 @@ pub async fn get_public_rooms_route(
         body.since.as_deref(),
         &IncomingFilter::default(),
         &IncomingRoomNetwork::Matrix,
+        true,
     )
     .await?;
 
@@ -649,6 +666,9 @@ pub async fn send_transaction_message_route(
         .as_ref()
         .expect("server is authenticated");
 
+    let (max_pdus, max_edus) = db.globals.max_transaction_size();
+    check_transaction_size(body.pdus.len(), body.edus.len(), max_pdus, max_edus)?;
+
     let mut resolved_map = BTreeMap::new();
 
     let pub_key_map = RwLock::new(BTreeMap::new());
@@ -686,6 +706,13 @@ pub async fn send_transaction_message_route(
 
         acl_check(&sender_servername, &room_id, &db)?;
 
+        // We are not in rooms we only knocked on, but the answer to the knock still reaches us
+        if !db.rooms.exists(&room_id)? {
+            if let Err(e) = handle_knock_rejection(&db, sender_servername, &room_id, &value).await {
+                warn!("Failed to handle knock rejection: {}", e);
+            }
+        }
+
         let mutex = Arc::clone(
             db.globals
                 .roomid_mutex_federation
@@ -735,7 +762,51 @@ pub async fn send_transaction_message_route(
         .filter_map(|edu| serde_json::from_str::<Edu>(edu.json().get()).ok())
     {
         match edu {
-            Edu::Presence(_) => {}
+            Edu::Presence(presence) => {
+                if !db.globals.allow_presence() {
+                    continue;
+                }
+
+                for update in presence.push {
+                    // Servers can only update the presence of their own users
+                    if update.user_id.server_name() != &**sender_servername {
+                        continue;
+                    }
+
+                    if !db
+                        .globals
+                        .remote_presence_limiter
+                        .allow(sender_servername, Instant::now())
+                    {
+                        debug!("Dropping presence updates from {}", sender_servername);
+                        break;
+                    }
+
+                    let last_active: UInt = utils::millis_since_unix_epoch()
+                        .saturating_sub(update.last_active_ago.into())
+                        .try_into()
+                        .expect("time is valid");
+
+                    for room_id in db.rooms.rooms_joined(&update.user_id) {
+                        db.rooms.edus.update_presence(
+                            &update.user_id,
+                            &room_id?,
+                            PresenceEvent {
+                                content: PresenceEventContent {
+                                    avatar_url: None,
+                                    currently_active: Some(update.currently_active),
+                                    displayname: None,
+                                    last_active_ago: Some(last_active),
+                                    presence: update.presence.clone(),
+                                    status_msg: update.status_msg.clone(),
+                                },
+                                sender: update.user_id.clone(),
+                            },
+                            &db.globals,
+                        )?;
+                    }
+                }
+            }
             Edu::Receipt(receipt) => {
                 for (room_id, room_updates) in receipt.receipts {
                     for (user_id, user_updates) in room_updates.read {
@@ -747,23 +818,12 @@ pub async fn send_transaction_message_route(
                             })
                             .max_by_key(|(_, count)| *count)
                         {
-                            let mut user_receipts = BTreeMap::new();
-                            user_receipts.insert(user_id.clone(), user_updates.data);
-
-                            let mut receipts = BTreeMap::new();
-                            receipts.insert(ReceiptType::Read, user_receipts);
-
-                            let mut receipt_content = BTreeMap::new();
-                            receipt_content.insert(event_id.to_owned(), receipts);
-
-                            let event = ReceiptEvent {
-                                content: ReceiptEventContent(receipt_content),
-                                room_id: room_id.clone(),
-                            };
-                            db.rooms.edus.readreceipt_update(
+                            // Older receipts don't replace the current one
+                            db.rooms.read_receipt_set(
                                 &user_id,
                                 &room_id,
-                                event,
+                                event_id,
+                                user_updates.data,
                                 &db.globals,
                             )?;
                         } else {
@@ -791,9 +851,43 @@ pub async fn send_transaction_message_route(
                     }
                 }
             }
-            Edu::DeviceListUpdate(DeviceListUpdateContent { user_id, .. }) => {
-                db.users
-                    .mark_device_key_update(&user_id, &db.rooms, &db.globals)?;
+            Edu::DeviceListUpdate(DeviceListUpdateContent {
+                user_id,
+                device_id,
+                stream_id,
+                prev_id,
+                deleted,
+                keys,
+                ..
+            }) => {
+                if user_id.server_name() != &**sender_servername {
+                    warn!(
+                        "Server {} sent a device list update for {}",
+                        sender_servername, user_id
+                    );
+                    continue;
+                }
+
+                let prev_ids = prev_id.into_iter().map(u64::from).collect::<Vec<_>>();
+                if db.users.handle_remote_device_list_update(
+                    &user_id,
+                    &device_id,
+                    stream_id.into(),
+                    &prev_ids,
+                    deleted == Some(true),
+                    keys,
+                    &db.rooms,
+                    &db.globals,
+                )? == DeviceListUpdateAction::Resync
+                {
+                    debug!(
+                        "Missed device list updates of {}, resyncing their keys",
+                        user_id
+                    );
+                    if let Err(e) = resync_remote_keys(&db, &user_id).await {
+                        warn!("Failed to resync the keys of {}: {}", user_id, e);
+                    }
+                }
             }
             Edu::DirectToDevice(DirectDeviceContent {
                 sender,
@@ -882,9 +976,92 @@ pub async fn send_transaction_message_route(
     Ok(send_transaction_message::v1::Response { pdus: resolved_map })
 }
 
+/// Finds the room version in the stripped state a server sent when we knocked.
+fn knock_room_version(state: &[Raw<AnyStrippedStateEvent>]) -> Option<RoomVersionId> {
+    state
+        .iter()
+        .filter_map(|event| serde_json::from_str::<serde_json::Value>(event.json().get()).ok())
+        .find(|event| event.get("type").and_then(|t| t.as_str()) == Some("m.room.create"))
+        .and_then(|event| {
+            serde_json::from_value::<RoomCreateEventContent>(event.get("content")?.clone()).ok()
+        })
+        .map(|content| content.room_version)
+}
+
 /// An async function that can recursively call itself.
 type AsyncRecursiveType<'a, T> = Pin<Box<dyn Future<Output = T> + 'a + Send>>;
 
+/// Drops the knock of a local user when a room member kicked them. We can't check the event
+/// against the room state, only its signature, so it only clears what the user sees about their
+/// knock.
+async fn handle_knock_rejection(
+    db: &Database,
+    origin: &ServerName,
+    room_id: &RoomId,
+    value: &CanonicalJsonObject,
+) -> Result<()> {
+    let user_id = |field: &str| {
+        value
+            .get(field)
+            .and_then(|id| UserId::parse(id.as_str()?).ok())
+    };
+    let (target_user, sender) = match (user_id("state_key"), user_id("sender")) {
+        (Some(target_user), Some(sender)) => (target_user, sender),
+        _ => return Ok(()),
+    };
+    let membership = match value.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => {
+            content.get("membership").and_then(|m| m.as_str())
+        }
+        _ => None,
+    };
+
+    if value.get("type").and_then(|t| t.as_str()) != Some("m.room.member")
+        || membership != Some("leave")
+        || sender.server_name() != origin
+        || target_user.server_name() != db.globals.server_name()
+        || db.rooms.get_knock_count(room_id, &target_user)?.is_none()
+    {
+        return Ok(());
+    }
+
+    // The room version decides how the event is signed, we only know it from the knock
+    let room_version = match db
+        .rooms
+        .knock_state(&target_user, room_id)?
+        .and_then(|state| knock_room_version(&state))
+    {
+        Some(room_version) => room_version,
+        None => return Ok(()),
+    };
+
+    let pub_key_map = RwLock::new(BTreeMap::new());
+    fetch_required_signing_keys(value, &pub_key_map, db).await?;
+    if let Err(e) = ruma::signatures::verify_event(
+        &*pub_key_map
+            .read()
+            .map_err(|_| Error::bad_database("RwLock is poisoned."))?,
+        value,
+        &room_version,
+    ) {
+        warn!(
+            "Dropping knock rejection in {} from {}: {}",
+            room_id, origin, e
+        );
+        return Ok(());
+    }
+
+    db.rooms.update_membership(
+        room_id,
+        &target_user,
+        MembershipState::Leave,
+        &sender,
+        None,
+        db,
+        false,
+    )
+}
+
 /// When receiving an event one needs to:
 /// 0. Check the server is in the room
 /// 1. Skip the PDU if we already know about it
@@ -931,6 +1108,12 @@ pub(crate) async fn handle_incoming_pdu<'a>(
         return Ok(Some(pdu_id.to_vec()));
     }
 
+    // Events of other members can't be authorised before the state of a partial join is filled
+    db.rooms
+        .wait_for_full_state(room_id)
+        .await
+        .map_err(|e| e.to_string())?;
+
     let create_event = db
         .rooms
         .room_state_get(room_id, &StateEventType::RoomCreate, "")
@@ -1880,25 +2063,22 @@ pub(crate) fn fetch_and_handle_outliers<'a>(
                                 next_id, calculated_event_id, &res.pdu);
                         }
 
-                        if let Some(auth_events) =
-                            value.get("auth_events").and_then(|c| c.as_array())
-                        {
-                            for auth_event in auth_events {
-                                if let Ok(auth_event) =
-                                    serde_json::from_value(auth_event.clone().into())
-                                {
-                                    let a: Arc<EventId> = auth_event;
-                                    todo_auth_events.push(a);
-                                } else {
-                                    warn!("Auth event id is not valid");
-                                }
-                            }
-                        } else {
-                            warn!("Auth event list invalid");
+                        events_all.insert(next_id.clone());
+
+                        if let Err(e) = queue_auth_events(
+                            &value,
+                            &mut todo_auth_events,
+                            &events_all,
+                            |event_id| matches!(db.rooms.get_pdu(event_id), Ok(Some(_))),
+                            db.globals.max_fetched_auth_chain_size(),
+                        ) {
+                            warn!("Rejecting {}: {}", id, e);
+                            back_off((**id).to_owned());
+                            events_in_reverse_order.clear();
+                            break;
                         }
 
-                        events_in_reverse_order.push((next_id.clone(), value));
-                        events_all.insert(next_id);
+                        events_in_reverse_order.push((next_id, value));
                     }
                     Err(_) => {
                         warn!("Failed to fetch event: {}", next_id);
@@ -1935,6 +2115,42 @@ pub(crate) fn fetch_and_handle_outliers<'a>(
     })
 }
 
+/// Adds the auth events of a fetched event to the events that still need to be fetched.
+///
+/// Fails if that would make the auth chain we fetch for a single event larger than `limit`.
+fn queue_auth_events(
+    value: &CanonicalJsonObject,
+    todo_auth_events: &mut Vec<Arc<EventId>>,
+    fetched: &HashSet<Arc<EventId>>,
+    is_known: impl Fn(&EventId) -> bool,
+    limit: usize,
+) -> Result<()> {
+    if let Some(auth_events) = value.get("auth_events").and_then(|c| c.as_array()) {
+        for auth_event in auth_events {
+            if let Ok(auth_event) = serde_json::from_value(auth_event.clone().into()) {
+                let a: Arc<EventId> = auth_event;
+                // Events we already have or already plan to fetch don't grow the chain
+                if fetched.contains(&a) || todo_auth_events.contains(&a) || is_known(&a) {
+                    continue;
+                }
+                todo_auth_events.push(a);
+            } else {
+                warn!("Auth event id is not valid");
+            }
+        }
+    } else {
+        warn!("Auth event list invalid");
+    }
+
+    if fetched.len() + todo_auth_events.len() > limit {
+        return Err(Error::BadServerResponse(
+            "Auth chain of event is too large.",
+        ));
+    }
+
+    Ok(())
+}
+
 /// Search the DB for the signing keys of the given server, if we don't have them
 /// fetch them from the server and save to our DB.
 #[tracing::instrument(skip_all)]
@@ -2599,43 +2815,96 @@ pub async fn create_join_event_template_route(
 
     acl_check(sender_servername, &body.room_id, &db)?;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event =
-        db.rooms
-            .room_state_get(&body.room_id, &StateEventType::RoomJoinRules, "")?;
+    let mut content = RoomMemberEventContent::new(MembershipState::Join);
+
+    // Joins of restricted rooms name one of our members who could have invited the user
+    if client_server::check_restricted_join(&db, &body.room_id, &body.user_id)? {
+        content.join_authorized_via_users_server = Some(
+            client_server::local_join_authoriser(&db, &body.room_id)?.ok_or(Error::BadRequest(
+                ErrorKind::Forbidden,
+                "No user on this server can authorise joining this room.",
+            ))?,
+        );
+    }
+
+    let (room_version_id, event) =
+        membership_event_template(&db, &body.room_id, &body.user_id, &body.ver, content)?;
+
+    Ok(prepare_join_event::v1::Response {
+        room_version: Some(room_version_id),
+        event,
+    })
+}
+
+/// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+///
+/// Creates a knock template.
+///
+/// - Only works in rooms whose join rule is `knock` or `knock_restricted`
+pub async fn create_knock_event_template_route(
+    db: DatabaseGuard,
+    body: Ruma<create_knock_event_template::v1::IncomingRequest>,
+) -> Result<create_knock_event_template::v1::Response> {
+    if !db.globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
 
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
+    let sender_servername = body
+        .sender_servername
         .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
+        .expect("server is authenticated");
 
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::Unknown,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
+    if body.user_id.server_name() != &**sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only knock for their own users.",
+        ));
     }
 
+    acl_check(sender_servername, &body.room_id, &db)?;
+
+    client_server::check_knock_allowed(&db, &body.room_id, &body.user_id)?;
+
+    let (room_version, event) = membership_event_template(
+        &db,
+        &body.room_id,
+        &body.user_id,
+        &body.ver,
+        RoomMemberEventContent::new(MembershipState::Knock),
+    )?;
+
+    Ok(create_knock_event_template::v1::Response {
+        room_version,
+        event,
+    })
+}
+
+/// Builds an unsigned membership event for a remote user, the remote server fills it in, signs it
+/// and sends it back.
+fn membership_event_template(
+    db: &Database,
+    room_id: &RoomId,
+    user_id: &UserId,
+    ver: &[RoomVersionId],
+    content: RoomMemberEventContent,
+) -> Result<(RoomVersionId, Box<RawJsonValue>)> {
     let prev_events: Vec<_> = db
         .rooms
-        .get_pdu_leaves(&body.room_id)?
+        .get_pdu_leaves(room_id)?
         .into_iter()
         .take(20)
         .collect();
 
     let create_event = db
         .rooms
-        .room_state_get(&body.room_id, &StateEventType::RoomCreate, "")?;
+        .room_state_get(room_id, &StateEventType::RoomCreate, "")?;
 
     let create_event_content: Option<RoomCreateEventContent> = create_event
         .as_ref()
@@ -2655,7 +2924,12 @@ pub async fn create_join_event_template_route(
         });
     let room_version = RoomVersion::new(&room_version_id).expect("room version is supported");
 
-    if !body.ver.contains(&room_version_id) {
+    check_federation_join_room_version(
+        db.globals.federation_join_room_versions(),
+        &room_version_id,
+    )?;
+
+    if !ver.contains(&room_version_id) {
         return Err(Error::BadRequest(
             ErrorKind::IncompatibleRoomVersion {
                 room_version: room_version_id,
@@ -2664,25 +2938,15 @@ pub async fn create_join_event_template_route(
         ));
     }
 
-    let content = to_raw_value(&RoomMemberEventContent {
-        avatar_url: None,
-        blurhash: None,
-        displayname: None,
-        is_direct: None,
-        membership: MembershipState::Join,
-        third_party_invite: None,
-        reason: None,
-        join_authorized_via_users_server: None,
-    })
-    .expect("member event is valid value");
+    let content = to_raw_value(&content).expect("member event is valid value");
 
-    let state_key = body.user_id.to_string();
+    let state_key = user_id.to_string();
     let kind = StateEventType::RoomMember;
 
     let auth_events = db.rooms.get_auth_events(
-        &body.room_id,
+        room_id,
         &kind.to_string().into(),
-        &body.user_id,
+        user_id,
         Some(&state_key),
         &content,
     )?;
@@ -2697,7 +2961,7 @@ pub async fn create_join_event_template_route(
 
     let mut unsigned = BTreeMap::new();
 
-    if let Some(prev_pdu) = db.rooms.room_state_get(&body.room_id, &kind, &state_key)? {
+    if let Some(prev_pdu) = db.rooms.room_state_get(room_id, &kind, &state_key)? {
         unsigned.insert("prev_content".to_owned(), prev_pdu.content.clone());
         unsigned.insert(
             "prev_sender".to_owned(),
@@ -2707,8 +2971,8 @@ pub async fn create_join_event_template_route(
 
     let pdu = PduEvent {
         event_id: ruma::event_id!("$thiswillbefilledinlater").into(),
-        room_id: body.room_id.clone(),
-        sender: body.user_id.clone(),
+        room_id: room_id.to_owned(),
+        sender: user_id.to_owned(),
         origin_server_ts: utils::millis_since_unix_epoch()
             .try_into()
             .expect("time is valid"),
@@ -2763,10 +3027,10 @@ pub async fn create_join_event_template_route(
         CanonicalJsonValue::String(db.globals.server_name().as_str().to_owned()),
     );
 
-    Ok(prepare_join_event::v1::Response {
-        room_version: Some(room_version_id),
-        event: to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
-    })
+    Ok((
+        room_version_id,
+        to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
+    ))
 }
 
 async fn create_join_event(
@@ -2774,7 +3038,7 @@ async fn create_join_event(
     sender_servername: &ServerName,
     room_id: &RoomId,
     pdu: &RawJsonValue,
-) -> Result<RoomState> {
+) -> Result<(RoomState, Option<Box<RawJsonValue>>)> {
     if !db.globals.allow_federation() {
         return Err(Error::bad_config("Federation is disabled."));
     }
@@ -2788,32 +3052,13 @@ async fn create_join_event(
 
     acl_check(sender_servername, room_id, db)?;
 
-    // TODO: Conduit does not implement restricted join rules yet, we always reject
-    let join_rules_event = db
-        .rooms
-        .room_state_get(room_id, &StateEventType::RoomJoinRules, "")?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                Error::bad_database("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
+    check_federation_join_room_version(
+        db.globals.federation_join_room_versions(),
+        &db.rooms.get_room_version(room_id)?,
+    )?;
 
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. }
-        ) {
-            return Err(Error::BadRequest(
-                ErrorKind::Unknown,
-                "Conduit does not support restricted rooms yet.",
-            ));
-        }
-    }
+    let needs_authorisation =
+        client_server::check_restricted_join(db, room_id, &membership_target(pdu)?)?;
 
     // We need to return the state prior to joining, let's keep a reference to that here
     let shortstatehash = db
@@ -2824,11 +3069,76 @@ async fn create_join_event(
             "Pdu state not found.",
         ))?;
 
+    let pdu_id = handle_membership_pdu(db, room_id, pdu, MembershipState::Join).await?;
+
+    let state_ids = db.rooms.state_full_ids(shortstatehash)?;
+    let auth_chain_ids = get_auth_chain(
+        room_id,
+        state_ids.iter().map(|(_, id)| id.clone()).collect(),
+        db,
+    )?;
+
+    let servers = db
+        .rooms
+        .room_servers(room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| &**server != db.globals.server_name());
+
+    db.sending.send_pdu(servers, &pdu_id)?;
+
+    db.flush()?;
+
+    // The joining server needs our signature on restricted joins we authorised
+    let event = if needs_authorisation {
+        db.rooms
+            .get_pdu_json_from_id(&pdu_id)?
+            .map(PduEvent::convert_to_outgoing_federation_event)
+    } else {
+        None
+    };
+
+    Ok((
+        RoomState {
+            auth_chain: auth_chain_ids
+                .filter_map(|id| db.rooms.get_pdu_json(&id).ok().flatten())
+                .map(PduEvent::convert_to_outgoing_federation_event)
+                .collect(),
+            state: state_ids
+                .iter()
+                .filter_map(|(_, id)| db.rooms.get_pdu_json(id).ok().flatten())
+                .map(PduEvent::convert_to_outgoing_federation_event)
+                .collect(),
+        },
+        event,
+    ))
+}
+
+/// The user whose membership a membership event changes.
+fn membership_target(pdu: &RawJsonValue) -> Result<Box<UserId>> {
+    serde_json::from_str::<serde_json::Value>(pdu.get())
+        .ok()
+        .and_then(|pdu| UserId::parse(pdu.get("state_key")?.as_str()?).ok())
+        .ok_or(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Membership event needs a valid state key.",
+        ))
+}
+
+/// Checks and appends a membership event a remote server sent through `send_join` or
+/// `send_knock`. Returns the pdu id.
+///
+/// - Restricted joins authorised by one of our users get our signature
+async fn handle_membership_pdu(
+    db: &Database,
+    room_id: &RoomId,
+    pdu: &RawJsonValue,
+    membership: MembershipState,
+) -> Result<Vec<u8>> {
     let pub_key_map = RwLock::new(BTreeMap::new());
     // let mut auth_cache = EventMap::new();
 
     // We do not add the event_id field to the pdu here because of signature and hashes checks
-    let (event_id, value) = match crate::pdu::gen_event_id_canonical_json(pdu, &db) {
+    let (event_id, mut value) = match crate::pdu::gen_event_id_canonical_json(pdu, db) {
         Ok(t) => t,
         Err(_) => {
             // Event could not be converted to canonical json
@@ -2839,6 +3149,42 @@ async fn create_join_event(
         }
     };
 
+    let content = match value.get("content") {
+        Some(CanonicalJsonValue::Object(content)) => Some(content),
+        _ => None,
+    };
+    let event_membership = content
+        .and_then(|content| content.get("membership"))
+        .and_then(|m| m.as_str());
+    let is_expected_membership = value.get("type").and_then(|t| t.as_str())
+        == Some("m.room.member")
+        && event_membership == Some(membership.as_str());
+
+    if !is_expected_membership {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Event has the wrong type or membership for this endpoint.",
+        ));
+    }
+
+    let authorised_by_us = content
+        .and_then(|content| content.get("join_authorised_via_users_server"))
+        .and_then(|user_id| UserId::parse(user_id.as_str()?).ok())
+        .map_or(false, |user_id| {
+            user_id.server_name() == db.globals.server_name()
+        });
+
+    if authorised_by_us {
+        ruma::signatures::sign_json(
+            db.globals.server_name().as_str(),
+            db.globals.keypair(),
+            &mut value,
+        )
+        .map_err(|_| {
+            Error::BadRequest(ErrorKind::InvalidParam, "Failed to sign the join event.")
+        })?;
+    }
+
     let origin: Box<ServerName> = serde_json::from_value(
         serde_json::to_value(value.get("origin").ok_or(Error::BadRequest(
             ErrorKind::InvalidParam,
@@ -2860,7 +3206,10 @@ async fn create_join_event(
     let pdu_id = handle_incoming_pdu(&origin, &event_id, room_id, value, true, db, &pub_key_map)
         .await
         .map_err(|e| {
-            warn!("Error while handling incoming send join PDU: {}", e);
+            warn!(
+                "Error while handling incoming send {} PDU: {}",
+                membership, e
+            );
             Error::BadRequest(
                 ErrorKind::InvalidParam,
                 "Error while handling incoming PDU.",
@@ -2872,68 +3221,114 @@ async fn create_join_event(
         ))?;
     drop(mutex_lock);
 
-    let state_ids = db.rooms.state_full_ids(shortstatehash)?;
-    let auth_chain_ids = get_auth_chain(
-        room_id,
-        state_ids.iter().map(|(_, id)| id.clone()).collect(),
-        db,
-    )?;
-
-    let servers = db
-        .rooms
-        .room_servers(room_id)
-        .filter_map(|r| r.ok())
-        .filter(|server| &**server != db.globals.server_name());
-
-    db.sending.send_pdu(servers, &pdu_id)?;
-
-    db.flush()?;
-
-    Ok(RoomState {
-        auth_chain: auth_chain_ids
-            .filter_map(|id| db.rooms.get_pdu_json(&id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-        state: state_ids
-            .iter()
-            .filter_map(|(_, id)| db.rooms.get_pdu_json(id).ok().flatten())
-            .map(PduEvent::convert_to_outgoing_federation_event)
-            .collect(),
-    })
+    Ok(pdu_id)
 }
 
 /// # `PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
+///
+/// - Restricted joins we authorise are returned with our signature
 pub async fn create_join_event_v1_route(
     db: DatabaseGuard,
     body: Ruma<create_join_event::v1::IncomingRequest>,
-) -> Result<create_join_event::v1::Response> {
+) -> Result<SendJoinResponse<create_join_event::v1::Response>> {
     let sender_servername = body
         .sender_servername
         .as_ref()
         .expect("server is authenticated");
 
-    let room_state = create_join_event(&db, sender_servername, &body.room_id, &body.pdu).await?;
+    let (room_state, event) =
+        create_join_event(&db, sender_servername, &body.room_id, &body.pdu).await?;
 
-    Ok(create_join_event::v1::Response { room_state })
+    Ok(SendJoinResponse {
+        response: create_join_event::v1::Response { room_state },
+        event,
+    })
 }
 
 /// # `PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}`
 ///
 /// Submits a signed join event.
+///
+/// - Restricted joins we authorise are returned with our signature
 pub async fn create_join_event_v2_route(
     db: DatabaseGuard,
     body: Ruma<create_join_event::v2::IncomingRequest>,
-) -> Result<create_join_event::v2::Response> {
+) -> Result<SendJoinResponse<create_join_event::v2::Response>> {
+    let sender_servername = body
+        .sender_servername
+        .as_ref()
+        .expect("server is authenticated");
+
+    let (room_state, event) =
+        create_join_event(&db, sender_servername, &body.room_id, &body.pdu).await?;
+
+    Ok(SendJoinResponse {
+        response: create_join_event::v2::Response { room_state },
+        event,
+    })
+}
+
+/// # `PUT /_matrix/federation/v1/send_knock/{roomId}/{eventId}`
+///
+/// Submits a signed knock event.
+///
+/// - Returns a subset of the room state, so the knocking user can tell what room they knocked on
+pub async fn create_knock_event_v1_route(
+    db: DatabaseGuard,
+    body: Ruma<send_knock::v1::IncomingRequest>,
+) -> Result<send_knock::v1::Response> {
+    if !db.globals.allow_federation() {
+        return Err(Error::bad_config("Federation is disabled."));
+    }
+
+    if !db.rooms.exists(&body.room_id)? {
+        return Err(Error::BadRequest(
+            ErrorKind::NotFound,
+            "Room is unknown to this server.",
+        ));
+    }
+
     let sender_servername = body
         .sender_servername
         .as_ref()
         .expect("server is authenticated");
 
-    let room_state = create_join_event(&db, sender_servername, &body.room_id, &body.pdu).await?;
+    acl_check(sender_servername, &body.room_id, &db)?;
+
+    let knocking_user = membership_target(&body.pdu)?;
+
+    if knocking_user.server_name() != &**sender_servername {
+        return Err(Error::BadRequest(
+            ErrorKind::Forbidden,
+            "Servers can only knock for their own users.",
+        ));
+    }
+
+    client_server::check_knock_allowed(&db, &body.room_id, &knocking_user)?;
+
+    let pdu_id =
+        handle_membership_pdu(&db, &body.room_id, &body.pdu, MembershipState::Knock).await?;
+
+    let knock_event = db
+        .rooms
+        .get_pdu_from_id(&pdu_id)?
+        .ok_or_else(|| Error::bad_database("Knock event we just added is missing."))?;
+
+    let servers = db
+        .rooms
+        .room_servers(&body.room_id)
+        .filter_map(|r| r.ok())
+        .filter(|server| &**server != db.globals.server_name());
+
+    db.sending.send_pdu(servers, &pdu_id)?;
 
-    Ok(create_join_event::v2::Response { room_state })
+    db.flush()?;
+
+    Ok(send_knock::v1::Response {
+        knock_room_state: db.rooms.calculate_invite_state(&knock_event)?,
+    })
 }
 
 /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
@@ -2988,7 +3383,7 @@ pub async fn create_invite_route(
         CanonicalJsonValue::String(event_id.into()),
     );
 
-    let sender: Box<_> = serde_json::from_value(
+    let sender: Box<UserId> = serde_json::from_value(
         signed_event
             .get("sender")
             .ok_or(Error::BadRequest(
@@ -3000,6 +3395,11 @@ pub async fn create_invite_route(
     )
     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "sender is not a user id."))?;
 
+    // We can only verify the inviter's membership in rooms we participate in
+    if db.globals.invite_only_from_members() && db.rooms.exists(&body.room_id)? {
+        client_server::check_inviter_is_member(db.rooms.is_joined(&sender, &body.room_id)?)?;
+    }
+
     let invited_user: Box<_> = serde_json::from_value(
         signed_event
             .get("state_key")
@@ -3344,8 +3744,8 @@ fn get_server_keys_from_cache(
     Ok(())
 }
 
-pub(crate) async fn fetch_join_signing_keys(
-    event: &create_join_event::v2::Response,
+pub(crate) async fn fetch_join_signing_keys<'a>(
+    pdus: impl IntoIterator<Item = &'a RawJsonValue>,
     room_version: &RoomVersionId,
     pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
     db: &Database,
@@ -3360,10 +3760,7 @@ pub(crate) async fn fetch_join_signing_keys(
 
         // Try to fetch keys, failure is okay
         // Servers we couldn't find in the cache will be added to `servers`
-        for pdu in &event.room_state.state {
-            let _ = get_server_keys_from_cache(pdu, &mut servers, room_version, &mut pkm, db);
-        }
-        for pdu in &event.room_state.auth_chain {
+        for pdu in pdus {
             let _ = get_server_keys_from_cache(pdu, &mut servers, room_version, &mut pkm, db);
         }
 
@@ -3451,24 +3848,12 @@ pub(crate) async fn fetch_join_signing_keys(
 
 /// Returns Ok if the acl allows the server
 fn acl_check(server_name: &ServerName, room_id: &RoomId, db: &Database) -> Result<()> {
-    let acl_event = match db
-        .rooms
-        .room_state_get(room_id, &StateEventType::RoomServerAcl, "")?
-    {
+    let acl = match db.rooms.server_acl(room_id)? {
         Some(acl) => acl,
         None => return Ok(()),
     };
 
-    let acl_event_content: RoomServerAclEventContent =
-        match serde_json::from_str(acl_event.content.get()) {
-            Ok(content) => content,
-            Err(_) => {
-                warn!("Invalid ACL event");
-                return Ok(());
-            }
-        };
-
-    if acl_event_content.is_allowed(server_name) {
+    if acl.is_allowed(server_name) {
         Ok(())
     } else {
         Err(Error::BadRequest(
@@ -3478,9 +3863,72 @@ fn acl_check(server_name: &ServerName, room_id: &RoomId, db: &Database) -> Resul
     }
 }
 
+/// Rejects transactions with more PDUs or EDUs than allowed, before any of them is processed.
+fn check_transaction_size(
+    pdus: usize,
+    edus: usize,
+    max_pdus: usize,
+    max_edus: usize,
+) -> Result<()> {
+    if pdus > max_pdus {
+        return Err(Error::BadRequestDetailed(
+            ErrorKind::TooLarge,
+            format!(
+                "Transaction contains {} PDUs, at most {} are allowed.",
+                pdus, max_pdus
+            ),
+        ));
+    }
+
+    if edus > max_edus {
+        return Err(Error::BadRequestDetailed(
+            ErrorKind::TooLarge,
+            format!(
+                "Transaction contains {} EDUs, at most {} are allowed.",
+                edus, max_edus
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns Ok if remote servers may join rooms of this version. An empty list allows all versions.
+fn check_federation_join_room_version(
+    allowed: &[RoomVersionId],
+    room_version: &RoomVersionId,
+) -> Result<()> {
+    if allowed.is_empty() || allowed.contains(room_version) {
+        Ok(())
+    } else {
+        Err(Error::BadRequestDetailed(
+            ErrorKind::IncompatibleRoomVersion {
+                room_version: room_version.clone(),
+            },
+            format!(
+                "This server does not accept federated joins to rooms of version {}.",
+                room_version
+            ),
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{add_port_to_hostname, get_ip_with_port, FedDest};
+    use super::{
+        add_port_to_hostname, check_federation_join_room_version, check_transaction_size,
+        get_ip_with_port, knock_room_version, queue_auth_events, signing_key_valid_until, FedDest,
+    };
+    use crate::Error;
+    use ruma::{
+        api::client::error::ErrorKind, events::AnyStrippedStateEvent, serde::Raw, EventId,
+        MilliSecondsSinceUnixEpoch, RoomVersionId, UInt,
+    };
+    use std::{
+        collections::HashSet,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
 
     #[test]
     fn ips_get_default_ports() {
@@ -3521,4 +3969,135 @@ mod tests {
             FedDest::Named(String::from("example.com"), String::from(":1337"))
         )
     }
+
+    #[test]
+    fn signing_keys_are_valid_for_configured_window() {
+        let validity = Duration::from_secs(60 * 60);
+
+        let earliest =
+            MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now() + validity).unwrap();
+        let valid_until = signing_key_valid_until(validity);
+        let latest =
+            MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now() + validity).unwrap();
+
+        assert!(earliest <= valid_until);
+        assert!(valid_until <= latest);
+
+        assert_eq!(
+            signing_key_valid_until(Duration::from_secs(u64::MAX)),
+            MilliSecondsSinceUnixEpoch(UInt::MAX)
+        );
+    }
+
+    #[test]
+    fn federated_join_to_disallowed_version_is_rejected() {
+        let allowed = [RoomVersionId::V9];
+
+        assert!(check_federation_join_room_version(&allowed, &RoomVersionId::V9).is_ok());
+        assert!(check_federation_join_room_version(&[], &RoomVersionId::V6).is_ok());
+
+        match check_federation_join_room_version(&allowed, &RoomVersionId::V6) {
+            Err(Error::BadRequestDetailed(
+                ErrorKind::IncompatibleRoomVersion { room_version },
+                _,
+            )) => assert_eq!(room_version, RoomVersionId::V6),
+            _ => panic!("expected an incompatible room version error"),
+        }
+    }
+
+    #[test]
+    fn over_limit_auth_chain_is_rejected() {
+        let event = |auth_events: serde_json::Value| {
+            serde_json::from_value(serde_json::json!({ "auth_events": auth_events })).unwrap()
+        };
+
+        let id = |id: &str| -> Arc<EventId> { Arc::from(EventId::parse(id).unwrap()) };
+        let unknown = |_: &EventId| false;
+
+        let mut fetched = HashSet::new();
+        fetched.insert(id("$x"));
+        let mut todo = Vec::new();
+        assert!(queue_auth_events(
+            &event(serde_json::json!(["$a", "$b"])),
+            &mut todo,
+            &fetched,
+            unknown,
+            3
+        )
+        .is_ok());
+        assert_eq!(todo.len(), 2);
+
+        // A crafted event keeps pointing at more and more unknown auth events
+        fetched.insert(todo.pop().unwrap());
+        assert!(queue_auth_events(
+            &event(serde_json::json!(["$c", "$d"])),
+            &mut todo,
+            &fetched,
+            unknown,
+            3
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn known_auth_events_do_not_count_against_the_limit() {
+        let event = |auth_events: serde_json::Value| {
+            serde_json::from_value(serde_json::json!({ "auth_events": auth_events })).unwrap()
+        };
+        let id = |id: &str| -> Arc<EventId> { Arc::from(EventId::parse(id).unwrap()) };
+
+        let mut fetched = HashSet::new();
+        fetched.insert(id("$a"));
+        let mut todo = vec![id("$b")];
+
+        // $a was fetched, $b is queued, $c is in the database and $b is listed twice
+        assert!(queue_auth_events(
+            &event(serde_json::json!(["$a", "$b", "$b", "$c", "$d"])),
+            &mut todo,
+            &fetched,
+            |event_id| event_id.as_str() == "$c",
+            3
+        )
+        .is_ok());
+        assert_eq!(todo, vec![id("$b"), id("$d")]);
+    }
+
+    #[test]
+    fn oversized_transactions_are_rejected() {
+        assert!(check_transaction_size(50, 100, 50, 100).is_ok());
+        assert!(matches!(
+            check_transaction_size(51, 0, 50, 100),
+            Err(Error::BadRequestDetailed(ErrorKind::TooLarge, _))
+        ));
+        assert!(matches!(
+            check_transaction_size(0, 101, 50, 100),
+            Err(Error::BadRequestDetailed(ErrorKind::TooLarge, _))
+        ));
+    }
+
+    #[test]
+    fn knock_room_version_comes_from_create_event() {
+        let state = |events: serde_json::Value| -> Vec<Raw<AnyStrippedStateEvent>> {
+            serde_json::from_value(events).unwrap()
+        };
+
+        let with_create = state(serde_json::json!([
+            {
+                "type": "m.room.name",
+                "state_key": "",
+                "sender": "@alice:example.org",
+                "content": { "name": "Room" },
+            },
+            {
+                "type": "m.room.create",
+                "state_key": "",
+                "sender": "@alice:example.org",
+                "content": { "creator": "@alice:example.org", "room_version": "7" },
+            },
+        ]));
+        assert_eq!(knock_room_version(&with_create), Some(RoomVersionId::V7));
+
+        // Without the create event the rejection can't be verified
+        assert_eq!(knock_room_version(&with_create[..1]), None);
+    }
 }
@@ -1,11 +1,14 @@
+use crate::config::PasswordHashing;
 use argon2::{Config, Variant};
 use cmp::Ordering;
 use rand::prelude::*;
 use ruma::serde::{try_from_json_map, CanonicalJsonError, CanonicalJsonObject};
 use std::{
-    cmp, fmt,
+    cmp, fmt, fs, io,
+    net::IpAddr,
+    path::Path,
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 pub fn millis_since_unix_epoch() -> u64 {
@@ -56,10 +59,13 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
-/// Calculate a new hash for the given password
-pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
+/// Calculate a new hash for the given password. The parameters are stored in the encoded hash.
+pub fn calculate_hash(password: &str, params: &PasswordHashing) -> Result<String, argon2::Error> {
     let hashing_config = Config {
         variant: Variant::Argon2id,
+        mem_cost: params.memory_cost,
+        time_cost: params.time_cost,
+        lanes: params.parallelism,
         ..Default::default()
     };
 
@@ -67,6 +73,33 @@ pub fn calculate_hash(password: &str) -> Result<String, argon2::Error> {
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
 }
 
+/// Whether an encoded hash was calculated with weaker parameters than the given ones.
+pub fn hash_needs_upgrade(hash: &str, params: &PasswordHashing) -> bool {
+    let mut parts = hash.split('$').skip(1);
+    if parts.next() != Some("argon2id") {
+        return true;
+    }
+
+    let costs = match parts.find(|part| part.starts_with("m=")) {
+        Some(costs) => costs,
+        None => return true,
+    };
+
+    let (mut memory_cost, mut time_cost, mut parallelism) = (0, 0, 0);
+    for cost in costs.split(',') {
+        match cost.split_once('=') {
+            Some(("m", value)) => memory_cost = value.parse().unwrap_or(0),
+            Some(("t", value)) => time_cost = value.parse().unwrap_or(0),
+            Some(("p", value)) => parallelism = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+
+    memory_cost < params.memory_cost
+        || time_cost < params.time_cost
+        || parallelism < params.parallelism
+}
+
 pub fn common_elements(
     mut iterators: impl Iterator<Item = impl Iterator<Item = Vec<u8>>>,
     check_order: impl Fn(&[u8], &[u8]) -> Ordering,
@@ -133,6 +166,129 @@ pub fn deserialize_from_str<
     deserializer.deserialize_str(Visitor(std::marker::PhantomData))
 }
 
+/// Parses a duration like `30s`, `15m`, `12h`, `7d` or `2w`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let split = duration.len() - duration.chars().last()?.len_utf8();
+    let (amount, unit) = duration.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(amount.checked_mul(seconds)?))
+}
+
+/// Parses a date like `2022-03-31` as midnight UTC.
+pub fn parse_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    UNIX_EPOCH.checked_add(Duration::from_secs(
+        u64::try_from(days).ok()? * 60 * 60 * 24,
+    ))
+}
+
+/// Lowers a client-requested pagination limit to the server maximum.
+pub fn clamp_limit(limit: u64, max: usize) -> usize {
+    usize::try_from(limit).unwrap_or(usize::MAX).min(max)
+}
+
+/// Returns the combined size in bytes of all files below `path`.
+pub fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Estimates the strength of a password with zxcvbn. Words in `user_inputs`, like the username,
+/// count as easy to guess. Returns why the password is too weak, if it is.
+pub fn validate_password(
+    password: &str,
+    minimum_score: u8,
+    user_inputs: &[&str],
+) -> Result<(), String> {
+    let entropy = match zxcvbn::zxcvbn(password, user_inputs) {
+        Ok(entropy) => entropy,
+        // Only empty passwords can't be estimated
+        Err(_) => return Err("The password is too weak.".to_owned()),
+    };
+
+    if entropy.score() >= minimum_score {
+        return Ok(());
+    }
+
+    let mut reason = "The password is too weak.".to_owned();
+    if let Some(feedback) = entropy.feedback() {
+        if let Some(warning) = feedback.warning() {
+            reason = format!("{} {}", reason, warning);
+        }
+        for suggestion in feedback.suggestions() {
+            reason = format!("{} {}", reason, suggestion);
+        }
+    }
+
+    Err(reason)
+}
+
+/// Finds the address of the client behind a chain of reverse proxies.
+///
+/// `X-Forwarded-For` is only read if the request came from a trusted proxy. The first address
+/// from the right that isn't a trusted proxy belongs to the client.
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+
+    for hop in forwarded_for
+        .into_iter()
+        .flat_map(|header| header.rsplit(','))
+    {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
 // Copied from librustdoc:
 // https://github.com/rust-lang/rust/blob/cbaeec14f90b59a91a6b0f17fc046c66fa811892/src/librustdoc/html/escape.rs
 
@@ -169,3 +325,108 @@ impl<'a> fmt::Display for HtmlEscape<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        calculate_hash, clamp_limit, client_ip, hash_needs_upgrade, parse_date, parse_duration,
+        validate_password,
+    };
+    use crate::config::PasswordHashing;
+    use std::{
+        net::IpAddr,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn over_max_limit_is_clamped() {
+        assert_eq!(clamp_limit(1_000_000, 100), 100);
+        assert_eq!(clamp_limit(100, 100), 100);
+        assert_eq!(clamp_limit(10, 100), 10);
+        assert_eq!(clamp_limit(u64::MAX, 100), 100);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("5µ"), None);
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_date("2022-03-01"),
+            Some(UNIX_EPOCH + Duration::from_secs(1_646_092_800))
+        );
+        assert_eq!(parse_date("2022-13-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn client_ip_is_only_read_from_trusted_proxies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        // Direct connections can't fake their address
+        assert_eq!(
+            client_ip(ip("1.2.3.4"), Some("5.6.7.8"), &proxies),
+            ip("1.2.3.4")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), None, &proxies), ip("10.0.0.1"));
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("5.6.7.8, 1.2.3.4, 10.0.0.2"), &proxies),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("garbage"), &proxies),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn weak_passwords_are_rejected() {
+        assert!(validate_password("password1", 3, &[]).is_err());
+        assert!(validate_password("", 0, &[]).is_err());
+        // Passwords made from the username are easy to guess
+        assert!(validate_password("wonderland", 0, &[]).is_ok());
+        assert!(validate_password("wonderland", 1, &["wonderland"]).is_err());
+
+        assert!(validate_password("correct horse battery staple", 3, &["alice"]).is_ok());
+    }
+
+    #[test]
+    fn weaker_hashes_are_upgraded() {
+        let weak = PasswordHashing {
+            memory_cost: 8,
+            time_cost: 1,
+            parallelism: 1,
+        };
+        let strong = PasswordHashing {
+            memory_cost: 16,
+            time_cost: 2,
+            parallelism: 1,
+        };
+
+        let hash = calculate_hash("hunter2", &weak).unwrap();
+        assert!(argon2::verify_encoded(&hash, b"hunter2").unwrap());
+        assert!(!hash_needs_upgrade(&hash, &weak));
+        assert!(hash_needs_upgrade(&hash, &strong));
+
+        let hash = calculate_hash("hunter2", &strong).unwrap();
+        assert!(!hash_needs_upgrade(&hash, &weak));
+        assert!(!hash_needs_upgrade(&hash, &strong));
+
+        assert!(hash_needs_upgrade(
+            "$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA",
+            &weak
+        ));
+    }
+}